@@ -0,0 +1,60 @@
+#!/usr/bin/env bash
+# Compares the cold-boot time of a VM across the block I/O setups singlevm offers: Firecracker
+# with and without O_DIRECT on the root and app file systems, and cloud-hypervisor with its
+# synchronous and io_uring engines, each with and without O_DIRECT. Our Firecracker fork only
+# has the synchronous engine, so io_uring is measured on cloud-hypervisor.
+#
+# Every round drops the page cache first, so that the root file system is read from the disk,
+# and records how long singlevm took to launch the VM until its runtime connected, as singlevm
+# logs it. Runs as root, for dropping the page cache and for /dev/kvm.
+#
+# usage: benchmarks/io-engine.sh KERNEL ROOTFS APPFS LMDB [ROUNDS]
+# Prints CSV lines of setup,round,boot_us to stdout. SINGLEVM overrides the singlevm binary.
+
+set -euo pipefail
+
+if [ $# -lt 4 ]; then
+    echo 'usage: benchmarks/io-engine.sh KERNEL ROOTFS APPFS LMDB [ROUNDS]' >&2
+    exit 1
+fi
+KERNEL=$1
+ROOTFS=$2
+APPFS=$3
+LMDB=$4
+ROUNDS=${5:-10}
+SINGLEVM=${SINGLEVM:-singlevm}
+
+SETUPS=(
+    firecracker-odirect
+    firecracker-buffered
+    cloud-hypervisor-sync-odirect
+    cloud-hypervisor-sync-buffered
+    cloud-hypervisor-io-uring-odirect
+    cloud-hypervisor-io-uring-buffered
+)
+
+# singlevm arguments of a setup
+args() {
+    case $1 in
+        firecracker-*) echo --hypervisor firecracker ;;
+        cloud-hypervisor-sync-*) echo --hypervisor cloud-hypervisor --io-engine sync ;;
+        cloud-hypervisor-io-uring-*) echo --hypervisor cloud-hypervisor --io-engine async ;;
+    esac
+    case $1 in
+        *-buffered) echo --no-odirect-root --no-odirect-app ;;
+    esac
+}
+
+echo setup,round,boot_us
+for round in $(seq 1 "$ROUNDS"); do
+    for setup in "${SETUPS[@]}"; do
+        sync
+        echo 3 > /proc/sys/vm/drop_caches
+        # no requests, so the VM shuts down once it booted
+        boot_us=$(RUST_LOG=singlevm=debug "$SINGLEVM" \
+            --kernel "$KERNEL" --rootfs "$ROOTFS" --appfs "$APPFS" --lmdb "$LMDB" \
+            $(args "$setup") < /dev/null 2>&1 \
+            | sed -n 's/.*VM ready in: \([0-9]*\) us.*/\1/p' || true)
+        echo "$setup,$round,${boot_us:-failed}"
+    done
+done
@@ -0,0 +1,50 @@
+//! Typed client for Faasten: the webfront REST API for users and the scheduler protocol for
+//! services running next to the scheduler.
+//!
+//! ```no_run
+//! use faasten_client::{Scheduler, Webfront};
+//!
+//! let webfront = Webfront::new("https://faasten.example").with_token("JWT");
+//! let result = webfront.invoke("home:<T,alice>:resize", &serde_json::json!({"width": 64}))?;
+//! let mut scheduler = Scheduler::connect("127.0.0.1:3000")?;
+//! scheduler.ping()?;
+//! # Ok::<(), faasten_client::Error>(())
+//! ```
+pub mod scheduler;
+pub mod webfront;
+
+pub use scheduler::Scheduler;
+pub use webfront::{FunctionSpec, GateSpec, Webfront};
+
+#[derive(Debug)]
+pub enum Error {
+    /// the request did not reach the webfront or its response could not be read
+    Http(reqwest::Error),
+    /// the webfront refused the request, with the `error` it reported
+    Status(u16, String),
+    Scheduler(snapfaas::sched::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "{}", e),
+            Error::Status(code, error) => write!(f, "{}: {}", code, error),
+            Error::Scheduler(e) => write!(f, "{:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<snapfaas::sched::Error> for Error {
+    fn from(e: snapfaas::sched::Error) -> Self {
+        Error::Scheduler(e)
+    }
+}
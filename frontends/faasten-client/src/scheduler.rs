@@ -0,0 +1,82 @@
+//! Invocations over the scheduler protocol, for services that can reach the scheduler directly
+use std::collections::HashMap;
+
+use snapfaas::sched::{
+    message::{GateInvoke, LabeledInvoke, TaskReturn},
+    rpc,
+};
+
+use super::Error;
+
+/// Connection to the scheduler. Clones share it.
+#[derive(Clone)]
+pub struct Scheduler {
+    conn: rpc::Client,
+}
+
+impl Scheduler {
+    /// Connects to the scheduler at `addr`, either ADDR:PORT or unix://PATH
+    pub fn connect(addr: &str) -> Result<Self, Error> {
+        Ok(Scheduler {
+            conn: rpc::connect(addr)?,
+        })
+    }
+
+    pub fn ping(&mut self) -> Result<(), Error> {
+        rpc::ping(&mut self.conn)?;
+        Ok(())
+    }
+
+    /// Invokes the gate at `gate` as the holder of the login `token`, the public principal if
+    /// `None`, and waits for its result. Bodies too large to pass inline come back as the name of
+    /// a blob in `payload.body_blob`.
+    pub fn invoke(
+        &mut self,
+        gate: &str,
+        token: Option<&str>,
+        payload: Vec<u8>,
+    ) -> Result<TaskReturn, Error> {
+        self.invoke_gate(GateInvoke {
+            gate: gate.to_string(),
+            token: token.unwrap_or_default().to_string(),
+            payload,
+            headers: HashMap::new(),
+            sync: true,
+            priority: 0,
+            label: None,
+            trace_taint: false,
+            preemptible: false,
+        })
+    }
+
+    /// Queues an invocation of the gate at `gate` without waiting for its result
+    pub fn invoke_async(
+        &mut self,
+        gate: &str,
+        token: Option<&str>,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.invoke_gate(GateInvoke {
+            gate: gate.to_string(),
+            token: token.unwrap_or_default().to_string(),
+            payload,
+            headers: HashMap::new(),
+            sync: false,
+            priority: 0,
+            label: None,
+            trace_taint: false,
+            preemptible: false,
+        })?;
+        Ok(())
+    }
+
+    /// Submits a fully specified gate invocation
+    pub fn invoke_gate(&mut self, invoke: GateInvoke) -> Result<TaskReturn, Error> {
+        Ok(rpc::invoke_gate(&mut self.conn, invoke)?)
+    }
+
+    /// Submits an invocation of an already resolved function, as workers and the webfront do
+    pub fn labeled_invoke(&mut self, invoke: LabeledInvoke) -> Result<TaskReturn, Error> {
+        Ok(rpc::labeled_invoke(&mut self.conn, invoke)?)
+    }
+}
@@ -0,0 +1,182 @@
+//! The webfront's REST API
+use std::collections::BTreeMap;
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use serde::Serialize;
+use snapfaas::fs::{Metadata, ResultRetention};
+
+use super::Error;
+
+/// What to run behind a direct gate, see `deploy_gate`. Images are Faasten paths of blobs.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct FunctionSpec {
+    pub memory: usize,
+    pub app_image: String,
+    pub runtime: String,
+    /// the default kernel if absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    pub devices: Vec<String>,
+    pub keep_alive: bool,
+    pub min_instances: usize,
+    pub speculative_boot: bool,
+    /// the function may run again after being preempted
+    pub idempotent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_ttl: Option<u64>,
+    pub requirements: BTreeMap<String, String>,
+    /// invocation headers passed to the function, `*` for all
+    pub forward_headers: Vec<String>,
+    /// invocations a worker node is expected to run at once, for autoscaling
+    pub concurrency_target: usize,
+    pub scratch: usize,
+    pub app_layers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<ResultRetention>,
+}
+
+/// A gate to deploy. Exactly one of `function` and `gate`, the path of the gate to redirect to,
+/// must be set.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct GateSpec {
+    /// public if absent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub privilege: String,
+    pub invoker_integrity_clearance: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub declassify: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function: Option<FunctionSpec>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+}
+
+/// Client of the webfront at a base URL, acting as the holder of a login token if it has one
+#[derive(Clone)]
+pub struct Webfront {
+    base_url: String,
+    token: Option<String>,
+    http: Client,
+}
+
+impl Webfront {
+    pub fn new(base_url: &str) -> Self {
+        Webfront {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token: None,
+            http: Client::new(),
+        }
+    }
+
+    /// Makes requests as the holder of the login `token`
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Invokes the gate at `gate` with the JSON `payload` and returns the function's response body
+    pub fn invoke<T: Serialize + ?Sized>(&self, gate: &str, payload: &T) -> Result<Vec<u8>, Error> {
+        let request = self.request(reqwest::Method::POST, "/faasten/invoke", gate);
+        bytes(send(request.json(payload))?)
+    }
+
+    /// Queues an invocation of the gate at `gate` whose result the scheduler POSTs to `callback`.
+    /// Takes a login, and `callback` must be on one of the scheduler's `--callback-hosts`.
+    pub fn invoke_async<T: Serialize + ?Sized>(
+        &self,
+        gate: &str,
+        payload: &T,
+        callback: &str,
+    ) -> Result<(), Error> {
+        let request = self.request(reqwest::Method::POST, "/faasten/invoke", gate);
+        send(request.header("x-faasten-callback", callback).json(payload))?;
+        Ok(())
+    }
+
+    /// Reads the file at `path`
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        bytes(send(self.request(reqwest::Method::GET, "/faasten/fs/file", path))?)
+    }
+
+    /// Overwrites the file at `path` with `data`
+    pub fn write(&self, path: &str, data: Vec<u8>) -> Result<(), Error> {
+        send(self.request(reqwest::Method::PUT, "/faasten/fs/file", path).body(data))?;
+        Ok(())
+    }
+
+    /// Creates an empty file at `path` labeled `label`
+    pub fn create_file(&self, path: &str, label: &str) -> Result<(), Error> {
+        let request = self.request(reqwest::Method::POST, "/faasten/fs/file", path);
+        send(request.json(&serde_json::json!({ "label": label })))?;
+        Ok(())
+    }
+
+    /// Creates the gate `spec` at `path`, or replaces the gate already there
+    pub fn deploy_gate(&self, path: &str, spec: &GateSpec) -> Result<(), Error> {
+        let create = self.request(reqwest::Method::POST, "/faasten/fs/gate", path);
+        match send(create.json(spec)) {
+            Err(Error::Status(400, error)) if error == "NameExists" => {
+                let update = self.request(reqwest::Method::PUT, "/faasten/fs/gate", path);
+                send(update.json(spec))?;
+                Ok(())
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Stores `data` as a blob labeled `label`, public if `None`, and links it at `path`
+    pub fn upload_blob(
+        &self,
+        path: &str,
+        label: Option<&str>,
+        content_type: Option<&str>,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        let mut request = self.request(reqwest::Method::POST, "/faasten/fs/blob", path);
+        if let Some(label) = label {
+            request = request.query(&[("label", label)]);
+        }
+        if let Some(content_type) = content_type {
+            request = request.header(reqwest::header::CONTENT_TYPE, content_type);
+        }
+        send(request.body(data))?;
+        Ok(())
+    }
+
+    fn request(&self, method: reqwest::Method, endpoint: &str, path: &str) -> RequestBuilder {
+        let url = format!(
+            "{}{}/{}",
+            self.base_url,
+            endpoint,
+            utf8_percent_encode(path, NON_ALPHANUMERIC)
+        );
+        let request = self.http.request(method, url);
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+// Sends `request`, turning error statuses into `Error::Status`
+fn send(request: RequestBuilder) -> Result<Response, Error> {
+    let response = request.send()?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let error = response
+        .json::<serde_json::Value>()
+        .ok()
+        .and_then(|body| body.get("error").and_then(|e| e.as_str()).map(String::from))
+        .unwrap_or_else(|| status.to_string());
+    Err(Error::Status(status.as_u16(), error))
+}
+
+fn bytes(response: Response) -> Result<Vec<u8>, Error> {
+    Ok(response.bytes()?.to_vec())
+}
@@ -2,7 +2,6 @@ use std::io::prelude::*;
 use std::net::{TcpListener, TcpStream};
 
 use bytes::{BufMut, Bytes, BytesMut};
-use http;
 
 pub struct Client {
     stream: TcpStream,
@@ -4,25 +4,24 @@ use std::time::SystemTime;
 
 use jwt::{PKeyWithDigest, SignWithKey, VerifyWithKey};
 use labeled::buckle::Buckle;
-use labeled::buckle::Clause;
 use labeled::buckle::Component;
-use log::error;
+use log::{error, warn};
 use openssl::pkey::{self, PKey};
-use reqwest::blocking::Client;
 use rouille::{Request, Response};
 use serde::{Deserialize, Serialize};
 
 use snapfaas::blobstore::Blobstore;
+use snapfaas::delegation::Delegation;
+use snapfaas::download::{DownloadError, DownloadToken};
+use snapfaas::fs::aliases::LabelAliases;
+use snapfaas::fs::catalog::{Catalog, Listing};
 use snapfaas::fs::BackingStore;
 use snapfaas::fs::FS;
 use snapfaas::sched;
-use snapfaas::sched::Scheduler;
 
-#[derive(Clone)]
-pub struct GithubOAuthCredentials {
-    pub client_id: String,
-    pub client_secret: String,
-}
+use crate::auth::{AuthProvider, GithubPairing, Providers};
+use crate::fsapi;
+use crate::openapi;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 struct Claims {
@@ -30,46 +29,211 @@ struct Claims {
     pub iat: u64,
     pub exp: u64,
     pub sub: Component,
+    /// the login session the token belongs to, tokens without one can't be revoked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+}
+
+const SESSION_COOKIE: &str = "faasten_session";
+const CSRF_HEADER: &str = "X-Faasten-CSRF";
+// the `state` of the login in progress, see `crate::auth`
+const LOGIN_STATE_COOKIE: &str = "faasten_login_state";
+
+// The `state` of the login the request completes, empty without the cookie
+fn login_state(request: &Request) -> String {
+    rouille::input::cookies(request)
+        .find(|(name, _)| *name == LOGIN_STATE_COOKIE)
+        .map(|(_, state)| state.to_string())
+        .unwrap_or_default()
+}
+
+// Keeps `state` for the login's callback
+fn with_login_state(response: Response, state: &str) -> Response {
+    response.with_additional_header(
+        "Set-Cookie",
+        format!(
+            "{}={}; Path=/authenticate; Max-Age=600; HttpOnly; Secure; SameSite=Lax",
+            LOGIN_STATE_COOKIE, state
+        ),
+    )
+}
+
+fn session_cookie(request: &Request) -> Option<&str> {
+    rouille::input::cookies(request)
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, sid)| sid)
 }
 
 #[derive(Clone)]
 pub struct App<B> {
     pkey: PKey<pkey::Private>,
     pubkey: PKey<pkey::Public>,
-    gh_creds: GithubOAuthCredentials,
+    platform_key: Option<PKey<pkey::Public>>,
+    providers: Providers,
+    github: Option<GithubPairing>,
     blobstore: Arc<Mutex<Blobstore>>,
     fs: Arc<FS<B>>,
     base_url: String,
-    conn: r2d2::Pool<Scheduler>,
+    allowed_origins: Vec<String>,
+    conn: sched::rpc::Client,
+    invoke_options: super::init::Options,
 }
 
 impl<B: BackingStore> App<B> {
     pub fn new(
         pkey: PKey<pkey::Private>,
         pubkey: PKey<pkey::Public>,
-        gh_creds: GithubOAuthCredentials,
         blobstore: Blobstore,
         kvdb: B,
         base_url: String,
         addr: String,
     ) -> Self {
-        let conn = r2d2::Pool::builder()
-            .max_size(10)
-            .build(Scheduler::new(&addr))
-            .expect("pool");
+        let conn = sched::rpc::connect(&addr).expect("connect to the scheduler");
+        let origin = reqwest::Url::parse(&base_url)
+            .expect("parse the base URL")
+            .origin()
+            .ascii_serialization();
         let blobstore = Arc::new(Mutex::new(blobstore));
+        let fs = FS::new(kvdb);
+        fs.assert_partitioned();
+        if let Err(e) = snapfaas::session::initialize(&fs) {
+            warn!("login sessions unavailable: {:?}", e);
+        }
         App {
             conn,
             blobstore,
-            fs: Arc::new(FS::new(kvdb)),
+            fs: Arc::new(fs),
             pkey,
             pubkey,
-            gh_creds,
+            platform_key: None,
+            providers: Default::default(),
+            github: None,
             base_url,
+            allowed_origins: vec![origin],
+            invoke_options: Default::default(),
         }
     }
 
+    /// Passes payloads larger than `bytes` through the scheduler as blobs, 0 passes everything
+    /// inline
+    pub fn set_max_inline(&mut self, bytes: usize) {
+        self.invoke_options.max_inline = bytes;
+    }
+
+    /// Forwards only the HTTP headers named in `names` to functions, instead of all but
+    /// `Authorization`
+    pub fn set_forward_headers(&mut self, names: Vec<String>) {
+        self.invoke_options.forward_headers = Some(names);
+    }
+
+    /// Lets logged in users ask for priorities up to `max` with `x-faasten-priority`. Defaults
+    /// to 0, which runs every invocation at the lowest priority.
+    pub fn set_max_priority(&mut self, max: u32) {
+        self.invoke_options.max_priority = max;
+    }
+
+    /// Seals payloads for their gates with `secret`, which workers share, so that scheduler
+    /// nodes cannot read them
+    pub fn set_payload_secret(&mut self, secret: Vec<u8>) {
+        self.invoke_options.payload_secret = Some(secret);
+    }
+
+    /// Also serves download tokens signed by `key`, the public half of the key workers sign
+    /// receipts and download tokens with
+    pub fn set_platform_key(&mut self, key: PKey<pkey::Public>) {
+        self.platform_key = Some(key);
+    }
+
+    /// Accepts state-changing requests authenticated by the session cookie from pages on
+    /// `origin`, e.g. `https://app.example.com`, besides the base URL's
+    pub fn add_allowed_origin(&mut self, origin: String) {
+        self.allowed_origins.push(origin);
+    }
+
+    /// Serves `provider` under `/login/<name>` and `/authenticate/<name>`
+    pub fn add_auth_provider(&mut self, name: String, provider: Arc<dyn AuthProvider>) {
+        self.providers.insert(name, provider);
+    }
+
+    /// Lets logged in users pair GitHub accounts through `/login/github`
+    pub fn set_github_pairing(&mut self, github: GithubPairing) {
+        self.github = Some(github);
+    }
+
+    /// Removes expired login sessions in the background
+    pub fn spawn_session_sweeper(&self)
+    where
+        B: Send + Sync + 'static,
+    {
+        snapfaas::session::spawn_sweeper(self.fs.clone());
+    }
+
+    // Opens a login session for `sub`. Tokens are only issued within a session, so that
+    // logging out revokes them.
+    fn open_session(&self, sub: &Component) -> Result<String, Response> {
+        snapfaas::session::create(self.fs.as_ref(), sub.clone()).map_err(|e| {
+            error!("no login session for {:?}: {:?}", sub, e);
+            Response::json(&serde_json::json!({ "error": "Failed to open a login session" }))
+                .with_status_code(500)
+        })
+    }
+
+    // a login token for `sub` in session `sid`, valid for 10 minutes
+    fn issue_token(&self, sub: Component, sid: String) -> String {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let claims = Claims {
+            alg: "ES256".to_string(),
+            iat: now,
+            exp: now + 10 * 60,
+            sub,
+            sid: Some(sid),
+        };
+        let key = PKeyWithDigest {
+            key: self.pkey.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        claims.sign_with_key(&key).unwrap()
+    }
+
     fn verify_jwt(&self, request: &Request) -> Result<Component, Response> {
+        self.current_login(request).map(|(sub, _)| sub)
+    }
+
+    // The login of an operator, i.e. one holding the faasten privilege
+    fn verify_operator(&self, request: &Request) -> Result<Component, Response> {
+        let login = self.verify_jwt(request)?;
+        if login.implies(&snapfaas::fs::bootstrap::FAASTEN_PRIV) {
+            Ok(login)
+        } else {
+            Err(Response::json(&serde_json::json!({
+                "error": "Only operators may see this"
+            }))
+            .with_status_code(403))
+        }
+    }
+
+    // The principal the request speaks for and its login session, if any. Browsers send the
+    // session cookie, everyone else a login token.
+    fn current_login(&self, request: &Request) -> Result<(Component, Option<String>), Response> {
+        if let Some(sub) = self.providers.values().find_map(|p| p.authenticate(request)) {
+            return Ok((sub, None));
+        }
+        let revoked = || {
+            Response::json(&serde_json::json!({
+                "error": "Login session expired or revoked"
+            }))
+            .with_status_code(401)
+        };
+        if request.header("Authorization").is_none() {
+            if let Some(sid) = session_cookie(request) {
+                let session = snapfaas::session::lookup(self.fs.as_ref(), sid).ok_or_else(revoked)?;
+                return Ok((session.principal, Some(sid.to_string())));
+            }
+        }
         let jwt = request
             .header("Authorization")
             .and_then(|header| header.split(" ").last())
@@ -86,15 +250,21 @@ impl<B: BackingStore> App<B> {
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        if claims.exp < now && false {
-            // TODO: remove false for production
-            Err(Response::json(&serde_json::json!({
+        if claims.exp < now {
+            return Err(Response::json(&serde_json::json!({
                 "error": "Authentication token expired"
             }))
-            .with_status_code(403))
-        } else {
-            Ok(claims.sub)
+            .with_status_code(403));
         }
+        // tokens outside a session could not be revoked
+        let sid = claims.sid.ok_or_else(|| {
+            Response::json(&serde_json::json!({
+                "error": "Login token without a session"
+            }))
+            .with_status_code(401)
+        })?;
+        snapfaas::session::lookup(self.fs.as_ref(), &sid).ok_or_else(revoked)?;
+        Ok((claims.sub, Some(sid)))
     }
 
     pub fn handle(&mut self, request: &Request) -> Response {
@@ -103,21 +273,17 @@ impl<B: BackingStore> App<B> {
                 .with_additional_header("Access-Control-Allow-Origin", "*")
                 .with_additional_header(
                     "Access-Control-Allow-Headers",
-                    "Authorization, Content-type,X-Faasten-Label"
+                    "Authorization, Content-type,X-Faasten-Label,X-Faasten-Delegation,\
+                    X-Faasten-Priority,If-None-Match,X-Faasten-CSRF"
                 )
                 .with_additional_header("Access-Control-Allow-Methods", "*");
         }
+        if let Err(resp) = self.check_csrf(request) {
+            return resp.with_additional_header("Access-Control-Allow-Origin", "*");
+        }
         rouille::router!(request,
-            (GET) (/login/cas) => {
-                Ok(Response::redirect_302(
-                    format!("{}/login?service={}", "https://fed.princeton.edu/cas", format!("{}/authenticate/cas", self.base_url))))
-            },
-            (GET) (/authenticate/cas) => {
-                self.authenticate_cas(request)
-            },
             (GET) (/login/github) => {
-                Ok(Response::redirect_302(
-                    format!("https://github.com/login/oauth/authorize?client_id={}&scope=repo:invites", self.gh_creds.client_id)))
+                self.login_github()
             },
             (GET) (/authenticate/github) => {
                 self.auth_github(request)
@@ -125,21 +291,126 @@ impl<B: BackingStore> App<B> {
             (POST) (/pair_github) => {
                 self.pair_github_to_user(request)
             },
+            (GET) (/login/{provider: String}) => {
+                self.login(&provider)
+            },
+            (GET) (/authenticate/{provider: String}) => {
+                self.authenticate(&provider, request)
+            },
+            (POST) (/logout) => {
+                self.logout(request)
+            },
+            (GET) (/csrf) => {
+                self.csrf(request)
+            },
             (GET) (/me) => {
                 self.whoami(request)
             },
+            (GET) (/openapi.json) => {
+                Ok(Response::json(&*openapi::DOCUMENT))
+            },
             (GET) (/faasten/ping) => {
                 Ok(Response::text("Pong.").with_status_code(200))
             },
             (GET) (/faasten/ping/scheduler) => {
                 self.faasten_ping_scheduler()
             },
+            (GET) (/faasten/stats/contention) => {
+                self.verify_operator(request)
+                    .map(|_| Response::json(&snapfaas::fs::contention_stats()))
+            },
+            (GET) (/faasten/stats/blobs) => {
+                self.verify_operator(request)
+                    .map(|_| Response::json(&self.blobstore.lock().unwrap().total_usage()))
+            },
             (POST) (/faasten/invoke/{gate_path}) => {
                 self.faasten_invoke(gate_path, request)
             },
             (POST) (/faasten/delegate) => {
                 self.delegate(request)
             },
+            (POST) (/faasten/delegate/certificate) => {
+                self.delegation_certificate(request)
+            },
+            (POST) (/explain) => {
+                self.explain(request)
+            },
+            (GET) (/faasten/catalog) => {
+                self.catalog(request, None)
+            },
+            (GET) (/faasten/catalog/{name: String}) => {
+                self.catalog(request, Some(&name))
+            },
+            (GET) (/faasten/fs/ls/{path}) => {
+                self.fs_request(request, |fs| fsapi::list(fs, &path, request))
+            },
+            (GET) (/home/search) => {
+                self.fs_request(request, |fs| fsapi::search_home(fs, request))
+            },
+            (GET) (/faasten/fs/ls_faceted/{path}) => {
+                self.fs_request(request, |fs| fsapi::list_faceted(fs, &path, request))
+            },
+            (POST) (/faasten/fs/download_url/{path}) => {
+                self.fs_request(request, |fs| {
+                    fsapi::download_url(fs, &path, request, &self.pkey, &self.base_url)
+                })
+            },
+            (GET) (/faasten/download/{token}) => {
+                self.download(&token)
+            },
+            (GET) (/faasten/fs/file/{path}) => {
+                self.fs_request(request, |fs| fsapi::read(fs, &path, request))
+            },
+            (PUT) (/faasten/fs/file/{path}) => {
+                self.fs_request(request, |fs| fsapi::write(fs, &path, request))
+            },
+            (POST) (/faasten/fs/file/{path}) => {
+                self.fs_request(request, |fs| fsapi::create_file(fs, &path, request))
+            },
+            (GET) (/faasten/fs/log/{path}) => {
+                self.fs_request(request, |fs| fsapi::read_log(fs, &path, request))
+            },
+            (PUT) (/faasten/fs/log/{path}) => {
+                self.fs_request(request, |fs| fsapi::append_log(fs, &path, request))
+            },
+            (POST) (/faasten/fs/log/{path}) => {
+                self.fs_request(request, |fs| fsapi::create_log(fs, &path, request))
+            },
+            (POST) (/faasten/fs/blob/{path}) => {
+                self.fs_request(request, |fs| {
+                    fsapi::upload_blob(fs, &self.blobstore, &path, request)
+                })
+            },
+            (POST) (/faasten/fs/dir/{path}) => {
+                self.fs_request(request, |fs| fsapi::create_dir(fs, &path, request))
+            },
+            (POST) (/faasten/fs/faceted/{path}) => {
+                self.fs_request(request, |fs| fsapi::create_faceted_dir(fs, &path))
+            },
+            (POST) (/faasten/fs/link/{path}) => {
+                self.fs_request(request, |fs| fsapi::link(fs, &path, request))
+            },
+            (DELETE) (/faasten/fs/link/{path}) => {
+                self.fs_request(request, |fs| fsapi::unlink(fs, &path, request))
+            },
+            (POST) (/faasten/fs/snapshot/{path}) => {
+                self.fs_request(request, |fs| fsapi::snapshot(fs, &path, request))
+            },
+            (POST) (/faasten/fs/restore/{path}) => {
+                self.fs_request(request, |fs| fsapi::restore(fs, &path, request))
+            },
+            (POST) (/faasten/fs/gate/{path}) => {
+                self.fs_request(request, |fs| fsapi::create_gate(fs, &path, request))
+            },
+            (PUT) (/faasten/fs/gate/{path}) => {
+                self.fs_request(request, |fs| fsapi::update_gate(fs, &path, request))
+            },
+            (POST) (/faasten/fs/service/{path}) => {
+                self.fs_request(request, |fs| fsapi::create_service(fs, &path, request))
+            },
+            (PUT) (/faasten/fs/service/{path}) => {
+                self.fs_request(request, |fs| fsapi::update_service(fs, &path, request))
+            },
             _ => {
                 error!("404: {} {}", request.method(), request.raw_url());
                 Ok(Response::empty_404())
@@ -147,68 +418,9 @@ impl<B: BackingStore> App<B> {
         ).unwrap_or_else(|e| e).with_additional_header("Access-Control-Allow-Origin", "*")
     }
 
-    fn pair_github_to_user(&self, request: &Request) -> Result<Response, Response> {
-        //let local_user = self.verify_jwt(request)?;
-
-        let input = rouille::post_input!(request, {
-            github_token: String,
-        })
-        .map_err(|e| {
-            println!("{:?}", e);
-            Response::empty_400()
-        })?;
-        let client = Client::builder()
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .unwrap();
-        let github_user: github_types::User = client
-            .get(format!("https://api.github.com/user"))
-            .header(reqwest::header::ACCEPT, "application/vnd.github.v3+json")
-            .header(reqwest::header::USER_AGENT, "SnapFaaS Web Frontend")
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("Bearer {}", input.github_token),
-            )
-            .send()
-            .expect("reqwest")
-            .json()
-            .unwrap();
-        Ok(Response::json(&github_user.login))
-    }
-
-    fn auth_github(&self, request: &Request) -> Result<Response, Response> {
-        let code = request.get_param("code").ok_or(Response::empty_404())?;
-        let client = Client::builder()
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .unwrap();
-        let uat = client
-            .post(format!("https://github.com/login/oauth/access_token"))
-            .header(reqwest::header::ACCEPT, "application/vnd.github.v3+json")
-            .header(reqwest::header::USER_AGENT, "SnapFaaS Web Frontend")
-            .multipart(
-                reqwest::blocking::multipart::Form::new()
-                    .text("client_id", self.gh_creds.client_id.clone())
-                    .text("client_secret", self.gh_creds.client_secret.clone())
-                    .text("code", code),
-            )
-            .send()
-            .expect("reqwest");
-
-        #[derive(Debug, Deserialize)]
-        struct AuthResponse {
-            access_token: String,
-        }
-        let t: AuthResponse = uat.json().map_err(|_| Response::empty_400())?;
-        Ok(Response::html(format!(
-            include_str!("authenticated_github.html"),
-            token = t.access_token,
-            base_url = self.base_url
-        )))
-    }
-
     fn delegate(&self, request: &Request) -> Result<Response, Response> {
-        let login = self.verify_jwt(request)?;
+        // delegated tokens end with the session they were issued in
+        let (login, sid) = self.current_login(request)?;
 
         let mut request_body = request.data().ok_or(Response::empty_400())?;
         #[derive(Deserialize)]
@@ -236,39 +448,90 @@ impl<B: BackingStore> App<B> {
                 snapfaas::fs::utils::set_my_privilge(Component::dc_true());
             }
 
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-
-            let claims = Claims {
-                alg: "ES256".to_string(),
-                iat: now,
-                exp: now + 10 * 60,
-                sub: new_principal,
-            };
-            let key = PKeyWithDigest {
-                key: self.pkey.clone(),
-                digest: openssl::hash::MessageDigest::sha256(),
+            // logins through a provider have no session to end with, so they get their own
+            let sid = match sid {
+                Some(sid) => sid,
+                None => self.open_session(&new_principal)?,
             };
-            let token = claims.sign_with_key(&key).unwrap();
-
-            Ok(Response::text(token))
+            Ok(Response::text(self.issue_token(new_principal, sid)))
         } else {
             Err(Response::empty_406())
         }
     }
 
+    // Issue a time-limited certificate granting part of the caller's privilege to another
+    // principal. The certificate is signed with the same key as login tokens.
+    fn delegation_certificate(&self, request: &Request) -> Result<Response, Response> {
+        let login = self.verify_jwt(request)?;
+
+        let mut request_body = request.data().ok_or(Response::empty_400())?;
+        #[derive(Deserialize)]
+        struct Certificate {
+            grantee: String,
+            grant: String,
+            ttl: u64,
+        }
+        let cert: Certificate = serde_json::from_reader(&mut request_body)
+            .map_err(|e|Response::json(&serde_json::json!({ "error": e.to_string() })).with_status_code(400))?;
+        let grantee = Buckle::parse(format!("{},T", cert.grantee).as_str())
+            .map_err(|e|Response::json(&serde_json::json!({ "error": e.to_string() })).with_status_code(400))?.secrecy;
+        let grant = Buckle::parse(format!("{},T", cert.grant).as_str())
+            .map_err(|e|Response::json(&serde_json::json!({ "error": e.to_string() })).with_status_code(400))?.secrecy;
+
+        let delegation = Delegation::new(login, grantee, grant, cert.ttl)
+            .map_err(|e|Response::json(&serde_json::json!({ "error": format!("{:?}", e) })).with_status_code(406))?;
+        Ok(Response::text(delegation.sign(&self.pkey)))
+    }
+
+    // Merge the privilege granted by an `X-Faasten-Delegation` certificate, if any.
+    fn apply_delegation(&self, request: &Request, login: Option<Component>) -> Result<Option<Component>, Response> {
+        match request.header("X-Faasten-Delegation") {
+            Some(token) => {
+                let privilege = login.unwrap_or(Component::dc_true());
+                Delegation::verify(token, &self.pubkey)
+                    .and_then(|d| d.apply(privilege))
+                    .map(Some)
+                    .map_err(|e| {
+                        Response::json(&serde_json::json!({ "error": format!("{:?}", e) }))
+                            .with_status_code(403)
+                    })
+            }
+            None => Ok(login),
+        }
+    }
+
+    // Run a file system operation on behalf of the logged-in user
+    fn fs_request<F>(&self, request: &Request, f: F) -> Result<Response, Response>
+    where
+        F: FnOnce(&FS<B>) -> Result<Response, Response>,
+    {
+        let login = self.verify_jwt(request)?;
+        fsapi::enter(login);
+        f(self.fs.as_ref())
+    }
+
     fn faasten_invoke(&self, gate_path: String, request: &Request) -> Result<Response, Response> {
         let login = self.verify_jwt(request).ok();
+        let login = self.apply_delegation(request, login)?;
         let gate_path = percent_encoding::percent_decode_str(&gate_path).decode_utf8_lossy().to_string();
 
-        let conn = &mut self.conn.get().map_err(|_| {
-            Response::json(&serde_json::json!({
-                "error": "failed to get scheduler connection"
-            }))
-            .with_status_code(500)
-        })?;
+        // clones share the connection
+        let conn = &mut self.conn.clone();
+
+        // The scheduler's admission policy decides whether a busy queue takes the invocation.
+        // If it doesn't, tell the client when to retry, if the load is known.
+        let retry_after = |resp: Response| {
+            if resp.status_code != 503 && resp.status_code != 504 {
+                return resp;
+            }
+            match sched::rpc::load(&mut self.conn.clone()) {
+                Ok(load) => resp.with_additional_header(
+                    "Retry-After",
+                    load.estimated_wait_ms.div_ceil(1000).max(1).to_string(),
+                ),
+                Err(_) => resp,
+            }
+        };
 
         super::init::init(
             login,
@@ -277,17 +540,15 @@ impl<B: BackingStore> App<B> {
             conn,
             self.fs.as_ref(),
             self.blobstore.clone(),
+            &self.invoke_options,
         )
+        .map_err(retry_after)
     }
 
     // check if we can reach the scheduler
     fn faasten_ping_scheduler(&self) -> Result<Response, Response> {
-        let conn = &mut self.conn.get().map_err(|_| {
-            Response::json(&serde_json::json!({
-                "error": "failed to get scheduler connection"
-            }))
-            .with_status_code(500)
-        })?;
+        // clones share the connection
+        let conn = &mut self.conn.clone();
 
         sched::rpc::ping(conn)
             .map_err(|_| {
@@ -299,6 +560,66 @@ impl<B: BackingStore> App<B> {
             .map(|_| Response::empty_204())
     }
 
+    // Report whether `current_label` can flow to `target` with `privilege` (defaults to the
+    // logged-in user's privilege) and which clauses fail.
+    fn explain(&self, request: &Request) -> Result<Response, Response> {
+        let login = self.verify_jwt(request).ok();
+
+        let mut request_body = request.data().ok_or(Response::empty_400())?;
+        #[derive(Deserialize)]
+        struct Explain {
+            current_label: String,
+            target: String,
+            privilege: Option<String>,
+        }
+        let explain: Explain = serde_json::from_reader(&mut request_body)
+            .map_err(|e|Response::json(&serde_json::json!({ "error": e.to_string() })).with_status_code(400))?;
+        let aliases = LabelAliases::load(self.fs.as_ref());
+        let current = aliases.resolve(explain.current_label.as_str())
+            .map_err(|e|Response::json(&serde_json::json!({ "error": e.to_string() })).with_status_code(400))?;
+        let target = aliases.resolve(explain.target.as_str())
+            .map_err(|e|Response::json(&serde_json::json!({ "error": e.to_string() })).with_status_code(400))?;
+        let privilege = if let Some(p) = explain.privilege {
+            Buckle::parse(format!("{},T", p).as_str())
+                .map_err(|e|Response::json(&serde_json::json!({ "error": e.to_string() })).with_status_code(400))?.secrecy
+        } else {
+            login.unwrap_or(Component::dc_true())
+        };
+
+        Ok(Response::json(&snapfaas::fs::utils::explain_flow(&current, &target, &privilege)))
+    }
+
+    // Lists the published gates, or describes the one named `name`. Anyone may browse the
+    // catalog. For a logged-in user, each gate also tells whether the user may invoke it.
+    fn catalog(&self, request: &Request, name: Option<&str>) -> Result<Response, Response> {
+        let login = self.verify_jwt(request).ok();
+        #[derive(Serialize)]
+        struct Item {
+            #[serde(flatten)]
+            listing: Listing,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            invokable: Option<bool>,
+        }
+        let item = |listing: Listing| Item {
+            invokable: login
+                .as_ref()
+                .map(|login| login.implies(&listing.invoker_integrity_clearance)),
+            listing,
+        };
+        let catalog = Catalog::load(self.fs.as_ref());
+        match name {
+            Some(name) => catalog
+                .listing(self.fs.as_ref(), name)
+                .map(|listing| Response::json(&item(listing)))
+                .ok_or(Response::empty_404()),
+            None => {
+                let items: Vec<Item> =
+                    catalog.listings(self.fs.as_ref()).into_iter().map(item).collect();
+                Ok(Response::json(&items))
+            }
+        }
+    }
+
     fn whoami(&self, request: &Request) -> Result<Response, Response> {
         let login = self.verify_jwt(request)?;
         #[derive(Serialize)]
@@ -308,53 +629,161 @@ impl<B: BackingStore> App<B> {
         Ok(Response::json(&User { login: login.to_string() }))
     }
 
-    fn authenticate_cas(&self, request: &Request) -> Result<Response, Response> {
-        let ticket = request.get_param("ticket").ok_or(Response::empty_404())?;
-        let service = format!("{}/authenticate/cas", self.base_url);
-
-        let client = Client::builder()
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .unwrap();
-        let validate_cas = client
-            .get(format!("{}/validate", "https://fed.princeton.edu/cas"))
-            .query(&[("ticket", ticket), ("service", service)])
-            .send()
-            .expect("reqwest");
-        let sub: Component = validate_cas
-            .text()
-            .or(Err(Response::empty_400()))
-            .and_then(|text| {
-                let result: Vec<&str> = text.lines().collect();
-                match result.as_slice() {
-                    // FIXME buckle parser does not allow `@`. should we?
-                    ["yes", user] => Ok(Component::formula([Clause::new_from_vec(vec![vec!["princeton.edu", user]])])),
-                    _ => Err(Response::empty_400()),
-                }
-            })?;
+    fn login(&self, provider: &str) -> Result<Response, Response> {
+        let callback = format!("{}/authenticate/{}", self.base_url, provider);
+        let state = crate::auth::new_state();
+        let url = self
+            .providers
+            .get(provider)
+            .and_then(|p| p.login_url(&callback, &state))
+            .ok_or(Response::empty_404())?;
+        Ok(with_login_state(Response::redirect_302(url), &state))
+    }
 
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        let claims = Claims {
-            alg: "ES256".to_string(),
-            iat: now,
-            exp: now + 10 * 60,
-            sub: sub.clone(),
-        };
-        let key = PKeyWithDigest {
-            key: self.pkey.clone(),
-            digest: openssl::hash::MessageDigest::sha256(),
-        };
-        let token = claims.sign_with_key(&key).unwrap();
+    fn authenticate(&self, provider: &str, request: &Request) -> Result<Response, Response> {
+        let callback = format!("{}/authenticate/{}", self.base_url, provider);
+        let state = login_state(request);
+        let sub = self
+            .providers
+            .get(provider)
+            .ok_or(Response::empty_404())?
+            .callback(request, &callback, &state)?;
+        let sid = self.open_session(&sub)?;
+        let token = self.issue_token(sub.clone(), sid.clone());
 
         snapfaas::fs::bootstrap::register_user_fsutil(self.fs.as_ref(), sub.clone(), sub);
 
-        Ok(Response::html(format!(
-            include_str!("authenticated_cas.html"),
+        let response = Response::html(format!(
+            include_str!("authenticated.html"),
             token
-        )))
+        ));
+        Ok(response
+            .with_additional_header(
+                "Set-Cookie",
+                format!(
+                    "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+                    SESSION_COOKIE,
+                    sid,
+                    snapfaas::session::SESSION_TTL
+                ),
+            )
+            .with_additional_header(
+                "Set-Cookie",
+                format!("{}=; Path=/authenticate; Max-Age=0", LOGIN_STATE_COOKIE),
+            ))
+    }
+
+    fn login_github(&self) -> Result<Response, Response> {
+        let github = self.github.as_ref().ok_or(Response::empty_404())?;
+        let state = crate::auth::new_state();
+        Ok(with_login_state(
+            Response::redirect_302(github.login_url(&state)),
+            &state,
+        ))
+    }
+
+    // Hands the popup a GitHub token for the web app to pair with its login at /pair_github
+    fn auth_github(&self, request: &Request) -> Result<Response, Response> {
+        let github = self.github.as_ref().ok_or(Response::empty_404())?;
+        let token = github.access_token(request, &login_state(request))?;
+        Ok(Response::html(format!(
+            include_str!("authenticated_github.html"),
+            token = token,
+            base_url = self.base_url
+        ))
+        .with_additional_header(
+            "Set-Cookie",
+            format!("{}=; Path=/authenticate; Max-Age=0", LOGIN_STATE_COOKIE),
+        ))
+    }
+
+    fn pair_github_to_user(&self, request: &Request) -> Result<Response, Response> {
+        let input = rouille::post_input!(request, {
+            github_token: String,
+        })
+        .map_err(|e| {
+            warn!("pair_github: {:?}", e);
+            Response::empty_400()
+        })?;
+        crate::auth::github_login(&input.github_token).map(|login| Response::json(&login))
+    }
+
+    // Browsers attach the session cookie to requests from any page, so state-changing requests
+    // authenticated by it must come from an allowed origin and carry the session's CSRF token.
+    // Requests with an Authorization header are only sent by code that holds the token.
+    fn check_csrf(&self, request: &Request) -> Result<(), Response> {
+        if matches!(request.method(), "GET" | "HEAD" | "OPTIONS")
+            || request.header("Authorization").is_some()
+        {
+            return Ok(());
+        }
+        let sid = match session_cookie(request) {
+            Some(sid) => sid,
+            None => return Ok(()),
+        };
+        let origin_ok = request
+            .header("Origin")
+            .or_else(|| request.header("Referer"))
+            .is_some_and(|origin| {
+                self.allowed_origins.iter().any(|allowed| {
+                    origin == allowed
+                        || origin.strip_prefix(allowed.as_str()).is_some_and(|p| p.starts_with('/'))
+                })
+            });
+        let token_ok = request.header(CSRF_HEADER).is_some_and(|token| {
+            let expected = snapfaas::session::csrf_token(sid);
+            token.len() == expected.len()
+                && openssl::memcmp::eq(token.as_bytes(), expected.as_bytes())
+        });
+        if origin_ok && token_ok {
+            Ok(())
+        } else {
+            Err(Response::json(&serde_json::json!({
+                "error": "Cross-site request rejected"
+            }))
+            .with_status_code(403))
+        }
+    }
+
+    // The CSRF token of the request's login session
+    fn csrf(&self, request: &Request) -> Result<Response, Response> {
+        let (_, sid) = self.current_login(request)?;
+        let sid = sid.ok_or_else(|| {
+            Response::json(&serde_json::json!({ "error": "Not in a login session" }))
+                .with_status_code(400)
+        })?;
+        Ok(Response::json(&serde_json::json!({
+            "token": snapfaas::session::csrf_token(&sid)
+        })))
+    }
+
+    // Anyone holding a download token may read the object it names, no login needed
+    fn download(&self, token: &str) -> Result<Response, Response> {
+        let token = match DownloadToken::verify(token, &self.pubkey) {
+            Err(DownloadError::BadSignature) if self.platform_key.is_some() => {
+                DownloadToken::verify(token, self.platform_key.as_ref().unwrap())
+            }
+            result => result,
+        }
+        .map_err(fsapi::download_error)?;
+        fsapi::enter(Component::dc_true());
+        fsapi::download(self.fs.as_ref(), &self.blobstore, token)
+    }
+
+    // Revokes the request's login session, and with it every token issued in the session
+    fn logout(&self, request: &Request) -> Result<Response, Response> {
+        let (_, sid) = self.current_login(request)?;
+        let sid = sid.ok_or_else(|| {
+            Response::json(&serde_json::json!({ "error": "Not in a login session" }))
+                .with_status_code(400)
+        })?;
+        snapfaas::session::revoke(self.fs.as_ref(), &sid).map_err(|e| {
+            Response::json(&serde_json::json!({ "error": format!("{:?}", e) }))
+                .with_status_code(500)
+        })?;
+        Ok(Response::empty_204().with_additional_header(
+            "Set-Cookie",
+            format!("{}=; Path=/; Max-Age=0; HttpOnly; Secure; SameSite=Lax", SESSION_COOKIE),
+        ))
     }
 }
@@ -0,0 +1,423 @@
+//! Login providers
+//!
+//! An `AuthProvider` turns proof of an identity into the principal webfront issues login tokens
+//! for. Interactive providers send the browser to a login page that redirects back to
+//! `/authenticate/<name>`. Others vouch for each request on their own, in place of a login
+//! token.
+//!
+//! Each interactive login gets a random `state`, which webfront keeps in a cookie for the
+//! callback. Providers that support it send it along to the login page and check that it comes
+//! back, so that a login started in another browser cannot be completed in this one.
+//!
+//! GitHub is not a login provider. Logged in users pair a GitHub account with `GithubPairing`,
+//! which never names a principal.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use labeled::buckle::{Buckle, Clause, Component};
+use log::warn;
+use reqwest::blocking::Client;
+use rouille::{Request, Response};
+use serde::Deserialize;
+
+pub trait AuthProvider: Send + Sync {
+    /// Where to send the browser to log in, `None` for providers without an interactive login.
+    /// The login page sends the browser back to `callback`.
+    fn login_url(&self, _callback: &str, _state: &str) -> Option<String> {
+        None
+    }
+
+    /// The principal the login page's redirect back to `callback` proves, for the login
+    /// started with `state`
+    fn callback(
+        &self,
+        _request: &Request,
+        _callback: &str,
+        _state: &str,
+    ) -> Result<Component, Response> {
+        Err(Response::empty_404())
+    }
+
+    /// The principal the request proves on its own, if any
+    fn authenticate(&self, _request: &Request) -> Option<Component> {
+        None
+    }
+}
+
+/// Login providers by the name in their routes
+pub type Providers = BTreeMap<String, Arc<dyn AuthProvider>>;
+
+/// Provider configurations by name, e.g.
+///
+/// ```yaml
+/// cas:
+///   type: cas
+///   server: https://fed.princeton.edu/cas
+///   domain: princeton.edu
+/// ```
+pub type AuthConfig = BTreeMap<String, ProviderConfig>;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum ProviderConfig {
+    /// users log in as `<domain>/<user>`
+    Cas { server: String, domain: String },
+    /// OpenID Connect authorization code flow, users log in as `<domain>/<claim>`
+    Oidc {
+        authorize_url: String,
+        token_url: String,
+        userinfo_url: String,
+        client_id: String,
+        client_secret: String,
+        domain: String,
+        /// userinfo claim naming the user
+        #[serde(default = "default_oidc_claim")]
+        claim: String,
+    },
+    /// bearer tokens that speak for fixed principals, e.g. for service accounts
+    StaticTokens { tokens: BTreeMap<String, String> },
+    /// trusts the principal a reverse proxy names in `header`. Development only: anyone who
+    /// can reach webfront directly can claim any principal.
+    TrustHeader { header: String },
+}
+
+fn default_oidc_claim() -> String {
+    "sub".to_string()
+}
+
+/// Builds the providers in `config`, panicking on principals that don't parse
+pub fn providers(config: AuthConfig) -> Providers {
+    config
+        .into_iter()
+        .map(|(name, config)| {
+            let provider: Arc<dyn AuthProvider> = match config {
+                ProviderConfig::Cas { server, domain } => Arc::new(Cas { server, domain }),
+                ProviderConfig::Oidc {
+                    authorize_url,
+                    token_url,
+                    userinfo_url,
+                    client_id,
+                    client_secret,
+                    domain,
+                    claim,
+                } => Arc::new(Oidc {
+                    authorize_url,
+                    token_url,
+                    userinfo_url,
+                    client_id,
+                    client_secret,
+                    domain,
+                    claim,
+                }),
+                ProviderConfig::StaticTokens { tokens } => Arc::new(StaticTokens {
+                    tokens: tokens
+                        .into_iter()
+                        .map(|(token, p)| {
+                            let principal = parse_principal(&p)
+                                .unwrap_or_else(|| panic!("bad principal {} in {}", p, name));
+                            (token, principal)
+                        })
+                        .collect(),
+                }),
+                ProviderConfig::TrustHeader { header } => {
+                    warn!("{}: trusting the principal in {} of every request", name, header);
+                    Arc::new(TrustHeader { header })
+                }
+            };
+            (name, provider)
+        })
+        .collect()
+}
+
+fn parse_principal(principal: &str) -> Option<Component> {
+    Buckle::parse(format!("{},T", principal).as_str())
+        .ok()
+        .map(|b| b.secrecy)
+}
+
+// the principal `<domain>/<user>`, e.g. princeton.edu/alice
+fn domain_principal(domain: &str, user: &str) -> Component {
+    // FIXME buckle parser does not allow `@`. should we?
+    Component::formula([Clause::new_from_vec(vec![vec![domain, user]])])
+}
+
+fn http_client() -> Client {
+    Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+/// A fresh login `state`
+pub fn new_state() -> String {
+    let mut bytes = [0u8; 32];
+    openssl::rand::rand_bytes(&mut bytes).unwrap();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// the OIDC nonce of the login started with `state`, so that it needs no storage of its own
+fn nonce(state: &str) -> String {
+    let digest = openssl::sha::sha256(format!("nonce:{}", state).as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && openssl::memcmp::eq(a.as_bytes(), b.as_bytes())
+}
+
+// Whether the login page sent `state` back as `returned`
+fn state_matches(returned: Option<&str>, state: &str) -> bool {
+    returned.is_some_and(|returned| !state.is_empty() && same(returned, state))
+}
+
+// Fails unless the login page sent `state` back
+fn check_state(request: &Request, state: &str) -> Result<(), Response> {
+    if state_matches(request.get_param("state").as_deref(), state) {
+        Ok(())
+    } else {
+        Err(Response::json(&serde_json::json!({ "error": "Login state mismatch" }))
+            .with_status_code(400))
+    }
+}
+
+// The `nonce` claim of an ID token. The token comes straight from the token endpoint over TLS,
+// so its signature need not be checked (OpenID Connect Core 3.1.3.7).
+fn id_token_nonce(id_token: &str) -> Option<String> {
+    let payload = id_token.split('.').nth(1)?;
+    let mut payload: String = payload
+        .chars()
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+    while !payload.len().is_multiple_of(4) {
+        payload.push('=');
+    }
+    let payload = openssl::base64::decode_block(&payload).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    claims.get("nonce")?.as_str().map(String::from)
+}
+
+fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .header("Authorization")
+        .and_then(|header| header.split(' ').next_back())
+}
+
+pub struct Cas {
+    server: String,
+    domain: String,
+}
+
+// CAS has no state parameter, its tickets are single use and bound to the service URL
+impl AuthProvider for Cas {
+    fn login_url(&self, callback: &str, _state: &str) -> Option<String> {
+        Some(format!("{}/login?service={}", self.server, callback))
+    }
+
+    fn callback(
+        &self,
+        request: &Request,
+        callback: &str,
+        _state: &str,
+    ) -> Result<Component, Response> {
+        let ticket = request.get_param("ticket").ok_or(Response::empty_404())?;
+        let validate_cas = http_client()
+            .get(format!("{}/validate", self.server))
+            .query(&[("ticket", ticket.as_str()), ("service", callback)])
+            .send()
+            .map_err(|_| Response::empty_400())?;
+        let text = validate_cas.text().or(Err(Response::empty_400()))?;
+        let result: Vec<&str> = text.lines().collect();
+        match result.as_slice() {
+            ["yes", user] => Ok(domain_principal(&self.domain, user)),
+            _ => Err(Response::empty_400()),
+        }
+    }
+}
+
+/// GitHub OAuth app credentials for pairing GitHub accounts with logins
+#[derive(Clone)]
+pub struct GithubPairing {
+    client_id: String,
+    client_secret: String,
+}
+
+impl GithubPairing {
+    pub fn new(client_id: String, client_secret: String) -> Self {
+        GithubPairing {
+            client_id,
+            client_secret,
+        }
+    }
+
+    /// Where to send the browser to authorize the OAuth app. GitHub redirects to the callback
+    /// URL registered with the app.
+    pub fn login_url(&self, state: &str) -> String {
+        format!(
+            "https://github.com/login/oauth/authorize?client_id={}&scope=repo:invites&state={}",
+            self.client_id, state
+        )
+    }
+
+    /// The GitHub access token GitHub's redirect to the callback proves, for the authorization
+    /// started with `state`
+    pub fn access_token(&self, request: &Request, state: &str) -> Result<String, Response> {
+        check_state(request, state)?;
+        let code = request.get_param("code").ok_or(Response::empty_404())?;
+        #[derive(Debug, Deserialize)]
+        struct AuthResponse {
+            access_token: String,
+        }
+        let t: AuthResponse = http_client()
+            .post("https://github.com/login/oauth/access_token")
+            .header(reqwest::header::ACCEPT, "application/vnd.github.v3+json")
+            .header(reqwest::header::USER_AGENT, "SnapFaaS Web Frontend")
+            .multipart(
+                reqwest::blocking::multipart::Form::new()
+                    .text("client_id", self.client_id.clone())
+                    .text("client_secret", self.client_secret.clone())
+                    .text("code", code),
+            )
+            .send()
+            .and_then(|r| r.json())
+            .map_err(|_| Response::empty_400())?;
+        Ok(t.access_token)
+    }
+}
+
+/// The login of the GitHub user `token` belongs to
+pub fn github_login(token: &str) -> Result<String, Response> {
+    let github_user: github_types::User = http_client()
+        .get("https://api.github.com/user")
+        .header(reqwest::header::ACCEPT, "application/vnd.github.v3+json")
+        .header(reqwest::header::USER_AGENT, "SnapFaaS Web Frontend")
+        .header(reqwest::header::AUTHORIZATION, format!("Bearer {}", token))
+        .send()
+        .and_then(|r| r.json())
+        .map_err(|_| Response::empty_400())?;
+    Ok(github_user.login)
+}
+
+pub struct Oidc {
+    authorize_url: String,
+    token_url: String,
+    userinfo_url: String,
+    client_id: String,
+    client_secret: String,
+    domain: String,
+    claim: String,
+}
+
+impl AuthProvider for Oidc {
+    fn login_url(&self, callback: &str, state: &str) -> Option<String> {
+        let url = reqwest::Url::parse_with_params(
+            &self.authorize_url,
+            &[
+                ("response_type", "code"),
+                ("scope", "openid profile"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", callback),
+                ("state", state),
+                ("nonce", &nonce(state)),
+            ],
+        );
+        url.map(String::from).ok()
+    }
+
+    fn callback(
+        &self,
+        request: &Request,
+        callback: &str,
+        state: &str,
+    ) -> Result<Component, Response> {
+        check_state(request, state)?;
+        let code = request.get_param("code").ok_or(Response::empty_404())?;
+        let client = http_client();
+        #[derive(Debug, Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+            id_token: String,
+        }
+        let t: TokenResponse = client
+            .post(&self.token_url)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", &code),
+                ("redirect_uri", callback),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+            ])
+            .send()
+            .and_then(|r| r.json())
+            .map_err(|_| Response::empty_400())?;
+        // the ID token was issued for this login, not replayed from another
+        if !id_token_nonce(&t.id_token).is_some_and(|n| same(&n, &nonce(state))) {
+            return Err(Response::json(&serde_json::json!({ "error": "ID token nonce mismatch" }))
+                .with_status_code(400));
+        }
+        let userinfo: serde_json::Value = client
+            .get(&self.userinfo_url)
+            .bearer_auth(t.access_token)
+            .send()
+            .and_then(|r| r.json())
+            .map_err(|_| Response::empty_400())?;
+        let user = userinfo
+            .get(&self.claim)
+            .and_then(|v| v.as_str())
+            .ok_or(Response::empty_400())?;
+        Ok(domain_principal(&self.domain, user))
+    }
+}
+
+pub struct StaticTokens {
+    tokens: BTreeMap<String, Component>,
+}
+
+impl AuthProvider for StaticTokens {
+    fn authenticate(&self, request: &Request) -> Option<Component> {
+        bearer_token(request).and_then(|token| self.tokens.get(token).cloned())
+    }
+}
+
+pub struct TrustHeader {
+    header: String,
+}
+
+impl AuthProvider for TrustHeader {
+    fn authenticate(&self, request: &Request) -> Option<Component> {
+        request.header(&self.header).and_then(parse_principal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_matches() {
+        let state = new_state();
+        assert_ne!(state, new_state());
+        assert!(state_matches(Some(&state), &state));
+        assert!(!state_matches(Some(&new_state()), &state));
+        assert!(!state_matches(None, &state));
+        // without the cookie
+        assert!(!state_matches(Some(""), ""));
+    }
+
+    #[test]
+    fn test_id_token_nonce() {
+        let state = new_state();
+        let claims = serde_json::json!({ "sub": "alice", "nonce": nonce(&state) });
+        let payload = openssl::base64::encode_block(claims.to_string().as_bytes())
+            .trim_end_matches('=')
+            .replace('+', "-")
+            .replace('/', "_");
+        let id_token = format!("e30.{}.signature", payload);
+        assert_eq!(id_token_nonce(&id_token), Some(nonce(&state)));
+        assert_ne!(nonce(&state), nonce(&new_state()));
+        assert_eq!(id_token_nonce("e30"), None);
+    }
+}
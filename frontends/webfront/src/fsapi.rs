@@ -0,0 +1,930 @@
+//! REST endpoints for managing the file system, mirroring the capabilities `syscall_server`
+//! gives functions. Every handler runs with the logged-in user's privilege and a fresh public
+//! current label, and only returns data the user's privilege can declassify.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use labeled::buckle::{Buckle, Component};
+use openssl::pkey::{PKey, Private};
+use rouille::{Request, Response};
+use serde::Deserialize;
+use snapfaas::blobstore::Blobstore;
+use snapfaas::download::{DownloadError, DownloadToken};
+use snapfaas::fs::{
+    self, aliases::LabelAliases, path::Path, BackingStore, DirEntry, DirectGate, Function, Gate,
+    HttpVerb, Metadata, Provenance, RedirectGate, ResultRetention, Service, FS,
+};
+
+fn error_response(error: String, status_code: u16) -> Response {
+    Response::json(&serde_json::json!({ "error": error })).with_status_code(status_code)
+}
+
+fn fs_error(e: fs::FsError) -> Response {
+    let status_code = fs_error_status(&e);
+    error_response(format!("{:?}", e), status_code)
+}
+
+fn fs_error_status(e: &fs::FsError) -> u16 {
+    match e {
+        // worth retrying later, unlike the others
+        fs::FsError::Contended => 503,
+        fs::FsError::TooLarge => 413,
+        _ => 400,
+    }
+}
+
+fn parse_path(path: &str) -> Result<Path, Response> {
+    let path = percent_encoding::percent_decode_str(path).decode_utf8_lossy().to_string();
+    Path::parse(&path).map_err(|_| error_response("Invalid path.".to_string(), 400))
+}
+
+// split a path into its parent directory and the name of the last component
+fn split_path(path: &str) -> Result<(Path, String), Response> {
+    let path = parse_path(path)?;
+    path.parent()
+        .zip(path.file_name())
+        .ok_or(error_response("Invalid path.".to_string(), 400))
+}
+
+fn parse_component(component: &str) -> Result<Component, Response> {
+    Buckle::parse(format!("{},T", component).as_str())
+        .map(|b| b.secrecy)
+        .map_err(|e| error_response(e.to_string(), 400))
+}
+
+fn parse_label(aliases: &LabelAliases, label: &str) -> Result<Buckle, Response> {
+    aliases
+        .resolve(label)
+        .map_err(|e| error_response(e.to_string(), 400))
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(request: &Request) -> Result<T, Response> {
+    let mut request_body = request.data().ok_or(Response::empty_400())?;
+    serde_json::from_reader(&mut request_body).map_err(|e| error_response(e.to_string(), 400))
+}
+
+/// Sets up the thread's label state for a request made by `login`
+pub fn enter(login: Component) {
+    fs::utils::clear_label();
+    fs::utils::set_my_privilge(login);
+}
+
+/// Stores the request body as a blob with the `label` query parameter, public by default, and
+/// links it at `path`
+pub fn upload_blob<S: BackingStore>(
+    fs: &FS<S>,
+    blobstore: &Mutex<Blobstore>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let label = match request.get_param("label") {
+        Some(label) => parse_label(&LabelAliases::load(fs), &label)?,
+        None => Buckle::public(),
+    };
+    let (base_dir, name) = split_path(path)?;
+    let mut data = request.data().ok_or(Response::empty_400())?;
+    // the body streams into a temporary file without holding the blobstore
+    let mut newblob = blobstore
+        .lock()
+        .unwrap()
+        .create()
+        .map_err(|e| error_response(e.to_string(), 500))?;
+    let size = match std::io::copy(&mut data, &mut newblob) {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = blobstore.lock().unwrap().abort(newblob);
+            return Err(error_response(e.to_string(), 400));
+        }
+    };
+    let (blob, created) = blobstore
+        .lock()
+        .unwrap()
+        .save_new(newblob)
+        .map_err(|e| error_response(e.to_string(), 500))?;
+    let meta = fs::BlobMeta::new(size, request.header("Content-Type").map(String::from));
+    let linked = fs
+        .create_blob(label, blob.name.clone(), meta)
+        .and_then(|entry| fs.link(base_dir, name, entry));
+    if let Err(e) = linked {
+        // nothing refers to a blob this upload created
+        if created {
+            let _ = blobstore.lock().unwrap().delete(&blob.name);
+        }
+        return Err(fs_error(e));
+    }
+    Ok(Response::empty_204())
+}
+
+// Data read during the request may only be returned if the user's privilege can declassify it.
+fn check_readable() -> Result<(), Response> {
+    let current = fs::utils::get_current_label();
+    if fs::utils::get_privilege().implies(&current.secrecy) {
+        Ok(())
+    } else {
+        Err(Response::json(&serde_json::json!({
+            "error": "unauthorized to read response",
+            "current_label": format!("{:?}", current),
+        }))
+        .with_status_code(401))
+    }
+}
+
+fn kind(entry: &DirEntry) -> &'static str {
+    match entry {
+        DirEntry::Directory(_) => "directory",
+        DirEntry::File(_) => "file",
+        DirEntry::FacetedDirectory(_) => "faceted_directory",
+        DirEntry::Gate(_) => "gate",
+        DirEntry::Service(_) => "service",
+        DirEntry::Blob(_) => "blob",
+        DirEntry::Log(_) => "log",
+    }
+}
+
+/// Maps entry names to their kinds. With `long`, maps them to objects with the kind and, for
+/// gates and services, their description, tags and example payload.
+pub fn list<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    #[derive(serde::Serialize)]
+    struct Long {
+        kind: &'static str,
+        #[serde(flatten)]
+        metadata: Metadata,
+    }
+    let entries = fs.list_dir(parse_path(path)?).map_err(fs_error)?;
+    if request.get_param("long").is_some() {
+        let entries: BTreeMap<String, Long> = entries
+            .iter()
+            .map(|(name, entry)| {
+                let metadata = entry.metadata(fs);
+                (name.clone(), Long { kind: kind(entry), metadata })
+            })
+            .collect();
+        check_readable()?;
+        return Ok(Response::json(&entries));
+    }
+    let entries: BTreeMap<String, &str> = entries
+        .iter()
+        .map(|(name, entry)| (name.clone(), kind(entry)))
+        .collect();
+    check_readable()?;
+    Ok(Response::json(&entries))
+}
+
+/// Lists the facets up to `clearance`. With `secrecy` or `integrity`, lists only the facets
+/// whose labels mention that principal.
+pub fn list_faceted<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let aliases = LabelAliases::load(fs);
+    let clearance = match request.get_param("clearance") {
+        Some(clearance) => parse_label(&aliases, &clearance)?,
+        None => Buckle::public(),
+    };
+    let principal = |param: &str| match request.get_param(param) {
+        Some(p) => fs::FacetPattern::parse_principal(&p)
+            .map(Some)
+            .ok_or_else(|| error_response(format!("bad principal `{}`", p), 400)),
+        None => Ok(None),
+    };
+    let pattern = fs::FacetPattern {
+        secrecy: principal("secrecy")?,
+        integrity: principal("integrity")?,
+    };
+    let facets: Vec<String> = fs
+        .query_faceted(parse_path(path)?, &clearance, &pattern)
+        .map_err(fs_error)?
+        .keys()
+        .map(|label| aliases.display(label))
+        .collect();
+    check_readable()?;
+    Ok(Response::json(&facets))
+}
+
+// bounds on the work one search does. There is no index of names yet, so a search walks the
+// directories it can read.
+const SEARCH_MAX_DEPTH: usize = 16;
+const SEARCH_MAX_RESULTS: usize = 1000;
+
+#[derive(serde::Serialize)]
+struct Found {
+    path: String,
+    kind: &'static str,
+}
+
+#[derive(serde::Serialize, Default)]
+struct Search {
+    results: Vec<Found>,
+    /// whether the search hit `SEARCH_MAX_DEPTH` or `SEARCH_MAX_RESULTS`, so that matches may
+    /// be missing
+    truncated: bool,
+}
+
+// Walks `dir` for names containing `q`, returning false once the results are full
+fn walk<S: BackingStore>(
+    fs: &FS<S>,
+    dir: fs::ObjectRef<fs::Labeled<fs::Directory>>,
+    path: String,
+    depth: usize,
+    clearance: &Buckle,
+    q: &str,
+    search: &mut Search,
+) -> bool {
+    use labeled::Label;
+    for (name, entry) in dir.list(fs) {
+        let entry_path = format!("{}:{}", path, name);
+        if name.contains(q) {
+            if search.results.len() == SEARCH_MAX_RESULTS {
+                search.truncated = true;
+                return false;
+            }
+            search.results.push(Found {
+                path: entry_path.clone(),
+                kind: kind(&entry),
+            });
+        }
+        if let DirEntry::Directory(sub) = entry {
+            let within = sub
+                .get(fs)
+                .is_some_and(|sub| sub.label().can_flow_to(clearance));
+            if !within {
+                continue;
+            }
+            if depth == SEARCH_MAX_DEPTH {
+                search.truncated = true;
+            } else if !walk(fs, sub, entry_path, depth + 1, clearance, q, search) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// Finds the entries whose names contain `q` in the home facets up to `clearance`
+fn search<S: BackingStore>(
+    fs: &FS<S>,
+    clearance: &Buckle,
+    q: &str,
+) -> Result<Search, fs::FsError> {
+    let facets = fs.list_faceted(Path::parse("home").unwrap(), clearance)?;
+    let mut search = Search::default();
+    for (facet, dir) in facets {
+        let path = format!("home:<{},{}>", facet.secrecy, facet.integrity);
+        if !walk(fs, dir, path, 0, clearance, q, &mut search) {
+            break;
+        }
+    }
+    Ok(search)
+}
+
+/// Finds the entries whose names contain `q` in the home facets up to `clearance`. Only descends
+/// into directories whose label is within the clearance, so the search returns whatever the
+/// clearance reaches rather than failing on one overly secret directory. Stops
+/// `SEARCH_MAX_DEPTH` directories deep and after `SEARCH_MAX_RESULTS` matches, and says so with
+/// `truncated`.
+pub fn search_home<S: BackingStore>(fs: &FS<S>, request: &Request) -> Result<Response, Response> {
+    let aliases = LabelAliases::load(fs);
+    let clearance = match request.get_param("clearance") {
+        Some(clearance) => parse_label(&aliases, &clearance)?,
+        None => Buckle::public(),
+    };
+    let q = request.get_param("q").unwrap_or_default();
+    let found = search(fs, &clearance, &q).map_err(fs_error)?;
+    check_readable()?;
+    Ok(Response::json(&found))
+}
+
+/// Returns the file's content with its hash as the ETag, or 304 if the hash matches the
+/// `If-None-Match` header
+pub fn read<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let file = match fs.read_path(parse_path(path)?).map_err(fs_error)? {
+        DirEntry::File(file) => file,
+        _ => return Err(fs_error(fs::FsError::NotAFile)),
+    };
+    // hash first, so the data returned is never older than its tag
+    let etag = format!("\"{}\"", file.etag(fs));
+    check_readable()?;
+    let unchanged = request.header("If-None-Match").is_some_and(|tags| {
+        tags.trim() == "*" || tags.split(',').any(|tag| tag.trim().trim_start_matches("W/") == etag)
+    });
+    let response = if unchanged {
+        Response::text("").with_status_code(304)
+    } else {
+        Response::from_data("application/octet-stream", file.read(fs))
+    };
+    Ok(response
+        .with_unique_header("ETag", etag)
+        .with_additional_header("Access-Control-Expose-Headers", "ETag"))
+}
+
+/// Mints a link that lets anyone download the file or blob at `path` for `ttl` seconds, an hour
+/// by default
+pub fn download_url<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+    key: &PKey<Private>,
+    base_url: &str,
+) -> Result<Response, Response> {
+    let ttl = match request.get_param("ttl") {
+        Some(ttl) => ttl
+            .parse()
+            .map_err(|_| error_response("Invalid ttl.".to_string(), 400))?,
+        None => 60 * 60,
+    };
+    let entry = fs.read_path(parse_path(path)?).map_err(fs_error)?;
+    let token = DownloadToken::mint(fs, entry, ttl).map_err(download_error)?;
+    Ok(Response::json(&serde_json::json!({
+        "url": format!("{}/faasten/download/{}", base_url, token.sign(key)),
+        "expires": token.exp,
+    })))
+}
+
+pub fn download_error(e: DownloadError) -> Response {
+    let status_code = match e {
+        DownloadError::BadSignature => 403,
+        DownloadError::Expired => 410,
+        DownloadError::NotDownloadable => 400,
+        DownloadError::Unauthorized => 401,
+    };
+    error_response(format!("{:?}", e), status_code)
+}
+
+/// Serves the file or blob a verified download token names
+pub fn download<S: BackingStore>(
+    fs: &FS<S>,
+    blobstore: &Mutex<Blobstore>,
+    token: DownloadToken,
+) -> Result<Response, Response> {
+    if !token.permits(fs) {
+        return Err(error_response("No longer downloadable.".to_string(), 410));
+    }
+    match token.entry {
+        DirEntry::File(file) => Ok(Response::from_data("application/octet-stream", file.read(fs))),
+        DirEntry::Blob(entry) => {
+            let blob = blobstore
+                .lock()
+                .unwrap()
+                .open(entry.read(fs))
+                .map_err(|e| error_response(e.to_string(), 500))?;
+            let meta = entry.meta(fs);
+            let len = match &meta {
+                Some(meta) => meta.size,
+                None => blob.len().map_err(|e| error_response(e.to_string(), 500))?,
+            };
+            let content_type = meta
+                .and_then(|m| m.content_type)
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            let mut response = Response::from_data(content_type, vec![]);
+            response.data = rouille::ResponseBody::from_reader_and_size(blob, len as usize);
+            Ok(response)
+        }
+        _ => Err(download_error(DownloadError::NotDownloadable)),
+    }
+}
+
+pub fn write<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    use std::io::Read;
+    let mut data = Vec::new();
+    request
+        .data()
+        .ok_or(Response::empty_400())?
+        .read_to_end(&mut data)
+        .map_err(|e| error_response(e.to_string(), 400))?;
+    fs.write_file(parse_path(path)?, data).map_err(fs_error)?;
+    Ok(Response::empty_204())
+}
+
+/// Returns the log entries with indices in `from..to`, from the start and through the end of the
+/// log by default
+pub fn read_log<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let index = |name: &str, default: u64| match request.get_param(name) {
+        Some(index) => index
+            .parse()
+            .map_err(|_| error_response(format!("Invalid {}.", name), 400)),
+        None => Ok(default),
+    };
+    let entries = fs
+        .read_log(parse_path(path)?, index("from", 0)?, index("to", u64::MAX)?)
+        .map_err(fs_error)?;
+    check_readable()?;
+    Ok(Response::json(&entries))
+}
+
+/// Appends the request body to the log at `path`
+pub fn append_log<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    use std::io::Read;
+    let mut data = Vec::new();
+    request
+        .data()
+        .ok_or(Response::empty_400())?
+        .read_to_end(&mut data)
+        .map_err(|e| error_response(e.to_string(), 400))?;
+    let index = fs.append_log(parse_path(path)?, data).map_err(fs_error)?;
+    Ok(Response::json(&serde_json::json!({ "index": index })))
+}
+
+#[derive(Deserialize)]
+struct Labeled {
+    label: String,
+}
+
+pub fn create_file<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let body: Labeled = parse_body(request)?;
+    let label = parse_label(&LabelAliases::load(fs), &body.label)?;
+    let (base_dir, name) = split_path(path)?;
+    let entry = fs.create_file(label);
+    fs.link(base_dir, name, entry).map_err(fs_error)?;
+    Ok(Response::empty_204())
+}
+
+pub fn create_log<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let body: Labeled = parse_body(request)?;
+    let label = parse_label(&LabelAliases::load(fs), &body.label)?;
+    let (base_dir, name) = split_path(path)?;
+    let entry = fs.create_log(label);
+    fs.link(base_dir, name, entry).map_err(fs_error)?;
+    Ok(Response::empty_204())
+}
+
+pub fn create_dir<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let body: Labeled = parse_body(request)?;
+    let label = parse_label(&LabelAliases::load(fs), &body.label)?;
+    let (base_dir, name) = split_path(path)?;
+    let entry = fs.create_directory(label);
+    fs.link(base_dir, name, entry).map_err(fs_error)?;
+    Ok(Response::empty_204())
+}
+
+pub fn create_faceted_dir<S: BackingStore>(fs: &FS<S>, path: &str) -> Result<Response, Response> {
+    let (base_dir, name) = split_path(path)?;
+    fs::utils::create_faceted(fs, base_dir, name).map_err(fs_error)?;
+    Ok(Response::empty_204())
+}
+
+/// Links the object at `target` under `path`
+pub fn link<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    #[derive(Deserialize)]
+    struct Link {
+        target: String,
+    }
+    let body: Link = parse_body(request)?;
+    let target = fs.read_path(parse_path(&body.target)?).map_err(fs_error)?;
+    let (base_dir, name) = split_path(path)?;
+    fs.link(base_dir, name, target).map_err(fs_error)?;
+    Ok(Response::empty_204())
+}
+
+// unlinks at most this many objects per recursive delete request
+const REMOVE_BUDGET: usize = 1000;
+
+/// Links an immutable snapshot of the object at `target` under `path`
+pub fn snapshot<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    #[derive(Deserialize)]
+    struct Snapshot {
+        target: String,
+    }
+    let body: Snapshot = parse_body(request)?;
+    let snapshot = fs.snapshot(parse_path(&body.target)?).map_err(fs_error)?;
+    let (base_dir, name) = split_path(path)?;
+    fs.link(base_dir, name, snapshot).map_err(fs_error)?;
+    Ok(Response::empty_204())
+}
+
+/// Unlinks the object at `path`, or moves it to the facet's trash directory if the request has
+/// a `trash` parameter, the number of seconds it stays restorable. With a `recursive`
+/// parameter, also unlinks everything under the object a batch at a time: a response with a
+/// cursor asks for another request with that `cursor` parameter. Responses tell how many
+/// objects they unlinked, errors included, so a failed request may have removed some.
+pub fn unlink<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    if request.get_param("recursive").is_some() {
+        let cursor: Option<Vec<String>> = match request.get_param("cursor") {
+            Some(cursor) => Some(
+                serde_json::from_str(&cursor)
+                    .map_err(|_| error_response("Invalid cursor.".to_string(), 400))?,
+            ),
+            None => None,
+        };
+        let removal = fs
+            .remove_recursive(parse_path(path)?, cursor.as_deref(), REMOVE_BUDGET)
+            .map_err(fs_error)?;
+        let mut body = serde_json::json!({
+            "cursor": removal.cursor,
+            "unlinked": removal.unlinked,
+        });
+        return match removal.error {
+            None => Ok(Response::json(&body)),
+            Some(e) => {
+                body["error"] = format!("{:?}", e).into();
+                Err(Response::json(&body).with_status_code(fs_error_status(&e)))
+            }
+        };
+    }
+    let (base_dir, name) = split_path(path)?;
+    let removed = match request.get_param("trash") {
+        Some(retention) => {
+            let retention = retention
+                .parse()
+                .map_err(|_| error_response("Invalid retention.".to_string(), 400))?;
+            fs.trash(base_dir, &name, retention)
+        }
+        None => fs.rm(base_dir, &name),
+    };
+    if removed.map_err(fs_error)? {
+        Ok(Response::empty_204())
+    } else {
+        Ok(Response::empty_404())
+    }
+}
+
+/// Links the trashed entry named in the body back into the directory at `path`
+pub fn restore<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    #[derive(Deserialize)]
+    struct Restore {
+        trashed: String,
+    }
+    let body: Restore = parse_body(request)?;
+    if fs.restore(parse_path(path)?, &body.trashed).map_err(fs_error)? {
+        Ok(Response::empty_204())
+    } else {
+        Ok(Response::empty_404())
+    }
+}
+
+#[derive(Deserialize)]
+struct FunctionSpec {
+    memory: usize,
+    /// Faasten path of the app image blob
+    app_image: String,
+    /// Faasten path of the runtime image blob
+    runtime: String,
+    /// Faasten path of the kernel blob, the default kernel if absent
+    kernel: Option<String>,
+    /// classes of passthrough devices the function needs, one entry per device
+    #[serde(default)]
+    devices: Vec<String>,
+    /// keep the function's VMs up between invocations
+    #[serde(default)]
+    keep_alive: bool,
+    /// idle VMs of the function to keep warm
+    #[serde(default)]
+    min_instances: usize,
+    /// boot another VM for invocations waiting on busy VMs of the function
+    #[serde(default)]
+    speculative_boot: bool,
+    /// the function may run again after being preempted
+    #[serde(default)]
+    idempotent: bool,
+    /// seconds idle VMs of the function live, the worker's default if absent
+    #[serde(default)]
+    idle_ttl: Option<u64>,
+    /// attributes a worker must advertise with these values to run the function
+    #[serde(default)]
+    requirements: std::collections::BTreeMap<String, String>,
+    /// invocation headers passed to the function, `*` for all
+    #[serde(default)]
+    forward_headers: Vec<String>,
+    /// invocations a worker node is expected to run at once, for autoscaling
+    #[serde(default)]
+    concurrency_target: usize,
+    /// MiB of scratch space each invocation gets
+    #[serde(default)]
+    scratch: usize,
+    /// Faasten paths of layer blobs overlaid on the app image, the last on top
+    #[serde(default)]
+    app_layers: Vec<String>,
+    /// where the app image came from, for supply-chain audits
+    provenance: Option<ProvenanceSpec>,
+    /// where results of async invocations are stored and for how long
+    results: Option<ResultRetention>,
+}
+
+#[derive(Deserialize)]
+struct ProvenanceSpec {
+    source_repo: Option<String>,
+    commit: Option<String>,
+    builder: Option<String>,
+    /// Faasten path of a blob holding a software bill of materials of the app image
+    sbom: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GateSpec {
+    label: Option<String>,
+    privilege: String,
+    invoker_integrity_clearance: String,
+    declassify: Option<String>,
+    /// creates a direct gate
+    function: Option<FunctionSpec>,
+    /// creates a redirect gate to the gate at this Faasten path
+    gate: Option<String>,
+    /// description, tags and example payload of a direct gate
+    metadata: Option<Metadata>,
+}
+
+fn open_blob<S: BackingStore>(fs: &FS<S>, path: &str) -> Result<String, Response> {
+    fs.open_blob(parse_path(path)?).map_err(fs_error)
+}
+
+fn build_gate<S: BackingStore>(fs: &FS<S>, spec: &GateSpec) -> Result<Gate, Response> {
+    let privilege = parse_component(&spec.privilege)?;
+    let invoker_integrity_clearance = parse_component(&spec.invoker_integrity_clearance)?;
+    let declassify = match &spec.declassify {
+        Some(d) => parse_component(d)?,
+        None => Component::dc_true(),
+    };
+    match (&spec.function, &spec.gate) {
+        (Some(f), None) => Ok(Gate::Direct(Box::new(DirectGate {
+            privilege,
+            invoker_integrity_clearance,
+            declassify,
+            function: Function {
+                memory: f.memory,
+                app_image: open_blob(fs, &f.app_image)?,
+                runtime_image: open_blob(fs, &f.runtime)?,
+                kernel: match &f.kernel {
+                    Some(kernel) => open_blob(fs, kernel)?,
+                    None => fs::bootstrap::get_kernel_blob(fs, None).map_err(fs_error)?,
+                },
+                devices: f.devices.clone(),
+                keep_alive: f.keep_alive,
+                min_instances: f.min_instances,
+                speculative_boot: f.speculative_boot,
+                idempotent: f.idempotent,
+                idle_ttl: f.idle_ttl,
+                requirements: f.requirements.clone(),
+                forward_headers: f.forward_headers.clone(),
+                concurrency_target: f.concurrency_target,
+                scratch: f.scratch,
+                app_layers: f
+                    .app_layers
+                    .iter()
+                    .map(|layer| open_blob(fs, layer))
+                    .collect::<Result<_, _>>()?,
+                provenance: match &f.provenance {
+                    Some(p) => Some(Box::new(Provenance {
+                        source_repo: p.source_repo.clone(),
+                        commit: p.commit.clone(),
+                        builder: p.builder.clone(),
+                        sbom: p.sbom.as_ref().map(|sbom| open_blob(fs, sbom)).transpose()?,
+                    })),
+                    None => None,
+                },
+                results: match &f.results {
+                    Some(r) => {
+                        Path::parse(&r.dir).map_err(|_| {
+                            error_response("Invalid results directory.".to_string(), 400)
+                        })?;
+                        Some(Box::new(r.clone()))
+                    }
+                    None => None,
+                },
+            },
+            metadata: spec.metadata.clone().map(Box::new),
+        }))),
+        (None, Some(target)) => match fs.read_path(parse_path(target)?).map_err(fs_error)? {
+            DirEntry::Gate(gate) => Ok(Gate::Redirect(RedirectGate {
+                privilege,
+                invoker_integrity_clearance,
+                declassify,
+                gate,
+            })),
+            _ => Err(fs_error(fs::FsError::NotAGate)),
+        },
+        _ => Err(error_response(
+            "Exactly one of `function` and `gate` is required".to_string(),
+            400,
+        )),
+    }
+}
+
+pub fn create_gate<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let spec: GateSpec = parse_body(request)?;
+    let label = match &spec.label {
+        Some(l) => parse_label(&LabelAliases::load(fs), l)?,
+        None => Buckle::public(),
+    };
+    let entry = match build_gate(fs, &spec)? {
+        Gate::Direct(dg) => fs.create_direct_gate(label, *dg),
+        Gate::Redirect(rg) => fs.create_redirect_gate(label, rg),
+    }
+    .map_err(fs_error)?;
+    let (base_dir, name) = split_path(path)?;
+    fs.link(base_dir, name, entry).map_err(fs_error)?;
+    Ok(Response::empty_204())
+}
+
+pub fn update_gate<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let spec: GateSpec = parse_body(request)?;
+    match fs.read_path(parse_path(path)?).map_err(fs_error)? {
+        DirEntry::Gate(gate) => {
+            gate.replace(build_gate(fs, &spec)?, fs).map_err(fs_error)?;
+            Ok(Response::empty_204())
+        }
+        _ => Err(fs_error(fs::FsError::NotAGate)),
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceSpec {
+    label: Option<String>,
+    privilege: String,
+    invoker_integrity_clearance: String,
+    taint: String,
+    url: String,
+    verb: HttpVerb,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    metadata: Option<Metadata>,
+}
+
+fn build_service(aliases: &LabelAliases, spec: ServiceSpec) -> Result<Service, Response> {
+    Ok(Service {
+        privilege: parse_component(&spec.privilege)?,
+        invoker_integrity_clearance: parse_component(&spec.invoker_integrity_clearance)?,
+        taint: parse_label(aliases, &spec.taint)?,
+        url: spec.url,
+        verb: spec.verb,
+        headers: spec.headers,
+        metadata: spec.metadata.map(Box::new),
+    })
+}
+
+pub fn create_service<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let mut spec: ServiceSpec = parse_body(request)?;
+    let aliases = LabelAliases::load(fs);
+    let label = match spec.label.take() {
+        Some(l) => parse_label(&aliases, &l)?,
+        None => Buckle::public(),
+    };
+    let entry = fs
+        .create_service(label, build_service(&aliases, spec)?)
+        .map_err(fs_error)?;
+    let (base_dir, name) = split_path(path)?;
+    fs.link(base_dir, name, entry).map_err(fs_error)?;
+    Ok(Response::empty_204())
+}
+
+pub fn update_service<S: BackingStore>(
+    fs: &FS<S>,
+    path: &str,
+    request: &Request,
+) -> Result<Response, Response> {
+    let spec: ServiceSpec = parse_body(request)?;
+    let aliases = LabelAliases::load(fs);
+    match fs.read_path(parse_path(path)?).map_err(fs_error)? {
+        DirEntry::Service(service) => {
+            service
+                .replace(build_service(&aliases, spec)?, fs)
+                .map_err(fs_error)?;
+            Ok(Response::empty_204())
+        }
+        _ => Err(fs_error(fs::FsError::NotAService)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs `test` on a file system with an empty `home`, in a fresh LMDB environment
+    fn with_home(name: &str, test: impl FnOnce(&FS<&lmdb::Environment>)) {
+        let dir = std::env::temp_dir().join(format!("webfront-{}-{}", name, std::process::id()));
+        let dbenv = fs::lmdb::get_dbenv(dir.to_str().unwrap());
+        let fs = FS::new(&dbenv);
+        fs.initialize();
+        fs::utils::clear_label();
+        fs::utils::set_my_privilge(Component::dc_false());
+        fs::utils::create_faceted(&fs, Path::root(), "home".to_string()).unwrap();
+        test(&fs);
+        drop(fs);
+        drop(dbenv);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn public_home() -> Path {
+        Path::parse("home:<T,T>").unwrap()
+    }
+
+    #[test]
+    fn test_search() {
+        with_home("search", |fs| {
+            let secret = Buckle::parse("alice,T").unwrap();
+            fs.link(public_home(), "notes".to_string(), fs.create_directory(Buckle::public()))
+                .unwrap();
+            fs.link(public_home(), "secret".to_string(), fs.create_directory(secret))
+                .unwrap();
+            let notes = Path::parse("home:<T,T>:notes").unwrap();
+            fs.link(notes, "todo".to_string(), fs.create_file(Buckle::public()))
+                .unwrap();
+            let secret = Path::parse("home:<T,T>:secret").unwrap();
+            fs.link(secret, "todo".to_string(), fs.create_file(Buckle::public()))
+                .unwrap();
+
+            let found = search(fs, &Buckle::public(), "todo").unwrap();
+            assert_eq!(found.results.len(), 1);
+            assert!(found.results[0].path.ends_with(":notes:todo"));
+            assert_eq!(found.results[0].kind, "file");
+            assert!(!found.truncated);
+
+            let clearance = Buckle::parse("alice,T").unwrap();
+            let found = search(fs, &clearance, "todo").unwrap();
+            assert_eq!(found.results.len(), 2);
+            assert!(!found.truncated);
+        });
+    }
+
+    #[test]
+    fn test_search_truncated() {
+        with_home("search-truncated", |fs| {
+            // directories d0 to d17, each in the one before
+            let mut dir = public_home();
+            for depth in 0..SEARCH_MAX_DEPTH + 2 {
+                let name = format!("d{}", depth);
+                fs.link(dir.clone(), name.clone(), fs.create_directory(Buckle::public()))
+                    .unwrap();
+                dir.push_dscrp(name);
+            }
+            let found = search(fs, &Buckle::public(), "d").unwrap();
+            assert_eq!(found.results.len(), SEARCH_MAX_DEPTH + 1);
+            assert!(found.truncated);
+
+            for i in 0..SEARCH_MAX_RESULTS {
+                let name = format!("f{}", i);
+                fs.link(public_home(), name, fs.create_file(Buckle::public()))
+                    .unwrap();
+            }
+            let found = search(fs, &Buckle::public(), "f").unwrap();
+            assert_eq!(found.results.len(), SEARCH_MAX_RESULTS);
+            assert!(!found.truncated);
+
+            fs.link(public_home(), "f-last".to_string(), fs.create_file(Buckle::public()))
+                .unwrap();
+            let found = search(fs, &Buckle::public(), "f").unwrap();
+            assert_eq!(found.results.len(), SEARCH_MAX_RESULTS);
+            assert!(found.truncated);
+        });
+    }
+}
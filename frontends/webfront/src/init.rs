@@ -5,30 +5,49 @@
 
 use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
 
 use labeled::buckle::{Buckle, Component};
 use labeled::{buckle, HasPrivilege};
 use log::{debug, error};
 use rouille::{input::post::BufferedFile, Request, Response};
-use snapfaas::blobstore;
+use snapfaas::{blobstore, envelope};
 use snapfaas::fs::BackingStore;
 use snapfaas::{
     blobstore::Blobstore,
     fs::{self, FS},
-    sched::{self, message::LabeledInvoke},
+    sched::{
+        self,
+        message::{LabeledInvoke, ReturnCode},
+    },
 };
 
+/// How the webfront turns HTTP requests into invocations
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// payloads larger than this many bytes pass through the scheduler as blobs
+    pub max_inline: usize,
+    /// HTTP headers passed on to functions, all but `Authorization` if `None`
+    pub forward_headers: Option<Vec<String>>,
+    /// seals payloads for their gates with this secret shared with workers, see `envelope`
+    pub payload_secret: Option<Vec<u8>>,
+    /// highest priority logged in users may ask for with `x-faasten-priority`
+    pub max_priority: u32,
+}
+
 pub fn init<S: BackingStore>(
     login: Option<Component>,
     gate_path: String,
     request: &Request,
-    sched_conn: &mut TcpStream,
+    sched_conn: &mut sched::rpc::Client,
     fs: &FS<S>,
     blobstore: Arc<Mutex<Blobstore>>,
+    options: &Options,
 ) -> Result<Response, Response> {
-    let (payload, blob, label, headers) = prepare_payload(request, blobstore)?;
+    let aliases = fs::aliases::LabelAliases::load(fs);
+    let (payload, blob, label, headers) =
+        prepare_payload(request, blobstore.clone(), &aliases, options)?;
+    let authenticated = login.is_some();
     let privilege = login.unwrap_or(Component::dc_true());
 
     {
@@ -39,13 +58,81 @@ pub fn init<S: BackingStore>(
         }
     }
 
-    let req = prepare_labeled_invoke(gate_path, blob, payload, headers, fs)?;
-    wait_for_completion(req, sched_conn)
+    let mut req = prepare_labeled_invoke(gate_path, blob, payload, headers, fs)?;
+    // requests without a valid `x-faasten-priority` or a login get the lowest priority
+    let requested = request
+        .header("x-faasten-priority")
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(0);
+    req.priority =
+        sched::resolver::invoker_priority(requested, authenticated, options.max_priority);
+    // a result too tainted to read comes back with the syscalls that tainted it, as far as the
+    // invoker may see them
+    req.trace_taint = request.header("x-faasten-trace-taint").is_some();
+    // batch work can let the scheduler stop it and run it again later, if its gate is idempotent
+    req.preemptible = request.header("x-faasten-preemptible").is_some();
+    if let Some(secret) = options.payload_secret.as_ref() {
+        let gate: Component = req.gate_privilege.clone().unwrap().into();
+        let key = envelope::gate_key(secret, &gate);
+        let internal = |e: envelope::EnvelopeError| {
+            Response::json(&serde_json::json!({"error": format!("{:?}", e)})).with_status_code(500)
+        };
+        // the payload only opens for this invocation, under this label
+        req.seal_id = envelope::new_seal_id().map_err(internal)?;
+        let binding = envelope::Binding {
+            label: req.label.as_ref().unwrap(),
+            seal_id: &req.seal_id,
+        };
+        req.payload = envelope::seal(&key, &binding, &req.payload).map_err(internal)?;
+        req.sealed = true;
+    }
+    // the response body comes back sealed to the invoker's key, see `envelope::open_with`
+    if let Some(key) = request.header("x-faasten-reply-key") {
+        req.reply_key = openssl::base64::decode_block(key)
+            .ok()
+            .filter(|der| openssl::pkey::PKey::public_key_from_der(der).is_ok())
+            .ok_or_else(|| {
+                Response::json(&serde_json::json!({
+                    "error": "x-faasten-reply-key must be a base64 DER encoded P-256 public key"
+                }))
+                .with_status_code(400)
+            })?;
+    }
+    // the scheduler POSTs the result there instead of the client waiting for it, if the URL is on
+    // one of its callback hosts
+    if let Some(url) = request.header("x-faasten-callback") {
+        if !authenticated {
+            return Err(Response::json(&serde_json::json!({
+                "error": "x-faasten-callback requires a login"
+            }))
+            .with_status_code(401));
+        }
+        let valid = reqwest::Url::parse(url)
+            .is_ok_and(|url| url.scheme() == "http" || url.scheme() == "https");
+        if !valid {
+            return Err(Response::json(&serde_json::json!({
+                "error": "x-faasten-callback must be an http or https URL"
+            }))
+            .with_status_code(400));
+        }
+        req.sync = false;
+        req.callback = url.to_string();
+    }
+    req.spill_payload(&mut blobstore.lock().unwrap(), options.max_inline)
+        .map_err(|e| {
+            Response::json(&serde_json::json!({"error": e.to_string()})).with_status_code(500)
+        })?;
+    if !req.sync {
+        return submit_with_callback(req, sched_conn);
+    }
+    wait_for_completion(req, sched_conn, &blobstore)
 }
 
 fn prepare_payload(
     request: &Request,
     blobstore: Arc<Mutex<Blobstore>>,
+    aliases: &fs::aliases::LabelAliases,
+    options: &Options,
 ) -> Result<
     (
         Vec<u8>,
@@ -72,10 +159,14 @@ fn prepare_payload(
         let headers: HashMap<String, String> = request
             .headers()
             .filter_map(|(k, v)| {
-                if k.eq_ignore_ascii_case("authorization") {
-                    None
-                } else {
+                let forward = match options.forward_headers.as_ref() {
+                    Some(names) => names.iter().any(|n| n.eq_ignore_ascii_case(k)),
+                    None => !k.eq_ignore_ascii_case("authorization"),
+                };
+                if forward {
                     Some((k.to_ascii_lowercase(), v.to_string()))
+                } else {
+                    None
                 }
             })
             .collect();
@@ -90,7 +181,7 @@ fn prepare_payload(
                     .with_status_code(400)
             })?;
             let label = if let Some(label) = &parsed_form.label {
-                Some(buckle::Buckle::parse(label).map_err(|e| {
+                Some(aliases.resolve(label).map_err(|e| {
                     Response::json(&serde_json::json!({"error": e.to_string()}))
                         .with_status_code(400)
                 })?)
@@ -102,7 +193,7 @@ fn prepare_payload(
             let mut payload = String::new();
             let label = request
                 .header("x-faasten-label")
-                .and_then(|b| Buckle::parse(b).ok());
+                .and_then(|b| aliases.resolve(b).ok());
             let _ = request
                 .data()
                 .unwrap()
@@ -155,8 +246,12 @@ fn prepare_labeled_invoke<S: BackingStore>(
     })?;
     let (f, gate_privilege) =
         fs::utils::resolve_gate_with_clearance_check(fs, path).map_err(|e| {
-            Response::json(&serde_json::json!({ "error": format!("{:?}", e) }))
-                .with_status_code(400)
+            let code = ReturnCode::for_gate_error(&e);
+            Response::json(&serde_json::json!({
+                "error": format!("{:?}", e),
+                "code": code.name(),
+            }))
+            .with_status_code(code.http_status())
         })?;
     let gate_privilege = Some(gate_privilege.into());
     let label = fs::utils::get_current_label();
@@ -171,59 +266,101 @@ fn prepare_labeled_invoke<S: BackingStore>(
         blobs,
         sync: true,
         invoker: Some(fs::utils::get_privilege().into()),
+        priority: 0,
+        stream: false,
+        trace_taint: false,
+        preemptible: false,
+        sealed: false,
+        reply_key: vec![],
+        callback: String::new(),
+        payload_blob: String::new(),
+        payload_blob_transient: false,
+        seal_id: String::new(),
     })
 }
 
+fn submit_with_callback(
+    invoke: LabeledInvoke,
+    sched_conn: &mut sched::rpc::Client,
+) -> Result<Response, Response> {
+    debug!("submitting with callback: {:?}", invoke);
+    let tr = sched::rpc::labeled_invoke(sched_conn, invoke).map_err(|e| match e {
+        // the scheduler refuses callbacks to hosts it doesn't allow
+        sched::Error::Status(s) if s.code() == tonic::Code::PermissionDenied => {
+            Response::json(&serde_json::json!({"error": s.message()})).with_status_code(403)
+        }
+        e => {
+            error!("{:?}", e);
+            Response::json(&serde_json::json!({
+                "error": "failed to submit invocation to the scheduler",
+            }))
+            .with_status_code(500)
+        }
+    })?;
+    if tr.code != ReturnCode::Success as i32 {
+        let resp: Response = tr.into();
+        return Err(resp);
+    }
+    Ok(Response::json(&serde_json::json!({"queued": true})).with_status_code(202))
+}
+
 fn wait_for_completion(
     invoke: LabeledInvoke,
-    sched_conn: &mut TcpStream,
+    sched_conn: &mut sched::rpc::Client,
+    blobstore: &Mutex<Blobstore>,
 ) -> Result<Response, Response> {
     debug!("submitting: {:?}", invoke);
-    // submit the labeled_invoke to the scheduler
-    sched::rpc::labeled_invoke(sched_conn, invoke).map_err(|e| {
+    // submit the labeled_invoke to the scheduler and wait for the return
+    let mut tr = sched::rpc::labeled_invoke(sched_conn, invoke).map_err(|e| {
         error!("{:?}", e);
-        Response::json(&serde_json::json!({
-            "error": "failed to submit invocation to the scheduler",
-        }))
-        .with_status_code(500)
+        match e {
+            sched::Error::Timeout => ReturnCode::Timeout.error_response(),
+            _ => Response::json(&serde_json::json!({
+                "error": "failed to submit invocation to the scheduler",
+            }))
+            .with_status_code(500),
+        }
     })?;
 
-    debug!("waiting...");
-    // wait for the return
-    let bs = sched::message::read_u8(sched_conn).map_err(|_| {
-        Response::json(&serde_json::json!({
-            "error": "failed to read the task return",
+    if !Into::<Buckle>::into(tr.label.clone().unwrap()).can_flow_to_with_privilege(
+        &fs::utils::get_current_label(),
+        &fs::utils::get_privilege(),
+    ) {
+        // the trace stops where the function's label left what the invoker may see
+        tr.redact_taint_trace(&fs::utils::get_current_label(), &fs::utils::get_privilege());
+        let label = |l: &Option<_>| l.clone().map(|l| format!("{:?}", Into::<Buckle>::into(l)));
+        let taint_trace: Vec<_> = tr
+            .taint_trace
+            .iter()
+            .map(|raise| {
+                serde_json::json!({
+                    "syscall": raise.syscall,
+                    "path": raise.path,
+                    "from": label(&raise.from),
+                    "to": label(&raise.to),
+                })
+            })
+            .collect();
+        Err(Response::json(&serde_json::json!({
+            "error": ReturnCode::Unauthorized.description(),
+            "code": ReturnCode::Unauthorized.name(),
+            "label": format!("{:?}", Into::<Buckle>::into(tr.label.unwrap())),
+            "current_label": format!("{:?}", fs::utils::get_current_label()),
+            "privilege": format!("{:?}", fs::utils::get_privilege()),
+            "taint_trace": taint_trace,
         }))
-        .with_status_code(500)
-    })?;
-
-    use prost::Message;
-    use snapfaas::sched::message::TaskReturn;
-    match TaskReturn::decode(bs.as_slice()) {
-        Ok(tr) => {
-            if !Into::<Buckle>::into(tr.label.clone().unwrap()).can_flow_to_with_privilege(
-                &fs::utils::get_current_label(),
-                &fs::utils::get_privilege(),
-            ) {
-                Err(Response::json(&serde_json::json!({
-                    "error": "unauthorized to read response",
-                    "label": format!("{:?}", Into::<Buckle>::into(tr.label.unwrap())),
-                    "current_label": format!("{:?}", fs::utils::get_current_label()),
-                    "privilege": format!("{:?}", fs::utils::get_privilege())
-                }))
-                .with_status_code(401))
-            } else {
-                let resp: Response = tr.into();
-                if resp.is_success() {
-                    Ok(resp)
-                } else {
-                    Err(resp)
-                }
-            }
+        .with_status_code(ReturnCode::Unauthorized.http_status()))
+    } else {
+        if let Some(response) = tr.payload.as_mut() {
+            response.unspill_body(&mut blobstore.lock().unwrap()).map_err(|e| {
+                Response::json(&serde_json::json!({"error": e.to_string()})).with_status_code(500)
+            })?;
+        }
+        let resp: Response = tr.into();
+        if resp.is_success() {
+            Ok(resp)
+        } else {
+            Err(resp)
         }
-        Err(_) => Err(Response::json(&serde_json::json!({
-            "error": "failed to decode return from Faasten core"
-        }))
-        .with_status_code(500)),
     }
 }
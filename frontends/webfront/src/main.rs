@@ -3,7 +3,10 @@ use openssl::pkey::PKey;
 use snapfaas::{blobstore::Blobstore, cli, fs::BackingStore};
 
 mod app;
+mod auth;
+mod fsapi;
 pub mod init;
+mod openapi;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -28,74 +31,187 @@ struct Cli {
     /// Base URL of the gateway server
     #[arg(long, value_name = "URL")]
     base_url: String,
-    /// Address of the Faasten scheduler
-    #[arg(long, value_name = "ADDR:PORT")]
+    /// Address of the Faasten scheduler, ADDR:PORT or unix://PATH
+    #[arg(long, value_name = "ADDR")]
     faasten_scheduler: String,
+    /// Compress large messages exchanged with the Faasten scheduler (zstd)
+    #[arg(long)]
+    compress: bool,
+    /// Largest message in MB exchanged with the Faasten scheduler, decompressed
+    #[arg(long, value_name = "MB", default_value_t = 64)]
+    max_message_size: usize,
+    /// Payloads larger than BYTES pass through the Faasten scheduler as blobs, 0 passes
+    /// everything inline
+    #[arg(long, value_name = "BYTES", default_value_t = 1 << 20)]
+    max_inline: usize,
+    /// Highest priority logged in users may ask for with the x-faasten-priority header. Others
+    /// always get the lowest.
+    #[arg(long, value_name = "NUM", default_value_t = 0)]
+    max_priority: u32,
+    /// HTTP header to pass on to functions, e.g. for service URL templates. Repeat for more
+    /// headers. All headers but Authorization are passed on if none is given.
+    #[arg(long, value_name = "NAME")]
+    forward_header: Vec<String>,
+    /// Path of the YAML login provider configuration. Without one, users log in through
+    /// Princeton CAS. Logged in users pair GitHub accounts if GITHUB_CLIENT_ID and
+    /// GITHUB_CLIENT_SECRET are set.
+    #[arg(long, value_name = "PATH")]
+    auth_config: Option<std::path::PathBuf>,
+    /// Origin of a web app, e.g. https://app.example.com, allowed to change state with the
+    /// session cookie besides the base URL's. Repeat for more origins.
+    #[arg(long, value_name = "ORIGIN")]
+    allowed_origin: Vec<String>,
+    /// Path of the PEM encoded public key of the key workers sign download tokens with, to
+    /// serve the download links functions mint
+    #[arg(long, value_name = "PATH")]
+    platform_key: Option<std::ffi::OsString>,
+    /// Path of a secret shared with workers to seal payloads with, so that scheduler nodes
+    /// cannot read them
+    #[arg(long, value_name = "PATH")]
+    payload_secret: Option<std::ffi::OsString>,
+    /// Warn each time a saved blob leaves the blob directory holding more than BYTES
+    #[arg(long, value_name = "BYTES")]
+    blob_usage_alert: Option<u64>,
 }
 
 fn main() -> Result<(), std::io::Error> {
     env_logger::init();
 
-    let github_client_id = std::env::var("GITHUB_CLIENT_ID").expect("client id");
-    let github_client_secret = std::env::var("GITHUB_CLIENT_SECRET").expect("client secret");
-
     let cli = Cli::parse();
 
+    let auth_config = match cli.auth_config.as_ref() {
+        Some(path) => {
+            let f = std::fs::File::open(path)?;
+            serde_yaml::from_reader(f).expect("parse the login provider configuration")
+        }
+        None => default_auth_config(),
+    };
+    let providers = auth::providers(auth_config);
+    let github = match (
+        std::env::var("GITHUB_CLIENT_ID"),
+        std::env::var("GITHUB_CLIENT_SECRET"),
+    ) {
+        (Ok(client_id), Ok(client_secret)) => {
+            Some(auth::GithubPairing::new(client_id, client_secret))
+        }
+        _ => None,
+    };
+
+    if cli.compress {
+        snapfaas::sched::codec::enable_compression();
+    }
+    snapfaas::sched::codec::set_max_message_size(cli.max_message_size << 20);
+
     let public_key_bytes = std::fs::read(cli.public_key)?;
     let private_key_bytes = std::fs::read(cli.secret_key)?;
     let base_url = cli.base_url;
     let sched_address = cli.faasten_scheduler;
     let blobstore = Blobstore::new(cli.blobs, cli.tmp);
+    snapfaas::blobstore::set_usage_alert(cli.blob_usage_alert);
+    blobstore.spawn_tmp_cleaner();
     let listen_addr = cli.listen;
+    let max_inline = cli.max_inline;
+    let max_priority = cli.max_priority;
+    let forward_headers = cli.forward_header;
+    let allowed_origins = cli.allowed_origin;
+    let platform_key = match cli.platform_key {
+        Some(path) => Some(PKey::public_key_from_pem(&std::fs::read(path)?).unwrap()),
+        None => None,
+    };
+    let payload_secret = match cli.payload_secret {
+        Some(path) => Some(std::fs::read(path)?),
+        None => None,
+    };
     if let Some(tikv_pds) = cli.store.tikv {
         let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
         let client =
             rt.block_on(async { tikv_client::RawClient::new(tikv_pds).await.unwrap() });
         let tikv = snapfaas::fs::tikv::TikvClient::new(client, std::sync::Arc::new(rt));
-        let app = app::App::new(
+        let mut app = app::App::new(
             PKey::private_key_from_pem(private_key_bytes.as_slice()).unwrap(),
             PKey::public_key_from_pem(public_key_bytes.as_slice()).unwrap(),
-            app::GithubOAuthCredentials {
-                client_id: github_client_id,
-                client_secret: github_client_secret,
-            },
             blobstore,
             tikv,
             base_url,
             sched_address,
         );
+        app.set_max_inline(max_inline);
+        app.set_max_priority(max_priority);
+        if !forward_headers.is_empty() {
+            app.set_forward_headers(forward_headers.clone());
+        }
+        for (name, provider) in providers {
+            app.add_auth_provider(name, provider);
+        }
+        if let Some(github) = github {
+            app.set_github_pairing(github);
+        }
+        for origin in allowed_origins {
+            app.add_allowed_origin(origin);
+        }
+        if let Some(key) = platform_key {
+            app.set_platform_key(key);
+        }
+        if let Some(secret) = payload_secret {
+            app.set_payload_secret(secret);
+        }
         start_app(app, &listen_addr)
-    } else if let Some(path) = cli.store.lmdb {
-        let dbenv = std::boxed::Box::leak(Box::new(
-            lmdb::Environment::new()
-                .set_map_size(100 * 1024 * 1024 * 1024)
-                .set_max_dbs(2)
-                .open(&std::path::Path::new(&path))
-                .unwrap(),
-        ));
-        let app = app::App::new(
+    } else if let Some(path) = cli.store.lmdb.as_ref() {
+        let options = cli.store.lmdb_options();
+        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::open_dbenv(path, &options)));
+        let mut app = app::App::new(
             PKey::private_key_from_pem(private_key_bytes.as_slice()).unwrap(),
             PKey::public_key_from_pem(public_key_bytes.as_slice()).unwrap(),
-            app::GithubOAuthCredentials {
-                client_id: github_client_id,
-                client_secret: github_client_secret,
-            },
             blobstore,
             &*dbenv,
             base_url,
             sched_address,
         );
+        app.set_max_inline(max_inline);
+        app.set_max_priority(max_priority);
+        if !forward_headers.is_empty() {
+            app.set_forward_headers(forward_headers.clone());
+        }
+        for (name, provider) in providers {
+            app.add_auth_provider(name, provider);
+        }
+        if let Some(github) = github {
+            app.set_github_pairing(github);
+        }
+        for origin in allowed_origins {
+            app.add_allowed_origin(origin);
+        }
+        if let Some(key) = platform_key {
+            app.set_platform_key(key);
+        }
+        if let Some(secret) = payload_secret {
+            app.set_payload_secret(secret);
+        }
         start_app(app, &listen_addr)
     } else {
         panic!("We shouldn't reach here.")
     }
 }
 
+// Princeton CAS
+fn default_auth_config() -> auth::AuthConfig {
+    let mut config = auth::AuthConfig::new();
+    config.insert(
+        "cas".to_string(),
+        auth::ProviderConfig::Cas {
+            server: "https://fed.princeton.edu/cas".to_string(),
+            domain: "princeton.edu".to_string(),
+        },
+    );
+    config
+}
+
 fn start_app<B>(app: app::App<B>, listen_addr: &str) -> Result<(), std::io::Error>
 where
     B: BackingStore + Clone + Send + 'static + Sync,
 {
-    rouille::start_server(listen_addr, move |request| {
+    app.spawn_session_sweeper();
+    let handler = move |request: &rouille::Request| {
         use log::{error, info};
         use rouille::{Request, Response};
 
@@ -120,5 +236,46 @@ where
             );
         };
         rouille::log_custom(request, log_ok, log_err, || app.handle(request))
-    });
+    };
+    // With socket activation, systemd holds the socket across restarts. rouille only serves
+    // sockets it binds itself, so connections to the passed one are relayed to a loopback one.
+    let activated = snapfaas::systemd::tcp_listener();
+    let bind_addr = if activated.is_some() { "127.0.0.1:0" } else { listen_addr };
+    let server = rouille::Server::new(bind_addr, handler).map_err(std::io::Error::other)?;
+    if let Some(listener) = activated {
+        let backend = server.server_addr();
+        std::thread::spawn(move || relay(listener, backend));
+    }
+    snapfaas::systemd::notify_ready();
+    let (handle, _stop) = server.stoppable();
+    handle
+        .join()
+        .map_err(|_| std::io::Error::other("the server panicked"))
+}
+
+// Relays each connection accepted on `listener` to `backend`
+fn relay(listener: std::net::TcpListener, backend: std::net::SocketAddr) {
+    use std::net::{Shutdown, TcpStream};
+
+    let pipe = |mut from: TcpStream, mut to: TcpStream| {
+        let _ = std::io::copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Write);
+    };
+    for client in listener.incoming() {
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                log::warn!("failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        std::thread::spawn(move || {
+            let server = TcpStream::connect(backend)?;
+            let (client_dup, server_dup) = (client.try_clone()?, server.try_clone()?);
+            let upstream = std::thread::spawn(move || pipe(client_dup, server_dup));
+            pipe(server, client);
+            let _ = upstream.join();
+            Ok::<_, std::io::Error>(())
+        });
+    }
 }
@@ -0,0 +1,277 @@
+//! OpenAPI description of the REST API, served at `/openapi.json` so that users of other
+//! languages can generate clients. `ROUTES` mirrors the router in `app.rs`, keep them in sync.
+
+use lazy_static::lazy_static;
+use serde_json::{json, Map, Value};
+
+use Body::{Bytes, Empty, Json};
+
+// what a request or response carries
+enum Body {
+    Empty,
+    Json(&'static str),
+    Bytes,
+}
+
+struct Route {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    query: &'static [&'static str],
+    request: Body,
+    response: Body,
+}
+
+const fn route(
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    request: Body,
+    response: Body,
+) -> Route {
+    Route {
+        method,
+        path,
+        summary,
+        query: &[],
+        request,
+        response,
+    }
+}
+
+const fn with_query(mut r: Route, query: &'static [&'static str]) -> Route {
+    r.query = query;
+    r
+}
+
+const ROUTES: &[Route] = &[
+    route("get", "/login/{provider}", "Start logging in with a provider", Empty, Empty),
+    route("get", "/authenticate/{provider}", "Finish logging in", Empty, Empty),
+    route("post", "/logout", "End the login session", Empty, Empty),
+    route("get", "/csrf", "Get the CSRF token of the login session", Empty, Json("Object")),
+    route(
+        "post",
+        "/pair_github",
+        "The GitHub login of the github_token form field, to pair with the logged-in user",
+        Empty,
+        Json("Object"),
+    ),
+    route("get", "/me", "The logged-in user", Empty, Json("Object")),
+    route("get", "/faasten/ping", "Check the webfront is up", Empty, Empty),
+    route("get", "/faasten/ping/scheduler", "Check the scheduler is up", Empty, Empty),
+    route(
+        "get",
+        "/faasten/stats/contention",
+        "File system contention, for operators",
+        Empty,
+        Json("Object"),
+    ),
+    route("get", "/faasten/stats/blobs", "Blob store usage, for operators", Empty, Json("Object")),
+    route("post", "/faasten/invoke/{path}", "Invoke the gate at path", Json("Object"), Bytes),
+    route("post", "/faasten/delegate", "Mint a delegation token", Json("Object"), Json("Object")),
+    route(
+        "post",
+        "/faasten/delegate/certificate",
+        "Mint a delegation certificate",
+        Json("Object"),
+        Json("Object"),
+    ),
+    route("post", "/explain", "Explain a label flow", Json("Object"), Json("Object")),
+    route("get", "/faasten/catalog", "List published gates", Empty, Json("Object")),
+    route("get", "/faasten/catalog/{name}", "A published gate", Empty, Json("Object")),
+    with_query(
+        route("get", "/faasten/fs/ls/{path}", "List a directory", Empty, Json("Object")),
+        &["long"],
+    ),
+    with_query(
+        route(
+            "get",
+            "/home/search",
+            "Search the user's home, at most 16 directories deep and for at most 1000 matches. \
+            `truncated` is true if the search stopped early.",
+            Empty,
+            Json("Object"),
+        ),
+        &["q", "clearance"],
+    ),
+    with_query(
+        route(
+            "get",
+            "/faasten/fs/ls_faceted/{path}",
+            "List a faceted directory",
+            Empty,
+            Json("Object"),
+        ),
+        &["clearance"],
+    ),
+    with_query(
+        route(
+            "post",
+            "/faasten/fs/download_url/{path}",
+            "Mint a download link for a file or blob",
+            Empty,
+            Json("Object"),
+        ),
+        &["ttl"],
+    ),
+    route("get", "/faasten/download/{token}", "Download through a link", Empty, Bytes),
+    route("get", "/faasten/fs/file/{path}", "Read a file", Empty, Bytes),
+    route("put", "/faasten/fs/file/{path}", "Overwrite a file", Bytes, Empty),
+    route("post", "/faasten/fs/file/{path}", "Create a file", Json("Labeled"), Empty),
+    with_query(
+        route("get", "/faasten/fs/log/{path}", "Read log entries", Empty, Json("Object")),
+        &["from", "to"],
+    ),
+    route("put", "/faasten/fs/log/{path}", "Append to a log", Bytes, Json("Object")),
+    route("post", "/faasten/fs/log/{path}", "Create a log", Json("Labeled"), Empty),
+    with_query(
+        route("post", "/faasten/fs/blob/{path}", "Upload a blob", Bytes, Empty),
+        &["label"],
+    ),
+    route("post", "/faasten/fs/dir/{path}", "Create a directory", Json("Labeled"), Empty),
+    route("post", "/faasten/fs/faceted/{path}", "Create a faceted directory", Empty, Empty),
+    route("post", "/faasten/fs/link/{path}", "Link an object", Json("Target"), Empty),
+    with_query(
+        route("delete", "/faasten/fs/link/{path}", "Unlink an object", Empty, Empty),
+        &["recursive", "cursor", "trash"],
+    ),
+    route("post", "/faasten/fs/snapshot/{path}", "Snapshot an object", Json("Target"), Empty),
+    route("post", "/faasten/fs/restore/{path}", "Restore a trashed object", Json("Object"), Empty),
+    route("post", "/faasten/fs/gate/{path}", "Create a gate", Json("GateSpec"), Empty),
+    route("put", "/faasten/fs/gate/{path}", "Replace a gate", Json("GateSpec"), Empty),
+    route("post", "/faasten/fs/service/{path}", "Create a service", Json("ServiceSpec"), Empty),
+    route("put", "/faasten/fs/service/{path}", "Replace a service", Json("ServiceSpec"), Empty),
+    route("get", "/openapi.json", "This document", Empty, Json("Object")),
+];
+
+lazy_static! {
+    pub static ref DOCUMENT: Value = document();
+}
+
+fn content(body: &Body) -> Option<Value> {
+    match body {
+        Body::Empty => None,
+        Body::Json(schema) => Some(json!({
+            "application/json": {"schema": {"$ref": format!("#/components/schemas/{}", schema)}}
+        })),
+        Body::Bytes => Some(json!({
+            "application/octet-stream": {"schema": {"type": "string", "format": "binary"}}
+        })),
+    }
+}
+
+fn operation(r: &Route) -> Value {
+    let path_params = r
+        .path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            json!({"name": name, "in": "path", "required": true, "schema": {"type": "string"}})
+        });
+    let query_params = r
+        .query
+        .iter()
+        .map(|name| json!({"name": name, "in": "query", "schema": {"type": "string"}}));
+    let mut success = json!({"description": "Success"});
+    if let Some(content) = content(&r.response) {
+        success["content"] = content;
+    }
+    let mut op = json!({
+        "summary": r.summary,
+        "parameters": path_params.chain(query_params).collect::<Vec<_>>(),
+        "responses": {
+            "2XX": success,
+            "default": {"description": "Error", "content": content(&Body::Json("Error"))},
+        },
+    });
+    if let Some(content) = content(&r.request) {
+        op["requestBody"] = json!({"required": true, "content": content});
+    }
+    op
+}
+
+fn document() -> Value {
+    let mut paths = Map::new();
+    for r in ROUTES {
+        let item = paths.entry(r.path).or_insert_with(|| json!({}));
+        item[r.method] = operation(r);
+    }
+    let string = json!({"type": "string"});
+    json!({
+        "openapi": "3.0.3",
+        "info": {"title": "Faasten webfront", "version": env!("CARGO_PKG_VERSION")},
+        "security": [{"bearer": []}, {}],
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer", "bearerFormat": "JWT"},
+            },
+            "schemas": {
+                "Object": {"type": "object"},
+                "Error": {"type": "object", "properties": {"error": string}},
+                "Labeled": {
+                    "type": "object",
+                    "required": ["label"],
+                    "properties": {"label": string},
+                },
+                "Target": {
+                    "type": "object",
+                    "required": ["target"],
+                    "properties": {"target": string},
+                },
+                "FunctionSpec": {
+                    "type": "object",
+                    "required": ["memory", "app_image", "runtime"],
+                    "properties": {
+                        "memory": {"type": "integer"},
+                        "app_image": string,
+                        "runtime": string,
+                        "kernel": string,
+                        "devices": {"type": "array", "items": string},
+                        "keep_alive": {"type": "boolean"},
+                        "min_instances": {"type": "integer"},
+                        "speculative_boot": {"type": "boolean"},
+                        "idempotent": {"type": "boolean"},
+                        "idle_ttl": {"type": "integer"},
+                        "requirements": {"type": "object", "additionalProperties": string},
+                        "forward_headers": {"type": "array", "items": string},
+                        "concurrency_target": {"type": "integer"},
+                        "scratch": {"type": "integer"},
+                        "app_layers": {"type": "array", "items": string},
+                        "provenance": {"type": "object"},
+                        "results": {"type": "object"},
+                    },
+                },
+                "GateSpec": {
+                    "type": "object",
+                    "required": ["privilege", "invoker_integrity_clearance"],
+                    "properties": {
+                        "label": string,
+                        "privilege": string,
+                        "invoker_integrity_clearance": string,
+                        "declassify": string,
+                        "function": {"$ref": "#/components/schemas/FunctionSpec"},
+                        "gate": string,
+                        "metadata": {"type": "object"},
+                    },
+                },
+                "ServiceSpec": {
+                    "type": "object",
+                    "required": [
+                        "privilege", "invoker_integrity_clearance", "taint", "url", "verb",
+                    ],
+                    "properties": {
+                        "label": string,
+                        "privilege": string,
+                        "invoker_integrity_clearance": string,
+                        "taint": string,
+                        "url": string,
+                        "verb": string,
+                        "headers": {"type": "object", "additionalProperties": string},
+                        "metadata": {"type": "object"},
+                    },
+                },
+            },
+        },
+    })
+}
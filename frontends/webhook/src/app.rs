@@ -1,53 +1,106 @@
 use bytes::Bytes;
-use http;
 use http::status::StatusCode;
-use log::{error, debug};
+use log::{error, debug, warn};
+use reqwest::blocking::RequestBuilder;
+use serde::Deserialize;
 
-use std::net::TcpStream;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-use snapfaas::request;
+use snapfaas::sched::{self, message::{GateInvoke, ReturnCode, TaskReturn}};
 
 use httpserver::Handler;
 
-struct SnapFaasManager {
-    address: String,
+// How long deliveries wait for room in a full scheduler queue before they are submitted anyway.
+// GitHub gives up on webhook requests after 10 seconds, so replies can't wait long.
+const REPLY_DEFER_MAX: Duration = Duration::from_secs(5);
+const DEFER_MAX: Duration = Duration::from_secs(600);
+// bounds on how long to wait between polls of the scheduler's load
+const DEFER_POLL_MIN: Duration = Duration::from_millis(100);
+const DEFER_POLL_MAX: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AppConfig {
+    #[serde(default)]
+    pub repos: HashMap<String, RepoConfig>,
+    /// base URL of the GitHub REST API, e.g. a `testutil::MockHttpService` in tests
+    #[serde(default = "default_github_api")]
+    pub github_api: String,
 }
 
-impl r2d2::ManageConnection for SnapFaasManager {
-    type Connection = TcpStream;
-    type Error = std::io::Error;
+fn default_github_api() -> String {
+    "https://api.github.com".to_string()
+}
 
-    fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        Ok(TcpStream::connect(&self.address)?)
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            repos: Default::default(),
+            github_api: default_github_api(),
+        }
     }
+}
 
-    fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        let req = request::Request {
-            gate: String::from("ping"),
-            payload: serde_json::Value::Null,
-        };
-        request::write_u8(&req.to_vec(), conn)?;
-        request::read_u8(conn)?;
-        Ok(())
-    }
+#[derive(Clone, Debug, Deserialize)]
+pub struct RepoConfig {
+    /// GitHub token to report results with
+    pub token: Option<String>,
+    /// webhook shared secret, `--secret` if unset
+    pub secret: Option<String>,
+    /// gate invoked with the repository's events
+    #[serde(default = "default_gate")]
+    pub gate: String,
+    #[serde(default)]
+    pub result: ResultDelivery,
+}
 
-    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.take_error().ok().flatten().is_some()
-    }
+fn default_gate() -> String {
+    "gh_repo".to_string()
+}
+
+fn default_context() -> String {
+    "faasten".to_string()
+}
+
+/// What to do with the result of an event's invocation
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ResultDelivery {
+    /// answer the webhook request with the function's response
+    #[default]
+    Reply,
+    /// set a commit status on the commit the event is about, pending until the function returns
+    GithubStatus {
+        #[serde(default = "default_context")]
+        context: String,
+    },
+    /// POST the `TaskReturn` to `url` as JSON
+    Callback { url: String },
+    /// run a GitHub check on the commit the event is about, which needs a GitHub App token.
+    /// The check run is queued when the event arrives, in progress once it is invoked and
+    /// completed with the function's response, see `check_run_output`. The function gets the
+    /// check run's id as `check_run_id`, so it can post progress itself through a PATCH service
+    /// for `https://api.github.com/repos/{repo}/check-runs/{id}`.
+    GithubCheck {
+        #[serde(default = "default_context")]
+        name: String,
+    },
 }
 
 #[derive(Clone)]
 pub struct App {
-    conn: r2d2::Pool<SnapFaasManager>,
+    conn: sched::rpc::Client,
     secret: Option<String>,
+    config: AppConfig,
 }
 
 impl App {
-    pub fn new(secret: Option<String>, snapfaas_address: String) -> Self {
-        let conn = r2d2::Pool::builder().max_size(10).build(SnapFaasManager { address: snapfaas_address }).expect("pool");
+    pub fn new(secret: Option<String>, scheduler_address: String, config: AppConfig) -> Self {
+        let conn = sched::rpc::connect(&scheduler_address).expect("connect to the scheduler");
         App {
             secret,
             conn,
+            config,
         }
     }
 
@@ -58,67 +111,400 @@ impl App {
             .ok_or(StatusCode::BAD_REQUEST)?;
         debug!("Headers contain x-github-event key.");
         let etype = event_type.to_str().or(Err(StatusCode::BAD_REQUEST))?;
-        match etype {
-            "ping" => {
-                verify_github_request(
-                    &self.secret,
-                    &request.body(),
-                    request
-                        .headers()
-                        .get("x-hub-signature")
-                        .map(|v| v.as_bytes()),
-                )?;
-
-                debug!("GitHub pinged.");
-                Ok(Bytes::new())
-            }
-            _ => {
-                debug!("{} event.", etype);
-                verify_github_request(
-                    &self.secret,
-                    &request.body(),
-                    request
-                        .headers()
-                        .get("x-hub-signature")
-                        .map(|v| v.as_bytes()),
-                )?;
-
-                // TODO: use the event body to set a label?
-                /*let event_body: PushEvent =
-                    serde_json::from_slice(request.body().as_ref()).or(Err(StatusCode::BAD_REQUEST))?;*/
-                let mut event_body: serde_json::Map<String, serde_json::Value> =
-                    serde_json::from_slice(request.body().as_ref()).or(Err(StatusCode::BAD_REQUEST))?;
-                event_body.insert(String::from("event"), etype.into());
-
-                let req = request::Request {
-                    gate: "gh_repo".to_string(),
-                    payload: event_body.into(),
-                };
+        // TODO: use the event body to set a label?
+        let mut event_body: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(request.body().as_ref()).or(Err(StatusCode::BAD_REQUEST))?;
+        let repo_name = event_body
+            .get("repository")
+            .and_then(|r| r.get("full_name"))
+            .and_then(|n| n.as_str())
+            .map(String::from);
+        let repo = repo_name.as_ref().and_then(|name| self.config.repos.get(name));
+        let secret = repo.and_then(|r| r.secret.clone()).or(self.secret.clone());
+        verify_github_request(
+            &secret,
+            request.body(),
+            request
+                .headers()
+                .get("x-hub-signature")
+                .map(|v| v.as_bytes()),
+        )?;
+        if etype == "ping" {
+            debug!("GitHub pinged.");
+            return Ok(Bytes::new());
+        }
+
+        debug!("{} event.", etype);
+        let delivery = repo.map(|r| r.result.clone()).unwrap_or_default();
+        let mut report = Report {
+            github_api: self.config.github_api.clone(),
+            repo: repo_name.unwrap_or_default(),
+            token: repo.and_then(|r| r.token.clone()),
+            event: etype.to_string(),
+            head_sha: head_sha(etype, &event_body),
+            delivery,
+            check_run: None,
+        };
+        report.queue();
+        event_body.insert(String::from("event"), etype.into());
+        if let Some(id) = report.check_run {
+            event_body.insert(String::from("check_run_id"), id.into());
+        }
+        let invoke = GateInvoke {
+            gate: repo.map_or_else(default_gate, |r| r.gate.clone()),
+            payload: serde_json::to_vec(&event_body).unwrap(),
+            sync: true,
+            ..Default::default()
+        };
+
+        if let ResultDelivery::Reply = report.delivery {
+            let mut conn = self.conn.clone();
+            wait_for_room(&mut conn, REPLY_DEFER_MAX);
+            let result = sched::rpc::invoke_gate(&mut conn, invoke).map_err(|e| {
+                error!("Failed to invoke the gate: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            return reply(result);
+        }
 
-                let conn = &mut self.conn.get().expect("Lock failed");
-                if let Err(e) = request::write_u8(&req.to_vec(), conn) {
-                    error!("Failed to send request to snapfaas: {:?}", e);
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        // GitHub gives up on webhooks after 10 seconds, so report the result on the side
+        let mut conn = self.conn.clone();
+        std::thread::spawn(move || {
+            wait_for_room(&mut conn, DEFER_MAX);
+            report.started();
+            match sched::rpc::invoke_gate(&mut conn, invoke) {
+                Ok(result) => report.finished(&result),
+                Err(e) => {
+                    error!("Failed to invoke the gate: {:?}", e);
+                    report.failed();
                 }
+            }
+        });
+        // no body, the result goes elsewhere
+        Err(StatusCode::ACCEPTED)
+    }
+}
 
-                match request::read_u8(conn) {
-                    Err(e) => {
-                        error!("Failed to read response from snapfaas: {:?}", e);
-                        Err(StatusCode::INTERNAL_SERVER_ERROR)
-                    },
-                    Ok(buf) => {
-                        let rsp: request::Response = serde_json::from_slice(&buf).unwrap();
-                        debug!("Reponse {:?}", rsp);
-                        match rsp.status {
-                            request::RequestStatus::ResourceExhausted => Err(StatusCode::TOO_MANY_REQUESTS),
-                            request::RequestStatus::GateNotExist | request::RequestStatus::FunctionNotExist | request::RequestStatus::Dropped => Err(StatusCode::BAD_REQUEST),
-                            request::RequestStatus::LaunchFailed => Err(StatusCode::INTERNAL_SERVER_ERROR),
-                            request::RequestStatus::SentToVM(response) => Ok(Bytes::from(response)),
-                            request::RequestStatus::ProcessRequestFailed => Err(StatusCode::INTERNAL_SERVER_ERROR),
+// Waits until the scheduler's queue has room or `deadline` passes, polling its load. The
+// invocation is submitted either way, the scheduler's admission policy has the last word.
+fn wait_for_room(conn: &mut sched::rpc::Client, deadline: Duration) {
+    let start = Instant::now();
+    loop {
+        let Ok(load) = sched::rpc::load(conn) else {
+            return;
+        };
+        if load.queue_depth < load.capacity {
+            return;
+        }
+        let waited = start.elapsed();
+        if waited >= deadline {
+            warn!("Scheduler queue still full after {:?}, submitting anyway", waited);
+            return;
+        }
+        let wait = Duration::from_millis(load.estimated_wait_ms)
+            .clamp(DEFER_POLL_MIN, DEFER_POLL_MAX)
+            .min(deadline - waited);
+        debug!("Scheduler queue full, deferring the delivery for {:?}", wait);
+        std::thread::sleep(wait);
+    }
+}
+
+fn reply(result: TaskReturn) -> AppResult<Bytes> {
+    debug!("Reponse {:?}", result);
+    match ReturnCode::from_i32(result.code) {
+        Some(ReturnCode::Success) => Ok(Bytes::from(
+            result.payload.and_then(|p| p.body).unwrap_or_default(),
+        )),
+        Some(ReturnCode::ResourceExhausted)
+        | Some(ReturnCode::QueueFull)
+        | Some(ReturnCode::QueueShed)
+        | Some(ReturnCode::QueueTimeout) => Err(StatusCode::TOO_MANY_REQUESTS),
+        Some(ReturnCode::GateNotExist) => Err(StatusCode::BAD_REQUEST),
+        Some(ReturnCode::Unauthorized) => Err(StatusCode::UNAUTHORIZED),
+        Some(code) => Err(StatusCode::from_u16(code.http_status())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)),
+        None => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// the commit an event is about, if any
+fn head_sha(event: &str, body: &serde_json::Map<String, serde_json::Value>) -> Option<String> {
+    let sha = match event {
+        "push" => body.get("after"),
+        "pull_request" => body
+            .get("pull_request")
+            .and_then(|pr| pr.get("head"))
+            .and_then(|head| head.get("sha")),
+        "check_suite" | "check_run" => body
+            .get(event)
+            .and_then(|check| check.get("head_sha")),
+        _ => None,
+    };
+    sha.and_then(|sha| sha.as_str()).map(String::from)
+}
+
+// Reports the result of one event's invocation
+struct Report {
+    github_api: String,
+    repo: String,
+    token: Option<String>,
+    event: String,
+    head_sha: Option<String>,
+    delivery: ResultDelivery,
+    check_run: Option<u64>,
+}
+
+// Bounds GitHub puts on a check run's output
+const CHECK_TITLE_MAX: usize = 255;
+const CHECK_TEXT_MAX: usize = 65535;
+
+/// The output of a completed check run. A function's response may set any of these fields by
+/// being a JSON object with them, otherwise the response is the summary and its status code
+/// decides the conclusion.
+#[derive(Debug, Default, Deserialize)]
+struct CheckRunOutput {
+    conclusion: Option<String>,
+    title: Option<String>,
+    summary: Option<String>,
+    text: Option<String>,
+}
+
+fn check_run_output(status_code: u32, body: &[u8]) -> CheckRunOutput {
+    let mut output: CheckRunOutput = serde_json::from_slice(body).unwrap_or_else(|_| {
+        CheckRunOutput {
+            summary: Some(String::from_utf8_lossy(body).into_owned()),
+            ..Default::default()
+        }
+    });
+    if output.conclusion.is_none() {
+        let conclusion = if status_code < 400 { "success" } else { "failure" };
+        output.conclusion = Some(conclusion.to_string());
+    }
+    output
+}
+
+impl Report {
+    fn queue(&mut self) {
+        match self.delivery {
+            ResultDelivery::GithubStatus { .. } => self.commit_status("pending", "queued"),
+            // our own check runs raise check events, which must not start more check runs
+            ResultDelivery::GithubCheck { .. }
+                if self.event != "check_run" && self.event != "check_suite" =>
+            {
+                self.check_run = self.create_check_run();
+            }
+            _ => (),
+        }
+    }
+
+    fn started(&self) {
+        match self.delivery {
+            ResultDelivery::GithubStatus { .. } => self.commit_status("pending", "running"),
+            ResultDelivery::GithubCheck { .. } => self.update_check_run(serde_json::json!({
+                "status": "in_progress",
+            })),
+            _ => (),
+        }
+    }
+
+    fn failed(&self) {
+        match &self.delivery {
+            ResultDelivery::GithubStatus { .. } => {
+                self.commit_status("error", "could not reach the scheduler")
+            }
+            ResultDelivery::GithubCheck { .. } => self.update_check_run(serde_json::json!({
+                "status": "completed",
+                "conclusion": "failure",
+                "output": {
+                    "title": "Faasten",
+                    "summary": "Could not reach the scheduler.",
+                },
+            })),
+            ResultDelivery::Callback { url } => self.callback(url, serde_json::json!({
+                "repo": self.repo,
+                "event": self.event,
+                "head_sha": self.head_sha,
+                "code": "unreachable",
+            })),
+            ResultDelivery::Reply => (),
+        }
+    }
+
+    fn finished(&self, result: &TaskReturn) {
+        let code = ReturnCode::from_i32(result.code);
+        match &self.delivery {
+            ResultDelivery::GithubStatus { .. } => {
+                let status_code = result.payload.as_ref().map_or(0, |p| p.status_code);
+                match code {
+                    Some(ReturnCode::Success) if status_code < 400 => {
+                        self.commit_status("success", "succeeded")
+                    }
+                    Some(ReturnCode::Success) => self.commit_status("failure", "failed"),
+                    Some(ReturnCode::FunctionError) => {
+                        let reason = result.error.as_ref().map_or("", |e| e.reason.as_str());
+                        self.commit_status("failure", reason)
+                    }
+                    Some(code) => self.commit_status("error", code.description()),
+                    None => self.commit_status("error", "unknown return code"),
+                }
+            }
+            ResultDelivery::Callback { url } => {
+                let payload = result.payload.clone().unwrap_or_default();
+                let error = result.error.clone().unwrap_or_default();
+                self.callback(url, serde_json::json!({
+                    "repo": self.repo,
+                    "event": self.event,
+                    "head_sha": self.head_sha,
+                    "code": code.map(ReturnCode::name),
+                    "status_code": payload.status_code,
+                    "body": String::from_utf8_lossy(&payload.body.unwrap_or_default()),
+                    "body_blob": payload.body_blob,
+                    "error": error.reason,
+                    "traceback": error.traceback,
+                    "receipts": result.receipts,
+                }))
+            }
+            ResultDelivery::GithubCheck { name } => {
+                let output = match (code, result.payload.as_ref()) {
+                    (Some(ReturnCode::Success), Some(payload)) => check_run_output(
+                        payload.status_code,
+                        payload.body.as_deref().unwrap_or_default(),
+                    ),
+                    (Some(ReturnCode::FunctionError), _) => {
+                        let error = result.error.clone().unwrap_or_default();
+                        CheckRunOutput {
+                            conclusion: Some("failure".to_string()),
+                            summary: Some(error.reason),
+                            text: Some(error.traceback),
+                            ..Default::default()
                         }
+                    }
+                    _ => CheckRunOutput {
+                        conclusion: Some("failure".to_string()),
+                        summary: Some(
+                            code.map_or("unknown return code", ReturnCode::description)
+                                .to_string(),
+                        ),
+                        ..Default::default()
                     },
-                }
-            },
+                };
+                let cap = |s: Option<String>, max: usize| s.map(|s| s.chars().take(max).collect());
+                let title: Option<String> = cap(output.title, CHECK_TITLE_MAX);
+                let summary: Option<String> = cap(output.summary, CHECK_TEXT_MAX);
+                let text: Option<String> = cap(output.text, CHECK_TEXT_MAX);
+                self.update_check_run(serde_json::json!({
+                    "status": "completed",
+                    "conclusion": output.conclusion,
+                    "output": {
+                        "title": title.unwrap_or_else(|| name.clone()),
+                        "summary": summary.unwrap_or_default(),
+                        "text": text,
+                    },
+                }))
+            }
+            ResultDelivery::Reply => (),
+        }
+    }
+
+    fn github_request(&self, method: reqwest::Method, route: &str) -> Option<RequestBuilder> {
+        let Some(token) = self.token.as_ref() else {
+            warn!("No token to report the {} event of {} with", self.event, self.repo);
+            return None;
+        };
+        let url = format!("{}/repos/{}/{}", self.github_api, self.repo, route);
+        Some(
+            reqwest::blocking::Client::new()
+                .request(method, url)
+                .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+                .header(reqwest::header::USER_AGENT, "Faasten Webhook Frontend")
+                .bearer_auth(token),
+        )
+    }
+
+    fn create_check_run(&self) -> Option<u64> {
+        let name = match &self.delivery {
+            ResultDelivery::GithubCheck { name } => name,
+            _ => return None,
+        };
+        let Some(sha) = self.head_sha.as_ref() else {
+            warn!("No commit to run a check on for the {} event of {}", self.event, self.repo);
+            return None;
+        };
+        #[derive(Deserialize)]
+        struct CheckRun {
+            id: u64,
+        }
+        let result = self
+            .github_request(reqwest::Method::POST, "check-runs")?
+            .json(&serde_json::json!({
+                "name": name,
+                "head_sha": sha,
+                "status": "queued",
+            }))
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json::<CheckRun>());
+        match result {
+            Ok(check_run) => Some(check_run.id),
+            Err(e) => {
+                error!("Failed to create a check run on {}@{}: {:?}", self.repo, sha, e);
+                None
+            }
+        }
+    }
+
+    fn update_check_run(&self, body: serde_json::Value) {
+        let Some(id) = self.check_run else {
+            return;
+        };
+        let Some(request) =
+            self.github_request(reqwest::Method::PATCH, &format!("check-runs/{}", id))
+        else {
+            return;
+        };
+        let result = request.json(&body).send().and_then(|r| r.error_for_status());
+        if let Err(e) = result {
+            error!("Failed to update check run {} of {}: {:?}", id, self.repo, e);
+        }
+    }
+
+    fn commit_status(&self, state: &str, description: &str) {
+        let Some(sha) = self.head_sha.as_ref() else {
+            warn!("No commit to report the {} event of {} on", self.event, self.repo);
+            return;
+        };
+        let context = match &self.delivery {
+            ResultDelivery::GithubStatus { context } => context.as_str(),
+            _ => return,
+        };
+        let Some(request) =
+            self.github_request(reqwest::Method::POST, &format!("statuses/{}", sha))
+        else {
+            return;
+        };
+        // GitHub caps descriptions at 140 characters
+        let description: String = description.chars().take(140).collect();
+        let result = request
+            .json(&serde_json::json!({
+                "state": state,
+                "context": context,
+                "description": description,
+            }))
+            .send()
+            .and_then(|r| r.error_for_status());
+        if let Err(e) = result {
+            error!("Failed to set the commit status of {}@{}: {:?}", self.repo, sha, e);
+        }
+    }
+
+    fn callback(&self, url: &str, body: serde_json::Value) {
+        let result = reqwest::blocking::Client::new()
+            .post(url)
+            .json(&body)
+            .send()
+            .and_then(|r| r.error_for_status());
+        if let Err(e) = result {
+            error!("Failed to call back {}: {:?}", url, e);
         }
     }
 }
@@ -145,7 +531,7 @@ fn from_hex(hex_str: &str) -> AppResult<Vec<u8>> {
             b'0'..=b'9' => Ok(digit - b'0'),
             b'A'..=b'F' => Ok(10 + digit - b'A'),
             b'a'..=b'f' => Ok(10 + digit - b'a'),
-            _ => return Err(StatusCode::BAD_REQUEST),
+            _ => Err(StatusCode::BAD_REQUEST),
         }
     }
 
@@ -187,3 +573,50 @@ fn verify_github_request(
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_head_sha() {
+        let body = |v: serde_json::Value| v.as_object().unwrap().clone();
+        let push = body(serde_json::json!({ "after": "abc" }));
+        assert_eq!(head_sha("push", &push).as_deref(), Some("abc"));
+        let pr = body(serde_json::json!({ "pull_request": { "head": { "sha": "def" } } }));
+        assert_eq!(head_sha("pull_request", &pr).as_deref(), Some("def"));
+        assert_eq!(head_sha("issues", &push), None);
+    }
+
+    #[test]
+    fn test_check_run_output() {
+        let output = check_run_output(200, b"all good");
+        assert_eq!(output.conclusion.as_deref(), Some("success"));
+        assert_eq!(output.summary.as_deref(), Some("all good"));
+        assert_eq!(check_run_output(500, b"").conclusion.as_deref(), Some("failure"));
+        let output = check_run_output(200, br#"{"conclusion": "neutral", "title": "lint"}"#);
+        assert_eq!(output.conclusion.as_deref(), Some("neutral"));
+        assert_eq!(output.title.as_deref(), Some("lint"));
+    }
+
+    #[test]
+    fn test_verify_github_request() {
+        use ring::hmac;
+        let secret = Some("s3cret".to_string());
+        let payload = Bytes::from_static(b"{}");
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, b"s3cret");
+        let tag: String = hmac::sign(&key, &payload)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let tag = format!("sha1={}", tag);
+        assert!(verify_github_request(&secret, &payload, Some(tag.as_bytes())).is_ok());
+        assert_eq!(
+            verify_github_request(&secret, &Bytes::from_static(b"[]"), Some(tag.as_bytes())),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(verify_github_request(&secret, &payload, None), Err(StatusCode::UNAUTHORIZED));
+        assert!(verify_github_request(&None, &payload, None).is_ok());
+    }
+}
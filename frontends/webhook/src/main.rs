@@ -11,10 +11,18 @@ fn main() -> Result<(), std::io::Error> {
             Arg::with_name("snapfaas address")
                 .short("p")
                 .long("snapfaas_address")
-                .value_name("[ADDR:]PORT")
+                .value_name("ADDR")
                 .takes_value(true)
                 .required(true)
-                .help("Path to snapfaas config YAML file"),
+                .help("Address of the Faasten scheduler, ADDR:PORT or unix://PATH"),
+        )
+        .arg(
+            Arg::with_name("app config")
+                .long("app_config")
+                .value_name("YAML_PATH")
+                .takes_value(true)
+                .required(false)
+                .help("Path to the per-repository configuration YAML file"),
         )
         .arg(
             Arg::with_name("secret")
@@ -36,15 +44,24 @@ fn main() -> Result<(), std::io::Error> {
         )
         .get_matches();
 
+    let config = match matches.value_of("app config") {
+        Some(path) => serde_yaml::from_reader(std::fs::File::open(path)?)
+            .expect("parse the app config"),
+        None => app::AppConfig::default(),
+    };
     let app = app::App::new(
         matches.value_of("secret").map(ToString::to_string),
-        matches.value_of("snapfaas address").unwrap().to_string()
+        matches.value_of("snapfaas address").unwrap().to_string(),
+        config,
     );
     let listen_addr = matches.value_of("listen").unwrap();
-    let listener = TcpListener::bind(listen_addr).unwrap();
+    // the socket systemd passed if there is one
+    let listener = snapfaas::systemd::tcp_listener()
+        .unwrap_or_else(|| TcpListener::bind(listen_addr).unwrap());
     let server = httpserver::Server::new(
         listener,
         app
     );
+    snapfaas::systemd::notify_ready();
     server.run()
 }
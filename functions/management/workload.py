@@ -0,0 +1,90 @@
+import json
+from contextlib import ExitStack
+
+from syscalls import Gate, OpenError, ResponseDict
+
+# Platform management over the normal invoke path. Installed during the system bootstrapping as
+# a gate at "home:<T,faasten>:management" that grants the "faasten" privilege and only admits
+# invokers endorsed by "faasten". Paths are lists of path components, as in fsutil.
+
+def open_blobs(syscall, stack, args):
+    images = {}
+    for key in ["app_image", "runtime", "kernel"]:
+        if args.get(key):
+            images[key] = stack.enter_context(syscall.root().open_at(args[key]))
+    layers = [stack.enter_context(syscall.root().open_at(layer)) for layer in args.get("app_layers", [])]
+    return images, layers
+
+def component(syscall, args, key):
+    return args.get(key) and syscall.buckle_parse(args[key] + ",T").secrecy
+
+def deploy(syscall, args):
+    with ExitStack() as stack:
+        images, layers = open_blobs(syscall, stack, args)
+        privilege = component(syscall, args, "privilege")
+        clearance = component(syscall, args, "clearance")
+        dir = stack.enter_context(syscall.root().open_at(args["base"]))
+        if args["name"] in dir.ls():
+            gate = stack.enter_context(dir.open_at([args["name"]]))
+            res = gate.update_direct(privilege=privilege, invoker_clearance=clearance,
+                                     memory=args.get("memory"), **images)
+            return {"success": res.success, "updated": True}
+        res = syscall.dent_create_direct_gate(
+            syscall.buckle_parse(args["label"]),
+            privilege,
+            clearance,
+            args["memory"],
+            images["app_image"],
+            images["runtime"],
+            images["kernel"],
+            keep_alive=args.get("keep_alive", False),
+            min_instances=args.get("min_instances", 0),
+            idle_ttl=args.get("idle_ttl"),
+            requirements=args.get("requirements", {}),
+            scratch=args.get("scratch", 0),
+            app_layers=layers,
+            forward_headers=args.get("forward_headers", []),
+            concurrency_target=args.get("concurrency_target", 0))
+        if res is None or not res.success:
+            return {"success": False}
+        linked = syscall.link(dir.fd, res.fd, args["name"])
+        return {"success": linked is not None and linked.success, "updated": False}
+
+def update(syscall, args):
+    with ExitStack() as stack:
+        images, _ = open_blobs(syscall, stack, args)
+        gate = stack.enter_context(syscall.root().open_at(args["path"]))
+        res = gate.update_direct(privilege=component(syscall, args, "privilege"),
+                                 invoker_clearance=component(syscall, args, "clearance"),
+                                 memory=args.get("memory"), **images)
+        return {"success": res.success}
+
+def list_gates(syscall, args):
+    gates = {}
+    with syscall.root().open_at(args["path"]) as dir:
+        for name in dir.ls():
+            try:
+                with dir.open_at([name]) as entry:
+                    if isinstance(entry, Gate):
+                        gates[name] = entry.ls()
+            except OpenError:
+                continue
+    return {"success": True, "value": gates}
+
+OPS = {
+    "deploy": deploy,
+    "update": update,
+    "list": list_gates,
+}
+
+def handle(syscall, payload=b'', blobs={}, **kwargs):
+    request = json.loads(payload)
+    op = OPS.get(request.get("op"))
+    if op is None:
+        return ResponseDict({"success": False, "error": "[management] unknown op"}, 400)
+    try:
+        return ResponseDict(op(syscall, request.get("args", {})))
+    except OpenError:
+        return ResponseDict({"success": False, "error": "[management] no such path"}, 404)
+    except KeyError as e:
+        return ResponseDict({"success": False, "error": "[management] missing " + str(e)}, 400)
@@ -1,5 +1,24 @@
 #!/usr/bin/env sh
 
-/bin/mount -r /dev/vdb /srv
+# the appfs is the bottom layer of a layered app image, whose other layers follow it as
+# /dev/vdc, /dev/vdd, ...
+layers=$(sed -n 's/.*faasten\.layers=\([0-9]*\).*/\1/p' /proc/cmdline)
+if [ -n "$layers" ]; then
+    /bin/mount -t tmpfs -o size=1m tmpfs /mnt
+    mkdir /mnt/base
+    /bin/mount -r /dev/vdb /mnt/base
+    lower=/mnt/base
+    i=0
+    for dev in c d e f g h i j k l m n o p q r s t u v w x y z; do
+        [ "$i" -lt "$layers" ] || break
+        mkdir /mnt/$i
+        /bin/mount -r /dev/vd$dev /mnt/$i
+        lower=/mnt/$i:$lower
+        i=$((i + 1))
+    done
+    /bin/mount -t overlay overlay -o lowerdir=$lower /srv
+else
+    /bin/mount -r /dev/vdb /srv
+fi
 
 NODE_PATH=$NODE_PATH:/usr/local/lib/node_modules node /bin/runtime-workload.js
@@ -1,5 +1,7 @@
 from abc import abstractmethod
 import syscalls_pb2
+import os
+import resource
 import socket
 import struct
 import json
@@ -8,7 +10,14 @@ from contextlib import contextmanager
 
 HttpVerb = syscalls_pb2.HttpVerb
 
+# bodies smaller than this are sent inline even if the VM has a shared memory region
+SHM_THRESHOLD = 64 * 1024
+
 ### helper functions ###
+def peak_memory():
+    # ru_maxrss is in KB on Linux
+    return resource.getrusage(resource.RUSAGE_SELF).ru_maxrss // 1024
+
 def recvall(sock, n):
     # Helper function to recv n bytes or return None if EOF is hit
     data = bytearray()
@@ -85,6 +94,20 @@ class DirEntry():
         self.fd = fd
         self.syscall = syscall
 
+    def stat(self):
+        return self.syscall.dent_stat(self.fd)
+
+    def download_token(self, ttl: int = 3600):
+        """Returns a token that lets anyone download this file or blob for `ttl` seconds at
+        <webfront>/faasten/download/<token>, or None if the function can't release it"""
+        req = syscalls_pb2.Syscall(dentDownloadToken=syscalls_pb2.DentDownloadToken(fd=self.fd, ttl=ttl))
+        self.syscall._send(req)
+        response = self.syscall._recv(syscalls_pb2.DownloadTokenResult())
+        if response.success:
+            return response.token
+        else:
+            return None
+
 class Directory(DirEntry):
     def ls(self):
         req = syscalls_pb2.Syscall(dentList = self.fd)
@@ -127,6 +150,8 @@ class Directory(DirEntry):
                 yield Service(cur_dent, self.syscall)
             case syscalls_pb2.DentBlob:
                 yield BlobEntry(cur_dent, self.syscall)
+            case syscalls_pb2.DentLog:
+                yield Log(cur_dent, self.syscall)
             case _:
                 yield DirEntry(cur_dent, self.syscall)
         req = syscalls_pb2.Syscall(dentClose=cur_dent)
@@ -143,12 +168,43 @@ class File(DirEntry):
         else:
             return None
 
+    def etag(self):
+        """Returns the hash of the file's content, which changes whenever the content does"""
+        response = self.stat()
+        if response.success:
+            return response.etag
+        else:
+            return None
+
     def write(self, data):
         req = syscalls_pb2.Syscall(dentUpdate=syscalls_pb2.DentUpdate(fd=self.fd, file=data))
         self.syscall._send(req)
         response = self.syscall._recv(syscalls_pb2.DentResult())
         return response.success
 
+class Log(DirEntry):
+    def append(self, data: bytes):
+        """Appends `data` to the log and returns its index, or None on failure"""
+        req = syscalls_pb2.Syscall(dentLogAppend=syscalls_pb2.DentLogAppend(fd=self.fd, data=data))
+        self.syscall._send(req)
+        response = self.syscall._recv(syscalls_pb2.LogResult())
+        if response.success:
+            return response.index
+        else:
+            return None
+
+    def read_range(self, start: int, end: int):
+        """Returns the entries with indices in [start, end), at most 1024 at a time, as a list of
+        (time in milliseconds, data) pairs, or None on failure"""
+        req = syscalls_pb2.Syscall(
+            dentLogRead=syscalls_pb2.DentLogRead(fd=self.fd, to=end, **{'from': start}))
+        self.syscall._send(req)
+        response = self.syscall._recv(syscalls_pb2.LogResult())
+        if response.success:
+            return [(e.time, e.data) for e in response.entries]
+        else:
+            return None
+
 class FacetedDirectory(DirEntry):
     def ls(self):
         req = syscalls_pb2.Syscall(dentLsFaceted = syscalls_pb2.DentLsFaceted(fd = self.fd))
@@ -177,6 +233,18 @@ class Gate(DirEntry):
         response = self.syscall._recv(syscalls_pb2.DentInvokeResult())
         return response
 
+    def invoke_stream(self, payload: bytes = b"", params: dict[str,str] = {}):
+        """Invokes the gate synchronously and yields a DentInvokeResult per response chunk as
+        it arrives, the last one (with `more` unset) carrying the response body. The results
+        must be consumed before issuing another syscall."""
+        req = syscalls_pb2.Syscall(dentInvoke=syscalls_pb2.DentInvoke(fd=self.fd, payload=payload, sync=True, parameters=params, stream=True))
+        self.syscall._send(req)
+        while True:
+            response = self.syscall._recv(syscalls_pb2.DentInvokeResult())
+            yield response
+            if not response.more:
+                break
+
     def ls(self):
         req = syscalls_pb2.Syscall(dentLsGate=self.fd)
         self.syscall._send(req)
@@ -282,6 +350,9 @@ class Blob():
 class Syscall():
     def __init__(self, sock):
         self.sock = sock
+        # fd and size of the shared memory region, opened on the first request
+        self.shm = None
+        self.shm_size = 0
 
     def _send(self, obj):
         objData = obj.SerializeToString()
@@ -296,17 +367,60 @@ class Syscall():
         obj.ParseFromString(objData)
         return obj
 
+    def _shm_read(self, ref):
+        # the host writes to the region behind the guest's page cache
+        os.posix_fadvise(self.shm, 0, 0, os.POSIX_FADV_DONTNEED)
+        data = bytearray()
+        while len(data) < ref.length:
+            chunk = os.pread(self.shm, ref.length - len(data), ref.offset + len(data))
+            if not chunk:
+                break
+            data.extend(chunk)
+        return bytes(data)
+
+    def _shm_write(self, data):
+        os.pwrite(self.shm, data, 0)
+        os.fsync(self.shm)
+        return syscalls_pb2.ShmRef(offset = 0, length = len(data))
+
     def request(self):
-        request = syscalls_pb2.Request()
-        return self._recv(request)
+        request = self._recv(syscalls_pb2.Request())
+        if request.shmDevice and self.shm is None:
+            self.shm = os.open(request.shmDevice, os.O_RDWR)
+            self.shm_size = request.shmSize
+        if request.HasField('payloadShm'):
+            request.payload = self._shm_read(request.payloadShm)
+        return request
 
     def respond(self, resp: Response):
-        response = syscalls_pb2.Syscall(response = syscalls_pb2.Response(body = resp.body_to_bytes(), statusCode = resp.status_code()))
-        self._send(response)
+        body = resp.body_to_bytes()
+        if self.shm is not None and SHM_THRESHOLD <= len(body) <= self.shm_size:
+            response = syscalls_pb2.Response(bodyShm = self._shm_write(body), statusCode = resp.status_code())
+        else:
+            response = syscalls_pb2.Response(body = body, statusCode = resp.status_code())
+        response.peakMemory = peak_memory()
+        self._send(syscalls_pb2.Syscall(response = response))
+
+    def abort(self, reason: str, traceback: str, out_of_memory: bool = False):
+        """Ends the invocation with an unhandled error instead of a response"""
+        abort = syscalls_pb2.Abort(reason = reason, traceback = traceback, outOfMemory = out_of_memory)
+        self._send(syscalls_pb2.Syscall(abort = abort))
+
+    def respond_chunk(self, data: bytes):
+        """Sends part of the response ahead of `respond`. Streaming invokers get it right away,
+        others get it prepended to the response body."""
+        self._send(syscalls_pb2.Syscall(responseChunk = data))
 
     def root(self):
         return Directory(0, self)
 
+    def dent_stat(self, fd: int):
+        """Returns the kind, label and, for files, the content hash of an open entry. For blobs,
+        also the size and content type, if recorded."""
+        req = syscalls_pb2.Syscall(dentStat = fd)
+        self._send(req)
+        return self._recv(syscalls_pb2.DentStatResult())
+
     def dent_list(self, fd: int):
         """Returns a JSON object"""
         req = syscalls_pb2.Syscall(dentList = fd)
@@ -325,15 +439,22 @@ class Syscall():
         self._send(req)
         return self._recv(syscalls_pb2.DentResult())
 
+    def dent_create_log(self, label: syscalls_pb2.Buckle):
+        req = syscalls_pb2.Syscall(
+            dentCreate = syscalls_pb2.DentCreate(label = label, log = syscalls_pb2.Void()))
+        self._send(req)
+        return self._recv(syscalls_pb2.DentResult())
+
     def dent_create_faceted(self):
         req = syscalls_pb2.Syscall(
             dentCreate = syscalls_pb2.DentCreate(facetedDirectory = syscalls_pb2.Void()))
         self._send(req)
         return self._recv(syscalls_pb2.DentResult())
 
-    def dent_create_blob(self, label: syscalls_pb2.Buckle, blobfd: int):
+    def dent_create_blob(self, label: syscalls_pb2.Buckle, blobfd: int, content_type: str = None):
         req = syscalls_pb2.Syscall(
-            dentCreate = syscalls_pb2.DentCreate(label = label, blob = blobfd))
+            dentCreate = syscalls_pb2.DentCreate(label = label, blob = blobfd,
+                                                 contentType = content_type))
         self._send(req)
         return self._recv(syscalls_pb2.DentResult())
 
@@ -343,7 +464,16 @@ class Syscall():
                                 memory: int,
                                 app_image: BlobEntry,
                                 runtime: BlobEntry,
-                                kernel: BlobEntry):
+                                kernel: BlobEntry,
+                                keep_alive: bool = False,
+                                min_instances: int = 0,
+                                idle_ttl: int = None,
+                                requirements: dict[str,str] = {},
+                                scratch: int = 0,
+                                app_layers: list[BlobEntry] = [],
+                                forward_headers: list[str] = [],
+                                concurrency_target: int = 0,
+                                idempotent: bool = False):
         directGate = syscalls_pb2.DirectGate(
             privilege = privilege,
             invoker_integrity_clearance = invoker_clearance,
@@ -351,7 +481,16 @@ class Syscall():
                 memory = memory,
                 appImage = app_image.fd,
                 runtime = runtime.fd,
-                kernel = kernel.fd
+                kernel = kernel.fd,
+                keepAlive = keep_alive,
+                minInstances = min_instances,
+                idleTtl = idle_ttl,
+                requirements = requirements,
+                scratch = scratch,
+                appLayers = [layer.fd for layer in app_layers],
+                forwardHeaders = forward_headers,
+                concurrencyTarget = concurrency_target,
+                idempotent = idempotent
             )
         )
 
@@ -419,6 +558,94 @@ class Syscall():
         self._send(req)
         return self._recv(syscalls_pb2.DentResult())
 
+    def counter_increment(self, path: str, delta: int = 1):
+        """Atomically adds `delta` to the counter file at `path` and returns the new value,
+        or None on failure"""
+        req = syscalls_pb2.Syscall(
+            counterIncrement = syscalls_pb2.CounterIncrement(path = path, delta = delta))
+        self._send(req)
+        response = self._recv(syscalls_pb2.CounterResult())
+        if response.success:
+            return response.value
+        else:
+            return None
+
+    def lock_acquire(self, path: str, ttl_ms: int, token: int = None):
+        """Takes the lock file at `path` for `ttl_ms` milliseconds without blocking. Returns the
+        lease's token, or None if the lock is held by someone else. Passing the token of a lease
+        still held renews it."""
+        req = syscalls_pb2.Syscall(
+            lockAcquire = syscalls_pb2.LockAcquire(path = path, ttl = ttl_ms, token = token))
+        self._send(req)
+        response = self._recv(syscalls_pb2.LockResult())
+        if response.success:
+            return response.token
+        else:
+            return None
+
+    def lock_release(self, path: str, token: int):
+        """Frees the lock file at `path`. Returns False if the lease had expired."""
+        req = syscalls_pb2.Syscall(
+            lockRelease = syscalls_pb2.LockRelease(path = path, token = token))
+        self._send(req)
+        return self._recv(syscalls_pb2.LockResult()).success
+
+    def barrier_init(self, path: str, expected: int, gate: str):
+        """Sets up a barrier in the file at `path`. Once `expected` results arrive, at least
+        one, the gate at `gate` is invoked with a JSON list of them and the caller's privilege,
+        limited to the integrity of the file's label."""
+        req = syscalls_pb2.Syscall(
+            barrierInit = syscalls_pb2.BarrierInit(path = path, expected = expected, gate = gate))
+        self._send(req)
+        return self._recv(syscalls_pb2.BarrierResult()).success
+
+    def barrier_arrive(self, path: str, result: str):
+        """Adds `result` to the barrier in the file at `path`. Returns the BarrierResult, or
+        None if the file is not a barrier or all its results already arrived."""
+        req = syscalls_pb2.Syscall(
+            barrierArrive = syscalls_pb2.BarrierArrive(path = path, result = result))
+        self._send(req)
+        response = self._recv(syscalls_pb2.BarrierResult())
+        if response.success:
+            return response
+        else:
+            return None
+
+    def scratch_write(self, name: str, data: bytes, offset: int = None):
+        """Writes `data` at `offset` of the scratch file `name`, or appends it if `offset` is
+        None. Returns the file's new size, or None if the gate's scratch space would overflow."""
+        req = syscalls_pb2.Syscall(
+            scratchWrite = syscalls_pb2.ScratchWrite(name = name, data = data, offset = offset))
+        self._send(req)
+        response = self._recv(syscalls_pb2.ScratchResult())
+        if response.success:
+            return response.size
+        else:
+            return None
+
+    def scratch_read(self, name: str, offset: int = 0, length: int = 1 << 20):
+        """Reads up to `length` bytes at `offset` of the scratch file `name`, None if it does not
+        exist"""
+        req = syscalls_pb2.Syscall(
+            scratchRead = syscalls_pb2.ScratchRead(name = name, offset = offset, length = length))
+        self._send(req)
+        response = self._recv(syscalls_pb2.ScratchResult())
+        if response.success:
+            return response.data
+        else:
+            return None
+
+    def scratch_remove(self, name: str):
+        req = syscalls_pb2.Syscall(scratchRemove = name)
+        self._send(req)
+        return self._recv(syscalls_pb2.ScratchResult()).success
+
+    def scratch_list(self):
+        """Returns the ScratchListResult, with the sizes of the scratch files by name"""
+        req = syscalls_pb2.Syscall(scratchList = syscalls_pb2.Void())
+        self._send(req)
+        return self._recv(syscalls_pb2.ScratchListResult())
+
 
     ## OLD
 
@@ -528,6 +755,20 @@ class Syscall():
         return response
     ### end of github APIs ###
 
+    def invoke_gang(self, invocations, min_fraction: float = 0.0, sync: bool = True):
+        """Invokes several gates together. `invocations` is a list of (Gate, payload) pairs. The
+        scheduler starts them once it can run at least `min_fraction` of them (all of them if
+        zero). Returns a DentInvokeResult per invocation, in order, or None if any of them is not
+        an invokable gate."""
+        invokes = [syscalls_pb2.DentInvoke(fd=gate.fd, payload=payload, sync=sync)
+                   for (gate, payload) in invocations]
+        req = syscalls_pb2.Syscall(dentInvokeGang=syscalls_pb2.DentInvokeGang(invokes=invokes, minFraction=min_fraction))
+        self._send(req)
+        response = self._recv(syscalls_pb2.DentInvokeGangResult())
+        if response.success:
+            return list(response.results)
+        return None
+
     def invoke(self, gate, payload):
         req = syscalls_pb2.Syscall(invoke = syscalls_pb2.Invoke(gate = convert_path(gate), payload = payload))
         self._send(req)
@@ -3,32 +3,50 @@
 from importlib import import_module
 import json
 import socket
+import struct
 import sys
+import time
 import traceback
-from syscalls import Syscall, Response, ResponseDict
+from syscalls import Syscall, Response, ResponseRaw
 
 # vsock to communicate with the host
 VSOCKPORT = 1234
-sock = socket.socket(socket.AF_VSOCK, socket.SOCK_STREAM)
 hostaddr = (socket.VMADDR_CID_HOST, VSOCKPORT)
 
+def connect():
+    # a worker that restarted takes idle VMs back once they reconnect
+    while True:
+        sock = socket.socket(socket.AF_VSOCK, socket.SOCK_STREAM)
+        try:
+            sock.connect(hostaddr)
+            return sock
+        except OSError:
+            sock.close()
+            time.sleep(0.1)
+
 app = import_module('workload')
 
-sock.connect(hostaddr)
-sc = Syscall(sock)
+sc = Syscall(connect())
 while True:
     try:
         request = sc.request()
+    except (OSError, struct.error):
+        # the worker went away
+        sc = Syscall(connect())
+        continue
+    try:
+        if request.init:
+            # optional warmup entrypoint, run once before the first request
+            if hasattr(app, 'init'):
+                app.init(sc)
+            sc.respond(ResponseRaw(b''))
+            continue
         response = app.handle(sc, payload=request.payload, blobs=request.blobs, headers=request.headers, invoker=request.invoker)
         assert(isinstance(response, Response))
         sc.respond(response)
     except:
         ty, val, tb = sys.exc_info()
-        response = {
-            'error': {
-                'type': str(ty),
-                'value': str(val),
-                'traceback': traceback.format_tb(tb),
-            },
-        }
-        sc.respond(ResponseDict(response))
+        try:
+            sc.abort('%s: %s' % (ty.__name__, val), ''.join(traceback.format_tb(tb)), ty is MemoryError)
+        except OSError:
+            pass
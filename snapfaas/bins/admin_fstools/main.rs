@@ -2,20 +2,22 @@
 //!
 //! The preparer installs supported kernels and runtime images in the directory ``home:^T,faasten''.
 //! Kernels and runtime images are stored as blobs.
+//!
+//! Actions run with faasten's privilege, as whoever can open the store is trusted with all of it
+//! already. With `--token`, they run as the principal a login token speaks for instead, and with
+//! `--sudo` as well, elevate to faasten's privilege if the principal is a sudoer.
 
-use clap::{Parser, Subcommand};
-use jwt::{PKeyWithDigest, SignWithKey};
-use labeled::buckle::{Buckle, Component};
+use clap::{error::ErrorKind, CommandFactory, FromArgMatches, Parser, Subcommand};
+use labeled::buckle::{Buckle, Component, Principal};
 use openssl::pkey::PKey;
-use serde::{Deserialize, Serialize};
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
 use snapfaas::{
     blobstore, cli,
-    fs::{BackingStore, FS},
+    fs::{aliases::LabelAliases, BackingStore, FS},
 };
 use std::{
-    io::{stdout, Write},
-    time::SystemTime,
+    io::{stdin, stdout, BufRead, Write},
+    path::PathBuf,
 };
 
 #[derive(Parser)]
@@ -25,6 +27,22 @@ struct Cli {
     action: Action,
     #[command(flatten)]
     store: cli::Store,
+    #[command(flatten)]
+    identity: Identity,
+}
+
+#[derive(clap::Args, Debug)]
+struct Identity {
+    /// File holding a login token. Acts as the principal the token speaks for instead of faasten.
+    #[arg(long, value_name = "PATH", requires = "verify_key")]
+    token: Option<PathBuf>,
+    /// Public key login tokens are signed with
+    #[arg(long, value_name = "PEM")]
+    verify_key: Option<PathBuf>,
+    /// Also act with faasten's privilege. The token's principal must be a sudoer. The elevation
+    /// is recorded in the audit log.
+    #[arg(long, requires = "token")]
+    sudo: bool,
 }
 
 #[derive(Parser, Debug)]
@@ -42,10 +60,50 @@ struct UpdateImage {
 }
 
 #[derive(Parser, Debug)]
-struct FaastenPath {
+struct DeletePath {
+    /// Faasten path
+    #[arg(value_name = "FAASTEN_PATH")]
+    path: String,
+    /// Delete a directory and everything under it
+    #[arg(short, long, conflicts_with = "trash")]
+    recursive: bool,
+    /// Move to the facet's trash directory, restorable for SECS seconds
+    #[arg(long, value_name = "SECS")]
+    trash: Option<u64>,
+}
+
+#[derive(Parser, Debug)]
+struct Restore {
+    /// Faasten path of the directory to restore into
+    #[arg(value_name = "FAASTEN_PATH")]
+    dir: String,
+    /// Name of the entry in the facet's trash directory, EXPIRES@NAME
+    #[arg(value_name = "NAME")]
+    trashed: String,
+}
+
+#[derive(Parser, Debug)]
+struct ReadPath {
     /// Faasten path
     #[arg(value_name = "FAASTEN_PATH")]
     path: String,
+    /// Label reading may taint the session up to. Fails without reading anything labeled above
+    /// it. Reads anything if not given.
+    #[arg(long, value_name = "BUCKLE")]
+    clearance: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct Invoke {
+    /// Faasten path of the gate
+    #[arg(value_name = "FAASTEN_PATH")]
+    path: String,
+    /// Address of the scheduler, ADDR:PORT or unix://PATH
+    #[arg(short, long, value_name = "ADDR")]
+    scheduler: String,
+    /// Header of the invocations. Repeat for more.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    header: Vec<(String, String)>,
 }
 
 #[derive(Parser, Debug)]
@@ -59,6 +117,29 @@ struct CreateBlob {
     /// Label of the blob in Faasten
     #[arg(value_name = "BUCKLE")]
     label: String,
+    /// Content type recorded on the blob entry and sent with downloads
+    #[arg(long)]
+    content_type: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct BuildImage {
+    /// Faasten path of a blob holding the function's source as a tar archive, with a
+    /// requirements.txt at its root if the function needs packages
+    #[arg(long, value_name = "FAASTEN_PATH")]
+    source: String,
+    /// Local requirements.txt to install instead of the source's
+    #[arg(long, value_name = "LOCAL_PATH")]
+    requirements: Option<String>,
+    /// pip of the Python version the runtime image runs
+    #[arg(long, value_name = "CMD", default_value = "pip3")]
+    pip: String,
+    /// Label of the image blob. Uses the gate's label if not given.
+    #[arg(long, value_name = "BUCKLE")]
+    image_label: Option<String>,
+    /// The gate to register. The image is stored at its --app-image.
+    #[command(flatten)]
+    gate: NewGate,
 }
 
 #[derive(Parser, Debug)]
@@ -71,6 +152,105 @@ struct Mkdir {
     label: String,
 }
 
+#[derive(Parser, Debug)]
+struct NewGate {
+    /// Faasten path of the new gate
+    #[arg(value_name = "FAASTEN_PATH")]
+    path: String,
+    /// Label of the gate in Faasten
+    #[arg(value_name = "BUCKLE")]
+    label: String,
+    /// Privilege of the gate
+    #[arg(long, value_name = "COMPONENT")]
+    privilege: String,
+    /// Integrity an invoker must have to invoke the gate
+    #[arg(long, value_name = "COMPONENT", default_value = "T")]
+    invoker_integrity_clearance: String,
+    /// Memory size of the function in MB
+    #[arg(long, value_name = "MB", default_value_t = 128)]
+    memory: usize,
+    /// Faasten path of the app image blob
+    #[arg(long, value_name = "FAASTEN_PATH")]
+    app_image: String,
+    /// Name of the runtime image installed by bootstrap
+    #[arg(long, value_name = "NAME", default_value = "python")]
+    runtime: String,
+    /// Version of the kernel installed by bootstrap. Uses the default kernel if not given.
+    #[arg(long, value_name = "VERSION")]
+    kernel: Option<String>,
+    /// Class of a passthrough device the function needs, e.g. gpu. Repeat for more devices.
+    #[arg(long, value_name = "CLASS")]
+    device: Vec<String>,
+    /// Keep the function's VMs up between invocations
+    #[arg(long)]
+    keep_alive: bool,
+    /// Idle VMs of the function to keep warm
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    min_instances: usize,
+    /// Boot another VM for invocations waiting on busy VMs of the function
+    #[arg(long)]
+    speculative_boot: bool,
+    /// Running the function again with the same payload is harmless, so preemptible
+    /// invocations of it may be stopped and run again later
+    #[arg(long)]
+    idempotent: bool,
+    /// Seconds idle VMs of the function live, the worker's default if not given
+    #[arg(long, value_name = "SECS")]
+    idle_ttl: Option<u64>,
+    /// Attribute a worker must advertise with VALUE to run the function. Repeat for more.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    require: Vec<(String, String)>,
+    /// Invocation header passed to the function, * for all. Repeat for more. Others are stripped.
+    #[arg(long, value_name = "NAME")]
+    forward_header: Vec<String>,
+    /// Invocations a worker node is expected to run at once, for autoscaling. 0 for the
+    /// scheduler's default.
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    concurrency_target: usize,
+    /// MiB of scratch space each invocation gets
+    #[arg(long, value_name = "MiB", default_value_t = 0)]
+    scratch: usize,
+    /// Faasten path of a layer blob overlaid on the app image. Repeat for more, the last on top.
+    #[arg(long, value_name = "FAASTEN_PATH")]
+    app_layer: Vec<String>,
+    /// Repository the app image was built from, recorded for supply-chain audits
+    #[arg(long, value_name = "URL")]
+    source_repo: Option<String>,
+    /// Revision of --source-repo the app image was built from
+    #[arg(long, value_name = "REV")]
+    commit: Option<String>,
+    /// Who or what built the app image
+    #[arg(long, value_name = "NAME")]
+    builder: Option<String>,
+    /// Faasten path of a blob holding a software bill of materials of the app image
+    #[arg(long, value_name = "FAASTEN_PATH")]
+    sbom: Option<String>,
+    /// What the gate does, shown in listings
+    #[arg(long)]
+    description: Option<String>,
+    /// Tag shown in listings. Repeat for more tags.
+    #[arg(long, value_name = "TAG")]
+    tag: Vec<String>,
+    /// A payload to try the function with
+    #[arg(long, value_name = "PAYLOAD")]
+    example_payload: Option<String>,
+    /// Faasten path of a directory to store the results of async invocations in
+    #[arg(long, value_name = "FAASTEN_PATH")]
+    results_dir: Option<String>,
+    /// Keep only this many of the most recent results
+    #[arg(long, value_name = "N", requires = "results_dir")]
+    keep_last: Option<usize>,
+    /// Keep results for this many days
+    #[arg(long, value_name = "DAYS", requires = "results_dir")]
+    keep_days: Option<u64>,
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| "expected KEY=VALUE".to_string())
+}
+
 #[derive(Parser, Debug)]
 struct Jwt {
     #[arg(value_name = "Component")]
@@ -79,6 +259,16 @@ struct Jwt {
     secret_key: std::ffi::OsString,
 }
 
+#[derive(Parser, Debug)]
+struct SignImage {
+    /// Faasten paths of the kernel, runtime or app image blobs to sign
+    #[arg(value_name = "FAASTEN_PATH", required = true)]
+    paths: Vec<String>,
+    /// Path of the PEM encoded private key to sign with
+    #[arg(short = 'k', long, value_name = "PATH")]
+    secret_key: std::ffi::OsString,
+}
+
 #[derive(Parser, Debug)]
 struct GenKeypair {
     /// Faasten path to store the private key
@@ -97,6 +287,147 @@ struct GenKeypair {
     public_key: String,
 }
 
+#[derive(Parser, Debug)]
+struct Explain {
+    /// Current label
+    #[arg(value_name = "BUCKLE")]
+    current: String,
+    /// Target label
+    #[arg(value_name = "BUCKLE")]
+    target: String,
+    /// Privilege used for the flow
+    #[arg(short, long, value_name = "COMPONENT", default_value = "T")]
+    privilege: String,
+}
+
+#[derive(Parser, Debug)]
+struct AnalyzeGate {
+    /// Faasten path of the gate
+    #[arg(value_name = "FAASTEN_PATH")]
+    path: String,
+    /// Privilege of the invoker
+    #[arg(long, value_name = "COMPONENT", default_value = "T")]
+    invoker: String,
+    /// Label the invoker's payload carries
+    #[arg(long, value_name = "BUCKLE", default_value = "T,T")]
+    invoker_label: String,
+    /// Label of the consumer of the result
+    #[arg(long, value_name = "BUCKLE", default_value = "T,T")]
+    consumer: String,
+    /// Privilege of the consumer of the result
+    #[arg(long, value_name = "COMPONENT", default_value = "T")]
+    consumer_privilege: String,
+}
+
+#[derive(Parser, Debug)]
+struct SetAlias {
+    /// Alias name
+    #[arg(value_name = "NAME")]
+    name: String,
+    /// Label the alias stands for
+    #[arg(value_name = "BUCKLE")]
+    label: String,
+}
+
+#[derive(Parser, Debug)]
+struct RemoveAlias {
+    /// Alias name
+    #[arg(value_name = "NAME")]
+    name: String,
+}
+
+#[derive(Parser, Debug)]
+struct FacetedList {
+    /// Faasten path
+    #[arg(value_name = "FAASTEN_PATH")]
+    path: String,
+    /// List only facets whose secrecy mentions PRINCIPAL or a sub-principal of it
+    #[arg(long, value_name = "PRINCIPAL", value_parser = parse_principal)]
+    secrecy: Option<Principal>,
+    /// List only facets whose integrity mentions PRINCIPAL or a sub-principal of it
+    #[arg(long, value_name = "PRINCIPAL", value_parser = parse_principal)]
+    integrity: Option<Principal>,
+    /// List only facets up to this label. Lists all facets if not given.
+    #[arg(long, value_name = "BUCKLE")]
+    clearance: Option<String>,
+}
+
+fn parse_principal(s: &str) -> Result<Principal, String> {
+    snapfaas::fs::FacetPattern::parse_principal(s).ok_or_else(|| "expected a principal".into())
+}
+
+#[derive(Parser, Debug)]
+struct Sudoer {
+    /// The principal, e.g. alice
+    #[arg(value_name = "COMPONENT")]
+    principal: String,
+}
+
+#[derive(Parser, Debug)]
+struct PublishGate {
+    /// Name of the gate in the catalog
+    #[arg(value_name = "NAME")]
+    name: String,
+    /// Faasten path of the gate
+    #[arg(value_name = "FAASTEN_PATH")]
+    path: String,
+    /// What the gate does, shown to users browsing the catalog
+    #[arg(long, default_value = "")]
+    description: String,
+}
+
+#[derive(Parser, Debug)]
+struct UnpublishGate {
+    /// Name of the gate in the catalog
+    #[arg(value_name = "NAME")]
+    name: String,
+}
+
+#[derive(Parser, Debug)]
+struct Audit {
+    /// Index of the first record to print
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    from: u64,
+    /// Records to print at most
+    #[arg(long, value_name = "N", default_value_t = snapfaas::fs::LOG_READ_MAX)]
+    limit: u64,
+}
+
+#[derive(Parser, Debug)]
+struct RightSize {
+    /// Faasten paths of the gates to size
+    #[arg(value_name = "FAASTEN_PATH", required = true)]
+    paths: Vec<String>,
+    /// Address of the scheduler, ADDR:PORT or unix://PATH
+    #[arg(short, long, value_name = "ADDR")]
+    scheduler: String,
+    /// File with the scheduler's admin token, which function statistics take
+    #[arg(long, value_name = "PATH")]
+    admin_token: std::path::PathBuf,
+    /// Memory on top of the 99th percentile peak, as a fraction of it
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.25)]
+    headroom: f64,
+    /// Update direct gates to the recommended size
+    #[arg(long)]
+    apply: bool,
+}
+
+#[derive(Parser, Debug)]
+struct MigrateStore {
+    /// Store to copy from, lmdb:PATH
+    #[arg(long, value_name = "STORE")]
+    from: String,
+    /// Store to copy to, tikv:ADDR:PORT[,ADDR:PORT...]
+    #[arg(long, value_name = "STORE")]
+    to: String,
+    /// Only copy the objects that changed since an earlier run and delete the objects removed
+    /// since, leaving keys other than object keys alone. Refuses to run while another process
+    /// has the source open, so stop all services using it first, right before switching them
+    /// to the destination.
+    #[arg(long)]
+    delta: bool,
+}
+
 #[derive(Subcommand, Debug)]
 enum Action {
     /// Bootstrap Faasten FS from the configuration file
@@ -106,26 +437,197 @@ enum Action {
     /// Update the python image
     UpdatePython(UpdateImage),
     /// List the Faasten directory
-    List(FaastenPath),
+    List(ReadPath),
     /// List the Faasten faceted directory
-    FacetedList(FaastenPath),
+    FacetedList(FacetedList),
     /// Read the Faasten file
-    Read(FaastenPath),
+    Read(ReadPath),
     /// Delete the Faasten FS object
-    Delete(FaastenPath),
+    #[command(alias = "rm")]
+    Delete(DeletePath),
+    /// Restore a trashed entry into a directory
+    Restore(Restore),
     /// Create a blob from a local file
     CreateBlob(CreateBlob),
     /// Create a directory
     Mkdir(Mkdir),
+    /// Create a direct gate
+    NewGate(NewGate),
     /// Generate JWT
     Jwt(Jwt),
     /// Generate a key pair and store them in Faasten storage
     GenKeypair(GenKeypair),
+    /// Sign image blobs for workers that only boot signed images
+    SignImage(SignImage),
+    /// Explain whether a flow between two labels is allowed
+    Explain(Explain),
+    /// Report a gate's effective policy and whether an invoker can ever produce a result a
+    /// consumer may read
+    AnalyzeGate(AnalyzeGate),
+    /// Register a label alias
+    SetAlias(SetAlias),
+    /// Remove a label alias
+    RemoveAlias(RemoveAlias),
+    /// List label aliases
+    ListAliases,
+    /// Allow a principal to elevate to faasten's privilege with `--sudo`
+    AddSudoer(Sudoer),
+    /// Disallow a principal to elevate to faasten's privilege
+    RemoveSudoer(Sudoer),
+    /// Publish a gate in the public gate catalog
+    PublishGate(PublishGate),
+    /// Remove a gate from the public gate catalog
+    UnpublishGate(UnpublishGate),
+    /// Unlink expired entries from the trash directories of all home facets
+    PurgeTrash,
+    /// Print the records of gate and service updates, one JSON object per line
+    Audit(Audit),
+    /// Recommend memory sizes for gates from the peak memory their invocations used
+    RightSize(RightSize),
+    /// Copy every object from one backing store to another and verify the copies
+    MigrateStore(MigrateStore),
+    /// Reclaim the free pages of an LMDB store. Nothing else may have the store open.
+    Compact,
+    /// Move objects of a store created before object keys were prefixed by kind to their new keys.
+    /// Nothing else may use the store meanwhile, and nothing else starts on the store until it ran.
+    PartitionKeys,
+    /// Build the app image of a Python function from its source and register a gate running it
+    BuildImage(BuildImage),
+    /// Invoke the gate with each line of stdin as the payload and print the results
+    Invoke(Invoke),
+}
+
+impl Action {
+    // actions that bypass labels, which a token's principal may only run with --sudo
+    fn bypasses_labels(&self) -> bool {
+        matches!(
+            self,
+            Action::Bootstrap(_)
+                | Action::UpdateFsutil(_)
+                | Action::UpdatePython(_)
+                | Action::PurgeTrash
+                | Action::PartitionKeys
+        )
+    }
+}
+
+// The principal a login token speaks for
+fn authenticate<S: BackingStore>(
+    fs: &FS<S>,
+    token: &PathBuf,
+    verify_key: &PathBuf,
+) -> Result<Component, String> {
+    let token =
+        std::fs::read_to_string(token).map_err(|e| format!("Cannot read the token. {}", e))?;
+    let key = std::fs::read(verify_key).map_err(|e| format!("Cannot read the key. {}", e))?;
+    let key = PKey::public_key_from_pem(&key).map_err(|e| format!("Bad key. {}", e))?;
+    let claims = snapfaas::login::Claims::verify(token.trim(), &key)
+        .map_err(|e| format!("Bad token. {:?}", e))?;
+    let sid = claims.sid.ok_or("Bad token. It has no login session.")?;
+    snapfaas::session::lookup(fs, &sid).ok_or("The token's login session expired or was revoked.")?;
+    Ok(claims.sub)
+}
+
+// The privilege actions run with, see the module documentation
+fn privilege<S: BackingStore>(
+    fs: &FS<S>,
+    identity: &Identity,
+    action: &Action,
+) -> Result<Component, String> {
+    use snapfaas::fs::{audit, bootstrap::FAASTEN_PRIV, sudoers::Sudoers};
+
+    let (Some(token), Some(verify_key)) = (&identity.token, &identity.verify_key) else {
+        return Ok(FAASTEN_PRIV.clone());
+    };
+    let principal = authenticate(fs, token, verify_key)?;
+    if !identity.sudo {
+        if action.bypasses_labels() {
+            return Err("The action needs faasten's privilege, see --sudo.".to_string());
+        }
+        return Ok(principal);
+    }
+    if !Sudoers::load(fs).permits(&principal) {
+        return Err("Not a sudoer.".to_string());
+    }
+    let command = std::env::args().skip(1).collect::<Vec<String>>().join(" ");
+    audit::record_elevation(fs, principal.clone(), command)
+        .map_err(|e| format!("Cannot record the elevation. {:?}", e))?;
+    Ok(principal & FAASTEN_PRIV.clone())
+}
+
+// Fails rather than read anything labeled above `clearance`, so that reading never taints the
+// session above it
+fn check_clearance<S: BackingStore>(
+    fs: &FS<S>,
+    path: &snapfaas::fs::path::Path,
+    clearance: &Buckle,
+) -> Result<(), String> {
+    use labeled::Label;
+
+    let entry = fs.read_path(path.clone()).map_err(|e| format!("{:?}", e))?;
+    let label = snapfaas::fs::utils::get_current_label();
+    if !label.can_flow_to(clearance) {
+        return Err(format!("The path is labeled {:?}, above the clearance.", label));
+    }
+    match entry.label(fs) {
+        Some(label) if !label.can_flow_to(clearance) => {
+            Err(format!("The entry is labeled {:?}, above the clearance.", label))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Removes the tree at `path` a bounded number of objects at a time and reports how many objects
+// it unlinked
+fn remove_recursive<S: BackingStore>(fs: &FS<S>, path: snapfaas::fs::path::Path) {
+    let mut cursor = None;
+    let mut unlinked = 0;
+    loop {
+        match fs.remove_recursive(path.clone(), cursor.as_deref(), 1000) {
+            Ok(removal) => {
+                unlinked += removal.unlinked;
+                if let Some(e) = removal.error {
+                    eprintln!("Failed to delete after unlinking {} objects. {:?}", unlinked, e);
+                    std::process::exit(1);
+                }
+                match removal.cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to delete after unlinking {} objects. {:?}", unlinked, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    println!("unlinked {} objects", unlinked);
 }
 
 pub fn main() -> std::io::Result<()> {
     env_logger::init();
-    let cli = Cli::parse();
+    // migrate-store names its stores itself
+    let mut command = Cli::command().mut_group(cli::STORE_GROUP, |group| group.required(false));
+    let cli = Cli::from_arg_matches(&command.get_matches_mut()).unwrap_or_else(|e| e.exit());
+    if let Action::MigrateStore(ms) = &cli.action {
+        migrate_store(ms);
+        return Ok(());
+    }
+    if let (Action::Compact, Some(lmdb)) = (&cli.action, cli.store.lmdb.as_ref()) {
+        match snapfaas::fs::lmdb::compact(lmdb) {
+            Ok((before, after)) => println!("compacted {} bytes to {}", before, after),
+            Err(e) => {
+                eprintln!("failed to compact {}: {}", lmdb, e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    if cli.store.tikv.is_none() && cli.store.lmdb.is_none() {
+        command
+            .error(ErrorKind::MissingRequiredArgument, "--tikv or --lmdb is required")
+            .exit();
+    }
 
     let fs: FS<Box<dyn BackingStore>> = if let Some(tikv_pds) = cli.store.tikv {
         let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
@@ -139,16 +641,33 @@ pub fn main() -> std::io::Result<()> {
             eprintln!("LMDB path does not exist: {}", lmdb);
             std::process::exit(1);
         }
-        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(lmdb)));
+        let options = cli.store.lmdb_options();
+        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::open_dbenv(lmdb, &options)));
         FS::new(Box::new(&*dbenv))
     } else {
         panic!("We shouldn't reach here.")
     };
+    if !matches!(cli.action, Action::PartitionKeys) && fs.has_legacy_keys() {
+        eprintln!("{}", snapfaas::fs::partition::LEGACY_STORE);
+        std::process::exit(1);
+    }
 
+    let privilege = privilege(&fs, &cli.identity, &cli.action).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
     let blobstore = blobstore::Blobstore::default();
+    let mut aliases = LabelAliases::load(&fs);
     match cli.action {
         Action::Bootstrap(bs) => {
-            snapfaas::fs::bootstrap::prepare_fs(&fs, &bs.yaml).expect("");
+            let config = match snapfaas::fs::bootstrap::Config::load(&bs.yaml) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Bad configuration: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            snapfaas::fs::bootstrap::prepare_fs(&fs, config).expect("");
         }
         Action::UpdatePython(ui) => {
             snapfaas::fs::bootstrap::update_python(&fs, blobstore, &ui.path);
@@ -157,9 +676,16 @@ pub fn main() -> std::io::Result<()> {
             snapfaas::fs::bootstrap::update_fsutil(&fs, blobstore, &ui.path);
         }
         Action::List(fp) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
 
             let path = snapfaas::fs::path::Path::parse(&fp.path).unwrap();
+            if let Some(clearance) = &fp.clearance {
+                let clearance = aliases.resolve(clearance).unwrap();
+                if let Err(e) = check_clearance(&fs, &path, &clearance) {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
             match fs.list_dir(path) {
                 Ok(entries) => {
                     for (name, dent) in entries {
@@ -169,23 +695,38 @@ pub fn main() -> std::io::Result<()> {
                 Err(e) => log::warn!("Failed list. {:?}", e),
             }
         }
-        Action::FacetedList(fp) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+        Action::FacetedList(fl) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
 
-            let path = snapfaas::fs::path::Path::parse(&fp.path).unwrap();
-            match fs.list_faceted(path, &Buckle::top()) {
+            let path = snapfaas::fs::path::Path::parse(&fl.path).unwrap();
+            let clearance = match &fl.clearance {
+                Some(clearance) => aliases.resolve(clearance).unwrap(),
+                None => Buckle::top(),
+            };
+            let pattern = snapfaas::fs::FacetPattern {
+                secrecy: fl.secrecy,
+                integrity: fl.integrity,
+            };
+            match fs.query_faceted(path, &clearance, &pattern) {
                 Ok(entries) => {
                     for (label, _directory) in entries {
-                        println!("{:?}", label);
+                        println!("{}", aliases.display(&label));
                     }
                 }
                 Err(e) => log::warn!("Failed list. {:?}", e),
             }
         }
         Action::Read(fp) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
 
             let path = snapfaas::fs::path::Path::parse(&fp.path).unwrap();
+            if let Some(clearance) = &fp.clearance {
+                let clearance = aliases.resolve(clearance).unwrap();
+                if let Err(e) = check_clearance(&fs, &path, &clearance) {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
             match fs.read_file(path) {
                 Ok(data) => {
                     stdout().write(&data).unwrap();
@@ -193,21 +734,47 @@ pub fn main() -> std::io::Result<()> {
                 Err(e) => log::warn!("Failed read. {:?}", e),
             }
         }
-        Action::Delete(fp) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+        Action::Delete(dp) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
 
-            let path = snapfaas::fs::path::Path::parse(&fp.path).unwrap();
-            println!(
-                "{}",
-                fs.rm(path.parent().unwrap(), &path.file_name().unwrap())
-                    .is_ok()
-            );
+            let path = snapfaas::fs::path::Path::parse(&dp.path).unwrap();
+            if dp.recursive {
+                remove_recursive(&fs, path);
+            } else if let Some(retention) = dp.trash {
+                println!(
+                    "{}",
+                    fs.trash(path.parent().unwrap(), &path.file_name().unwrap(), retention)
+                        .is_ok()
+                );
+            } else {
+                println!(
+                    "{}",
+                    fs.rm(path.parent().unwrap(), &path.file_name().unwrap())
+                        .is_ok()
+                );
+            }
+        }
+        Action::Restore(r) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+
+            let dir = snapfaas::fs::path::Path::parse(&r.dir).unwrap();
+            match fs.restore(dir, &r.trashed) {
+                Ok(true) => println!("true"),
+                Ok(false) => {
+                    eprintln!("No such trashed entry.");
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Failed to restore. {:?}", e);
+                    std::process::exit(1);
+                }
+            }
         }
         Action::Mkdir(md) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
 
             let dest = snapfaas::fs::path::Path::parse(&md.path).unwrap();
-            let label = labeled::buckle::Buckle::parse(&md.label).unwrap();
+            let label = aliases.resolve(&md.label).unwrap();
 
             let new_dir = fs.create_directory(label);
             println!(
@@ -216,17 +783,26 @@ pub fn main() -> std::io::Result<()> {
                     .is_ok()
             );
         }
+        Action::NewGate(ng) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+            println!("{}", new_gate(&fs, &aliases, ng));
+        }
+        Action::BuildImage(bi) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+            println!("{}", build_and_register(&fs, &aliases, bi));
+        }
         Action::CreateBlob(cb) => {
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
 
             let mut file = std::fs::File::open(&cb.src)?;
             let dest = snapfaas::fs::path::Path::parse(&cb.dest).unwrap();
-            let label = labeled::buckle::Buckle::parse(&cb.label).unwrap();
+            let label = aliases.resolve(&cb.label).unwrap();
             let mut blobstore: blobstore::Blobstore<Sha256> =
                 snapfaas::blobstore::Blobstore::default();
             let mut blob = blobstore.create().unwrap();
             let _ = std::io::copy(&mut file, &mut blob);
             let blob = blobstore.save(blob).unwrap();
+            let meta = snapfaas::fs::BlobMeta::new(blob.len()?, cb.content_type);
             println!(
                 "{}",
                 snapfaas::fs::utils::create_or_update_blob(
@@ -234,7 +810,8 @@ pub fn main() -> std::io::Result<()> {
                     dest.parent().unwrap(),
                     dest.file_name().unwrap(),
                     label,
-                    blob.name
+                    blob.name,
+                    meta
                 )
                 .is_ok()
             );
@@ -271,7 +848,7 @@ pub fn main() -> std::io::Result<()> {
                 public_key_pem,
             } = generate_ec_keys()?;
 
-            snapfaas::fs::utils::set_my_privilge(snapfaas::fs::bootstrap::FAASTEN_PRIV.clone());
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
             let private_dest = snapfaas::fs::path::Path::parse(&gkp.private_key).unwrap();
             let private_label = labeled::buckle::Buckle::parse("faasten,faasten").unwrap();
             println!(
@@ -299,6 +876,23 @@ pub fn main() -> std::io::Result<()> {
                 .is_ok()
             );
         }
+        Action::SignImage(si) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+            let pkey = PKey::private_key_from_pem(&std::fs::read(si.secret_key)?)?;
+            for p in si.paths.iter() {
+                let path = snapfaas::fs::path::Path::parse(p).unwrap();
+                let signed = fs.open_blob(path).map_err(|e| format!("{:?}", e)).and_then(|name| {
+                    let signature = snapfaas::signing::sign(&pkey, &name);
+                    blobstore
+                        .save_signature(&name, &signature)
+                        .map_err(|e| e.to_string())
+                });
+                match signed {
+                    Ok(()) => println!("{}\tsigned", p),
+                    Err(e) => println!("{}\t{}", p, e),
+                }
+            }
+        }
         Action::Jwt(jwt) => {
             let private_key_bytes = std::fs::read(jwt.secret_key)?;
             let pkey = PKey::private_key_from_pem(private_key_bytes.as_slice())?;
@@ -306,32 +900,519 @@ pub fn main() -> std::io::Result<()> {
             let component = Buckle::parse(format!("{},T", jwt.component).as_str())
                 .unwrap()
                 .secrecy;
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
+            // in a session of its own, which `snapfaas::session::revoke` ends
+            let sid = snapfaas::session::initialize(&fs)
+                .and_then(|_| snapfaas::session::create(&fs, component.clone()))
+                .map_err(|e| std::io::Error::other(format!("no login session: {:?}", e)))?;
+            let token = snapfaas::login::Claims::new(component, 10 * 60, Some(sid)).sign(&pkey);
+            println!("{}", token);
+        }
+        Action::Explain(ex) => {
+            let current = aliases.resolve(&ex.current).unwrap();
+            let target = aliases.resolve(&ex.target).unwrap();
+            let privilege = Buckle::parse(format!("{},T", ex.privilege).as_str())
                 .unwrap()
-                .as_secs();
-
-            #[derive(Clone, Serialize, Deserialize, Debug)]
-            struct Claims {
-                pub alg: String,
-                pub iat: u64,
-                pub exp: u64,
-                pub sub: Component,
+                .secrecy;
+            let explanation = snapfaas::fs::utils::explain_flow(&current, &target, &privilege);
+            println!("allowed: {}", explanation.allowed);
+            for clause in explanation.secrecy_failures {
+                println!("secrecy clause not satisfied: {}", clause);
             }
+            for clause in explanation.integrity_failures {
+                println!("integrity clause not satisfied: {}", clause);
+            }
+        }
+        Action::AnalyzeGate(ag) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
 
-            let claims = Claims {
-                alg: "ES256".to_string(),
-                iat: now,
-                exp: now + 10 * 60,
-                sub: component,
-            };
-            let key = PKeyWithDigest {
-                key: pkey,
-                digest: openssl::hash::MessageDigest::sha256(),
-            };
-            let token = claims.sign_with_key(&key).unwrap();
-            println!("{}", token);
+            let component = |c: &str| Buckle::parse(format!("{},T", c).as_str()).unwrap().secrecy;
+            let path = snapfaas::fs::path::Path::parse(&ag.path).unwrap();
+            let analysis = snapfaas::fs::utils::analyze_gate(
+                &fs,
+                path,
+                &component(&ag.invoker),
+                &aliases.resolve(&ag.invoker_label).unwrap(),
+                &aliases.resolve(&ag.consumer).unwrap(),
+                &component(&ag.consumer_privilege),
+            );
+            match analysis {
+                Ok(analysis) => {
+                    for (i, hop) in analysis.chain.iter().enumerate() {
+                        println!(
+                            "gate {}: privilege {}, clearance {}, declassify {}",
+                            i, hop.privilege, hop.invoker_integrity_clearance, hop.declassify
+                        );
+                    }
+                    println!("effective privilege: {}", analysis.privilege);
+                    println!("effective clearance: {}", analysis.invoker_integrity_clearance);
+                    println!("effective declassify: {}", analysis.declassify);
+                    println!("invokable: {}", analysis.invokable);
+                    println!("best result label: {}", aliases.display(&analysis.best_result));
+                    println!("readable: {}", analysis.invokable && analysis.readable.allowed);
+                    for clause in analysis.readable.secrecy_failures {
+                        println!("secrecy clause not satisfied: {}", clause);
+                    }
+                    for clause in analysis.readable.integrity_failures {
+                        println!("integrity clause not satisfied: {}", clause);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to analyze gate: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Action::SetAlias(sa) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+
+            if let Err(e) = aliases.insert(sa.name, sa.label) {
+                eprintln!("Cannot set the alias: {}", e);
+                std::process::exit(1);
+            }
+            println!("{}", aliases.save(&fs).is_ok());
+        }
+        Action::RemoveAlias(ra) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+
+            println!("{}", aliases.remove(&ra.name) && aliases.save(&fs).is_ok());
+        }
+        Action::ListAliases => {
+            for (name, label) in aliases.iter() {
+                println!("{}\t{}", name, label);
+            }
+        }
+        Action::AddSudoer(s) => {
+            use snapfaas::fs::sudoers::Sudoers;
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+
+            let mut sudoers = Sudoers::load(&fs);
+            if !sudoers.insert(s.principal) {
+                eprintln!("Invalid principal");
+                std::process::exit(1);
+            }
+            println!("{}", sudoers.save(&fs).is_ok());
+        }
+        Action::RemoveSudoer(s) => {
+            use snapfaas::fs::sudoers::Sudoers;
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+
+            let mut sudoers = Sudoers::load(&fs);
+            println!("{}", sudoers.remove(&s.principal) && sudoers.save(&fs).is_ok());
+        }
+        Action::PublishGate(pg) => {
+            use snapfaas::fs::catalog::Catalog;
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+
+            let mut catalog = Catalog::load(&fs);
+            if !catalog.insert(pg.name, pg.path, pg.description) {
+                eprintln!("Invalid path");
+                std::process::exit(1);
+            }
+            println!("{}", catalog.save(&fs).is_ok());
+        }
+        Action::UnpublishGate(ug) => {
+            use snapfaas::fs::catalog::Catalog;
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+
+            let mut catalog = Catalog::load(&fs);
+            println!("{}", catalog.remove(&ug.name) && catalog.save(&fs).is_ok());
+        }
+        Action::PurgeTrash => match fs.purge_trash() {
+            Ok(purged) => println!("{}", purged),
+            Err(e) => {
+                eprintln!("failed to purge the trash: {:?}", e);
+                std::process::exit(1);
+            }
+        },
+        Action::PartitionKeys => {
+            println!("moved {} objects", fs.partition_keys());
+        }
+        Action::Audit(a) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+            let to = a.from.saturating_add(a.limit);
+            match snapfaas::fs::audit::read(&fs, a.from, to) {
+                Ok(records) => {
+                    for record in records {
+                        println!(
+                            "{}",
+                            serde_json::json!({
+                                "time": record.time,
+                                "who": record.who,
+                                "kind": record.kind,
+                                "object": record.object,
+                                "old": record.old,
+                                "new": record.new,
+                                "command": record.command,
+                                "task": record.task,
+                                "from": record.from,
+                                "to": record.to,
+                            })
+                        );
+                    }
+                }
+                Err(e) => eprintln!("Cannot read the audit log: {:?}", e),
+            }
+        }
+        Action::RightSize(rs) => {
+            use snapfaas::fs::{DirEntry, Gate};
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+
+            let mut conn =
+                snapfaas::sched::rpc::connect(&rs.scheduler).expect("connect to the scheduler");
+            let admin_token =
+                std::fs::read_to_string(&rs.admin_token).expect("read the admin token");
+            let stats = snapfaas::sched::rpc::function_stats(&mut conn, admin_token.trim())
+                .expect("fetch function statistics");
+            println!("gate\tmemory\trecommended\tsamples\tooms");
+            for p in rs.paths.iter() {
+                let path = snapfaas::fs::path::Path::parse(&p).unwrap();
+                let gate = match fs.read_path(path) {
+                    Ok(DirEntry::Gate(gate)) => gate,
+                    _ => {
+                        eprintln!("{}: not a gate", p);
+                        continue;
+                    }
+                };
+                let function = gate.to_invokable(&fs).function;
+                let stats = stats.iter().find(|s| s.function == function);
+                let recommended = stats.and_then(|s| s.recommended_memory(rs.headroom));
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    p,
+                    function.memory,
+                    recommended.map_or("-".to_string(), |m| m.to_string()),
+                    stats.map_or(0, |s| s.peak_memory.len()),
+                    stats.map_or(0, |s| s.ooms)
+                );
+                let Some(memory) = recommended.filter(|_| rs.apply) else {
+                    continue;
+                };
+                match gate.get(&fs).map(|g| g.unlabel().clone()) {
+                    Some(Gate::Direct(mut direct)) if direct.function.memory != memory => {
+                        direct.function.memory = memory;
+                        if let Err(e) = gate.replace(Gate::Direct(direct), &fs) {
+                            eprintln!("{}: failed to update: {:?}", p, e);
+                        }
+                    }
+                    Some(Gate::Redirect(_)) => {
+                        eprintln!("{}: a redirect gate, size the gate it redirects to", p);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Action::Invoke(iv) => {
+            snapfaas::fs::utils::set_my_privilge(privilege.clone());
+            invoke(&fs, privilege, iv)?;
+        }
+        Action::MigrateStore(_) => unreachable!("handled before opening the store"),
+        Action::Compact => {
+            eprintln!("compact only applies to LMDB stores");
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+fn invoke<S: BackingStore>(fs: &FS<S>, privilege: Component, iv: Invoke) -> std::io::Result<()> {
+    use snapfaas::sched::{message::{LabeledInvoke, ReturnCode}, rpc};
+
+    let path = snapfaas::fs::path::Path::parse(&iv.path).unwrap();
+    let (function, gate_privilege) =
+        match snapfaas::fs::utils::resolve_gate_with_clearance_check(fs, path) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                eprintln!("Cannot invoke the gate: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+    let headers = iv.header.into_iter().map(|(k, v)| (k.to_lowercase(), v)).collect();
+    let mut conn = rpc::connect(&iv.scheduler).expect("connect to the scheduler");
+    for line in stdin().lock().lines() {
+        let invoke = LabeledInvoke {
+            function: Some(function.clone().into()),
+            label: Some(snapfaas::fs::utils::get_current_label().into()),
+            gate_privilege: Some(gate_privilege.clone().into()),
+            payload: line?.into_bytes(),
+            headers: Clone::clone(&headers),
+            sync: true,
+            invoker: Some(privilege.clone().into()),
+            ..Default::default()
+        };
+        match rpc::labeled_invoke(&mut conn, invoke) {
+            Ok(ret) if ret.code == ReturnCode::Success as i32 => match ret.payload {
+                Some(response) if !response.body_blob.is_empty() => {
+                    println!("body in blob {}", response.body_blob);
+                }
+                response => {
+                    let body = response.and_then(|r| r.body).unwrap_or_default();
+                    println!("{}", String::from_utf8_lossy(&body));
+                }
+            },
+            Ok(ret) => {
+                let code = ReturnCode::from_i32(ret.code).map_or("unknown", ReturnCode::name);
+                eprintln!("Failed to invoke: {}", code);
+            }
+            Err(e) => eprintln!("Failed to invoke: {:?}", e),
         }
     }
     Ok(())
 }
+
+fn new_gate<S: BackingStore>(fs: &FS<S>, aliases: &LabelAliases, ng: NewGate) -> bool {
+    use snapfaas::fs::{bootstrap, DirectGate, Function, Metadata, Provenance, ResultRetention};
+
+    let component = |c: &str| Buckle::parse(format!("{},T", c).as_str()).unwrap().secrecy;
+    let dest = snapfaas::fs::path::Path::parse(&ng.path).unwrap();
+    let label = aliases.resolve(&ng.label).unwrap();
+    let kernel = match bootstrap::get_kernel_blob(fs, ng.kernel.as_deref()) {
+        Ok(kernel) => kernel,
+        Err(e) => {
+            eprintln!("Unknown kernel {:?}: {:?}", ng.kernel, e);
+            std::process::exit(1);
+        }
+    };
+    let provenance = Provenance {
+        source_repo: ng.source_repo,
+        commit: ng.commit,
+        builder: ng.builder,
+        sbom: ng.sbom.map(|sbom| {
+            fs.open_blob(snapfaas::fs::path::Path::parse(&sbom).unwrap())
+                .expect("open sbom")
+        }),
+    };
+    let (keep_last, keep_days) = (ng.keep_last, ng.keep_days);
+    let function = Function {
+        memory: ng.memory,
+        app_image: fs
+            .open_blob(snapfaas::fs::path::Path::parse(&ng.app_image).unwrap())
+            .expect("open app image"),
+        runtime_image: bootstrap::get_runtime_blob(fs, &ng.runtime),
+        kernel,
+        devices: ng.device,
+        keep_alive: ng.keep_alive,
+        min_instances: ng.min_instances,
+        speculative_boot: ng.speculative_boot,
+        idempotent: ng.idempotent,
+        idle_ttl: ng.idle_ttl,
+        requirements: ng.require.into_iter().collect(),
+        forward_headers: ng.forward_header,
+        concurrency_target: ng.concurrency_target,
+        scratch: ng.scratch,
+        app_layers: ng
+            .app_layer
+            .iter()
+            .map(|layer| {
+                fs.open_blob(snapfaas::fs::path::Path::parse(layer).unwrap())
+                    .expect("open app layer")
+            })
+            .collect(),
+        provenance: (provenance != Provenance::default()).then(|| Box::new(provenance)),
+        results: ng.results_dir.map(|dir| Box::new(ResultRetention { dir, keep_last, keep_days })),
+    };
+    let metadata = Metadata {
+        description: ng.description,
+        tags: ng.tag,
+        example_payload: ng.example_payload,
+    };
+    let gate = fs.create_direct_gate(
+        label,
+        DirectGate {
+            privilege: component(&ng.privilege),
+            invoker_integrity_clearance: component(&ng.invoker_integrity_clearance),
+            declassify: Component::dc_true(),
+            function,
+            metadata: (metadata != Metadata::default()).then(|| Box::new(metadata)),
+        },
+    );
+    gate.and_then(|gate| fs.link(dest.parent().unwrap(), dest.file_name().unwrap(), gate))
+        .is_ok()
+}
+
+// Runs `command` to completion, failing if it exits unsuccessfully
+fn run(command: &mut std::process::Command) -> std::io::Result<()> {
+    let status = command.status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("{:?} failed: {}", command, status)));
+    }
+    Ok(())
+}
+
+fn dir_size(dir: &std::path::Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+/// Builds an ext4 appfs image of the Python function whose source is the tar archive at
+/// `source`, with the packages in `requirements` or else in the archive's requirements.txt
+/// installed alongside it. Needs tar, pip and mkfs.ext4 but not root.
+fn build_image(
+    source: &str,
+    requirements: Option<&str>,
+    pip: &str,
+) -> std::io::Result<tempfile::NamedTempFile> {
+    use std::process::Command;
+    let root = tempfile::tempdir()?;
+    run(Command::new("tar").arg("-xf").arg(source).arg("-C").arg(root.path()))?;
+    let requirements = match requirements {
+        Some(path) => Some(std::path::PathBuf::from(path)),
+        None => Some(root.path().join("requirements.txt")).filter(|p| p.exists()),
+    };
+    if let Some(requirements) = requirements {
+        run(Command::new(pip)
+            .args(["install", "--no-cache-dir", "--target"])
+            .arg(root.path())
+            .arg("-r")
+            .arg(requirements))?;
+    }
+    // room for the file system's own metadata
+    let size = dir_size(root.path())? * 5 / 4 + (16 << 20);
+    let image = tempfile::NamedTempFile::new()?;
+    image.as_file().set_len(size)?;
+    run(Command::new("mkfs.ext4")
+        .args(["-q", "-F", "-d"])
+        .arg(root.path())
+        .arg(image.path()))?;
+    Ok(image)
+}
+
+// Builds the image `bi` describes, stores it at the gate's --app-image and registers the gate
+fn build_and_register<S: BackingStore>(
+    fs: &FS<S>,
+    aliases: &LabelAliases,
+    bi: BuildImage,
+) -> bool {
+    let source = snapfaas::fs::path::Path::parse(&bi.source).unwrap();
+    let mut blobstore: blobstore::Blobstore<Sha256> = blobstore::Blobstore::default();
+    let source = match fs.open_blob(source) {
+        Ok(name) => blobstore.local_path_string(&name).unwrap(),
+        Err(e) => {
+            eprintln!("Cannot open source blob {}: {:?}", bi.source, e);
+            return false;
+        }
+    };
+    let image = match build_image(&source, bi.requirements.as_deref(), &bi.pip) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Failed to build the image: {}", e);
+            return false;
+        }
+    };
+    let blob = blobstore.create().and_then(|mut blob| {
+        std::io::copy(&mut image.reopen()?, &mut blob)?;
+        blobstore.save(blob)
+    });
+    let blob = match blob {
+        Ok(blob) => blob,
+        Err(e) => {
+            eprintln!("Failed to store the image: {}", e);
+            return false;
+        }
+    };
+    let dest = snapfaas::fs::path::Path::parse(&bi.gate.app_image).unwrap();
+    let label = aliases
+        .resolve(bi.image_label.as_ref().unwrap_or(&bi.gate.label))
+        .unwrap();
+    let meta = snapfaas::fs::BlobMeta::new(blob.len().unwrap_or_default(), None);
+    let stored = snapfaas::fs::utils::create_or_update_blob(
+        fs,
+        dest.parent().unwrap(),
+        dest.file_name().unwrap(),
+        label,
+        blob.name,
+        meta,
+    );
+    if let Err(e) = stored {
+        eprintln!("Failed to link the image at {}: {:?}", bi.gate.app_image, e);
+        return false;
+    }
+    let mut gate = bi.gate;
+    gate.builder.get_or_insert_with(|| "admin_fstools build-image".to_string());
+    new_gate(fs, aliases, gate)
+}
+
+fn migrate_store(ms: &MigrateStore) {
+    use snapfaas::fs::tikv::TikvClient;
+    let Some(path) = ms.from.strip_prefix("lmdb:") else {
+        eprintln!("--from must be lmdb:PATH");
+        std::process::exit(1);
+    };
+    let Some(pds) = ms.to.strip_prefix("tikv:") else {
+        eprintln!("--to must be tikv:ADDR:PORT[,ADDR:PORT...]");
+        std::process::exit(1);
+    };
+    if !std::path::Path::new(path).exists() {
+        eprintln!("LMDB path does not exist: {}", path);
+        std::process::exit(1);
+    }
+    // a delta pass is the last one, nothing may write to the source during or after it
+    if ms.delta {
+        if let Err(e) = snapfaas::fs::lmdb::ensure_exclusive(std::path::Path::new(path)) {
+            eprintln!("stop the services using {} first: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    let source = snapfaas::fs::lmdb::get_dbenv(path);
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let pds: Vec<String> = pds.split(',').map(String::from).collect();
+    let client = rt.block_on(async { tikv_client::RawClient::new(pds).await.unwrap() });
+    let dest = TikvClient::new(client, std::sync::Arc::new(rt));
+    let same = |key: &[u8], value: &[u8]| {
+        dest.get(key).map(|copy| Sha256::digest(copy)) == Some(Sha256::digest(value))
+    };
+
+    let mut source_keys = std::collections::BTreeSet::new();
+    let mut copied = 0;
+    snapfaas::fs::lmdb::for_each(&source, |key, value| {
+        if ms.delta {
+            source_keys.insert(key.to_vec());
+            if same(key, value) {
+                return;
+            }
+        }
+        dest.put(key, value);
+        copied += 1;
+    })
+    .expect("read the source store");
+    let mut deleted = 0;
+    if ms.delta {
+        // the destination may hold more than objects
+        for key in dest.keys() {
+            if snapfaas::fs::ObjectKind::of_key(&key).is_some() && !source_keys.contains(&key) {
+                dest.del(&key);
+                deleted += 1;
+            }
+        }
+    }
+
+    let (mut total, mut mismatched) = (0, 0);
+    snapfaas::fs::lmdb::for_each(&source, |key, value| {
+        total += 1;
+        if !same(key, value) {
+            mismatched += 1;
+            eprintln!("{}: copy differs from the source", hex::encode(key));
+        }
+    })
+    .expect("read the source store");
+    println!(
+        "copied {}, deleted {}, {} of {} objects match the source",
+        copied,
+        deleted,
+        total - mismatched,
+        total
+    );
+    if mismatched > 0 {
+        // objects written during the copy differ until a delta pass
+        eprintln!("run again with --delta once nothing writes to the source");
+        std::process::exit(1);
+    }
+}
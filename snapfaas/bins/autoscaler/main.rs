@@ -0,0 +1,178 @@
+//! Reconciles the number of worker nodes with the scheduler's desired count, see
+//! `snapfaas::sched::autoscale`. Start the scheduler with `--autoscale-max` and the same
+//! `--admin-token` as the autoscaler.
+//!
+//! Nodes are added and removed by a script or a webhook that knows the platform. A script is
+//! run as `SCRIPT add COUNT` or `SCRIPT remove ADDR...`, with `FAASTEN_DESIRED_WORKERS` and
+//! `FAASTEN_CURRENT_WORKERS` in its environment. A webhook receives the same as a JSON POST:
+//!
+//! ```json
+//! {"action": "remove", "count": 1, "nodes": ["10.0.0.7"], "desired": 3, "current": 4}
+//! ```
+//!
+//! Nodes to remove are drained first, so the script or webhook should wait for their workers
+//! to exit before shutting them down. After acting, the autoscaler waits out a cooldown so that
+//! new nodes have time to register.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use log::{error, info, warn};
+use serde::Serialize;
+
+use snapfaas::sched::{message::Scale, rpc};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+#[command(group(clap::ArgGroup::new("hook").args(["script", "webhook"]).required(true)))]
+struct Cli {
+    /// Address of the scheduler, ADDR:PORT or unix://PATH
+    #[arg(long, value_name = "ADDR")]
+    scheduler: String,
+    /// Script that adds or removes worker nodes
+    #[arg(long, value_name = "PATH")]
+    script: Option<PathBuf>,
+    /// URL to POST requests to add or remove worker nodes to
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
+    /// How often to compare the desired and current number of nodes
+    #[arg(long, value_name = "SECS", default_value_t = 15)]
+    interval: u64,
+    /// How long to wait after adding or removing nodes before doing it again
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    cooldown: u64,
+    /// File with the scheduler's admin token, which reading the desired scale and the inventory
+    /// and draining nodes take
+    #[arg(long, value_name = "PATH")]
+    admin_token: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+struct Request {
+    action: &'static str,
+    count: usize,
+    /// nodes to remove, drained already
+    nodes: Vec<String>,
+    desired: u64,
+    current: u64,
+}
+
+fn main() {
+    env_logger::init();
+
+    let cli = Cli::parse();
+    let mut client = rpc::connect_retrying(&cli.scheduler);
+    let interval = Duration::from_secs(cli.interval);
+    let cooldown = Duration::from_secs(cli.cooldown);
+    let admin_token = std::fs::read_to_string(&cli.admin_token).expect("read the admin token");
+    let admin_token = admin_token.trim();
+    // nodes drained for removal, which the scheduler reports until their workers exit
+    let mut drained = HashSet::new();
+    let mut last_action: Option<Instant> = None;
+    loop {
+        if last_action.map_or(true, |t| t.elapsed() >= cooldown) {
+            match rpc::scale(&mut client, admin_token) {
+                Ok(scale) => {
+                    if let Some(request) = plan(&mut client, &scale, &mut drained, admin_token) {
+                        info!("{:?}", request);
+                        if let Err(e) = act(&cli, &request) {
+                            error!("failed to {} nodes: {}", request.action, e);
+                        }
+                        last_action = Some(Instant::now());
+                    }
+                }
+                Err(e) => warn!("failed to get the desired scale: {:?}", e),
+            }
+        }
+        thread::sleep(interval);
+    }
+}
+
+// What to ask of the hook to reach the desired scale, draining nodes to remove
+fn plan(
+    client: &mut rpc::Client,
+    scale: &Scale,
+    drained: &mut HashSet<String>,
+    admin_token: &str,
+) -> Option<Request> {
+    let request = |action, count, nodes| Request {
+        action,
+        count,
+        nodes,
+        desired: scale.desired_workers,
+        current: scale.current_workers,
+    };
+    if scale.desired_workers > scale.current_workers {
+        let count = (scale.desired_workers - scale.current_workers) as usize;
+        return Some(request("add", count, Vec::new()));
+    }
+    if scale.desired_workers == scale.current_workers {
+        return None;
+    }
+    let inventory = match rpc::inventory(client, admin_token) {
+        Ok(inventory) => inventory,
+        Err(e) => {
+            warn!("failed to get the inventory: {:?}", e);
+            return None;
+        }
+    };
+    let reported: HashSet<&String> = inventory.iter().map(|n| &n.node).collect();
+    drained.retain(|n| reported.contains(n));
+    // the nodes with the most idle workers are the least busy
+    let mut candidates: Vec<_> = inventory.iter().filter(|n| !drained.contains(&n.node)).collect();
+    candidates.sort_by_key(|n| std::cmp::Reverse(n.idle_workers));
+    let count = (scale.current_workers - scale.desired_workers) as usize;
+    let mut nodes = Vec::new();
+    for node in candidates.into_iter().take(count) {
+        let addr = match node.node.parse() {
+            Ok(addr) => addr,
+            Err(_) => continue,
+        };
+        match rpc::drain(client, Some(addr), Some(admin_token)) {
+            Ok(()) => {
+                drained.insert(node.node.clone());
+                nodes.push(node.node.clone());
+            }
+            Err(e) => warn!("failed to drain {}: {:?}", node.node, e),
+        }
+    }
+    if nodes.is_empty() {
+        return None;
+    }
+    Some(request("remove", nodes.len(), nodes))
+}
+
+fn act(cli: &Cli, request: &Request) -> Result<(), String> {
+    if let Some(script) = cli.script.as_ref() {
+        let mut command = Command::new(script);
+        command.arg(request.action);
+        match request.action {
+            "add" => command.arg(request.count.to_string()),
+            _ => command.args(&request.nodes),
+        };
+        let status = command
+            .env("FAASTEN_DESIRED_WORKERS", request.desired.to_string())
+            .env("FAASTEN_CURRENT_WORKERS", request.current.to_string())
+            .status()
+            .map_err(|e| e.to_string())?;
+        if !status.success() {
+            return Err(format!("{:?} exited with {}", script, status));
+        }
+    }
+    if let Some(url) = cli.webhook.as_ref() {
+        let response = reqwest::blocking::Client::new()
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(request).unwrap())
+            .send()
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", url, response.status()));
+        }
+    }
+    Ok(())
+}
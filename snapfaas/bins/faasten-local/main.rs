@@ -0,0 +1,265 @@
+//! Runs a single-host Faasten: a scheduler, a worker and the webfront as child processes,
+//! configured from one TOML file. Everything is stored under the working directory, the
+//! file system in `storage` and blobs in `blobs`, as the workers expect.
+//!
+//! ```toml
+//! # optional, bootstraps the file system before starting
+//! bootstrap = "bootstrap-config.yml"
+//!
+//! [scheduler]
+//! listen = "127.0.0.1:3000"
+//!
+//! [worker]
+//! memory = 4096
+//! hypervisor = "firecracker"
+//!
+//! [webfront]
+//! listen = "127.0.0.1:8080"
+//! base_url = "http://localhost:8080"
+//! secret_key = "secret.pem"
+//! public_key = "public.pem"
+//! ```
+//!
+//! The binaries are looked up next to this one, then in `PATH`. If any of them exits, the others
+//! are stopped too.
+
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use log::{error, info};
+use serde::Deserialize;
+
+// how long to wait for the scheduler to listen before starting its clients
+const SCHEDULER_STARTUP: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Path of the TOML configuration
+    #[arg(value_name = "PATH", default_value = "faasten.toml")]
+    config: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// LMDB directory of the file system
+    #[serde(default = "default_lmdb")]
+    lmdb: String,
+    /// YAML bootstrap configuration, see `snapfaas::fs::bootstrap::Config`. The file system is
+    /// bootstrapped before anything starts if given.
+    bootstrap: Option<PathBuf>,
+    #[serde(default)]
+    scheduler: SchedulerConfig,
+    #[serde(default)]
+    worker: WorkerConfig,
+    webfront: WebfrontConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SchedulerConfig {
+    #[serde(default = "default_scheduler_listen")]
+    listen: String,
+    /// where to save the queue on shutdown and restore it from on start
+    queue_snapshot: Option<PathBuf>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            listen: default_scheduler_listen(),
+            queue_snapshot: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WorkerConfig {
+    /// MB of memory for VMs
+    #[serde(default = "default_worker_memory")]
+    memory: u32,
+    /// `firecracker` or `cloud-hypervisor`
+    #[serde(default = "default_hypervisor")]
+    hypervisor: String,
+    /// extra command line arguments of `multivm`
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            memory: default_worker_memory(),
+            hypervisor: default_hypervisor(),
+            args: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebfrontConfig {
+    #[serde(default = "default_webfront_listen")]
+    listen: String,
+    /// the listen address over http if absent
+    base_url: Option<String>,
+    /// PEM encoded key pair that signs and verifies login tokens
+    secret_key: PathBuf,
+    public_key: PathBuf,
+    /// extra command line arguments of `webfront`
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+fn default_lmdb() -> String {
+    "storage".to_string()
+}
+
+fn default_scheduler_listen() -> String {
+    "127.0.0.1:3000".to_string()
+}
+
+fn default_worker_memory() -> u32 {
+    2048
+}
+
+fn default_hypervisor() -> String {
+    "firecracker".to_string()
+}
+
+fn default_webfront_listen() -> String {
+    "127.0.0.1:8080".to_string()
+}
+
+fn main() {
+    env_logger::init();
+
+    let cli = Cli::parse();
+    let config = std::fs::read_to_string(&cli.config).expect("read the configuration");
+    let config: Config = toml::from_str(&config).expect("parse the configuration");
+
+    if let Some(bootstrap) = config.bootstrap.as_ref() {
+        info!("bootstrapping the file system from {:?}", bootstrap);
+        let status = command("admin_fstools")
+            .args(["--lmdb", &config.lmdb, "bootstrap"])
+            .arg(bootstrap)
+            .status()
+            .expect("run admin_fstools");
+        if !status.success() {
+            error!("bootstrapping failed: {}", status);
+            std::process::exit(1);
+        }
+    }
+
+    let mut scheduler = command("scheduler");
+    scheduler.args(["--listen", &config.scheduler.listen]);
+    scheduler.args(["--lmdb", &config.lmdb]);
+    scheduler.arg("--login-key").arg(&config.webfront.public_key);
+    if let Some(path) = config.scheduler.queue_snapshot.as_ref() {
+        scheduler.arg("--queue-snapshot").arg(path);
+    }
+    let mut children = vec![spawn("scheduler", scheduler)];
+    wait_for_scheduler(&config.scheduler.listen);
+
+    let mut worker = command("multivm");
+    worker.args(["--scheduler", &config.scheduler.listen]);
+    worker.args(["--memory", &config.worker.memory.to_string()]);
+    worker.args(["--lmdb", &config.lmdb]);
+    worker.args(["--hypervisor", &config.worker.hypervisor]);
+    worker.args(&config.worker.args);
+    children.push(spawn("worker", worker));
+
+    let webfront_config = &config.webfront;
+    let base_url = webfront_config
+        .base_url
+        .clone()
+        .unwrap_or_else(|| format!("http://{}", webfront_config.listen));
+    let mut webfront = command("webfront");
+    webfront.args(["--listen", &webfront_config.listen]);
+    webfront.args(["--base-url", &base_url]);
+    webfront.args(["--faasten-scheduler", &config.scheduler.listen]);
+    webfront.args(["--lmdb", &config.lmdb]);
+    webfront.arg("--secret-key").arg(&webfront_config.secret_key);
+    webfront.arg("--public-key").arg(&webfront_config.public_key);
+    webfront.args(&webfront_config.args);
+    children.push(spawn("webfront", webfront));
+    info!("Faasten is up at {}", base_url);
+
+    // Ctrl-C reaches the children too, as they are in the same process group
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .expect("set Ctrl-C handler");
+    supervise(children, &interrupted);
+}
+
+// Waits until a child exits or Ctrl-C, then stops the rest and exits like the first child
+fn supervise(mut children: Vec<(&'static str, Child)>, interrupted: &AtomicBool) {
+    let code = loop {
+        let exited = children
+            .iter_mut()
+            .find_map(|(name, child)| child.try_wait().ok().flatten().map(|s| (*name, s)));
+        if let Some((name, status)) = exited {
+            error!("{} exited: {}", name, status);
+            break status.code().unwrap_or(1);
+        }
+        if interrupted.load(Ordering::SeqCst) {
+            break 0;
+        }
+        thread::sleep(Duration::from_millis(200));
+    };
+    // the webfront first, the scheduler last, so that the worker can deregister
+    for (name, mut child) in children.into_iter().rev() {
+        if child.try_wait().ok().flatten().is_none() {
+            info!("stopping {}", name);
+            unsafe {
+                libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+            }
+            let _ = child.wait();
+        }
+    }
+    std::process::exit(code);
+}
+
+// The binary `name` next to this one if there is one, from `PATH` otherwise
+fn command(name: &str) -> Command {
+    let sibling = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join(name)))
+        .filter(|path| path.is_file());
+    match sibling {
+        Some(path) => Command::new(path),
+        None => Command::new(Path::new(name)),
+    }
+}
+
+fn spawn(name: &'static str, mut command: Command) -> (&'static str, Child) {
+    info!("starting {}: {:?}", name, command);
+    let child = command
+        .spawn()
+        .unwrap_or_else(|e| panic!("start {}: {}", name, e));
+    (name, child)
+}
+
+fn wait_for_scheduler(addr: &str) {
+    let start = Instant::now();
+    while start.elapsed() < SCHEDULER_STARTUP {
+        let up = match addr.strip_prefix("unix://") {
+            Some(path) => std::os::unix::net::UnixStream::connect(path).is_ok(),
+            None => TcpStream::connect(addr).is_ok(),
+        };
+        if up {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    error!("the scheduler is not listening at {} yet", addr);
+}
@@ -1,7 +1,8 @@
 use std::fs::File;
 use std::io::BufReader;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 use memory_model::MemoryFileOption;
@@ -22,13 +23,23 @@ use snapfaas::firecracker_wrapper::VmmWrapper;
 struct Cli {
     #[command(flatten)]
     vmconfig: cli::VmConfig,
+    /// File backing the memory region shared with the guest runtime, attached as a writable
+    /// drive after the rootfs, the appfs and its layers
+    #[arg(long, value_name = "PATH")]
+    shared_mem_file: Option<String>,
 }
 
 fn main() {
     let mut ts_vec = Vec::with_capacity(10);
     ts_vec.push(Instant::now());
 
-    let args = Cli::parse().vmconfig;
+    let cli = Cli::parse();
+    if let Err(e) = snapfaas::set_run_dir(&cli.vmconfig.run_dir) {
+        eprintln!("cannot use the run directory: {:?}", e);
+        std::process::exit(1);
+    }
+    let shared_mem_file = cli.shared_mem_file.map(PathBuf::from);
+    let args = cli.vmconfig;
 
     // process command line arguments
     let instance_id = args.id;
@@ -40,6 +51,7 @@ fn main() {
 
     // optional arguments:
     let appfs = args.appfs.map(PathBuf::from);
+    let app_layers: Vec<PathBuf> = args.app_layer.iter().map(PathBuf::from).collect();
     let dump_dir = args.dump.dump_dir.map(PathBuf::from);
     let load_dir = args.load.load_dir.map_or(Vec::new(), |x| {
         x.split(',')
@@ -49,6 +61,7 @@ fn main() {
             .collect()
     });
     let copy_base = args.load.copy_base_memory;
+    let load_threads = args.load.load_threads as usize;
     let copy_diff = args.load.copy_diff_memory;
     let odirect_base = args.load.odirect_base;
     let odirect_diff = !args.load.no_odirect_diff;
@@ -57,7 +70,7 @@ fn main() {
     let load_ws = args.load.load_ws;
     let mac = args.network.mac;
     let tap_name = args.network.tap;
-    let cid = args.vsock_cid;
+    let cid = args.vsock_cid.unwrap_or(snapfaas::cid::FIRST_CID);
 
     // Make sure kernel, rootfs, appfs, load_dir, dump_dir exist
     if !&kernel.exists() {
@@ -74,6 +87,13 @@ fn main() {
         std::process::exit(1);
     }
 
+    for layer in &app_layers {
+        if !layer.exists() {
+            eprintln!("{:?} app layer not exist", layer);
+            std::process::exit(1);
+        }
+    }
+
     if dump_dir.is_some() && !dump_dir.as_ref().unwrap().exists() {
         eprintln!("dump directory not exist");
         std::process::exit(1);
@@ -100,6 +120,17 @@ fn main() {
     });
     ts_vec.push(Instant::now());
 
+    // the VMM copies the base memory dump with a single thread, warm the page cache for it
+    if copy_base && !odirect_base && load_threads > 1 {
+        if let Some(base) = load_dir.first() {
+            let start = Instant::now();
+            if let Err(e) = prefetch(&base.join("memory_dump"), load_threads) {
+                eprintln!("failed to prefetch the base memory dump: {:?}", e);
+            }
+            eprintln!("FR: Prefetch base memory: {} us", start.elapsed().as_micros());
+        }
+    }
+
     let from_snapshot = !load_dir.is_empty();
     let config = SnapFaaSConfig {
         parsed_json,
@@ -184,6 +215,38 @@ fn main() {
         }
     }
 
+    for (i, layer) in app_layers.into_iter().enumerate() {
+        let block_config = BlockDeviceConfig {
+            drive_id: format!("layer{}", i),
+            path_on_host: layer,
+            is_root_device: false,
+            is_read_only: true,
+            partuuid: None,
+            rate_limiter: None,
+            odirect: odirect_appfs,
+        };
+        if let Err(e) = vmm.insert_block_device(block_config) {
+            eprintln!("Vmm failed to insert app layer {} due to: {:?}", i, e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(shared_mem_file) = shared_mem_file {
+        let block_config = BlockDeviceConfig {
+            drive_id: String::from("shm"),
+            path_on_host: shared_mem_file,
+            is_root_device: false,
+            is_read_only: false,
+            partuuid: None,
+            rate_limiter: None,
+            odirect: false,
+        };
+        if let Err(e) = vmm.insert_block_device(block_config) {
+            eprintln!("Vmm failed to insert the shared memory region due to: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+
     if let Some(mac_addr) = mac {
         let netif_config = NetworkInterfaceConfig {
             iface_id: String::from("eth0"),
@@ -200,12 +263,12 @@ fn main() {
         }
     }
 
-    let vsock_path = format!("worker-{}.sock", cid);
+    let vsock_path = snapfaas::run_dir().join(format!("worker-{}.sock", cid));
     let _ = std::fs::remove_file(&vsock_path);
     let vsock_config = VsockDeviceConfig {
         vsock_id: "vsock0".to_string(),
         guest_cid: cid,
-        uds_path: vsock_path.to_string(),
+        uds_path: vsock_path.to_string_lossy().into_owned(),
     };
     if let Err(e) = vmm.add_vsock(vsock_config) {
         eprintln!("Vmm failed to add vsock due to: {:?}", e);
@@ -223,14 +286,15 @@ fn main() {
 
     // listen for dump working set
     if args.dump.dump_ws {
-        let listener_port = format!("dump_ws-{}.sock", instance_id);
+        let listener_port = snapfaas::run_dir().join(format!("dump_ws-{}.sock", instance_id));
         let unix_sock_listener =
             UnixListener::bind(listener_port).expect("Failed to bind to unix listener");
         match unix_sock_listener.accept() {
             Ok((_, _)) => match vmm.dump_working_set() {
                 Ok(_) => {
                     eprintln!("VMM: dumped the working set.");
-                    let port = format!("dump_ws-{}.sock.back", instance_id);
+                    let port =
+                        snapfaas::run_dir().join(format!("dump_ws-{}.sock.back", instance_id));
                     UnixStream::connect(port).expect("Failed to connect");
                 }
                 Err(e) => {
@@ -253,3 +317,38 @@ fn main() {
     vmm.join_vmm();
     std::process::exit(0);
 }
+
+/// Reads the file at `path` into the page cache, `threads` chunks in parallel
+fn prefetch(path: &Path, threads: usize) -> std::io::Result<()> {
+    // chunks are rounded up to 2 MB, the huge page size
+    const ALIGN: u64 = 2 << 20;
+    const BUF_SIZE: usize = 1 << 20;
+
+    let len = File::open(path)?.metadata()?.len();
+    let chunk = len.div_ceil(threads as u64).div_ceil(ALIGN) * ALIGN;
+    std::thread::scope(|s| {
+        let handles: Vec<_> = (0..threads as u64)
+            .map(|i| i * chunk)
+            .take_while(|&start| start < len)
+            .map(|start| {
+                s.spawn(move || -> std::io::Result<()> {
+                    let file = File::open(path)?;
+                    let end = (start + chunk).min(len);
+                    let mut buf = vec![0u8; BUF_SIZE];
+                    let mut offset = start;
+                    while offset < end {
+                        let want = ((end - offset) as usize).min(BUF_SIZE);
+                        match file.read_at(&mut buf[..want], offset)? {
+                            0 => break,
+                            n => offset += n as u64,
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|h| h.join().expect("prefetch thread panicked"))
+    })
+}
@@ -1,6 +1,8 @@
 use clap::Parser;
 use labeled::buckle::Buckle;
+use snapfaas::fs::{tikv::TikvClient, BackingStore, FS};
 use snapfaas::{cli, fs};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -15,6 +17,8 @@ struct Cli {
     once: bool,
     #[command(flatten)]
     store: cli::Store,
+    #[command(flatten)]
+    maintenance: cli::Maintenance,
 }
 
 fn main() {
@@ -24,21 +28,41 @@ fn main() {
 
     let interval = cli.interval;
 
-    if let Some(_) = cli.store.tikv {
-        todo!();
+    let fs: FS<Box<dyn BackingStore>> = if let Some(tikv_pds) = cli.store.tikv.clone() {
+        FS::new({
+            let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+            let client =
+                rt.block_on(async { tikv_client::RawClient::new(tikv_pds).await.unwrap() });
+            let read_mode = cli.store.tikv_read_mode();
+            Box::new(TikvClient::new(client, Arc::new(rt)).with_read_mode(read_mode))
+        })
     } else if let Some(lmdb) = cli.store.lmdb.as_ref() {
-        fs::utils::taint_with_label(Buckle::top());
-        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(lmdb)));
-        let mut fs = fs::FS::new(&*dbenv);
-        loop {
-            if let Ok(collected) = fs.collect_garbage() {
-                log::debug!("garbage collected {}", collected.len())
-            }
-            if cli.once {
-                break;
-            } else {
-                thread::sleep(Duration::new(interval, 0));
-            }
+        let options = cli.store.lmdb_options();
+        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::open_dbenv(lmdb, &options)));
+        FS::new(Box::new(&*dbenv))
+    } else {
+        panic!("We shouldn't reach here.");
+    };
+    fs.assert_partitioned();
+
+    fs::utils::taint_with_label(Buckle::top());
+    let mut maintenance = cli.maintenance.build();
+    loop {
+        // each pass waits for the window and yields to load between its steps
+        maintenance.wait();
+        match fs.purge_trash() {
+            Ok(purged) => log::debug!("purged {} expired trash entries", purged),
+            Err(e) => log::warn!("gave up purging the trash: {:?}", e),
+        }
+        maintenance.wait();
+        match fs::retention::enforce(&fs) {
+            Ok(pruned) => log::debug!("pruned {} retained results", pruned),
+            Err(e) => log::warn!("gave up pruning retained results: {:?}", e),
+        }
+        if cli.once {
+            break;
+        } else {
+            thread::sleep(Duration::new(interval, 0));
         }
     }
 }
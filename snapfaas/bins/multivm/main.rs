@@ -11,93 +11,246 @@
 
 use clap::Parser;
 use log::warn;
+use openssl::pkey::PKey;
 use snapfaas::cli;
+use snapfaas::hypervisor::Backend;
+use snapfaas::fs::{replica::Replicating, BackingStore};
+use snapfaas::manifest::Manifest;
 use snapfaas::resource_manager::ResourceManager;
-use snapfaas::worker::Worker;
-use snapfaas::{fs::tikv::TikvClient, fs::BackingStore, sched};
+use snapfaas::signing::Keyset;
+use snapfaas::worker::{RestartPolicy, WorkerPool};
+use snapfaas::{fs::tikv::TikvClient, sched};
 
-use std::net::{SocketAddr, TcpStream};
-use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Address of the scheduler
-    #[arg(short, long, value_name = "ADDR:PORT")]
+    /// Address of the scheduler, ADDR:PORT or unix://PATH
+    #[arg(short, long, value_name = "ADDR")]
     scheduler: String,
     /// Total memory in MBs of the worker machine
     #[arg(short, long, value_name="MB", value_parser=clap::value_parser!(u32).range(128..))]
     memory: u32,
     #[command(flatten)]
     store: cli::Store,
+    /// Path of the PEM encoded public key that verifies delegation certificates
+    #[arg(long, value_name = "PATH")]
+    delegation_key: Option<std::ffi::OsString>,
+    /// Path of the PEM encoded private key that signs declassification receipts and download
+    /// tokens
+    #[arg(long, value_name = "PATH")]
+    receipt_key: Option<std::ffi::OsString>,
+    /// Path of the secret the webfront seals payloads with, to open them
+    #[arg(long, value_name = "PATH")]
+    payload_secret: Option<std::ffi::OsString>,
+    /// PEM encoded public key, or directory of .pem files, trusted to sign VM images. Without
+    /// it, VMs boot from unsigned images.
+    #[arg(long, value_name = "PATH")]
+    image_keys: Option<std::path::PathBuf>,
+    /// Compress large messages exchanged with the scheduler (zstd)
+    #[arg(long)]
+    compress: bool,
+    /// Largest message in MB exchanged with the scheduler, decompressed
+    #[arg(long, value_name = "MB", default_value_t = 64)]
+    max_message_size: usize,
+    /// Size in MB of the memory region each VM shares with its runtime, 0 disables it
+    #[arg(long, value_name = "MB", default_value_t = 0)]
+    shared_mem: u32,
+    /// Host device bound to vfio-pci that functions needing a device of CLASS (e.g. gpu) may
+    /// use. Repeat for more devices. Needs `--hypervisor cloud-hypervisor`.
+    #[arg(long, value_name = "CLASS=PCI_ADDR", value_parser = parse_device)]
+    device: Vec<(String, String)>,
+    /// Attribute of the machine that gates may require, e.g. cpu=icelake or nvme=true. Repeat
+    /// for more attributes.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_attribute)]
+    attribute: Vec<(String, String)>,
+    /// Largest VM in MB the machine runs, the total memory if not given
+    #[arg(long, value_name = "MB")]
+    max_vm_mem: Option<usize>,
+    /// Payloads and responses larger than BYTES pass through the scheduler as blobs, 0 passes
+    /// everything inline
+    #[arg(long, value_name = "BYTES", default_value_t = 1 << 20)]
+    max_inline: usize,
+    /// Hypervisor that boots the VMs
+    #[arg(long, value_enum, default_value_t = Backend::Firecracker)]
+    hypervisor: Backend,
+    /// When to replace a VM whose hypervisor exits with a fresh one and retry the invocation
+    #[arg(long, value_enum, default_value_t = RestartPolicy::Boot)]
+    restart_policy: RestartPolicy,
+    /// Path in the FS of the JSON worker configuration, e.g. home:<T,faasten>:worker.json,
+    /// re-read whenever the scheduler asks workers to reload
+    #[arg(long, value_name = "FS_PATH")]
+    config: Option<String>,
+    /// Directory to give each VM a directory of its own in, holding links to the blobs the VM
+    /// boots from, so that hypervisors need not see the whole blobstore
+    #[arg(long, value_name = "PATH")]
+    jail_dir: Option<std::path::PathBuf>,
+    /// Directory for invocations' scratch spaces, ideally a tmpfs. Defaults to the system's
+    /// temporary directory.
+    #[arg(long, value_name = "PATH")]
+    scratch_dir: Option<std::path::PathBuf>,
+    /// File recording the worker's VMs. A worker restarted with the same file takes back the
+    /// idle VMs the previous one left running instead of booting them again.
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<std::path::PathBuf>,
+    /// Directory of the Unix sockets of the worker and its VMs
+    #[arg(long, value_name = "PATH", default_value = snapfaas::DEFAULT_RUN_DIR)]
+    run_dir: std::path::PathBuf,
+    /// Space delimited addresses of the TiKV PDs of a store to replicate an lmdb store to
+    #[arg(long, value_name = "ADDR:PORT", requires_all = ["lmdb", "replication_journal"])]
+    replicate_to: Option<Vec<String>>,
+    /// File journaling the writes not yet replicated
+    #[arg(long, value_name = "PATH")]
+    replication_journal: Option<std::path::PathBuf>,
+    /// Copy the whole lmdb store to the replica, e.g. after losing the journal
+    #[arg(long, requires = "replicate_to")]
+    replication_resync: bool,
+}
+
+fn parse_device(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(class, addr)| (class.to_string(), addr.to_string()))
+        .ok_or_else(|| "expected CLASS=PCI_ADDR".to_string())
+}
+
+fn parse_attribute(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| "expected KEY=VALUE".to_string())
 }
 
 fn main() {
     env_logger::init();
 
     let cli = Cli::parse();
+    // our Firecracker fork has no VFIO support
+    if !cli.device.is_empty() && cli.hypervisor != Backend::CloudHypervisor {
+        use clap::CommandFactory;
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--device needs --hypervisor cloud-hypervisor",
+            )
+            .exit();
+    }
+
+    if cli.compress {
+        sched::codec::enable_compression();
+    }
+    sched::codec::set_max_message_size(cli.max_message_size << 20);
+    snapfaas::set_run_dir(&cli.run_dir).expect("create the run directory");
 
     // create the local resource manager
-    let sched_addr: SocketAddr =
-        SocketAddr::from_str(&cli.scheduler).expect("Invalid socket address");
-    let mut manager = ResourceManager::new(sched_addr.clone());
+    let sched_addr = cli.scheduler.clone();
+    let mut manager = ResourceManager::new(&sched_addr);
 
     // set total memory
     manager.set_total_mem(cli.memory as usize);
 
-    // create the worker pool
-    let pool_size = manager.total_mem_in_mb() / 128;
-    let pool = if let Some(path) = cli.store.lmdb.as_ref() {
-        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(path)));
-        new_workerpool(pool_size, sched_addr, manager, &*dbenv)
-    } else if let Some(tikv_pds) = cli.store.tikv {
-        let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
-        let client =
-            rt.block_on(async { tikv_client::RawClient::new(tikv_pds).await.unwrap() });
-        let db = TikvClient::new(client, Arc::new(rt));
-        new_workerpool(pool_size, sched_addr, manager, db)
-    } else {
-        panic!("We shouldn't reach here");
-    };
+    for (class, addr) in cli.device.clone() {
+        manager.add_device(class, addr);
+    }
+    for (key, value) in cli.attribute.clone() {
+        manager.add_attribute(key, value);
+    }
+    if let Some(mb) = cli.max_vm_mem {
+        manager.set_max_vm_mem(mb);
+    }
+    if let Some(path) = cli.manifest.as_ref() {
+        let manifest = Manifest::open(path).expect("open the VM manifest");
+        manager.set_manifest(manifest);
+    }
+
+    let delegation_key = cli.delegation_key.as_ref().map(|path| {
+        let bytes = std::fs::read(path).expect("read delegation key");
+        PKey::public_key_from_pem(bytes.as_slice()).expect("parse delegation key")
+    });
+    let receipt_key = cli.receipt_key.as_ref().map(|path| {
+        let bytes = std::fs::read(path).expect("read receipt key");
+        PKey::private_key_from_pem(bytes.as_slice()).expect("parse receipt key")
+    });
 
     // register signal handler
     set_ctrlc_handler(sched_addr.clone());
 
-    // hold on
-    pool.join();
+    // run the worker pool until the scheduler terminates it, e.g., when draining the node
+    let manager = Arc::new(Mutex::new(manager));
+    let pool_sched_addr = sched_addr.clone();
+    if let Some(path) = cli.store.lmdb.as_ref() {
+        let options = cli.store.lmdb_options();
+        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::open_dbenv(path, &options)));
+        if let Some(tikv_pds) = cli.replicate_to.clone() {
+            let journal = cli.replication_journal.clone().unwrap();
+            let db = Replicating::new(&*dbenv, tikv_client(tikv_pds), &journal)
+                .expect("open replication journal");
+            if cli.replication_resync {
+                db.resync();
+            }
+            let pool = WorkerPool::new(pool_sched_addr, manager, db, delegation_key);
+            run_pool(pool, &cli, receipt_key);
+        } else {
+            let pool = WorkerPool::new(pool_sched_addr, manager, &*dbenv, delegation_key);
+            run_pool(pool, &cli, receipt_key);
+        }
+    } else if let Some(tikv_pds) = cli.store.tikv.clone() {
+        let db = tikv_client(tikv_pds).with_read_mode(cli.store.tikv_read_mode());
+        let pool = WorkerPool::new(pool_sched_addr, manager, db, delegation_key);
+        run_pool(pool, &cli, receipt_key);
+    } else {
+        panic!("We shouldn't reach here");
+    }
+    shutdown(&sched_addr);
 }
 
-fn new_workerpool<T>(
-    pool_size: usize,
-    sched_addr: SocketAddr,
-    manager: ResourceManager,
-    db: T,
-) -> threadpool::ThreadPool
-where
-    T: BackingStore + Clone + Send + 'static,
-{
-    let pool = threadpool::ThreadPool::new(pool_size);
-    let manager = Arc::new(Mutex::new(manager));
-    for i in 0..pool_size as u32 {
-        let sched_addr_dup = sched_addr.clone();
-        let manager_dup = Arc::clone(&manager);
-        let db_dup = db.clone();
-        pool.execute(move || {
-            Worker::new(i + 100, sched_addr_dup, manager_dup, db_dup).wait_and_process();
-        });
+fn tikv_client(pds: Vec<String>) -> TikvClient {
+    let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+    let client = rt.block_on(async { tikv_client::RawClient::new(pds).await.unwrap() });
+    TikvClient::new(client, Arc::new(rt))
+}
+
+fn run_pool<B: BackingStore + Clone + Send + 'static>(
+    mut pool: WorkerPool<B>,
+    cli: &Cli,
+    receipt_key: Option<PKey<openssl::pkey::Private>>,
+) {
+    pool.set_shared_mem(cli.shared_mem as usize);
+    pool.set_hypervisor(cli.hypervisor);
+    pool.set_restart_policy(cli.restart_policy);
+    pool.set_max_inline(cli.max_inline);
+    if let Some(dir) = cli.jail_dir.clone() {
+        pool.set_jail_dir(dir);
+    }
+    if let Some(dir) = cli.scratch_dir.clone() {
+        pool.set_scratch_dir(dir);
+    }
+    if let Some(key) = receipt_key {
+        pool.set_receipt_key(key);
     }
-    pool
+    if let Some(path) = cli.image_keys.as_ref() {
+        pool.set_image_keys(Keyset::load(path).expect("load image keys"));
+    }
+    if let Some(path) = cli.payload_secret.as_ref() {
+        pool.set_payload_secret(std::fs::read(path).expect("read payload secret"));
+    }
+    if let Some(path) = cli.config.clone() {
+        pool.set_config_path(path);
+    }
+    pool.run();
 }
 
-fn set_ctrlc_handler(sched_addr: SocketAddr) {
+fn set_ctrlc_handler(sched_addr: String) {
     ctrlc::set_handler(move || {
         warn!("{}", "Handling Ctrl-C. Shutting down...");
-        if let Ok(mut sched) = TcpStream::connect(sched_addr.clone()) {
-            let _ = sched::rpc::drop_resource(&mut sched);
-        }
-        snapfaas::unlink_unix_sockets();
-        std::process::exit(0);
+        shutdown(&sched_addr);
     })
     .expect("set Ctrl-C handler");
 }
+
+fn shutdown(sched_addr: &str) {
+    if let Ok(mut sched) = sched::rpc::connect(sched_addr) {
+        let _ = sched::rpc::drop_resource(&mut sched);
+    }
+    snapfaas::unlink_unix_sockets();
+    std::process::exit(0);
+}
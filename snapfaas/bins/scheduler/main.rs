@@ -1,21 +1,115 @@
 use clap::Parser;
 
 use std::{
+    path::{Path, PathBuf},
     sync::{Arc, Condvar, Mutex},
     thread,
+    time::Duration,
 };
 
-use snapfaas::sched::{resource_manager::ResourceManager, rpc_server::RpcServer, schedule};
+use snapfaas::fs::tikv::TikvClient;
+use snapfaas::sched::{
+    autoscale,
+    queue::{self, AdmissionPolicy, TaskQueue},
+    resolver::Resolver,
+    resource_manager::{Callback, ResourceManager},
+    rpc_server::RpcServer,
+    schedule,
+    simulate::{ServiceTime, Simulation},
+    Task,
+};
+
+#[derive(Clone, clap::ValueEnum)]
+enum Admission {
+    /// Reject new requests with QueueFull
+    Reject,
+    /// Drop the newest lowest-priority request to admit a higher-priority one
+    Shed,
+    /// Wait up to the admission deadline for space in the queue
+    Block,
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(group(clap::ArgGroup::new("store").args(["tikv", "lmdb"])))]
 struct Cli {
-    /// Address to listen at
-    #[arg(short, long, value_name = "ADDR:PORT")]
+    /// Address to listen at, ADDR:PORT or unix://PATH
+    #[arg(short, long, value_name = "ADDR")]
     listen: String,
     /// Capacity of the request queue
     #[arg(short, long, value_name = "CAP_NUM_OF_TASK", default_value_t = 1000000)]
     qcap: u32,
+    /// What to do with new requests when the queue is full
+    #[arg(long, value_enum, default_value_t = Admission::Reject)]
+    admission: Admission,
+    /// How long `--admission block` waits for space in the queue
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    admission_deadline_ms: u64,
+    /// Compress large messages sent to workers and frontends (zstd)
+    #[arg(long)]
+    compress: bool,
+    /// Largest message in MB exchanged with workers and frontends, decompressed
+    #[arg(long, value_name = "MB", default_value_t = 64)]
+    max_message_size: usize,
+    /// Public key (PEM) that verifies login tokens. If present, the scheduler resolves gates
+    /// for clients that invoke them by path.
+    #[arg(long, value_name = "PATH", requires = "store")]
+    login_key: Option<String>,
+    /// Space delimited addresses of TiKV PDs to resolve gates from
+    #[arg(long, value_name = "ADDR:PORT", requires = "login_key")]
+    tikv: Option<Vec<String>>,
+    /// Path of the LMDB directory to resolve gates from
+    #[arg(long, value_name = "PATH", requires = "login_key", conflicts_with = "tikv")]
+    lmdb: Option<String>,
+    /// Highest priority logged in invokers may ask for through the resolver. Others always get
+    /// the lowest.
+    #[arg(long, value_name = "NUM", default_value_t = 0, requires = "login_key")]
+    max_priority: u32,
+    /// Run this many synthetic worker nodes that sleep instead of running functions, for trying
+    /// out scheduling policies without VMs
+    #[arg(long, value_name = "NODES")]
+    simulate: Option<usize>,
+    /// Service time of simulated invocations: fixed:MS, exp:MEAN_MS or uniform:MIN_MS-MAX_MS
+    #[arg(long, value_name = "DIST", default_value = "exp:100", requires = "simulate")]
+    service_time: ServiceTime,
+    /// Tasks each simulated node runs at once
+    #[arg(long, value_name = "NUM", default_value_t = 4, requires = "simulate")]
+    simulate_slots: usize,
+    /// Memory of each simulated node in MB, which bounds the VMs it keeps warm
+    #[arg(long, value_name = "MB", default_value_t = 8192, requires = "simulate")]
+    simulate_mem: usize,
+    /// Added to simulated invocations that find no warm VM
+    #[arg(long, value_name = "MS", default_value_t = 0, requires = "simulate")]
+    simulate_cold_start: u64,
+    /// Most VMs booted at once for invocations of speculative-boot gates waiting on busy VMs
+    #[arg(long, value_name = "NUM", default_value_t = 0)]
+    max_speculative_boots: usize,
+    /// Most idle VMs kept warm per gate for its min-instances, 0 to keep none warm
+    #[arg(long, value_name = "NUM", default_value_t = 1)]
+    max_min_instances: usize,
+    /// File to save queued tasks to on Ctrl-C and to queue them again from on start
+    #[arg(long, value_name = "PATH")]
+    queue_snapshot: Option<PathBuf>,
+    /// File with the token admins present to drain nodes, such as the autoscaler's, reload nodes,
+    /// pause VMs and read the inventory, dispatches, function statistics and desired scale
+    #[arg(long, value_name = "PATH")]
+    admin_token: Option<PathBuf>,
+    /// Space delimited hosts async invocations may have their results POSTed to. Callbacks are
+    /// refused without any.
+    #[arg(long, value_name = "HOST")]
+    callback_hosts: Option<Vec<String>>,
+    /// Report a desired number of worker nodes to autoscalers, at most this many
+    #[arg(long, value_name = "NODES")]
+    autoscale_max: Option<usize>,
+    /// Fewest worker nodes to ask autoscalers for
+    #[arg(long, value_name = "NODES", default_value_t = 1, requires = "autoscale_max")]
+    autoscale_min: usize,
+    /// Invocations a worker node runs at once, for gates without a concurrency target
+    #[arg(long, value_name = "NUM", default_value_t = 4, requires = "autoscale_max")]
+    autoscale_target: usize,
+    /// How far ahead to provision for a queue growing faster than it drains
+    #[arg(long, value_name = "SECS", default_value_t = 60, requires = "autoscale_max")]
+    autoscale_horizon: u64,
 }
 
 fn main() {
@@ -23,27 +117,134 @@ fn main() {
 
     let cli = Cli::parse();
 
+    if cli.compress {
+        snapfaas::sched::codec::enable_compression();
+    }
+    snapfaas::sched::codec::set_max_message_size(cli.max_message_size << 20);
+
     // Intialize remote scheduler
-    let (queue_tx, queue_rx) = crossbeam::channel::bounded(cli.qcap as usize);
-    let manager = Arc::new(Mutex::new(ResourceManager::new()));
+    let policy = match cli.admission {
+        Admission::Reject => AdmissionPolicy::Reject,
+        Admission::Shed => AdmissionPolicy::ShedLowestPriority,
+        Admission::Block => {
+            AdmissionPolicy::BlockWithDeadline(Duration::from_millis(cli.admission_deadline_ms))
+        }
+    };
+    let queue = Arc::new(TaskQueue::new(cli.qcap as usize, policy));
+    let mut manager = ResourceManager::new();
+    manager.set_max_speculative(cli.max_speculative_boots);
+    manager.set_max_min_instances(cli.max_min_instances);
+    let manager = Arc::new(Mutex::new(manager));
     let cvar = Arc::new(Condvar::new());
 
     // Register signal handler
-    set_ctrlc_handler(manager.clone());
+    set_ctrlc_handler(manager.clone(), queue.clone(), cli.queue_snapshot.clone());
+
+    if let Some(path) = cli.queue_snapshot.as_ref() {
+        restore_queue(path, &queue, &manager);
+    }
 
     // kick off scheduling thread
     let manager_dup = manager.clone();
     let cvar_dup = cvar.clone();
-    thread::spawn(move || schedule(queue_rx, manager_dup, cvar_dup));
+    let queue_dup = queue.clone();
+    thread::spawn(move || schedule(queue_dup, manager_dup, cvar_dup));
 
-    let s = RpcServer::new(&cli.listen, manager.clone(), queue_tx, cvar);
+    let mut s = RpcServer::new(&cli.listen, manager.clone(), queue, cvar);
+    if let Some(path) = cli.login_key.as_ref() {
+        let pem = std::fs::read(path).expect("read the login key");
+        let login_key =
+            openssl::pkey::PKey::public_key_from_pem(&pem).expect("parse the login key");
+        if let Some(path) = cli.lmdb.as_ref() {
+            let dbenv = Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(path)));
+            let mut resolver = Resolver::new(&*dbenv, login_key);
+            resolver.set_max_priority(cli.max_priority);
+            s.set_resolver(Arc::new(resolver));
+        } else if let Some(tikv_pds) = cli.tikv {
+            let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+            let client =
+                rt.block_on(async { tikv_client::RawClient::new(tikv_pds).await.unwrap() });
+            let db = TikvClient::new(client, Arc::new(rt));
+            let mut resolver = Resolver::new(db, login_key);
+            resolver.set_max_priority(cli.max_priority);
+            s.set_resolver(Arc::new(resolver));
+        }
+    }
+    if let Some(path) = cli.admin_token.as_ref() {
+        let token = std::fs::read_to_string(path).expect("read the admin token");
+        s.set_admin_token(token.trim().as_bytes());
+    }
+    if let Some(hosts) = cli.callback_hosts {
+        s.set_callback_hosts(hosts);
+    }
+    if let Some(max_workers) = cli.autoscale_max {
+        s.set_autoscale(autoscale::Policy {
+            default_target: cli.autoscale_target,
+            min_workers: cli.autoscale_min,
+            max_workers,
+            horizon: Duration::from_secs(cli.autoscale_horizon),
+        });
+    }
+    if let Some(nodes) = cli.simulate {
+        s.simulate(Simulation {
+            nodes,
+            slots: cli.simulate_slots,
+            memory: cli.simulate_mem,
+            service_time: cli.service_time,
+            cold_start: Duration::from_millis(cli.simulate_cold_start),
+        });
+    }
     log::debug!("Scheduler starts listening at {:?}", cli.listen);
     s.run();
 }
 
-fn set_ctrlc_handler(manager: Arc<Mutex<ResourceManager>>) {
+// Queues the tasks saved at `path` again, then removes the file so they don't run twice
+fn restore_queue(path: &Path, queue: &TaskQueue, manager: &Mutex<ResourceManager>) {
+    let tasks = match queue::load_snapshot(path) {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            log::error!("Failed to read queued tasks from {:?}: {}", path, e);
+            return;
+        }
+    };
+    let restored = tasks.len();
+    for task in tasks {
+        let invokes = match &task {
+            Task::Invoke(uuid, li) => vec![(*uuid, li)],
+            Task::Gang(members, _) => members.iter().map(|(uuid, li)| (*uuid, li)).collect(),
+            Task::Terminate => vec![],
+        };
+        for (uuid, li) in invokes {
+            if let Some(callback) = Callback::of(li) {
+                manager.lock().unwrap().callbacks.insert(uuid, callback);
+            }
+        }
+        if let Err(e) = queue.push(task) {
+            log::warn!("Dropping a restored task: {:?}", e);
+        }
+    }
+    log::info!("Restored {} queued tasks from {:?}", restored, path);
+    if let Err(e) = std::fs::remove_file(path) {
+        log::error!("Failed to remove {:?}: {}", path, e);
+    }
+}
+
+fn set_ctrlc_handler(
+    manager: Arc<Mutex<ResourceManager>>,
+    queue: Arc<TaskQueue>,
+    snapshot: Option<PathBuf>,
+) {
     ctrlc::set_handler(move || {
         log::warn!("{}", "Handling Ctrl-C. Shutting down...");
+        snapfaas::systemd::notify_stopping();
+        // a task being dispatched right now is lost
+        if let Some(path) = snapshot.as_ref() {
+            let tasks = queue.drain();
+            match queue::save_snapshot(path, &tasks) {
+                Ok(()) => log::warn!("Saved {} queued tasks to {:?}", tasks.len(), path),
+                Err(e) => log::error!("Failed to save queued tasks to {:?}: {}", path, e),
+            }
+        }
         manager.lock().unwrap().remove_all();
         std::process::exit(0);
     })
@@ -1,39 +1,51 @@
-#[macro_use(crate_version, crate_authors)]
-extern crate clap;
-use clap::{App, Arg};
+use clap::{Parser, Subcommand};
 use sha2::Sha256;
 use snapfaas::blobstore::Blobstore;
 use std::{io::{stdin, copy, BufRead, stdout}, path::Path, ffi::OsString};
 
+#[derive(Parser)]
+#[command(author, version, about = "Read and write blobs", long_about = None)]
+struct Cli {
+    /// Read blob names from stdin and print the blobs
+    #[arg(short)]
+    read: bool,
+    /// Directory of the blobstore
+    #[arg(short, long, value_name = "DIR", default_value = "blobs")]
+    storage: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print bytes and blobs stored under each top-level directory
+    Du {
+        /// Recount usage from the blobs on disk first
+        #[arg(long)]
+        recount: bool,
+    },
+}
+
 fn main() -> std::io::Result<()> {
-    let cmd_arguments = App::new("SnapFaaS Blob CLI")
-        .version(crate_version!())
-        .author(crate_authors!())
-        .about("Read and write blobs")
-        .arg(
-            Arg::with_name("READ")
-                .short("r")
-                .required(false)
-                .help("read names from stdout")
-            )
-        .arg(
-            Arg::with_name("STORAGE")
-                .short("s")
-                .long("storage")
-                .required(false)
-                .default_value("blobs")
-                .takes_value(true)
-                .value_name("DIR")
-            ).get_matches();
+    let cli = Cli::parse();
 
-    let base_dir_path = Path::new(cmd_arguments.value_of("STORAGE").unwrap());
+    let base_dir_path = Path::new(&cli.storage);
     let tmp_dir_path = base_dir_path.join("tmp");
     let _ = std::fs::create_dir_all(&tmp_dir_path);
     let base_dir = OsString::from(base_dir_path);
     let mut blobstore = Blobstore::<Sha256>::new(base_dir, OsString::from(tmp_dir_path));
 
     let mut stdin = stdin();
-    if cmd_arguments.is_present("READ") {
+    if let Some(Command::Du { recount }) = cli.command {
+        if recount {
+            blobstore.recount()?;
+        }
+        for (dir, usage) in blobstore.usage()? {
+            println!("{}\t{}\t{}", dir, usage.bytes, usage.blobs);
+        }
+        let total = blobstore.total_usage();
+        println!("total\t{}\t{}", total.bytes, total.blobs);
+    } else if cli.read {
         for line in stdin.lock().lines() {
             let mut blob = blobstore.open(line?)?;
             copy(&mut blob, &mut stdout())?;
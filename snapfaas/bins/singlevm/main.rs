@@ -6,9 +6,10 @@ use labeled::buckle::{self, Buckle};
 use log::{debug, error};
 use snapfaas::blobstore::Blobstore;
 use snapfaas::cli;
-use snapfaas::configs::FunctionConfig;
+use snapfaas::configs::{FunctionConfig, IoEngine};
 use snapfaas::fs::tikv::TikvClient;
 use snapfaas::fs::{BackingStore, FS};
+use snapfaas::hypervisor::Backend;
 use snapfaas::syscall_server::SyscallGlobalEnv;
 use snapfaas::vm::Vm;
 use snapfaas::{syscall_server, unlink_unix_sockets};
@@ -35,12 +36,23 @@ struct Cli {
     start_label: Option<String>,
     #[command(flatten)]
     store: cli::Store,
+    /// Hypervisor that boots the VM
+    #[arg(long, value_enum, default_value_t = Backend::Firecracker)]
+    hypervisor: Backend,
+    /// PCI address (e.g. 0000:01:00.0) of a host device bound to vfio-pci to pass through to the
+    /// VM. Repeat for more devices. Needs `--hypervisor cloud-hypervisor`.
+    #[arg(long, value_name = "PCI_ADDR")]
+    device: Vec<String>,
+    /// I/O engine of the block devices. Firecracker only has the synchronous one.
+    #[arg(long, value_enum, default_value_t = IoEngine::Sync)]
+    io_engine: IoEngine,
 }
 
 fn main() {
     env_logger::init();
 
     let cli = Cli::parse();
+    snapfaas::set_run_dir(&cli.vmconfig.run_dir).expect("create the run directory");
 
     // Create a FunctionConfig value based on cmdline inputs
     let vm_app_config = FunctionConfig {
@@ -48,17 +60,23 @@ fn main() {
         tap: cli.vmconfig.network.tap,
         runtimefs: cli.vmconfig.rootfs,
         appfs: cli.vmconfig.appfs,
+        app_layers: cli.vmconfig.app_layer,
         vcpus: cli.vmconfig.vcpu as u64,
         memory: cli.vmconfig.memory as usize,
         concurrency_limit: 1,
         load_dir: cli.vmconfig.load.load_dir,
         copy_base: cli.vmconfig.load.copy_base_memory,
         copy_diff: cli.vmconfig.load.copy_diff_memory,
+        load_threads: cli.vmconfig.load.load_threads as usize,
         load_ws: cli.vmconfig.load.load_ws,
         dump_dir: cli.vmconfig.dump.dump_dir,
         dump_ws: cli.vmconfig.dump.dump_ws,
         kernel: cli.vmconfig.kernel,
         cmdline: cli.vmconfig.kernel_args,
+        shared_mem: cli.vmconfig.shared_mem as usize,
+        io_engine: cli.io_engine,
+        devices: cli.device,
+        hypervisor: cli.hypervisor,
     };
 
     let id = cli.vmconfig.id as usize;
@@ -72,12 +90,8 @@ fn main() {
     // Launch a vm based on the FunctionConfig value
     let t1 = Instant::now();
     let mut vm = Vm::new(id, vm_app_config.clone().into());
-    let vm_listener_path = format!("worker-{}.sock_1234", cli.vmconfig.vsock_cid);
-    let _ = std::fs::remove_file(&vm_listener_path);
-    let vm_listener = UnixListener::bind(vm_listener_path).expect("bind to the UNIX listener");
     let force_exit = cli.force_exit;
     if let Err(e) = vm.launch(
-        vm_listener.try_clone().unwrap(),
         cli.vmconfig.vsock_cid,
         force_exit,
         vm_app_config,
@@ -110,24 +124,32 @@ fn main() {
         .as_ref()
         .map_or(Buckle::public(), |s| Buckle::parse(s).unwrap());
 
-    let fs: FS<Box<dyn BackingStore>> = if let Some(tikv_pds) = cli.store.tikv {
+    let fs: FS<Box<dyn BackingStore>> = if let Some(tikv_pds) = cli.store.tikv.clone() {
         FS::new({
             let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
             let client =
                 rt.block_on(async { tikv_client::RawClient::new(tikv_pds).await.unwrap() });
-            Box::new(TikvClient::new(client, Arc::new(rt)))
+            let read_mode = cli.store.tikv_read_mode();
+            Box::new(TikvClient::new(client, Arc::new(rt)).with_read_mode(read_mode))
         })
     } else if let Some(path) = cli.store.lmdb.as_ref() {
-        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::get_dbenv(path)));
+        let options = cli.store.lmdb_options();
+        let dbenv = std::boxed::Box::leak(Box::new(snapfaas::fs::lmdb::open_dbenv(path, &options)));
         FS::new(Box::new(&*dbenv))
     } else {
         panic!("We shouldn't reach here.");
     };
+    fs.assert_partitioned();
 
     let mut env = SyscallGlobalEnv {
         sched_conn: None,
         fs,
         blobstore: Blobstore::default(),
+        delegation_key: None,
+        max_inline: 0,
+        receipt_key: None,
+        egress: None,
+        scratch_dir: None,
     };
 
     // Synchronously send the request to vm and wait for a response
@@ -162,9 +184,9 @@ fn main() {
             }
         }
         if dump_working_set {
-            let listener_port = format!("dump_ws-{}.sock", id);
+            let listener_port = snapfaas::run_dir().join(format!("dump_ws-{}.sock", id));
             UnixStream::connect(listener_port).expect("Failed to connect to VMM UNIX listener");
-            let port = format!("dump_ws-{}.sock.back", id);
+            let port = snapfaas::run_dir().join(format!("dump_ws-{}.sock.back", id));
             let li = UnixListener::bind(port).expect("Failed to listen at the port");
             li.accept().expect("Failed to accept a connection");
             break;
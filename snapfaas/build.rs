@@ -1,5 +1,60 @@
 use std::io::Result;
 fn main() -> Result<()> {
     prost_build::compile_protos(&["src/syscalls.proto", "src/sched/messages.proto"], &["src/"])?;
+
+    // The scheduler service reuses the messages above. It is defined here rather than in
+    // messages.proto so that it can use our own codec, which optionally compresses messages.
+    let method = |name: &str, route: &str, input: &str, output: &str| {
+        tonic_build::manual::Method::builder()
+            .name(name)
+            .route_name(route)
+            .input_type(format!("crate::sched::message::{}", input))
+            .output_type(format!("crate::sched::message::{}", output))
+            .codec_path("crate::sched::codec::Codec")
+            .build()
+    };
+    let scheduler = tonic_build::manual::Service::builder()
+        .name("Scheduler")
+        .package("snapfaas.sched")
+        // Workers
+        .method(method("get_task", "GetTask", "GetTask", "Response"))
+        .method(method("finish_task", "FinishTask", "FinishTask", "Ack"))
+        .method(method("watch_preemption", "WatchPreemption", "WatchPreemption", "Ack"))
+        .method(method("labeled_invoke", "LabeledInvoke", "LabeledInvoke", "TaskReturn"))
+        .method(method("gang_invoke", "GangInvoke", "GangInvoke", "GangReturn"))
+        .method(method("send_chunk", "SendChunk", "ResponseChunk", "Ack"))
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("invoke_stream")
+                .route_name("InvokeStream")
+                .input_type("crate::sched::message::LabeledInvoke")
+                .output_type("crate::sched::message::InvokeEvent")
+                .codec_path("crate::sched::codec::Codec")
+                .server_streaming()
+                .build(),
+        )
+        // Local resource managers
+        .method(method("update_resource", "UpdateResource", "UpdateResource", "Ack"))
+        .method(method("drop_resource", "DropResource", "DropResource", "Ack"))
+        // Operators
+        .method(method("drain_node", "DrainNode", "DrainNode", "Ack"))
+        .method(method("reload_node", "ReloadNode", "ReloadNode", "Ack"))
+        .method(method("pause_vm", "PauseVm", "PauseVm", "Ack"))
+        // Debug
+        .method(method("ping", "Ping", "Ping", "Pong"))
+        // Dashboard
+        .method(method("get_inventory", "GetInventory", "GetInventory", "Dashboard"))
+        .method(method("get_dispatches", "GetDispatches", "GetDispatches", "Dashboard"))
+        .method(method("get_function_stats", "GetFunctionStats", "GetFunctionStats", "Dashboard"))
+        // Frontends
+        .method(method("get_load", "GetLoad", "GetLoad", "Load"))
+        .method(method("invoke_gate", "InvokeGate", "GateInvoke", "TaskReturn"))
+        // Autoscalers
+        .method(method("get_scale", "GetScale", "GetScale", "Scale"))
+        .build();
+    // the generated `connect` needs the 2021 prelude, and we build channels ourselves anyway
+    tonic_build::manual::Builder::new()
+        .build_transport(false)
+        .compile(&[scheduler]);
     Ok(())
 }
@@ -1,11 +1,75 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Result, Seek, Write};
-use std::os::unix::prelude::FileExt;
-use std::path::PathBuf;
+use std::os::unix::prelude::{AsRawFd, FileExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use std::{ffi::OsString, fs::File, marker::PhantomData};
 
+use log::warn;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tempfile::NamedTempFile;
 
+/// Temporary files untouched for this long belong to uploads that were abandoned, e.g. by a
+/// crashed process
+pub const TMP_TTL: Duration = Duration::from_secs(60 * 60);
+/// How often `spawn_tmp_cleaner` looks for stale temporary files
+pub const TMP_CLEAN_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Each top-level directory keeps its usage in this file, the store's base directory the total
+const USAGE_FILE: &str = ".usage";
+// Directory under the base directory holding the signatures of blobs, see `signing`
+const SIGNATURE_DIR: &str = "signatures";
+
+static USAGE_ALERT: AtomicU64 = AtomicU64::new(0);
+
+/// Warns whenever a save leaves the store holding more than `bytes`. `None` turns it off.
+pub fn set_usage_alert(bytes: Option<u64>) {
+    USAGE_ALERT.store(bytes.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// Bytes and blobs stored under a directory
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub bytes: u64,
+    pub blobs: u64,
+}
+
+// Adds to the usage recorded in the file at `path` and returns the new usage. Holds an
+// exclusive lock on the file meanwhile, so processes sharing the store never lose updates.
+fn add_usage(path: &Path, bytes: i64, blobs: i64) -> Result<Usage> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // released when the file closes
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    let mut usage: Usage = serde_json::from_slice(&data).unwrap_or_default();
+    usage.bytes = usage.bytes.saturating_add_signed(bytes);
+    usage.blobs = usage.blobs.saturating_add_signed(blobs);
+    file.set_len(0)?;
+    file.write_all_at(&serde_json::to_vec(&usage)?, 0)?;
+    Ok(usage)
+}
+
+fn read_usage(path: &Path) -> Usage {
+    std::fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+// Top-level directories are named by the first two hex digits of the names of their blobs
+fn is_top_level(name: &str) -> bool {
+    name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
 
 #[derive(Debug)]
 pub struct Blobstore<D = Sha256> {
@@ -57,7 +121,14 @@ impl<D: Digest> Blobstore<D> {
     }
 
     pub fn save(&mut self, new_blob: NewBlob<D>) -> Result<Blob> {
+        self.save_new(new_blob).map(|(blob, _)| blob)
+    }
+
+    /// Like `save`, also telling whether the blob is new rather than one saved before with the
+    /// same content
+    pub fn save_new(&mut self, new_blob: NewBlob<D>) -> Result<(Blob, bool)> {
         let name = hex::encode(new_blob.digest.finalize());
+        let len = new_blob.len as i64;
 
         let mut hpath = std::path::PathBuf::new();
         hpath.push(&self.base_dir);
@@ -65,14 +136,154 @@ impl<D: Digest> Blobstore<D> {
         hpath.push(dir);
         let _ = std::fs::create_dir_all(hpath.clone());
         hpath.push(fname);
+        // blobs are named by their content, saving one again changes nothing
+        let existed = hpath.exists();
         let file = new_blob.file.persist(hpath)?;
         let mut perms = file.metadata()?.permissions();
         perms.set_readonly(true);
         file.set_permissions(perms)?;
-        Ok(Blob {
-            name,
-            file
-        })
+        if !existed {
+            self.account(dir, len, 1);
+        }
+        Ok((Blob { name, file }, !existed))
+    }
+
+    /// Discards a blob that will not be saved, removing its temporary file
+    pub fn abort(&mut self, new_blob: NewBlob<D>) -> Result<()> {
+        new_blob.file.close()
+    }
+}
+
+impl<D> Blobstore<D> {
+    /// Removes the temporary files of uploads untouched for `ttl`. Returns how many it removed.
+    pub fn clean_tmp(&self, ttl: Duration) -> Result<usize> {
+        let mut removed = 0;
+        for entry in std::fs::read_dir(&self.tmp_dir)? {
+            let entry = entry?;
+            // the temporary directory may be shared, only touch files `create` made
+            if !entry.file_name().to_string_lossy().starts_with(".tmp") {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let idle = metadata.modified()?.elapsed().unwrap_or_default();
+            if metadata.is_file() && idle > ttl {
+                match std::fs::remove_file(entry.path()) {
+                    Ok(()) => removed += 1,
+                    // removed by another process cleaning up
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Removes stale temporary files, see `TMP_TTL`, now and every `TMP_CLEAN_INTERVAL` on a
+    /// background thread
+    pub fn spawn_tmp_cleaner(&self) {
+        let store = Blobstore::<()>::new(self.base_dir.clone(), self.tmp_dir.clone());
+        let tmp_dir = self.tmp_dir.clone();
+        std::thread::spawn(move || loop {
+            match store.clean_tmp(TMP_TTL) {
+                Ok(0) => {}
+                Ok(n) => log::info!("removed {} stale temporary files from {:?}", n, tmp_dir),
+                Err(e) => warn!("failed to clean up {:?}: {:?}", tmp_dir, e),
+            }
+            std::thread::sleep(TMP_CLEAN_INTERVAL);
+        });
+    }
+
+    /// Removes the blob `name`
+    pub fn delete(&mut self, name: &str) -> Result<()> {
+        let (dir, fname) = name.split_at(2);
+        let path = PathBuf::from(&self.base_dir).join(dir).join(fname);
+        let len = std::fs::metadata(&path)?.len() as i64;
+        std::fs::remove_file(&path)?;
+        self.account(dir, -len, -1);
+        let _ = std::fs::remove_file(self.signature_path(name));
+        Ok(())
+    }
+
+    /// Stores `signature` as the signature of blob `name`, replacing an earlier one
+    pub fn save_signature(&self, name: &str, signature: &[u8]) -> Result<()> {
+        let path = self.signature_path(name);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, signature)?;
+        std::fs::rename(&tmp, &path)
+    }
+
+    /// The signature of blob `name`
+    pub fn signature(&self, name: &str) -> Result<Vec<u8>> {
+        std::fs::read(self.signature_path(name))
+    }
+
+    fn signature_path(&self, name: &str) -> PathBuf {
+        PathBuf::from(&self.base_dir).join(SIGNATURE_DIR).join(name)
+    }
+
+    // Records a change in the usage of the top-level directory `dir` and warns if the store
+    // now holds more than the alert threshold
+    fn account(&self, dir: &str, bytes: i64, blobs: i64) {
+        let base = Path::new(&self.base_dir);
+        let total = add_usage(&base.join(dir).join(USAGE_FILE), bytes, blobs)
+            .and_then(|_| add_usage(&base.join(USAGE_FILE), bytes, blobs));
+        match total {
+            Ok(total) => {
+                let alert = USAGE_ALERT.load(Ordering::Relaxed);
+                if alert > 0 && total.bytes > alert {
+                    warn!(
+                        "blobs take up {} bytes, over the alert threshold of {}",
+                        total.bytes, alert
+                    );
+                }
+            }
+            Err(e) => warn!("failed to account for blob usage: {:?}", e),
+        }
+    }
+
+    /// Usage of the whole store
+    pub fn total_usage(&self) -> Usage {
+        read_usage(&Path::new(&self.base_dir).join(USAGE_FILE))
+    }
+
+    /// Usage of each top-level directory
+    pub fn usage(&self) -> Result<BTreeMap<String, Usage>> {
+        let mut usage = BTreeMap::new();
+        for entry in std::fs::read_dir(&self.base_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if is_top_level(&name) {
+                usage.insert(name, read_usage(&entry.path().join(USAGE_FILE)));
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Recounts the usage of each top-level directory and of the store from the blobs on
+    /// disk, e.g. for stores that predate usage accounting. Saves and deletes running
+    /// meanwhile may be miscounted.
+    pub fn recount(&self) -> Result<Usage> {
+        let mut total = Usage::default();
+        for entry in std::fs::read_dir(&self.base_dir)? {
+            let entry = entry?;
+            if !is_top_level(&entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            let mut usage = Usage::default();
+            for blob in std::fs::read_dir(entry.path())? {
+                let blob = blob?;
+                if blob.file_name() != USAGE_FILE {
+                    usage.bytes += blob.metadata()?.len();
+                    usage.blobs += 1;
+                }
+            }
+            std::fs::write(entry.path().join(USAGE_FILE), serde_json::to_vec(&usage)?)?;
+            total.bytes += usage.bytes;
+            total.blobs += usage.blobs;
+        }
+        std::fs::write(Path::new(&self.base_dir).join(USAGE_FILE), serde_json::to_vec(&total)?)?;
+        Ok(total)
     }
 }
 
@@ -0,0 +1,77 @@
+//! Host-wide allocation of vsock CIDs
+//!
+//! Every VM on a host needs a CID of its own, which also names the Unix sockets of its vsock in
+//! the run directory. Workers, singlevm and tests sharing a host take CIDs from this allocator
+//! rather than counting from a fixed number. A CID is held by an exclusive lock on a file of its
+//! own under `LOCK_DIR` for as long as its `Lease` lives. The kernel drops the locks of a process
+//! that dies, so the CIDs of crashed processes are free again right away.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Directory of the lock files, shared by all processes on the host
+pub const LOCK_DIR: &str = "/run/lock/faasten/cid";
+/// Smallest CID handed out, CIDs below 3 being reserved
+pub const FIRST_CID: u32 = 100;
+// number of CIDs handed out
+const CIDS: u32 = 1 << 16;
+
+// where this process starts looking for a free CID, so that it doesn't retry taken ones
+static NEXT: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug)]
+pub struct Lease {
+    cid: u32,
+    // locked for as long as the lease lives
+    _lock: File,
+}
+
+impl Lease {
+    pub fn cid(&self) -> u32 {
+        self.cid
+    }
+}
+
+/// Takes a free CID
+pub fn allocate() -> io::Result<Lease> {
+    std::fs::create_dir_all(LOCK_DIR)?;
+    let start = NEXT.load(Ordering::Relaxed);
+    for i in 0..CIDS {
+        let cid = FIRST_CID + (start + i) % CIDS;
+        if let Some(lease) = try_lock(cid)? {
+            NEXT.store((cid - FIRST_CID + 1) % CIDS, Ordering::Relaxed);
+            return Ok(lease);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "no free vsock CID"))
+}
+
+/// Takes `cid`, e.g. one given on the command line, failing if another VM has it
+pub fn reserve(cid: u32) -> io::Result<Lease> {
+    if cid < 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "reserved vsock CID"));
+    }
+    std::fs::create_dir_all(LOCK_DIR)?;
+    try_lock(cid)?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::AddrInUse, format!("vsock CID {} in use", cid))
+    })
+}
+
+fn try_lock(cid: u32) -> io::Result<Option<Lease>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(Path::new(LOCK_DIR).join(cid.to_string()))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(Some(Lease { cid, _lock: file }));
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::WouldBlock {
+        Ok(None)
+    } else {
+        Err(e)
+    }
+}
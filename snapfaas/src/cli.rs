@@ -1,6 +1,6 @@
 //! Definitions of common CLI arguments
 
-use clap::{Args, Parser};
+use clap::{ArgGroup, Args, Parser};
 
 #[derive(Parser, Debug)]
 pub struct VmConfig {
@@ -19,15 +19,20 @@ pub struct VmConfig {
     /// Path of the app file-system, mounted at "/srv" in the microVM
     #[arg(long, value_name = "PATH")]
     pub appfs: Option<String>,
+    /// Path of a layer overlaid on the app file-system, attached as a read-only drive after it.
+    /// Repeat for more layers, the last on top.
+    #[arg(long, value_name = "PATH", requires = "appfs")]
+    pub app_layer: Vec<String>,
     /// Memory size in MB of the microVM
     #[arg(long, value_name = "MB", default_value_t = 128)]
     pub memory: u32,
     /// VCPU count of the microVM
     #[arg(long, value_name = "COUNT", default_value_t = 1)]
     pub vcpu: u32,
-    /// CID of the microVM's vsock
-    #[arg(long, value_name = "CID", default_value_t = 100)]
-    pub vsock_cid: u32,
+    /// CID of the microVM's vsock. singlevm allocates one no other VM on the host uses if
+    /// absent, firerunner uses 100.
+    #[arg(long, value_name = "CID")]
+    pub vsock_cid: Option<u32>,
     #[command(flatten)]
     pub network: Network,
     #[command(flatten)]
@@ -40,6 +45,12 @@ pub struct VmConfig {
     /// If present, don't open appfs with O_DIRECT (required when using tmpfs)
     #[arg(long)]
     pub no_odirect_app: bool,
+    /// Size in MB of the memory region shared with the guest runtime, 0 disables it
+    #[arg(long, value_name = "MB", default_value_t = 0)]
+    pub shared_mem: u32,
+    /// Directory of the Unix sockets of the microVM's vsock and of the worker it connects to
+    #[arg(long, value_name = "PATH", default_value = crate::DEFAULT_RUN_DIR)]
+    pub run_dir: std::path::PathBuf,
 }
 
 #[derive(Args, Debug)]
@@ -74,6 +85,10 @@ pub struct Load {
     /// If present, don't open diff memory snapshot with O_DIRECT
     #[arg(long, requires = "load_dir")]
     pub no_odirect_diff: bool,
+    /// Number of threads that read the base memory snapshot into the page cache before it is
+    /// copied, 1 leaves loading to the VMM alone
+    #[arg(long, value_name = "COUNT", default_value_t = 1, requires = "load_dir")]
+    pub load_threads: u32,
 }
 
 #[derive(Args, Debug)]
@@ -87,8 +102,12 @@ pub struct Dump {
     pub dump_ws: bool,
 }
 
+/// Id of the group of `Store`'s `--tikv` and `--lmdb`, exactly one of which is required
+pub const STORE_GROUP: &str = "Store";
+
 #[derive(Args, Debug)]
-#[group(required = true, multiple = false)]
+#[group(skip)]
+#[command(group(ArgGroup::new(STORE_GROUP).args(["tikv", "lmdb"]).required(true)))]
 pub struct Store {
     /// Space delimited addresses of TiKV PDs
     #[arg(long, value_name = "ADDR:PORT")]
@@ -96,4 +115,83 @@ pub struct Store {
     /// Path of the LMDB directory
     #[arg(long, value_name = "PATH")]
     pub lmdb: Option<String>,
+    /// Initial size in MB of the LMDB memory map, which grows as writes need
+    #[arg(long, value_name = "MB", default_value_t = 1024)]
+    pub lmdb_map_size: usize,
+    /// Size in MB beyond which the LMDB memory map doesn't grow, and writes fail
+    #[arg(long, value_name = "MB", default_value_t = 1024 * 1024)]
+    pub lmdb_max_map_size: usize,
+    /// If present, don't flush LMDB to disk on commit. Faster, but a system crash may lose or
+    /// corrupt the latest writes.
+    #[arg(long)]
+    pub lmdb_no_sync: bool,
+    /// If present, write to LMDB through a writeable memory map. Faster, but stray writes of the
+    /// process can corrupt the store.
+    #[arg(long)]
+    pub lmdb_write_map: bool,
+    /// If present, order each TiKV read after every acknowledged write, so that a worker sees
+    /// what another wrote just before handing off to it. Each read then costs a
+    /// compare-and-swap.
+    #[arg(long, conflicts_with = "lmdb")]
+    pub tikv_consistent_reads: bool,
+}
+
+impl Store {
+    pub fn lmdb_options(&self) -> crate::fs::lmdb::Options {
+        const MB: usize = 1024 * 1024;
+        crate::fs::lmdb::Options {
+            map_size: self.lmdb_map_size * MB,
+            max_map_size: self.lmdb_max_map_size.max(self.lmdb_map_size) * MB,
+            no_sync: self.lmdb_no_sync,
+            write_map: self.lmdb_write_map,
+        }
+    }
+
+    pub fn tikv_read_mode(&self) -> crate::fs::tikv::ReadMode {
+        crate::fs::tikv::ReadMode::new(self.tikv_consistent_reads)
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct Maintenance {
+    /// Crontab expression of the minutes, in UTC, maintenance may run in, e.g. "* 2-4 * * *".
+    /// Any time if absent.
+    #[arg(long, value_name = "CRON")]
+    pub window: Option<crate::maintenance::Window>,
+    /// Scheduler whose queue depth maintenance yields to
+    #[arg(long, value_name = "ADDR:PORT")]
+    pub scheduler: Option<String>,
+    /// Pause maintenance while more tasks than this are queued at the scheduler
+    #[arg(long, value_name = "COUNT", default_value_t = 0, requires = "scheduler")]
+    pub max_queue_depth: u64,
+}
+
+impl Maintenance {
+    pub fn build(&self) -> crate::maintenance::Maintenance {
+        let scheduler = self.scheduler.as_deref().map(crate::sched::rpc::connect_retrying);
+        crate::maintenance::Maintenance::new(self.window.clone(), scheduler, self.max_queue_depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tikv::ReadMode;
+
+    #[derive(Parser, Debug)]
+    struct Cli {
+        #[command(flatten)]
+        store: Store,
+    }
+
+    #[test]
+    fn test_tikv_read_mode() {
+        let cli = Cli::try_parse_from(["test", "--tikv", "pd:2379"]).unwrap();
+        assert_eq!(cli.store.tikv_read_mode(), ReadMode::Fast);
+        let args = ["test", "--tikv", "pd:2379", "--tikv-consistent-reads"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.store.tikv_read_mode(), ReadMode::Consistent);
+        let args = ["test", "--lmdb", "store", "--tikv-consistent-reads"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
 }
@@ -2,6 +2,8 @@
 //! In-memory data structures that represent controller configuration and
 //! function configurations
 use serde::{Deserialize, Serialize};
+
+use crate::hypervisor::Backend;
 //use serde_yaml;
 //use url::Url;
 //use log::{info, debug};
@@ -110,6 +112,17 @@ use serde::{Deserialize, Serialize};
 //    //}
 //}
 
+/// I/O engine of the VM's block devices. Our Firecracker fork only has the synchronous one.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IoEngine {
+    /// blocking reads and writes on the VMM thread
+    #[default]
+    Sync,
+    /// io_uring
+    Async,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FunctionConfig {
     /// enable network, requires "tap"
@@ -123,6 +136,9 @@ pub struct FunctionConfig {
     /// path to appfs
     #[serde(default)]
     pub appfs: Option<String>,
+    /// paths to layers overlaid on the appfs, the last on top
+    #[serde(default)]
+    pub app_layers: Vec<String>,
     /// VM vcpu count
     pub vcpus: u64,
     /// VM memory size
@@ -137,6 +153,9 @@ pub struct FunctionConfig {
     /// copy diff snapshot memory dump
     #[serde(default)]
     pub copy_diff: bool,
+    /// number of threads loading the base snapshot memory dump when copying it
+    #[serde(default = "default_load_threads")]
+    pub load_threads: usize,
     /// path to uncompressed kernel, only used by `fc_wrapper` not by `snapctr`
     /// `snapctr` set this field to the path specified in the configuration file
     #[serde(default)]
@@ -155,6 +174,23 @@ pub struct FunctionConfig {
     /// load the working set
     #[serde(default)]
     pub load_ws: bool,
+    /// size in MB of the memory region shared with the guest runtime, 0 disables it
+    #[serde(default)]
+    pub shared_mem: usize,
+    /// I/O engine of the block devices
+    #[serde(default)]
+    pub io_engine: IoEngine,
+    /// PCI addresses of host devices passed through to the VM with VFIO. Only cloud-hypervisor
+    /// can pass devices through.
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// hypervisor that boots the VM
+    #[serde(default)]
+    pub hypervisor: Backend,
+}
+
+fn default_load_threads() -> usize {
+    1
 }
 
 impl From<super::fs::Function> for FunctionConfig {
@@ -163,6 +199,7 @@ impl From<super::fs::Function> for FunctionConfig {
         default.memory = f.memory;
         default.runtimefs = f.runtime_image;
         default.appfs = Some(f.app_image);
+        default.app_layers = f.app_layers;
         default.kernel = f.kernel;
         default
     }
@@ -176,6 +213,7 @@ impl Default for FunctionConfig {
             kernel: String::new(),
             runtimefs: String::new(),
             appfs: None,
+            app_layers: Vec::new(),
             vcpus: 1,
             memory: 128,
             concurrency_limit: 1, // not in use
@@ -183,10 +221,45 @@ impl Default for FunctionConfig {
             //diff_dirs: None,
             copy_base: false,
             copy_diff: true,
+            load_threads: default_load_threads(),
             cmdline: None,
             dump_dir: None,
             dump_ws: false,
             load_ws: false,
+            shared_mem: 0,
+            io_engine: IoEngine::Sync,
+            devices: Vec::new(),
+            hypervisor: Backend::Firecracker,
         }
     }
 }
+
+/// Runtime tunables of a worker node. `multivm` reads them as JSON from a file in the FS at
+/// startup and whenever the scheduler asks it to reload, so they change without restarting
+/// workers. Missing fields mean no limit.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// most tasks the node runs at once
+    #[serde(default)]
+    pub pool_size: Option<usize>,
+    /// MBs of memory idle VMs may hold on to
+    #[serde(default)]
+    pub cache_budget: Option<usize>,
+    /// seconds an idle VM lives before it is shut down, unless its gate says otherwise. Idle
+    /// VMs live until their memory is needed if absent.
+    #[serde(default)]
+    pub idle_ttl: Option<u64>,
+    /// hosts services may send requests to
+    #[serde(default)]
+    pub egress: Option<Vec<String>>,
+    /// bytes of blobs past which the worker warns each time it saves a blob
+    #[serde(default)]
+    pub blob_usage_alert: Option<u64>,
+    /// most idle VMs per gate kept from eviction for its min_instances, 1 if absent
+    #[serde(default)]
+    pub max_min_instances: Option<usize>,
+    /// most idle VMs per keep-alive gate kept up between invocations, 1 if absent
+    #[serde(default)]
+    pub max_keep_alive: Option<usize>,
+}
@@ -0,0 +1,163 @@
+//! Time-limited delegation certificates.
+//!
+//! A delegation certificate states that principal `grantor` grants the privilege `grant` to
+//! `grantee` until `exp` (seconds since the UNIX epoch). Certificates are ES256 JWTs signed with
+//! the platform key, the same key webfront uses for login tokens. Whoever holds a certificate and
+//! speaks for `grantee` may merge `grant` into their privilege for the duration of an invocation,
+//! which gives temporary access without editing any gate policy.
+//!
+//! Other tokens are signed with the same key, so certificates carry `typ` set to
+//! `DELEGATION_TYPE` and only tokens with it verify as certificates.
+use std::time::SystemTime;
+
+use jwt::{PKeyWithDigest, SignWithKey, VerifyWithKey};
+use labeled::buckle::Component;
+use openssl::pkey::{PKey, Private, Public};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug)]
+pub enum DelegationError {
+    /// the grantor does not own the granted privilege
+    GrantorNotAuthorized,
+    /// the holder does not speak for the grantee
+    NotGrantee,
+    Expired,
+    BadSignature,
+    /// the token is not a delegation certificate
+    WrongType,
+    /// the certificate would be valid for longer than `MAX_TTL`
+    TtlTooLong,
+}
+
+/// `typ` of delegation certificates
+pub const DELEGATION_TYPE: &str = "faasten-delegation";
+
+/// Longest a certificate may be valid for, in seconds
+pub const MAX_TTL: u64 = 7 * 24 * 3600;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Delegation {
+    pub alg: String,
+    /// `DELEGATION_TYPE`, absent from tokens of other kinds
+    #[serde(default)]
+    pub typ: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub grantor: Component,
+    pub grantee: Component,
+    pub grant: Component,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl Delegation {
+    /// Create a certificate valid for `ttl` seconds, at most `MAX_TTL`. `grantor` must already
+    /// own `grant`.
+    pub fn new(
+        grantor: Component,
+        grantee: Component,
+        grant: Component,
+        ttl: u64,
+    ) -> Result<Self, DelegationError> {
+        if !grantor.implies(&grant) {
+            return Err(DelegationError::GrantorNotAuthorized);
+        }
+        if ttl > MAX_TTL {
+            return Err(DelegationError::TtlTooLong);
+        }
+        let now = now();
+        Ok(Delegation {
+            alg: "ES256".to_string(),
+            typ: DELEGATION_TYPE.to_string(),
+            iat: now,
+            exp: now + ttl,
+            grantor,
+            grantee,
+            grant,
+        })
+    }
+
+    pub fn sign(&self, key: &PKey<Private>) -> String {
+        let key = PKeyWithDigest {
+            key: key.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        self.sign_with_key(&key).unwrap()
+    }
+
+    /// Check the signature, the type and the expiration time of a certificate.
+    pub fn verify(token: &str, key: &PKey<Public>) -> Result<Self, DelegationError> {
+        let key = PKeyWithDigest {
+            key: key.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        let delegation: Delegation = token
+            .verify_with_key(&key)
+            .map_err(|_| DelegationError::BadSignature)?;
+        if delegation.typ != DELEGATION_TYPE {
+            return Err(DelegationError::WrongType);
+        }
+        if delegation.exp < now() {
+            return Err(DelegationError::Expired);
+        }
+        if !delegation.grantor.implies(&delegation.grant) {
+            return Err(DelegationError::GrantorNotAuthorized);
+        }
+        Ok(delegation)
+    }
+
+    /// Merge the granted privilege into `privilege`, which must speak for the grantee.
+    pub fn apply(&self, privilege: Component) -> Result<Component, DelegationError> {
+        if self.exp < now() {
+            return Err(DelegationError::Expired);
+        }
+        if !privilege.implies(&self.grantee) {
+            return Err(DelegationError::NotGrantee);
+        }
+        Ok(privilege & self.grant.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    fn keypair() -> (PKey<Private>, PKey<Public>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let public = EcKey::from_public_key(&group, key.public_key()).unwrap();
+        (PKey::from_ec_key(key).unwrap(), PKey::from_ec_key(public).unwrap())
+    }
+
+    #[test]
+    fn test_verify() {
+        let (private, public) = keypair();
+        let alice = labeled::buckle::Buckle::parse("alice,T").unwrap().secrecy;
+        let bob = labeled::buckle::Buckle::parse("bob,T").unwrap().secrecy;
+        assert!(matches!(
+            Delegation::new(alice.clone(), bob.clone(), alice.clone(), MAX_TTL + 1),
+            Err(DelegationError::TtlTooLong)
+        ));
+        assert!(matches!(
+            Delegation::new(alice.clone(), bob.clone(), alice.clone(), u64::MAX),
+            Err(DelegationError::TtlTooLong)
+        ));
+
+        let mut delegation = Delegation::new(alice.clone(), bob.clone(), alice, 60).unwrap();
+        let verified = Delegation::verify(&delegation.sign(&private), &public).unwrap();
+        assert!(verified.apply(bob).is_ok());
+        // another kind of token signed with the same key
+        delegation.typ = String::new();
+        assert!(matches!(
+            Delegation::verify(&delegation.sign(&private), &public),
+            Err(DelegationError::WrongType)
+        ));
+    }
+}
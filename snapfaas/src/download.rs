@@ -0,0 +1,101 @@
+//! Signed download links.
+//!
+//! A download token lets whoever holds it read one file or blob until the token expires, without
+//! logging in. Minting a token releases the object's data to the public, so it requires a
+//! privilege that can declassify the object. The token records the label that check passed at.
+//! Tokens are ES256 JWTs that webfront verifies without keeping state and serves at
+//! `/faasten/download/<token>`, as long as the object's label still flows to the recorded one.
+use jwt::{PKeyWithDigest, SignWithKey, VerifyWithKey};
+use labeled::buckle::Buckle;
+use labeled::Label;
+use openssl::pkey::{PKey, Private, Public};
+use serde::{Deserialize, Serialize};
+
+use crate::fs::{self, BackingStore, DirEntry, FS};
+
+/// Longest a download token may live, in seconds
+pub const MAX_TTL: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug)]
+pub enum DownloadError {
+    BadSignature,
+    Expired,
+    /// only files and blobs can be downloaded
+    NotDownloadable,
+    /// the minter's privilege can't declassify the object
+    Unauthorized,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DownloadToken {
+    pub alg: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub entry: DirEntry,
+    /// the object's label joined with the minter's label at minting
+    pub label: Buckle,
+}
+
+impl DownloadToken {
+    /// Mints a token for `entry` valid for `ttl` seconds, capped at `MAX_TTL`, if the thread's
+    /// privilege can declassify the object's data read at the current label.
+    ///
+    /// The thread's current label is tainted with the object's label.
+    pub fn mint<B: BackingStore>(
+        fs: &FS<B>,
+        entry: DirEntry,
+        ttl: u64,
+    ) -> Result<Self, DownloadError> {
+        let label = entry_label(fs, &entry)?;
+        let label = fs::utils::taint_with_label(label);
+        if !fs::utils::get_privilege().implies(&label.secrecy) {
+            return Err(DownloadError::Unauthorized);
+        }
+        let now = crate::delegation::now();
+        Ok(DownloadToken {
+            alg: "ES256".to_string(),
+            iat: now,
+            exp: now + ttl.min(MAX_TTL),
+            entry,
+            label,
+        })
+    }
+
+    pub fn sign(&self, key: &PKey<Private>) -> String {
+        let key = PKeyWithDigest {
+            key: key.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        self.sign_with_key(&key).unwrap()
+    }
+
+    /// Checks the signature and expiry of a token
+    pub fn verify(token: &str, key: &PKey<Public>) -> Result<Self, DownloadError> {
+        let key = PKeyWithDigest {
+            key: key.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        let token: Self = token
+            .verify_with_key(&key)
+            .map_err(|_| DownloadError::BadSignature)?;
+        if token.exp < crate::delegation::now() {
+            return Err(DownloadError::Expired);
+        }
+        Ok(token)
+    }
+
+    /// Whether the object may still be released under this token, i.e. it exists and has not
+    /// become more secret than it was at minting
+    pub fn permits<B: BackingStore>(&self, fs: &FS<B>) -> bool {
+        entry_label(fs, &self.entry).is_ok_and(|label| label.can_flow_to(&self.label))
+    }
+}
+
+fn entry_label<B: BackingStore>(fs: &FS<B>, entry: &DirEntry) -> Result<Buckle, DownloadError> {
+    let label = match entry {
+        DirEntry::File(file) => file.get(fs).map(|f| f.label().clone()),
+        DirEntry::Blob(blob) => blob.get(fs).map(|b| b.label().clone()),
+        _ => return Err(DownloadError::NotDownloadable),
+    };
+    label.ok_or(DownloadError::NotDownloadable)
+}
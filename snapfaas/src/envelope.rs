@@ -0,0 +1,205 @@
+//! End-to-end encryption of invocation payloads and results
+//!
+//! In deployments whose scheduler nodes are run by other operators, payloads and results should
+//! be opaque to the scheduler. The webfront seals a payload with a key derived for the target
+//! gate from a secret it shares with the workers, so that only workers open it. A worker seals
+//! the body of the result to a public key of the invoker's, an ephemeral P-256 key agreement
+//! away, so that only the invoker opens it. Both use AES-256-GCM. Headers, labels, blobs other
+//! than a spilled payload and streamed response chunks stay in the clear.
+//!
+//! A sealed payload is bound to the label of its invocation and to a random seal id, see
+//! `Binding`, so the scheduler can't relabel it. Worker nodes open each seal id for one task
+//! only, see `Opened`, so the scheduler can't replay a payload into another invocation on the
+//! same node either.
+use std::collections::{HashMap, VecDeque};
+
+use labeled::buckle::Component;
+use prost::Message;
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum EnvelopeError {
+    /// too short, or not sealed with the key
+    Malformed,
+    Crypto(ErrorStack),
+}
+
+impl From<ErrorStack> for EnvelopeError {
+    fn from(e: ErrorStack) -> Self {
+        EnvelopeError::Crypto(e)
+    }
+}
+
+/// Key sealing the payloads of invocations of the gate granting `gate`
+pub fn gate_key(secret: &[u8], gate: &Component) -> Vec<u8> {
+    let gate = serde_json::to_vec(gate).unwrap();
+    kdf(secret, b"faasten payload", &gate).unwrap()
+}
+
+/// The invocation a payload is sealed for
+pub struct Binding<'a> {
+    /// label of the invocation
+    pub label: &'a crate::syscalls::Buckle,
+    /// random id the sealer picked for the invocation, see `new_seal_id`
+    pub seal_id: &'a str,
+}
+
+impl Binding<'_> {
+    // associated data of the payload's AES-GCM
+    fn aad(&self) -> Vec<u8> {
+        [&self.label.encode_to_vec()[..], &[0], self.seal_id.as_bytes()].concat()
+    }
+}
+
+/// A fresh seal id for `Binding::seal_id`
+pub fn new_seal_id() -> Result<String, EnvelopeError> {
+    let mut id = [0; 16];
+    openssl::rand::rand_bytes(&mut id)?;
+    Ok(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Seals `plaintext` with a key from `gate_key` for the invocation `binding`
+pub fn seal(key: &[u8], binding: &Binding, plaintext: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    seal_aead(key, &binding.aad(), plaintext)
+}
+
+/// Opens what `seal` sealed with `key`, if it was sealed for the invocation `binding`
+pub fn open(key: &[u8], binding: &Binding, sealed: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    open_aead(key, &binding.aad(), sealed)
+}
+
+fn seal_aead(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    let mut nonce = [0; NONCE_LEN];
+    openssl::rand::rand_bytes(&mut nonce)?;
+    let mut tag = [0; TAG_LEN];
+    let cipher = Cipher::aes_256_gcm();
+    let ciphertext = encrypt_aead(cipher, key, Some(&nonce), aad, plaintext, &mut tag)?;
+    Ok([&nonce[..], &ciphertext, &tag].concat())
+}
+
+fn open_aead(key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(EnvelopeError::Malformed);
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    decrypt_aead(Cipher::aes_256_gcm(), key, Some(nonce), aad, ciphertext, tag)
+        .map_err(|_| EnvelopeError::Malformed)
+}
+
+// seal ids a worker node remembers
+const OPENED_CAPACITY: usize = 100_000;
+
+/// Seal ids of the payloads a worker node opened and the tasks it opened them for. The oldest
+/// are forgotten past a bound.
+#[derive(Debug, Default)]
+pub struct Opened {
+    tasks: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl Opened {
+    /// Records that the payload sealed as `seal_id` is opened for `task_id`. False if it was
+    /// opened for another task before, i.e. it is replayed. A preempted task runs again as the
+    /// same task, so it may open its payload again.
+    pub fn record(&mut self, seal_id: &str, task_id: &str) -> bool {
+        if let Some(task) = self.tasks.get(seal_id) {
+            return task == task_id;
+        }
+        if self.order.len() >= OPENED_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.tasks.remove(&oldest);
+            }
+        }
+        self.tasks.insert(seal_id.to_string(), task_id.to_string());
+        self.order.push_back(seal_id.to_string());
+        true
+    }
+}
+
+/// Seals `plaintext` to the P-256 public key `to`. The result is the length of the ephemeral
+/// public key as 2 big-endian bytes, the key DER encoded, then what `seal` returns.
+pub fn seal_to(to: &PKey<Public>, plaintext: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let ephemeral = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let ephemeral_der = ephemeral.public_key_to_der()?;
+    let mut deriver = Deriver::new(&ephemeral)?;
+    deriver.set_peer(to)?;
+    let key = kdf(&deriver.derive_to_vec()?, b"faasten result", &ephemeral_der)?;
+    let len = (ephemeral_der.len() as u16).to_be_bytes();
+    Ok([&len[..], &ephemeral_der, &seal_aead(&key, &[], plaintext)?].concat())
+}
+
+/// Opens what `seal_to` sealed to the public key of `key`, for invokers
+pub fn open_with(key: &PKey<Private>, sealed: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    if sealed.len() < 2 {
+        return Err(EnvelopeError::Malformed);
+    }
+    let len = u16::from_be_bytes([sealed[0], sealed[1]]) as usize;
+    let ephemeral_der = sealed.get(2..2 + len).ok_or(EnvelopeError::Malformed)?;
+    let ephemeral =
+        PKey::public_key_from_der(ephemeral_der).map_err(|_| EnvelopeError::Malformed)?;
+    let mut deriver = Deriver::new(key)?;
+    deriver.set_peer(&ephemeral)?;
+    let key = kdf(&deriver.derive_to_vec()?, b"faasten result", ephemeral_der)?;
+    open_aead(&key, &[], &sealed[2 + len..])
+}
+
+// A 256-bit key for `context` bound to `info`, HMAC-SHA256 keyed with `secret`
+fn kdf(secret: &[u8], context: &[u8], info: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(secret)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(context)?;
+    signer.update(&[0])?;
+    signer.update(info)?;
+    signer.sign_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binding() {
+        let key = gate_key(b"secret", &Component::dc_true());
+        let label: crate::syscalls::Buckle = labeled::buckle::Buckle::public().into();
+        let binding = Binding {
+            label: &label,
+            seal_id: "a",
+        };
+        let sealed = seal(&key, &binding, b"payload").unwrap();
+        assert_eq!(open(&key, &binding, &sealed).unwrap(), b"payload");
+        let other_id = Binding {
+            label: &label,
+            seal_id: "b",
+        };
+        assert!(open(&key, &other_id, &sealed).is_err());
+        let relabeled: crate::syscalls::Buckle =
+            labeled::buckle::Buckle::parse("alice,T").unwrap().into();
+        let relabeled = Binding {
+            label: &relabeled,
+            seal_id: "a",
+        };
+        assert!(open(&key, &relabeled, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_opened() {
+        let mut opened = Opened::default();
+        assert!(opened.record("a", "task1"));
+        // a preempted task opens its payload again
+        assert!(opened.record("a", "task1"));
+        assert!(!opened.record("a", "task2"));
+        assert!(opened.record("b", "task2"));
+    }
+}
@@ -16,6 +16,7 @@ use vmm::vmm_config::machine_config::VmConfig;
 use vmm::SnapFaaSConfig;
 use sys_util::EventFd;
 
+
 pub struct VmmWrapper {
     vmm_thread_handle: JoinHandle<()>,
     vmm_action_sender: Sender<Box<VmmAction>>,
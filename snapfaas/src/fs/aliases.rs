@@ -0,0 +1,136 @@
+//! Human-readable label aliases
+//!
+//! Aliases (e.g., `alice-private` for `alice,T`) are stored as a JSON map in the file
+//! `home:<T,faasten>:label_aliases`. Anyone can read the table but only `faasten` can update it.
+//! Alias names are never themselves Buckle strings, so an alias can't change what a label
+//! written out in full means.
+use std::collections::BTreeMap;
+
+use labeled::buckle::Buckle;
+use lazy_static::lazy_static;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{path::Path, BackingStore, FsError, FS};
+
+const ALIASES_FILE: &str = "label_aliases";
+
+lazy_static! {
+    static ref ALIASES_BASE: Path = Path::parse("home:<T,faasten>").unwrap();
+    static ref ALIASES_POLICY: Buckle = Buckle::parse("T,faasten").unwrap();
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LabelAliases(BTreeMap<String, String>);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AliasError {
+    /// the aliased label is not a valid Buckle string
+    InvalidLabel,
+    /// the alias name is a valid Buckle string, which the alias would shadow
+    NameIsLabel,
+}
+
+impl std::fmt::Display for AliasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AliasError::InvalidLabel => write!(f, "invalid label"),
+            AliasError::NameIsLabel => write!(f, "alias names may not be labels"),
+        }
+    }
+}
+
+impl LabelAliases {
+    /// Loads the alias table, or an empty table if none is installed.
+    ///
+    /// The table is public and trusted, so reading it leaves the current label untouched.
+    pub fn load<S: BackingStore>(fs: &FS<S>) -> Self {
+        let saved = super::utils::get_current_label();
+        let mut path = ALIASES_BASE.clone();
+        path.push_dscrp(ALIASES_FILE.to_string());
+        let mut aliases: Self = fs
+            .read_file(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        super::utils::clear_label();
+        super::utils::taint_with_label(saved);
+        // tables saved before names were checked may have names that shadow labels
+        aliases.0.retain(|name, _| {
+            let shadows = Buckle::parse(name).is_ok();
+            if shadows {
+                warn!("Ignoring label alias {:?}, which is itself a label", name);
+            }
+            !shadows
+        });
+        aliases
+    }
+
+    /// Stores the table. Requires `faasten`'s privilege.
+    pub fn save<S: BackingStore>(&self, fs: &FS<S>) -> Result<(), FsError> {
+        super::utils::create_or_update_file(
+            fs,
+            ALIASES_BASE.clone(),
+            ALIASES_FILE.to_string(),
+            ALIASES_POLICY.clone(),
+            serde_json::to_vec(self).unwrap(),
+        )
+    }
+
+    /// Registers `name` for `label`. `label` must itself be a valid Buckle string and `name`
+    /// must not be one.
+    pub fn insert(&mut self, name: String, label: String) -> Result<Buckle, AliasError> {
+        if Buckle::parse(&name).is_ok() {
+            return Err(AliasError::NameIsLabel);
+        }
+        let parsed = Buckle::parse(&label).map_err(|_| AliasError::InvalidLabel)?;
+        self.0.insert(name, label);
+        Ok(parsed)
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.0.remove(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter()
+    }
+
+    /// Parses `label` as an alias name first and as a Buckle string otherwise.
+    pub fn resolve(&self, label: &str) -> Result<Buckle, impl std::fmt::Display + std::fmt::Debug> {
+        Buckle::parse(self.0.get(label).map(String::as_str).unwrap_or(label))
+    }
+
+    /// Formats `label` with its alias name if it has one.
+    pub fn display(&self, label: &Buckle) -> String {
+        self.0
+            .iter()
+            .find(|(_, l)| Buckle::parse(l).is_ok_and(|l| &l == label))
+            .map(|(name, _)| name.clone())
+            .unwrap_or(format!("{:?}", label))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut aliases = LabelAliases::default();
+        assert!(aliases.insert("alice-private".to_string(), "alice,T".to_string()).is_ok());
+        assert_eq!(
+            aliases.insert("alice,T".to_string(), "T,T".to_string()),
+            Err(AliasError::NameIsLabel)
+        );
+        assert_eq!(
+            aliases.insert("bad".to_string(), "alice".to_string()),
+            Err(AliasError::InvalidLabel)
+        );
+        assert_eq!(
+            aliases.resolve("alice-private").unwrap(),
+            Buckle::parse("alice,T").unwrap()
+        );
+        assert_eq!(aliases.resolve("alice,T").unwrap(), Buckle::parse("alice,T").unwrap());
+    }
+}
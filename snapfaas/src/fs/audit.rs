@@ -0,0 +1,238 @@
+//! Audit trail of gate and service updates
+//!
+//! Replacing a gate or a service changes who may invoke what with which privilege, and the
+//! entry itself keeps no trace of what it was. Every `replace` of one first appends a record of
+//! the change to an append-only log at a fixed object that only `faasten` can read. Appends
+//! bypass label checks, so that updates by any principal are recorded. Clients elevating to
+//! `faasten`'s privilege, see `super::sudoers`, record that in the same log, as do workers when
+//! an invocation declassifies data or releases it through a download token.
+use labeled::buckle::{Buckle, Component};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    content_digest, BackingStore, CasRetry, FsError, Gate, Labeled, Lease, Log, LogEntry,
+    ObjectRef, Service, FS,
+};
+
+pub(super) const AUDIT_LOG_REF: ObjectRef<Labeled<Log>> = ObjectRef::new(1);
+
+lazy_static! {
+    static ref AUDIT_POLICY: Buckle = Buckle::parse("faasten,faasten").unwrap();
+}
+
+/// What a gate or service granted before or after an update
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Version {
+    pub privilege: Component,
+    /// hex encoded SHA-256 of a direct gate's function, or of the whole entry for redirect
+    /// gates and services
+    pub digest: String,
+}
+
+impl From<&Gate> for Version {
+    fn from(gate: &Gate) -> Self {
+        match gate {
+            Gate::Direct(g) => Version {
+                privilege: g.privilege.clone(),
+                digest: content_digest(&serde_json::to_vec(&g.function).unwrap()),
+            },
+            Gate::Redirect(g) => Version {
+                privilege: g.privilege.clone(),
+                digest: content_digest(&serde_json::to_vec(g).unwrap()),
+            },
+        }
+    }
+}
+
+impl From<&Service> for Version {
+    fn from(service: &Service) -> Self {
+        Version {
+            privilege: service.privilege.clone(),
+            digest: content_digest(&serde_json::to_vec(service).unwrap()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// milliseconds since the UNIX epoch, from the log entry
+    #[serde(skip)]
+    pub time: u64,
+    /// privilege of the principal that made the update
+    pub who: Component,
+    /// "gate", "service", "sudo", "declassify" or "download"
+    pub kind: String,
+    /// object id of the updated entry, the same for every link to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old: Option<Version>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new: Option<Version>,
+    /// what a principal elevated to `faasten`'s privilege for, or the path a download token
+    /// released
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// the invocation that declassified or released data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task: Option<String>,
+    /// label of the declassified or released data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<Buckle>,
+    /// label of the data once declassified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<Buckle>,
+}
+
+// Appends a record of the update of entry `object` from `old` to `new`
+pub(super) fn record<B: BackingStore>(
+    fs: &FS<B>,
+    kind: &str,
+    object: u64,
+    old: Version,
+    new: Version,
+) -> Result<(), FsError> {
+    append(fs, AuditRecord {
+        time: 0,
+        who: super::utils::get_privilege(),
+        kind: kind.to_string(),
+        object: Some(object),
+        old: Some(old),
+        new: Some(new),
+        command: None,
+        task: None,
+        from: None,
+        to: None,
+    })
+}
+
+/// Records that `who` elevated to `faasten`'s privilege to run `command`
+pub fn record_elevation<B: BackingStore>(
+    fs: &FS<B>,
+    who: Component,
+    command: String,
+) -> Result<(), FsError> {
+    append(fs, AuditRecord {
+        time: 0,
+        who,
+        kind: "sudo".to_string(),
+        object: None,
+        old: None,
+        new: None,
+        command: Some(command),
+        task: None,
+        from: None,
+        to: None,
+    })
+}
+
+/// Records that task `task` declassified data labeled `from` to `to` with privilege `who`
+pub fn record_declassification<B: BackingStore>(
+    fs: &FS<B>,
+    task: String,
+    who: Component,
+    from: Buckle,
+    to: Buckle,
+) -> Result<(), FsError> {
+    append(fs, AuditRecord {
+        time: 0,
+        who,
+        kind: "declassify".to_string(),
+        object: None,
+        old: None,
+        new: None,
+        command: None,
+        task: Some(task),
+        from: Some(from),
+        to: Some(to),
+    })
+}
+
+/// Records that task `task` released the data labeled `label` at `path` through a download
+/// token
+pub fn record_download<B: BackingStore>(
+    fs: &FS<B>,
+    task: String,
+    path: String,
+    label: Buckle,
+) -> Result<(), FsError> {
+    append(fs, AuditRecord {
+        time: 0,
+        who: super::utils::get_privilege(),
+        kind: "download".to_string(),
+        object: None,
+        old: None,
+        new: None,
+        command: Some(path),
+        task: Some(task),
+        from: Some(label),
+        to: None,
+    })
+}
+
+fn append<B: BackingStore>(fs: &FS<B>, record: AuditRecord) -> Result<(), FsError> {
+    let entry = LogEntry { time: Lease::now(), data: serde_json::to_vec(&record).unwrap() };
+    let mut retry = CasRetry::new();
+    loop {
+        let prev_log = AUDIT_LOG_REF.get(fs);
+        let mut labeled_log = prev_log.clone().unwrap_or_else(|| Labeled {
+            label: AUDIT_POLICY.clone(),
+            data: Log::default(),
+            frozen: false,
+            digest: None,
+            blob_meta: None,
+        });
+        let (_, sealed) = labeled_log.data.push(entry.clone(), &fs.0);
+        match AUDIT_LOG_REF.cas(prev_log.as_ref(), &labeled_log, &fs.0) {
+            Ok(()) => return Ok(()),
+            Err(_) => {
+                if let Some(chunk) = sealed {
+                    chunk.delete(&fs.0);
+                }
+                retry.backoff()?;
+            }
+        }
+    }
+}
+
+/// Returns the records with indices in `from..to`, like `read_range` on a log. Taints the
+/// current label with `faasten`'s secrecy.
+pub fn read<B: BackingStore>(fs: &FS<B>, from: u64, to: u64) -> Result<Vec<AuditRecord>, FsError> {
+    if AUDIT_LOG_REF.get(fs).is_none() {
+        return Ok(Vec::new());
+    }
+    let entries = AUDIT_LOG_REF.read_range(from, to, fs)?;
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| {
+            let mut record: AuditRecord = serde_json::from_slice(&entry.data).ok()?;
+            record.time = entry.time;
+            Some(record)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memory::MemoryStore;
+
+    #[test]
+    fn test_record_declassification() {
+        let fs = FS::new(MemoryStore::default());
+        fs.initialize();
+        super::super::utils::clear_label();
+        super::super::utils::set_my_privilge(Component::dc_false());
+        let from = Buckle::parse("alice,T").unwrap();
+        let who = from.secrecy.clone();
+        record_declassification(&fs, "task".to_string(), who, from.clone(), Buckle::public())
+            .unwrap();
+        let records = read(&fs, 0, 10).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].kind, "declassify");
+        assert_eq!(records[0].task.as_deref(), Some("task"));
+        assert_eq!(records[0].from, Some(from));
+        assert_eq!(records[0].to, Some(Buckle::public()));
+    }
+}
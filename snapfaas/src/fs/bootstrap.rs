@@ -1,16 +1,19 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 use lazy_static::lazy_static;
 use log::{debug, warn};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use sha2::Sha256;
 
 use labeled::buckle::{self, Component, Buckle};
 
-use super::{BackingStore, Blob, FsError};
+use super::{BackingStore, Blob, BlobMeta, FsError};
 use crate::{blobstore::Blobstore, fs::{Function, DirectGate, DirEntry, Gate}};
 
 const FSUTIL_MEMSIZE: usize = 128;
+const MANAGEMENT_MEMSIZE: usize = 128;
 
 lazy_static! {
     static ref FSTN_IMAGE_BASE: super::path::Path =
@@ -27,33 +30,190 @@ lazy_static! {
 const ROOT_PRIV: buckle::Component = buckle::Component::dc_false();
 const EMPTY_PRIV: buckle::Component = buckle::Component::dc_true();
 
-fn localfile2blob(blobstore: &mut Blobstore, local_path: &str) -> String {
+/// Bootstrap configuration. Any value can be replaced by `!include <path>` to read it from another
+/// YAML file, with `<path>` relative to the including file. Image paths are relative to the
+/// working directory.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// the default kernel
+    pub kernel: PathBuf,
+    /// additional kernels by version, installed as `kernel-<version>`
+    #[serde(default)]
+    pub kernels: BTreeMap<String, PathBuf>,
+    pub python: PathBuf,
+    pub fsutil: PathBuf,
+    /// image of the management function, installed as a gate at `home:<T,faasten>:management`
+    /// that lets invokers endorsed by `faasten` deploy, update and list gates as `faasten`
+    #[serde(default)]
+    pub management: Option<PathBuf>,
+    /// installed under their file names
+    #[serde(default)]
+    pub other_runtimes: Vec<PathBuf>,
+    /// label of the installed kernels and images, `T,faasten` by default
+    #[serde(default = "default_image_label", deserialize_with = "deserialize_label")]
+    pub label: Buckle,
+}
+
+fn default_image_label() -> Buckle {
+    FSUTIL_POLICY.clone()
+}
+
+fn deserialize_label<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Buckle, D::Error> {
+    let label = String::deserialize(deserializer)?;
+    Buckle::parse(&label)
+        .map_err(|_| serde::de::Error::custom(format!("bad label `{}`", label)))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    /// not YAML, or doesn't match `Config`
+    Parse(PathBuf, serde_yaml::Error),
+    BadInclude(PathBuf),
+    IncludeCycle(PathBuf),
+    MissingImage(PathBuf),
+    /// two images would be installed under the same name
+    DuplicateImage(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::BadInclude(path) => {
+                write!(f, "{}: !include takes the path of a YAML file", path.display())
+            }
+            ConfigError::IncludeCycle(path) => {
+                write!(f, "{}: includes itself", path.display())
+            }
+            ConfigError::MissingImage(path) => {
+                write!(f, "image {} is not a file", path.display())
+            }
+            ConfigError::DuplicateImage(name) => {
+                write!(f, "more than one image would be installed as `{}`", name)
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Reads and validates the configuration at `path`, following `!include`s
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let value = load_yaml(path, &mut Vec::new())?;
+        let config: Config =
+            serde_yaml::from_value(value).map_err(|e| ConfigError::Parse(path.into(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut names = std::collections::BTreeSet::new();
+        let kernels = self.kernels.iter().map(|(v, path)| (kernel_name(Some(v)), path));
+        let images = vec![
+            (kernel_name(None), &self.kernel),
+            ("python".to_string(), &self.python),
+            ("fsutil_image".to_string(), &self.fsutil),
+            ("fsutil".to_string(), &self.fsutil),
+        ]
+        .into_iter()
+        .chain(self.management.iter().map(|path| ("management_image".to_string(), path)))
+        .chain(kernels);
+        for (name, path) in images {
+            if !path.is_file() {
+                return Err(ConfigError::MissingImage(path.clone()));
+            }
+            names.insert(name);
+        }
+        for path in self.other_runtimes.iter() {
+            if !path.is_file() {
+                return Err(ConfigError::MissingImage(path.clone()));
+            }
+            let name = runtime_name(path);
+            if !names.insert(name.clone()) {
+                return Err(ConfigError::DuplicateImage(name));
+            }
+        }
+        Ok(())
+    }
+}
+
+// `stack` holds the files including `path`
+fn load_yaml(path: &Path, stack: &mut Vec<PathBuf>) -> Result<serde_yaml::Value, ConfigError> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| ConfigError::Io(path.into(), e))?;
+    if stack.contains(&canonical) {
+        return Err(ConfigError::IncludeCycle(path.into()));
+    }
+    let file = std::fs::File::open(path).map_err(|e| ConfigError::Io(path.into(), e))?;
+    let value = serde_yaml::from_reader(file).map_err(|e| ConfigError::Parse(path.into(), e))?;
+    stack.push(canonical);
+    let value = resolve_includes(value, path, stack)?;
+    stack.pop();
+    Ok(value)
+}
+
+fn resolve_includes(
+    value: serde_yaml::Value,
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+) -> Result<serde_yaml::Value, ConfigError> {
+    use serde_yaml::Value;
+    Ok(match value {
+        Value::Tagged(tagged) if tagged.tag == "include" => match tagged.value {
+            Value::String(included) => {
+                let base = path.parent().unwrap_or(Path::new(""));
+                load_yaml(&base.join(included), stack)?
+            }
+            _ => return Err(ConfigError::BadInclude(path.into())),
+        },
+        Value::Sequence(seq) => Value::Sequence(
+            seq.into_iter()
+                .map(|v| resolve_includes(v, path, stack))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Mapping(map) => Value::Mapping(
+            map.into_iter()
+                .map(|(k, v)| Ok((k, resolve_includes(v, path, stack)?)))
+                .collect::<Result<_, ConfigError>>()?,
+        ),
+        value => value,
+    })
+}
+
+fn runtime_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn localfile2blob(blobstore: &mut Blobstore, local_path: &Path) -> (String, BlobMeta) {
     let mut f = std::fs::File::open(local_path).expect("open");
     let mut blob = blobstore.create().expect("blobstore create");
     let buf = &mut Vec::new();
     let _ = f.read_to_end(buf).expect("read");
     blob.write_all(buf).expect("write blob");
     let blob = blobstore.save(blob).expect("finalize blob");
-    debug!("DONE! local {} to blob {}", local_path, blob.name);
-    blob.name
+    debug!("DONE! local {:?} to blob {}", local_path, blob.name);
+    (blob.name, BlobMeta::new(buf.len() as u64, None))
 }
 
-/// The preparer installs supported kernels and runtime images in the directory `FSTN_IMAGE_BASE`.
-/// Kernels and runtime images are stored as blobs.
-pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Result<(), FsError> {
-    #[derive(Deserialize)]
-    struct Config {
-        kernel: String,
-        python: String,
-        fsutil: String,
-        other_runtimes: Vec<String>,
+/// Name under `FSTN_IMAGE_BASE` of the kernel of version `version`, or of the default kernel
+pub fn kernel_name(version: Option<&str>) -> String {
+    match version {
+        Some(v) => format!("kernel-{}", v),
+        None => "kernel".to_string(),
     }
+}
 
-    let config = std::fs::File::open(config_path).expect("open configuration file");
-    let config: Config = serde_yaml::from_reader(config).expect("deserialize");
-
+/// The preparer installs supported kernels and runtime images in the directory `FSTN_IMAGE_BASE`.
+/// Kernels and runtime images are stored as blobs.
+pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config: Config) -> Result<(), FsError> {
     let mut blobstore = crate::blobstore::Blobstore::<Sha256>::default();
-    let label = buckle::Buckle::parse("T,faasten").unwrap();
+    let label = config.label;
 
     if !fs.initialize() {
         warn!("Existing root detected.");
@@ -73,21 +233,35 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
 
     debug!("creating kernel blob...");
     let kernel_blob = {
-        let name = "kernel".to_string();
-        let blobname = localfile2blob(&mut blobstore, &config.kernel);
+        let name = kernel_name(None);
+        let (blobname, meta) = localfile2blob(&mut blobstore, &config.kernel);
         super::utils::create_or_update_blob(
             fs,
             FSTN_IMAGE_BASE.clone(),
             name,
             label.clone(),
             blobname.clone(),
+            meta,
         )?;
         blobname
     };
 
+    for (version, path) in config.kernels {
+        debug!("creating kernel {} blob...", version);
+        let (blobname, meta) = localfile2blob(&mut blobstore, &path);
+        super::utils::create_or_update_blob(
+            fs,
+            FSTN_IMAGE_BASE.clone(),
+            kernel_name(Some(&version)),
+            label.clone(),
+            blobname,
+            meta,
+        )?;
+    }
+
     debug!("creating python runtime blob...");
     let python_blob = {
-        let blobname = localfile2blob(&mut blobstore, &config.python);
+        let (blobname, meta) = localfile2blob(&mut blobstore, &config.python);
         let name = "python".to_string();
         super::utils::create_or_update_blob(
             fs,
@@ -95,13 +269,14 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
             name,
             label.clone(),
             blobname.clone(),
+            meta,
         )?;
         blobname
     };
 
     debug!("creating fsutil blob...");
     let fsutil_blob = {
-        let blobname = localfile2blob(&mut blobstore, &config.fsutil);
+        let (blobname, meta) = localfile2blob(&mut blobstore, &config.fsutil);
         let name = "fsutil_image".to_string();
         super::utils::create_or_update_blob(
             fs,
@@ -109,68 +284,110 @@ pub fn prepare_fs<S: BackingStore>(fs: &super::FS<S>, config_path: &str) -> Resu
             name,
             label.clone(),
             blobname.clone(),
+            meta,
         )?;
         blobname
     };
 
-    {
-        debug!("creating fsutil gate...");
-        let function = Function {
-            memory: FSUTIL_MEMSIZE,
-            app_image: fsutil_blob,
-            runtime_image: python_blob,
-            kernel: kernel_blob,
-        };
-
-        if let DirEntry::Directory(dir) = fs.read_path(FSTN_IMAGE_BASE.clone())? {
-            let name: String = "fsutil".into();
-            match dir.list(fs).get(&name) {
-                Some(DirEntry::Gate(gate)) => {
-                    gate.replace(Gate::Direct(DirectGate {
-                        privilege: buckle::Component::dc_true(),
-                        invoker_integrity_clearance: buckle::Component::dc_true(),
-                        declassify: buckle::Component::dc_true(),
-                        function,
-                    }), fs)?;
-                },
-                Some(_) => {
-                    dir.unlink(&name, fs)?;
-                    let gate = fs.create_direct_gate(FSUTIL_POLICY.clone(), DirectGate { privilege: buckle::Component::dc_true(), invoker_integrity_clearance: buckle::Component::dc_true(), declassify: buckle::Component::dc_true(),  function }).expect("create gate");
-                    dir.link(name, gate, fs)?;
-                },
-                None => {
-                    let gate = fs.create_direct_gate(FSUTIL_POLICY.clone(), DirectGate { privilege: buckle::Component::dc_true(), invoker_integrity_clearance: buckle::Component::dc_true(), declassify: buckle::Component::dc_true(), function }).expect("create gate");
-                    dir.link(name, gate, fs)?;
-                }
-            }
-        } else {
-            Err(FsError::BadPath)?
-        }
+    debug!("creating fsutil gate...");
+    install_gate(fs, "fsutil", DirectGate {
+        privilege: EMPTY_PRIV,
+        invoker_integrity_clearance: EMPTY_PRIV,
+        declassify: EMPTY_PRIV,
+        function: python_function(
+            FSUTIL_MEMSIZE,
+            fsutil_blob,
+            python_blob.clone(),
+            kernel_blob.clone(),
+        ),
+        metadata: None,
+    })?;
+
+    if let Some(management) = config.management {
+        debug!("creating management blob and gate...");
+        let (blobname, meta) = localfile2blob(&mut blobstore, &management);
+        super::utils::create_or_update_blob(
+            fs,
+            FSTN_IMAGE_BASE.clone(),
+            "management_image".to_string(),
+            label.clone(),
+            blobname.clone(),
+            meta,
+        )?;
+        // acts as `faasten` on behalf of invokers `faasten` endorses
+        install_gate(fs, "management", DirectGate {
+            privilege: FAASTEN_PRIV.clone(),
+            invoker_integrity_clearance: FAASTEN_PRIV.clone(),
+            declassify: EMPTY_PRIV,
+            function: python_function(MANAGEMENT_MEMSIZE, blobname, python_blob, kernel_blob),
+            metadata: None,
+        })?;
     }
 
     for rt in config.other_runtimes {
-        debug!("creating {} runtime blob...", rt);
-        let blobname = localfile2blob(&mut blobstore, &rt);
-        let name = std::path::Path::new(&rt)
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
+        debug!("creating {:?} runtime blob...", rt);
+        let (blobname, meta) = localfile2blob(&mut blobstore, &rt);
         super::utils::create_or_update_blob(
             &fs,
             FSTN_IMAGE_BASE.clone(),
-            name,
+            runtime_name(&rt),
             label.clone(),
             blobname,
-        )
-        .expect(&format!("link {:?} blob", rt));
+            meta,
+        )?;
     }
     super::utils::set_my_privilge(EMPTY_PRIV.clone());
     debug!("Done with bootstrapping.");
     Ok(())
 }
 
+// A Python function running `app_image` with the default settings
+fn python_function(memory: usize, app_image: Blob, runtime_image: Blob, kernel: Blob) -> Function {
+    Function {
+        memory,
+        app_image,
+        runtime_image,
+        kernel,
+        devices: Default::default(),
+        keep_alive: false,
+        min_instances: 0,
+        speculative_boot: false,
+        idempotent: false,
+        idle_ttl: None,
+        requirements: Default::default(),
+        forward_headers: Vec::new(),
+        concurrency_target: 0,
+        scratch: 0,
+        app_layers: Vec::new(),
+        provenance: None,
+        results: None,
+    }
+}
+
+// Links `gate` at `home:<T,faasten>:<name>`, replacing what is there. Anyone may read the gate.
+fn install_gate<S: BackingStore>(
+    fs: &super::FS<S>,
+    name: &str,
+    gate: DirectGate,
+) -> Result<(), FsError> {
+    let DirEntry::Directory(dir) = fs.read_path(FSTN_IMAGE_BASE.clone())? else {
+        return Err(FsError::BadPath);
+    };
+    match dir.list(fs).get(name) {
+        Some(DirEntry::Gate(existing)) => {
+            existing.replace(Gate::Direct(Box::new(gate)), fs)?;
+            return Ok(());
+        }
+        Some(_) => {
+            dir.unlink(&name.to_string(), fs)?;
+        }
+        None => {}
+    }
+    let entry = fs.create_direct_gate(FSUTIL_POLICY.clone(), gate)?;
+    dir.link(name.to_string(), entry, fs)?;
+    Ok(())
+}
+
 fn dup_fsutil<S: BackingStore>(
     fs: &super::FS<S>,
     privilege: Component,
@@ -208,10 +425,14 @@ pub fn get_runtime_blob<S: BackingStore>(fs: &super::FS<S>, runtime: &str) -> Bl
     fs.open_blob(runtime_fs_path).unwrap()
 }
 
-pub fn get_kernel_blob<S: BackingStore>(fs: &super::FS<S>) -> Blob {
+/// Resolves a kernel version to its blob. `None` resolves to the default kernel.
+pub fn get_kernel_blob<S: BackingStore>(
+    fs: &super::FS<S>,
+    version: Option<&str>,
+) -> Result<Blob, FsError> {
     let mut kernel_fs_path = FSTN_IMAGE_BASE.clone();
-    kernel_fs_path.push_dscrp("kernel".to_string());
-    fs.open_blob(kernel_fs_path).unwrap()
+    kernel_fs_path.push_dscrp(kernel_name(version));
+    fs.open_blob(kernel_fs_path)
 }
 
 pub fn update_fsutil<S: BackingStore>(
@@ -234,10 +455,10 @@ pub fn update_python<S: BackingStore>(
     super::utils::set_my_privilge(FAASTEN_PRIV.clone());
 
     debug!("repointing :home:<T,faasten>:python...");
-    let blobname = localfile2blob(&mut blobstore, local_path);
+    let (blobname, meta) = localfile2blob(&mut blobstore, Path::new(local_path));
     let mut path = FSTN_IMAGE_BASE.clone();
     path.push_dscrp("python".to_string());
-    fs.replace_blob(path, blobname.clone()).expect("repoint python blob");
+    fs.replace_blob(path, blobname, meta).expect("repoint python blob");
 
     super::utils::set_my_privilge(EMPTY_PRIV.clone());
 }
@@ -0,0 +1,128 @@
+//! Public catalog of gates
+//!
+//! Operators publish gates under short names with a description so that users can discover
+//! what they may invoke without knowing its path. The catalog is stored as a JSON map in the
+//! file `home:<T,faasten>:gate_catalog`. Anyone can read it but only `faasten` can update it.
+use std::collections::BTreeMap;
+
+use labeled::buckle::{Buckle, Component};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use super::{path::Path, BackingStore, DirEntry, FsError, FS};
+
+const CATALOG_FILE: &str = "gate_catalog";
+
+lazy_static! {
+    static ref CATALOG_BASE: Path = Path::parse("home:<T,faasten>").unwrap();
+    static ref CATALOG_POLICY: Buckle = Buckle::parse("T,faasten").unwrap();
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// Faasten path of the gate
+    pub gate: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// A published gate with what it takes to invoke it
+#[derive(Debug, Clone, Serialize)]
+pub struct Listing {
+    pub name: String,
+    pub gate: String,
+    /// the catalog's description, or the gate's own if the catalog has none
+    pub description: String,
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub example_payload: Option<String>,
+    /// integrity an invoker must have, accumulated along redirects
+    pub invoker_integrity_clearance: Component,
+    /// privilege the function runs with
+    pub privilege: Component,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Catalog(BTreeMap<String, CatalogEntry>);
+
+impl Catalog {
+    /// Loads the catalog, or an empty catalog if none is installed.
+    ///
+    /// The catalog is public and trusted, so reading it leaves the current label untouched.
+    pub fn load<S: BackingStore>(fs: &FS<S>) -> Self {
+        let saved = super::utils::get_current_label();
+        let mut path = CATALOG_BASE.clone();
+        path.push_dscrp(CATALOG_FILE.to_string());
+        let catalog = fs
+            .read_file(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        super::utils::clear_label();
+        super::utils::taint_with_label(saved);
+        catalog
+    }
+
+    /// Stores the catalog. Requires `faasten`'s privilege.
+    pub fn save<S: BackingStore>(&self, fs: &FS<S>) -> Result<(), FsError> {
+        super::utils::create_or_update_file(
+            fs,
+            CATALOG_BASE.clone(),
+            CATALOG_FILE.to_string(),
+            CATALOG_POLICY.clone(),
+            serde_json::to_vec(self).unwrap(),
+        )
+    }
+
+    /// Publishes the gate at `gate` as `name`. The path must parse.
+    pub fn insert(&mut self, name: String, gate: String, description: String) -> bool {
+        if Path::parse(&gate).is_err() {
+            return false;
+        }
+        self.0.insert(name, CatalogEntry { gate, description });
+        true
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.0.remove(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &CatalogEntry)> {
+        self.0.iter()
+    }
+
+    /// Describes the published gate `name`, `None` if it isn't published or its path no longer
+    /// leads to a gate.
+    ///
+    /// Publishing a gate declassifies its policy, so this leaves the current label untouched.
+    pub fn listing<S: BackingStore>(&self, fs: &FS<S>, name: &str) -> Option<Listing> {
+        let entry = self.0.get(name)?;
+        let saved = super::utils::get_current_label();
+        let gate = match Path::parse(&entry.gate).map(|path| fs.read_path(path)) {
+            Ok(Ok(DirEntry::Gate(gate))) => Some(gate.to_invokable(fs)),
+            _ => None,
+        };
+        super::utils::clear_label();
+        super::utils::taint_with_label(saved);
+        gate.map(|gate| {
+            let metadata = gate.metadata.map(|m| *m).unwrap_or_default();
+            Listing {
+                name: name.to_string(),
+                gate: entry.gate.clone(),
+                description: match metadata.description {
+                    Some(description) if entry.description.is_empty() => description,
+                    _ => entry.description.clone(),
+                },
+                tags: metadata.tags,
+                example_payload: metadata.example_payload,
+                invoker_integrity_clearance: gate.invoker_integrity_clearance,
+                privilege: gate.privilege,
+            }
+        })
+    }
+
+    /// Describes every published gate that still exists
+    pub fn listings<S: BackingStore>(&self, fs: &FS<S>) -> Vec<Listing> {
+        self.0.keys().filter_map(|name| self.listing(fs, name)).collect()
+    }
+}
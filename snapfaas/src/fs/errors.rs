@@ -19,6 +19,18 @@ pub enum FsError {
     ServiceError(ServiceError),
     NameExists,
     InvalidFd,
+    NotACounter,
+    NotALock,
+    NotABarrier,
+    /// all expected results already arrived at the barrier
+    BarrierComplete,
+    /// a barrier must expect at least one result
+    EmptyBarrier,
+    NotALog,
+    /// a compare-and-swap loop kept losing races to concurrent updates and gave up
+    Contended,
+    /// the operation would copy or visit more objects than it may
+    TooLarge,
 }
 
 impl From<LabelError> for FsError {
@@ -67,6 +79,8 @@ impl From<ServiceError> for FsError {
 pub enum LabelError {
     CannotRead,
     CannotWrite,
+    /// the object is part of a snapshot
+    Frozen,
 }
 
 #[derive(Debug)]
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
 use crate::configs::FunctionConfig;
@@ -9,6 +11,97 @@ pub struct Function {
     pub app_image: String,
     pub runtime_image: String,
     pub kernel: String,
+    /// classes of passthrough devices the function needs, one entry per device, e.g. "gpu"
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// keep the function's VMs up between invocations rather than evicting them once idle, for
+    /// low-latency services. Invocations run one after another in a kept-alive VM without
+    /// initializing it again. Workers keep up to their `max_keep_alive` VMs of it up.
+    #[serde(default)]
+    pub keep_alive: bool,
+    /// idle VMs of the function the scheduler keeps warm, so that invocations never wait for a
+    /// cold start. Workers don't evict them.
+    #[serde(default)]
+    pub min_instances: usize,
+    /// when no idle VM of the function is left but some are busy, boot another one for a
+    /// waiting invocation and run it on whichever VM becomes idle first, trading CPU for tail
+    /// latency. The scheduler caps such boots across the cluster.
+    #[serde(default)]
+    pub speculative_boot: bool,
+    /// running the function again with the same payload is harmless, so the scheduler may stop
+    /// its preemptible invocations for more urgent ones and run them again later
+    #[serde(default)]
+    pub idempotent: bool,
+    /// seconds an idle VM of the function lives before the worker shuts it down, overriding
+    /// the worker's `idle_ttl`
+    #[serde(default)]
+    pub idle_ttl: Option<u64>,
+    /// attributes a worker must advertise with these values to run the function, e.g.
+    /// `cpu: icelake`
+    #[serde(default)]
+    pub requirements: BTreeMap<String, String>,
+    /// names of the invocation headers passed to the function, matched case-insensitively, or
+    /// `*` for all. Workers strip the others, so invokers can't influence the function through
+    /// headers it doesn't expect.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forward_headers: Vec<String>,
+    /// invocations of the function a worker node is expected to run at once, which autoscaling
+    /// sizes the cluster by, see `crate::sched::autoscale`. The scheduler's default if 0.
+    #[serde(default)]
+    pub concurrency_target: usize,
+    /// MiB of host scratch space each invocation gets, see `crate::scratch`
+    #[serde(default)]
+    pub scratch: usize,
+    /// thin layers overlaid on `app_image` in order, the last on top, so redeploying code
+    /// doesn't mean uploading its dependencies again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub app_layers: Vec<String>,
+    /// where the app image came from, for supply-chain audits. Workers ignore it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Box<Provenance>>,
+    /// where the results of async invocations are stored and for how long, see
+    /// `super::retention`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub results: Option<Box<ResultRetention>>,
+}
+
+/// Durable storage of a function's async results
+#[derive(Default, Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
+pub struct ResultRetention {
+    /// Faasten path of the directory holding the results, one file per invocation
+    pub dir: String,
+    /// how many of the most recent results to keep, all if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+    /// days to keep a result, forever if absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_days: Option<u64>,
+}
+
+/// Provenance of a function's app image, recorded when the gate is deployed
+#[derive(Default, Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
+pub struct Provenance {
+    /// repository the image was built from, e.g. a git URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_repo: Option<String>,
+    /// revision of `source_repo` the image was built from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// who or what built the image, e.g. a CI job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub builder: Option<String>,
+    /// blob holding a software bill of materials of the image, e.g. SPDX or CycloneDX JSON
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sbom: Option<String>,
+}
+
+impl Function {
+    /// Whether invocations pass the header `name` to the function
+    pub fn forwards_header(&self, name: &str) -> bool {
+        self.forward_headers
+            .iter()
+            .any(|h| h == "*" || h.eq_ignore_ascii_case(name))
+    }
 }
 
 // used by singlevm. singlevm allows more complicated configurations than multivm.
@@ -19,6 +112,19 @@ impl From<FunctionConfig> for Function {
             app_image: cfg.appfs.unwrap_or_default(),
             runtime_image: cfg.runtimefs,
             kernel: cfg.kernel,
+            devices: Default::default(),
+            keep_alive: false,
+            min_instances: 0,
+            speculative_boot: false,
+            idempotent: false,
+            idle_ttl: None,
+            requirements: Default::default(),
+            forward_headers: Vec::new(),
+            concurrency_target: 0,
+            scratch: 0,
+            app_layers: cfg.app_layers,
+            provenance: None,
+            results: None,
         }
     }
 }
@@ -30,6 +136,32 @@ impl From<crate::sched::message::Function> for Function {
             app_image: pbf.app_image,
             runtime_image: pbf.runtime,
             kernel: pbf.kernel,
+            devices: pbf.devices,
+            keep_alive: pbf.keep_alive,
+            min_instances: pbf.min_instances as usize,
+            speculative_boot: pbf.speculative_boot,
+            idempotent: pbf.idempotent,
+            idle_ttl: pbf.idle_ttl,
+            requirements: pbf.requirements.into_iter().collect(),
+            forward_headers: pbf.forward_headers,
+            concurrency_target: pbf.concurrency_target as usize,
+            scratch: pbf.scratch as usize,
+            app_layers: pbf.app_layers,
+            provenance: pbf.provenance.map(|p| {
+                Box::new(Provenance {
+                    source_repo: p.source_repo,
+                    commit: p.commit,
+                    builder: p.builder,
+                    sbom: p.sbom,
+                })
+            }),
+            results: pbf.results.map(|r| {
+                Box::new(ResultRetention {
+                    dir: r.dir,
+                    keep_last: r.keep_last.map(|n| n as usize),
+                    keep_days: r.keep_days,
+                })
+            }),
         }
     }
 }
@@ -41,6 +173,28 @@ impl From<Function> for crate::sched::message::Function {
             app_image: f.app_image,
             runtime: f.runtime_image,
             kernel: f.kernel,
+            devices: f.devices,
+            keep_alive: f.keep_alive,
+            min_instances: f.min_instances as u32,
+            speculative_boot: f.speculative_boot,
+            idempotent: f.idempotent,
+            idle_ttl: f.idle_ttl,
+            requirements: f.requirements.into_iter().collect(),
+            forward_headers: f.forward_headers,
+            concurrency_target: f.concurrency_target as u64,
+            scratch: f.scratch as u64,
+            app_layers: f.app_layers,
+            provenance: f.provenance.map(|p| crate::sched::message::Provenance {
+                source_repo: p.source_repo,
+                commit: p.commit,
+                builder: p.builder,
+                sbom: p.sbom,
+            }),
+            results: f.results.map(|r| crate::sched::message::ResultRetention {
+                dir: r.dir,
+                keep_last: r.keep_last.map(|n| n as u64),
+                keep_days: r.keep_days,
+            }),
         }
     }
 }
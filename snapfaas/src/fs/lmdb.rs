@@ -1,43 +1,325 @@
-use lmdb::{self, Transaction, WriteFlags};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use lmdb::{self, Cursor, DatabaseFlags, EnvironmentFlags, Transaction, WriteFlags};
+use log::{info, warn};
+
+use super::ObjectKind;
+
+const MB: usize = 1024 * 1024;
+
+/// Named databases objects are kept in by kind, the first byte of their keys, so that the bulky
+/// kinds can be left out of copies of the store and each can be inspected on its own. Other keys
+/// stay in the unnamed database.
+pub const DATABASES: [(&str, &[ObjectKind]); 4] = [
+    (
+        "directories",
+        &[ObjectKind::Directory, ObjectKind::FacetedDirectory],
+    ),
+    ("files", &[ObjectKind::File]),
+    ("gates", &[ObjectKind::Gate, ObjectKind::Service]),
+    ("blobs", &[ObjectKind::Blob]),
+];
+
+// length of object keys, see `ObjectKind::key`
+const OBJECT_KEY_LEN: usize = 9;
+
+/// How to open an LMDB environment, see `cli::Store`
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// initial size in bytes of the memory map, which grows as writes need
+    pub map_size: usize,
+    /// size in bytes beyond which the memory map doesn't grow
+    pub max_map_size: usize,
+    /// don't flush to disk on commit, see `EnvironmentFlags::NO_SYNC`
+    pub no_sync: bool,
+    /// use a writeable memory map, see `EnvironmentFlags::WRITE_MAP`
+    pub write_map: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            map_size: 1024 * MB,
+            max_map_size: 1024 * 1024 * MB,
+            no_sync: false,
+            write_map: false,
+        }
+    }
+}
+
+// The limit of map growth of the process's environments. LMDB can only resize a map while the
+// process has no transaction open on it, so transactions hold `TXNS` for reading and growing the
+// map holds it for writing.
+static MAX_MAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+static TXNS: RwLock<()> = RwLock::new(());
 
 pub fn get_dbenv(path: &str) -> lmdb::Environment {
-    let path = std::path::Path::new(path);
+    open_dbenv(path, &Options::default())
+}
+
+pub fn open_dbenv(path: &str, options: &Options) -> lmdb::Environment {
+    let path = Path::new(path);
     if !path.exists() {
         let _ = std::fs::create_dir(path).unwrap();
     }
 
-    lmdb::Environment::new()
-        .set_map_size(100 * 1024 * 1024 * 1024)
+    let mut flags = EnvironmentFlags::empty();
+    flags.set(EnvironmentFlags::NO_SYNC, options.no_sync);
+    flags.set(EnvironmentFlags::WRITE_MAP, options.write_map);
+    MAX_MAP_SIZE.store(options.max_map_size, Ordering::Relaxed);
+    let env = lmdb::Environment::new()
+        .set_map_size(options.map_size)
         .set_max_readers(1024)
+        .set_max_dbs(DATABASES.len() as u32)
+        .set_flags(flags)
         .open(path)
-        .unwrap()
+        .unwrap();
+    create_databases(&env);
+    env
+}
+
+// Creates the named databases. A store created before them has all objects in the unnamed
+// database, which move in the same transaction, so that processes opening the store meanwhile
+// either wait or see them moved.
+fn create_databases(env: &lmdb::Environment) {
+    if DATABASES.iter().all(|(name, _)| env.open_db(Some(name)).is_ok()) {
+        return;
+    }
+    let main = env.open_db(None).unwrap();
+    let moved = write(env, main, |txn, main| {
+        let mut dbs = Vec::new();
+        for (name, _) in DATABASES.iter() {
+            dbs.push(unsafe { txn.create_db(Some(name), DatabaseFlags::empty())? });
+        }
+        let mut objects = Vec::new();
+        {
+            let mut cursor = txn.open_ro_cursor(main)?;
+            for entry in cursor.iter_start() {
+                let (key, value) = entry?;
+                if let Some(i) = database_index(key) {
+                    objects.push((i, key.to_vec(), value.to_vec()));
+                }
+            }
+        }
+        for (i, key, value) in objects.iter() {
+            txn.put(dbs[*i], key, value, WriteFlags::empty())?;
+            txn.del(main, key, None)?;
+        }
+        Ok(objects.len())
+    });
+    if moved > 0 {
+        info!("moved {} objects to named databases", moved);
+    }
+}
+
+// Index in `DATABASES` of the database holding `key`, `None` for the unnamed one
+fn database_index(key: &[u8]) -> Option<usize> {
+    if key.len() != OBJECT_KEY_LEN {
+        return None;
+    }
+    kind_database_index(key[0])
+}
+
+fn kind_database_index(kind: u8) -> Option<usize> {
+    DATABASES
+        .iter()
+        .position(|(_, kinds)| kinds.iter().any(|k| *k as u8 == kind))
+}
+
+// The database holding `key`
+fn database(env: &lmdb::Environment, key: &[u8]) -> lmdb::Result<lmdb::Database> {
+    env.open_db(database_index(key).map(|i| DATABASES[i].0))
+}
+
+/// Calls `f` with every key and value in the store, the unnamed database first, then each of
+/// `DATABASES`, in key order, as of a single snapshot. `f` must not use the store, as the map
+/// can't grow meanwhile.
+pub fn for_each(env: &lmdb::Environment, mut f: impl FnMut(&[u8], &[u8])) -> lmdb::Result<()> {
+    let mut dbs = vec![env.open_db(None)?];
+    for (name, _) in DATABASES.iter() {
+        dbs.push(env.open_db(Some(name))?);
+    }
+    let _txns = TXNS.read().unwrap();
+    let txn = begin_ro_txn(env)?;
+    for db in dbs {
+        let mut cursor = txn.open_ro_cursor(db)?;
+        for entry in cursor.iter_start() {
+            let (key, value) = entry?;
+            // the unnamed database also records the named ones
+            if !is_database_name(key) {
+                f(key, value);
+            }
+        }
+    }
+    txn.commit()
+}
+
+fn is_database_name(key: &[u8]) -> bool {
+    DATABASES.iter().any(|(name, _)| name.as_bytes() == key)
+}
+
+/// Compacts the store in the directory `path`, returning the sizes in bytes of its data file
+/// before and after. The store is copied without its free pages, then the copy replaces it.
+///
+/// LMDB can't swap the file of an environment other processes have open, as they would keep
+/// using the old one, so compaction fails if any process has the store open. Stop the services
+/// using it first.
+pub fn compact(path: &str) -> io::Result<(u64, u64)> {
+    let dir = Path::new(path);
+    let data = dir.join("data.mdb");
+    let before = fs::metadata(&data)?.len();
+    ensure_exclusive(dir)?;
+    let copy = dir.join("compact");
+    let _ = fs::remove_dir_all(&copy);
+    fs::create_dir(&copy)?;
+    {
+        let env = lmdb::Environment::new()
+            .set_max_readers(1024)
+            .open(dir)
+            .map_err(io::Error::other)?;
+        let c_copy = CString::new(copy.as_os_str().as_bytes())?;
+        let rc = unsafe {
+            lmdb_sys::mdb_env_copy2(env.env(), c_copy.as_ptr(), lmdb_sys::MDB_CP_COMPACT)
+        };
+        if rc != 0 {
+            let _ = fs::remove_dir_all(&copy);
+            return Err(io::Error::other(lmdb::Error::from_err_code(rc)));
+        }
+    }
+    // a process may have opened the store during the copy
+    if let Err(e) = ensure_exclusive(dir) {
+        let _ = fs::remove_dir_all(&copy);
+        return Err(e);
+    }
+    fs::rename(copy.join("data.mdb"), &data)?;
+    fs::remove_dir_all(&copy)?;
+    Ok((before, fs::metadata(&data)?.len()))
+}
+
+/// Fails if a process has the environment in `dir` open, which LMDB marks with a shared lock of
+/// the first byte of the lock file. The process mustn't have it open itself, as closing the file
+/// here would release its locks.
+pub fn ensure_exclusive(dir: &Path) -> io::Result<()> {
+    let file = match fs::File::open(dir.join("lock.mdb")) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = 0;
+    lock.l_len = 1;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if lock.l_type != libc::F_UNLCK as libc::c_short {
+        return Err(io::Error::other(format!(
+            "process {} has the store open",
+            lock.l_pid
+        )));
+    }
+    Ok(())
+}
+
+// Begins a read-only transaction, adopting the map size if another process grew the map
+fn begin_ro_txn(env: &lmdb::Environment) -> lmdb::Result<lmdb::RoTransaction<'_>> {
+    match env.begin_ro_txn() {
+        Err(lmdb::Error::MapResized) => {
+            env.set_map_size(0)?;
+            env.begin_ro_txn()
+        }
+        result => result,
+    }
+}
+
+// Runs `f` in a write transaction and commits it. If the map is full, the map grows and `f` runs
+// again. Panics with the reason if it can't grow or another error occurs.
+fn write<T>(
+    env: &lmdb::Environment,
+    db: lmdb::Database,
+    mut f: impl FnMut(&mut lmdb::RwTransaction, lmdb::Database) -> lmdb::Result<T>,
+) -> T {
+    loop {
+        let result = {
+            let _txns = TXNS.read().unwrap();
+            env.begin_rw_txn().and_then(|mut txn| {
+                let res = f(&mut txn, db)?;
+                txn.commit()?;
+                Ok(res)
+            })
+        };
+        match result {
+            Ok(res) => return res,
+            Err(lmdb::Error::MapFull) => grow(env),
+            Err(lmdb::Error::MapResized) => {
+                let _txns = TXNS.write().unwrap();
+                env.set_map_size(0).expect("adopt the LMDB map size");
+            }
+            Err(e) => panic!("LMDB write failed: {}", e),
+        }
+    }
+}
+
+// Doubles the map, up to `MAX_MAP_SIZE`
+fn grow(env: &lmdb::Environment) {
+    let _txns = TXNS.write().unwrap();
+    let size = env.info().expect("LMDB environment info").map_size();
+    let max = MAX_MAP_SIZE.load(Ordering::Relaxed);
+    let new_size = size.saturating_mul(2).min(max);
+    if new_size <= size {
+        panic!(
+            "the LMDB map is full at {} MB and may not grow beyond {} MB, see --lmdb-max-map-size",
+            size / MB,
+            max / MB
+        );
+    }
+    if let Err(e) = env.set_map_size(new_size) {
+        panic!("failed to grow the LMDB map from {} MB: {}", size / MB, e);
+    }
+    warn!("grew the LMDB map from {} MB to {} MB", size / MB, new_size / MB);
+}
+
+// Ignores the errors of a write that the store has always ignored, but not a full map, so that
+// the write is retried in a larger one
+fn unless_full(result: lmdb::Result<()>) -> lmdb::Result<()> {
+    match result {
+        Err(lmdb::Error::MapFull) => Err(lmdb::Error::MapFull),
+        _ => Ok(()),
+    }
 }
 
 impl super::BackingStore for lmdb::Environment {
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        let db = self.open_db(None).ok()?;
-        let txn = self.begin_ro_txn().ok()?;
+        let db = database(self, key).ok()?;
+        let _txns = TXNS.read().unwrap();
+        let txn = begin_ro_txn(self).ok()?;
         let res = txn.get(db, &key).ok().map(Into::<Vec<u8>>::into);
         txn.commit().ok()?;
         res
     }
 
     fn put(&self, key: &[u8], value: &[u8]) {
-        let db = self.open_db(None).unwrap();
-        let mut txn = self.begin_rw_txn().unwrap();
-        let _ = txn.put(db, &key, &value, WriteFlags::empty());
-        txn.commit().unwrap();
+        let db = database(self, key).unwrap();
+        write(self, db, |txn, db| unless_full(txn.put(db, &key, &value, WriteFlags::empty())))
     }
 
     fn add(&self, key: &[u8], value: &[u8]) -> bool {
-        let db = self.open_db(None).unwrap();
-        let mut txn = self.begin_rw_txn().unwrap();
-        let res = match txn.put(db, &key, &value, WriteFlags::NO_OVERWRITE) {
-            Ok(_) => true,
-            Err(_) => false,
-        };
-        txn.commit().unwrap();
-        res
+        let db = database(self, key).unwrap();
+        write(self, db, |txn, db| {
+            match txn.put(db, &key, &value, WriteFlags::NO_OVERWRITE) {
+                Ok(_) => Ok(true),
+                Err(lmdb::Error::MapFull) => Err(lmdb::Error::MapFull),
+                Err(_) => Ok(false),
+            }
+        })
     }
 
     fn cas(
@@ -46,23 +328,72 @@ impl super::BackingStore for lmdb::Environment {
         expected: Option<&[u8]>,
         value: &[u8],
     ) -> Result<(), Option<Vec<u8>>> {
-        let db = self.open_db(None).unwrap();
-        let mut txn = self.begin_rw_txn().unwrap();
-        let old = txn.get(db, &key).ok().map(Into::into);
-        let res = if expected.map(|e| Vec::from(e)) == old {
-            let _ = txn.put(db, &key, &value, WriteFlags::empty());
-            Ok(())
-        } else {
-            Err(old)
-        };
-        txn.commit().unwrap();
-        res
+        let db = database(self, key).unwrap();
+        write(self, db, |txn, db| {
+            let old = txn.get(db, &key).ok().map(Into::into);
+            Ok(if expected.map(|e| Vec::from(e)) == old {
+                unless_full(txn.put(db, &key, &value, WriteFlags::empty()))?;
+                Ok(())
+            } else {
+                Err(old)
+            })
+        })
     }
 
     fn del(&self, key: &[u8]) {
-        let db = self.open_db(None).unwrap();
-        let mut txn = self.begin_rw_txn().unwrap();
-        let _ = txn.del(db, &key, None);
-        txn.commit().unwrap();
+        let db = database(self, key).unwrap();
+        write(self, db, |txn, db| unless_full(txn.del(db, &key, None)))
+    }
+
+    fn scan(&self, prefix: &[u8], f: &mut dyn FnMut(&[u8], &[u8])) {
+        // objects of kinds in a named database are only there, and other keys never are
+        let names: Vec<Option<&str>> = match prefix.first() {
+            Some(kind) => vec![kind_database_index(*kind).map(|i| DATABASES[i].0)],
+            None => std::iter::once(None)
+                .chain(DATABASES.iter().map(|(name, _)| Some(*name)))
+                .collect(),
+        };
+        for name in names {
+            let db = self.open_db(name).expect("LMDB scan");
+            scan_database(self, db, prefix, f);
+        }
+    }
+}
+
+// Scans `db` in batches, each in its own transaction, so that `f` may use the store
+fn scan_database(
+    env: &lmdb::Environment,
+    db: lmdb::Database,
+    prefix: &[u8],
+    f: &mut dyn FnMut(&[u8], &[u8]),
+) {
+    const BATCH: usize = 1024;
+    let mut from = prefix.to_vec();
+    loop {
+        let batch: Vec<(Vec<u8>, Vec<u8>)> = {
+            let _txns = TXNS.read().unwrap();
+            let txn = begin_ro_txn(env).expect("LMDB scan");
+            let mut cursor = txn.open_ro_cursor(db).expect("LMDB scan");
+            let entries = if from.is_empty() {
+                cursor.iter_start()
+            } else {
+                cursor.iter_from(&from)
+            };
+            entries
+                .map(|entry| entry.expect("LMDB scan"))
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .take(BATCH)
+                .map(|(key, value)| (key.to_vec(), value.to_vec()))
+                .collect()
+        };
+        for (key, value) in batch.iter().filter(|(key, _)| !is_database_name(key)) {
+            f(key, value);
+        }
+        if batch.len() < BATCH {
+            return;
+        }
+        // the smallest key after the last one seen
+        from = batch.into_iter().last().unwrap().0;
+        from.push(0);
     }
 }
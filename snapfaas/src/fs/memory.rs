@@ -0,0 +1,57 @@
+//! In-memory backing store for tests
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+pub struct MemoryStore(Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+impl MemoryStore {
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+impl super::BackingStore for MemoryStore {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        self.0.lock().unwrap().insert(key.to_vec(), value.to_vec());
+    }
+
+    fn add(&self, key: &[u8], value: &[u8]) -> bool {
+        let mut map = self.0.lock().unwrap();
+        if map.contains_key(key) {
+            return false;
+        }
+        map.insert(key.to_vec(), value.to_vec());
+        true
+    }
+
+    fn cas(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<(), Option<Vec<u8>>> {
+        let mut map = self.0.lock().unwrap();
+        let current = map.get(key);
+        if current.map(Vec::as_slice) != expected {
+            return Err(current.cloned());
+        }
+        map.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn del(&self, key: &[u8]) {
+        self.0.lock().unwrap().remove(key);
+    }
+
+    fn scan(&self, prefix: &[u8], f: &mut dyn FnMut(&[u8], &[u8])) {
+        let map = self.0.lock().unwrap();
+        for (k, v) in map.range(prefix.to_vec()..).take_while(|(k, _)| k.starts_with(prefix)) {
+            f(k, v);
+        }
+    }
+}
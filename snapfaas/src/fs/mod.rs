@@ -1,15 +1,26 @@
 use std::collections::BTreeMap;
 use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use labeled::{buckle::{Buckle, Component}, Label, HasPrivilege};
+use labeled::{buckle::{Buckle, Component, Principal}, Label, HasPrivilege};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 mod errors;
 mod function;
 
+pub mod aliases;
+pub mod audit;
+pub mod catalog;
 pub mod bootstrap;
 pub mod lmdb;
+#[cfg(test)]
+pub(crate) mod memory;
+pub mod partition;
 pub mod path;
+pub mod replica;
+pub mod retention;
+pub mod sudoers;
 pub mod tikv;
 pub mod utils;
 
@@ -30,6 +41,7 @@ pub enum HttpVerb {
     POST,
     PUT,
     DELETE,
+    PATCH,
 }
 
 impl From<HttpVerb> for reqwest::Method {
@@ -40,6 +52,7 @@ impl From<HttpVerb> for reqwest::Method {
             HttpVerb::POST => reqwest::Method::POST,
             HttpVerb::PUT => reqwest::Method::PUT,
             HttpVerb::DELETE => reqwest::Method::DELETE,
+            HttpVerb::PATCH => reqwest::Method::PATCH,
         }
     }
 }
@@ -51,6 +64,7 @@ impl From<reqwest::Method> for HttpVerb {
             reqwest::Method::POST => HttpVerb::POST,
             reqwest::Method::PUT => HttpVerb::PUT,
             reqwest::Method::DELETE => HttpVerb::DELETE,
+            reqwest::Method::PATCH => HttpVerb::PATCH,
             _ => panic!("Request method {} not supported", method),
         }
     }
@@ -70,27 +84,118 @@ impl<T> ObjectRef<T> {
     const fn new(uid: u64) -> Self {
         ObjectRef { uid, _inner: core::marker::PhantomData }
     }
+}
+
+/// What an object is. An object's key is its kind followed by its id, so that the objects of
+/// one kind can be scanned without reading the others, see `FS::scan_objects`. On TiKV, the
+/// objects of a kind form a key range, on LMDB most kinds have a database, see
+/// `lmdb::DATABASES`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ObjectKind {
+    Directory = b'd',
+    File = b'f',
+    FacetedDirectory = b'F',
+    Gate = b'g',
+    Service = b's',
+    Blob = b'b',
+    Log = b'l',
+    LogChunk = b'c',
+}
+
+impl ObjectKind {
+    const ALL: [ObjectKind; 8] = [
+        ObjectKind::Directory,
+        ObjectKind::File,
+        ObjectKind::FacetedDirectory,
+        ObjectKind::Gate,
+        ObjectKind::Service,
+        ObjectKind::Blob,
+        ObjectKind::Log,
+        ObjectKind::LogChunk,
+    ];
+
+    /// The key of the object `uid` of this kind
+    pub fn key(self, uid: u64) -> [u8; 9] {
+        let mut key = [self as u8; 9];
+        key[1..].copy_from_slice(&uid.to_be_bytes());
+        key
+    }
+
+    /// The kind of object stored at `key`, `None` if `key` isn't an object key
+    pub fn of_key(key: &[u8]) -> Option<ObjectKind> {
+        if key.len() != 9 {
+            return None;
+        }
+        Self::ALL.iter().copied().find(|kind| *kind as u8 == key[0])
+    }
+}
+
+/// Types stored as objects
+pub trait Object {
+    const KIND: ObjectKind;
+}
+
+impl Object for Labeled<Directory> {
+    const KIND: ObjectKind = ObjectKind::Directory;
+}
+
+impl Object for Labeled<File> {
+    const KIND: ObjectKind = ObjectKind::File;
+}
+
+impl Object for FacetedDirectory {
+    const KIND: ObjectKind = ObjectKind::FacetedDirectory;
+}
+
+impl Object for Labeled<Gate> {
+    const KIND: ObjectKind = ObjectKind::Gate;
+}
+
+impl Object for Labeled<DirectGate> {
+    const KIND: ObjectKind = ObjectKind::Gate;
+}
+
+impl Object for Labeled<Service> {
+    const KIND: ObjectKind = ObjectKind::Service;
+}
+
+impl Object for Labeled<Blob> {
+    const KIND: ObjectKind = ObjectKind::Blob;
+}
+
+impl Object for Labeled<Log> {
+    const KIND: ObjectKind = ObjectKind::Log;
+}
+
+impl Object for Vec<LogEntry> {
+    const KIND: ObjectKind = ObjectKind::LogChunk;
+}
+
+impl<T: Object> ObjectRef<T> {
+    fn key(&self) -> [u8; 9] {
+        T::KIND.key(self.uid)
+    }
 
-    #[allow(dead_code)]
     fn delete<B: BackingStore>(&self, storage: &B) {
-        storage.del(&self.uid.to_be_bytes())
+        storage.del(&self.key())
     }
 }
 
-impl<T: ?Sized + DeserializeOwned> ObjectRef<T> {
+impl<T: ?Sized + Object + DeserializeOwned> ObjectRef<T> {
     pub fn get<B: BackingStore>(&self, storage: &FS<B>) -> Option<T> {
-        let bs = storage.0.get(&self.uid.to_be_bytes())?;
+        let bs = storage.0.get(&self.key())?;
         let res = serde_json::from_slice(bs.as_slice()).ok()?;
         res
     }
 }
 
-impl<T: Serialize> ObjectRef<T> {
+impl<T: Object + Serialize> ObjectRef<T> {
     fn set_new_id<B: BackingStore>(value: &T, storage: &B) -> ObjectRef<T> {
         let mut uid: u64;
         loop {
             uid = rand::random();
-            if storage.add(&uid.to_be_bytes(), &[]) {
+            if storage.add(&T::KIND.key(uid), &[]) {
                 break;
             }
         }
@@ -102,18 +207,18 @@ impl<T: Serialize> ObjectRef<T> {
 
     fn set<B: BackingStore>(&self, value: &T, storage: &B) {
         storage.put(
-            &self.uid.to_be_bytes(),
+            &self.key(),
             serde_json::to_vec(value).unwrap().as_slice(),
         );
     }
 }
 
-impl<T: ?Sized + Serialize + DeserializeOwned> ObjectRef<T> {
+impl<T: ?Sized + Object + Serialize + DeserializeOwned> ObjectRef<T> {
     fn cas<B: BackingStore>(&self, expected: Option<&T>, value: &T, storage: &B) -> Result<(), Option<T>> {
         let expected: Option<Vec<u8>> = expected.and_then(|e| serde_json::to_vec(e).ok());
 
         let res = storage.cas(
-            &self.uid.to_be_bytes(),
+            &self.key(),
             expected.as_ref().map(Vec::as_slice),
             serde_json::to_vec(value).or(Err(None))?.as_slice(),
         );
@@ -125,10 +230,87 @@ impl<T: ?Sized + Serialize + DeserializeOwned> ObjectRef<T> {
     }
 }
 
+/// Attempts a compare-and-swap loop makes before giving up with `FsError::Contended`
+pub const CAS_MAX_ATTEMPTS: u32 = 16;
+const CAS_BACKOFF_BASE: Duration = Duration::from_micros(100);
+const CAS_BACKOFF_MAX: Duration = Duration::from_millis(50);
+
+static CAS_CONFLICTS: AtomicU64 = AtomicU64::new(0);
+static CAS_GIVE_UPS: AtomicU64 = AtomicU64::new(0);
+
+/// Counters of the races lost by this process's compare-and-swap loops since it started
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ContentionStats {
+    /// swaps that failed because another update got in first
+    pub conflicts: u64,
+    /// loops that gave up with `FsError::Contended`
+    pub give_ups: u64,
+}
+
+pub fn contention_stats() -> ContentionStats {
+    ContentionStats {
+        conflicts: CAS_CONFLICTS.load(Ordering::Relaxed),
+        give_ups: CAS_GIVE_UPS.load(Ordering::Relaxed),
+    }
+}
+
+// Paces the retries of a compare-and-swap loop
+struct CasRetry {
+    attempt: u32,
+}
+
+impl CasRetry {
+    fn new() -> Self {
+        CasRetry { attempt: 0 }
+    }
+
+    // Records a lost race and sleeps before the next attempt, for an exponentially growing time
+    // with full jitter so that the racing writers spread out. Fails once the loop lost
+    // `CAS_MAX_ATTEMPTS` races.
+    fn backoff(&mut self) -> Result<(), FsError> {
+        CAS_CONFLICTS.fetch_add(1, Ordering::Relaxed);
+        self.attempt += 1;
+        if self.attempt >= CAS_MAX_ATTEMPTS {
+            CAS_GIVE_UPS.fetch_add(1, Ordering::Relaxed);
+            return Err(FsError::Contended);
+        }
+        let cap = CAS_BACKOFF_BASE
+            .saturating_mul(1 << self.attempt)
+            .min(CAS_BACKOFF_MAX);
+        std::thread::sleep(cap.mul_f64(rand::random::<f64>()));
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Labeled<T> {
     label: Buckle,
     data: T,
+    /// snapshot objects can never be written
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    frozen: bool,
+    /// hex encoded SHA-256 of a file's content, kept up to date on writes. Missing for files
+    /// created empty or written before content hashing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+    /// size and content type of a blob, recorded when the blob entry is created or replaced.
+    /// Missing for blob entries created before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blob_meta: Option<BlobMeta>,
+}
+
+/// What a blob entry records about its blob, so stat and download don't need to open it
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlobMeta {
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+impl BlobMeta {
+    pub fn new(size: u64, content_type: Option<String>) -> Self {
+        BlobMeta { size, content_type }
+    }
 }
 
 impl<T> Labeled<T> {
@@ -147,6 +329,9 @@ impl<T> Labeled<T> {
     }
 
     pub fn write(&mut self, value: T) -> Result<(), errors::LabelError> {
+        if self.frozen {
+            return Err(errors::LabelError::Frozen);
+        }
         CURRENT_LABEL.with(|current_label| {
             PRIVILEGE.with(|privilege| {
                 if current_label.borrow().can_flow_to_with_privilege(&self.label, &privilege.borrow()) {
@@ -160,6 +345,9 @@ impl<T> Labeled<T> {
     }
 
     fn modify<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> Result<R, errors::LabelError> {
+        if self.frozen {
+            return Err(errors::LabelError::Frozen);
+        }
         CURRENT_LABEL.with(|current_label| {
             let new_label = {
                 self.label.clone().lub(current_label.borrow().clone())
@@ -176,29 +364,146 @@ impl<T> Labeled<T> {
     }
 }
 
-impl<T: Default + Serialize> ObjectRef<Labeled<T>> {
+impl<T: Clone + Serialize + DeserializeOwned> ObjectRef<Labeled<T>>
+where
+    Labeled<T>: Object,
+{
+    // A frozen copy of the object, or the object itself if it is frozen already, recording the
+    // keys of copies in `copied`. Reading the object taints the current label.
+    fn frozen_copy<B: BackingStore>(
+        &self,
+        fs: &FS<B>,
+        copied: &mut Vec<[u8; 9]>,
+    ) -> Result<Self, FsError> {
+        let mut copy = self.get(fs).ok_or(FsError::BadPath)?;
+        copy.unlabel();
+        if copy.frozen {
+            return Ok(*self);
+        }
+        if copied.len() >= SNAPSHOT_LIMIT {
+            return Err(FsError::TooLarge);
+        }
+        copy.frozen = true;
+        let copy = ObjectRef::set_new_id(&copy, &fs.0);
+        copied.push(copy.key());
+        Ok(copy)
+    }
+}
+
+impl<T: Default + Serialize> ObjectRef<Labeled<T>>
+where
+    Labeled<T>: Object,
+{
     pub fn create<B: BackingStore>(label: Buckle, storage: &B) -> Self {
         let labeled = Labeled {
             label,
             data: T::default(),
+            frozen: false,
+            digest: None,
+            blob_meta: None,
         };
         ObjectRef::set_new_id(&labeled, storage)
     }
 }
 
+/// Most objects `FS::snapshot` copies
+pub const SNAPSHOT_LIMIT: usize = 100_000;
+
+/// Directories with more entries than this are split into `SHARD_COUNT` shards
+pub const SHARD_THRESHOLD: usize = 1024;
+pub const SHARD_COUNT: usize = 64;
+
+/// A directory holds its entries itself until it grows past `SHARD_THRESHOLD`, when they move
+/// to shards, directories of the same label that each hold the entries whose names hash to
+/// them. Linking and unlinking then only rewrite one shard. A shard that grows past the
+/// threshold is sharded in turn, hashing names with its depth so that its entries spread over
+/// its own shards.
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Directory {
     entries: BTreeMap<String, DirEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    shards: Vec<ObjectRef<Labeled<Directory>>>,
+    // number of shards above this one, 0 for a directory
+    #[serde(default, skip_serializing_if = "is_zero")]
+    depth: u32,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+// Index of the shard holding the entry `name` among the `n` shards of a directory or shard at
+// `depth`. Depth 0 hashes the name alone, as directories were sharded before nesting.
+fn shard_index(name: &str, n: usize, depth: u32) -> usize {
+    use sha2::{Digest, Sha256};
+    use std::convert::TryInto;
+    let mut hasher = Sha256::new();
+    if depth > 0 {
+        hasher.update(depth.to_be_bytes());
+    }
+    hasher.update(name.as_bytes());
+    let hash = u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap());
+    (hash % n as u64) as usize
+}
+
+impl Directory {
+    // The shard holding the entry `name` of a sharded directory
+    fn shard(&self, name: &str) -> ObjectRef<Labeled<Directory>> {
+        self.shards[shard_index(name, self.shards.len(), self.depth)]
+    }
+
+    // The entry `name`, without tainting the current label
+    fn entry<B: BackingStore>(&self, name: &str, fs: &FS<B>) -> Option<DirEntry> {
+        let mut dir = std::borrow::Cow::Borrowed(self);
+        while !dir.shards.is_empty() {
+            dir = std::borrow::Cow::Owned(dir.shard(name).get(fs)?.data);
+        }
+        dir.entries.get(name).cloned()
+    }
+
+    // Adds the entries of the directory and of all its shards to `entries`
+    fn collect_entries<B: BackingStore>(
+        &self,
+        fs: &FS<B>,
+        entries: &mut BTreeMap<String, DirEntry>,
+    ) {
+        entries.extend(self.entries.clone());
+        let mut pending = self.shards.clone();
+        while let Some(shard) = pending.pop() {
+            if let Some(shard) = shard.get(fs) {
+                entries.extend(shard.data.entries);
+                pending.extend(shard.data.shards);
+            }
+        }
+    }
 }
 
 impl ObjectRef<Labeled<Directory>> {
     pub fn list<B: BackingStore>(&self, fs: &FS<B>) -> BTreeMap<String, DirEntry> {
-        self.get(fs).unwrap().unlabel().entries.clone()
+        let dir = self.get(fs).unwrap();
+        let dir = dir.unlabel();
+        let mut entries = BTreeMap::new();
+        // shards have the directory's label
+        dir.collect_entries(fs, &mut entries);
+        entries
+    }
+
+    /// Returns the entry `name`, reading only its shard in a sharded directory
+    pub fn lookup<B: BackingStore>(&self, name: &str, fs: &FS<B>) -> Option<DirEntry> {
+        let dir = self.get(fs)?;
+        dir.unlabel();
+        dir.data.entry(name, fs)
     }
 
-    pub fn link<B: BackingStore>(&self, name: String, entry: DirEntry, fs: &FS<B>) -> Result<bool, errors::LabelError> {
+    pub fn link<B: BackingStore>(&self, name: String, entry: DirEntry, fs: &FS<B>) -> Result<bool, FsError> {
         let mut prev_dir = self.get(fs).unwrap();
+        let mut retry = CasRetry::new();
         loop {
+            if !prev_dir.data.shards.is_empty() {
+                // writing to a shard needs the same privilege as writing to the directory
+                prev_dir.modify(|_| ())?;
+                return prev_dir.data.shard(&name).link(name, entry, fs);
+            }
             let mut labeled_dir = prev_dir.clone();
             let existed = labeled_dir.modify(|dir| {
                 dir.entries.insert(name.clone(), entry.clone()).is_some()
@@ -206,16 +511,85 @@ impl ObjectRef<Labeled<Directory>> {
             if existed {
                 return Ok(false);
             }
+            if labeled_dir.data.entries.len() > SHARD_THRESHOLD {
+                self.shard_entries(&mut labeled_dir, fs);
+            }
             if let Err(Some(p)) = self.cas(Some(&prev_dir), &labeled_dir, &fs.0) {
+                labeled_dir.data.shards.iter().for_each(|s| s.delete(&fs.0));
                 prev_dir = p;
+                retry.backoff()?;
             } else {
                 return Ok(true)
             }
         }
     }
 
-    pub fn unlink<B: BackingStore>(&self, name: &String, fs: &FS<B>) -> Result<bool, errors::LabelError> {
+    // Moves the entries of `dir` to new shards
+    fn shard_entries<B: BackingStore>(&self, dir: &mut Labeled<Directory>, fs: &FS<B>) {
+        let depth = dir.data.depth;
+        let mut buckets: Vec<BTreeMap<String, DirEntry>> = vec![Default::default(); SHARD_COUNT];
+        for (name, entry) in std::mem::take(&mut dir.data.entries) {
+            buckets[shard_index(&name, SHARD_COUNT, depth)].insert(name, entry);
+        }
+        dir.data.shards = buckets
+            .into_iter()
+            .map(|entries| {
+                let shard = Labeled {
+                    label: dir.label.clone(),
+                    data: Directory { entries, shards: vec![], depth: depth + 1 },
+                    frozen: false,
+                    digest: None,
+                    blob_meta: None,
+                };
+                ObjectRef::set_new_id(&shard, &fs.0)
+            })
+            .collect();
+    }
+
+    // Unlinks the entries of a trash directory that expired by `now`, regardless of labels
+    fn purge_expired<B: BackingStore>(&self, now: u64, fs: &FS<B>) -> Result<usize, FsError> {
+        let expired = |name: &str| split_trashed(name).is_some_and(|(expires, _)| expires <= now);
+        self.unlink_unchecked(&expired, fs)
+    }
+
+    // Unlinks the entries whose names `doomed` picks, regardless of labels, and returns how
+    // many it unlinked. Fails with `FsError::Contended` if the directory kept changing under it.
+    fn unlink_unchecked<B: BackingStore>(
+        &self,
+        doomed: &dyn Fn(&str) -> bool,
+        fs: &FS<B>,
+    ) -> Result<usize, FsError> {
+        let Some(mut prev_dir) = self.get(fs) else {
+            return Ok(0);
+        };
+        if !prev_dir.data.shards.is_empty() {
+            return prev_dir.data.shards.iter().map(|s| s.unlink_unchecked(doomed, fs)).sum();
+        }
+        let mut retry = CasRetry::new();
+        loop {
+            let mut labeled_dir = prev_dir.clone();
+            let before = labeled_dir.data.entries.len();
+            labeled_dir.data.entries.retain(|name, _| !doomed(name));
+            let purged = before - labeled_dir.data.entries.len();
+            if purged == 0 {
+                return Ok(0);
+            }
+            if let Err(Some(p)) = self.cas(Some(&prev_dir), &labeled_dir, &fs.0) {
+                prev_dir = p;
+                retry.backoff()?;
+            } else {
+                return Ok(purged);
+            }
+        }
+    }
+
+    pub fn unlink<B: BackingStore>(&self, name: &String, fs: &FS<B>) -> Result<bool, FsError> {
         let mut prev_dir = self.get(fs).unwrap();
+        if !prev_dir.data.shards.is_empty() {
+            prev_dir.modify(|_| ())?;
+            return prev_dir.data.shard(name).unlink(name, fs);
+        }
+        let mut retry = CasRetry::new();
         loop {
             let mut labeled_dir = prev_dir.clone();
             let existed = labeled_dir.modify(|dir| {
@@ -226,6 +600,7 @@ impl ObjectRef<Labeled<Directory>> {
             }
             if let Err(Some(p)) = self.cas(Some(&prev_dir), &labeled_dir, &fs.0) {
                 prev_dir = p;
+                retry.backoff()?;
             } else {
                 return Ok(true)
             }
@@ -235,6 +610,11 @@ impl ObjectRef<Labeled<Directory>> {
 
 type File = Vec<u8>;
 
+fn content_digest(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(data))
+}
+
 impl ObjectRef<Labeled<File>> {
     pub fn read<B: BackingStore>(&self, fs: &FS<B>) -> File {
         self.get(fs).unwrap().unlabel().clone()
@@ -242,10 +622,310 @@ impl ObjectRef<Labeled<File>> {
 
     pub fn write<B: BackingStore>(&self, data: Vec<u8>, fs: &FS<B>) -> Result<(), errors::LabelError> {
         let mut file = self.get(fs).unwrap();
+        let digest = content_digest(&data);
         file.write(data)?;
+        file.digest = Some(digest);
         self.set(&file, &fs.0);
         Ok(())
     }
+
+    /// Returns the file's entity tag, the hex encoded SHA-256 of its content. Like reading the
+    /// file, this taints the current label with the file's label.
+    pub fn etag<B: BackingStore>(&self, fs: &FS<B>) -> String {
+        let file = self.get(fs).unwrap();
+        let data = file.unlabel();
+        file.digest.clone().unwrap_or_else(|| content_digest(data))
+    }
+
+    /// Adds `delta` to the counter in the file and returns the new value. A counter is a
+    /// decimal integer, and an empty file counts as 0.
+    ///
+    /// Unlike reading and then writing the file, increments racing with each other are never
+    /// lost.
+    pub fn increment<B: BackingStore>(&self, delta: i64, fs: &FS<B>) -> Result<i64, FsError> {
+        self.update(fs, |data| {
+            let value = if data.is_empty() {
+                0
+            } else {
+                std::str::from_utf8(data)
+                    .ok()
+                    .and_then(|s| s.trim().parse::<i64>().ok())
+                    .ok_or(FsError::NotACounter)?
+            };
+            let value = value.checked_add(delta).ok_or(FsError::NotACounter)?;
+            *data = value.to_string().into_bytes();
+            Ok(value)
+        })
+    }
+
+    /// Takes the lock in the file for `ttl` milliseconds and returns the lease's token, or
+    /// `None` if someone else holds the lock. A lock is a JSON encoded `Lease`, and an empty
+    /// file is a free lock. Passing the token of a lease still held renews it.
+    pub fn lock<B: BackingStore>(
+        &self,
+        ttl: u64,
+        token: Option<u64>,
+        fs: &FS<B>,
+    ) -> Result<Option<u64>, FsError> {
+        let now = Lease::now();
+        self.update(fs, |data| {
+            if let Some(lease) = Lease::parse(data)? {
+                if lease.expires > now && Some(lease.token) != token {
+                    return Ok(None);
+                }
+            }
+            let lease = Lease {
+                token: token.unwrap_or_else(rand::random),
+                expires: now.saturating_add(ttl),
+            };
+            *data = serde_json::to_vec(&lease).unwrap();
+            Ok(Some(lease.token))
+        })
+    }
+
+    /// Frees the lock in the file if it's held with the lease `token`. Returns whether the
+    /// lease was still held.
+    pub fn unlock<B: BackingStore>(&self, token: u64, fs: &FS<B>) -> Result<bool, FsError> {
+        let now = Lease::now();
+        self.update(fs, |data| match Lease::parse(data)? {
+            Some(lease) if lease.token == token => {
+                data.clear();
+                Ok(lease.expires > now)
+            }
+            _ => Ok(false),
+        })
+    }
+
+    /// Sets up a barrier in the file that completes once `expected` results arrive, replacing
+    /// the barrier already in it, if any. The barrier keeps the thread's privilege to invoke
+    /// its gate with. A barrier is a JSON encoded `Barrier`.
+    pub fn init_barrier<B: BackingStore>(
+        &self,
+        expected: u64,
+        gate: String,
+        fs: &FS<B>,
+    ) -> Result<(), FsError> {
+        if expected == 0 {
+            return Err(FsError::EmptyBarrier);
+        }
+        let privilege = PRIVILEGE.with(|p| p.borrow().clone());
+        self.update(fs, |data| {
+            let barrier = Barrier {
+                expected,
+                gate: gate.clone(),
+                results: vec![],
+                privilege: Some(privilege.clone()),
+                claimed: 0,
+                fired: false,
+            };
+            *data = serde_json::to_vec(&barrier).unwrap();
+            Ok(())
+        })
+    }
+
+    /// Adds `result` to the barrier in the file and returns the barrier after the arrival.
+    /// Arrivals racing with each other are never lost. A complete barrier was claimed for its
+    /// caller to invoke the gate, see `barrier_fired`. Arrivals at a complete barrier whose gate
+    /// wasn't invoked `BARRIER_CLAIM_MS` after it was last claimed add nothing but claim it
+    /// again, so that invoking the gate can be retried.
+    pub fn arrive<B: BackingStore>(&self, result: String, fs: &FS<B>) -> Result<Barrier, FsError> {
+        let now = Lease::now();
+        self.update(fs, |data| {
+            let mut barrier: Barrier =
+                serde_json::from_slice(data).or(Err(FsError::NotABarrier))?;
+            if barrier.is_complete() {
+                if barrier.fired || now < barrier.claimed.saturating_add(BARRIER_CLAIM_MS) {
+                    return Err(FsError::BarrierComplete);
+                }
+            } else {
+                barrier.results.push(result.clone());
+            }
+            if barrier.is_complete() {
+                barrier.claimed = now;
+            }
+            *data = serde_json::to_vec(&barrier).unwrap();
+            Ok(barrier)
+        })
+    }
+
+    /// Records that the gate of the barrier in the file was invoked
+    pub fn barrier_fired<B: BackingStore>(&self, fs: &FS<B>) -> Result<(), FsError> {
+        self.update(fs, |data| {
+            let mut barrier: Barrier =
+                serde_json::from_slice(data).or(Err(FsError::NotABarrier))?;
+            barrier.fired = true;
+            *data = serde_json::to_vec(&barrier).unwrap();
+            Ok(())
+        })
+    }
+
+    // Replaces the file's data with `f` applied to it, retrying `f` on the latest data until
+    // no other update interleaves
+    fn update<B, R, F>(&self, fs: &FS<B>, f: F) -> Result<R, FsError>
+    where
+        B: BackingStore,
+        F: Fn(&mut File) -> Result<R, FsError>,
+    {
+        let mut prev_file = self.get(fs).ok_or(FsError::BadPath)?;
+        let mut retry = CasRetry::new();
+        loop {
+            let mut labeled_file = prev_file.clone();
+            let result = labeled_file.modify(&f)??;
+            labeled_file.digest = Some(content_digest(&labeled_file.data));
+            match self.cas(Some(&prev_file), &labeled_file, &fs.0) {
+                Ok(()) => return Ok(result),
+                Err(Some(p)) => {
+                    prev_file = p;
+                    retry.backoff()?;
+                }
+                Err(None) => return Err(FsError::BadPath),
+            }
+        }
+    }
+}
+
+/// Content of a file used as a lock
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    pub token: u64,
+    /// milliseconds since the UNIX epoch
+    pub expires: u64,
+}
+
+/// Content of a file used as a barrier, which collects the results of a fan-out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Barrier {
+    pub expected: u64,
+    /// path of the gate invoked with the results once they have all arrived
+    pub gate: String,
+    /// in order of arrival
+    pub results: Vec<String>,
+    /// privilege of the principal that set up the barrier
+    #[serde(default)]
+    pub privilege: Option<Component>,
+    /// when an arrival last claimed invoking the gate, in milliseconds since the UNIX epoch
+    #[serde(default)]
+    pub claimed: u64,
+    /// whether the gate was invoked
+    #[serde(default)]
+    pub fired: bool,
+}
+
+/// How long the arrival completing a barrier has to invoke its gate before another arrival may
+/// try again
+pub const BARRIER_CLAIM_MS: u64 = 60_000;
+
+impl Barrier {
+    pub fn is_complete(&self) -> bool {
+        self.results.len() as u64 >= self.expected
+    }
+
+    /// The privilege to invoke the gate with: what the principal that set up the barrier had
+    /// and what anyone who can write to a file labeled `label` vouches for. The file holds no
+    /// more than that since anyone who can write to it can rewrite `privilege`.
+    pub fn firing_privilege(&self, label: &Buckle) -> Component {
+        let privilege = self.privilege.clone().unwrap_or_else(Component::dc_true);
+        privilege | label.integrity.clone()
+    }
+}
+
+impl Lease {
+    fn parse(data: &[u8]) -> Result<Option<Self>, FsError> {
+        if data.is_empty() {
+            Ok(None)
+        } else {
+            serde_json::from_slice(data).map(Some).or(Err(FsError::NotALock))
+        }
+    }
+
+    fn now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+}
+
+// Fails unless the thread's privilege implies `privilege`, as creating a gate or service
+// holding it requires
+fn can_delegate(privilege: &Component) -> Result<(), FsError> {
+    if PRIVILEGE.with(|p| p.borrow().implies(privilege)) {
+        Ok(())
+    } else {
+        Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate))
+    }
+}
+
+/// Deepest directory tree `FS::remove_recursive` descends
+pub const REMOVE_MAX_DEPTH: usize = 256;
+
+/// How far a call to `FS::remove_recursive` got
+#[derive(Debug, Default)]
+pub struct Removal {
+    /// objects unlinked by the call
+    pub unlinked: usize,
+    /// where to resume, `None` once the object itself is unlinked
+    pub cursor: Option<Vec<String>>,
+    /// why the call stopped before using up its budget, if it failed
+    pub error: Option<FsError>,
+}
+
+/// Name of the optional directory in a facet that `FS::trash` moves entries to
+pub const TRASH_DIR: &str = ".trash";
+
+/// Longest `FS::trash` keeps an entry restorable, in seconds
+pub const MAX_TRASH_RETENTION: u64 = 365 * 24 * 3600;
+
+// Trashed entries are named `EXPIRES@NAME`, EXPIRES in seconds since the UNIX epoch. Entries
+// trashed earlier are named `EXPIRES:NAME`, which paths can't address since ':' separates their
+// components, so they are still understood here.
+fn split_trashed(name: &str) -> Option<(u64, &str)> {
+    ['@', ':'].iter().find_map(|sep| {
+        let (expires, name) = name.split_once(*sep)?;
+        Some((expires.parse().ok()?, name))
+    })
+}
+
+/// Selects the facets of a faceted directory by the principals their labels mention, e.g. all
+/// facets whose secrecy mentions `alice`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FacetPattern {
+    /// a principal the facet's secrecy must mention, itself or a sub-principal of it
+    pub secrecy: Option<Principal>,
+    /// a principal the facet's integrity must mention, itself or a sub-principal of it
+    pub integrity: Option<Principal>,
+}
+
+impl FacetPattern {
+    /// Parses a principal written as a Buckle component of one principal, e.g. `alice`
+    pub fn parse_principal(principal: &str) -> Option<Principal> {
+        match Buckle::parse(&format!("{},T", principal)).ok()?.secrecy {
+            Component::DCFormula(clauses) if clauses.len() == 1 => {
+                let clause = clauses.into_iter().next()?;
+                if clause.0.len() == 1 {
+                    clause.0.into_iter().next()
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, facet: &Buckle) -> bool {
+        fn mentions(component: &Component, principal: &Option<Principal>) -> bool {
+            let Some(principal) = principal else {
+                return true;
+            };
+            match component {
+                Component::DCFalse => false,
+                Component::DCFormula(clauses) => clauses
+                    .iter()
+                    .any(|clause| clause.0.iter().any(|p| p.starts_with(principal))),
+            }
+        }
+        mentions(&facet.secrecy, &self.secrecy) && mentions(&facet.integrity, &self.integrity)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -254,30 +934,48 @@ pub struct FacetedDirectory {
 }
 
 impl ObjectRef<FacetedDirectory> {
-    pub fn open<B: BackingStore>(&self, facet: &Buckle, fs: &FS<B>) -> ObjectRef<Labeled<Directory>> {
+    pub fn open<B: BackingStore>(&self, facet: &Buckle, fs: &FS<B>) -> Result<ObjectRef<Labeled<Directory>>, FsError> {
         let mut mfaceted_dir = self.get(fs);
+        let mut retry = CasRetry::new();
         loop {
             if let Some(faceted_dir) = mfaceted_dir.as_ref() {
                 if let Some(res) = faceted_dir.facets.iter().find_map(|(f, value)| if f.eq(facet) { Some(value) } else { None }) {
-                    return *res;
+                    return Ok(*res);
                 }
             }
             let new_dir = ObjectRef::set_new_id(&Labeled {
                 label: facet.clone(),
                 data: Directory::default(),
+                frozen: false,
+                digest: None,
+                blob_meta: None,
             }, &fs.0);
 
             let mut new_faceted_dir = mfaceted_dir.clone().unwrap_or_default();
             new_faceted_dir.facets.push((facet.clone(), new_dir));
 
             match self.cas(mfaceted_dir.as_ref(), &new_faceted_dir, &fs.0) {
-                Ok(()) => return new_dir,
-                Err(d) => mfaceted_dir = d.clone(),
+                Ok(()) => return Ok(new_dir),
+                Err(d) => {
+                    new_dir.delete(&fs.0);
+                    mfaceted_dir = d;
+                    retry.backoff()?;
+                }
             }
         }
     }
 
     pub fn list<B: BackingStore>(&self, fs: &FS<B>, clearance: &Buckle) -> BTreeMap<Buckle, ObjectRef<Labeled<Directory>>> {
+        self.query(fs, clearance, &FacetPattern::default())
+    }
+
+    /// Like `list`, but returns only the facets `pattern` matches
+    pub fn query<B: BackingStore>(
+        &self,
+        fs: &FS<B>,
+        clearance: &Buckle,
+        pattern: &FacetPattern,
+    ) -> BTreeMap<Buckle, ObjectRef<Labeled<Directory>>> {
         CURRENT_LABEL.with(|current_label| {
             let cl = {
                 current_label.borrow().clone().lub(clearance.clone())
@@ -285,7 +983,7 @@ impl ObjectRef<FacetedDirectory> {
             *current_label.borrow_mut() = cl;
         });
         self.get(fs).unwrap().facets.iter().filter_map(|(label, entry)| {
-            if label.can_flow_to(clearance) {
+            if label.can_flow_to(clearance) && pattern.matches(label) {
                 Some((label.clone(), *entry))
             } else {
                 None
@@ -302,8 +1000,23 @@ pub struct Service {
     pub url: String,
     pub verb: HttpVerb,
     pub headers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Box<Metadata>>,
+}
+
+/// Human-readable information about a gate or service, shown in listings
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// a payload to try the function with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub example_payload: Option<String>,
 }
 
+
 impl ObjectRef<Labeled<Service>> {
     pub fn to_invokable<B: BackingStore>(&self, fs: &FS<B>) -> Service {
         self.get(fs).unwrap().unlabel().clone()
@@ -319,14 +1032,17 @@ impl ObjectRef<Labeled<Service>> {
             }
         })?;
         let mut service = self.get(fs).unwrap();
+        let old = audit::Version::from(&service.data);
+        let new = audit::Version::from(&new_service);
         service.write(new_service)?;
+        audit::record(fs, "service", self.uid, old, new)?;
         Ok(self.set(&service, &fs.0))
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Gate {
-    Direct(DirectGate),
+    Direct(Box<DirectGate>),
     Redirect(RedirectGate),
 }
 
@@ -358,6 +1074,7 @@ impl ObjectRef<Labeled<Gate>> {
                         invoker_integrity_clearance,
                         declassify,
                         function: gate.function,
+                        metadata: gate.metadata,
                     }
                 },
                 Gate::Redirect(redirect_gate) => {
@@ -386,7 +1103,10 @@ impl ObjectRef<Labeled<Gate>> {
             })?;
         }
         let mut gate = self.get(fs).unwrap();
+        let old = audit::Version::from(&gate.data);
+        let new = audit::Version::from(&new_gate);
         gate.write(new_gate)?;
+        audit::record(fs, "gate", self.uid, old, new)?;
         Ok(self.set(&gate, &fs.0))
     }
 }
@@ -397,6 +1117,8 @@ pub struct DirectGate {
     pub invoker_integrity_clearance: Component,
     pub declassify: Component,
     pub function: Function,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Box<Metadata>>,
 }
 
 impl ObjectRef<Labeled<DirectGate>> {
@@ -412,11 +1134,131 @@ impl ObjectRef<Labeled<Blob>> {
         self.get(fs).unwrap().unlabel().clone()
     }
 
-    pub fn replace<B: BackingStore>(&self, new_blob: Blob, fs: &FS<B>) -> Result<(), LabelError> {
+    pub fn replace<B: BackingStore>(
+        &self,
+        new_blob: Blob,
+        meta: BlobMeta,
+        fs: &FS<B>,
+    ) -> Result<(), LabelError> {
         let mut blob = self.get(fs).unwrap();
         blob.write(new_blob)?;
+        blob.blob_meta = Some(meta);
         Ok(self.set(&blob, &fs.0))
     }
+
+    /// The recorded size and content type, `None` for entries that predate them. Reading the
+    /// metadata taints the current label like reading the blob name does.
+    pub fn meta<B: BackingStore>(&self, fs: &FS<B>) -> Option<BlobMeta> {
+        let blob = self.get(fs)?;
+        blob.unlabel();
+        blob.blob_meta
+    }
+}
+
+/// Number of entries in a sealed log chunk
+pub const LOG_CHUNK_SIZE: usize = 256;
+/// Most entries `read_range` returns at once
+pub const LOG_READ_MAX: u64 = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// milliseconds since the UNIX epoch
+    pub time: u64,
+    pub data: Vec<u8>,
+}
+
+/// An append-only log. Appends go to the tail, which is sealed into an immutable chunk object
+/// once it holds `LOG_CHUNK_SIZE` entries, so an append never rewrites more than one chunk.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Log {
+    chunks: Vec<ObjectRef<Vec<LogEntry>>>,
+    tail: Vec<LogEntry>,
+}
+
+impl Log {
+    pub fn len(&self) -> u64 {
+        (self.chunks.len() * LOG_CHUNK_SIZE + self.tail.len()) as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Appends `entry` to the tail and returns its index, sealing the tail into a new chunk
+    // once full. Also returns the sealed chunk, to delete if the append is lost.
+    fn push<B: BackingStore>(
+        &mut self,
+        entry: LogEntry,
+        storage: &B,
+    ) -> (u64, Option<ObjectRef<Vec<LogEntry>>>) {
+        self.tail.push(entry);
+        let index = self.len() - 1;
+        let mut sealed = None;
+        if self.tail.len() >= LOG_CHUNK_SIZE {
+            let chunk = ObjectRef::set_new_id(&std::mem::take(&mut self.tail), storage);
+            self.chunks.push(chunk);
+            sealed = Some(chunk);
+        }
+        (index, sealed)
+    }
+}
+
+impl ObjectRef<Labeled<Log>> {
+    /// Appends `data` to the log and returns its index. Unlike rewriting a file, appends racing
+    /// with each other are never lost.
+    pub fn append<B: BackingStore>(&self, data: Vec<u8>, fs: &FS<B>) -> Result<u64, FsError> {
+        let entry = LogEntry { time: Lease::now(), data };
+        let mut prev_log = self.get(fs).ok_or(FsError::BadPath)?;
+        let mut retry = CasRetry::new();
+        loop {
+            let mut labeled_log = prev_log.clone();
+            let (index, sealed) = labeled_log.modify(|log| log.push(entry.clone(), &fs.0))?;
+            match self.cas(Some(&prev_log), &labeled_log, &fs.0) {
+                Ok(()) => return Ok(index),
+                Err(p) => {
+                    if let Some(chunk) = sealed {
+                        chunk.delete(&fs.0);
+                    }
+                    prev_log = p.ok_or(FsError::BadPath)?;
+                    retry.backoff()?;
+                }
+            }
+        }
+    }
+
+    /// Returns the entries with indices in `from..to`, stopping at the end of the log and after
+    /// `LOG_READ_MAX` entries. Like reading a file, this taints the current label with the
+    /// log's label.
+    pub fn read_range<B: BackingStore>(
+        &self,
+        from: u64,
+        to: u64,
+        fs: &FS<B>,
+    ) -> Result<Vec<LogEntry>, FsError> {
+        let labeled_log = self.get(fs).ok_or(FsError::BadPath)?;
+        let log = labeled_log.unlabel();
+        let to = to.min(log.len()).min(from.saturating_add(LOG_READ_MAX));
+        let mut entries = Vec::new();
+        let mut index = from;
+        while index < to {
+            let start = (index % LOG_CHUNK_SIZE as u64) as usize;
+            let end = start + (to - index).min((LOG_CHUNK_SIZE - start) as u64) as usize;
+            match log.chunks.get((index / LOG_CHUNK_SIZE as u64) as usize) {
+                Some(chunk) => {
+                    let chunk = chunk.get(fs).ok_or(FsError::BadPath)?;
+                    entries.extend_from_slice(&chunk[start..end]);
+                }
+                None => entries.extend_from_slice(&log.tail[start..end]),
+            }
+            index += (end - start) as u64;
+        }
+        Ok(entries)
+    }
+
+    /// Returns the number of entries in the log, tainting the current label with its label
+    pub fn len<B: BackingStore>(&self, fs: &FS<B>) -> Result<u64, FsError> {
+        Ok(self.get(fs).ok_or(FsError::BadPath)?.unlabel().len())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -428,6 +1270,39 @@ pub enum DirEntry {
     Gate(ObjectRef<Labeled<Gate>>) = 3,
     Service(ObjectRef<Labeled<Service>>) = 4,
     Blob(ObjectRef<Labeled<Blob>>) = 5,
+    Log(ObjectRef<Labeled<Log>>) = 6,
+}
+
+impl DirEntry {
+    /// The label of the entry, leaving the current label untouched. Faceted directories have
+    /// none, each of their facets has its own.
+    pub fn label<B: BackingStore>(&self, fs: &FS<B>) -> Option<Buckle> {
+        match self {
+            DirEntry::Directory(o) => o.get(fs).map(|l| l.label().clone()),
+            DirEntry::File(o) => o.get(fs).map(|l| l.label().clone()),
+            DirEntry::FacetedDirectory(_) => None,
+            DirEntry::Gate(o) => o.get(fs).map(|l| l.label().clone()),
+            DirEntry::Service(o) => o.get(fs).map(|l| l.label().clone()),
+            DirEntry::Blob(o) => o.get(fs).map(|l| l.label().clone()),
+            DirEntry::Log(o) => o.get(fs).map(|l| l.label().clone()),
+        }
+    }
+
+    /// The metadata of a direct gate or a service, empty for other entries. Like reading the
+    /// entry, this taints the current label with its label.
+    pub fn metadata<B: BackingStore>(&self, fs: &FS<B>) -> Metadata {
+        match self {
+            DirEntry::Gate(gate) => match gate.get(fs).as_ref().map(Labeled::unlabel) {
+                Some(Gate::Direct(gate)) => gate.metadata.as_deref().cloned().unwrap_or_default(),
+                _ => Metadata::default(),
+            },
+            DirEntry::Service(service) => service
+                .get(fs)
+                .and_then(|service| service.unlabel().metadata.as_deref().cloned())
+                .unwrap_or_default(),
+            _ => Metadata::default(),
+        }
+    }
 }
 
 // FS definition
@@ -446,20 +1321,22 @@ impl<S: BackingStore> FS<S> {
     pub fn initialize(&self) -> bool {
         let root = Labeled {
             label: Buckle::new(true, false),
-            data: Directory {
-                entries: Default::default(),
-            }
+            data: Directory::default(),
+            frozen: false,
+            digest: None,
+            blob_meta: None,
         };
         self.0
-            .add(&ROOT_REF.uid.to_be_bytes(), &serde_json::ser::to_vec(&root).unwrap())
+            .add(&ROOT_REF.key(), &serde_json::ser::to_vec(&root).unwrap())
     }
 
     pub fn root(&self) -> Labeled<Directory> {
         ROOT_REF.get(self).unwrap_or(Labeled {
             label: Buckle::new(true, false),
-            data: Directory {
-                entries: Default::default(),
-            }
+            data: Directory::default(),
+            frozen: false,
+            digest: None,
+            blob_meta: None,
         })
     }
 
@@ -476,7 +1353,9 @@ impl<S: BackingStore> FS<S> {
 
         let mut cur_entry;
         if let Some(PathComponent::Dscrp(comp)) = path.pop_front() {
-            cur_entry = self.root().unlabel().entries.get(&comp).cloned();
+            let root = self.root();
+            root.unlabel();
+            cur_entry = root.data.entry(&comp, self);
         } else {
             return Ok(DirEntry::Directory(ROOT_REF));
         }
@@ -484,10 +1363,10 @@ impl<S: BackingStore> FS<S> {
         while let Some(comp) = path.pop_front() {
             match (cur_entry, comp) {
                 (Some(DirEntry::Directory(ref dir_obj)), PathComponent::Dscrp(ref dscrp)) => {
-                    cur_entry = dir_obj.list(self).get(dscrp).cloned();
+                    cur_entry = dir_obj.lookup(dscrp, self);
                 },
                 (Some(DirEntry::FacetedDirectory(ref facet_obj)), PathComponent::Facet(ref facet)) => {
-                    cur_entry = Some(DirEntry::Directory(facet_obj.open(facet, self)));
+                    cur_entry = Some(DirEntry::Directory(facet_obj.open(facet, self)?));
                 },
                 _ => return Err(FsError::BadPath),
             }
@@ -522,12 +1401,313 @@ impl<S: BackingStore> FS<S> {
     pub fn rm<P: Into<Path>>(&self, dir: P, name: &String) -> Result<bool, FsError> {
         match self.read_path(dir)? {
             DirEntry::Directory(dir_obj) => {
-                dir_obj.unlink(name, self).map_err(Into::into)
+                dir_obj.unlink(name, self)
             },
             _ => Err(FsError::NotADir)
         }
     }
 
+    /// Unlinks the object at `path` and, if it is a directory, everything under it, bottom-up.
+    /// Each unlink is checked against the labels of the directory it modifies. Stops after
+    /// `budget` unlinks with a cursor to pass to the next call, which is `None` once done.
+    /// Stops early with the error and a cursor to retry from if an unlink fails or the tree is
+    /// deeper than `REMOVE_MAX_DEPTH`, keeping what it already unlinked.
+    ///
+    /// The thread's current label is tainted for each path component and each directory
+    /// emptied, so removing a directory whose contents are more secret than it needs the
+    /// privilege to write to it after reading them.
+    pub fn remove_recursive<P: Into<Path>>(
+        &self,
+        path: P,
+        cursor: Option<&[String]>,
+        budget: usize,
+    ) -> Result<Removal, FsError> {
+        let path: Path = path.into();
+        let (dir, name) = path.parent().zip(path.file_name()).ok_or(FsError::BadPath)?;
+        let DirEntry::Directory(dir_obj) = self.read_path(dir)? else {
+            return Err(FsError::NotADir);
+        };
+        let mut removal = Removal::default();
+        if let Some(DirEntry::Directory(target)) = dir_obj.list(self).get(&name).cloned() {
+            let cursor = cursor.unwrap_or_default();
+            if !self.empty_dir(target, cursor, budget, &mut removal) {
+                return Ok(removal);
+            }
+        }
+        if removal.unlinked >= budget {
+            removal.cursor = Some(Vec::new());
+            return Ok(removal);
+        }
+        match dir_obj.unlink(&name, self) {
+            Ok(_) => removal.unlinked += 1,
+            Err(e) => {
+                removal.cursor = Some(Vec::new());
+                removal.error = Some(e);
+            }
+        }
+        Ok(removal)
+    }
+
+    // Unlinks everything under `dir`, first under the subdirectory `cursor` leads to, until
+    // `removal` counts `budget` unlinks. Returns whether `dir` is empty, or leaves the path to
+    // the directory it stopped in, and the error that stopped it if any, in `removal`.
+    fn empty_dir(
+        &self,
+        dir: ObjectRef<Labeled<Directory>>,
+        cursor: &[String],
+        budget: usize,
+        removal: &mut Removal,
+    ) -> bool {
+        struct Frame {
+            dir: ObjectRef<Labeled<Directory>>,
+            entries: Vec<(String, DirEntry)>,
+            // the entry being removed
+            next: usize,
+            // whether the frame is the directory `cursor` leads to or one on the way there
+            on_cursor: bool,
+        }
+        let frame = |dir: ObjectRef<Labeled<Directory>>, on_cursor: bool, depth: usize| {
+            let mut entries: Vec<(String, DirEntry)> = dir.list(self).into_iter().collect();
+            let resume = cursor.get(depth).filter(|_| on_cursor);
+            if let Some(i) = resume.and_then(|name| entries.iter().position(|(n, _)| n == name)) {
+                let entry = entries.remove(i);
+                entries.insert(0, entry);
+            }
+            Frame { dir, entries, next: 0, on_cursor }
+        };
+        // the path from `dir` to the directory being emptied
+        let stop = |frames: &[Frame], removal: &mut Removal, error: Option<FsError>| {
+            let path = frames[..frames.len() - 1].iter().map(|f| f.entries[f.next].0.clone());
+            removal.cursor = Some(path.collect());
+            removal.error = error;
+            false
+        };
+        let mut frames = vec![frame(dir, true, 0)];
+        loop {
+            let depth = frames.len();
+            let top = frames.last().unwrap();
+            match top.entries.get(top.next) {
+                Some((name, DirEntry::Directory(sub))) => {
+                    if depth >= REMOVE_MAX_DEPTH {
+                        return stop(&frames, removal, Some(FsError::TooLarge));
+                    }
+                    let on_cursor =
+                        top.on_cursor && top.next == 0 && cursor.get(depth - 1) == Some(name);
+                    let sub = frame(*sub, on_cursor, depth);
+                    frames.push(sub);
+                    continue;
+                }
+                Some(_) => {}
+                // emptied, unlink it from its parent
+                None if depth > 1 => {
+                    frames.pop();
+                }
+                None => return true,
+            }
+            if removal.unlinked >= budget {
+                return stop(&frames, removal, None);
+            }
+            let top = frames.last_mut().unwrap();
+            if let Err(e) = top.dir.unlink(&top.entries[top.next].0, self) {
+                return stop(&frames, removal, Some(e));
+            }
+            top.next += 1;
+            removal.unlinked += 1;
+        }
+    }
+
+    /// Creates an immutable copy of the object at `path`, for example a directory tree to
+    /// checkpoint before a risky change. Directories, files, gates, services and blob
+    /// references are copied, so later writes to the original never show in the snapshot.
+    /// Objects that never change are shared instead: frozen objects, such as those of earlier
+    /// snapshots, blob contents and sealed log chunks. Writing to any object in the snapshot
+    /// fails. Facets of faceted directories are copied, but new facets can still be opened in
+    /// the copy.
+    ///
+    /// Copying a gate or service needs the privilege to create it. Trees that need more than
+    /// `SNAPSHOT_LIMIT` copies fail with `FsError::TooLarge`, leaving no copies behind.
+    ///
+    /// The thread's current label is tainted for each path component and each object copied.
+    pub fn snapshot<P: Into<Path>>(&self, path: P) -> Result<DirEntry, FsError> {
+        let entry = self.read_path(path)?;
+        let mut copied = Vec::new();
+        let snapshot = self.frozen_copy(&entry, &mut copied);
+        if snapshot.is_err() {
+            for key in copied {
+                self.0.del(&key);
+            }
+        }
+        snapshot
+    }
+
+    fn frozen_copy(
+        &self,
+        entry: &DirEntry,
+        copied: &mut Vec<[u8; 9]>,
+    ) -> Result<DirEntry, FsError> {
+        Ok(match entry {
+            DirEntry::Directory(dir) => DirEntry::Directory(self.frozen_dir(dir, copied)?),
+            DirEntry::FacetedDirectory(faceted) => {
+                if copied.len() >= SNAPSHOT_LIMIT {
+                    return Err(FsError::TooLarge);
+                }
+                let mut copy = faceted.get(self).unwrap_or_default();
+                for (_, facet) in copy.facets.iter_mut() {
+                    *facet = self.frozen_dir(facet, copied)?;
+                }
+                let copy = ObjectRef::set_new_id(&copy, &self.0);
+                copied.push(copy.key());
+                DirEntry::FacetedDirectory(copy)
+            }
+            DirEntry::File(file) => DirEntry::File(file.frozen_copy(self, copied)?),
+            DirEntry::Gate(gate) => {
+                let (privilege, declassify) = match gate.get(self).ok_or(FsError::BadPath)?.data {
+                    Gate::Direct(gate) => (gate.privilege, gate.declassify),
+                    Gate::Redirect(gate) => (gate.privilege, gate.declassify),
+                };
+                can_delegate(&privilege)?;
+                can_delegate(&declassify)?;
+                DirEntry::Gate(gate.frozen_copy(self, copied)?)
+            }
+            DirEntry::Service(service) => {
+                can_delegate(&service.get(self).ok_or(FsError::BadPath)?.data.privilege)?;
+                DirEntry::Service(service.frozen_copy(self, copied)?)
+            }
+            DirEntry::Blob(blob) => DirEntry::Blob(blob.frozen_copy(self, copied)?),
+            // sealed chunks never change, so the copy shares them
+            DirEntry::Log(log) => DirEntry::Log(log.frozen_copy(self, copied)?),
+        })
+    }
+
+    // A frozen copy of the directory and its shards, sharded as the original is
+    fn frozen_dir(
+        &self,
+        dir: &ObjectRef<Labeled<Directory>>,
+        copied: &mut Vec<[u8; 9]>,
+    ) -> Result<ObjectRef<Labeled<Directory>>, FsError> {
+        let mut copy = dir.get(self).ok_or(FsError::BadPath)?;
+        copy.unlabel();
+        if copy.frozen {
+            return Ok(*dir);
+        }
+        if copied.len() >= SNAPSHOT_LIMIT {
+            return Err(FsError::TooLarge);
+        }
+        for entry in copy.data.entries.values_mut() {
+            *entry = self.frozen_copy(entry, copied)?;
+        }
+        for shard in copy.data.shards.iter_mut() {
+            *shard = self.frozen_dir(shard, copied)?;
+        }
+        copy.frozen = true;
+        let copy = ObjectRef::set_new_id(&copy, &self.0);
+        copied.push(copy.key());
+        Ok(copy)
+    }
+
+    // The trash directory of the facet enclosing `dir`, if the facet has one
+    fn trash_dir(&self, dir: &Path) -> Option<ObjectRef<Labeled<Directory>>> {
+        let mut trash = dir.facet_root()?;
+        trash.push_dscrp(TRASH_DIR.to_string());
+        match self.read_path(trash) {
+            Ok(DirEntry::Directory(trash)) => Some(trash),
+            _ => None,
+        }
+    }
+
+    /// Moves `name` from the directory at `dir` to the trash directory of the enclosing facet,
+    /// where it can be restored for `retention` seconds, at most `MAX_TRASH_RETENTION`. The
+    /// entry is named `EXPIRES@NAME` there. Acts as `rm` if the facet has no trash directory.
+    /// Moving needs the same labels and privilege as unlinking plus linking into the trash
+    /// directory.
+    ///
+    /// The thread's current label is tainted for each path component, meaning path
+    /// traversal never fails when the path exists, but may increase the current
+    /// label arbitrarily high.
+    pub fn trash<P: Into<Path>>(
+        &self,
+        dir: P,
+        name: &String,
+        retention: u64,
+    ) -> Result<bool, FsError> {
+        let dir: Path = dir.into();
+        let trash = self.trash_dir(&dir);
+        let DirEntry::Directory(dir_obj) = self.read_path(dir)? else {
+            return Err(FsError::NotADir);
+        };
+        let Some(trash) = trash else {
+            return dir_obj.unlink(name, self);
+        };
+        let Some(entry) = dir_obj.list(self).get(name).cloned() else {
+            return Ok(false);
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let expires = now.saturating_add(retention.min(MAX_TRASH_RETENTION));
+        let trashed = format!("{}@{}", expires, name);
+        if !trash.link(trashed.clone(), entry, self)? {
+            return Err(FsError::NameExists);
+        }
+        match dir_obj.unlink(name, self) {
+            Ok(true) => Ok(true),
+            res => {
+                let _ = trash.unlink(&trashed, self);
+                res
+            }
+        }
+    }
+
+    /// Links the entry `trashed`, as named in the trash directory of the facet enclosing `dir`,
+    /// back into the directory at `dir` under its original name
+    ///
+    /// The thread's current label is tainted for each path component, meaning path
+    /// traversal never fails when the path exists, but may increase the current
+    /// label arbitrarily high.
+    pub fn restore<P: Into<Path>>(&self, dir: P, trashed: &String) -> Result<bool, FsError> {
+        let dir: Path = dir.into();
+        let trash = self.trash_dir(&dir).ok_or(FsError::BadPath)?;
+        let DirEntry::Directory(dir_obj) = self.read_path(dir)? else {
+            return Err(FsError::NotADir);
+        };
+        let Some((_, name)) = split_trashed(trashed) else {
+            return Ok(false);
+        };
+        let Some(entry) = trash.list(self).get(trashed).cloned() else {
+            return Ok(false);
+        };
+        if !dir_obj.link(name.to_string(), entry, self)? {
+            return Err(FsError::NameExists);
+        }
+        trash.unlink(trashed, self)
+    }
+
+    /// Unlinks the expired entries from the trash directories of all facets and returns how
+    /// many it unlinked. Bypasses labels, so only the garbage collector should call it. Fails
+    /// with `FsError::Contended` if a trash directory kept changing under it, in which case the
+    /// next purge picks up what this one missed.
+    pub fn purge_trash(&self) -> Result<usize, FsError> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // the store may not be read from while it is scanned
+        let mut facets = Vec::new();
+        self.scan_objects(ObjectKind::FacetedDirectory, |_, value| {
+            if let Ok(faceted) = serde_json::from_slice::<FacetedDirectory>(value) {
+                facets.extend(faceted.facets.into_iter().map(|(_, facet)| facet));
+            }
+        });
+        facets
+            .iter()
+            .filter_map(|facet| match facet.get(self)?.data.entry(TRASH_DIR, self) {
+                Some(DirEntry::Directory(trash)) => Some(trash.purge_expired(now, self)),
+                _ => None,
+            })
+            .sum()
+    }
+
 
     /// Lists the contents of a faceted directory up to a clearance label
     ///
@@ -551,6 +1731,20 @@ impl<S: BackingStore> FS<S> {
         }
     }
 
+    /// Like `list_faceted`, but returns only the facets `pattern` matches. Filtering happens
+    /// here rather than in the caller, so the caller never holds the other facets' labels.
+    pub fn query_faceted<P: Into<Path>>(
+        &self,
+        path: P,
+        clearance: &Buckle,
+        pattern: &FacetPattern,
+    ) -> Result<BTreeMap<Buckle, ObjectRef<Labeled<Directory>>>, FsError> {
+        match self.read_path(path)? {
+            DirEntry::FacetedDirectory(dir_obj) => Ok(dir_obj.query(self, clearance, pattern)),
+            _ => Err(FsError::NotADir),
+        }
+    }
+
     /// Reads and returns the data of the file at `path`
     ///
     /// The thread's current label is tainted for each path component, meaning path
@@ -565,6 +1759,18 @@ impl<S: BackingStore> FS<S> {
         }
     }
 
+    /// Returns the entity tag of the file at `path`, which changes whenever the file's content
+    /// does, or an error if the file doesn't exist.
+    ///
+    /// The thread's current label is tainted for each path component and with the file's
+    /// label, as when reading the file.
+    pub fn file_etag<P: Into<Path>>(&self, path: P) -> Result<String, FsError> {
+        match self.read_path(path)? {
+            DirEntry::File(file_obj) => Ok(file_obj.etag(self)),
+            _ => Err(FsError::NotAFile),
+        }
+    }
+
     /// Writes `data` to the file at `path`, or returns an error if the file
     /// doesn't exist or the current thread's label and privilege aren't
     /// sufficient for writing to it.
@@ -581,16 +1787,101 @@ impl<S: BackingStore> FS<S> {
         }
     }
 
+    /// Adds `delta` to the counter in the file at `path` and returns the new value, or returns
+    /// an error if the file doesn't exist, doesn't hold a counter or the current thread's label
+    /// and privilege aren't sufficient for writing to it.
+    ///
+    /// The thread's current label is tainted for each path component, meaning path
+    /// traversal never fails when the path exists, but may increase the current
+    /// label arbitrarily high.
+    pub fn increment_counter<P: Into<Path>>(&self, path: P, delta: i64) -> Result<i64, FsError> {
+        match self.read_path(path)? {
+            DirEntry::File(file_obj) => file_obj.increment(delta, self),
+            _ => Err(FsError::NotAFile),
+        }
+    }
+
+    /// Takes the lock in the file at `path` for `ttl` milliseconds, see
+    /// `ObjectRef::<Labeled<File>>::lock`.
+    ///
+    /// The thread's current label is tainted for each path component, meaning path
+    /// traversal never fails when the path exists, but may increase the current
+    /// label arbitrarily high.
+    pub fn lock<P: Into<Path>>(
+        &self,
+        path: P,
+        ttl: u64,
+        token: Option<u64>,
+    ) -> Result<Option<u64>, FsError> {
+        match self.read_path(path)? {
+            DirEntry::File(file_obj) => file_obj.lock(ttl, token, self),
+            _ => Err(FsError::NotAFile),
+        }
+    }
+
+    /// Frees the lock in the file at `path` if it's held with the lease `token`
+    ///
+    /// The thread's current label is tainted for each path component, meaning path
+    /// traversal never fails when the path exists, but may increase the current
+    /// label arbitrarily high.
+    pub fn unlock<P: Into<Path>>(&self, path: P, token: u64) -> Result<bool, FsError> {
+        match self.read_path(path)? {
+            DirEntry::File(file_obj) => file_obj.unlock(token, self),
+            _ => Err(FsError::NotAFile),
+        }
+    }
+
+    /// Sets up a barrier in the file at `path`, see `ObjectRef::<Labeled<File>>::init_barrier`.
+    ///
+    /// The thread's current label is tainted for each path component, meaning path
+    /// traversal never fails when the path exists, but may increase the current
+    /// label arbitrarily high.
+    pub fn init_barrier<P: Into<Path>>(
+        &self,
+        path: P,
+        expected: u64,
+        gate: String,
+    ) -> Result<(), FsError> {
+        match self.read_path(path)? {
+            DirEntry::File(file_obj) => file_obj.init_barrier(expected, gate, self),
+            _ => Err(FsError::NotAFile),
+        }
+    }
+
+    /// Adds `result` to the barrier in the file at `path` and returns the barrier after the
+    /// arrival
+    ///
+    /// The thread's current label is tainted for each path component, meaning path
+    /// traversal never fails when the path exists, but may increase the current
+    /// label arbitrarily high.
+    pub fn arrive<P: Into<Path>>(&self, path: P, result: String) -> Result<Barrier, FsError> {
+        match self.read_path(path)? {
+            DirEntry::File(file_obj) => file_obj.arrive(result, self),
+            _ => Err(FsError::NotAFile),
+        }
+    }
+
     /// Creates an empty file object
     pub fn create_file(&self, label: Buckle) -> DirEntry {
         let new_file = ObjectRef::create(label, &self.0);
         DirEntry::File(new_file)
     }
 
+    /// Creates an empty log object
+    pub fn create_log(&self, label: Buckle) -> DirEntry {
+        let new_log = ObjectRef::create(label, &self.0);
+        DirEntry::Log(new_log)
+    }
+
     /// Creates a labeled Blob object
-    pub fn create_blob(&self, label: Buckle, blob_name: String) -> Result<DirEntry, FsError> {
+    pub fn create_blob(
+        &self,
+        label: Buckle,
+        blob_name: String,
+        meta: BlobMeta,
+    ) -> Result<DirEntry, FsError> {
         let new_blob: ObjectRef<Labeled<Blob>> = ObjectRef::create(label, &self.0);
-        new_blob.replace(blob_name, self)?;
+        new_blob.replace(blob_name, meta, self)?;
         Ok(DirEntry::Blob(new_blob))
     }
 
@@ -623,7 +1914,10 @@ impl<S: BackingStore> FS<S> {
         })?;
         let labeled = Labeled {
             label,
-            data: Gate::Direct(direct_gate),
+            data: Gate::Direct(Box::new(direct_gate)),
+            frozen: false,
+            digest: None,
+            blob_meta: None,
         };
         let new_gate = ObjectRef::set_new_id(&labeled, &self.0);
         Ok(DirEntry::Gate(new_gate))
@@ -645,6 +1939,9 @@ impl<S: BackingStore> FS<S> {
         let labeled = Labeled {
             label,
             data: Gate::Redirect(redirect_gate),
+            frozen: false,
+            digest: None,
+            blob_meta: None,
         };
         let new_gate = ObjectRef::set_new_id(&labeled, &self.0);
         Ok(DirEntry::Gate(new_gate))
@@ -665,6 +1962,9 @@ impl<S: BackingStore> FS<S> {
         let labeled = Labeled {
             label,
             data: service,
+            frozen: false,
+            digest: None,
+            blob_meta: None,
         };
         let new_service = ObjectRef::set_new_id(&labeled, &self.0);
         Ok(DirEntry::Service(new_service))
@@ -678,7 +1978,7 @@ impl<S: BackingStore> FS<S> {
     pub fn link<P: Into<Path>>(&self, base_dir: P, name: String, direntry: DirEntry) -> Result<(), FsError> {
         match self.read_path(base_dir.into())? {
             DirEntry::Directory(dir_obj) => {
-                dir_obj.link(name, direntry, &self).map_err(Into::into).and_then(|success| {
+                dir_obj.link(name, direntry, &self).and_then(|success| {
                     if success {
                         Ok(())
                     } else {
@@ -690,6 +1990,38 @@ impl<S: BackingStore> FS<S> {
         }
     }
 
+    /// Appends `data` to the log at `path` and returns its index, or returns an error if the
+    /// log doesn't exist or the current thread's label and privilege aren't sufficient for
+    /// writing to it.
+    ///
+    /// The thread's current label is tainted for each path component, meaning path
+    /// traversal never fails when the path exists, but may increase the current
+    /// label arbitrarily high.
+    pub fn append_log<P: Into<Path>>(&self, path: P, data: Vec<u8>) -> Result<u64, FsError> {
+        match self.read_path(path)? {
+            DirEntry::Log(log_obj) => log_obj.append(data, self),
+            _ => Err(FsError::NotALog),
+        }
+    }
+
+    /// Returns the entries of the log at `path` with indices in `from..to`, see
+    /// `ObjectRef::<Labeled<Log>>::read_range`.
+    ///
+    /// The thread's current label is tainted for each path component, meaning path
+    /// traversal never fails when the path exists, but may increase the current
+    /// label arbitrarily high.
+    pub fn read_log<P: Into<Path>>(
+        &self,
+        path: P,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<LogEntry>, FsError> {
+        match self.read_path(path)? {
+            DirEntry::Log(log_obj) => log_obj.read_range(from, to, self),
+            _ => Err(FsError::NotALog),
+        }
+    }
+
     pub fn open_blob<P: Into<Path>>(&self, path: P) -> Result<Blob, FsError> {
         match self.read_path(path)? {
             DirEntry::Blob(blob_obj) => {
@@ -699,15 +2031,29 @@ impl<S: BackingStore> FS<S> {
         }
     }
 
-    pub fn replace_blob<P: Into<Path>>(&self, path: P, new_blob: Blob) -> Result<(), FsError> {
+    pub fn replace_blob<P: Into<Path>>(
+        &self,
+        path: P,
+        new_blob: Blob,
+        meta: BlobMeta,
+    ) -> Result<(), FsError> {
         match self.read_path(path)? {
             DirEntry::Blob(blob_obj) => {
-                blob_obj.replace(new_blob, self).map_err(Into::into)
+                blob_obj.replace(new_blob, meta, self).map_err(Into::into)
             },
             _ => Err(FsError::NotABlob),
         }
     }
 
+    /// Calls `f` with the id and the stored value of every object of `kind`. Bypasses labels.
+    pub fn scan_objects(&self, kind: ObjectKind, mut f: impl FnMut(u64, &[u8])) {
+        self.0.scan(&[kind as u8], &mut |key, value| {
+            use std::convert::TryInto;
+            if let Ok(uid) = key[1..].try_into() {
+                f(u64::from_be_bytes(uid), value)
+            }
+        })
+    }
 }
 
 // Backing store trait
@@ -719,6 +2065,8 @@ pub trait BackingStore {
     fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8])
         -> Result<(), Option<Vec<u8>>>;
     fn del(&self, key: &[u8]);
+    /// Calls `f` with every key starting with `prefix` and its value, in key order
+    fn scan(&self, prefix: &[u8], f: &mut dyn FnMut(&[u8], &[u8]));
 }
 
 impl<B: BackingStore> BackingStore for &B {
@@ -742,6 +2090,9 @@ impl<B: BackingStore> BackingStore for &B {
     fn del(&self, key: &[u8]) {
         (*self).del(key)
     }
+    fn scan(&self, prefix: &[u8], f: &mut dyn FnMut(&[u8], &[u8])) {
+        (*self).scan(prefix, f)
+    }
 }
 
 impl<B: BackingStore + ?Sized> BackingStore for Box<B> {
@@ -765,4 +2116,280 @@ impl<B: BackingStore + ?Sized> BackingStore for Box<B> {
     fn del(&self, key: &[u8]) {
         self.as_ref().del(key)
     }
+    fn scan(&self, prefix: &[u8], f: &mut dyn FnMut(&[u8], &[u8])) {
+        self.as_ref().scan(prefix, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryStore;
+    use super::*;
+
+    #[test]
+    fn test_object_kind_of_key() {
+        for kind in ObjectKind::ALL.iter() {
+            assert_eq!(ObjectKind::of_key(&kind.key(7)), Some(*kind));
+        }
+        assert_eq!(ObjectKind::of_key(&7u64.to_be_bytes()), None);
+        assert_eq!(ObjectKind::of_key(b"xuid00007"), None);
+        assert_eq!(ObjectKind::of_key(b"MIGRATING"), None);
+    }
+
+    #[test]
+    fn test_shard_of_shards() {
+        let fs = FS::new(MemoryStore::default());
+        utils::clear_label();
+        let dir = ObjectRef::<Labeled<Directory>>::create(Buckle::public(), &fs.0);
+        let file = ObjectRef::<Labeled<File>>::create(Buckle::public(), &fs.0);
+        // names that all land in the first shard, enough to pass the threshold there
+        let names: Vec<String> = (0..)
+            .map(|i| format!("entry{}", i))
+            .filter(|name| shard_index(name, SHARD_COUNT, 0) == 0)
+            .take(SHARD_THRESHOLD * 2)
+            .collect();
+        let others: Vec<String> = (0..SHARD_THRESHOLD).map(|i| format!("other{}", i)).collect();
+        for name in others.iter().chain(names.iter()) {
+            assert!(dir.link(name.clone(), DirEntry::File(file), &fs).unwrap());
+        }
+
+        let top = dir.get(&fs).unwrap().data;
+        assert_eq!(top.shards.len(), SHARD_COUNT);
+        let first = top.shards[0].get(&fs).unwrap().data;
+        assert_eq!(first.depth, 1);
+        assert_eq!(first.shards.len(), SHARD_COUNT);
+        let spread = first
+            .shards
+            .iter()
+            .filter(|s| !s.get(&fs).unwrap().data.entries.is_empty())
+            .count();
+        assert!(spread > SHARD_COUNT / 2);
+
+        assert_eq!(dir.list(&fs).len(), names.len() + others.len());
+        for name in others.iter().chain(names.iter()) {
+            assert!(dir.lookup(name, &fs).is_some());
+        }
+        assert!(dir.unlink(&names[0], &fs).unwrap());
+        assert!(dir.lookup(&names[0], &fs).is_none());
+    }
+
+    // slow in debug builds, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn test_nested_shards() {
+        let fs = FS::new(MemoryStore::default());
+        utils::clear_label();
+        let dir = ObjectRef::<Labeled<Directory>>::create(Buckle::public(), &fs.0);
+        let file = ObjectRef::<Labeled<File>>::create(Buckle::public(), &fs.0);
+        // enough for some shards to pass the threshold and be sharded in turn
+        let n = SHARD_COUNT * SHARD_THRESHOLD + 4096;
+        for i in 0..n {
+            assert!(dir.link(format!("entry{}", i), DirEntry::File(file), &fs).unwrap());
+        }
+        let top = dir.get(&fs).unwrap().data;
+        assert_eq!(top.shards.len(), SHARD_COUNT);
+        let nested = top.shards.iter().filter(|s| !s.get(&fs).unwrap().data.shards.is_empty());
+        assert!(nested.count() > 0);
+        // at most the directory, its shards and theirs, rather than new shards on every link
+        assert!(fs.0.len() <= 2 + SHARD_COUNT + SHARD_COUNT * SHARD_COUNT);
+
+        assert_eq!(dir.list(&fs).len(), n);
+        for i in (0..n).step_by(7) {
+            assert!(dir.lookup(&format!("entry{}", i), &fs).is_some());
+        }
+        assert!(dir.unlink(&"entry0".to_string(), &fs).unwrap());
+        assert!(dir.lookup("entry0", &fs).is_none());
+        assert_eq!(dir.list(&fs).len(), n - 1);
+    }
+
+    #[test]
+    fn test_remove_recursive() {
+        let fs = FS::new(MemoryStore::default());
+        fs.initialize();
+        utils::clear_label();
+        utils::set_my_privilge(Component::dc_false());
+        let dir = || ObjectRef::<Labeled<Directory>>::create(Buckle::public(), &fs.0);
+        let file = || DirEntry::File(ObjectRef::<Labeled<File>>::create(Buckle::public(), &fs.0));
+        let (base, top, sub, subsub) = (dir(), dir(), dir(), dir());
+        assert!(ROOT_REF.link("base".to_string(), DirEntry::Directory(base), &fs).unwrap());
+        for (parent, name, entry) in [
+            (top, "a", file()),
+            (top, "b", file()),
+            (top, "s", DirEntry::Directory(sub)),
+            (sub, "c", file()),
+            (sub, "s", DirEntry::Directory(subsub)),
+            (subsub, "d", file()),
+        ]
+        .iter()
+        {
+            assert!(parent.link(name.to_string(), entry.clone(), &fs).unwrap());
+        }
+        assert!(base.link("top".to_string(), DirEntry::Directory(top), &fs).unwrap());
+
+        let path = path::Path::parse(":base:top").unwrap();
+        let mut cursor = None;
+        let mut unlinked = 0;
+        loop {
+            let removal = fs.remove_recursive(path.clone(), cursor.as_deref(), 2).unwrap();
+            assert!(removal.error.is_none());
+            assert!(removal.unlinked <= 2);
+            unlinked += removal.unlinked;
+            cursor = removal.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(unlinked, 7);
+        assert!(base.list(&fs).is_empty());
+
+        // too deep a tree stops with an error instead of overflowing the stack
+        let top = dir();
+        let mut parent = top;
+        for _ in 0..REMOVE_MAX_DEPTH {
+            let sub = dir();
+            assert!(parent.link("s".to_string(), DirEntry::Directory(sub), &fs).unwrap());
+            parent = sub;
+        }
+        assert!(base.link("deep".to_string(), DirEntry::Directory(top), &fs).unwrap());
+        let deep = path::Path::parse(":base:deep").unwrap();
+        let removal = fs.remove_recursive(deep, None, 10).unwrap();
+        assert_eq!(removal.unlinked, 0);
+        assert!(matches!(removal.error, Some(FsError::TooLarge)));
+        assert_eq!(removal.cursor.map(|c| c.len()), Some(REMOVE_MAX_DEPTH - 1));
+    }
+
+    #[test]
+    fn test_barrier() {
+        let fs = FS::new(MemoryStore::default());
+        utils::clear_label();
+        let alice = Buckle::parse("T,alice").unwrap();
+        utils::set_my_privilge(alice.integrity.clone());
+        let file = ObjectRef::<Labeled<File>>::create(alice.clone(), &fs.0);
+        let gate = "gate".to_string();
+        assert!(matches!(file.init_barrier(0, gate.clone(), &fs), Err(FsError::EmptyBarrier)));
+        file.init_barrier(2, gate, &fs).unwrap();
+
+        assert!(!file.arrive("a".to_string(), &fs).unwrap().is_complete());
+        let barrier = file.arrive("b".to_string(), &fs).unwrap();
+        assert!(barrier.is_complete());
+        assert_eq!(barrier.firing_privilege(&alice), alice.integrity);
+        // the privilege is no more than what writers of the file vouch for
+        assert_eq!(barrier.firing_privilege(&Buckle::public()), Component::dc_true());
+        // claimed by the arrival that completed it
+        assert!(matches!(file.arrive("c".to_string(), &fs), Err(FsError::BarrierComplete)));
+
+        // the gate wasn't invoked, so another arrival may try once the claim expires
+        file.update(&fs, |data| {
+            let mut barrier: Barrier = serde_json::from_slice(data).unwrap();
+            barrier.claimed -= BARRIER_CLAIM_MS;
+            *data = serde_json::to_vec(&barrier).unwrap();
+            Ok(())
+        })
+        .unwrap();
+        let barrier = file.arrive("c".to_string(), &fs).unwrap();
+        assert!(barrier.is_complete());
+        assert_eq!(barrier.results, vec!["a".to_string(), "b".to_string()]);
+        file.barrier_fired(&fs).unwrap();
+        assert!(matches!(file.arrive("c".to_string(), &fs), Err(FsError::BarrierComplete)));
+    }
+
+    #[test]
+    fn test_trash() {
+        let fs = FS::new(MemoryStore::default());
+        fs.initialize();
+        utils::clear_label();
+        utils::set_my_privilge(Component::dc_false());
+        // a facet outside of home
+        utils::create_faceted(&fs, path::Path::root(), "proj".to_string()).unwrap();
+        let facet = path::Path::parse(":proj:<T,T>").unwrap();
+        fs.link(facet.clone(), TRASH_DIR.to_string(), fs.create_directory(Buckle::public()))
+            .unwrap();
+        let file = fs.create_file(Buckle::public());
+        fs.link(facet.clone(), "a".to_string(), file.clone()).unwrap();
+
+        assert!(fs.trash(facet.clone(), &"a".to_string(), u64::MAX).unwrap());
+        let trash = path::Path::parse(":proj:<T,T>:.trash").unwrap();
+        let trashed = fs.list_dir(trash).unwrap().into_keys().next().unwrap();
+        let (expires, name) = split_trashed(&trashed).unwrap();
+        assert_eq!(name, "a");
+        assert!(expires > MAX_TRASH_RETENTION && expires < u64::MAX);
+        // the trashed entry can be named by a path
+        let path = path::Path::parse(&format!(":proj:<T,T>:.trash:{}", trashed)).unwrap();
+        assert!(fs.read_path(path).is_ok());
+        assert!(fs.restore(facet.clone(), &trashed).unwrap());
+        assert!(fs.list_dir(facet.clone()).unwrap().contains_key("a"));
+
+        assert!(fs.trash(facet.clone(), &"a".to_string(), 0).unwrap());
+        // named as before ':' was replaced
+        fs.link(path::Path::parse(":proj:<T,T>:.trash").unwrap(), "5:b".to_string(), file)
+            .unwrap();
+        assert_eq!(fs.purge_trash().unwrap(), 2);
+        assert_eq!(fs.purge_trash().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let fs = FS::new(MemoryStore::default());
+        fs.initialize();
+        utils::clear_label();
+        utils::set_my_privilge(Component::dc_false());
+        let dir = ObjectRef::<Labeled<Directory>>::create(Buckle::public(), &fs.0);
+        let file = ObjectRef::<Labeled<File>>::create(Buckle::public(), &fs.0);
+        assert!(dir.link("file".to_string(), DirEntry::File(file), &fs).unwrap());
+        assert!(ROOT_REF.link("dir".to_string(), DirEntry::Directory(dir), &fs).unwrap());
+        let path = path::Path::parse(":dir").unwrap();
+
+        let Ok(DirEntry::Directory(snapshot)) = fs.snapshot(path.clone()) else {
+            panic!("not a directory");
+        };
+        assert_ne!(snapshot.uid, dir.uid);
+        let Some(DirEntry::File(copy)) = snapshot.lookup("file", &fs) else {
+            panic!("not a file");
+        };
+        assert_ne!(copy.uid, file.uid);
+        assert!(copy.get(&fs).unwrap().frozen);
+
+        // a snapshot of a snapshot shares its objects
+        assert!(ROOT_REF.link("snap".to_string(), DirEntry::Directory(snapshot), &fs).unwrap());
+        let before = fs.0.len();
+        let Ok(DirEntry::Directory(again)) = fs.snapshot(path::Path::parse(":snap").unwrap()) else {
+            panic!("not a directory");
+        };
+        assert_eq!(again.uid, snapshot.uid);
+        assert_eq!(fs.0.len(), before);
+    }
+
+    #[test]
+    fn test_snapshot_gate_needs_privilege() {
+        let fs = FS::new(MemoryStore::default());
+        fs.initialize();
+        utils::clear_label();
+        utils::set_my_privilge(Component::dc_false());
+        let alice = Buckle::parse("alice,alice").unwrap().integrity;
+        let gate = DirectGate {
+            privilege: alice.clone(),
+            invoker_integrity_clearance: Component::dc_true(),
+            declassify: Component::dc_true(),
+            function: Function::default(),
+            metadata: None,
+        };
+        let gate = fs.create_direct_gate(Buckle::public(), gate).unwrap();
+        let dir = ObjectRef::<Labeled<Directory>>::create(Buckle::public(), &fs.0);
+        assert!(dir.link("gate".to_string(), gate, &fs).unwrap());
+        assert!(ROOT_REF.link("dir".to_string(), DirEntry::Directory(dir), &fs).unwrap());
+        let path = path::Path::parse(":dir").unwrap();
+        utils::set_my_privilge(alice);
+        assert!(fs.snapshot(path.clone()).is_ok());
+
+        utils::clear_label();
+        utils::set_my_privilge(Component::dc_true());
+        let before = fs.0.len();
+        match fs.snapshot(path) {
+            Err(FsError::PrivilegeError(PrivilegeError::CannotDelegate)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        // the directory copied before the gate failed is gone
+        assert_eq!(fs.0.len(), before);
+    }
+
 }
@@ -0,0 +1,150 @@
+//! Migration of stores to kind-prefixed object keys
+//!
+//! Objects used to be keyed by their 8-byte id alone, so scanning the objects of one kind meant
+//! reading all of them. Their keys now start with their `ObjectKind`, see `ObjectKind::key`.
+//! Ids stay the same, so references between objects need no rewriting, only keys move. As a
+//! legacy key doesn't tell what it holds, `FS::partition_keys` learns the kinds by walking the
+//! file system from its root. Unreachable objects are garbage and keep their legacy keys.
+//!
+//! Objects under legacy keys are invisible to everything else, so services refuse to start on
+//! a store that still has them, see `FS::assert_partitioned`.
+use std::collections::HashSet;
+
+use serde::de::DeserializeOwned;
+
+use super::{
+    audit, BackingStore, DirEntry, Directory, FacetedDirectory, Gate, Labeled, Log, ObjectKind,
+    FS, ROOT_REF,
+};
+
+// Stored while a migration runs, so that an interrupted one is still detected once the root
+// has moved. Object keys are 8 or 9 bytes long, so it can't clash with one.
+const MIGRATING: &[u8] = b"\0faasten-partitioning-keys\0";
+
+/// Why services refuse to start on a store with legacy keys
+pub const LEGACY_STORE: &str =
+    "the store has objects under legacy keys, run `admin_fstools partition-keys` first";
+
+impl<S: BackingStore> FS<S> {
+    /// Whether the store predates kind-prefixed keys or its migration was interrupted
+    pub fn has_legacy_keys(&self) -> bool {
+        self.0.get(&ROOT_REF.uid.to_be_bytes()).is_some() || self.0.get(MIGRATING).is_some()
+    }
+
+    /// Panics if the store has objects under legacy keys. Services call it at startup.
+    pub fn assert_partitioned(&self) {
+        assert!(!self.has_legacy_keys(), "{}", LEGACY_STORE);
+    }
+
+    /// Moves every object reachable from the root, and the audit log, from its legacy key to its
+    /// kind-prefixed one, returning how many moved. Objects already moved are walked but left
+    /// alone, so an interrupted migration can run again. Run it with all services using the
+    /// store stopped.
+    pub fn partition_keys(&self) -> usize {
+        self.0.put(MIGRATING, &[]);
+        let moved = migrate(&self.0);
+        self.0.del(MIGRATING);
+        moved
+    }
+}
+
+fn migrate<B: BackingStore>(store: &B) -> usize {
+    let mut pending = vec![
+        (ObjectKind::Directory, ROOT_REF.uid),
+        (ObjectKind::Log, audit::AUDIT_LOG_REF.uid),
+    ];
+    let mut visited = HashSet::new();
+    let mut moved = 0;
+    while let Some((kind, uid)) = pending.pop() {
+        if !visited.insert((kind, uid)) {
+            continue;
+        }
+        let legacy = uid.to_be_bytes();
+        let value = match store.get(&legacy) {
+            Some(value) => {
+                store.put(&kind.key(uid), &value);
+                store.del(&legacy);
+                moved += 1;
+                value
+            }
+            None => match store.get(&kind.key(uid)) {
+                Some(value) => value,
+                None => continue,
+            },
+        };
+        references(kind, &value, &mut pending);
+    }
+    moved
+}
+
+// Pushes the objects the object of `kind` stored as `value` refers to
+fn references(kind: ObjectKind, value: &[u8], pending: &mut Vec<(ObjectKind, u64)>) {
+    fn parse<T: DeserializeOwned>(value: &[u8]) -> Option<T> {
+        serde_json::from_slice(value).ok()
+    }
+    match kind {
+        ObjectKind::Directory => {
+            if let Some(dir) = parse::<Labeled<Directory>>(value) {
+                pending.extend(dir.data.entries.values().map(entry));
+                pending.extend(dir.data.shards.iter().map(|s| (ObjectKind::Directory, s.uid)));
+            }
+        }
+        ObjectKind::FacetedDirectory => {
+            if let Some(faceted) = parse::<FacetedDirectory>(value) {
+                pending.extend(faceted.facets.iter().map(|(_, f)| (ObjectKind::Directory, f.uid)));
+            }
+        }
+        ObjectKind::Gate => {
+            if let Some(Gate::Redirect(redirect)) = parse::<Labeled<Gate>>(value).map(|g| g.data) {
+                pending.push((ObjectKind::Gate, redirect.gate.uid));
+            }
+        }
+        ObjectKind::Log => {
+            if let Some(log) = parse::<Labeled<Log>>(value) {
+                pending.extend(log.data.chunks.iter().map(|c| (ObjectKind::LogChunk, c.uid)));
+            }
+        }
+        ObjectKind::File | ObjectKind::Service | ObjectKind::Blob | ObjectKind::LogChunk => {}
+    }
+}
+
+fn entry(entry: &DirEntry) -> (ObjectKind, u64) {
+    match entry {
+        DirEntry::Directory(o) => (ObjectKind::Directory, o.uid),
+        DirEntry::File(o) => (ObjectKind::File, o.uid),
+        DirEntry::FacetedDirectory(o) => (ObjectKind::FacetedDirectory, o.uid),
+        DirEntry::Gate(o) => (ObjectKind::Gate, o.uid),
+        DirEntry::Service(o) => (ObjectKind::Service, o.uid),
+        DirEntry::Blob(o) => (ObjectKind::Blob, o.uid),
+        DirEntry::Log(o) => (ObjectKind::Log, o.uid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memory::MemoryStore;
+
+    #[test]
+    fn test_partition_keys() {
+        let fs = FS::new(MemoryStore::default());
+        fs.initialize();
+        assert!(!fs.has_legacy_keys());
+
+        // as stores created before kind-prefixed keys were
+        let root = fs.0.get(&ROOT_REF.key()).unwrap();
+        fs.0.del(&ROOT_REF.key());
+        fs.0.put(&ROOT_REF.uid.to_be_bytes(), &root);
+        assert!(fs.has_legacy_keys());
+
+        // interrupted after moving the root
+        fs.0.put(MIGRATING, &[]);
+        migrate(&fs.0);
+        assert!(fs.has_legacy_keys());
+
+        assert_eq!(fs.partition_keys(), 0);
+        assert!(!fs.has_legacy_keys());
+        fs.assert_partitioned();
+        assert_eq!(fs.0.get(&ROOT_REF.key()), Some(root));
+    }
+}
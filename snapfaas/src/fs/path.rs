@@ -87,6 +87,14 @@ impl Path {
     pub fn push_dscrp(&mut self, s: String) {
         self.components.push_back(PathComponent::Dscrp(s));
     }
+
+    /// The path up to the last facet in it, i.e., the facet enclosing the object at the path
+    pub fn facet_root(&self) -> Option<Self> {
+        let end = self.components.iter().rposition(|c| matches!(c, PathComponent::Facet(_)))?;
+        let mut res = self.clone();
+        res.components.truncate(end + 1);
+        Some(res)
+    }
 }
 
 impl IntoIterator for Path {
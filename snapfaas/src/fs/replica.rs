@@ -0,0 +1,296 @@
+//! Asynchronous replication of a backing store
+//!
+//! `Replicating` serves everything from a primary store and mirrors writes to a secondary one
+//! in the background, e.g. to keep an off-box TiKV copy of a single-node lmdb deployment. It
+//! replicates keys rather than operations: for each key written, a background thread copies the
+//! key's latest value from the primary, so a burst of writes to one object costs the secondary
+//! a single write.
+//!
+//! Keys written but not yet replicated are appended to a journal file, which is truncated
+//! whenever the replica catches up. A key is journaled and synced to disk before it is written
+//! to the primary, so no write the primary kept is missing from the journal after a crash. A
+//! restarted process replays the journal, so the secondary catches up after crashes as well as
+//! after outages. Only writes made through the wrapper are replicated, so every process writing
+//! to the primary must wrap it, each with its own journal. A secondary that missed writes
+//! otherwise, e.g. because the journal was lost, is brought up to date with `resync`, which
+//! copies every key of the primary and removes those the primary doesn't have.
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use super::BackingStore;
+
+// How long the replicator waits before retrying after the secondary failed
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+// Journal line requesting a full resync
+const RESYNC: &str = "*";
+
+#[derive(Default)]
+struct State {
+    keys: BTreeSet<Vec<u8>>,
+    // writes to the primary under way, whose keys are journaled but not in `keys` yet
+    writing: usize,
+    resync: bool,
+}
+
+struct Pending {
+    state: Mutex<State>,
+    cvar: Condvar,
+    // locked only while holding `state`
+    journal: Mutex<File>,
+}
+
+// Marks the key of a write to the primary as pending when dropped, once the write is done or
+// failed
+struct Writing<'a> {
+    pending: &'a Pending,
+    key: &'a [u8],
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        let mut state = self.pending.state.lock().unwrap();
+        state.writing -= 1;
+        state.keys.insert(self.key.to_vec());
+        self.pending.cvar.notify_one();
+    }
+}
+
+impl Pending {
+    // Journals `key` durably before it is written to the primary
+    fn write<'a>(&'a self, key: &'a [u8]) -> Writing<'a> {
+        let mut state = self.state.lock().unwrap();
+        // a pending key is journaled already, and it stays there until it is copied
+        if !state.keys.contains(key) {
+            self.journal_line(&hex::encode(key));
+        }
+        state.writing += 1;
+        Writing { pending: self, key }
+    }
+
+    fn journal_line(&self, line: &str) {
+        let mut journal = self.journal.lock().unwrap();
+        if let Err(e) = writeln!(journal, "{}", line).and_then(|_| journal.sync_data()) {
+            error!("failed to journal replicated key: {:?}", e);
+        }
+    }
+
+    fn resync(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.journal_line(RESYNC);
+        state.resync = true;
+        self.cvar.notify_one();
+    }
+
+    // Waits for keys to copy, and returns them and whether to resync everything
+    fn take(&self) -> (BTreeSet<Vec<u8>>, bool) {
+        let mut state = self.state.lock().unwrap();
+        while state.keys.is_empty() && !state.resync {
+            state = self.cvar.wait(state).unwrap();
+        }
+        (std::mem::take(&mut state.keys), std::mem::take(&mut state.resync))
+    }
+
+    // Truncates the journal if nothing is pending
+    fn checkpoint(&self) {
+        let state = self.state.lock().unwrap();
+        if state.keys.is_empty() && state.writing == 0 && !state.resync {
+            if let Err(e) = self.journal.lock().unwrap().set_len(0) {
+                error!("failed to truncate the replication journal: {:?}", e);
+            }
+        }
+    }
+}
+
+pub struct Replicating<P> {
+    primary: Arc<P>,
+    pending: Arc<Pending>,
+}
+
+impl<P> Clone for Replicating<P> {
+    fn clone(&self) -> Self {
+        Replicating {
+            primary: self.primary.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<P: BackingStore + Send + Sync + 'static> Replicating<P> {
+    /// Wraps `primary` and starts replicating to `secondary`, first the keys left in the
+    /// journal at `journal` by an earlier run
+    pub fn new<S: BackingStore + Send + 'static>(
+        primary: P,
+        secondary: S,
+        journal: &Path,
+    ) -> std::io::Result<Self> {
+        let mut state = State::default();
+        if journal.exists() {
+            for line in BufReader::new(File::open(journal)?).lines() {
+                let line = line?;
+                if line == RESYNC {
+                    state.resync = true;
+                    continue;
+                }
+                match hex::decode(line) {
+                    Ok(key) => {
+                        state.keys.insert(key);
+                    }
+                    Err(e) => warn!("skipping malformed replication journal line: {:?}", e),
+                }
+            }
+        }
+        let journal = OpenOptions::new().create(true).append(true).open(journal)?;
+        let pending = Arc::new(Pending {
+            state: Mutex::new(state),
+            cvar: Condvar::new(),
+            journal: Mutex::new(journal),
+        });
+        let primary = Arc::new(primary);
+        let replicator = (primary.clone(), pending.clone());
+        std::thread::spawn(move || replicate(&*replicator.0, secondary, &replicator.1));
+        Ok(Replicating { primary, pending })
+    }
+
+    /// Number of keys written but not yet replicated
+    pub fn lag(&self) -> usize {
+        self.pending.state.lock().unwrap().keys.len()
+    }
+
+    /// Copies every key of the primary to the secondary and removes the keys the primary
+    /// doesn't have from the secondary, in the background. Survives restarts like pending keys.
+    pub fn resync(&self) {
+        self.pending.resync();
+    }
+}
+
+fn replicate<P: BackingStore, S: BackingStore>(primary: &P, secondary: S, pending: &Pending) {
+    loop {
+        let (keys, resync) = pending.take();
+        let keys: Vec<Vec<u8>> = keys.into_iter().collect();
+        let mut copied = 0;
+        // backing stores panic when they fail
+        let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            if resync {
+                copy_all(primary, &secondary);
+            }
+            for key in keys.iter() {
+                match primary.get(key) {
+                    Some(value) => secondary.put(key, &value),
+                    None => secondary.del(key),
+                }
+                copied += 1;
+            }
+        }));
+        if res.is_err() {
+            warn!("replication failed, retrying in {:?}", RETRY_DELAY);
+            let mut state = pending.state.lock().unwrap();
+            state.keys.extend(keys.into_iter().skip(copied));
+            state.resync |= resync;
+            drop(state);
+            std::thread::sleep(RETRY_DELAY);
+        }
+        pending.checkpoint();
+    }
+}
+
+// Makes the secondary a copy of the primary
+fn copy_all<P: BackingStore, S: BackingStore>(primary: &P, secondary: &S) {
+    let mut copied = 0;
+    primary.scan(&[], &mut |key, value| {
+        secondary.put(key, value);
+        copied += 1;
+    });
+    let mut stale = Vec::new();
+    secondary.scan(&[], &mut |key, _| {
+        if primary.get(key).is_none() {
+            stale.push(key.to_vec());
+        }
+    });
+    for key in stale.iter() {
+        secondary.del(key);
+    }
+    info!("resynced {} keys, removed {} stale ones", copied, stale.len());
+}
+
+impl<P: BackingStore> BackingStore for Replicating<P> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.primary.get(key)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) {
+        let _write = self.pending.write(key);
+        self.primary.put(key, value);
+    }
+
+    fn add(&self, key: &[u8], value: &[u8]) -> bool {
+        let _write = self.pending.write(key);
+        self.primary.add(key, value)
+    }
+
+    fn cas(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<(), Option<Vec<u8>>> {
+        let _write = self.pending.write(key);
+        self.primary.cas(key, expected, value)
+    }
+
+    fn del(&self, key: &[u8]) {
+        let _write = self.pending.write(key);
+        self.primary.del(key);
+    }
+
+    fn scan(&self, prefix: &[u8], f: &mut dyn FnMut(&[u8], &[u8])) {
+        self.primary.scan(prefix, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memory::MemoryStore;
+
+    // waits for the replicator to catch up
+    fn settle(secondary: &MemoryStore, check: impl Fn(&MemoryStore) -> bool) {
+        for _ in 0..500 {
+            if check(secondary) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("the secondary didn't catch up");
+    }
+
+    #[test]
+    fn test_resync() {
+        let journal = std::env::temp_dir().join(format!("replica-{}", std::process::id()));
+        let primary: &'static MemoryStore = Box::leak(Box::default());
+        let secondary: &'static MemoryStore = Box::leak(Box::default());
+        // an earlier run left a key and a resync in the journal
+        primary.put(b"a", b"1");
+        primary.put(b"b", b"2");
+        secondary.put(b"stale", b"0");
+        std::fs::write(&journal, format!("{}\n{}\n", hex::encode(b"a"), RESYNC)).unwrap();
+
+        let replicating = Replicating::new(primary, secondary, &journal).unwrap();
+        settle(secondary, |s| s.len() == 2 && s.get(b"b").is_some());
+        assert!(secondary.get(b"stale").is_none());
+
+        replicating.put(b"c", b"3");
+        settle(secondary, |s| s.get(b"c").as_deref() == Some(&b"3"[..]));
+        // a write the wrapper didn't see is copied by the next resync
+        primary.put(b"d", b"4");
+        replicating.resync();
+        settle(secondary, |s| s.get(b"d").is_some());
+        std::fs::remove_file(&journal).unwrap();
+    }
+}
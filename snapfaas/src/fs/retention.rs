@@ -0,0 +1,178 @@
+//! Durable results of async invocations
+//!
+//! A function with `Function::results` set has the result of each of its async invocations
+//! stored as a file in a directory, named `MILLIS:TASK_ID` and holding the protobuf encoded
+//! `TaskReturn`, so that invokers can pick it up later. The garbage collector walks the file
+//! system for gates of such functions and unlinks the results their retention no longer covers.
+use std::collections::HashSet;
+
+use super::{path::Path, BackingStore, DirEntry, FsError, Gate, Lease, ResultRetention, FS};
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Stores `result`, the encoded result of the async invocation `task_id`, in `retention.dir`.
+///
+/// Like the function's own writes, the file gets the current label, and storing fails unless
+/// the current label can flow into the directory.
+pub fn store<B: BackingStore>(
+    fs: &FS<B>,
+    retention: &ResultRetention,
+    task_id: &str,
+    result: Vec<u8>,
+) -> Result<(), FsError> {
+    let path = Path::parse(&retention.dir).map_err(|_| FsError::BadPath)?;
+    let DirEntry::Directory(dir) = fs.read_path(path)? else {
+        return Err(FsError::NotADir);
+    };
+    let file = fs.create_file(super::utils::get_current_label());
+    if let DirEntry::File(f) = &file {
+        f.write(result, fs)?;
+    }
+    if !dir.link(format!("{}:{}", Lease::now(), task_id), file, fs)? {
+        return Err(FsError::NameExists);
+    }
+    Ok(())
+}
+
+/// Unlinks the results outside the retention of every gate's function and returns how many it
+/// unlinked. Bypasses labels, so only the garbage collector should call it. Fails with
+/// `FsError::Contended` if a result directory kept changing under it.
+pub fn enforce<B: BackingStore>(fs: &FS<B>) -> Result<usize, FsError> {
+    let now = Lease::now();
+    let mut visited = HashSet::new();
+    let mut pending = vec![DirEntry::Directory(super::ROOT_REF)];
+    let mut unlinked = 0;
+    while let Some(entry) = pending.pop() {
+        match entry {
+            DirEntry::Directory(dir) if visited.insert(dir.uid) => {
+                let Some(dir) = dir.get(fs) else {
+                    continue;
+                };
+                pending.extend(dir.data.shards.iter().map(|shard| DirEntry::Directory(*shard)));
+                pending.extend(dir.data.entries.into_values());
+            }
+            DirEntry::FacetedDirectory(faceted) if visited.insert(faceted.uid) => {
+                if let Some(faceted) = faceted.get(fs) {
+                    let facets = faceted.facets.into_iter();
+                    pending.extend(facets.map(|(_, facet)| DirEntry::Directory(facet)));
+                }
+            }
+            DirEntry::Gate(gate) if visited.insert(gate.uid) => {
+                if let Some(Gate::Direct(gate)) = gate.get(fs).map(|g| g.data) {
+                    if let Some(retention) = gate.function.results {
+                        unlinked += prune(fs, &retention, now)?;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(unlinked)
+}
+
+// Unlinks the results in `retention.dir` older than `keep_days` or beyond the `keep_last` most
+// recent ones
+fn prune<B: BackingStore>(
+    fs: &FS<B>,
+    retention: &ResultRetention,
+    now: u64,
+) -> Result<usize, FsError> {
+    let dir = match Path::parse(&retention.dir).map(|path| fs.read_path(path)) {
+        Ok(Ok(DirEntry::Directory(dir))) => dir,
+        _ => return Ok(0),
+    };
+    // newest first
+    let mut results: Vec<(u64, String)> = dir
+        .list(fs)
+        .into_keys()
+        .filter_map(|name| Some((result_time(&name)?, name)))
+        .collect();
+    results.sort_unstable_by(|a, b| b.cmp(a));
+    let cutoff = retention
+        .keep_days
+        .map_or(0, |days| now.saturating_sub(days.saturating_mul(DAY_MS)));
+    let doomed: HashSet<String> = results
+        .into_iter()
+        .enumerate()
+        .filter(|(i, (time, _))| retention.keep_last.is_some_and(|n| *i >= n) || *time < cutoff)
+        .map(|(_, (_, name))| name)
+        .collect();
+    if doomed.is_empty() {
+        return Ok(0);
+    }
+    dir.unlink_unchecked(&|name| doomed.contains(name), fs)
+}
+
+// Milliseconds since the UNIX epoch at which the result `name` was stored
+fn result_time(name: &str) -> Option<u64> {
+    name.split_once(':').and_then(|(time, _)| time.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use labeled::buckle::{Buckle, Component};
+
+    use super::*;
+    use crate::fs::{memory::MemoryStore, utils, DirectGate, Function};
+
+    // Links a gate keeping results in a new directory `:dir` as `:gate`
+    fn retaining(fs: &FS<MemoryStore>, gate: &str, dir: &str, retention: ResultRetention) {
+        fs.link(Path::root(), dir.to_string(), fs.create_directory(Buckle::public()))
+            .unwrap();
+        let gate_entry = DirectGate {
+            privilege: Component::dc_true(),
+            invoker_integrity_clearance: Component::dc_true(),
+            declassify: Component::dc_true(),
+            function: Function {
+                results: Some(Box::new(retention)),
+                ..Default::default()
+            },
+            metadata: None,
+        };
+        let gate_entry = fs.create_direct_gate(Buckle::public(), gate_entry).unwrap();
+        fs.link(Path::root(), gate.to_string(), gate_entry).unwrap();
+    }
+
+    fn results(fs: &FS<MemoryStore>, dir: &str) -> Vec<String> {
+        let dir = Path::parse(&format!(":{}", dir)).unwrap();
+        fs.list_dir(dir).unwrap().into_keys().collect()
+    }
+
+    #[test]
+    fn test_enforce() {
+        let fs = FS::new(MemoryStore::default());
+        fs.initialize();
+        utils::clear_label();
+        utils::set_my_privilge(Component::dc_false());
+        let last_two = ResultRetention {
+            dir: ":last".to_string(),
+            keep_last: Some(2),
+            keep_days: None,
+        };
+        retaining(&fs, "g1", "last", last_two);
+        let one_day = ResultRetention {
+            dir: ":recent".to_string(),
+            keep_last: None,
+            keep_days: Some(1),
+        };
+        retaining(&fs, "g2", "recent", one_day);
+        let now = Lease::now();
+        for (dir, names) in [
+            (":last", vec!["1:a".to_string(), "2:b".to_string(), "3:c".to_string()]),
+            (":recent", vec![format!("{}:a", now), "5:b".to_string()]),
+        ] {
+            for name in names {
+                let dir = Path::parse(dir).unwrap();
+                fs.link(dir, name, fs.create_file(Buckle::public())).unwrap();
+            }
+        }
+        // not a result, so never pruned
+        let notes = fs.create_file(Buckle::public());
+        fs.link(Path::parse(":last").unwrap(), "notes".to_string(), notes).unwrap();
+
+        assert_eq!(enforce(&fs).unwrap(), 2);
+        assert_eq!(results(&fs, "last"), vec!["2:b", "3:c", "notes"]);
+        assert_eq!(results(&fs, "recent"), vec![format!("{}:a", now)]);
+        assert_eq!(enforce(&fs).unwrap(), 0);
+    }
+}
@@ -0,0 +1,78 @@
+//! Principals allowed to act with `faasten`'s privilege
+//!
+//! Clients that authenticate their principal, like `admin_fstools --token`, may elevate to
+//! `faasten`'s privilege with `--sudo` if the principal is listed here. The list is stored as
+//! JSON in the file `home:<T,faasten>:sudoers`. Anyone can read it but only `faasten` can
+//! update it.
+use std::collections::BTreeSet;
+
+use labeled::buckle::{Buckle, Component};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use super::{path::Path, BackingStore, FsError, FS};
+
+const SUDOERS_FILE: &str = "sudoers";
+
+lazy_static! {
+    static ref SUDOERS_BASE: Path = Path::parse("home:<T,faasten>").unwrap();
+    static ref SUDOERS_POLICY: Buckle = Buckle::parse("T,faasten").unwrap();
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Sudoers(BTreeSet<String>);
+
+impl Sudoers {
+    /// Loads the list, or an empty list if none is installed.
+    ///
+    /// The list is public and trusted, so reading it leaves the current label untouched.
+    pub fn load<S: BackingStore>(fs: &FS<S>) -> Self {
+        let saved = super::utils::get_current_label();
+        let mut path = SUDOERS_BASE.clone();
+        path.push_dscrp(SUDOERS_FILE.to_string());
+        let sudoers = fs
+            .read_file(path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        super::utils::clear_label();
+        super::utils::taint_with_label(saved);
+        sudoers
+    }
+
+    /// Stores the list. Requires `faasten`'s privilege.
+    pub fn save<S: BackingStore>(&self, fs: &FS<S>) -> Result<(), FsError> {
+        super::utils::create_or_update_file(
+            fs,
+            SUDOERS_BASE.clone(),
+            SUDOERS_FILE.to_string(),
+            SUDOERS_POLICY.clone(),
+            serde_json::to_vec(self).unwrap(),
+        )
+    }
+
+    /// Lists `principal`, a component such as `alice`. Fails if it doesn't parse.
+    pub fn insert(&mut self, principal: String) -> bool {
+        if Buckle::parse(&format!("{},T", principal)).is_err() {
+            return false;
+        }
+        self.0.insert(principal);
+        true
+    }
+
+    pub fn remove(&mut self, principal: &str) -> bool {
+        self.0.remove(principal)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &String> {
+        self.0.iter()
+    }
+
+    /// Whether `privilege` speaks for a listed principal
+    pub fn permits(&self, privilege: &Component) -> bool {
+        self.0.iter().any(|principal| {
+            Buckle::parse(&format!("{},T", principal))
+                .is_ok_and(|listed| privilege.implies(&listed.secrecy))
+        })
+    }
+}
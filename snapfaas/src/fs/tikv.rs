@@ -2,23 +2,88 @@ use std::sync::Arc;
 
 use tikv_client::RawClient;
 
+/// How `TikvClient` reads objects
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadMode {
+    /// Plain raw reads. Cheap, but a read may miss a write another client made moments before,
+    /// e.g. a worker may not yet see the gate a frontend just linked.
+    #[default]
+    Fast,
+    /// Reads through the same atomic path as compare-and-swap, which orders them after every
+    /// write acknowledged to any client. A session that writes and then hands off to another
+    /// worker is guaranteed to have that worker read its writes. Every read then costs a
+    /// compare-and-swap round trip and contends with writes to the key, so it is opt-in.
+    Consistent,
+}
+
+impl ReadMode {
+    pub fn new(consistent: bool) -> Self {
+        if consistent {
+            ReadMode::Consistent
+        } else {
+            ReadMode::Fast
+        }
+    }
+}
+
+// A value no object is ever stored as: object keys hold JSON or nothing
+const NEVER_STORED: &[u8] = b"\0faasten-consistent-read\0";
+
 #[derive(Clone)]
 pub struct TikvClient {
     tokio_runtime: Arc<tokio::runtime::Runtime>,
     client: RawClient,
+    read_mode: ReadMode,
 }
 
 impl TikvClient {
-    pub const fn new(client: RawClient, tokio_runtime: Arc<tokio::runtime::Runtime> ) -> Self {
-        TikvClient { tokio_runtime, client }
+    /// Writes all go through the atomic client, as TiKV does not order non-atomic writes with
+    /// compare-and-swaps on the same keys. Reads are `ReadMode::Fast` unless set otherwise with
+    /// `with_read_mode`.
+    pub fn new(client: RawClient, tokio_runtime: Arc<tokio::runtime::Runtime> ) -> Self {
+        TikvClient {
+            tokio_runtime,
+            client: client.with_atomic_for_cas(),
+            read_mode: ReadMode::default(),
+        }
+    }
+
+    pub fn with_read_mode(mut self, read_mode: ReadMode) -> Self {
+        self.read_mode = read_mode;
+        self
+    }
+
+    /// Returns every key in the store, in key order
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        const PAGE: u32 = 1024;
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        loop {
+            // the smallest key after the last one seen
+            let mut from = keys.last().cloned().unwrap_or_default();
+            if !keys.is_empty() {
+                from.push(0);
+            }
+            let page = self.tokio_runtime.block_on(async {
+                self.client.scan(from.., PAGE).await.expect("tikv scan")
+            });
+            let full = page.len() == PAGE as usize;
+            keys.extend(page.into_iter().map(|pair| pair.into_key().into()));
+            if !full {
+                return keys;
+            }
+        }
     }
 }
 
 impl super::BackingStore  for TikvClient {
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.tokio_runtime.block_on(async {
-            self.client.get(Vec::from(key)).await.expect("tikv get")
-        })
+        match self.read_mode {
+            ReadMode::Fast => self.tokio_runtime.block_on(async {
+                self.client.get(Vec::from(key)).await.expect("tikv get")
+            }),
+            // a swap expecting a value never stored always fails and returns the current value
+            ReadMode::Consistent => self.cas(key, Some(NEVER_STORED), NEVER_STORED).err()?,
+        }
     }
 
     fn put(&self, key: &[u8], value: &[u8]) {
@@ -33,7 +98,7 @@ impl super::BackingStore  for TikvClient {
 
     fn cas(&self, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<(), Option<Vec<u8>>> {
         let (orig, success) = self.tokio_runtime.block_on(async {
-            self.client.with_atomic_for_cas().compare_and_swap(Vec::from(key), expected.map(Vec::from), value).await.expect("tikv cas")
+            self.client.compare_and_swap(Vec::from(key), expected.map(Vec::from), value).await.expect("tikv cas")
         });
         if success {
             Ok(())
@@ -47,4 +112,28 @@ impl super::BackingStore  for TikvClient {
             self.client.delete(Vec::from(key)).await.expect("tikv del")
         });
     }
+
+    fn scan(&self, prefix: &[u8], f: &mut dyn FnMut(&[u8], &[u8])) {
+        const PAGE: u32 = 1024;
+        let mut from = prefix.to_vec();
+        loop {
+            let page = self.tokio_runtime.block_on(async {
+                self.client.scan(from.clone().., PAGE).await.expect("tikv scan")
+            });
+            let full = page.len() == PAGE as usize;
+            for pair in page {
+                let key: Vec<u8> = pair.key().as_ref().to_vec();
+                if !key.starts_with(prefix) {
+                    return;
+                }
+                f(&key, pair.value());
+                // the smallest key after the last one seen
+                from = key;
+                from.push(0);
+            }
+            if !full {
+                return;
+            }
+        }
+    }
 }
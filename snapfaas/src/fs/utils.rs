@@ -48,18 +48,21 @@ pub fn create_or_update_blob<S: BackingStore, P: Into<self::path::Path>>(
     name: String,
     label: Buckle,
     blob_name: String,
+    meta: BlobMeta,
 ) -> Result<(), FsError> {
     if let DirEntry::Directory(dir) = fs.read_path(base_dir)? {
         match dir.list(fs).get(&name) {
-            Some(DirEntry::Blob(blobentry)) => blobentry.replace(blob_name, fs).map_err(Into::into),
+            Some(DirEntry::Blob(blobentry)) => {
+                blobentry.replace(blob_name, meta, fs).map_err(Into::into)
+            }
             Some(_) => {
                 dir.unlink(&name, fs)?;
-                let new_blob = fs.create_blob(label, blob_name)?;
+                let new_blob = fs.create_blob(label, blob_name, meta)?;
                 dir.link(name, new_blob, fs)?;
                 Ok(())
             }
             None => {
-                let new_blob = fs.create_blob(label, blob_name)?;
+                let new_blob = fs.create_blob(label, blob_name, meta)?;
                 dir.link(name, new_blob, fs)?;
                 Ok(())
             }
@@ -165,3 +168,135 @@ pub fn declassify(target: Component) -> Result<Buckle, Buckle> {
     });
     res
 }
+
+/// Result of checking whether data labeled `current` may flow to `target`
+#[derive(Debug, serde::Serialize)]
+pub struct FlowExplanation {
+    pub allowed: bool,
+    /// clauses of the current secrecy not implied by the target secrecy and the privilege
+    pub secrecy_failures: Vec<String>,
+    /// clauses of the target integrity not implied by the current integrity and the privilege
+    pub integrity_failures: Vec<String>,
+}
+
+// clauses of `rhs` that `lhs` does not imply
+fn unimplied_clauses(lhs: &Component, rhs: &Component) -> Vec<String> {
+    match rhs {
+        Component::DCFalse => {
+            if lhs.implies(rhs) {
+                vec![]
+            } else {
+                vec![rhs.to_string()]
+            }
+        }
+        Component::DCFormula(clauses) => clauses
+            .iter()
+            .map(|clause| Component::formula([clause.clone()]))
+            .filter(|clause| !lhs.implies(clause))
+            .map(|clause| clause.to_string())
+            .collect(),
+    }
+}
+
+pub fn explain_flow(current: &Buckle, target: &Buckle, privilege: &Component) -> FlowExplanation {
+    let secrecy_failures = unimplied_clauses(
+        &(target.secrecy.clone() & privilege.clone()),
+        &current.secrecy,
+    );
+    let integrity_failures = unimplied_clauses(
+        &(current.integrity.clone() & privilege.clone()),
+        &target.integrity,
+    );
+    FlowExplanation {
+        allowed: secrecy_failures.is_empty() && integrity_failures.is_empty(),
+        secrecy_failures,
+        integrity_failures,
+    }
+}
+
+/// One gate in a redirect chain
+#[derive(Debug, serde::Serialize)]
+pub struct GateHop {
+    pub privilege: Component,
+    pub invoker_integrity_clearance: Component,
+    pub declassify: Component,
+}
+
+/// Effective policy of a gate, and what it means for one invoker and one consumer
+#[derive(Debug, serde::Serialize)]
+pub struct GateAnalysis {
+    /// the gate at the path first, the direct gate last
+    pub chain: Vec<GateHop>,
+    pub privilege: Component,
+    pub invoker_integrity_clearance: Component,
+    pub declassify: Component,
+    /// whether the invoker's privilege satisfies the clearance
+    pub invokable: bool,
+    /// label of the result if the function reads nothing beyond its payload. Reads only raise
+    /// the label, so a consumer that cannot read this result cannot read any result.
+    pub best_result: Buckle,
+    /// whether the consumer can read `best_result`
+    pub readable: FlowExplanation,
+}
+
+/// Walks the redirect chain of the gate at `path` and checks whether an invocation by `invoker`
+/// with payload labeled `payload_label` can produce a result `consumer` may read with
+/// `consumer_privilege`.
+///
+/// The thread's current label is tainted for each path component traversed.
+pub fn analyze_gate<S: BackingStore, P: Into<self::path::Path>>(
+    fs: &FS<S>,
+    path: P,
+    invoker: &Component,
+    payload_label: &Buckle,
+    consumer: &Buckle,
+    consumer_privilege: &Component,
+) -> Result<GateAnalysis, FsError> {
+    use labeled::HasPrivilege;
+    let DirEntry::Gate(gate) = fs.read_path(path)? else {
+        return Err(FsError::NotAGate);
+    };
+    let mut chain = Vec::new();
+    let mut cur = gate.get(fs).ok_or(FsError::NotAGate)?.unlabel().clone();
+    loop {
+        match cur {
+            Gate::Direct(gate) => {
+                chain.push(GateHop {
+                    privilege: gate.privilege,
+                    invoker_integrity_clearance: gate.invoker_integrity_clearance,
+                    declassify: gate.declassify,
+                });
+                break;
+            }
+            Gate::Redirect(gate) => {
+                chain.push(GateHop {
+                    privilege: gate.privilege,
+                    invoker_integrity_clearance: gate.invoker_integrity_clearance,
+                    declassify: gate.declassify,
+                });
+                cur = gate.gate.get(fs).ok_or(FsError::NotAGate)?.unlabel().clone();
+            }
+        }
+    }
+    let (privilege, invoker_integrity_clearance, declassify) = chain.iter().fold(
+        (Component::dc_true(), Component::dc_true(), Component::dc_true()),
+        |(p, c, d), hop| {
+            (
+                p & hop.privilege.clone(),
+                c & hop.invoker_integrity_clearance.clone(),
+                d & hop.declassify.clone(),
+            )
+        },
+    );
+    let best_result = payload_label.clone().downgrade(&privilege);
+    let readable = explain_flow(&best_result, consumer, consumer_privilege);
+    Ok(GateAnalysis {
+        invokable: invoker.implies(&invoker_integrity_clearance),
+        chain,
+        privilege,
+        invoker_integrity_clearance,
+        declassify,
+        best_result,
+        readable,
+    })
+}
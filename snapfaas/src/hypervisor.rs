@@ -0,0 +1,260 @@
+//! Hypervisors that boot function VMs
+//!
+//! `Vm` boots a VM by running a hypervisor process and then talks to the guest over the worker's
+//! vsock listener. A `Hypervisor` turns a `FunctionConfig` into that process's command line, so
+//! every backend must forward the guest's connections to vsock port 1234 to the Unix socket
+//! `worker-[cid].sock_1234` in the run directory, see `run_dir`.
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::configs::{FunctionConfig, IoEngine};
+use crate::vm::{Error, OdirectOption};
+
+/// Everything a hypervisor needs to boot one VM
+pub struct LaunchSpec<'a> {
+    pub id: usize,
+    pub cid: u32,
+    pub config: &'a FunctionConfig,
+    pub odirect: Option<&'a OdirectOption>,
+    /// file backing the memory region shared with the guest runtime, attached as a writable
+    /// drive after the rootfs, the appfs and its layers
+    pub shared_mem_file: Option<&'a str>,
+}
+
+// Kernel command line of a VM of `config`. The guest runtime learns from `faasten.layers` how
+// many layers to overlay on the appfs.
+fn cmdline(config: &FunctionConfig) -> String {
+    let cmdline = config.cmdline.as_deref().unwrap_or("console=ttyS0");
+    if config.app_layers.is_empty() {
+        cmdline.to_string()
+    } else {
+        format!("{} faasten.layers={}", cmdline, config.app_layers.len())
+    }
+}
+
+pub trait Hypervisor {
+    /// Command that boots the VM and runs until the VM shuts down
+    fn command(&self, spec: &LaunchSpec) -> Result<Command, Error>;
+}
+
+/// Hypervisor backends a worker can run its VMs on
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// our Firecracker fork, through `firerunner`
+    #[default]
+    Firecracker,
+    /// cloud-hypervisor, for hosts where the Firecracker fork does not build. No snapshots.
+    CloudHypervisor,
+}
+
+impl Backend {
+    pub fn hypervisor(self) -> &'static dyn Hypervisor {
+        match self {
+            Backend::Firecracker => &Firecracker,
+            Backend::CloudHypervisor => &CloudHypervisor,
+        }
+    }
+}
+
+pub struct Firecracker;
+
+impl Hypervisor for Firecracker {
+    fn command(&self, spec: &LaunchSpec) -> Result<Command, Error> {
+        let config = spec.config;
+        // our Firecracker fork has no VFIO support, and a VM without its devices is no use
+        if !config.devices.is_empty() {
+            return Err(Error::Unsupported("device passthrough on Firecracker"));
+        }
+        if config.io_engine == IoEngine::Async {
+            return Err(Error::Unsupported("the async I/O engine on Firecracker"));
+        }
+        let mut command = Command::new("firerunner");
+        command
+            .args(["--id", &spec.id.to_string()])
+            .args(["--kernel", &config.kernel])
+            .args(["--memory", &config.memory.to_string()])
+            .args(["--vcpu", &config.vcpus.to_string()])
+            .args(["--rootfs", &config.runtimefs])
+            .args(["--vsock-cid", &spec.cid.to_string()])
+            .arg("--run-dir")
+            .arg(crate::run_dir());
+
+        if let Some(f) = config.appfs.as_ref() {
+            command.args(["--appfs", f]);
+        }
+        for layer in config.app_layers.iter() {
+            command.args(["--app-layer", layer]);
+        }
+        if let Some(path) = spec.shared_mem_file {
+            command.args(["--shared-mem-file", path]);
+        }
+        if let Some(load_dir) = config.load_dir.as_ref() {
+            command.args(["--load-dir", load_dir]);
+            if config.copy_base {
+                command.arg("--copy-base");
+                if config.load_threads > 1 {
+                    command.args(["--load-threads", &config.load_threads.to_string()]);
+                }
+            }
+            if config.copy_diff {
+                command.arg("--copy-diff");
+            }
+            if config.load_ws {
+                command.arg("--load-ws");
+            }
+        }
+        if let Some(dump_dir) = config.dump_dir.as_ref() {
+            command.args(["--dump-to", dump_dir]);
+            if config.dump_ws {
+                command.arg("--dump-ws");
+            }
+        }
+        command.args(["--kernel-args", &cmdline(config)]);
+
+        // network config should be of the format <TAP-Name>/<MAC Address>
+        //let tap_name = format!("tap{}", cid - 100);
+        //let mac_addr = format!(
+        //    "{}:{:02X}:{:02X}",
+        //    MACPREFIX,
+        //    ((cid - 100) & 0xff00) >> 8,
+        //    (cid - 100) & 0xff
+        //);
+        if let Some(mac) = config.mac.as_ref() {
+            command.args(["--tap-name", config.tap.as_ref().unwrap()]);
+            command.args(["--mac", mac]);
+        }
+
+        // odirect
+        if let Some(odirect) = spec.odirect {
+            if odirect.base {
+                command.arg("--odirect-base");
+            }
+            if !odirect.diff {
+                command.arg("--no-odirect-diff");
+            }
+            if !odirect.rootfs {
+                command.arg("--no-odirect-root");
+            }
+            if !odirect.appfs {
+                command.arg("--no-odirect-app");
+            }
+        }
+        Ok(command)
+    }
+}
+
+pub struct CloudHypervisor;
+
+impl Hypervisor for CloudHypervisor {
+    fn command(&self, spec: &LaunchSpec) -> Result<Command, Error> {
+        let config = spec.config;
+        if config.load_dir.is_some() || config.dump_dir.is_some() {
+            return Err(Error::Unsupported("snapshots on cloud-hypervisor"));
+        }
+        let odirect = |f: fn(&OdirectOption) -> bool| spec.odirect.is_none_or(f);
+        let on = |b: bool| if b { "on" } else { "off" };
+        // cloud-hypervisor uses io_uring where the host has it
+        let sync = config.io_engine == IoEngine::Sync;
+        let disk = |path: &str, readonly: bool, direct: bool| {
+            format!(
+                "path={},readonly={},direct={},_disable_io_uring={}",
+                path,
+                on(readonly),
+                on(direct),
+                on(sync),
+            )
+        };
+        let cmdline = cmdline(config);
+
+        let mut command = Command::new("cloud-hypervisor");
+        command
+            .args(["--kernel", &config.kernel])
+            // Firecracker adds the root device to the command line on its own
+            .args(["--cmdline", &format!("{} root=/dev/vda ro", cmdline)])
+            .args(["--memory", &format!("size={}M", config.memory)])
+            .args(["--cpus", &format!("boot={}", config.vcpus)])
+            .args(["--serial", "tty", "--console", "off"]);
+
+        // disks show up in the guest in order, as with firerunner
+        let mut disks = vec![disk(&config.runtimefs, true, odirect(|o| o.rootfs))];
+        if let Some(f) = config.appfs.as_ref() {
+            disks.push(disk(f, true, odirect(|o| o.appfs)));
+        }
+        for layer in config.app_layers.iter() {
+            disks.push(disk(layer, true, odirect(|o| o.appfs)));
+        }
+        if let Some(path) = spec.shared_mem_file {
+            disks.push(disk(path, false, false));
+        }
+        command.arg("--disk").args(disks);
+
+        // guest connections to port P are forwarded to the Unix socket `<socket>_P`
+        let socket = crate::run_dir().join(format!("worker-{}.sock", spec.cid));
+        command.args([
+            "--vsock",
+            &format!("cid={},socket={}", spec.cid, socket.display()),
+        ]);
+        if let (Some(mac), Some(tap)) = (config.mac.as_ref(), config.tap.as_ref()) {
+            command.args(["--net", &format!("tap={},mac={}", tap, mac)]);
+        }
+        if !config.devices.is_empty() {
+            command.arg("--device").args(
+                config
+                    .devices
+                    .iter()
+                    .map(|addr| format!("path=/sys/bus/pci/devices/{}/", addr)),
+            );
+        }
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(config: &FunctionConfig) -> LaunchSpec<'_> {
+        LaunchSpec {
+            id: 1,
+            cid: 100,
+            config,
+            odirect: None,
+            shared_mem_file: None,
+        }
+    }
+
+    fn args(command: Command) -> Vec<String> {
+        command.get_args().map(|a| a.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn test_devices() {
+        let config = FunctionConfig {
+            devices: vec!["0000:01:00.0".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(Firecracker.command(&spec(&config)), Err(Error::Unsupported(_))));
+        let args = args(CloudHypervisor.command(&spec(&config)).unwrap());
+        assert!(args.contains(&"path=/sys/bus/pci/devices/0000:01:00.0/".to_string()));
+    }
+
+    #[test]
+    fn test_io_engine() {
+        let mut config = FunctionConfig {
+            runtimefs: "rootfs.img".to_string(),
+            io_engine: IoEngine::Async,
+            ..Default::default()
+        };
+        assert!(matches!(Firecracker.command(&spec(&config)), Err(Error::Unsupported(_))));
+        let disk = |config: &FunctionConfig| {
+            let args = args(CloudHypervisor.command(&spec(config)).unwrap());
+            args.into_iter().find(|a| a.starts_with("path=rootfs.img")).unwrap()
+        };
+        assert!(disk(&config).ends_with("_disable_io_uring=off"));
+        config.io_engine = IoEngine::Sync;
+        assert!(disk(&config).ends_with("_disable_io_uring=on"));
+        assert!(Firecracker.command(&spec(&config)).is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+//! Cleanup of what crashed workers leave behind
+//!
+//! A worker that crashes leaves its hypervisor processes running and the Unix sockets of its own
+//! and of its VMs in the run directory, see `run_dir`. A restarted worker reattaches to some of
+//! those VMs, see `manifest`. The janitor reaps the rest: hypervisor processes using the
+//! directory whose worker is gone and that no VM record claims, and socket files nothing listens
+//! on anymore. Workers sharing the directory are left alone, as they listen there.
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use log::{error, warn};
+
+// names of the hypervisor executables, see `hypervisor`
+const HYPERVISORS: [&str; 2] = ["firerunner", "cloud-hypervisor"];
+// the sockets of workers, of hypervisors' vsock devices and of the snapshot handshake
+const SOCKETS: [&str; 2] = ["worker-*.sock*", "dump_ws-*.sock*"];
+
+pub struct Janitor {
+    dir: PathBuf,
+}
+
+impl Default for Janitor {
+    /// The janitor of the run directory
+    fn default() -> Self {
+        Janitor::new(crate::run_dir().to_path_buf())
+    }
+}
+
+impl Janitor {
+    pub fn new(dir: PathBuf) -> Self {
+        Janitor { dir }
+    }
+
+    /// Kills orphaned hypervisors other than `claimed` ones and removes stale sockets
+    pub fn sweep(&self, claimed: &HashSet<u32>) {
+        self.reap_orphans(claimed);
+        self.remove_sockets(false);
+    }
+
+    /// Kills the hypervisors using the directory whose parent is gone, i.e. no longer listens
+    /// there, except `claimed` ones, and returns how many it killed
+    pub fn reap_orphans(&self, claimed: &HashSet<u32>) -> usize {
+        let Ok(dir) = self.dir.canonicalize() else {
+            return 0;
+        };
+        let listening: HashSet<u64> = bound_sockets()
+            .into_iter()
+            .filter(|(_, path)| Path::new(path).starts_with(&dir))
+            .map(|(inode, _)| inode)
+            .collect();
+        let mut reaped = 0;
+        for pid in processes() {
+            if claimed.contains(&pid) || !is_hypervisor(pid) || !uses_dir(pid, &dir) {
+                continue;
+            }
+            let orphaned =
+                parent(pid).is_none_or(|ppid| sockets(ppid).is_disjoint(&listening));
+            if orphaned {
+                warn!("killing orphaned hypervisor {}", pid);
+                unsafe {
+                    libc::kill(pid as libc::pid_t, libc::SIGKILL);
+                }
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+
+    /// Removes the socket files in the directory no process listens on, and those of this
+    /// process and its children too if `own`, e.g. before it exits. Returns how many it removed.
+    pub fn remove_sockets(&self, own: bool) -> usize {
+        let Ok(dir) = self.dir.canonicalize() else {
+            return 0;
+        };
+        let ours = if own { own_sockets() } else { HashSet::new() };
+        let bound: HashSet<PathBuf> = bound_sockets()
+            .into_iter()
+            .filter(|(inode, _)| !ours.contains(inode))
+            .map(|(_, path)| PathBuf::from(path))
+            .collect();
+        let mut removed = 0;
+        for pattern in SOCKETS {
+            let pattern = dir.join(pattern);
+            let Ok(paths) = glob::glob(&pattern.to_string_lossy()) else {
+                error!("Invalid file pattern {:?}", pattern);
+                continue;
+            };
+            for path in paths.flatten() {
+                if bound.contains(&path) {
+                    continue;
+                }
+                match std::fs::remove_file(&path) {
+                    Ok(()) => removed += 1,
+                    Err(e) => error!("Failed to unlink {:?}: {:?}", path, e),
+                }
+            }
+        }
+        removed
+    }
+}
+
+fn processes() -> Vec<u32> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_str()?.parse().ok())
+        .collect()
+}
+
+fn is_hypervisor(pid: u32) -> bool {
+    let Ok(cmdline) = std::fs::read(format!("/proc/{}/cmdline", pid)) else {
+        return false;
+    };
+    let argv0 = cmdline.split(|b| *b == 0).next().unwrap_or_default();
+    let name = Path::new(std::str::from_utf8(argv0).unwrap_or_default()).file_name();
+    name.is_some_and(|name| HYPERVISORS.iter().any(|h| name == *h))
+}
+
+// Whether the command line of process `pid` names a path in `dir`, as hypervisors are given
+// the paths of their sockets
+fn uses_dir(pid: u32, dir: &Path) -> bool {
+    let Ok(cmdline) = std::fs::read(format!("/proc/{}/cmdline", pid)) else {
+        return false;
+    };
+    let dir = dir.to_string_lossy();
+    String::from_utf8_lossy(&cmdline).contains(dir.as_ref())
+}
+
+fn parent(pid: u32) -> Option<u32> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the parent is the 4th field, the second after the command name
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(1)?.parse().ok()
+}
+
+// Inodes and paths of the bound Unix sockets
+fn bound_sockets() -> Vec<(u64, String)> {
+    let Ok(table) = std::fs::read_to_string("/proc/net/unix") else {
+        return vec![];
+    };
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            // Num RefCount Protocol Flags Type St Inode Path
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let inode = fields.get(6)?.parse().ok()?;
+            let path = fields.get(7).filter(|p| !p.starts_with('@'))?;
+            Some((inode, path.to_string()))
+        })
+        .collect()
+}
+
+// Inodes of the sockets process `pid` has open
+fn sockets(pid: u32) -> HashSet<u64> {
+    let Ok(fds) = std::fs::read_dir(format!("/proc/{}/fd", pid)) else {
+        return HashSet::new();
+    };
+    fds.flatten()
+        .filter_map(|fd| {
+            let link = std::fs::read_link(fd.path()).ok()?;
+            let link = link.to_str()?;
+            link.strip_prefix("socket:[")?.strip_suffix(']')?.parse().ok()
+        })
+        .collect()
+}
+
+// Inodes of the sockets this process and its children have open
+fn own_sockets() -> HashSet<u64> {
+    let me = std::process::id();
+    let children = processes().into_iter().filter(|&pid| parent(pid) == Some(me));
+    std::iter::once(me).chain(children).flat_map(sockets).collect()
+}
@@ -5,46 +5,61 @@ pub mod worker;
 // TODO what metrics do we want?
 //pub mod metrics;
 pub mod blobstore;
+pub mod cid;
 pub mod cli;
+pub mod delegation;
+pub mod download;
+pub mod envelope;
 pub mod firecracker_wrapper;
 pub mod fs;
+pub mod hypervisor;
+pub mod janitor;
+pub mod login;
+pub mod maintenance;
+pub mod manifest;
+pub mod receipt;
 pub mod sched;
+pub mod scratch;
+pub mod session;
+pub mod shm;
+pub mod signing;
+pub mod supervisor;
 pub mod syscall_server;
+pub mod systemd;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
 pub mod vm;
 
-use log::error;
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 const MEM_FILE: &str = "/proc/meminfo"; // meminfo file on linux
 const KB_IN_MB: usize = 1024;
 
-/// rm worker*
-pub fn unlink_unix_sockets() {
-    match glob::glob("worker-*sock*") {
-        Err(_) => error!("Invalid file pattern"),
-        Ok(paths) => {
-            for entry in paths {
-                if let Ok(path) = entry {
-                    if let Err(e) = std::fs::remove_file(&path) {
-                        error!("Failed to unlink {}: {:?}", path.to_str().unwrap(), e);
-                    }
-                }
-            }
-        }
-    }
+/// Where Unix sockets go unless the binary is told otherwise
+pub const DEFAULT_RUN_DIR: &str = "/run/faasten";
 
-    match glob::glob("dump_ws-*sock*") {
-        Err(_) => error!("Invalid file pattern"),
-        Ok(paths) => {
-            for entry in paths {
-                if let Ok(path) = entry {
-                    if let Err(e) = std::fs::remove_file(&path) {
-                        error!("Failed to unlink {}: {:?}", path.to_str().unwrap(), e);
-                    }
-                }
-            }
-        }
-    }
+static RUN_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Directory of the Unix sockets of workers, their VMs and the snapshot handshake, rather than
+/// the current directory, so that workers started in the same directory don't collide
+pub fn run_dir() -> &'static Path {
+    RUN_DIR.get_or_init(|| PathBuf::from(DEFAULT_RUN_DIR))
+}
+
+/// Puts the process's Unix sockets under `dir`, creating it if need be. This should be called
+/// once, before any socket is created.
+pub fn set_run_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let _ = RUN_DIR.set(dir.canonicalize()?);
+    Ok(())
+}
+
+/// Removes the Unix sockets of the process, of its VMs and the stale ones, before it exits.
+/// Sockets of other workers in the run directory stay.
+pub fn unlink_unix_sockets() {
+    janitor::Janitor::default().remove_sockets(true);
 }
 
 pub fn get_machine_memory() -> usize {
@@ -0,0 +1,99 @@
+//! Login tokens.
+//!
+//! A login token states that its holder speaks for the principal `sub` until `exp` (seconds since
+//! the UNIX epoch). Tokens are ES256 JWTs signed with the platform key. The webfront issues them
+//! at login and `admin_fstools jwt` on demand, both with `sid` naming a login session so that
+//! revoking the session revokes them. Tokens without a session are rejected.
+use jwt::{PKeyWithDigest, SignWithKey, VerifyWithKey};
+use labeled::buckle::Component;
+use openssl::pkey::{PKey, Private, Public};
+use serde::{Deserialize, Serialize};
+
+use crate::delegation::now;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoginError {
+    BadToken,
+    Expired,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Claims {
+    pub alg: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub sub: Component,
+    /// the login session the token belongs to, tokens without one can't be revoked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+}
+
+impl Claims {
+    /// Claims valid for `ttl` seconds
+    pub fn new(sub: Component, ttl: u64, sid: Option<String>) -> Self {
+        let now = now();
+        Claims {
+            alg: "ES256".to_string(),
+            iat: now,
+            exp: now.saturating_add(ttl),
+            sub,
+            sid,
+        }
+    }
+
+    pub fn sign(&self, key: &PKey<Private>) -> String {
+        let key = PKeyWithDigest {
+            key: key.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        self.sign_with_key(&key).unwrap()
+    }
+
+    /// Check the signature and the expiration time of a token. Whether its session is still
+    /// open is left to the caller, see `crate::session::lookup`.
+    pub fn verify(token: &str, key: &PKey<Public>) -> Result<Self, LoginError> {
+        let key = PKeyWithDigest {
+            key: key.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        let claims: Claims = token
+            .verify_with_key(&key)
+            .map_err(|_| LoginError::BadToken)?;
+        if claims.exp < now() {
+            return Err(LoginError::Expired);
+        }
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    fn keypair() -> (PKey<Private>, PKey<Public>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let public = EcKey::from_public_key(&group, key.public_key()).unwrap();
+        (PKey::from_ec_key(key).unwrap(), PKey::from_ec_key(public).unwrap())
+    }
+
+    #[test]
+    fn test_verify() {
+        let (private, public) = keypair();
+        let alice = labeled::buckle::Buckle::parse("alice,T").unwrap().secrecy;
+        let token = Claims::new(alice.clone(), 60, Some("s".to_string())).sign(&private);
+        let claims = Claims::verify(&token, &public).unwrap();
+        assert_eq!(claims.sub, alice);
+        assert_eq!(claims.sid.as_deref(), Some("s"));
+
+        let (_, other) = keypair();
+        assert_eq!(Claims::verify(&token, &other).unwrap_err(), LoginError::BadToken);
+
+        let mut expired = Claims::new(alice, 0, None);
+        expired.exp -= 1;
+        let token = expired.sign(&private);
+        assert_eq!(Claims::verify(&token, &public).unwrap_err(), LoginError::Expired);
+    }
+}
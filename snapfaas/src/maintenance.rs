@@ -0,0 +1,268 @@
+//! Scheduling of background maintenance, such as garbage collection and compaction, around
+//! foreground load
+//!
+//! Maintenance runs only inside its window, a cron-style expression of the minutes it may run
+//! in, and pauses whenever the scheduler's queue is deeper than a threshold, so that it doesn't
+//! compete with invocations for the storage.
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use crate::sched::message::Load;
+use crate::sched::rpc;
+
+// how often a paused task checks whether it may resume
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Minutes of the day, in UTC, as a crontab expression of five fields: minute, hour, day of
+/// month, month and day of week (0 is Sunday). Fields take `*`, numbers, ranges `a-b`, steps
+/// `*/n` and `a-b/n`, and comma separated lists of those. As in cron, a day matches if either
+/// the day of month or the day of week does when both are restricted.
+///
+/// E.g. `* 2-4 * * *` is every day from 02:00 to 04:59 and `*/10 * * * 6,0` is one minute in
+/// ten on weekends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+// bit i is set if value i matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    bits: u64,
+    any: bool,
+}
+
+impl Field {
+    fn parse(s: &str, min: u32, max: u32) -> Result<Self, String> {
+        let mut bits = 0;
+        for part in s.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => {
+                    let step: u32 = step.parse().map_err(|_| format!("bad step `{}`", step))?;
+                    if step == 0 {
+                        return Err(format!("bad step `{}`", part));
+                    }
+                    (range, step)
+                }
+                None => (part, 1),
+            };
+            let (from, to) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((from, to)) => (value(from, min, max)?, value(to, min, max)?),
+                    None => {
+                        let v = value(range, min, max)?;
+                        (v, v)
+                    }
+                },
+            };
+            if from > to {
+                return Err(format!("empty range `{}`", range));
+            }
+            for v in (from..=to).step_by(step as usize) {
+                bits |= 1 << v;
+            }
+        }
+        Ok(Field {
+            bits,
+            any: s == "*",
+        })
+    }
+
+    fn matches(&self, v: u32) -> bool {
+        self.bits & (1 << v) != 0
+    }
+}
+
+fn value(s: &str, min: u32, max: u32) -> Result<u32, String> {
+    match s.parse() {
+        Ok(v) if (min..=max).contains(&v) => Ok(v),
+        _ => Err(format!("`{}` is not in {}-{}", s, min, max)),
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("expected 5 fields, found {}", fields.len()));
+        }
+        Ok(Window {
+            minutes: Field::parse(fields[0], 0, 59)?,
+            hours: Field::parse(fields[1], 0, 23)?,
+            days: Field::parse(fields[2], 1, 31)?,
+            months: Field::parse(fields[3], 1, 12)?,
+            // 7 is also Sunday
+            weekdays: Field::parse(fields[4], 0, 7).map(|mut f| {
+                if f.matches(7) {
+                    f.bits |= 1;
+                }
+                f
+            })?,
+        })
+    }
+}
+
+impl Window {
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let days = secs / 86400;
+        let (_, month, day) = civil_from_days(days as i64);
+        // 1970-01-01 was a Thursday
+        let weekday = ((days + 4) % 7) as u32;
+        let day_matches = match (self.days.any, self.weekdays.any) {
+            (false, false) => self.days.matches(day) || self.weekdays.matches(weekday),
+            _ => self.days.matches(day) && self.weekdays.matches(weekday),
+        };
+        self.minutes.matches((secs / 60 % 60) as u32)
+            && self.hours.matches((secs / 3600 % 24) as u32)
+            && self.months.matches(month)
+            && day_matches
+    }
+}
+
+// Year, month and day of the date `days` after 1970-01-01, after Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Decides when a maintenance task may run, see the module documentation. Without a window it
+/// may run at any time, and without a scheduler regardless of load.
+pub struct Maintenance {
+    window: Option<Window>,
+    scheduler: Option<rpc::Client>,
+    max_queue_depth: u64,
+}
+
+impl Maintenance {
+    pub fn new(
+        window: Option<Window>,
+        scheduler: Option<rpc::Client>,
+        max_queue_depth: u64,
+    ) -> Self {
+        Maintenance {
+            window,
+            scheduler,
+            max_queue_depth,
+        }
+    }
+
+    /// Whether maintenance may run now
+    pub fn may_run(&mut self) -> bool {
+        if !self.in_window(SystemTime::now()) {
+            return false;
+        }
+        let load = self.scheduler.as_mut().map(rpc::load);
+        !self.yields_to(load)
+    }
+
+    // Whether `time` is inside the window, if there is one
+    fn in_window(&self, time: SystemTime) -> bool {
+        self.window.as_ref().map_or(true, |window| window.contains(time))
+    }
+
+    // Whether maintenance yields to the scheduler's `load`, none without a scheduler
+    fn yields_to(&self, load: Option<Result<Load, crate::sched::Error>>) -> bool {
+        match load {
+            Some(Ok(load)) if load.queue_depth > self.max_queue_depth => {
+                debug!("yielding to {} queued tasks", load.queue_depth);
+                true
+            }
+            // an unreachable scheduler doesn't hold maintenance back
+            Some(Err(e)) => {
+                warn!("failed to get the scheduler's load: {:?}", e);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Blocks until maintenance may run
+    pub fn wait(&mut self) {
+        while !self.may_run() {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-06-03, a Saturday, at `hour`:`minute` UTC
+    fn saturday(hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1685750400 + hour * 3600 + minute * 60)
+    }
+
+    #[test]
+    fn test_window() {
+        let nightly: Window = "* 2-4 * * *".parse().unwrap();
+        assert!(nightly.contains(saturday(2, 0)));
+        assert!(nightly.contains(saturday(4, 59)));
+        assert!(!nightly.contains(saturday(5, 0)));
+        assert!(!nightly.contains(saturday(1, 59)));
+
+        let weekends: Window = "*/10 * * * 6,0".parse().unwrap();
+        assert!(weekends.contains(saturday(13, 20)));
+        assert!(!weekends.contains(saturday(13, 21)));
+        // Monday
+        assert!(!weekends.contains(saturday(13, 20) + Duration::from_secs(2 * 86400)));
+        // 7 is also Sunday
+        let sundays: Window = "* * * * 7".parse().unwrap();
+        assert!(sundays.contains(saturday(13, 20) + Duration::from_secs(86400)));
+
+        // either the day of month or the day of week, 2023-06-03 being the 3rd
+        let either: Window = "* * 3 * 1".parse().unwrap();
+        assert!(either.contains(saturday(0, 0)));
+        assert!(!either.contains(saturday(0, 0) + Duration::from_secs(86400)));
+        assert!(either.contains(saturday(0, 0) + Duration::from_secs(2 * 86400)));
+
+        assert!("* * * *".parse::<Window>().is_err());
+        assert!("60 * * * *".parse::<Window>().is_err());
+        assert!("5-1 * * * *".parse::<Window>().is_err());
+        assert!("*/0 * * * *".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn test_in_window() {
+        let anytime = Maintenance::new(None, None, 0);
+        assert!(anytime.in_window(saturday(12, 0)));
+        let nightly = Maintenance::new(Some("* 2-4 * * *".parse().unwrap()), None, 0);
+        assert!(nightly.in_window(saturday(3, 0)));
+        assert!(!nightly.in_window(saturday(12, 0)));
+    }
+
+    #[test]
+    fn test_yields_to() {
+        let maintenance = Maintenance::new(None, None, 5);
+        let load = |queue_depth| {
+            Some(Ok(Load {
+                queue_depth,
+                ..Default::default()
+            }))
+        };
+        assert!(!maintenance.yields_to(None));
+        assert!(!maintenance.yields_to(load(5)));
+        assert!(maintenance.yields_to(load(6)));
+        let unreachable = crate::sched::Error::Other("unreachable".to_string());
+        assert!(!maintenance.yields_to(Some(Err(unreachable))));
+    }
+}
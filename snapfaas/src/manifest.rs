@@ -0,0 +1,222 @@
+//! Manifest of a worker's VMs
+//!
+//! Hypervisor processes outlive a worker that crashes or restarts. A worker given a manifest
+//! file records every VM it launches there, so that the next worker started with the same file
+//! takes the idle VMs back into its cache rather than leaking them and booting cold. The
+//! runtimes of idle VMs reconnect to the worker's Unix socket once their connection breaks, and
+//! the worker tells the VMs apart by the peer process of each connection. VMs that were running
+//! an invocation lost it and were tainted by it, so they are shut down instead, as are VMs
+//! sharing memory with their runtime, whose region went away with the old worker.
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use labeled::buckle::Buckle;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::fs::Function;
+use crate::vm::Vm;
+
+/// How long a restarted worker waits for the runtimes of idle VMs to reconnect
+pub const REATTACH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VmRecord {
+    pub id: usize,
+    /// the hypervisor process
+    pub pid: u32,
+    /// start time of the hypervisor process in clock ticks since boot, which tells it from a
+    /// later process with the same pid
+    pub started: u64,
+    pub cid: u32,
+    /// Unix socket the VM's runtime connects to
+    pub vsock_path: PathBuf,
+    pub function: Function,
+    pub label: Buckle,
+    /// PCI addresses of the VM's passthrough devices
+    #[serde(default)]
+    pub devices: Vec<String>,
+    #[serde(default)]
+    pub jail: Option<PathBuf>,
+    #[serde(default)]
+    pub shared_mem: bool,
+    /// seconds since the epoch the VM became idle, none while it runs an invocation
+    pub idle_since: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    records: BTreeMap<usize, VmRecord>,
+}
+
+impl Manifest {
+    /// Opens the manifest at `path`, which need not exist yet
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let records = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice::<Vec<VmRecord>>(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                .into_iter()
+                .map(|r| (r.id, r))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Default::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Manifest {
+            path: path.to_path_buf(),
+            records,
+        })
+    }
+
+    /// Records `vm`, replacing what was recorded about it, unless it was never launched
+    pub fn insert(&mut self, vm: &Vm, idle: bool) {
+        if let Some(record) = vm.record(idle) {
+            self.records.insert(record.id, record);
+            self.save();
+        }
+    }
+
+    /// Hypervisor processes of the recorded VMs
+    pub fn pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.records.values().map(|r| r.pid)
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        if self.records.remove(&id).is_some() {
+            self.save();
+        }
+    }
+
+    // Replaces the file as a whole, so that a crash leaves either the old or the new manifest
+    fn save(&self) {
+        let tmp = self.path.with_extension("tmp");
+        let records: Vec<&VmRecord> = self.records.values().collect();
+        let res = std::fs::write(&tmp, serde_json::to_vec(&records).unwrap())
+            .and_then(|_| std::fs::rename(&tmp, &self.path));
+        if let Err(e) = res {
+            warn!("failed to save the VM manifest {:?}: {:?}", self.path, e);
+        }
+    }
+
+    /// Takes back the idle VMs of the previous worker whose hypervisors still run and whose
+    /// runtimes reconnect within `timeout`, and shuts down the other VMs it left. The manifest
+    /// then records only the returned VMs.
+    pub fn reattach(&mut self, timeout: Duration) -> Vec<Vm> {
+        let mut pending: HashMap<PathBuf, Vec<VmRecord>> = HashMap::new();
+        for record in std::mem::take(&mut self.records).into_values() {
+            if process_start(record.pid) != Some(record.started) {
+                debug!("vm {} is gone", record.id);
+                remove_jail(&record);
+            } else if record.idle_since.is_none() || record.shared_mem {
+                shut_down(&record);
+            } else {
+                pending
+                    .entry(record.vsock_path.clone())
+                    .or_default()
+                    .push(record);
+            }
+        }
+
+        let mut listeners = Vec::new();
+        for path in pending.keys() {
+            let _ = std::fs::remove_file(path);
+            match UnixListener::bind(path).and_then(|l| l.set_nonblocking(true).map(|_| l)) {
+                Ok(listener) => listeners.push((path.clone(), listener)),
+                Err(e) => warn!("failed to listen at {:?} for vms: {:?}", path, e),
+            }
+        }
+        let mut vms = Vec::new();
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline && pending.values().any(|l| !l.is_empty()) {
+            let mut accepted = false;
+            for (path, listener) in listeners.iter() {
+                let Ok((conn, _)) = listener.accept() else {
+                    continue;
+                };
+                accepted = true;
+                let records = pending.get_mut(path).unwrap();
+                let peer = peer_pid(&conn);
+                let Some(i) = records.iter().position(|r| Some(r.pid) == peer) else {
+                    warn!("unknown vm connected at {:?}", path);
+                    continue;
+                };
+                let record = records.swap_remove(i);
+                // the CID was freed when the previous worker exited
+                let cid = match crate::cid::reserve(record.cid) {
+                    Ok(cid) if conn.set_nonblocking(false).is_ok() => cid,
+                    _ => {
+                        shut_down(&record);
+                        continue;
+                    }
+                };
+                vms.push(Vm::reattach(record, conn, cid));
+            }
+            if !accepted {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        for (path, _) in listeners {
+            let _ = std::fs::remove_file(path);
+        }
+        for record in pending.into_values().flatten() {
+            warn!("vm {} did not reconnect", record.id);
+            shut_down(&record);
+        }
+
+        info!("reattached {} vms", vms.len());
+        for vm in vms.iter() {
+            if let Some(record) = vm.record(true) {
+                self.records.insert(record.id, record);
+            }
+        }
+        self.save();
+        vms
+    }
+}
+
+/// Start time in clock ticks since boot of process `pid`, if it runs
+pub fn process_start(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name may contain spaces and parentheses, the fields after it don't. The start
+    // time is the 22nd field and the state, the 3rd, the first after the name.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Process at the other end of a Unix connection
+pub fn peer_pid(conn: &UnixStream) -> Option<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            conn.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (res == 0).then_some(cred.pid as u32)
+}
+
+fn shut_down(record: &VmRecord) {
+    debug!("shutting down vm {}", record.id);
+    unsafe {
+        libc::kill(record.pid as libc::pid_t, libc::SIGKILL);
+    }
+    remove_jail(record);
+}
+
+fn remove_jail(record: &VmRecord) {
+    if let Some(dir) = record.jail.as_ref() {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
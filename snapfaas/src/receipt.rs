@@ -0,0 +1,98 @@
+//! Signed declassification receipts.
+//!
+//! A receipt states that while running task `task`, the gate granting `gate` used that privilege
+//! to declassify data labeled `from` to `to`. Workers sign receipts as ES256 JWTs with the
+//! platform key and attach them to the task's result, so whoever receives declassified data can
+//! check which gate released it. Receipts carry `typ` set to `RECEIPT_TYPE`, so that other
+//! tokens signed with the platform key don't verify as receipts.
+use jwt::{PKeyWithDigest, SignWithKey, VerifyWithKey};
+use labeled::buckle::{Buckle, Component};
+use openssl::pkey::{PKey, Private, Public};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug)]
+pub enum ReceiptError {
+    BadSignature,
+    /// the token is not a receipt
+    WrongType,
+}
+
+/// `typ` of receipts
+pub const RECEIPT_TYPE: &str = "faasten-receipt";
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Receipt {
+    pub alg: String,
+    /// `RECEIPT_TYPE`, absent from tokens of other kinds
+    #[serde(default)]
+    pub typ: String,
+    pub iat: u64,
+    pub task: String,
+    pub gate: Component,
+    pub from: Buckle,
+    pub to: Buckle,
+}
+
+impl Receipt {
+    pub fn new(task: String, gate: Component, from: Buckle, to: Buckle) -> Self {
+        Receipt {
+            alg: "ES256".to_string(),
+            typ: RECEIPT_TYPE.to_string(),
+            iat: crate::delegation::now(),
+            task,
+            gate,
+            from,
+            to,
+        }
+    }
+
+    pub fn sign(&self, key: &PKey<Private>) -> String {
+        let key = PKeyWithDigest {
+            key: key.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        self.sign_with_key(&key).unwrap()
+    }
+
+    /// Check the signature and the type of a receipt. Receipts do not expire.
+    pub fn verify(token: &str, key: &PKey<Public>) -> Result<Self, ReceiptError> {
+        let key = PKeyWithDigest {
+            key: key.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        let receipt: Receipt = token
+            .verify_with_key(&key)
+            .map_err(|_| ReceiptError::BadSignature)?;
+        if receipt.typ != RECEIPT_TYPE {
+            return Err(ReceiptError::WrongType);
+        }
+        Ok(receipt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    #[test]
+    fn test_verify() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let public = EcKey::from_public_key(&group, key.public_key()).unwrap();
+        let private = PKey::from_ec_key(key).unwrap();
+        let public = PKey::from_ec_key(public).unwrap();
+        let from = Buckle::parse("alice,T").unwrap();
+        let gate = from.secrecy.clone();
+        let mut receipt = Receipt::new("task".to_string(), gate, from, Buckle::public());
+        let verified = Receipt::verify(&receipt.sign(&private), &public).unwrap();
+        assert_eq!(verified.task, "task");
+        // another kind of token signed with the same key
+        receipt.typ = String::new();
+        assert!(matches!(
+            Receipt::verify(&receipt.sign(&private), &public),
+            Err(ReceiptError::WrongType)
+        ));
+    }
+}
@@ -1,6 +1,7 @@
-use std::net::{SocketAddr, TcpStream};
 //use std::result::Result;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::time::SystemTime;
 //use std::sync::mpsc;
 //use std::sync::mpsc::{Receiver, Sender};
 
@@ -8,7 +9,11 @@ use log::{debug, error};
 //use serde::{Deserialize, Serialize};
 
 use crate::fs::Function;
-use crate::sched::{self, rpc::ResourceInfo};
+use crate::manifest::{self, Manifest};
+use crate::sched::{
+    self,
+    rpc::{ResourceInfo, VmInfo},
+};
 use crate::vm::Vm;
 
 //#[derive(Debug)]
@@ -27,31 +32,54 @@ pub struct ResourceManager {
     total_num_vms: usize, // total number of vms ever created
     total_mem: usize,
     free_mem: usize,
-    sched_conn: TcpStream,
+    // free passthrough devices by class, as PCI addresses
+    devices: HashMap<String, Vec<String>>,
+    // passthrough devices by class, free or not
+    total_devices: HashMap<String, usize>,
+    // most MBs idle VMs may hold
+    cache_budget: Option<usize>,
+    // seconds idle VMs live unless their function says otherwise
+    idle_ttl: Option<u64>,
+    pinning: Pinning,
+    // advertised to the scheduler, see `Function::requirements`
+    attributes: HashMap<String, String>,
+    max_vm_mem: Option<usize>,
+    // records launched VMs for a restarted worker to reattach to, see `manifest`
+    manifest: Option<Manifest>,
+    // hypervisor processes of the launched VMs, busy or idle, by VM id
+    pids: HashMap<usize, u32>,
+    // VMs an operator paused, see `pause`
+    paused: HashSet<usize>,
+    sched_conn: sched::rpc::Client,
 }
 
 impl ResourceManager {
     /// create and return a ResourceManager value
     /// The ResourceManager value encapsulates the idle lists and function configs
-    pub fn new(sched_addr: SocketAddr) -> Self {
+    pub fn new(sched_addr: &str) -> Self {
         // set default total memory to free memory on the machine
         let total_mem = crate::get_machine_memory();
-        let sched_conn = loop {
-            debug!(
-                "[localrm] trying to connect to the scheduler at {:?}",
-                sched_addr
-            );
-            if let Ok(conn) = TcpStream::connect(sched_addr) {
-                break conn;
-            }
-            std::thread::sleep(std::time::Duration::new(5, 0));
-        };
+        debug!(
+            "[localrm] trying to connect to the scheduler at {}",
+            sched_addr
+        );
+        let sched_conn = sched::rpc::connect_retrying(sched_addr);
         debug!("[localrm] connected.");
         Self {
             cache: Default::default(),
             total_num_vms: 0,
             total_mem,
             free_mem: total_mem,
+            devices: Default::default(),
+            total_devices: Default::default(),
+            cache_budget: None,
+            idle_ttl: None,
+            pinning: Pinning::default(),
+            attributes: Default::default(),
+            max_vm_mem: None,
+            manifest: None,
+            pids: Default::default(),
+            paused: Default::default(),
             sched_conn,
         }
         //let (sender, receiver) = mpsc::channel();
@@ -93,6 +121,94 @@ impl ResourceManager {
         self.total_mem
     }
 
+    /// Advertises the attribute `key` with `value`, e.g. `cpu` `icelake`, to the scheduler,
+    /// which only dispatches functions requiring attributes to nodes that have them
+    pub fn add_attribute(&mut self, key: String, value: String) {
+        self.attributes.insert(key, value);
+    }
+
+    /// Keeps the scheduler from dispatching functions needing VMs over `mb` MBs to the node
+    pub fn set_max_vm_mem(&mut self, mb: usize) {
+        self.max_vm_mem = Some(mb);
+    }
+
+    /// Makes the host device at `pci_addr` available to functions that need a device of `class`.
+    /// Like the total memory, devices should be added before the resource manager kicks off.
+    pub fn add_device(&mut self, class: String, pci_addr: String) {
+        *self.total_devices.entry(class.clone()).or_default() += 1;
+        self.devices.entry(class).or_default().push(pci_addr);
+    }
+
+    /// Records launched VMs in `manifest` from now on, after taking back the idle VMs a previous
+    /// worker recorded there. This should be called once, after the total memory and the
+    /// devices are set.
+    pub fn set_manifest(&mut self, mut manifest: Manifest) {
+        for vm in manifest.reattach(manifest::REATTACH_TIMEOUT) {
+            // the machine may have been configured differently for the previous worker
+            let free = |class: &String, addr: &String| {
+                self.devices.get(class).is_some_and(|l| l.contains(addr))
+            };
+            let fits = vm.function.memory <= self.free_mem
+                && vm.function.devices.iter().zip(&vm.devices).all(|(c, a)| free(c, a));
+            if !fits {
+                manifest.remove(vm.id);
+                continue;
+            }
+            self.free_mem -= vm.function.memory;
+            for (class, addr) in vm.function.devices.iter().zip(&vm.devices) {
+                self.devices.get_mut(class).unwrap().retain(|a| a != addr);
+            }
+            self.total_num_vms = self.total_num_vms.max(vm.id + 1);
+            self.pids.extend(vm.pid().map(|pid| (vm.id, pid)));
+            self.cache.entry(vm.function.clone()).or_default().push(vm);
+        }
+        self.manifest = Some(manifest);
+        self.update_scheduler();
+    }
+
+    /// Hypervisor processes of VMs the worker took over from a previous one, which the janitor
+    /// must not take for orphans
+    pub fn claimed_pids(&self) -> HashSet<u32> {
+        self.manifest.as_ref().map(|m| m.pids().collect()).unwrap_or_default()
+    }
+
+    /// Records `vm` as running an invocation, once it has been launched
+    pub fn launched(&mut self, vm: &Vm) {
+        self.pids.extend(vm.pid().map(|pid| (vm.id, pid)));
+        if let Some(manifest) = self.manifest.as_mut() {
+            manifest.insert(vm, false);
+        }
+    }
+
+    /// Freezes VM `id`, busy or idle, by stopping its hypervisor, which keeps the VM's memory
+    /// and state for inspection. A paused idle VM is not reused until resumed, though it is
+    /// still evicted when its memory is needed, and a paused busy VM's invocation may time out.
+    pub fn pause(&mut self, id: usize) -> io::Result<()> {
+        self.signal(id, libc::SIGSTOP)?;
+        self.paused.insert(id);
+        self.update_scheduler();
+        Ok(())
+    }
+
+    /// Resumes VM `id`, see `pause`
+    pub fn resume(&mut self, id: usize) -> io::Result<()> {
+        self.signal(id, libc::SIGCONT)?;
+        self.paused.remove(&id);
+        self.update_scheduler();
+        Ok(())
+    }
+
+    fn signal(&self, id: usize, signal: libc::c_int) -> io::Result<()> {
+        let pid = self
+            .pids
+            .get(&id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such running VM"))?;
+        if unsafe { libc::kill(*pid as libc::pid_t, signal) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
     ///// Kicks off the single thread resource manager
     //pub fn run(mut self) -> JoinHandle<()> {
     //    std::thread::spawn(move || {
@@ -128,29 +244,140 @@ impl ResourceManager {
     //    })
     //}
 
+    /// Keeps idle VMs from holding more than `mb` MBs, evicting the longest idle VMs first.
+    /// `None` caches idle VMs until their memory is needed. Pinned VMs don't count towards the
+    /// budget.
+    pub fn set_cache_budget(&mut self, mb: Option<usize>) {
+        self.cache_budget = mb;
+        if self.enforce_cache_budget() {
+            self.update_scheduler();
+        }
+    }
+
+    /// Shuts down idle VMs after `secs` seconds, unless their function sets its own idle TTL.
+    /// `None` keeps them until their memory is needed.
+    pub fn set_idle_ttl(&mut self, secs: Option<u64>) {
+        self.idle_ttl = secs;
+    }
+
+    /// Keeps up to `n` idle VMs per function from eviction for its `min_instances`, 1 if `None`
+    pub fn set_max_min_instances(&mut self, n: Option<usize>) {
+        self.pinning.max_min_instances = n.unwrap_or(DEFAULT_MAX_MIN_INSTANCES);
+        if self.enforce_cache_budget() {
+            self.update_scheduler();
+        }
+    }
+
+    /// Keeps up to `n` idle VMs per keep-alive function up between invocations, 1 if `None`
+    pub fn set_max_keep_alive(&mut self, n: Option<usize>) {
+        self.pinning.max_keep_alive = n.unwrap_or(DEFAULT_MAX_KEEP_ALIVE);
+        if self.enforce_cache_budget() {
+            self.update_scheduler();
+        }
+    }
+
+    /// Shuts down the idle VMs that outlived their idle TTL, except pinned ones, and returns
+    /// their memory to the free pool
+    pub fn reap_idle(&mut self) {
+        let pinning = self.pinning;
+        let now = SystemTime::now();
+        let default_ttl = self.idle_ttl;
+        let mut reaped = vec![];
+        for l in self.cache.values_mut() {
+            let Some(ttl) = l.first().and_then(|vm| vm.function.idle_ttl.or(default_ttl)) else {
+                continue;
+            };
+            let ttl = std::time::Duration::from_secs(ttl);
+            let expired = |vm: &Vm| now.duration_since(vm.idle_since).is_ok_and(|d| d >= ttl);
+            // the longest idle VMs come first, and reuse takes the last one
+            l.sort_by_key(|vm| vm.idle_since);
+            let n = l.iter().take_while(|vm| expired(vm)).count().min(l.len() - pinning.pinned(l));
+            reaped.extend(l.drain(..n));
+        }
+        if reaped.is_empty() {
+            return;
+        }
+        debug!("reaping {} idle vms", reaped.len());
+        for vm in reaped {
+            self.free(vm);
+        }
+        self.update_scheduler();
+    }
+
+    // Evicts idle VMs until they fit in the cache budget, true if any was evicted
+    fn enforce_cache_budget(&mut self) -> bool {
+        let Some(budget) = self.cache_budget else {
+            return false;
+        };
+        let pinning = self.pinning;
+        let mut cached: usize = self
+            .cache
+            .values()
+            .filter_map(|l| Some((l.len() - pinning.pinned(l), l.first()?.function.memory)))
+            .map(|(n, memory)| n * memory)
+            .sum();
+        let mut evicted = false;
+        while cached > budget {
+            let oldest = self
+                .cache
+                .values_mut()
+                .filter(|l| l.len() > pinning.pinned(l))
+                .filter_map(|l| {
+                    let i = (0..l.len()).min_by_key(|&i| l[i].idle_since)?;
+                    Some((l[i].idle_since, l, i))
+                })
+                .min_by_key(|(idle_since, _, _)| *idle_since);
+            let Some((_, l, i)) = oldest else {
+                break;
+            };
+            let vm = l.swap_remove(i);
+            cached -= vm.function.memory;
+            self.free(vm);
+            evicted = true;
+        }
+        evicted
+    }
+
     // Try to acquire an idle VM, otherwise try to allocate a new unlaunched VM.
     // If there's not enough resources on the machine to
     // allocate a new Vm, it will try to evict an idle Vm from another
     // function's idle list, and then allocate a new unlaunched VM.
     pub fn get_cached_vm(&mut self, f: &Function) -> Option<Vm> {
-        let ret = self.cache.get_mut(f).map_or(None, |l| l.pop());
+        // paused VMs stay put until resumed
+        let paused = &self.paused;
+        let ret = self.cache.get_mut(f).and_then(|l| {
+            let i = l.iter().rposition(|vm| !paused.contains(&vm.id))?;
+            Some(l.remove(i))
+        });
+        if let (Some(vm), Some(manifest)) = (ret.as_ref(), self.manifest.as_mut()) {
+            manifest.insert(vm, false);
+        }
         self.update_scheduler();
         ret
     }
 
     pub fn new_vm(&mut self, f: Function) -> Option<Vm> {
-        let ret = if self.try_allocate_memory(f.memory) {
-            Some(Vm::new(self.total_num_vms, f))
-        } else {
-            None
-        };
+        let ret = self.try_allocate_devices(&f.devices).and_then(|devices| {
+            if self.try_allocate_memory(f.memory) {
+                let mut vm = Vm::new(self.total_num_vms, f);
+                vm.devices = devices;
+                Some(vm)
+            } else {
+                self.free_devices(&f.devices, devices);
+                None
+            }
+        });
         self.update_scheduler();
         ret
     }
 
     // Push the VM into the VM cache
-    pub fn release(&mut self, vm: Vm) {
+    pub fn release(&mut self, mut vm: Vm) {
         debug!("release vm {:?}", vm.handle);
+        vm.idle_since = SystemTime::now();
+        if let Some(manifest) = self.manifest.as_mut() {
+            manifest.insert(&vm, true);
+        }
         if let Some(l) = self.cache.get_mut(&vm.function) {
             l.push(vm);
         } else {
@@ -158,26 +385,83 @@ impl ResourceManager {
             let l = vec![vm];
             let _ = self.cache.insert(k, l);
         }
+        self.enforce_cache_budget();
         self.update_scheduler();
     }
 
     pub fn delete(&mut self, vm: Vm) {
         debug!("delete vm {:?}", vm.handle);
+        self.free(vm);
+        self.update_scheduler();
+    }
+
+    // Returns the VM's memory and devices to the free pools
+    fn free(&mut self, mut vm: Vm) {
+        if let Some(manifest) = self.manifest.as_mut() {
+            manifest.remove(vm.id);
+        }
+        self.forget(vm.id);
         self.free_mem += vm.function.memory;
+        let devices = std::mem::take(&mut vm.devices);
+        self.free_devices(&vm.function.devices, devices);
         drop(vm); // being explicit
-        self.update_scheduler();
     }
 
-    fn update_scheduler(&mut self) {
+    // Forgets the hypervisor of VM `id`, which is being shut down
+    fn forget(&mut self, id: usize) {
+        self.pids.remove(&id);
+        self.paused.remove(&id);
+    }
+
+    /// Reports the node's resources to the scheduler, which also registers the node with a
+    /// scheduler that restarted
+    pub fn update_scheduler(&mut self) {
         let stats = self
             .cache
             .iter()
-            .map(|(k, v)| (k.clone(), v.len()))
+            .map(|(k, v)| {
+                let reusable = v.iter().filter(|vm| !self.paused.contains(&vm.id)).count();
+                (k.clone(), reusable)
+            })
             .collect();
+        let vms = self
+            .cache
+            .values()
+            .flatten()
+            .map(|vm| VmInfo {
+                id: vm.id,
+                function: vm.function.clone(),
+                label: vm.label.clone(),
+                memory: vm.function.memory,
+                idle_since: vm
+                    .idle_since
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                paused: self.paused.contains(&vm.id),
+            })
+            .collect();
+        let free_devices = self
+            .devices
+            .iter()
+            .map(|(class, l)| (class.clone(), l.len()))
+            .collect();
+        let pinning = self.pinning;
+        let reserved_mem = self
+            .cache
+            .values()
+            .filter_map(|l| Some(pinning.pinned(l) * l.first()?.function.memory))
+            .sum();
         let info = ResourceInfo {
             stats,
             total_mem: self.total_mem,
             free_mem: self.free_mem,
+            vms,
+            free_devices,
+            total_devices: self.total_devices.clone(),
+            reserved_mem,
+            attributes: self.attributes.clone(),
+            max_vm_mem: self.max_vm_mem,
         };
         let _ = sched::rpc::update_resource(&mut self.sched_conn, info);
     }
@@ -193,30 +477,118 @@ impl ResourceManager {
         }
     }
 
-    // Evict one or more vms to free `mem` MB of memory.
-    // The function only return false when the `mem` MB is larger than the total available memory,
-    // which is expected to never happen in a production system.
+    /// Takes one free device of each class in `classes`, evicting idle VMs that hold devices of
+    /// a class if none is free. Takes nothing if some class has no device left at all.
+    fn try_allocate_devices(&mut self, classes: &[String]) -> Option<Vec<String>> {
+        let mut taken = Vec::with_capacity(classes.len());
+        for (i, class) in classes.iter().enumerate() {
+            if self.devices.get(class).is_none_or(Vec::is_empty) {
+                self.evict_device_holder(class);
+            }
+            match self.devices.get_mut(class).and_then(Vec::pop) {
+                Some(addr) => taken.push(addr),
+                None => {
+                    self.free_devices(&classes[..i], taken);
+                    return None;
+                }
+            }
+        }
+        Some(taken)
+    }
+
+    fn free_devices(&mut self, classes: &[String], addrs: Vec<String>) {
+        for (class, addr) in classes.iter().zip(addrs) {
+            self.devices.entry(class.clone()).or_default().push(addr);
+        }
+    }
+
+    // Evict an idle vm holding a device of `class`, if any isn't pinned
+    fn evict_device_holder(&mut self, class: &String) {
+        let pinning = self.pinning;
+        let vm = self.cache.values_mut().find_map(|l| {
+            if l.len() <= pinning.pinned(l) {
+                return None;
+            }
+            l.iter()
+                .position(|vm| vm.function.devices.contains(class))
+                .map(|i| l.swap_remove(i))
+        });
+        if let Some(vm) = vm {
+            self.free(vm);
+        }
+    }
+
+    // Evict one or more vms to free `mem` MB of memory, never pinned ones.
+    // The function returns false when the `mem` MB is larger than the total available memory,
+    // which is expected to never happen in a production system, or when running and pinned VMs
+    // hold the memory.
     fn dummy_evict(&mut self, mem: usize) -> bool {
         if self.total_mem < mem {
             return false;
         }
-        let mut freed: usize = 0;
-        while freed < mem {
-            for l in self.cache.values_mut() {
-                // TODO instead of evicting from the first non-empty list in the map,
-                // collect some function popularity data and evict based on that.
-                // This is where some policies can be implemented.
-                if let Some(vm) = l.pop() {
-                    freed += vm.function.memory;
-                    self.free_mem += vm.function.memory;
-                    drop(vm); // being explicit
-                }
+        let pinning = self.pinning;
+        while self.free_mem < mem {
+            // TODO instead of evicting from the first non-empty list in the map,
+            // collect some function popularity data and evict based on that.
+            // This is where some policies can be implemented.
+            let Some(mut vm) = self
+                .cache
+                .values_mut()
+                .find(|l| l.len() > pinning.pinned(l))
+                .and_then(Vec::pop)
+            else {
+                return false;
+            };
+            if let Some(manifest) = self.manifest.as_mut() {
+                manifest.remove(vm.id);
+            }
+            self.forget(vm.id);
+            self.free_mem += vm.function.memory;
+            let devices = std::mem::take(&mut vm.devices);
+            for (class, addr) in vm.function.devices.iter().zip(devices) {
+                self.devices.entry(class.clone()).or_default().push(addr);
             }
+            drop(vm); // being explicit
         }
         true
     }
 }
 
+const DEFAULT_MAX_MIN_INSTANCES: usize = 1;
+const DEFAULT_MAX_KEEP_ALIVE: usize = 1;
+
+// Which idle VMs are kept from eviction
+#[derive(Debug, Clone, Copy)]
+struct Pinning {
+    max_min_instances: usize,
+    max_keep_alive: usize,
+}
+
+impl Default for Pinning {
+    fn default() -> Self {
+        Pinning {
+            max_min_instances: DEFAULT_MAX_MIN_INSTANCES,
+            max_keep_alive: DEFAULT_MAX_KEEP_ALIVE,
+        }
+    }
+}
+
+impl Pinning {
+    // Number of the idle VMs of a function, all in `l`, kept from eviction: up to the
+    // function's `min_instances` within its cap, or for a keep-alive function up to the
+    // keep-alive cap if that is more
+    fn pinned(&self, l: &[Vm]) -> usize {
+        match l.first() {
+            Some(vm) => {
+                let warm = vm.function.min_instances.min(self.max_min_instances);
+                let kept = if vm.function.keep_alive { self.max_keep_alive } else { 0 };
+                l.len().min(warm.max(kept))
+            }
+            None => 0,
+        }
+    }
+}
+
 impl Drop for ResourceManager {
     fn drop(&mut self) {
         for l in self.cache.values_mut() {
@@ -274,3 +646,32 @@ impl Drop for ResourceManager {
 //        self.num_vms.fetch_add(1, Ordering::Relaxed);
 //    }
 //}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pinned() {
+        let f = Function {
+            min_instances: 5,
+            ..Default::default()
+        };
+        let l: Vec<Vm> = (0..4).map(|id| Vm::new(id, f.clone())).collect();
+        let pinning = |max_min_instances, max_keep_alive| Pinning {
+            max_min_instances,
+            max_keep_alive,
+        };
+        assert_eq!(Pinning::default().pinned(&l), 1);
+        assert_eq!(pinning(0, 10).pinned(&l), 0);
+        assert_eq!(pinning(10, 0).pinned(&l), 4);
+        let f = Function {
+            keep_alive: true,
+            ..Default::default()
+        };
+        let l: Vec<Vm> = (0..4).map(|id| Vm::new(id, f.clone())).collect();
+        assert_eq!(Pinning::default().pinned(&l), 1);
+        assert_eq!(pinning(0, 2).pinned(&l), 2);
+        assert_eq!(pinning(0, 0).pinned(&l), 0);
+    }
+}
@@ -0,0 +1,49 @@
+//! Desired number of worker nodes, for external autoscalers
+//!
+//! The scheduler only reports how many nodes it wants through `GetScale`, adding or removing
+//! them is left to an autoscaler, such as the `autoscaler` binary, that knows the platform.
+//!
+//! Each invocation running or queued needs a share of a node: one over its function's
+//! `concurrency_target`, or the policy's default target for functions without one. When tasks
+//! arrive faster than they are dispatched, the backlog the queue builds up over the horizon is
+//! provisioned for as well.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::fs::Function;
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// invocations a node runs at once for functions without a concurrency target
+    pub default_target: usize,
+    pub min_workers: usize,
+    pub max_workers: usize,
+    /// how far ahead to provision for a growing queue
+    pub horizon: Duration,
+}
+
+impl Policy {
+    /// Nodes needed for `running` and `queued` invocations per function, with tasks arriving
+    /// and being dispatched at `arrival_rate` and `dispatch_rate` per second
+    pub fn desired_workers(
+        &self,
+        running: &HashMap<Function, usize>,
+        queued: &HashMap<Function, usize>,
+        arrival_rate: f64,
+        dispatch_rate: f64,
+    ) -> usize {
+        let target = |f: &Function| match f.concurrency_target {
+            0 => self.default_target.max(1),
+            n => n,
+        } as f64;
+        let demand: f64 = running
+            .iter()
+            .chain(queued.iter())
+            .map(|(f, n)| *n as f64 / target(f))
+            .sum();
+        let growth = (arrival_rate - dispatch_rate).max(0.0) * self.horizon.as_secs_f64()
+            / self.default_target.max(1) as f64;
+        let desired = (demand + growth).ceil() as usize;
+        desired.clamp(self.min_workers, self.max_workers.max(self.min_workers))
+    }
+}
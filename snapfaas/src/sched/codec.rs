@@ -0,0 +1,151 @@
+//! Protobuf codec for the scheduler service that zstd compresses large messages
+//!
+//! Every message is prefixed with a byte telling whether it is compressed, so a receiver always
+//! understands what it is sent and each side decides on its own whether to compress.
+//!
+//! Messages, compressed or not, are limited to `max_message_size` bytes, and decompression
+//! stops there, so a small compressed message cannot make its receiver allocate without bound.
+use std::io::Read;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use bytes::{Buf, BufMut};
+use prost::Message;
+use tonic::codec::{DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::Status;
+
+/// Messages at least this large are compressed when compression is enabled
+pub const COMPRESSION_THRESHOLD: usize = 4096;
+
+const RAW: u8 = 0;
+const ZSTD: u8 = 1;
+
+/// Default of `max_message_size`
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+static COMPRESSION_ENABLED: AtomicBool = AtomicBool::new(false);
+static MAX_MESSAGE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_MESSAGE_SIZE);
+
+/// Makes this process compress large messages it sends. Off by default.
+pub fn enable_compression() {
+    COMPRESSION_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Limits the messages this process sends and receives to `bytes`, decompressed
+pub fn set_max_message_size(bytes: usize) {
+    MAX_MESSAGE_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+/// Largest message in bytes this process sends or receives, decompressed
+pub fn max_message_size() -> usize {
+    MAX_MESSAGE_SIZE.load(Ordering::Relaxed)
+}
+
+// Decompresses `src`, failing if it decompresses to more than `limit` bytes
+fn decompress(src: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let decoder = zstd::stream::Decoder::new(src).map_err(|e| e.to_string())?;
+    let mut buf = Vec::new();
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut buf)
+        .map_err(|e| e.to_string())?;
+    if buf.len() > limit {
+        return Err(format!("decompressed message larger than {} bytes", limit));
+    }
+    Ok(buf)
+}
+
+#[derive(Debug)]
+pub struct Codec<T, U>(PhantomData<(T, U)>);
+
+impl<T, U> Default for Codec<T, U> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T, U> tonic::codec::Codec for Codec<T, U>
+where
+    T: Message + Send + 'static,
+    U: Message + Default + Send + 'static,
+{
+    type Encode = T;
+    type Decode = U;
+    type Encoder = MessageEncoder<T>;
+    type Decoder = MessageDecoder<U>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        MessageEncoder(PhantomData)
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        MessageDecoder(PhantomData)
+    }
+}
+
+#[derive(Debug)]
+pub struct MessageEncoder<T>(PhantomData<T>);
+
+impl<T: Message> Encoder for MessageEncoder<T> {
+    type Item = T;
+    type Error = Status;
+
+    fn encode(&mut self, item: T, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        let buf = item.encode_to_vec();
+        if buf.len() >= COMPRESSION_THRESHOLD && COMPRESSION_ENABLED.load(Ordering::Relaxed) {
+            if let Ok(compressed) = zstd::encode_all(buf.as_slice(), 0) {
+                if compressed.len() < buf.len() {
+                    dst.put_u8(ZSTD);
+                    dst.put_slice(&compressed);
+                    return Ok(());
+                }
+            }
+        }
+        dst.put_u8(RAW);
+        dst.put_slice(&buf);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MessageDecoder<U>(PhantomData<U>);
+
+impl<U: Message + Default> Decoder for MessageDecoder<U> {
+    type Item = U;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<U>, Status> {
+        if !src.has_remaining() {
+            return Err(Status::internal("missing compression flag"));
+        }
+        let flag = src.get_u8();
+        let buf = src.copy_to_bytes(src.remaining());
+        let decoded = match flag {
+            RAW => U::decode(buf),
+            ZSTD => {
+                let buf = decompress(buf.as_ref(), max_message_size())
+                    .map_err(Status::resource_exhausted)?;
+                U::decode(buf.as_slice())
+            }
+            _ => return Err(Status::internal("unknown compression flag")),
+        };
+        decoded
+            .map(Some)
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress() {
+        let data = vec![0u8; 1 << 20];
+        let compressed = zstd::encode_all(data.as_slice(), 0).unwrap();
+        assert_eq!(decompress(&compressed, 1 << 20).unwrap(), data);
+        // a message that decompresses past the limit fails without growing further
+        assert!(decompress(&compressed, 4096).unwrap_err().contains("larger than"));
+        assert!(decompress(b"not zstd", 4096).is_err());
+    }
+}
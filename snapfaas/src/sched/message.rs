@@ -1,41 +1,185 @@
 include!(concat!(env!("OUT_DIR"), "/snapfaas.sched.messages.rs"));
 
-use prost::Message;
+include!(concat!(env!("OUT_DIR"), "/snapfaas.sched.Scheduler.rs"));
+
+use std::io::Write;
+
+use labeled::buckle::{Buckle, Component};
+use labeled::HasPrivilege;
 use rouille;
-use std::io::{Read, Write};
-use std::net::TcpStream;
 
-use super::Error;
+use crate::blobstore::Blobstore;
+use crate::fs::{FsError, GateError};
+
+impl LabeledInvoke {
+    /// Moves a payload larger than `limit` bytes into a blob, unless `limit` is 0. Workers read
+    /// it back before running the function.
+    pub fn spill_payload(
+        &mut self,
+        blobstore: &mut Blobstore,
+        limit: usize,
+    ) -> std::io::Result<()> {
+        if limit > 0 && self.payload.len() > limit {
+            let mut newblob = blobstore.create()?;
+            if let Err(e) = newblob.write_all(&self.payload) {
+                let _ = blobstore.abort(newblob);
+                return Err(e);
+            }
+            let (blob, created) = blobstore.save_new(newblob)?;
+            self.payload_blob = blob.name;
+            self.payload_blob_transient = created;
+            self.payload.clear();
+        }
+        Ok(())
+    }
+}
+
+impl TaskReturn {
+    /// Keeps only the part of the taint trace an invoker at `label` with `privilege` may see:
+    /// the raises to labels that flow to it, then the first raise past it without the label it
+    /// raised to. Later raises happened at levels the invoker can't observe.
+    pub fn redact_taint_trace(&mut self, label: &Buckle, privilege: &Component) {
+        let visible = |l: &Option<crate::syscalls::Buckle>| {
+            l.clone().is_none_or(|l| {
+                Into::<Buckle>::into(l).can_flow_to_with_privilege(label, privilege)
+            })
+        };
+        let mut kept = Vec::new();
+        for mut raise in std::mem::take(&mut self.taint_trace) {
+            if visible(&raise.to) {
+                kept.push(raise);
+                continue;
+            }
+            if visible(&raise.from) {
+                raise.to = None;
+                kept.push(raise);
+            }
+            break;
+        }
+        self.taint_trace = kept;
+    }
+}
+
+impl ReturnCode {
+    /// Stable name clients can match on, e.g. `queue-full`
+    pub fn name(self) -> &'static str {
+        match self {
+            ReturnCode::ResourceExhausted => "resource-exhausted",
+            ReturnCode::LaunchFailed => "launch-failed",
+            ReturnCode::ProcessRequestFailed => "process-request-failed",
+            ReturnCode::GateNotExist => "gate-not-found",
+            ReturnCode::Success => "success",
+            ReturnCode::QueueFull => "queue-full",
+            ReturnCode::QueueShed => "queue-shed",
+            ReturnCode::QueueTimeout => "queue-timeout",
+            ReturnCode::FunctionError => "function-error",
+            ReturnCode::Unauthorized => "unauthorized",
+            ReturnCode::ClearanceDenied => "clearance-denied",
+            ReturnCode::GuestCrash => "guest-crash",
+            ReturnCode::Timeout => "timeout",
+            ReturnCode::OutOfMemory => "out-of-memory",
+            ReturnCode::Preempted => "preempted",
+            ReturnCode::HypervisorExited => "hypervisor-exited",
+            ReturnCode::Unschedulable => "unschedulable",
+        }
+    }
 
-// respond 500 if fail to start the execution of the requested gate; otherwise, act as a passthrough, i.e., respond whatever the execution responds.
+    pub fn description(self) -> &'static str {
+        match self {
+            ReturnCode::ResourceExhausted => "resource exhausted",
+            ReturnCode::LaunchFailed => "failed to launch the VM",
+            ReturnCode::ProcessRequestFailed => "failed to process request",
+            ReturnCode::GateNotExist => "gate does not exist",
+            ReturnCode::Success => "success",
+            ReturnCode::QueueFull => "queue full",
+            ReturnCode::QueueShed => "shed by a higher priority request",
+            ReturnCode::QueueTimeout => "timed out waiting for queue space",
+            ReturnCode::FunctionError => "function error",
+            ReturnCode::Unauthorized => "unauthorized to read response",
+            ReturnCode::ClearanceDenied => "not cleared to invoke the gate",
+            ReturnCode::GuestCrash => "the function's VM crashed",
+            ReturnCode::Timeout => "the function timed out",
+            ReturnCode::OutOfMemory => "the function ran out of memory",
+            ReturnCode::Preempted => "preempted by a more urgent invocation",
+            ReturnCode::HypervisorExited => "the function's hypervisor exited",
+            ReturnCode::Unschedulable => "no worker node can run the function",
+        }
+    }
+
+    /// The HTTP status of a failed invocation. Successful invocations answer with the function's
+    /// status.
+    pub fn http_status(self) -> u16 {
+        match self {
+            ReturnCode::Success => 200,
+            ReturnCode::GateNotExist => 404,
+            ReturnCode::Unauthorized | ReturnCode::ClearanceDenied => 403,
+            // the platform is busy, retrying later may succeed
+            ReturnCode::ResourceExhausted
+            | ReturnCode::QueueFull
+            | ReturnCode::QueueShed
+            | ReturnCode::Preempted
+            | ReturnCode::Unschedulable => 503,
+            ReturnCode::QueueTimeout | ReturnCode::Timeout => 504,
+            // something between the platform and the function broke
+            ReturnCode::LaunchFailed
+            | ReturnCode::GuestCrash
+            | ReturnCode::HypervisorExited => 502,
+            ReturnCode::ProcessRequestFailed
+            | ReturnCode::FunctionError
+            | ReturnCode::OutOfMemory => 500,
+        }
+    }
+
+    /// The code of an invocation whose gate fails to resolve with `e`
+    pub fn for_gate_error(e: &FsError) -> Self {
+        match e {
+            FsError::GateError(GateError::CannotInvoke) | FsError::ClearanceError => {
+                ReturnCode::ClearanceDenied
+            }
+            _ => ReturnCode::GateNotExist,
+        }
+    }
+
+    /// The JSON error response for a failed invocation
+    pub fn error_response(self) -> rouille::Response {
+        rouille::Response::json(&serde_json::json!({
+            "error": self.description(),
+            "code": self.name(),
+        }))
+        .with_status_code(self.http_status())
+    }
+}
+
+// respond with the code's status if the invocation fails; otherwise, act as a passthrough, i.e.,
+// respond whatever the execution responds.
 impl From<TaskReturn> for rouille::Response {
     fn from(tr: TaskReturn) -> rouille::Response {
         use rouille::Response;
         let mut resp = match ReturnCode::from_i32(tr.code) {
-            Some(ReturnCode::QueueFull) => Response::json(&serde_json::json!({
-                "error": "queue full"
-            }))
-            .with_status_code(500),
-            Some(ReturnCode::LaunchFailed) => Response::json(&serde_json::json!({
-                "error": "failed to launch the VM"
-            }))
-            .with_status_code(500),
-            Some(ReturnCode::GateNotExist) => Response::json(&serde_json::json!({
-                "error": "gate does not exist"
-            }))
-            .with_status_code(500),
-            Some(ReturnCode::ResourceExhausted) => Response::json(&serde_json::json!({
-                "error": "resource exhausted"
-            }))
-            .with_status_code(500),
-            Some(ReturnCode::ProcessRequestFailed) => Response::json(&serde_json::json!({
-                "error": "failed to process request"
-            }))
-            .with_status_code(500),
+            Some(ReturnCode::FunctionError) => {
+                let error = tr.error.unwrap_or_default();
+                Response::json(&serde_json::json!({
+                    "error": "function error",
+                    "code": ReturnCode::FunctionError.name(),
+                    "reason": error.reason,
+                    "traceback": error.traceback,
+                }))
+                .with_status_code(ReturnCode::FunctionError.http_status())
+            }
             Some(ReturnCode::Success) => Response::from_data(
                 "application/octet-stream",
                 tr.payload.as_ref().unwrap().body(),
             ),
+            // platform failures may come with what exactly went wrong
+            Some(code) => match tr.error.as_ref() {
+                Some(error) => Response::json(&serde_json::json!({
+                    "error": code.description(),
+                    "code": code.name(),
+                    "reason": error.reason,
+                }))
+                .with_status_code(code.http_status()),
+                None => code.error_response(),
+            },
             None => Response::json(&serde_json::json!({
                 "error": "unknown return code"
             }))
@@ -45,60 +189,52 @@ impl From<TaskReturn> for rouille::Response {
         if resp.is_success() {
             resp = resp.with_status_code(tr.payload.unwrap().status_code as u16);
         }
+        for receipt in tr.receipts {
+            resp = resp.with_additional_header("X-Faasten-Receipt", receipt);
+        }
         resp
     }
 }
 
-fn _read_u8(stream: &mut TcpStream, allow_empty: bool) -> Result<Vec<u8>, Error> {
-    let mut lenbuf = [0; 8];
-    stream
-        .read_exact(&mut lenbuf)
-        .map_err(|e| Error::StreamRead(e))?;
-    let size = u64::from_be_bytes(lenbuf);
-    if allow_empty || size > 0 {
-        let mut buf = vec![0u8; size as usize];
-        stream
-            .read_exact(&mut buf)
-            .map_err(|e| Error::StreamRead(e))?;
-        Ok(buf)
-    } else {
-        Err(Error::Other("Empty Payload".to_string()))
-    }
-}
-
-/// Function that reads bytes from a stream
-pub fn read_u8(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
-    _read_u8(stream, false)
-}
-
-/// Function that writes bytes to a stream
-pub fn write_u8(stream: &mut TcpStream, msg: &[u8]) -> Result<(), Error> {
-    let size = (msg.len() as u64).to_be_bytes();
-    stream.write_all(&size).map_err(|e| Error::StreamWrite(e))?;
-    stream.write_all(msg).map_err(|e| Error::StreamWrite(e))?;
-    Ok(())
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-/// Wrapper function that sends a message
-pub fn write<T: Message>(stream: &mut TcpStream, msg: &T) -> Result<(), Error> {
-    let buf = msg.encode_to_vec();
-    write_u8(stream, &buf)
-}
-
-/// Wrapper function that reads a message
-pub fn read<T: Message + Default>(stream: &mut TcpStream) -> Result<T, Error> {
-    let buf = _read_u8(stream, true)?;
-    T::decode(&buf[..]).map_err(Error::Rpc)
-}
+    fn raise(from: &str, to: &str) -> LabelRaise {
+        LabelRaise {
+            syscall: "read".to_string(),
+            path: String::new(),
+            from: Some(Buckle::parse(from).unwrap().into()),
+            to: Some(Buckle::parse(to).unwrap().into()),
+        }
+    }
 
-/// Wrapper function that reads a request
-pub fn read_request(stream: &mut TcpStream) -> Result<Request, Error> {
-    let buf = _read_u8(stream, true)?;
-    Request::decode(&buf[..]).map_err(|e| Error::Rpc(e))
-}
+    #[test]
+    fn test_redact_taint_trace() {
+        let mut tr = TaskReturn {
+            taint_trace: vec![
+                raise("T,T", "alice,T"),
+                raise("alice,T", "alice&bob,T"),
+                raise("alice&bob,T", "alice&bob&carol,T"),
+            ],
+            ..Default::default()
+        };
+        tr.redact_taint_trace(&Buckle::parse("alice,T").unwrap(), &Component::dc_true());
+        assert_eq!(tr.taint_trace.len(), 2);
+        assert!(tr.taint_trace[0].to.is_some());
+        assert!(tr.taint_trace[1].from.is_some());
+        assert!(tr.taint_trace[1].to.is_none());
+    }
 
-/// Wrapper function that reads a response
-pub fn read_response(stream: &mut TcpStream) -> Result<Response, Error> {
-    let buf = _read_u8(stream, true)?;
-    Response::decode(&buf[..]).map_err(|e| Error::Rpc(e))
+    #[test]
+    fn test_redact_taint_trace_with_privilege() {
+        let mut tr = TaskReturn {
+            taint_trace: vec![raise("T,T", "alice,T"), raise("alice,T", "alice&bob,T")],
+            ..Default::default()
+        };
+        let bob = Buckle::parse("bob,T").unwrap().secrecy;
+        tr.redact_taint_trace(&Buckle::parse("alice,T").unwrap(), &bob);
+        assert_eq!(tr.taint_trace.len(), 2);
+        assert!(tr.taint_trace.iter().all(|r| r.to.is_some()));
+    }
 }
@@ -1,114 +1,190 @@
+pub mod autoscale;
+pub mod codec;
 pub mod message;
+pub mod queue;
+pub mod resolver;
 pub mod resource_manager;
 pub mod rpc;
 pub mod rpc_server;
+pub mod simulate;
 
-use log::error;
+use log::{error, warn};
 use message::LabeledInvoke;
-use std::{
-    net::{SocketAddr, TcpStream},
-    str::FromStr,
-    sync::{mpsc::Sender, Arc, Condvar, Mutex},
-};
+use std::sync::{mpsc::Sender, Arc, Condvar, Mutex};
+use std::time::Duration;
 use uuid::Uuid;
 
+use crate::fs::Function;
+
+use self::queue::TaskQueue;
 use self::resource_manager::ResourceManager;
 
 pub type RequestInfo = (message::LabeledInvoke, Sender<String>);
 
+// how often parked gangs are checked for enough idle workers
+const GANG_RECHECK: Duration = Duration::from_millis(50);
+
 #[derive(Debug)]
 pub enum Error {
-    Rpc(prost::DecodeError),
-    TaskSend(std::sync::mpsc::SendError<Task>),
-    StreamConnect(std::io::Error),
-    StreamRead(std::io::Error),
-    StreamWrite(std::io::Error),
+    Transport(tonic::transport::Error),
+    Status(tonic::Status),
+    Timeout,
     Other(String),
 }
 
 #[derive(Debug)]
 pub enum Task {
-    Invoke(Uuid, LabeledInvoke),
+    Invoke(Uuid, Box<LabeledInvoke>),
+    /// invocations dispatched together once there are idle workers for the given number of them
+    Gang(Vec<(Uuid, Box<LabeledInvoke>)>, usize),
     Terminate,
 }
 
-/// simple fifo
-pub fn schedule(
-    queue_rx: crossbeam::channel::Receiver<Task>,
-    manager: Arc<Mutex<ResourceManager>>,
-    cvar: Arc<Condvar>,
-) {
-    while let Ok(task) = queue_rx.recv() {
-        let f = match &task {
-            Task::Invoke(_, li) => li.function.as_ref().unwrap().clone().into(),
-            _ => panic!("Unexpected task {:?}", task),
-        };
-        use message::response::Kind as ResKind;
-        // we might be given broken stream, so if message::write fail
-        // we loop to try again
-        loop {
-            let mut maybe_worker: Option<resource_manager::Worker>;
-            {
-                // wait till there is an idle worker.
-                let mut manager = manager.lock().unwrap();
-                loop {
-                    maybe_worker = manager.find_idle(&f);
-                    if maybe_worker.is_none() {
-                        manager = cvar.wait(manager).unwrap();
-                    } else {
-                        break;
-                    }
+impl Task {
+    /// ids of the invocations in the task
+    pub fn ids(&self) -> Vec<Uuid> {
+        match self {
+            Task::Invoke(uuid, _) => vec![*uuid],
+            Task::Gang(members, _) => members.iter().map(|(uuid, _)| *uuid).collect(),
+            Task::Terminate => vec![],
+        }
+    }
+}
+
+/// fifo within each priority. Gangs without enough idle workers for their quorum are parked,
+/// so that the tasks behind them keep being dispatched, and go first once they have them.
+pub fn schedule(queue: Arc<TaskQueue>, manager: Arc<Mutex<ResourceManager>>, cvar: Arc<Condvar>) {
+    loop {
+        for task in queue.unpark() {
+            if let Task::Gang(members, quorum) = task {
+                if let Some(task) = dispatch_gang(&manager, &cvar, members, quorum) {
+                    queue.park(task);
                 }
             }
-            let mut worker = maybe_worker.unwrap();
-            match &task {
-                Task::Invoke(uuid, labeled_invoke) => {
-                    let res = message::Response {
-                        kind: Some(ResKind::ProcessTask(message::ProcessTask {
-                            task_id: uuid.to_string(),
-                            labeled_invoke: Some(labeled_invoke.clone()),
-                        })),
-                    };
-                    if let Err(e) = message::write(&mut worker.conn, &res) {
-                        error!("{:?}. try again.", e);
-                    } else {
-                        break;
-                    }
+        }
+        let task = if queue.has_parked() {
+            match queue.pop_timeout(GANG_RECHECK) {
+                Some(task) => task,
+                None => continue,
+            }
+        } else {
+            queue.pop()
+        };
+        match task {
+            Task::Invoke(uuid, labeled_invoke) => dispatch(&manager, &cvar, uuid, labeled_invoke),
+            Task::Gang(members, quorum) => {
+                if let Some(task) = dispatch_gang(&manager, &cvar, members, quorum) {
+                    queue.park(task);
                 }
-                _ => panic!("Unexpected task {:?}", task),
             }
-        } // retry loop upon message::write failure
-    }
-}
-
-#[derive(Debug)]
-pub struct Scheduler {
-    addr: SocketAddr,
-}
-
-impl Scheduler {
-    pub fn new(addr: &str) -> Self {
-        Self {
-            addr: SocketAddr::from_str(addr).unwrap(),
+            task => panic!("Unexpected task {:?}", task),
         }
     }
 }
 
-impl r2d2::ManageConnection for Scheduler {
-    type Connection = TcpStream;
-    type Error = std::io::Error;
-
-    fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        Ok(TcpStream::connect(&self.addr)?)
+/// Dispatches the gang if idle workers can take its quorum of members, or hands it back if
+/// they can't yet. Gangs whose quorum the nodes could never run at once fail.
+fn dispatch_gang(
+    manager: &Mutex<ResourceManager>,
+    cvar: &Condvar,
+    members: Vec<(Uuid, Box<LabeledInvoke>)>,
+    quorum: usize,
+) -> Option<Task> {
+    let functions: Vec<Function> = members
+        .iter()
+        .map(|(_, li)| li.function.as_ref().unwrap().clone().into())
+        .collect();
+    {
+        let mut manager = manager.lock().unwrap();
+        if manager.gang_capacity(&functions).is_some_and(|capacity| capacity < quorum) {
+            warn!("nodes can't run {} members of a gang at once, failing it", quorum);
+            for (uuid, _) in members.iter() {
+                manager.fail(*uuid, message::ReturnCode::Unschedulable);
+            }
+            return None;
+        }
+        if manager.gang_idle(&functions) < quorum {
+            return Some(Task::Gang(members, quorum));
+        }
     }
-
-    fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        use std::io::{Error, ErrorKind};
-        self::rpc::ping(conn).map_err(|e| Error::new(ErrorKind::Other, format!("{:?}", e)))?;
-        Ok(())
+    // members beyond the quorum wait for workers like any other invocation
+    for (uuid, labeled_invoke) in members {
+        dispatch(manager, cvar, uuid, labeled_invoke);
     }
+    None
+}
 
-    fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        conn.take_error().ok().flatten().is_some()
-    }
+/// Sends the invocation to an idle worker, waiting for one if there is none. Invocations no
+/// node can run fail with `Unschedulable` rather than wait for a worker that never comes.
+fn dispatch(
+    manager: &Mutex<ResourceManager>,
+    cvar: &Condvar,
+    uuid: Uuid,
+    labeled_invoke: Box<LabeledInvoke>,
+) {
+    let f = labeled_invoke.function.as_ref().unwrap().clone().into();
+    manager.lock().unwrap().require_instances(&f);
+    let priority = labeled_invoke.priority;
+    // whether the task has made room for itself by preempting another
+    let mut preempted = false;
+    // whether nodes have been told to read the function's images while the task waits
+    let mut prefetched = false;
+    use message::response::Kind as ResKind;
+    // the worker might have gone away since it asked for a task, in which case we try
+    // another one
+    loop {
+        let mut maybe_worker: Option<resource_manager::Worker>;
+        {
+            // wait till there is an idle worker.
+            let mut manager = manager.lock().unwrap();
+            loop {
+                maybe_worker = manager.find_idle(&f);
+                if maybe_worker.is_none() {
+                    if !manager.schedulable(&f) {
+                        warn!("no node can run {:?}, failing {:?}", f, uuid);
+                        manager.fail(uuid, message::ReturnCode::Unschedulable);
+                        return;
+                    }
+                    // a VM is being booted for it, no need to make room or warm a node
+                    if manager.speculating(&f) {
+                        manager = cvar.wait(manager).unwrap();
+                        continue;
+                    }
+                    if !preempted {
+                        preempted = manager.preempt(priority);
+                    }
+                    if !prefetched {
+                        manager.prefetch(&f);
+                        prefetched = true;
+                    }
+                    manager = cvar.wait(manager).unwrap();
+                } else {
+                    break;
+                }
+            }
+        }
+        let worker = maybe_worker.unwrap();
+        let res = message::Response {
+            kind: Some(ResKind::ProcessTask(message::ProcessTask {
+                task_id: uuid.to_string(),
+                labeled_invoke: Some(labeled_invoke.as_ref().clone()),
+            })),
+        };
+        // track it before the worker can watch for its preemption or finish it
+        {
+            let mut manager = manager.lock().unwrap();
+            manager.start_running(&f);
+            if labeled_invoke.preemptible {
+                manager.add_preemptible(uuid, labeled_invoke.clone(), worker.addr.ip());
+            }
+        }
+        if worker.tx.send(res).is_err() {
+            error!("worker {:?} is gone. try again.", worker.addr);
+            let mut manager = manager.lock().unwrap();
+            manager.stop_running(&f);
+            manager.finish_preemptible(uuid);
+        } else {
+            break;
+        }
+    } // retry loop upon a worker that is gone
 }
@@ -0,0 +1,346 @@
+//! Bounded task queue with configurable admission control
+//!
+//! Tasks are dequeued by priority (higher first) and in FIFO order among tasks of the same
+//! priority. When the queue is at capacity, the admission policy decides what happens to a new
+//! task.
+//!
+//! The queue lives in memory. A scheduler shutting down saves what is left in it with
+//! `save_snapshot` and queues it again on the next start, so that a restart doesn't lose queued
+//! invocations. Invokers waiting on sync invocations are gone by then, so those run as async
+//! ones.
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use prost::Message;
+use uuid::Uuid;
+
+use super::message::{QueueSnapshot, QueuedTask};
+use super::Task;
+use crate::fs::Function;
+
+/// What to do with a new task when the queue is full
+#[derive(Debug, Clone, Copy)]
+pub enum AdmissionPolicy {
+    /// reject the new task
+    Reject,
+    /// drop the newest task of the lowest priority if it has a lower priority than the new task,
+    /// otherwise reject the new task
+    ShedLowestPriority,
+    /// wait up to the deadline for space in the queue, then reject the new task
+    BlockWithDeadline(Duration),
+}
+
+#[derive(Debug)]
+pub enum AdmissionError {
+    Full,
+    Shed,
+    Timeout,
+}
+
+// weight of the newest sample in the moving average of queueing delays
+const WAIT_EWMA_WEIGHT: f64 = 0.2;
+// how far back arrival and dispatch rates look
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Inner {
+    tasks: BTreeMap<(u32, Reverse<u64>), (Instant, Task)>,
+    seq: u64,
+    // moving average of how long dequeued tasks waited, in seconds
+    avg_wait: f64,
+    arrivals: Rate,
+    dispatches: Rate,
+    // gangs dequeued before there were idle workers for their quorum, oldest first
+    parked: Vec<Task>,
+}
+
+// Events over the last `RATE_WINDOW`, counted per second
+#[derive(Default)]
+struct Rate {
+    // start of each second with events and their number, oldest first
+    counts: VecDeque<(Instant, u64)>,
+}
+
+impl Rate {
+    fn record(&mut self) {
+        let now = Instant::now();
+        match self.counts.back_mut() {
+            Some((start, n)) if now.duration_since(*start) < Duration::from_secs(1) => *n += 1,
+            _ => self.counts.push_back((now, 1)),
+        }
+    }
+
+    // events per second
+    fn per_sec(&mut self) -> f64 {
+        while let Some((start, _)) = self.counts.front() {
+            if start.elapsed() <= RATE_WINDOW {
+                break;
+            }
+            self.counts.pop_front();
+        }
+        self.counts.iter().map(|(_, n)| n).sum::<u64>() as f64 / RATE_WINDOW.as_secs_f64()
+    }
+}
+
+pub struct TaskQueue {
+    inner: Mutex<Inner>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: AdmissionPolicy,
+}
+
+fn priority(task: &Task) -> u32 {
+    match task {
+        Task::Invoke(_, li) => li.priority,
+        Task::Gang(members, _) => members.iter().map(|(_, li)| li.priority).max().unwrap_or(0),
+        Task::Terminate => u32::MAX,
+    }
+}
+
+impl TaskQueue {
+    pub fn new(capacity: usize, policy: AdmissionPolicy) -> Self {
+        Self {
+            inner: Default::default(),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    /// Enqueues `task`. On success, returns the task shed to make room for it, if any.
+    pub fn push(&self, task: Task) -> Result<Option<Task>, AdmissionError> {
+        let priority = priority(&task);
+        let mut inner = self.inner.lock().unwrap();
+        let mut victim = None;
+        if inner.tasks.len() >= self.capacity {
+            match self.policy {
+                AdmissionPolicy::Reject => return Err(AdmissionError::Full),
+                AdmissionPolicy::ShedLowestPriority => {
+                    match inner.tasks.keys().next().cloned() {
+                        Some(lowest) if lowest.0 < priority => {
+                            victim = inner.tasks.remove(&lowest).map(|(_, t)| t);
+                        }
+                        _ => return Err(AdmissionError::Shed),
+                    }
+                }
+                AdmissionPolicy::BlockWithDeadline(deadline) => {
+                    let deadline = Instant::now() + deadline;
+                    while inner.tasks.len() >= self.capacity {
+                        let now = Instant::now();
+                        if now >= deadline {
+                            return Err(AdmissionError::Timeout);
+                        }
+                        inner = self.not_full.wait_timeout(inner, deadline - now).unwrap().0;
+                    }
+                }
+            }
+        }
+        let seq = inner.seq;
+        inner.seq += 1;
+        inner.arrivals.record();
+        inner.tasks.insert((priority, Reverse(seq)), (Instant::now(), task));
+        self.not_empty.notify_one();
+        Ok(victim)
+    }
+
+    /// Dequeues the oldest task of the highest priority, blocking until there is one.
+    pub fn pop(&self) -> Task {
+        self.pop_until(None).unwrap()
+    }
+
+    /// Like `pop`, but gives up once `timeout` has passed without a task
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<Task> {
+        self.pop_until(Some(Instant::now() + timeout))
+    }
+
+    fn pop_until(&self, deadline: Option<Instant>) -> Option<Task> {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            let highest = inner.tasks.keys().next_back().cloned();
+            if let Some((enqueued, task)) = highest.and_then(|k| inner.tasks.remove(&k)) {
+                let wait = enqueued.elapsed().as_secs_f64();
+                inner.avg_wait = WAIT_EWMA_WEIGHT * wait + (1.0 - WAIT_EWMA_WEIGHT) * inner.avg_wait;
+                inner.dispatches.record();
+                self.not_full.notify_one();
+                return Some(task);
+            }
+            inner = match deadline {
+                None => self.not_empty.wait(inner).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.not_empty.wait_timeout(inner, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Sets aside a dequeued task the scheduler cannot dispatch yet, such as a gang waiting for
+    /// idle workers. Parked tasks don't count against the capacity.
+    pub fn park(&self, task: Task) {
+        self.inner.lock().unwrap().parked.push(task);
+    }
+
+    /// Takes back the parked tasks, oldest first
+    pub fn unpark(&self) -> Vec<Task> {
+        std::mem::take(&mut self.inner.lock().unwrap().parked)
+    }
+
+    pub fn has_parked(&self) -> bool {
+        !self.inner.lock().unwrap().parked.is_empty()
+    }
+
+    /// Dequeues every task, parked ones first, then highest priority first
+    pub fn drain(&self) -> Vec<Task> {
+        let mut inner = self.inner.lock().unwrap();
+        let tasks = std::mem::take(&mut inner.tasks);
+        let mut drained = std::mem::take(&mut inner.parked);
+        self.not_full.notify_all();
+        drained.extend(tasks.into_values().rev().map(|(_, task)| task));
+        drained
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How long a task enqueued now is expected to wait, based on recent queueing delays.
+    /// Zero if the queue is empty.
+    pub fn estimated_wait(&self) -> Duration {
+        let inner = self.inner.lock().unwrap();
+        if inner.tasks.is_empty() {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(inner.avg_wait)
+        }
+    }
+
+    /// Tasks enqueued and dequeued per second over the last minute
+    pub fn rates(&self) -> (f64, f64) {
+        let mut inner = self.inner.lock().unwrap();
+        (inner.arrivals.per_sec(), inner.dispatches.per_sec())
+    }
+
+    /// Number of queued invocations per function
+    pub fn queued_functions(&self) -> HashMap<Function, usize> {
+        let inner = self.inner.lock().unwrap();
+        let mut queued = HashMap::new();
+        for task in inner.tasks.values().map(|(_, task)| task).chain(&inner.parked) {
+            let invokes = match task {
+                Task::Invoke(_, li) => vec![li],
+                Task::Gang(members, _) => members.iter().map(|(_, li)| li).collect(),
+                Task::Terminate => vec![],
+            };
+            for f in invokes.into_iter().filter_map(|li| li.function.clone()) {
+                *queued.entry(f.into()).or_default() += 1;
+            }
+        }
+        queued
+    }
+}
+
+/// Writes `tasks` to the file at `path`, replacing it
+pub fn save_snapshot(path: &Path, tasks: &[Task]) -> io::Result<()> {
+    let tasks = tasks
+        .iter()
+        .filter_map(|task| match task {
+            Task::Invoke(_, li) => Some(QueuedTask {
+                invokes: vec![li.as_ref().clone()],
+                quorum: 0,
+            }),
+            Task::Gang(members, quorum) => Some(QueuedTask {
+                invokes: members.iter().map(|(_, li)| li.as_ref().clone()).collect(),
+                quorum: *quorum as u64,
+            }),
+            Task::Terminate => None,
+        })
+        .collect();
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, QueueSnapshot { tasks }.encode_to_vec())?;
+    std::fs::rename(tmp, path)
+}
+
+/// Reads the tasks `save_snapshot` wrote to `path`, with new ids, none if there is no file
+pub fn load_snapshot(path: &Path) -> io::Result<Vec<Task>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let snapshot = QueueSnapshot::decode(data.as_slice())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let task = |mut invoke: super::message::LabeledInvoke| {
+        // nobody waits for the result anymore
+        invoke.sync = false;
+        invoke.stream = false;
+        (Uuid::new_v4(), Box::new(invoke))
+    };
+    Ok(snapshot
+        .tasks
+        .into_iter()
+        .filter_map(|t| {
+            let mut invokes = t.invokes.into_iter().map(task);
+            match t.quorum {
+                0 => invokes.next().map(|(uuid, invoke)| Task::Invoke(uuid, invoke)),
+                quorum => Some(Task::Gang(invokes.collect(), quorum as usize)),
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invoke(priority: u32) -> Task {
+        let invoke = super::super::message::LabeledInvoke {
+            priority,
+            ..Default::default()
+        };
+        Task::Invoke(Uuid::new_v4(), Box::new(invoke))
+    }
+
+    #[test]
+    fn test_pop_timeout() {
+        let queue = TaskQueue::new(4, AdmissionPolicy::Reject);
+        assert!(queue.pop_timeout(Duration::from_millis(10)).is_none());
+        queue.push(invoke(0)).unwrap();
+        queue.push(invoke(1)).unwrap();
+        match queue.pop_timeout(Duration::from_millis(10)) {
+            Some(Task::Invoke(_, li)) => assert_eq!(li.priority, 1),
+            task => panic!("unexpected {:?}", task),
+        }
+    }
+
+    #[test]
+    fn test_park() {
+        let queue = TaskQueue::new(1, AdmissionPolicy::Reject);
+        queue.push(invoke(0)).unwrap();
+        let gang = queue.pop();
+        queue.park(gang);
+        assert!(queue.has_parked());
+        // parked tasks leave room for others
+        queue.push(invoke(1)).unwrap();
+        assert_eq!(queue.drain().len(), 2);
+        assert!(!queue.has_parked());
+        queue.park(invoke(2));
+        assert_eq!(queue.unpark().len(), 1);
+        assert!(queue.unpark().is_empty());
+    }
+}
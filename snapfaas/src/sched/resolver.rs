@@ -0,0 +1,212 @@
+//! Gate resolution on behalf of clients without access to the file system
+//!
+//! A `GateInvoke` names a gate by path and the invoker by a login token, the ES256 JWT webfront
+//! issues. The scheduler resolves the gate the way webfront does: it verifies the token, checks
+//! the invoker against the gate's invoker integrity clearance and labels the invocation with the
+//! label the path traversal ended at.
+use jwt::{PKeyWithDigest, VerifyWithKey};
+use labeled::buckle::{Buckle, Component};
+use openssl::pkey::{PKey, Public};
+use serde::{Deserialize, Serialize};
+use tonic::Status;
+
+use super::message::{GateInvoke, LabeledInvoke};
+use crate::fs::{self, BackingStore, FsError, FS};
+
+#[derive(Debug)]
+pub enum Error {
+    BadToken,
+    TokenExpired,
+    SessionRevoked,
+    NoSession,
+    BadPath,
+    Gate(FsError),
+}
+
+impl From<Error> for Status {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::BadToken => Status::unauthenticated("bad login token"),
+            Error::TokenExpired => Status::unauthenticated("login token expired"),
+            Error::SessionRevoked => Status::unauthenticated("login session revoked"),
+            Error::NoSession => Status::unauthenticated("login token without a session"),
+            Error::BadPath => Status::invalid_argument("bad gate path"),
+            Error::Gate(e) => Status::permission_denied(format!("{:?}", e)),
+        }
+    }
+}
+
+pub trait Resolve: Send + Sync {
+    /// Turns `invoke` into the invocation of the gate it names. Must be called from a thread
+    /// that may block, as it reads the file system and sets the thread's label.
+    fn resolve(&self, invoke: GateInvoke) -> Result<LabeledInvoke, Error>;
+}
+
+pub struct Resolver<B> {
+    fs: FS<B>,
+    login_key: PKey<Public>,
+    max_priority: u32,
+}
+
+// same as webfront's
+#[derive(Clone, Serialize, Deserialize, Debug)]
+struct Claims {
+    pub alg: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub sub: Component,
+    #[serde(default)]
+    pub sid: Option<String>,
+}
+
+impl<B: BackingStore> Resolver<B> {
+    /// `login_key` verifies login tokens
+    pub fn new(store: B, login_key: PKey<Public>) -> Self {
+        let fs = FS::new(store);
+        fs.assert_partitioned();
+        Self {
+            fs,
+            login_key,
+            max_priority: 0,
+        }
+    }
+
+    /// Lets logged in invokers ask for priorities up to `max`. Defaults to 0, which runs every
+    /// invocation at the lowest priority.
+    pub fn set_max_priority(&mut self, max: u32) {
+        self.max_priority = max;
+    }
+
+    // the privilege the token speaks for, the public principal's without a token
+    fn login(&self, token: &str) -> Result<Component, Error> {
+        if token.is_empty() {
+            return Ok(Component::dc_true());
+        }
+        let key = PKeyWithDigest {
+            key: self.login_key.clone(),
+            digest: openssl::hash::MessageDigest::sha256(),
+        };
+        let claims: Claims = token
+            .verify_with_key(&key)
+            .map_err(|_| Error::BadToken)?;
+        if claims.exp < crate::delegation::now() {
+            return Err(Error::TokenExpired);
+        }
+        // tokens outside a session could not be revoked
+        let sid = claims.sid.ok_or(Error::NoSession)?;
+        if crate::session::lookup(&self.fs, &sid).is_none() {
+            return Err(Error::SessionRevoked);
+        }
+        Ok(claims.sub)
+    }
+}
+
+impl<B: BackingStore + Send + Sync> Resolve for Resolver<B> {
+    fn resolve(&self, invoke: GateInvoke) -> Result<LabeledInvoke, Error> {
+        let privilege = self.login(&invoke.token)?;
+        fs::utils::clear_label();
+        fs::utils::set_my_privilge(privilege.clone());
+        if let Some(label) = invoke.label {
+            fs::utils::taint_with_label(label.into());
+        }
+        let path = fs::path::Path::parse(&invoke.gate).map_err(|_| Error::BadPath)?;
+        let (function, gate_privilege) =
+            fs::utils::resolve_gate_with_clearance_check(&self.fs, path).map_err(Error::Gate)?;
+        let priority =
+            invoker_priority(invoke.priority, !invoke.token.is_empty(), self.max_priority);
+        Ok(LabeledInvoke {
+            function: Some(function.into()),
+            label: Some(fs::utils::get_current_label().into()),
+            gate_privilege: Some(gate_privilege.into()),
+            payload: invoke.payload,
+            blobs: Default::default(),
+            headers: invoke.headers,
+            sync: invoke.sync,
+            invoker: Some(privilege.into()),
+            priority,
+            stream: false,
+            trace_taint: invoke.trace_taint,
+            preemptible: invoke.preemptible,
+            sealed: false,
+            reply_key: vec![],
+            callback: String::new(),
+            payload_blob: String::new(),
+            payload_blob_transient: false,
+            seal_id: String::new(),
+        })
+    }
+}
+
+/// The priority an invocation runs at: the one its invoker asked for if they are logged in, up
+/// to `max`, the lowest otherwise
+pub fn invoker_priority(requested: u32, authenticated: bool, max: u32) -> u32 {
+    if authenticated {
+        requested.min(max)
+    } else {
+        0
+    }
+}
+
+/// Whether the invoker of a resolved invocation may read a result labeled `result`
+pub fn can_read(invoke: &LabeledInvoke, result: &Buckle) -> bool {
+    use labeled::HasPrivilege;
+    let (label, privilege) = invoker_clearance(invoke);
+    result.can_flow_to_with_privilege(&label, &privilege)
+}
+
+/// The label and privilege of the invoker of a resolved invocation
+pub fn invoker_clearance(invoke: &LabeledInvoke) -> (Buckle, Component) {
+    let label: Buckle = invoke.label.clone().map_or(Buckle::public(), Into::into);
+    let privilege: Component = invoke
+        .invoker
+        .clone()
+        .map_or(Component::dc_true(), Into::into);
+    (label, privilege)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invoker_priority() {
+        assert_eq!(invoker_priority(u32::MAX, false, 10), 0);
+        assert_eq!(invoker_priority(u32::MAX, true, 10), 10);
+        assert_eq!(invoker_priority(3, true, 10), 3);
+        assert_eq!(invoker_priority(3, true, 0), 0);
+    }
+
+    #[test]
+    fn test_login() {
+        use crate::fs::memory::MemoryStore;
+        use crate::login::Claims;
+        use openssl::ec::{EcGroup, EcKey};
+
+        let group = EcGroup::from_curve_name(openssl::nid::Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let public = EcKey::from_public_key(&group, key.public_key()).unwrap();
+        let key = PKey::from_ec_key(key).unwrap();
+        let resolver = Resolver::new(MemoryStore::default(), PKey::from_ec_key(public).unwrap());
+        resolver.fs.initialize();
+        fs::utils::set_my_privilge(Component::dc_false());
+        fs::utils::create_faceted(&resolver.fs, fs::path::Path::root(), "home".to_string())
+            .unwrap();
+        crate::session::initialize(&resolver.fs).unwrap();
+
+        let alice = labeled::buckle::Buckle::parse("alice,T").unwrap().secrecy;
+        let sid = crate::session::create(&resolver.fs, alice.clone()).unwrap();
+        let token = Claims::new(alice.clone(), 60, Some(sid.clone())).sign(&key);
+        assert_eq!(resolver.login(&token).unwrap(), alice);
+        assert_eq!(resolver.login("").unwrap(), Component::dc_true());
+
+        let sessionless = Claims::new(alice.clone(), 60, None).sign(&key);
+        assert!(matches!(resolver.login(&sessionless), Err(Error::NoSession)));
+
+        let mut expired = Claims::new(alice, 0, Some(sid.clone()));
+        expired.exp -= 1;
+        assert!(matches!(resolver.login(&expired.sign(&key)), Err(Error::TokenExpired)));
+
+        crate::session::revoke(&resolver.fs, &sid).unwrap();
+        assert!(matches!(resolver.login(&token), Err(Error::SessionRevoked)));
+    }
+}
@@ -1,14 +1,23 @@
 //! This resource manager maintains a global resource
 //! state across worker nodes.
 
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use tonic::Status;
 use uuid::Uuid;
 
 use crate::fs::Function;
 
 use super::message;
-use super::rpc::ResourceInfo;
+use super::rpc::{Dispatch, FunctionStats, NodeInventory, ResourceInfo, VmInfo};
+
+// number of dispatch decisions kept for the dashboard
+const DISPATCH_HISTORY: usize = 256;
+// drained nodes not heard from for this long are forgotten, so that a node that went away
+// without dropping its resources doesn't linger and a new node with its address can join
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(600);
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Node(IpAddr);
@@ -19,6 +28,14 @@ pub struct NodeInfo {
     total_mem: usize,
     free_mem: usize,
     dirty: bool,
+    vms: Vec<VmInfo>,
+    free_devices: HashMap<String, usize>,
+    total_devices: HashMap<String, usize>,
+    reserved_mem: usize,
+    attributes: HashMap<String, String>,
+    max_vm_mem: Option<usize>,
+    // whether the node has reported its resources yet
+    reported: bool,
 }
 
 impl NodeInfo {
@@ -28,6 +45,13 @@ impl NodeInfo {
             dirty: false,
             total_mem: Default::default(),
             free_mem: Default::default(),
+            vms: Default::default(),
+            free_devices: Default::default(),
+            total_devices: Default::default(),
+            reserved_mem: Default::default(),
+            attributes: Default::default(),
+            max_vm_mem: Default::default(),
+            reported: false,
         }
     }
 
@@ -38,6 +62,98 @@ impl NodeInfo {
     fn set_dirty(&mut self, v: bool) {
         self.dirty = v;
     }
+
+    /// Whether the node meets the function's requirements, fits a VM of its size and has the
+    /// devices it needs. Sizes are only checked once the node has reported its memory.
+    fn can_run(&self, f: &Function) -> bool {
+        let max_vm_mem = self.max_vm_mem.unwrap_or(self.total_mem);
+        (self.total_mem == 0 || f.memory <= max_vm_mem)
+            && f.requirements.iter().all(|(k, v)| self.attributes.get(k) == Some(v))
+            && (f.devices.is_empty() || self.has_devices(&f.devices))
+    }
+
+    /// Whether the node could run `f` once its VMs and devices are free. Nodes that haven't
+    /// reported their resources yet are given the benefit of the doubt.
+    fn could_run(&self, f: &Function) -> bool {
+        if !self.reported {
+            return true;
+        }
+        let max_vm_mem = self.max_vm_mem.unwrap_or(self.total_mem);
+        (self.total_mem == 0 || f.memory <= max_vm_mem)
+            && f.requirements.iter().all(|(k, v)| self.attributes.get(k) == Some(v))
+            && device_counts(&f.devices)
+                .into_iter()
+                .all(|(class, n)| self.total_devices.get(class).is_some_and(|&total| total >= n))
+    }
+
+    /// Whether the node can provide the passthrough devices `classes`, counting devices held by
+    /// idle VMs, which the node evicts when it runs out
+    fn has_devices(&self, classes: &[String]) -> bool {
+        device_counts(classes).into_iter().all(|(class, n)| {
+            let held = self
+                .vms
+                .iter()
+                .flat_map(|vm| vm.function.devices.iter())
+                .filter(|c| *c == class)
+                .count();
+            self.free_devices.get(class).copied().unwrap_or_default() + held >= n
+        })
+    }
+}
+
+// Number of devices of each class in `classes`
+fn device_counts(classes: &[String]) -> HashMap<&String, usize> {
+    let mut counts = HashMap::new();
+    for class in classes {
+        *counts.entry(class).or_default() += 1;
+    }
+    counts
+}
+
+// A running preemptible invocation
+#[derive(Debug)]
+struct Preemptible {
+    invoke: Box<message::LabeledInvoke>,
+    // where it runs
+    node: Node,
+    // answers the worker's pending WatchPreemption
+    watcher: Option<oneshot::Sender<()>>,
+    preempting: bool,
+}
+
+impl Preemptible {
+    // Asks the worker running it to stop, now if it is watching or once it starts to
+    fn stop(&mut self) {
+        self.preempting = true;
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.send(());
+        }
+    }
+}
+
+/// Where to deliver the result of an async invocation, see `LabeledInvoke::callback`
+#[derive(Debug)]
+pub struct Callback {
+    pub url: String,
+    // label and invoker of the invocation, which decide whether the result may be delivered
+    pub readable: message::LabeledInvoke,
+}
+
+impl Callback {
+    /// The callback of `invoke`, `None` if it is sync or has none
+    pub fn of(invoke: &message::LabeledInvoke) -> Option<Self> {
+        if invoke.sync || invoke.callback.is_empty() {
+            return None;
+        }
+        Some(Callback {
+            url: invoke.callback.clone(),
+            readable: message::LabeledInvoke {
+                label: invoke.label.clone(),
+                invoker: invoke.invoker.clone(),
+                ..Default::default()
+            },
+        })
+    }
 }
 
 // type WorkerId = u64;
@@ -45,7 +161,8 @@ impl NodeInfo {
 pub struct Worker {
     // pub id: WorkerId,
     pub addr: SocketAddr,
-    pub conn: TcpStream,
+    // answers the worker's pending GetTask
+    pub tx: oneshot::Sender<message::Response>,
 }
 
 /// Global resource manager
@@ -59,7 +176,39 @@ pub struct ResourceManager {
     // the hashmap, which is why we need another struct to store info
     pub idle: HashMap<Node, Vec<Worker>>,
     // For sync invoke
-    pub wait_list: HashMap<Uuid, TcpStream>,
+    pub wait_list: HashMap<Uuid, oneshot::Sender<message::TaskReturn>>,
+    // For async invoke with a callback
+    pub callbacks: HashMap<Uuid, Callback>,
+    // Where to forward response chunks of streaming invocations
+    pub streams: HashMap<Uuid, mpsc::UnboundedSender<Result<message::InvokeEvent, Status>>>,
+    // Most recent dispatch decisions, oldest first
+    dispatches: VecDeque<Dispatch>,
+    // Nodes being drained, which get no new tasks, and when they were last heard from
+    draining: HashMap<Node, Instant>,
+    // Nodes to tell to reload their configuration once they ask for a task
+    reloading: HashSet<Node>,
+    // VMs for nodes to pause or resume once they ask for a task, oldest first
+    pausing: HashMap<Node, VecDeque<message::PauseVm>>,
+    // Functions whose images nodes should read once they ask for a task, oldest first
+    prefetching: HashMap<Node, VecDeque<Function>>,
+    // Outcomes of finished invocations per function
+    function_stats: HashMap<Function, FunctionStats>,
+    // Running preemptible invocations, oldest first
+    preemptible: Vec<(Uuid, Preemptible)>,
+    // Invoked functions with `min_instances`
+    provisioned: HashSet<Function>,
+    // VMs being booted for `provisioned` functions
+    provisioning: HashMap<Uuid, Function>,
+    // Dispatched invocations per function that haven't finished
+    running: HashMap<Function, usize>,
+    // VMs being booted for invocations of `speculative_boot` functions waiting on busy VMs
+    speculating: HashMap<Uuid, (Node, Function)>,
+    // Most VMs booted speculatively at once
+    max_speculative: usize,
+    // Most warm VMs kept per function for its `min_instances`
+    max_min_instances: usize,
+    // Where to deliver the results of invocations the scheduler gives up on, see `fail`
+    failed: Option<mpsc::UnboundedSender<(Uuid, message::TaskReturn)>>,
 }
 
 impl ResourceManager {
@@ -69,10 +218,34 @@ impl ResourceManager {
         }
     }
 
-    pub fn add_idle(&mut self, addr: SocketAddr, conn: TcpStream) {
+    pub fn add_idle(&mut self, addr: SocketAddr, tx: oneshot::Sender<message::Response>) {
         let node = Node(addr.ip());
+        self.forget_drained();
+        if let Some(seen) = self.draining.get_mut(&node) {
+            *seen = Instant::now();
+            let _ = tx.send(terminate());
+            return;
+        }
+        if self.reloading.remove(&node) {
+            let _ = tx.send(reload_config());
+            return;
+        }
+        if let Some(pause) = self.pausing.get_mut(&node).and_then(VecDeque::pop_front) {
+            let _ = tx.send(pause_vm(pause));
+            return;
+        }
+        if let Some(f) = self.prefetching.get_mut(&node).and_then(VecDeque::pop_front) {
+            let _ = tx.send(prefetch(&f));
+            return;
+        }
         self.try_add_node(&node);
-        let worker = Worker { addr, conn };
+        if let Some(f) = self.provision_deficit(&node) {
+            let uuid = Uuid::new_v4();
+            let _ = tx.send(provision_vm(uuid, &f));
+            self.provisioning.insert(uuid, f);
+            return;
+        }
+        let worker = Worker { addr, tx };
         let idle = &mut self.idle;
         if let Some(v) = idle.get_mut(&node) {
             v.push(worker);
@@ -110,12 +283,25 @@ impl ResourceManager {
                 let worker = self.idle.get_mut(&n).and_then(|v| v.pop());
                 self.idle.retain(|_, v| !v.is_empty());
                 log::debug!("find cached {:?}", worker);
+                if let Some(w) = worker.as_ref() {
+                    self.record_dispatch(f, w.addr, true);
+                }
                 worker
             }
             None => {
+                // While a VM is booted for them, invocations wait for it or a busy VM to become
+                // idle, whichever comes first
+                if self.speculating(f) {
+                    return None;
+                }
                 log::debug!("no cached {:?}", self.cached);
-                // If no cached, simply return some worker
-                let worker = self.idle.values_mut().next().and_then(|v| v.pop());
+                // If no cached, simply return some worker, on a node that can run the
+                // function
+                let worker = self
+                    .idle
+                    .iter_mut()
+                    .find(|(n, _)| info.get(n).is_some_and(|i| i.can_run(f)))
+                    .and_then(|(_, v)| v.pop());
                 // Mark the node dirty because it may or may not have
                 // the same cached functions. This indicates an implicit
                 // eviction on the remote worker node, thus we can't
@@ -127,14 +313,194 @@ impl ResourceManager {
                 }
                 // Remove the entry if no more idle remains
                 self.idle.retain(|_, v| !v.is_empty());
-                worker
+                match worker {
+                    Some(w) if self.should_speculate(f) => {
+                        let uuid = Uuid::new_v4();
+                        let node = Node(w.addr.ip());
+                        if w.tx.send(provision_vm(uuid, f)).is_ok() {
+                            log::debug!("speculatively booting {:?} on {:?}", f, node);
+                            self.speculating.insert(uuid, (node, f.clone()));
+                        }
+                        None
+                    }
+                    worker => {
+                        if let Some(w) = worker.as_ref() {
+                            self.record_dispatch(f, w.addr, false);
+                        }
+                        worker
+                    }
+                }
             }
         }
     }
 
+    /// Caps the VMs booted at once for invocations of `speculative_boot` functions. Defaults to
+    /// 0, which disables speculative boots.
+    pub fn set_max_speculative(&mut self, n: usize) {
+        self.max_speculative = n;
+    }
+
+    /// Whether a VM of `f` is being booted for invocations waiting on busy VMs
+    pub fn speculating(&self, f: &Function) -> bool {
+        self.speculating.values().any(|(_, s)| s == f)
+    }
+
+    // Whether to boot another VM of `f` rather than run an invocation of it on a new VM, which
+    // pays off when a busy VM may become idle before the new one boots
+    fn should_speculate(&self, f: &Function) -> bool {
+        f.speculative_boot
+            && self.running.get(f).is_some_and(|n| *n > 0)
+            && self.speculating.len() < self.max_speculative
+    }
+
+    /// Dispatched invocations per function that haven't finished
+    pub fn running(&self) -> &HashMap<Function, usize> {
+        &self.running
+    }
+
+    /// Number of known nodes that aren't being drained
+    pub fn active_nodes(&self) -> usize {
+        self.info.keys().filter(|n| !self.draining.contains_key(n)).count()
+    }
+
+    /// Accounts for an invocation of `f` dispatched to a worker
+    pub fn start_running(&mut self, f: &Function) {
+        *self.running.entry(f.clone()).or_default() += 1;
+    }
+
+    /// Accounts for an invocation of `f` that finished or never reached its worker
+    pub fn stop_running(&mut self, f: &Function) {
+        if let Some(n) = self.running.get_mut(f) {
+            *n -= 1;
+            if *n == 0 {
+                self.running.remove(f);
+            }
+        }
+    }
+
+    /// Caps the idle VMs kept warm per function for its `min_instances`. Defaults to 0, which
+    /// keeps none warm.
+    pub fn set_max_min_instances(&mut self, n: usize) {
+        self.max_min_instances = n;
+    }
+
+    /// Keeps `f.min_instances` idle VMs of `f` warm from now on, up to the cap set with
+    /// `set_max_min_instances`
+    pub fn require_instances(&mut self, f: &Function) {
+        if f.min_instances > 0 && self.max_min_instances > 0 && !self.provisioned.contains(f) {
+            self.provisioned.insert(f.clone());
+        }
+    }
+
+    /// Returns whether `uuid` was a VM booted to keep a function warm or speculatively rather
+    /// than an invocation. A function whose VM failed to boot is not kept warm until it is
+    /// invoked again. Once a speculative boot finishes, invocations waiting on it take the first
+    /// idle VM, so if a busy VM won, the booted one stays idle until its worker evicts it.
+    pub fn finish_provisioning(&mut self, uuid: Uuid, result: &message::TaskReturn) -> bool {
+        if self.speculating.remove(&uuid).is_some() {
+            return true;
+        }
+        match self.provisioning.remove(&uuid) {
+            Some(f) => {
+                if result.code != message::ReturnCode::Success as i32 {
+                    self.provisioned.remove(&f);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // A function short of warm VMs, counting the ones being booted, that `node` can run
+    fn provision_deficit(&self, node: &Node) -> Option<Function> {
+        let info = self.info.get(node)?;
+        self.provisioned
+            .iter()
+            .find(|f| {
+                let warm: usize = self.cached.get(f).map_or(0, |v| v.iter().map(|n| n.1).sum());
+                let booting = self.provisioning.values().filter(|p| p == f).count();
+                warm + booting < f.min_instances.min(self.max_min_instances)
+                    && info.can_run(f)
+            })
+            .cloned()
+    }
+
+    /// Whether some node could run `f` once it has an idle worker. Before any node registers,
+    /// every function could be.
+    pub fn schedulable(&self, f: &Function) -> bool {
+        self.info.is_empty() || self.info.values().any(|i| i.could_run(f))
+    }
+
+    /// Sends the results of the invocations the scheduler gives up on to `tx`, whose receiver
+    /// hands them to their waiters or callbacks
+    pub fn set_failed(&mut self, tx: mpsc::UnboundedSender<(Uuid, message::TaskReturn)>) {
+        self.failed = Some(tx);
+    }
+
+    /// Gives up on the invocation `uuid`, answering it with `code`
+    pub fn fail(&mut self, uuid: Uuid, code: message::ReturnCode) {
+        let result = message::TaskReturn {
+            code: code as i32,
+            label: Some(labeled::buckle::Buckle::public().into()),
+            ..Default::default()
+        };
+        match self.failed.as_ref() {
+            Some(tx) => {
+                let _ = tx.send((uuid, result));
+            }
+            None => {
+                if let Some(tx) = self.wait_list.remove(&uuid) {
+                    let _ = tx.send(result);
+                }
+            }
+        }
+    }
+
+    /// How many of a gang's members, running `fs`, idle workers could take now, one each
+    pub fn gang_idle(&self, fs: &[Function]) -> usize {
+        let mut idle: Vec<_> = self
+            .idle
+            .iter()
+            .filter_map(|(node, workers)| Some((self.info.get(node)?, workers.len())))
+            .collect();
+        let mut n = 0;
+        for f in fs {
+            let worker = idle.iter_mut().find(|(info, left)| *left > 0 && info.can_run(f));
+            if let Some((_, left)) = worker {
+                *left -= 1;
+                n += 1;
+            }
+        }
+        n
+    }
+
+    /// Most of a gang's members, running `fs`, that could ever run at once, bounded by the
+    /// memory of the nodes that could run any of them. `None` while that is unknown, before
+    /// nodes have reported their memory.
+    pub fn gang_capacity(&self, fs: &[Function]) -> Option<usize> {
+        if self.info.is_empty() {
+            return None;
+        }
+        let mut capacity = 0;
+        for info in self.info.values() {
+            let smallest = fs.iter().filter(|f| info.could_run(f)).map(|f| f.memory).min();
+            if let Some(memory) = smallest {
+                if !info.reported || info.total_mem == 0 {
+                    return None;
+                }
+                capacity += info.total_mem / memory.max(1);
+            }
+        }
+        Some(capacity)
+    }
+
     pub fn update(&mut self, addr: IpAddr, info: ResourceInfo) {
         log::debug!("update {:?}", info);
         let node = Node(addr);
+        if let Some(seen) = self.draining.get_mut(&node) {
+            *seen = Instant::now();
+            return;
+        }
 
         // Set node to not dirty bc we are sure of its state
         let success = self.try_add_node(&node);
@@ -146,6 +512,13 @@ impl ResourceManager {
         let nodeinfo = self.info.get_mut(&node).unwrap();
         nodeinfo.total_mem = info.total_mem;
         nodeinfo.free_mem = info.free_mem;
+        nodeinfo.vms = info.vms;
+        nodeinfo.free_devices = info.free_devices;
+        nodeinfo.total_devices = info.total_devices;
+        nodeinfo.reserved_mem = info.reserved_mem;
+        nodeinfo.attributes = info.attributes;
+        nodeinfo.max_vm_mem = info.max_vm_mem;
+        nodeinfo.reported = true;
 
         // Update number of cached VMs per funciton
         for (k, num_cached) in info.stats {
@@ -172,9 +545,29 @@ impl ResourceManager {
     }
 
     pub fn remove(&mut self, addr: IpAddr) {
-        use message::response::Kind as ResKind;
         let node = Node(addr);
         // They must have no busy worker
+        self.remove_cached(node);
+        self.detach(node);
+        // its invocations finish nowhere
+        self.preemptible.retain(|(_, t)| t.node != node);
+        self.draining.remove(&node);
+    }
+
+    // Forgets what the node has and can do, and terminates its idle workers
+    fn detach(&mut self, node: Node) {
+        self.info.remove(&node);
+        self.prefetching.remove(&node);
+        self.speculating.retain(|_, (n, _)| *n != node);
+        if let Some(mut workers) = self.idle.remove(&node) {
+            while let Some(w) = workers.pop() {
+                let _ = w.tx.send(terminate());
+            }
+        }
+    }
+
+    // Forgets the idle VMs of the node
+    fn remove_cached(&mut self, node: Node) {
         for (_, v) in self.cached.iter_mut() {
             if let Some(pos) = v.iter().position(|&n| n.0 == node) {
                 // This doesn't preserve ordering
@@ -182,17 +575,95 @@ impl ResourceManager {
                 v.retain(|n| n.1 != 0);
             }
         }
-        self.info.remove(&node);
-        if let Some(mut workers) = self.idle.remove(&node) {
-            while let Some(mut w) = workers.pop() {
-                let _ = message::write(
-                    &mut w.conn,
-                    &message::Response {
-                        kind: Some(ResKind::Terminate(message::Terminate {})),
-                    },
-                );
+    }
+
+    /// Removes the node and keeps it from getting tasks until it drops its resources or goes
+    /// quiet for `DRAIN_TIMEOUT`. Its idempotent invocations are preempted so that they run again
+    /// from the start on other nodes, its other busy workers finish their tasks. Either way,
+    /// workers are terminated when they ask for the next task. Running VMs aren't checkpointed
+    /// and moved to other nodes.
+    pub fn drain(&mut self, addr: IpAddr) {
+        let node = Node(addr);
+        self.remove_cached(node);
+        self.detach(node);
+        self.forget_drained();
+        self.draining.insert(node, Instant::now());
+        for (uuid, task) in self.preemptible.iter_mut().filter(|(_, t)| t.node == node) {
+            if !task.preempting {
+                log::info!("moving {:?} off drained node {:?}", uuid, node);
+                task.stop();
+            }
+        }
+    }
+
+    // Forgets drained nodes not heard from for `DRAIN_TIMEOUT`
+    fn forget_drained(&mut self) {
+        self.draining.retain(|node, seen| {
+            let quiet = seen.elapsed() >= DRAIN_TIMEOUT;
+            if quiet {
+                log::warn!("forgetting drained node {:?}, quiet for {:?}", node, seen.elapsed());
+            }
+            !quiet
+        });
+    }
+
+    /// Tells `addr`, or every known node if `None`, to reload its configuration. Nodes without an
+    /// idle worker are told when they next ask for a task.
+    pub fn reload(&mut self, addr: Option<IpAddr>) {
+        let nodes = match addr {
+            Some(addr) => vec![Node(addr)],
+            None => self.info.keys().cloned().collect(),
+        };
+        for node in nodes {
+            match self.idle.get_mut(&node).and_then(Vec::pop) {
+                Some(w) => {
+                    let _ = w.tx.send(reload_config());
+                }
+                None => {
+                    self.reloading.insert(node);
+                }
+            }
+        }
+    }
+
+    /// Tells `addr` to pause or resume one of its VMs. Nodes without an idle worker are told when
+    /// they next ask for a task.
+    pub fn pause_vm(&mut self, addr: IpAddr, pause: message::PauseVm) {
+        let node = Node(addr);
+        match self.idle.get_mut(&node).and_then(Vec::pop) {
+            Some(w) => {
+                let _ = w.tx.send(pause_vm(pause));
+            }
+            None => self.pausing.entry(node).or_default().push_back(pause),
+        }
+    }
+
+    /// Tells every node that can run `f` but has no idle VM of it to read the images of `f`,
+    /// for an invocation waiting for a worker. Nodes without an idle worker are told when they
+    /// next ask for a task, before they get one.
+    pub fn prefetch(&mut self, f: &Function) {
+        let cached = self.cached.get(f);
+        let nodes: Vec<Node> = self
+            .info
+            .values()
+            .filter(|i| i.can_run(f))
+            .filter(|i| !cached.is_some_and(|v| v.iter().any(|n| n.0 == i.node)))
+            .map(|i| i.node)
+            .collect();
+        for node in nodes {
+            match self.idle.get_mut(&node).and_then(Vec::pop) {
+                Some(w) => {
+                    let _ = w.tx.send(prefetch(f));
+                }
+                None => {
+                    let queue = self.prefetching.entry(node).or_default();
+                    if !queue.contains(f) {
+                        queue.push_back(f.clone());
+                    }
+                }
             }
         }
+        self.idle.retain(|_, v| !v.is_empty());
     }
 
     pub fn remove_all(&mut self) {
@@ -202,6 +673,133 @@ impl ResourceManager {
         }
     }
 
+    fn record_dispatch(&mut self, f: &Function, addr: SocketAddr, cached: bool) {
+        if self.dispatches.len() == DISPATCH_HISTORY {
+            self.dispatches.pop_front();
+        }
+        self.dispatches.push_back(Dispatch {
+            function: f.clone(),
+            node: addr.ip().to_string(),
+            cached,
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        });
+    }
+
+    /// Idle VMs and memory of every known node
+    pub fn inventory(&self) -> Vec<NodeInventory> {
+        self.info
+            .values()
+            .map(|i| NodeInventory {
+                node: i.node.0.to_string(),
+                total_mem: i.total_mem,
+                free_mem: i.free_mem,
+                idle_workers: self.idle.get(&i.node).map_or(0, Vec::len),
+                vms: i.vms.clone(),
+                free_devices: i.free_devices.clone(),
+                reserved_mem: i.reserved_mem,
+                attributes: i.attributes.clone(),
+                max_vm_mem: i.max_vm_mem,
+            })
+            .collect()
+    }
+
+    pub fn dispatches(&self) -> Vec<Dispatch> {
+        self.dispatches.iter().cloned().collect()
+    }
+
+    /// Tracks a dispatched preemptible invocation on `addr` until it finishes. Invocations of
+    /// functions that aren't idempotent are never preempted, as running them again may repeat
+    /// their effects.
+    pub fn add_preemptible(
+        &mut self,
+        uuid: Uuid,
+        invoke: Box<message::LabeledInvoke>,
+        addr: IpAddr,
+    ) {
+        if !invoke.function.as_ref().is_some_and(|f| f.idempotent) {
+            return;
+        }
+        let task = Preemptible {
+            invoke,
+            node: Node(addr),
+            watcher: None,
+            preempting: false,
+        };
+        self.preemptible.push((uuid, task));
+    }
+
+    /// Returns the receiver that fires when the scheduler wants the preemptible invocation
+    /// `uuid` stopped, `None` if there is no such invocation
+    pub fn watch_preemption(&mut self, uuid: Uuid) -> Option<oneshot::Receiver<()>> {
+        let (_, task) = self.preemptible.iter_mut().find(|(u, _)| *u == uuid)?;
+        let (tx, rx) = oneshot::channel();
+        if task.preempting {
+            let _ = tx.send(());
+        } else {
+            task.watcher = Some(tx);
+        }
+        Some(rx)
+    }
+
+    /// Asks the worker of the most recently dispatched preemptible invocation less urgent than
+    /// `priority` to stop it. Returns whether there was one.
+    pub fn preempt(&mut self, priority: u32) -> bool {
+        let victim = self
+            .preemptible
+            .iter_mut()
+            .rev()
+            .find(|(_, t)| !t.preempting && t.invoke.priority < priority);
+        let Some((uuid, task)) = victim else {
+            return false;
+        };
+        log::info!("preempting {:?} for priority {}", uuid, priority);
+        task.stop();
+        true
+    }
+
+    /// Stops tracking the preemptible invocation `uuid` and returns it, `None` if `uuid` is not
+    /// a preemptible invocation
+    pub fn finish_preemptible(&mut self, uuid: Uuid) -> Option<Box<message::LabeledInvoke>> {
+        let i = self.preemptible.iter().position(|(u, _)| *u == uuid)?;
+        Some(self.preemptible.remove(i).1.invoke)
+    }
+
+    /// Accounts for an invocation of `f` that finished with `result`
+    pub fn record_result(&mut self, f: Function, result: &message::TaskReturn) {
+        self.stop_running(&f);
+        let stats = self
+            .function_stats
+            .entry(f.clone())
+            .or_insert_with(|| FunctionStats {
+                function: f,
+                invocations: 0,
+                ooms: 0,
+                preemptions: 0,
+                peak_memory: VecDeque::new(),
+            });
+        stats.invocations += 1;
+        if result.code == message::ReturnCode::OutOfMemory as i32 {
+            stats.ooms += 1;
+        }
+        if result.code == message::ReturnCode::Preempted as i32 {
+            stats.preemptions += 1;
+        }
+        let peak = result.payload.as_ref().map_or(0, |p| p.peak_memory);
+        if peak > 0 {
+            if stats.peak_memory.len() == FunctionStats::PEAK_SAMPLES {
+                stats.peak_memory.pop_front();
+            }
+            stats.peak_memory.push_back(peak);
+        }
+    }
+
+    pub fn function_stats(&self) -> Vec<FunctionStats> {
+        self.function_stats.values().cloned().collect()
+    }
+
     fn try_add_node(&mut self, node: &Node) -> bool {
         let has_node = self.info.contains_key(&node);
         if !has_node {
@@ -210,3 +808,245 @@ impl ResourceManager {
         !has_node
     }
 }
+
+fn reload_config() -> message::Response {
+    message::Response {
+        kind: Some(message::response::Kind::ReloadConfig(message::ReloadConfig {})),
+    }
+}
+
+fn pause_vm(pause: message::PauseVm) -> message::Response {
+    message::Response {
+        kind: Some(message::response::Kind::PauseVm(pause)),
+    }
+}
+
+fn prefetch(f: &Function) -> message::Response {
+    message::Response {
+        kind: Some(message::response::Kind::Prefetch(message::Prefetch {
+            function: Some(f.clone().into()),
+        })),
+    }
+}
+
+fn provision_vm(uuid: Uuid, f: &Function) -> message::Response {
+    message::Response {
+        kind: Some(message::response::Kind::ProvisionVm(message::ProvisionVm {
+            task_id: uuid.to_string(),
+            function: Some(f.clone().into()),
+        })),
+    }
+}
+
+fn terminate() -> message::Response {
+    message::Response {
+        kind: Some(message::response::Kind::Terminate(message::Terminate {})),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(total_mem: usize, gpus: usize, attributes: &[(&str, &str)]) -> ResourceInfo {
+        ResourceInfo {
+            stats: Default::default(),
+            total_mem,
+            free_mem: total_mem,
+            vms: vec![],
+            free_devices: std::iter::once(("gpu".to_string(), gpus)).collect(),
+            total_devices: std::iter::once(("gpu".to_string(), gpus)).collect(),
+            reserved_mem: 0,
+            attributes: attributes.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            max_vm_mem: None,
+        }
+    }
+
+    #[test]
+    fn test_schedulable() {
+        let mut manager = ResourceManager::new();
+        let f = Function {
+            memory: 512,
+            devices: vec!["gpu".to_string(), "gpu".to_string()],
+            requirements: std::iter::once(("cpu".to_string(), "icelake".to_string())).collect(),
+            ..Default::default()
+        };
+        // nothing is known before nodes register
+        assert!(manager.schedulable(&f));
+        let node = "10.0.0.1".parse().unwrap();
+        manager.update(node, info(1024, 2, &[]));
+        assert!(!manager.schedulable(&f));
+        manager.update(node, info(256, 2, &[("cpu", "icelake")]));
+        assert!(!manager.schedulable(&f));
+        manager.update(node, info(1024, 1, &[("cpu", "icelake")]));
+        assert!(!manager.schedulable(&f));
+        manager.update(node, info(1024, 2, &[("cpu", "icelake")]));
+        assert!(manager.schedulable(&f));
+        // devices held by busy VMs still count
+        let mut busy = info(1024, 0, &[("cpu", "icelake")]);
+        busy.total_devices.insert("gpu".to_string(), 2);
+        manager.update(node, busy);
+        assert!(manager.schedulable(&f));
+    }
+
+    #[test]
+    fn test_gang_idle_and_capacity() {
+        let mut manager = ResourceManager::new();
+        let small = Function {
+            memory: 256,
+            ..Default::default()
+        };
+        let gpu = Function {
+            memory: 256,
+            devices: vec!["gpu".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(manager.gang_capacity(&[small.clone()]), None);
+        let node: IpAddr = "10.0.0.1".parse().unwrap();
+        manager.update(node, info(1024, 0, &[]));
+        assert_eq!(manager.gang_capacity(&[small.clone(), small.clone()]), Some(4));
+        assert_eq!(manager.gang_capacity(&[gpu.clone()]), Some(0));
+        let mut receivers = vec![];
+        for port in 0..2 {
+            let (tx, rx) = oneshot::channel();
+            manager.add_idle(SocketAddr::new(node, port), tx);
+            receivers.push(rx);
+        }
+        // idle workers only count for members they can run
+        assert_eq!(manager.gang_idle(&[small.clone(), gpu.clone()]), 1);
+        assert_eq!(manager.gang_idle(&[small.clone(), small.clone(), small]), 2);
+        assert_eq!(manager.gang_idle(&[gpu]), 0);
+    }
+
+    #[test]
+    fn test_inventory_and_dispatches() {
+        let mut manager = ResourceManager::new();
+        let node: IpAddr = "10.0.0.1".parse().unwrap();
+        manager.update(node, info(1024, 1, &[("cpu", "icelake")]));
+        let (tx, _rx) = oneshot::channel();
+        manager.add_idle(SocketAddr::new(node, 1), tx);
+        let inventory = manager.inventory();
+        assert_eq!(inventory.len(), 1);
+        assert_eq!(inventory[0].node, "10.0.0.1");
+        assert_eq!(inventory[0].free_mem, 1024);
+        assert_eq!(inventory[0].idle_workers, 1);
+        assert_eq!(inventory[0].attributes["cpu"], "icelake");
+        assert!(manager.dispatches().is_empty());
+
+        let f = Function {
+            memory: 256,
+            ..Default::default()
+        };
+        assert!(manager.find_idle(&f).is_some());
+        let dispatches = manager.dispatches();
+        assert_eq!(dispatches.len(), 1);
+        assert_eq!(dispatches[0].node, "10.0.0.1");
+        assert_eq!(dispatches[0].function, f);
+        // no VM of the function was cached on the node
+        assert!(!dispatches[0].cached);
+        assert_eq!(manager.inventory()[0].idle_workers, 0);
+    }
+
+    #[test]
+    fn test_min_instances_capped() {
+        let mut manager = ResourceManager::new();
+        let f = Function {
+            memory: 128,
+            min_instances: 100,
+            ..Default::default()
+        };
+        let node: IpAddr = "10.0.0.1".parse().unwrap();
+        manager.update(node, info(1024, 0, &[]));
+        // without a cap, nothing is kept warm
+        manager.require_instances(&f);
+        assert!(manager.provision_deficit(&Node(node)).is_none());
+
+        manager.set_max_min_instances(2);
+        manager.require_instances(&f);
+        let mut receivers = vec![];
+        for port in 0..3 {
+            let (tx, rx) = oneshot::channel();
+            manager.add_idle(SocketAddr::new(node, port), tx);
+            receivers.push(rx);
+        }
+        assert_eq!(manager.provisioning.len(), 2);
+        assert_eq!(manager.idle.get(&Node(node)).map(Vec::len), Some(1));
+    }
+
+    #[test]
+    fn test_preemptible() {
+        let mut manager = ResourceManager::new();
+        let invoke = |idempotent| {
+            Box::new(message::LabeledInvoke {
+                function: Some(
+                    Function {
+                        idempotent,
+                        ..Default::default()
+                    }
+                    .into(),
+                ),
+                preemptible: true,
+                ..Default::default()
+            })
+        };
+        let node: IpAddr = "10.0.0.1".parse().unwrap();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        manager.add_preemptible(a, invoke(false), node);
+        assert!(!manager.preempt(1));
+        manager.add_preemptible(b, invoke(true), node);
+        assert!(manager.preempt(1));
+        assert!(manager.watch_preemption(b).is_some());
+        // draining lets it finish
+        manager.drain(node);
+        assert!(manager.watch_preemption(b).is_some());
+        manager.remove(node);
+        assert!(manager.watch_preemption(b).is_none());
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut manager = ResourceManager::new();
+        let invoke = Box::new(message::LabeledInvoke {
+            function: Some(
+                Function {
+                    idempotent: true,
+                    ..Default::default()
+                }
+                .into(),
+            ),
+            preemptible: true,
+            ..Default::default()
+        });
+        let node: IpAddr = "10.0.0.1".parse().unwrap();
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        manager.add_preemptible(a, invoke.clone(), node);
+        manager.add_preemptible(b, invoke, "10.0.0.2".parse().unwrap());
+        let mut watching = manager.watch_preemption(a).unwrap();
+        // the node's idempotent invocations move, the others stay
+        manager.drain(node);
+        assert!(watching.try_recv().is_ok());
+        assert!(manager.watch_preemption(b).unwrap().try_recv().is_err());
+
+        let (tx, mut rx) = oneshot::channel();
+        manager.add_idle(SocketAddr::new(node, 1), tx);
+        assert!(rx.try_recv().is_ok());
+        // a node that went quiet is forgotten
+        *manager.draining.get_mut(&Node(node)).unwrap() -= DRAIN_TIMEOUT;
+        let (tx, mut rx) = oneshot::channel();
+        manager.add_idle(SocketAddr::new(node, 1), tx);
+        assert!(rx.try_recv().is_err());
+        assert!(manager.draining.is_empty());
+    }
+
+    #[test]
+    fn test_fail() {
+        let mut manager = ResourceManager::new();
+        let uuid = Uuid::new_v4();
+        let (tx, mut rx) = oneshot::channel();
+        manager.wait_list.insert(uuid, tx);
+        manager.fail(uuid, message::ReturnCode::Unschedulable);
+        let result = rx.try_recv().unwrap();
+        assert_eq!(result.code, message::ReturnCode::Unschedulable as i32);
+        assert!(result.label.is_some());
+    }
+}
@@ -1,92 +1,374 @@
+use lazy_static::lazy_static;
+use log::debug;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::hash::{Hash, Hasher};
-use std::net::TcpStream;
 use std::thread;
+use std::time::Duration;
+use tonic::transport::{Channel, Endpoint, Uri};
 
 use crate::fs::Function;
+use labeled::buckle::Buckle;
 
+use super::codec;
 use super::message;
-use super::message::request::Kind as ReqKind;
-use super::message::{Request, Response, TaskReturn};
+use super::message::scheduler_client::SchedulerClient;
+use super::message::{Response, TaskReturn};
 use super::Error;
 
+// RPCs other than `get` and sync `labeled_invoke` give up after this long
+const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+// bounds of the reconnection backoff
+const BACKOFF_BASE: Duration = Duration::from_millis(100);
+const BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+/// Metadata key of the admin token, see `drain`
+pub const ADMIN_TOKEN_KEY: &str = "x-faasten-admin-token";
+
+lazy_static! {
+    // drives the clients, which are used from synchronous code
+    static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("sched-rpc")
+        .enable_all()
+        .build()
+        .expect("tokio runtime");
+}
+
+/// Connection to the scheduler. Concurrent RPCs are multiplexed over the same connection, and
+/// clones share it.
+pub type Client = SchedulerClient<Channel>;
+
+/// Connects to the scheduler at `addr`, either ADDR:PORT or unix://PATH
+pub fn connect(addr: &str) -> Result<Client, Error> {
+    RUNTIME.block_on(connect_async(addr))
+}
+
+/// Connects to the scheduler at `addr`, either ADDR:PORT or unix://PATH, from async code. The
+/// connection is driven by the caller's runtime.
+pub async fn connect_async(addr: &str) -> Result<Client, Error> {
+    let channel = match addr.strip_prefix("unix://") {
+        Some(path) => {
+            let path = path.to_string();
+            // the URI is required but unused, the connector decides where to connect
+            Endpoint::from_static("http://localhost")
+                .connect_with_connector(tower::service_fn(move |_: Uri| {
+                    tokio::net::UnixStream::connect(path.clone())
+                }))
+                .await
+        }
+        None => {
+            Endpoint::from_shared(format!("http://{}", addr))
+                .map_err(Error::Transport)?
+                .connect()
+                .await
+        }
+    }
+    .map_err(Error::Transport)?;
+    Ok(SchedulerClient::new(channel)
+        .max_decoding_message_size(codec::max_message_size())
+        .max_encoding_message_size(codec::max_message_size()))
+}
+
+/// Connects to the scheduler at `addr`, retrying with `backoff` until it succeeds
+pub fn connect_retrying(addr: &str) -> Client {
+    let mut attempt = 0;
+    loop {
+        match connect(addr) {
+            Ok(client) => return client,
+            Err(e) => {
+                attempt += 1;
+                let delay = backoff(attempt);
+                debug!(
+                    "failed to connect to the scheduler at {}: {:?}, retrying in {:?}",
+                    addr, e, delay
+                );
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+/// How long to wait before the `attempt`-th retry of a failed RPC or connection: exponential
+/// backoff capped at 10 seconds, with full jitter so that workers losing the scheduler at the same
+/// time do not all come back at once.
+pub fn backoff(attempt: u32) -> Duration {
+    let cap = BACKOFF_BASE
+        .saturating_mul(1 << attempt.min(16))
+        .min(BACKOFF_MAX);
+    cap.mul_f64(rand::random::<f64>())
+}
+
+fn call<T>(
+    rpc: impl Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+) -> Result<T, Error> {
+    RUNTIME
+        .block_on(rpc)
+        .map(tonic::Response::into_inner)
+        .map_err(Error::Status)
+}
+
+fn call_with_timeout<T>(
+    rpc: impl Future<Output = Result<tonic::Response<T>, tonic::Status>>,
+) -> Result<T, Error> {
+    RUNTIME
+        .block_on(async { tokio::time::timeout(RPC_TIMEOUT, rpc).await })
+        .map_err(|_| Error::Timeout)?
+        .map(tonic::Response::into_inner)
+        .map_err(Error::Status)
+}
+
 /// ping
-pub fn ping(stream: &mut TcpStream) -> Result<Response, Error> {
-    let req = Request {
-        kind: Some(ReqKind::Ping(message::Ping {})),
-    };
-    message::write(stream, &req)?;
-    let response = message::read_response(stream)?;
-    Ok(response)
+pub fn ping(client: &mut Client) -> Result<message::Pong, Error> {
+    call_with_timeout(client.ping(message::Ping {}))
 }
 
 /// This method is for workers to retrieve a HTTP request, and
-/// it is supposed to block if there's no further HTTP requests
-pub fn get(stream: &mut TcpStream) -> Result<Response, Error> {
+/// it is supposed to wait if there's no further HTTP requests
+pub async fn get(client: &mut Client) -> Result<Response, Error> {
     // avoid using unstable #![feature(thread_id_value)]
     let thread_id = {
         let mut hasher = DefaultHasher::new();
         thread::current().id().hash(&mut hasher);
         hasher.finish()
     };
-    let req = Request {
-        kind: Some(ReqKind::GetTask(message::GetTask { thread_id })),
-    };
-    message::write(stream, &req)?;
-    let response = message::read_response(stream)?;
-    Ok(response)
+    client
+        .get_task(message::GetTask { thread_id })
+        .await
+        .map(tonic::Response::into_inner)
+        .map_err(Error::Status)
+}
+
+/// This method is for workers to learn when the scheduler wants them to stop the preemptible
+/// task `task_id`. It waits until then, or fails once the task has finished.
+pub async fn watch_preemption(client: &mut Client, task_id: String) -> Result<(), Error> {
+    client
+        .watch_preemption(message::WatchPreemption { task_id })
+        .await
+        .map(|_| ())
+        .map_err(Error::Status)
 }
 
 /// This method is for workers to return the result of a HTTP request
-pub fn finish(stream: &mut TcpStream, task_id: String, result: TaskReturn) -> Result<(), Error> {
-    let req = Request {
-        kind: Some(ReqKind::FinishTask(message::FinishTask {
-            task_id,
-            result: Some(result),
-        })),
-    };
-    message::write(stream, &req)?;
-    //let response = message::read_response(stream)?;
+pub async fn finish(
+    client: &mut Client,
+    task_id: String,
+    result: TaskReturn,
+    function: Option<message::Function>,
+) -> Result<(), Error> {
+    let rpc = client.finish_task(message::FinishTask {
+        task_id,
+        result: Some(result),
+        function,
+    });
+    tokio::time::timeout(RPC_TIMEOUT, rpc)
+        .await
+        .map_err(|_| Error::Timeout)?
+        .map_err(Error::Status)?;
     Ok(())
 }
 
-/// This method is for workers to invoke a function
+/// This method is for workers and frontends to invoke a function. Sync invocations block until
+/// the function returns, async ones return an empty success once the invocation is queued.
 pub fn labeled_invoke(
-    stream: &mut TcpStream,
+    client: &mut Client,
     labeled_invoke: message::LabeledInvoke,
-) -> Result<(), Error> {
-    let req = Request {
-        kind: Some(ReqKind::LabeledInvoke(labeled_invoke)),
+) -> Result<TaskReturn, Error> {
+    if labeled_invoke.sync {
+        call(client.labeled_invoke(labeled_invoke))
+    } else {
+        call_with_timeout(client.labeled_invoke(labeled_invoke))
+    }
+}
+
+/// This method is for workers to invoke several functions at once, which the scheduler only
+/// starts once it can run at least `min_fraction` of them (all of them if zero). Returns the
+/// results in order once all sync invocations have finished.
+pub fn gang_invoke(
+    client: &mut Client,
+    invokes: Vec<message::LabeledInvoke>,
+    min_fraction: f64,
+) -> Result<Vec<TaskReturn>, Error> {
+    let gang = message::GangInvoke {
+        invokes,
+        min_fraction,
     };
-    message::write(stream, &req)?;
-    //let _ = message::read_response(stream)?;
+    call(client.gang_invoke(gang)).map(|r| r.results)
+}
+
+/// This method is for clients without access to the file system to invoke the gate at a path,
+/// which the scheduler resolves if it runs with a file system
+pub fn invoke_gate(client: &mut Client, invoke: message::GateInvoke) -> Result<TaskReturn, Error> {
+    if invoke.sync {
+        call(client.invoke_gate(invoke))
+    } else {
+        call_with_timeout(client.invoke_gate(invoke))
+    }
+}
+
+/// This method is for workers to invoke a function synchronously and receive its response
+/// chunks, which are passed to `on_chunk` as they come, before its result
+pub fn labeled_invoke_stream(
+    client: &mut Client,
+    labeled_invoke: message::LabeledInvoke,
+    mut on_chunk: impl FnMut(message::ResponseChunk),
+) -> Result<TaskReturn, Error> {
+    use message::invoke_event::Kind;
+    RUNTIME.block_on(async {
+        let mut events = client
+            .invoke_stream(labeled_invoke)
+            .await
+            .map_err(Error::Status)?
+            .into_inner();
+        while let Some(event) = events.message().await.map_err(Error::Status)? {
+            match event.kind {
+                Some(Kind::Chunk(chunk)) => on_chunk(chunk),
+                Some(Kind::Result(result)) => return Ok(result),
+                None => {}
+            }
+        }
+        Err(Error::Other("invocation stream ended without a result".to_string()))
+    })
+}
+
+/// This method is for workers to pass a response chunk on to the invoker
+pub fn send_chunk(client: &mut Client, chunk: message::ResponseChunk) -> Result<(), Error> {
+    call_with_timeout(client.send_chunk(chunk))?;
     Ok(())
 }
 
 /// This method is for local resource managers to update it's
 /// resource status, such as number of cached VMs per function
-pub fn update_resource(stream: &mut TcpStream, info: ResourceInfo) -> Result<(), Error> {
+pub fn update_resource(client: &mut Client, info: ResourceInfo) -> Result<(), Error> {
     let info = serde_json::to_vec(&info).unwrap();
-    let req = Request {
-        kind: Some(ReqKind::UpdateResource(message::UpdateResource { info })),
-    };
-    message::write(stream, &req)?;
-    //let _ = message::read_response(stream)?;
+    call_with_timeout(client.update_resource(message::UpdateResource { info }))?;
     Ok(())
 }
 
 /// This method is for local resrouce managers to drop itself
-pub fn drop_resource(stream: &mut TcpStream) -> Result<(), Error> {
-    let req = Request {
-        kind: Some(ReqKind::DropResource(message::DropResource {})),
-    };
-    message::write(stream, &req)?;
-    //let _ = message::read_response(stream)?;
+pub fn drop_resource(client: &mut Client) -> Result<(), Error> {
+    call_with_timeout(client.drop_resource(message::DropResource {}))?;
+    Ok(())
+}
+
+/// Stops dispatching to `node`, or the caller's node if `None`, and terminates its workers as
+/// they become idle, so that the node can be shut down without killing invocations. Idempotent
+/// invocations on the node are preempted to run again elsewhere. Draining another node than
+/// the caller's takes the scheduler's `admin_token`.
+pub fn drain(
+    client: &mut Client,
+    node: Option<std::net::IpAddr>,
+    admin_token: Option<&str>,
+) -> Result<(), Error> {
+    let node = node.map(|n| n.to_string()).unwrap_or_default();
+    let mut request = tonic::Request::new(message::DrainNode { node });
+    if let Some(token) = admin_token {
+        with_admin_token(&mut request, token)?;
+    }
+    call_with_timeout(client.drain_node(request))?;
     Ok(())
 }
 
+// Presents the scheduler's `admin_token` with the request
+fn with_admin_token<T>(request: &mut tonic::Request<T>, token: &str) -> Result<(), Error> {
+    let token = token
+        .parse()
+        .map_err(|_| Error::Other("the admin token isn't valid metadata".to_string()))?;
+    request.metadata_mut().insert(ADMIN_TOKEN_KEY, token);
+    Ok(())
+}
+
+/// Tells `node`, or every node if `None`, to re-read its worker configuration from the file
+/// system. Takes the scheduler's `admin_token`.
+pub fn reload(
+    client: &mut Client,
+    node: Option<std::net::IpAddr>,
+    admin_token: &str,
+) -> Result<(), Error> {
+    let node = node.map(|n| n.to_string()).unwrap_or_default();
+    let mut request = tonic::Request::new(message::ReloadNode { node });
+    with_admin_token(&mut request, admin_token)?;
+    call_with_timeout(client.reload_node(request))?;
+    Ok(())
+}
+
+/// Pauses VM `vm` of `node`, busy or idle, until it is resumed. The node no longer reuses the
+/// VM while paused, and a busy VM's invocation may time out. Takes the scheduler's
+/// `admin_token`.
+pub fn pause_vm(
+    client: &mut Client,
+    node: std::net::IpAddr,
+    vm: usize,
+    admin_token: &str,
+) -> Result<(), Error> {
+    let mut request = tonic::Request::new(message::PauseVm {
+        node: node.to_string(),
+        vm: vm as u64,
+        resume: false,
+    });
+    with_admin_token(&mut request, admin_token)?;
+    call_with_timeout(client.pause_vm(request))?;
+    Ok(())
+}
+
+/// Resumes VM `vm` of `node`, see `pause_vm`
+pub fn resume_vm(
+    client: &mut Client,
+    node: std::net::IpAddr,
+    vm: usize,
+    admin_token: &str,
+) -> Result<(), Error> {
+    let mut request = tonic::Request::new(message::PauseVm {
+        node: node.to_string(),
+        vm: vm as u64,
+        resume: true,
+    });
+    with_admin_token(&mut request, admin_token)?;
+    call_with_timeout(client.pause_vm(request))?;
+    Ok(())
+}
+
+/// Dashboard: VM inventories of all worker nodes
+pub fn inventory(client: &mut Client, admin_token: &str) -> Result<Vec<NodeInventory>, Error> {
+    let mut request = tonic::Request::new(message::GetInventory {});
+    with_admin_token(&mut request, admin_token)?;
+    let d = call_with_timeout(client.get_inventory(request))?;
+    serde_json::from_slice(&d.info).map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Dashboard: most recent dispatch decisions, oldest first
+pub fn dispatches(client: &mut Client, admin_token: &str) -> Result<Vec<Dispatch>, Error> {
+    let mut request = tonic::Request::new(message::GetDispatches {});
+    with_admin_token(&mut request, admin_token)?;
+    let d = call_with_timeout(client.get_dispatches(request))?;
+    serde_json::from_slice(&d.info).map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Dashboard: outcomes of finished invocations per function
+pub fn function_stats(
+    client: &mut Client,
+    admin_token: &str,
+) -> Result<Vec<FunctionStats>, Error> {
+    let mut request = tonic::Request::new(message::GetFunctionStats {});
+    with_admin_token(&mut request, admin_token)?;
+    let d = call_with_timeout(client.get_function_stats(request))?;
+    serde_json::from_slice(&d.info).map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Frontends: current queue depth, capacity and estimated wait, for backpressure
+pub fn load(client: &mut Client) -> Result<message::Load, Error> {
+    call_with_timeout(client.get_load(message::GetLoad {}))
+}
+
+/// Autoscalers: desired and current number of worker nodes
+pub fn scale(client: &mut Client, admin_token: &str) -> Result<message::Scale, Error> {
+    let mut request = tonic::Request::new(message::GetScale {});
+    with_admin_token(&mut request, admin_token)?;
+    call_with_timeout(client.get_scale(request))
+}
+
 #[serde_with::serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ResourceInfo {
@@ -94,4 +376,105 @@ pub struct ResourceInfo {
     pub stats: HashMap<Function, usize>,
     pub total_mem: usize,
     pub free_mem: usize,
+    /// idle VMs cached on the node
+    #[serde(default)]
+    pub vms: Vec<VmInfo>,
+    /// number of free passthrough devices by class
+    #[serde(default)]
+    pub free_devices: HashMap<String, usize>,
+    /// number of passthrough devices by class, free or not
+    #[serde(default)]
+    pub total_devices: HashMap<String, usize>,
+    /// memory of idle VMs kept warm for `Function::min_instances` or `Function::keep_alive`,
+    /// which the node does not evict
+    #[serde(default)]
+    pub reserved_mem: usize,
+    /// what the node offers functions, e.g. `cpu: icelake`, see `Function::requirements`
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// largest VM in MB the node runs, `total_mem` if absent
+    #[serde(default)]
+    pub max_vm_mem: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VmInfo {
+    /// the VM's id on the node
+    #[serde(default)]
+    pub id: usize,
+    pub function: Function,
+    pub label: Buckle,
+    pub memory: usize,
+    /// seconds since the UNIX epoch at which the VM became idle
+    pub idle_since: u64,
+    /// whether an operator paused the VM, which the node then doesn't reuse
+    #[serde(default)]
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeInventory {
+    pub node: String,
+    pub total_mem: usize,
+    pub free_mem: usize,
+    pub idle_workers: usize,
+    pub vms: Vec<VmInfo>,
+    #[serde(default)]
+    pub free_devices: HashMap<String, usize>,
+    #[serde(default)]
+    pub reserved_mem: usize,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    #[serde(default)]
+    pub max_vm_mem: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dispatch {
+    pub function: Function,
+    pub node: String,
+    /// whether the node was picked because it caches an idle VM of the function
+    pub cached: bool,
+    /// seconds since the UNIX epoch
+    pub time: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionStats {
+    pub function: Function,
+    /// finished invocations
+    pub invocations: u64,
+    /// invocations that ran out of memory
+    pub ooms: u64,
+    /// times the scheduler stopped an invocation to make room for a more urgent one
+    #[serde(default)]
+    pub preemptions: u64,
+    /// peak memory in MB of the most recent invocations that reported one, oldest first
+    #[serde(default)]
+    pub peak_memory: VecDeque<u64>,
+}
+
+impl FunctionStats {
+    /// Peak memory samples kept per function
+    pub const PEAK_SAMPLES: usize = 1000;
+    /// Fewest samples to recommend a memory size from
+    pub const MIN_SAMPLES: usize = 20;
+
+    /// Recommended memory size in MB: the 99th percentile of the peaks plus `headroom`, a
+    /// fraction of it, rounded up to 32MB and at least 128MB. A function that ran out of
+    /// memory gets twice its current size, as its peaks fall short of what it needs. `None` if
+    /// there are too few samples to tell.
+    pub fn recommended_memory(&self, headroom: f64) -> Option<usize> {
+        if self.ooms > 0 {
+            return Some(self.function.memory * 2);
+        }
+        if self.peak_memory.len() < Self::MIN_SAMPLES {
+            return None;
+        }
+        let mut peaks: Vec<u64> = self.peak_memory.iter().copied().collect();
+        peaks.sort_unstable();
+        let p99 = peaks[(peaks.len() * 99).div_ceil(100) - 1];
+        let memory = (p99 as f64 * (1.0 + headroom)).ceil() as usize;
+        Some(memory.div_ceil(32).max(4) * 32)
+    }
 }
@@ -1,151 +1,1003 @@
-use core::panic;
 use log::{debug, error, warn};
-use std::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
-use std::thread;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::{TcpListenerStream, UnboundedReceiverStream, UnixListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::server::Connected;
+use tonic::{Request, Response, Status};
 
 use crate::fs;
 
+use super::autoscale;
+use super::codec;
 use super::message;
-use super::resource_manager::ResourceManager;
+use super::message::scheduler_server::{Scheduler, SchedulerServer};
+use super::queue::{AdmissionError, TaskQueue};
+use super::resolver::{self, Resolve};
+use super::resource_manager::{Callback, ResourceManager};
 use super::rpc::ResourceInfo;
+use super::simulate::{self, Simulation};
 use super::Task;
 
 pub type Manager = Arc<Mutex<ResourceManager>>;
 
+// attempts at delivering a result to a callback URL
+const CALLBACK_ATTEMPTS: u32 = 5;
+
 pub struct RpcServer {
-    manager: Manager,
-    listener: TcpListener,
-    queue_tx: crossbeam::channel::Sender<Task>,
-    cvar: Arc<Condvar>,
+    // ADDR:PORT or unix://PATH
+    addr: String,
+    service: Service,
+    simulation: Option<Simulation>,
+}
+
+#[derive(Clone)]
+pub(super) struct Service {
+    pub(super) manager: Manager,
+    queue: Arc<TaskQueue>,
+    pub(super) cvar: Arc<Condvar>,
+    // resolves gates for `InvokeGate`, which is disabled without one
+    resolver: Option<Arc<dyn Resolve>>,
+    // answers `GetScale`, which is disabled without one
+    autoscale: Option<autoscale::Policy>,
+    // lets admins drain other nodes than their own and read the dashboard and statistics, which
+    // no one can without one
+    admin_token: Option<Arc<[u8]>>,
+    // hosts results may be POSTed to, no callbacks are accepted without them
+    callback_hosts: Option<Arc<[String]>>,
 }
 
 impl RpcServer {
-    pub fn new(
-        addr: &str,
-        manager: Manager,
-        queue_tx: crossbeam::channel::Sender<Task>,
-        cvar: Arc<Condvar>,
-    ) -> Self {
+    pub fn new(addr: &str, manager: Manager, queue: Arc<TaskQueue>, cvar: Arc<Condvar>) -> Self {
         Self {
-            manager,
-            listener: TcpListener::bind(addr).expect("bind to the TCP listening address"),
-            queue_tx,
-            cvar,
+            addr: addr.to_string(),
+            service: Service {
+                manager,
+                queue,
+                cvar,
+                resolver: None,
+                autoscale: None,
+                admin_token: None,
+                callback_hosts: None,
+            },
+            simulation: None,
         }
     }
 
+    /// Lets clients invoke gates by path, see `resolver`
+    pub fn set_resolver(&mut self, resolver: Arc<dyn Resolve>) {
+        self.service.resolver = Some(resolver);
+    }
+
+    /// Reports a desired number of worker nodes to autoscalers, see `autoscale`
+    pub fn set_autoscale(&mut self, policy: autoscale::Policy) {
+        self.service.autoscale = Some(policy);
+    }
+
+    /// Lets clients presenting `token` drain other nodes than their own, reload nodes and pause
+    /// VMs, see `rpc::drain`, `rpc::reload` and `rpc::pause_vm`
+    pub fn set_admin_token(&mut self, token: &[u8]) {
+        self.service.admin_token = Some(token.into());
+    }
+
+    /// Lets async invocations have their results POSTed to URLs on `hosts`, see `Callback`
+    pub fn set_callback_hosts(&mut self, hosts: Vec<String>) {
+        self.service.callback_hosts = Some(hosts.into());
+    }
+
+    /// Runs synthetic workers alongside the real ones, see `simulate`
+    pub fn simulate(&mut self, simulation: Simulation) {
+        self.simulation = Some(simulation);
+    }
+
     pub fn run(self) {
-        loop {
-            for stream in self.listener.incoming() {
-                if let Ok(stream) = stream {
-                    debug!("connection from {:?}", stream.peer_addr());
-                    let manager = Arc::clone(&self.manager);
-                    let queue_tx = self.queue_tx.clone();
-                    let cvar = self.cvar.clone();
-
-                    thread::spawn(move || RpcServer::serve(stream, manager, queue_tx, cvar));
-                }
+        let rt = tokio::runtime::Runtime::new().expect("tokio runtime");
+        let addr = self.addr;
+        if let Some(simulation) = self.simulation {
+            rt.spawn(simulate::run(self.service.clone(), simulation));
+        }
+        // invocations the scheduler thread gives up on
+        let (failed_tx, mut failed) = mpsc::unbounded_channel();
+        self.service.manager.lock().unwrap().set_failed(failed_tx);
+        let service = self.service.clone();
+        rt.spawn(async move {
+            while let Some((uuid, result)) = failed.recv().await {
+                service.deliver(uuid, result);
+            }
+        });
+        let server = SchedulerServer::new(self.service)
+            .max_decoding_message_size(codec::max_message_size())
+            .max_encoding_message_size(codec::max_message_size());
+        let mut builder = tonic::transport::Server::builder();
+        let router = builder.add_service(server);
+        // the listeners are registered with the runtime
+        let _guard = rt.enter();
+        // serve the socket systemd passed if there is one
+        match addr.strip_prefix("unix://") {
+            Some(path) => {
+                let listener = crate::systemd::unix_listener().unwrap_or_else(|| {
+                    let _ = std::fs::remove_file(path);
+                    std::os::unix::net::UnixListener::bind(path).expect("bind to the Unix listener")
+                });
+                listener.set_nonblocking(true).expect("set the listener non-blocking");
+                let listener = tokio::net::UnixListener::from_std(listener).expect("listen");
+                let incoming = UnixListenerStream::new(listener).map(|r| r.map(UdsConn::new));
+                crate::systemd::notify_ready();
+                rt.block_on(router.serve_with_incoming(incoming))
+            }
+            None => {
+                let listener = crate::systemd::tcp_listener().unwrap_or_else(|| {
+                    std::net::TcpListener::bind(&addr).expect("bind to the listening address")
+                });
+                listener.set_nonblocking(true).expect("set the listener non-blocking");
+                let listener = tokio::net::TcpListener::from_std(listener).expect("listen");
+                crate::systemd::notify_ready();
+                rt.block_on(router.serve_with_incoming(TcpListenerStream::new(listener)))
             }
         }
+        .expect("serve scheduler RPCs");
     }
+}
 
-    // Process the RPC request
-    fn serve(
-        mut stream: TcpStream,
-        manager: Manager,
-        queue_tx: crossbeam::channel::Sender<Task>,
-        cvar: Arc<Condvar>,
-    ) {
-        while let Ok(req) = message::read_request(&mut stream) {
-            use message::{request::Kind, response::Kind as ResKind, Response};
-            match req.kind {
-                Some(Kind::Ping(_)) => {
-                    debug!("PING");
-                    let res = Response {
-                        kind: Some(ResKind::Pong(message::Pong {})),
-                    };
-                    let _ = message::write(&mut stream, &res);
-                }
-                Some(Kind::GetTask(r)) => {
-                    debug!("RPC GET from {:?}", r.thread_id);
-                    manager
-                        .lock()
-                        .unwrap()
-                        .add_idle(stream.peer_addr().unwrap(), stream.try_clone().unwrap());
-                    cvar.notify_one();
-                }
-                Some(Kind::FinishTask(r)) => {
-                    //let res = Response { kind: None };
-                    //let _ = message::write(&mut stream, &res);
-                    let result = r.result.unwrap();
-                    debug!("RPC FINISH result {:?}", result);
-                    if let Ok(uuid) = uuid::Uuid::parse_str(&r.task_id) {
-                        if !uuid.is_nil() {
-                            let mut manager = manager.lock().unwrap();
-                            if let Some(mut conn) = manager.wait_list.remove(&uuid) {
-                                let _ = message::write(&mut conn, &result);
-                            }
-                        }
-                    }
-                }
-                Some(Kind::LabeledInvoke(r)) => {
-                    debug!("RPC LABELED INVOKE received {:?}", r);
-                    let uuid = uuid::Uuid::new_v4();
-                    let sync = r.sync;
-                    match queue_tx.try_send(Task::Invoke(uuid, r)) {
-                        Err(crossbeam::channel::TrySendError::Full(_)) => {
-                            warn!("Dropping Invocation from {:?}", stream.peer_addr());
-                            let ret = message::TaskReturn {
-                                code: message::ReturnCode::QueueFull as i32,
-                                payload: None,
-                                label: Some(fs::utils::get_current_label().into()),
-                            };
-                            let _ = message::write(&mut stream, &ret);
-                        }
-                        Err(crossbeam::channel::TrySendError::Disconnected(_)) => {
-                            panic!("Broken request queue")
-                        }
-                        Ok(()) => {
-                            if sync {
-                                manager
-                                    .lock()
-                                    .unwrap()
-                                    .wait_list
-                                    .insert(uuid, stream.try_clone().unwrap());
-                            }
+/// Unix domain socket connection. Unix peers have no IP address, so each connection is given a
+/// distinct loopback address, which makes all of them one node to the resource manager.
+struct UdsConn {
+    stream: UnixStream,
+    peer: SocketAddr,
+}
+
+#[derive(Clone)]
+struct UdsPeer(SocketAddr);
+
+impl UdsConn {
+    fn new(stream: UnixStream) -> Self {
+        static NEXT_PORT: AtomicU16 = AtomicU16::new(1);
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        Self {
+            stream,
+            peer: (Ipv4Addr::LOCALHOST, port).into(),
+        }
+    }
+}
+
+impl Connected for UdsConn {
+    type ConnectInfo = UdsPeer;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        UdsPeer(self.peer)
+    }
+}
+
+impl AsyncRead for UdsConn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UdsConn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// address of a TCP peer, or the address given to a Unix domain socket peer
+fn peer_addr<T>(request: &Request<T>) -> Option<SocketAddr> {
+    request
+        .remote_addr()
+        .or_else(|| request.extensions().get::<UdsPeer>().map(|p| p.0))
+}
+
+fn unknown_peer() -> Status {
+    Status::failed_precondition("unknown peer address")
+}
+
+// whether the request carries the admin token, see `rpc::drain`
+fn is_admin<T>(request: &Request<T>, token: Option<&[u8]>) -> bool {
+    let presented = request
+        .metadata()
+        .get(super::rpc::ADMIN_TOKEN_KEY)
+        .map(|v| v.as_bytes());
+    token_matches(token, presented)
+}
+
+fn token_matches(expected: Option<&[u8]>, presented: Option<&[u8]>) -> bool {
+    match (expected, presented) {
+        (Some(expected), Some(presented)) => {
+            expected.len() == presented.len() && openssl::memcmp::eq(expected, presented)
+        }
+        _ => false,
+    }
+}
+
+impl Service {
+    /// Queues the invocation and waits for its result if it is sync
+    async fn invoke(
+        &self,
+        peer: Option<SocketAddr>,
+        r: message::LabeledInvoke,
+    ) -> Result<message::TaskReturn, Status> {
+        match self.enqueue(peer, uuid::Uuid::new_v4(), r).await? {
+            Some(rx) => rx.await.map_err(|_| Status::aborted("invocation dropped")),
+            // async invocations only learn that they are queued
+            None => Ok(message::TaskReturn {
+                code: message::ReturnCode::Success as i32,
+                payload: None,
+                label: None,
+                error: None,
+                receipts: vec![],
+                taint_trace: vec![],
+            }),
+        }
+    }
+
+    /// Queues the invocation and returns the receiver of its result, or `None` if the invocation
+    /// is async. The result of an invocation the queue does not admit is ready right away.
+    async fn enqueue(
+        &self,
+        peer: Option<SocketAddr>,
+        uuid: uuid::Uuid,
+        r: message::LabeledInvoke,
+    ) -> Result<Option<oneshot::Receiver<message::TaskReturn>>, Status> {
+        self.check_callback(&r)?;
+        let sync = r.sync;
+        let (tx, rx) = oneshot::channel();
+        // register the waiter first so that a fast worker cannot finish the task
+        // before its result has somewhere to go
+        let mut tx = Some(tx);
+        if sync {
+            let tx = tx.take().unwrap();
+            self.manager.lock().unwrap().wait_list.insert(uuid, tx);
+        } else if let Some(callback) = Callback::of(&r) {
+            self.manager.lock().unwrap().callbacks.insert(uuid, callback);
+        }
+        if let Err(e) = self.push(Task::Invoke(uuid, Box::new(r))).await? {
+            warn!("Dropping Invocation from {:?}: {:?}", peer, e);
+            // the invoker learns right away, not through its callback
+            self.manager.lock().unwrap().callbacks.remove(&uuid);
+            let waiter = self.manager.lock().unwrap().wait_list.remove(&uuid);
+            if let Some(tx) = waiter.or(tx) {
+                let _ = tx.send(queue_error(admission_error_code(e)));
+            }
+            return Ok(Some(rx));
+        }
+        Ok(sync.then_some(rx))
+    }
+
+    // Refuses callbacks to hosts the operator has not allowed
+    fn check_callback(&self, r: &message::LabeledInvoke) -> Result<(), Status> {
+        if r.sync || r.callback.is_empty() {
+            return Ok(());
+        }
+        let host = reqwest::Url::parse(&r.callback)
+            .ok()
+            .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+            .and_then(|url| url.host_str().map(String::from))
+            .ok_or_else(|| Status::invalid_argument("callbacks must be http or https URLs"))?;
+        if !callback_allowed(self.callback_hosts.as_deref(), &host) {
+            return Err(Status::permission_denied(format!(
+                "callbacks to {} are not allowed",
+                host
+            )));
+        }
+        Ok(())
+    }
+
+    /// Records the result of a task a worker finished and hands it to its invoker
+    pub(super) async fn finish(&self, r: message::FinishTask) -> Result<(), Status> {
+        let result = r.result.unwrap_or_default();
+        debug!("RPC FINISH result {:?}", result);
+        if let Ok(uuid) = uuid::Uuid::parse_str(&r.task_id) {
+            if self.manager.lock().unwrap().finish_provisioning(uuid, &result) {
+                return Ok(());
+            }
+        }
+        if let Some(function) = r.function {
+            self.manager
+                .lock()
+                .unwrap()
+                .record_result(function.into(), &result);
+        }
+        if let Ok(uuid) = uuid::Uuid::parse_str(&r.task_id) {
+            if !uuid.is_nil() {
+                let preemptible = self.manager.lock().unwrap().finish_preemptible(uuid);
+                if let Some(invoke) = preemptible {
+                    if result.code == message::ReturnCode::Preempted as i32 {
+                        // run it again once there is room, its invoker keeps waiting
+                        match self.push(Task::Invoke(uuid, invoke)).await? {
+                            Ok(()) => return Ok(()),
+                            Err(e) => warn!("Dropping preempted invocation {:?}: {:?}", uuid, e),
                         }
                     }
                 }
-                Some(Kind::UpdateResource(r)) => {
-                    debug!("RPC UPDATE received");
-                    let manager = &mut manager.lock().unwrap();
-                    let info = serde_json::from_slice::<ResourceInfo>(&r.info);
-                    if let Ok(info) = info {
-                        let addr = stream.peer_addr().unwrap().ip();
-                        manager.update(addr, info);
-                        //let res = Response { kind: None };
-                        //let _ = message::write(&mut stream, &res);
-                    } else {
-                        // TODO Send error code
-                        error!("Failed to deserialize ResourceInfo")
-                        //let res = Response { kind: None };
-                        //let _ = message::write(&mut stream, &res);
+                self.deliver(uuid, result);
+            }
+        }
+        Ok(())
+    }
+
+    // Hands the result of the invocation `uuid` to its waiter or callback, if any
+    fn deliver(&self, uuid: uuid::Uuid, result: message::TaskReturn) {
+        let (waiter, callback) = {
+            let mut manager = self.manager.lock().unwrap();
+            (manager.wait_list.remove(&uuid), manager.callbacks.remove(&uuid))
+        };
+        if let Some(tx) = waiter {
+            let _ = tx.send(result);
+        } else if let Some(callback) = callback {
+            post_result(callback, result);
+        }
+    }
+
+    /// Queues the task, failing the invocations of the task it sheds, if any
+    async fn push(&self, task: Task) -> Result<Result<(), AdmissionError>, Status> {
+        // pushing may block under the block-with-deadline admission policy
+        let queue = self.queue.clone();
+        let pushed = tokio::task::spawn_blocking(move || queue.push(task))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        if let Ok(Some(victim)) = &pushed {
+            for victim in victim.ids() {
+                warn!("Shed queued invocation {:?}", victim);
+                self.deliver(victim, queue_error(message::ReturnCode::QueueShed));
+            }
+        }
+        Ok(pushed.map(|_| ()))
+    }
+}
+
+fn admission_error_code(e: AdmissionError) -> message::ReturnCode {
+    match e {
+        AdmissionError::Full => message::ReturnCode::QueueFull,
+        AdmissionError::Shed => message::ReturnCode::QueueShed,
+        AdmissionError::Timeout => message::ReturnCode::QueueTimeout,
+    }
+}
+
+// The result, without its body if the invoker of `readable` can't read it
+fn withhold_unreadable(
+    readable: &message::LabeledInvoke,
+    result: message::TaskReturn,
+) -> message::TaskReturn {
+    match result.label.clone() {
+        Some(label) if !resolver::can_read(readable, &label.clone().into()) => {
+            let mut withheld = message::TaskReturn {
+                code: message::ReturnCode::Unauthorized as i32,
+                label: Some(label),
+                taint_trace: result.taint_trace,
+                ..Default::default()
+            };
+            let (clearance, privilege) = resolver::invoker_clearance(readable);
+            withheld.redact_taint_trace(&clearance, &privilege);
+            withheld
+        }
+        _ => result,
+    }
+}
+
+// POSTs the protobuf encoded result to the callback's URL, retrying with backoff until the
+// endpoint accepts it
+fn post_result(callback: Callback, result: message::TaskReturn) {
+    use prost::Message;
+    let body = withhold_unreadable(&callback.readable, result).encode_to_vec();
+    tokio::spawn(async move {
+        let client = match callback_client(&callback.url).await {
+            Ok(client) => client,
+            Err(e) => {
+                error!("refusing to deliver a result to callback {}: {}", callback.url, e);
+                return;
+            }
+        };
+        for attempt in 1..=CALLBACK_ATTEMPTS {
+            let res = client
+                .post(&callback.url)
+                .header("content-type", "application/x-protobuf")
+                .body(body.clone())
+                .send()
+                .await;
+            match res {
+                Ok(r) if r.status().is_success() => return,
+                Ok(r) => warn!("callback {} answered {}", callback.url, r.status()),
+                Err(e) => warn!("callback {} failed: {}", callback.url, e),
+            }
+            tokio::time::sleep(super::rpc::backoff(attempt)).await;
+        }
+        error!("giving up on delivering a result to callback {}", callback.url);
+    });
+}
+
+// A client that only connects to the callback's host at the addresses it resolves to now, all of
+// which must be public, and follows no redirects, so the callback can't reach internal services
+async fn callback_client(url: &str) -> Result<reqwest::Client, String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    let port = url.port_or_known_default().ok_or("no port")?;
+    let builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    let (builder, addrs) = match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, port))
+                .await
+                .map_err(|e| e.to_string())?
+                .collect();
+            let first = *addrs.first().ok_or("resolves to no address")?;
+            (builder.resolve(domain, first), addrs)
+        }
+        Some(url::Host::Ipv4(ip)) => (builder, vec![SocketAddr::new(ip.into(), port)]),
+        Some(url::Host::Ipv6(ip)) => (builder, vec![SocketAddr::new(ip.into(), port)]),
+        None => return Err("no host".to_string()),
+    };
+    if let Some(addr) = addrs.iter().find(|addr| is_internal(addr.ip())) {
+        return Err(format!("resolves to internal address {}", addr.ip()));
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn callback_allowed(hosts: Option<&[String]>, host: &str) -> bool {
+    hosts.is_some_and(|hosts| hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+}
+
+// loopback, link-local, private and other addresses that aren't on the public internet
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // shared address space, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(ip.into()),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, fc00::/7
+                    || first & 0xfe00 == 0xfc00
+                    // link-local, fe80::/10
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+fn queue_error(code: message::ReturnCode) -> message::TaskReturn {
+    message::TaskReturn {
+        code: code as i32,
+        payload: None,
+        label: Some(fs::utils::get_current_label().into()),
+        error: None,
+        receipts: vec![],
+        taint_trace: vec![],
+    }
+}
+
+#[tonic::async_trait]
+impl Scheduler for Service {
+    async fn get_task(
+        &self,
+        request: Request<message::GetTask>,
+    ) -> Result<Response<message::Response>, Status> {
+        debug!("RPC GET from {:?}", request.get_ref().thread_id);
+        let addr = peer_addr(&request).ok_or_else(unknown_peer)?;
+        let (tx, rx) = oneshot::channel();
+        self.manager.lock().unwrap().add_idle(addr, tx);
+        self.cvar.notify_one();
+        // if the worker disconnects, this future is dropped along with `rx`, and the scheduling
+        // thread moves on to another worker
+        rx.await
+            .map(Response::new)
+            .map_err(|_| Status::unavailable("worker dropped by the scheduler"))
+    }
+
+    async fn finish_task(
+        &self,
+        request: Request<message::FinishTask>,
+    ) -> Result<Response<message::Ack>, Status> {
+        self.finish(request.into_inner()).await?;
+        Ok(Response::new(message::Ack {}))
+    }
+
+    async fn watch_preemption(
+        &self,
+        request: Request<message::WatchPreemption>,
+    ) -> Result<Response<message::Ack>, Status> {
+        let task_id = &request.get_ref().task_id;
+        let uuid =
+            uuid::Uuid::parse_str(task_id).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let rx = self
+            .manager
+            .lock()
+            .unwrap()
+            .watch_preemption(uuid)
+            .ok_or_else(|| Status::not_found("no such preemptible task"))?;
+        // the sender goes away when the task finishes
+        rx.await
+            .map(|_| Response::new(message::Ack {}))
+            .map_err(|_| Status::cancelled("task finished"))
+    }
+
+    async fn labeled_invoke(
+        &self,
+        request: Request<message::LabeledInvoke>,
+    ) -> Result<Response<message::TaskReturn>, Status> {
+        let peer = peer_addr(&request);
+        let mut r = request.into_inner();
+        debug!("RPC LABELED INVOKE received {:?}", r);
+        r.stream = false;
+        self.invoke(peer, r).await.map(Response::new)
+    }
+
+    async fn gang_invoke(
+        &self,
+        request: Request<message::GangInvoke>,
+    ) -> Result<Response<message::GangReturn>, Status> {
+        let peer = peer_addr(&request);
+        let r = request.into_inner();
+        debug!("RPC GANG INVOKE received {} invocations", r.invokes.len());
+        for invoke in r.invokes.iter() {
+            self.check_callback(invoke)?;
+        }
+        let n = r.invokes.len();
+        let quorum = if r.min_fraction > 0.0 && r.min_fraction < 1.0 {
+            ((n as f64 * r.min_fraction).ceil() as usize).max(1)
+        } else {
+            n
+        };
+        let functions: Vec<fs::Function> = r
+            .invokes
+            .iter()
+            .filter_map(|invoke| Some(invoke.function.clone()?.into()))
+            .collect();
+        let capacity = self.manager.lock().unwrap().gang_capacity(&functions);
+        if capacity.is_some_and(|capacity| capacity < quorum) {
+            warn!("Rejecting gang invocation from {:?}: quorum {} over capacity", peer, quorum);
+            let code = message::ReturnCode::Unschedulable;
+            let results = (0..n).map(|_| queue_error(code)).collect();
+            return Ok(Response::new(message::GangReturn { results }));
+        }
+        let mut members = Vec::with_capacity(n);
+        let mut waiters = Vec::with_capacity(n);
+        {
+            // register the waiters first so that a fast worker cannot finish a member
+            // before its result has somewhere to go
+            let mut manager = self.manager.lock().unwrap();
+            for mut invoke in r.invokes {
+                invoke.stream = false;
+                let uuid = uuid::Uuid::new_v4();
+                if invoke.sync {
+                    let (tx, rx) = oneshot::channel();
+                    manager.wait_list.insert(uuid, tx);
+                    waiters.push(Some(rx));
+                } else {
+                    if let Some(callback) = Callback::of(&invoke) {
+                        manager.callbacks.insert(uuid, callback);
                     }
-                    cvar.notify_one();
-                }
-                Some(Kind::DropResource(_)) => {
-                    debug!("RPC DROP received");
-                    let manager = &mut manager.lock().unwrap();
-                    let addr = stream.peer_addr().unwrap().ip();
-                    manager.remove(addr);
-                    //let res = Response { kind: None };
-                    //let _ = message::write(&mut stream, &res);
-                    break;
+                    waiters.push(None);
                 }
-                _ => {}
+                members.push((uuid, Box::new(invoke)));
             }
         }
-        error!("Peer disconnected {:?}", stream.peer_addr());
+        let ids: Vec<_> = members.iter().map(|(uuid, _)| *uuid).collect();
+        if let Err(e) = self.push(Task::Gang(members, quorum)).await? {
+            warn!("Dropping gang invocation from {:?}: {:?}", peer, e);
+            let mut manager = self.manager.lock().unwrap();
+            for uuid in ids {
+                manager.wait_list.remove(&uuid);
+                manager.callbacks.remove(&uuid);
+            }
+            let code = admission_error_code(e);
+            let results = (0..n).map(|_| queue_error(code)).collect();
+            return Ok(Response::new(message::GangReturn { results }));
+        }
+        let mut results = Vec::with_capacity(n);
+        for waiter in waiters {
+            results.push(match waiter {
+                Some(rx) => rx.await.map_err(|_| Status::aborted("invocation dropped"))?,
+                None => message::TaskReturn {
+                    code: message::ReturnCode::Success as i32,
+                    ..Default::default()
+                },
+            });
+        }
+        Ok(Response::new(message::GangReturn { results }))
+    }
+
+    async fn invoke_gate(
+        &self,
+        request: Request<message::GateInvoke>,
+    ) -> Result<Response<message::TaskReturn>, Status> {
+        let peer = peer_addr(&request);
+        let r = request.into_inner();
+        debug!("RPC INVOKE GATE received {:?}", r.gate);
+        let resolver = self
+            .resolver
+            .clone()
+            .ok_or_else(|| Status::unimplemented("gate resolution is disabled"))?;
+        // resolution reads the file system and uses thread-local labels
+        let resolved = tokio::task::spawn_blocking(move || resolver.resolve(r))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let invoke = match resolved {
+            Ok(invoke) => invoke,
+            // a gate that can't be invoked is the invocation's result rather than an RPC error
+            Err(resolver::Error::Gate(e)) => {
+                return Ok(Response::new(message::TaskReturn {
+                    code: message::ReturnCode::for_gate_error(&e) as i32,
+                    ..Default::default()
+                }));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let readable = message::LabeledInvoke {
+            label: invoke.label.clone(),
+            invoker: invoke.invoker.clone(),
+            ..Default::default()
+        };
+        let result = self.invoke(peer, invoke).await?;
+        Ok(Response::new(withhold_unreadable(&readable, result)))
+    }
+
+    type InvokeStreamStream =
+        Pin<Box<dyn Stream<Item = Result<message::InvokeEvent, Status>> + Send + 'static>>;
+
+    async fn invoke_stream(
+        &self,
+        request: Request<message::LabeledInvoke>,
+    ) -> Result<Response<Self::InvokeStreamStream>, Status> {
+        use message::invoke_event::Kind;
+        let peer = peer_addr(&request);
+        let mut r = request.into_inner();
+        debug!("RPC INVOKE STREAM received {:?}", r);
+        r.sync = true;
+        r.stream = true;
+        let uuid = uuid::Uuid::new_v4();
+        let (tx, rx) = mpsc::unbounded_channel();
+        // like the waiter, the stream must be registered before a worker can send chunks
+        self.manager.lock().unwrap().streams.insert(uuid, tx.clone());
+        let waiter = self.enqueue(peer, uuid, r).await;
+        let manager = self.manager.clone();
+        tokio::spawn(async move {
+            let result = match waiter {
+                Ok(Some(rx)) => rx.await.map_err(|_| Status::aborted("invocation dropped")),
+                Ok(None) => unreachable!("streaming invocations are sync"),
+                Err(e) => Err(e),
+            };
+            // chunks of the task were all forwarded before it finished
+            manager.lock().unwrap().streams.remove(&uuid);
+            let event = result.map(|result| message::InvokeEvent {
+                kind: Some(Kind::Result(result)),
+            });
+            let _ = tx.send(event);
+        });
+        Ok(Response::new(Box::pin(UnboundedReceiverStream::new(rx))))
+    }
+
+    async fn send_chunk(
+        &self,
+        request: Request<message::ResponseChunk>,
+    ) -> Result<Response<message::Ack>, Status> {
+        use message::invoke_event::Kind;
+        let chunk = request.into_inner();
+        if let Ok(uuid) = uuid::Uuid::parse_str(&chunk.task_id) {
+            let stream = self.manager.lock().unwrap().streams.get(&uuid).cloned();
+            if let Some(tx) = stream {
+                let _ = tx.send(Ok(message::InvokeEvent {
+                    kind: Some(Kind::Chunk(chunk)),
+                }));
+            }
+        }
+        Ok(Response::new(message::Ack {}))
+    }
+
+    async fn update_resource(
+        &self,
+        request: Request<message::UpdateResource>,
+    ) -> Result<Response<message::Ack>, Status> {
+        debug!("RPC UPDATE received");
+        let addr = peer_addr(&request).ok_or_else(unknown_peer)?.ip();
+        let info = serde_json::from_slice::<ResourceInfo>(&request.get_ref().info);
+        match info {
+            Ok(info) => {
+                self.manager.lock().unwrap().update(addr, info);
+                self.cvar.notify_one();
+                Ok(Response::new(message::Ack {}))
+            }
+            Err(e) => {
+                error!("Failed to deserialize ResourceInfo");
+                Err(Status::invalid_argument(e.to_string()))
+            }
+        }
+    }
+
+    async fn drop_resource(
+        &self,
+        request: Request<message::DropResource>,
+    ) -> Result<Response<message::Ack>, Status> {
+        debug!("RPC DROP received");
+        let addr = peer_addr(&request).ok_or_else(unknown_peer)?.ip();
+        self.manager.lock().unwrap().remove(addr);
+        Ok(Response::new(message::Ack {}))
+    }
+
+    async fn drain_node(
+        &self,
+        request: Request<message::DrainNode>,
+    ) -> Result<Response<message::Ack>, Status> {
+        let node = &request.get_ref().node;
+        // nodes drain themselves, only admins drain others
+        let addr = if node.is_empty() {
+            peer_addr(&request).ok_or_else(unknown_peer)?.ip()
+        } else if is_admin(&request, self.admin_token.as_deref()) {
+            node.parse()
+                .map_err(|_| Status::invalid_argument("invalid node address"))?
+        } else {
+            return Err(Status::unauthenticated("draining other nodes takes the admin token"));
+        };
+        debug!("RPC DRAIN {:?}", addr);
+        self.manager.lock().unwrap().drain(addr);
+        Ok(Response::new(message::Ack {}))
+    }
+
+    async fn reload_node(
+        &self,
+        request: Request<message::ReloadNode>,
+    ) -> Result<Response<message::Ack>, Status> {
+        if !is_admin(&request, self.admin_token.as_deref()) {
+            return Err(Status::unauthenticated("reloading nodes takes the admin token"));
+        }
+        let node = &request.get_ref().node;
+        let addr = if node.is_empty() {
+            None
+        } else {
+            Some(
+                node.parse()
+                    .map_err(|_| Status::invalid_argument("invalid node address"))?,
+            )
+        };
+        debug!("RPC RELOAD {:?}", addr);
+        self.manager.lock().unwrap().reload(addr);
+        Ok(Response::new(message::Ack {}))
+    }
+
+    async fn pause_vm(
+        &self,
+        request: Request<message::PauseVm>,
+    ) -> Result<Response<message::Ack>, Status> {
+        if !is_admin(&request, self.admin_token.as_deref()) {
+            return Err(Status::unauthenticated("pausing VMs takes the admin token"));
+        }
+        let addr = request
+            .get_ref()
+            .node
+            .parse()
+            .map_err(|_| Status::invalid_argument("invalid node address"))?;
+        debug!("RPC PAUSE VM {:?}", request.get_ref());
+        self.manager.lock().unwrap().pause_vm(addr, request.into_inner());
+        Ok(Response::new(message::Ack {}))
+    }
+
+    async fn ping(
+        &self,
+        _request: Request<message::Ping>,
+    ) -> Result<Response<message::Pong>, Status> {
+        debug!("PING");
+        Ok(Response::new(message::Pong {}))
+    }
+
+    async fn get_inventory(
+        &self,
+        request: Request<message::GetInventory>,
+    ) -> Result<Response<message::Dashboard>, Status> {
+        debug!("RPC INVENTORY received");
+        if !is_admin(&request, self.admin_token.as_deref()) {
+            return Err(Status::unauthenticated("the inventory takes the admin token"));
+        }
+        let info = serde_json::to_vec(&self.manager.lock().unwrap().inventory()).unwrap();
+        Ok(Response::new(message::Dashboard { info }))
+    }
+
+    async fn get_dispatches(
+        &self,
+        request: Request<message::GetDispatches>,
+    ) -> Result<Response<message::Dashboard>, Status> {
+        debug!("RPC DISPATCHES received");
+        if !is_admin(&request, self.admin_token.as_deref()) {
+            return Err(Status::unauthenticated("the dispatches takes the admin token"));
+        }
+        let info = serde_json::to_vec(&self.manager.lock().unwrap().dispatches()).unwrap();
+        Ok(Response::new(message::Dashboard { info }))
+    }
+
+    async fn get_function_stats(
+        &self,
+        request: Request<message::GetFunctionStats>,
+    ) -> Result<Response<message::Dashboard>, Status> {
+        debug!("RPC FUNCTION STATS received");
+        if !is_admin(&request, self.admin_token.as_deref()) {
+            return Err(Status::unauthenticated("function statistics take the admin token"));
+        }
+        let info = serde_json::to_vec(&self.manager.lock().unwrap().function_stats()).unwrap();
+        Ok(Response::new(message::Dashboard { info }))
+    }
+
+    async fn get_load(
+        &self,
+        _request: Request<message::GetLoad>,
+    ) -> Result<Response<message::Load>, Status> {
+        debug!("RPC LOAD received");
+        Ok(Response::new(message::Load {
+            queue_depth: self.queue.len() as u64,
+            capacity: self.queue.capacity() as u64,
+            estimated_wait_ms: self.queue.estimated_wait().as_millis() as u64,
+        }))
+    }
+
+    async fn get_scale(
+        &self,
+        request: Request<message::GetScale>,
+    ) -> Result<Response<message::Scale>, Status> {
+        debug!("RPC SCALE received");
+        if !is_admin(&request, self.admin_token.as_deref()) {
+            return Err(Status::unauthenticated("the desired scale takes the admin token"));
+        }
+        let policy = self
+            .autoscale
+            .as_ref()
+            .ok_or_else(|| Status::unimplemented("autoscaling is disabled"))?;
+        let (arrival_rate, dispatch_rate) = self.queue.rates();
+        let queued = self.queue.queued_functions();
+        let manager = self.manager.lock().unwrap();
+        let desired =
+            policy.desired_workers(manager.running(), &queued, arrival_rate, dispatch_rate);
+        Ok(Response::new(message::Scale {
+            desired_workers: desired as u64,
+            current_workers: manager.active_nodes() as u64,
+            queue_depth: self.queue.len() as u64,
+            arrival_rate,
+            dispatch_rate,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches(Some(b"secret"), Some(b"secret")));
+        assert!(!token_matches(Some(b"secret"), Some(b"secre")));
+        assert!(!token_matches(Some(b"secret"), Some(b"Secret")));
+        assert!(!token_matches(Some(b"secret"), None));
+        // without a token no one is an admin
+        assert!(!token_matches(None, Some(b"")));
+    }
+
+    #[test]
+    fn test_callback_allowed() {
+        let hosts = vec!["hooks.example.com".to_string()];
+        assert!(callback_allowed(Some(&hosts), "hooks.example.com"));
+        assert!(callback_allowed(Some(&hosts), "HOOKS.example.com"));
+        assert!(!callback_allowed(Some(&hosts), "example.com"));
+        // without a list no host is allowed
+        assert!(!callback_allowed(None, "hooks.example.com"));
+    }
+
+    #[test]
+    fn test_is_internal() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(!is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    // A service whose admin token is `secret`
+    fn service() -> Service {
+        let mut server = RpcServer::new(
+            "127.0.0.1:0",
+            Arc::new(Mutex::new(ResourceManager::new())),
+            Arc::new(TaskQueue::new(16, super::super::queue::AdmissionPolicy::Reject)),
+            Arc::new(Condvar::new()),
+        );
+        server.set_admin_token(b"secret");
+        server.service
+    }
+
+    fn admin<T>(message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        let key = super::super::rpc::ADMIN_TOKEN_KEY;
+        request.metadata_mut().insert(key, "secret".parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_takes_admin_token() {
+        let service = service();
+        let denied = service.get_inventory(Request::new(message::GetInventory {})).await;
+        assert_eq!(denied.unwrap_err().code(), tonic::Code::Unauthenticated);
+        assert!(service.get_inventory(admin(message::GetInventory {})).await.is_ok());
+        let denied = service.get_dispatches(Request::new(message::GetDispatches {})).await;
+        assert_eq!(denied.unwrap_err().code(), tonic::Code::Unauthenticated);
+        assert!(service.get_dispatches(admin(message::GetDispatches {})).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_drain_node() {
+        let service = service();
+        let drain = |node: &str| message::DrainNode {
+            node: node.to_string(),
+        };
+        let denied = service.drain_node(Request::new(drain("10.0.0.1"))).await;
+        assert_eq!(denied.unwrap_err().code(), tonic::Code::Unauthenticated);
+        let invalid = service.drain_node(admin(drain("10.0.0"))).await;
+        assert_eq!(invalid.unwrap_err().code(), tonic::Code::InvalidArgument);
+        assert!(service.drain_node(admin(drain("10.0.0.1"))).await.is_ok());
+        // the drained node's workers are terminated when they ask for a task
+        let (tx, mut rx) = oneshot::channel();
+        service.manager.lock().unwrap().add_idle("10.0.0.1:1".parse().unwrap(), tx);
+        assert!(rx.try_recv().is_ok());
+        let (tx, mut rx) = oneshot::channel();
+        service.manager.lock().unwrap().add_idle("10.0.0.2:1".parse().unwrap(), tx);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_function_stats_take_admin_token() {
+        let service = service();
+        let denied = service.get_function_stats(Request::new(message::GetFunctionStats {})).await;
+        assert_eq!(denied.unwrap_err().code(), tonic::Code::Unauthenticated);
+        assert!(service.get_function_stats(admin(message::GetFunctionStats {})).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_scale_takes_admin_token() {
+        let service = service();
+        let denied = service.get_scale(Request::new(message::GetScale {})).await;
+        assert_eq!(denied.unwrap_err().code(), tonic::Code::Unauthenticated);
+        // the token gets past the check to autoscaling, which this service has disabled
+        let disabled = service.get_scale(admin(message::GetScale {})).await;
+        assert_eq!(disabled.unwrap_err().code(), tonic::Code::Unimplemented);
     }
 }
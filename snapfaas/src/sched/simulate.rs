@@ -0,0 +1,211 @@
+//! Synthetic workers for queueing and capacity experiments
+//!
+//! A simulation fabricates worker nodes inside the scheduler. They ask for tasks and report
+//! results the way real workers do, but run an invocation by sleeping for a service time drawn
+//! from a distribution, so that admission and placement policies can be tried out without VMs.
+//! Each node keeps the VMs of the functions it ran warm, as far as its memory goes, and pays a
+//! cold start for the others.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, info};
+use tokio::sync::oneshot;
+
+use crate::fs::Function;
+
+use super::message::{self, response::Kind};
+use super::rpc::ResourceInfo;
+use super::rpc_server::Service;
+
+/// How long simulated invocations take
+#[derive(Debug, Clone, Copy)]
+pub enum ServiceTime {
+    /// always this many milliseconds, `fixed:MS`
+    Fixed(f64),
+    /// exponentially distributed with this mean in milliseconds, `exp:MS`
+    Exponential(f64),
+    /// uniformly distributed between bounds in milliseconds, `uniform:MIN-MAX`
+    Uniform(f64, f64),
+}
+
+impl ServiceTime {
+    pub fn sample(&self) -> Duration {
+        let ms = match *self {
+            ServiceTime::Fixed(ms) => ms,
+            // inverse transform sampling
+            ServiceTime::Exponential(mean) => -mean * (1.0 - rand::random::<f64>()).ln(),
+            ServiceTime::Uniform(min, max) => min + (max - min) * rand::random::<f64>(),
+        };
+        Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+    }
+}
+
+impl FromStr for ServiceTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("expected fixed:MS, exp:MS or uniform:MIN-MAX, got {}", s);
+        let ms = |v: &str| v.parse::<f64>().map_err(|_| bad());
+        match s.split_once(':').ok_or_else(bad)? {
+            ("fixed", v) => Ok(ServiceTime::Fixed(ms(v)?)),
+            ("exp", v) => Ok(ServiceTime::Exponential(ms(v)?)),
+            ("uniform", v) => {
+                let (min, max) = v.split_once('-').ok_or_else(bad)?;
+                Ok(ServiceTime::Uniform(ms(min)?, ms(max)?))
+            }
+            _ => Err(bad()),
+        }
+    }
+}
+
+/// Shape of the simulated cluster
+#[derive(Debug, Clone)]
+pub struct Simulation {
+    pub nodes: usize,
+    /// tasks each node runs at once
+    pub slots: usize,
+    /// memory of each node in MB
+    pub memory: usize,
+    pub service_time: ServiceTime,
+    /// added to invocations the node has no warm VM of the function for
+    pub cold_start: Duration,
+}
+
+// Simulated node `i`. The addresses are in 198.18.0.0/15, which is set aside for benchmarks, so
+// they never collide with real workers.
+fn node_addr(i: usize) -> IpAddr {
+    Ipv4Addr::new(198, 18 + (i >> 16) as u8, (i >> 8) as u8, i as u8).into()
+}
+
+// Warm VMs of a simulated node by function
+#[derive(Default)]
+struct Warm(HashMap<Function, usize>);
+
+impl Warm {
+    // Takes a warm VM of `f` for an invocation, false if there is none
+    fn take(&mut self, f: &Function) -> bool {
+        match self.0.get_mut(f) {
+            Some(n) if *n > 0 => {
+                *n -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Keeps the VM of `f` that just finished warm, evicting others to stay within `memory`
+    fn keep(&mut self, f: Function, memory: usize) {
+        *self.0.entry(f).or_default() += 1;
+        let used = |warm: &HashMap<Function, usize>| {
+            warm.iter().map(|(f, n)| f.memory * n).sum::<usize>()
+        };
+        while used(&self.0) > memory {
+            let Some(victim) = self.0.keys().next().cloned() else {
+                break;
+            };
+            let n = self.0.get_mut(&victim).unwrap();
+            *n -= 1;
+            if *n == 0 {
+                self.0.remove(&victim);
+            }
+        }
+    }
+}
+
+/// Runs the simulated nodes until the scheduler tells them to terminate
+pub(super) async fn run(service: Service, sim: Simulation) {
+    info!(
+        "simulating {} nodes of {} slots, {:?}",
+        sim.nodes, sim.slots, sim.service_time
+    );
+    let sim = Arc::new(sim);
+    let mut slots = tokio::task::JoinSet::new();
+    for i in 0..sim.nodes {
+        let ip = node_addr(i);
+        let warm = Arc::new(Mutex::new(Warm::default()));
+        report(&service, ip, &sim, &warm.lock().unwrap());
+        for slot in 0..sim.slots {
+            let addr = SocketAddr::new(ip, slot as u16 + 1);
+            slots.spawn(run_slot(service.clone(), sim.clone(), addr, warm.clone()));
+        }
+    }
+    while slots.join_next().await.is_some() {}
+}
+
+// Tells the resource manager about the node's memory and warm VMs, as workers do
+fn report(service: &Service, ip: IpAddr, sim: &Simulation, warm: &Warm) {
+    let info = ResourceInfo {
+        stats: warm.0.clone(),
+        total_mem: sim.memory,
+        free_mem: sim.memory,
+        vms: vec![],
+        free_devices: Default::default(),
+        total_devices: Default::default(),
+        reserved_mem: 0,
+        attributes: Default::default(),
+        max_vm_mem: None,
+    };
+    service.manager.lock().unwrap().update(ip, info);
+    service.cvar.notify_one();
+}
+
+async fn run_slot(
+    service: Service,
+    sim: Arc<Simulation>,
+    addr: SocketAddr,
+    warm: Arc<Mutex<Warm>>,
+) {
+    loop {
+        let (tx, rx) = oneshot::channel();
+        service.manager.lock().unwrap().add_idle(addr, tx);
+        service.cvar.notify_one();
+        let Ok(response) = rx.await else {
+            return;
+        };
+        let (task_id, function, provision) = match response.kind {
+            Some(Kind::ProcessTask(message::ProcessTask {
+                task_id,
+                labeled_invoke: Some(invoke),
+            })) => (task_id, invoke.function, false),
+            Some(Kind::ProvisionVm(message::ProvisionVm { task_id, function })) => {
+                (task_id, function, true)
+            }
+            Some(Kind::ReloadConfig(_) | Kind::PauseVm(_) | Kind::Prefetch(_)) => continue,
+            _ => {
+                debug!("simulated worker {} terminating", addr);
+                return;
+            }
+        };
+        let f: Option<Function> = function.clone().map(Into::into);
+        let warm_start = f.as_ref().is_some_and(|f| warm.lock().unwrap().take(f));
+        let mut delay = if provision {
+            Duration::ZERO
+        } else {
+            sim.service_time.sample()
+        };
+        if !warm_start {
+            delay += sim.cold_start;
+        }
+        tokio::time::sleep(delay).await;
+        if let Some(f) = f {
+            let mut warm = warm.lock().unwrap();
+            warm.keep(f, sim.memory);
+            report(&service, addr.ip(), &sim, &warm);
+        }
+        let result = message::TaskReturn {
+            code: message::ReturnCode::Success as i32,
+            ..Default::default()
+        };
+        let finish = message::FinishTask {
+            task_id,
+            result: Some(result),
+            function,
+        };
+        if let Err(e) = service.finish(finish).await {
+            debug!("simulated worker {} failed to finish a task: {:?}", addr, e);
+        }
+    }
+}
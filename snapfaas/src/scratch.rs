@@ -0,0 +1,118 @@
+//! Per-invocation scratch space
+//!
+//! A function whose gate grants it scratch space gets a private host directory of named files
+//! for the length of one invocation, through the scratch syscalls. Writes past the gate's size
+//! fail. The directory is removed when the invocation completes, however it completes, so
+//! temporary data never outlives the task the way abandoned blobs do.
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+pub struct Scratch {
+    dir: PathBuf,
+    limit: u64,
+    used: u64,
+}
+
+impl Scratch {
+    /// Creates an empty scratch directory for task `task_id` under `base` holding at most
+    /// `limit` bytes
+    pub fn create(base: &Path, task_id: &str, limit: u64) -> io::Result<Self> {
+        let dir = base.join(format!("{}-{}", std::process::id(), task_id));
+        // a directory left behind by an earlier attempt at the task starts over
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        std::fs::create_dir_all(&dir)?;
+        Ok(Scratch {
+            dir,
+            limit,
+            used: 0,
+        })
+    }
+
+    // scratch files live directly in the directory
+    fn path(&self, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad scratch file name"));
+        }
+        Ok(self.dir.join(name))
+    }
+
+    fn size(&self, name: &str) -> io::Result<u64> {
+        match std::fs::metadata(self.path(name)?) {
+            Ok(m) => Ok(m.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes `data` at `offset` of file `name`, creating it if need be. Returns the file's new
+    /// size.
+    pub fn write(&mut self, name: &str, offset: u64, data: &[u8]) -> io::Result<u64> {
+        let old_size = self.size(name)?;
+        let new_size = old_size.max(offset + data.len() as u64);
+        if self.used - old_size + new_size > self.limit {
+            return Err(io::Error::other("scratch space exhausted"));
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path(name)?)?;
+        file.write_all_at(data, offset)?;
+        self.used = self.used - old_size + new_size;
+        Ok(new_size)
+    }
+
+    /// Appends `data` to file `name`, creating it if need be. Returns the file's new size.
+    pub fn append(&mut self, name: &str, data: &[u8]) -> io::Result<u64> {
+        let size = self.size(name)?;
+        self.write(name, size, data)
+    }
+
+    /// Reads up to `len` bytes at `offset` of file `name`
+    pub fn read(&self, name: &str, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(name)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = Vec::new();
+        file.take(len).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Removes file `name`, freeing the space it took
+    pub fn remove(&mut self, name: &str) -> io::Result<()> {
+        let size = self.size(name)?;
+        std::fs::remove_file(self.path(name)?)?;
+        self.used -= size;
+        Ok(())
+    }
+
+    /// Names and sizes of the scratch files
+    pub fn list(&self) -> io::Result<Vec<(String, u64)>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            files.push((name, entry.metadata()?.len()));
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Bytes in use and the most the scratch space may hold
+    pub fn usage(&self) -> (u64, u64) {
+        (self.used, self.limit)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            warn!("failed to remove scratch directory {:?}: {:?}", self.dir, e);
+        }
+    }
+}
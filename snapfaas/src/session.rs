@@ -0,0 +1,179 @@
+//! Login sessions
+//!
+//! Webfront opens a session for each login and names it in the login token it issues and in a
+//! session cookie. A session is a file in `home:<T,faasten>:sessions` labeled
+//! `<user,faasten>`, so only the platform can forge or revoke one. Files are named by the hash
+//! of the session id, since anyone can list the directory. Revoking a session invalidates every
+//! token naming it, well before the token itself expires.
+use labeled::buckle::{Buckle, Component};
+use lazy_static::lazy_static;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::fs::bootstrap::FAASTEN_PRIV;
+use crate::fs::path::Path;
+use crate::fs::{self, BackingStore, DirEntry, FsError, FS};
+
+/// How long a session lasts unless revoked, in seconds
+pub const SESSION_TTL: u64 = 7 * 24 * 60 * 60;
+
+// how often `spawn_sweeper` removes expired sessions
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+lazy_static! {
+    static ref SESSIONS_DIR: Path = Path::parse("home:<T,faasten>:sessions").unwrap();
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Session {
+    pub principal: Component,
+    pub created: u64,
+    pub expires: u64,
+}
+
+fn record_name(id: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(id.as_bytes()))
+}
+
+// Runs `f` as `faasten` with a clean label and drops both afterwards
+fn as_faasten<T>(f: impl FnOnce() -> T) -> T {
+    fs::utils::clear_label();
+    fs::utils::set_my_privilge(FAASTEN_PRIV.clone());
+    let res = f();
+    fs::utils::clear_label();
+    fs::utils::set_my_privilge(Component::dc_true());
+    res
+}
+
+/// Creates the sessions directory if it does not exist yet. Needs a bootstrapped file system.
+pub fn initialize<S: BackingStore>(fs: &FS<S>) -> Result<(), FsError> {
+    as_faasten(|| {
+        let parent = SESSIONS_DIR.parent().unwrap();
+        let name = SESSIONS_DIR.file_name().unwrap();
+        if fs.list_dir(parent.clone())?.contains_key(&name) {
+            return Ok(());
+        }
+        let label = Buckle::parse("T,faasten").unwrap();
+        fs.link(parent, name, fs.create_directory(label))
+    })
+}
+
+/// Opens a session for `principal` and returns its id
+pub fn create<S: BackingStore>(fs: &FS<S>, principal: Component) -> Result<String, FsError> {
+    let now = crate::delegation::now();
+    open(fs, principal, now, now.saturating_add(SESSION_TTL))
+}
+
+fn open<S: BackingStore>(
+    fs: &FS<S>,
+    principal: Component,
+    created: u64,
+    expires: u64,
+) -> Result<String, FsError> {
+    let id = hex::encode(rand::random::<[u8; 32]>());
+    let session = Session {
+        principal: principal.clone(),
+        created,
+        expires,
+    };
+    as_faasten(|| {
+        let label = Buckle::new(principal, FAASTEN_PRIV.clone());
+        fs::utils::create_or_update_file(
+            fs,
+            SESSIONS_DIR.clone(),
+            record_name(&id),
+            label,
+            serde_json::to_vec(&session).unwrap(),
+        )
+    })?;
+    Ok(id)
+}
+
+/// Returns the session `id` names, `None` if it does not exist, was revoked or has expired
+pub fn lookup<S: BackingStore>(fs: &FS<S>, id: &str) -> Option<Session> {
+    let mut path = SESSIONS_DIR.clone();
+    path.push_dscrp(record_name(id));
+    let session: Session = as_faasten(|| match fs.read_path(path) {
+        Ok(DirEntry::File(file)) => serde_json::from_slice(&file.read(fs)).ok(),
+        _ => None,
+    })?;
+    if session.expires < crate::delegation::now() {
+        if let Err(e) = revoke(fs, id) {
+            warn!("failed to remove expired session: {:?}", e);
+        }
+        return None;
+    }
+    Some(session)
+}
+
+/// The token a browser must send along with the session cookie to change state. Pages get it
+/// from webfront, cross-site pages can't.
+pub fn csrf_token(id: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(format!("csrf:{}", id).as_bytes()))
+}
+
+/// Revokes the session `id` names. Returns false if there was no such session.
+pub fn revoke<S: BackingStore>(fs: &FS<S>, id: &str) -> Result<bool, FsError> {
+    as_faasten(|| fs.rm(SESSIONS_DIR.clone(), &record_name(id)))
+}
+
+/// Removes the expired sessions, which `lookup` only removes when they are used again. Returns
+/// how many it removed.
+pub fn sweep<S: BackingStore>(fs: &FS<S>) -> Result<usize, FsError> {
+    let now = crate::delegation::now();
+    as_faasten(|| {
+        let mut removed = 0;
+        for (name, entry) in fs.list_dir(SESSIONS_DIR.clone())? {
+            let DirEntry::File(file) = entry else {
+                continue;
+            };
+            let session = serde_json::from_slice::<Session>(&file.read(fs)).ok();
+            // reading the session raised the label to its user's, which faasten may drop
+            fs::utils::clear_label();
+            if session.is_none_or(|s| s.expires < now) && fs.rm(SESSIONS_DIR.clone(), &name)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    })
+}
+
+/// Sweeps expired sessions in the background every hour
+pub fn spawn_sweeper<S: BackingStore + Send + Sync + 'static>(fs: std::sync::Arc<FS<S>>) {
+    std::thread::spawn(move || loop {
+        match sweep(fs.as_ref()) {
+            Ok(0) => {}
+            Ok(n) => log::info!("removed {} expired login sessions", n),
+            Err(e) => warn!("failed to remove expired login sessions: {:?}", e),
+        }
+        std::thread::sleep(SWEEP_INTERVAL);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::memory::MemoryStore;
+
+    fn fs() -> FS<MemoryStore> {
+        let fs = FS::new(MemoryStore::default());
+        fs.initialize();
+        fs::utils::set_my_privilge(Component::dc_false());
+        fs::utils::create_faceted(&fs, Path::root(), "home".to_string()).unwrap();
+        initialize(&fs).unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_sweep() {
+        let fs = fs();
+        let alice = Buckle::parse("alice,T").unwrap().secrecy;
+        let live = create(&fs, alice.clone()).unwrap();
+        open(&fs, alice, 0, 1).unwrap();
+        assert_eq!(sweep(&fs).unwrap(), 1);
+        assert!(lookup(&fs, &live).is_some());
+        assert_eq!(sweep(&fs).unwrap(), 0);
+    }
+}
@@ -0,0 +1,80 @@
+//! Shared memory region between a worker and a guest runtime
+//!
+//! The region is a memfd that firerunner exposes to the guest as an extra writable block device.
+//! Syscall messages refer to bytes in it with `syscalls::ShmRef` instead of carrying them over
+//! the vsock stream, which saves copying and framing multi-MB payloads.
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use crate::syscalls::ShmRef;
+
+/// Payloads smaller than this are sent inline even if the VM has a shared memory region
+pub const SHM_THRESHOLD: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct SharedMemory {
+    file: File,
+    size: u64,
+    guest_device: String,
+}
+
+impl SharedMemory {
+    /// Creates a zeroed region of `size_mb` MBs that the guest will see as `guest_device`
+    pub fn new(size_mb: usize, guest_device: String) -> io::Result<Self> {
+        let name = CString::new("faasten-shm").unwrap();
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        let size = (size_mb as u64) << 20;
+        file.set_len(size)?;
+        Ok(Self {
+            file,
+            size,
+            guest_device,
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn guest_device(&self) -> &str {
+        &self.guest_device
+    }
+
+    /// Whether `len` bytes should go through the region rather than inline
+    pub fn fits(&self, len: usize) -> bool {
+        len >= SHM_THRESHOLD && len as u64 <= self.size
+    }
+
+    /// Writes `data` at the start of the region. The guest owns the region again once it has
+    /// read the data, so only one message may be in flight at a time.
+    pub fn write(&self, data: &[u8]) -> io::Result<ShmRef> {
+        self.file.write_all_at(data, 0)?;
+        Ok(ShmRef {
+            offset: 0,
+            length: data.len() as u64,
+        })
+    }
+
+    pub fn read(&self, r: &ShmRef) -> io::Result<Vec<u8>> {
+        if r.offset.checked_add(r.length).is_none_or(|end| end > self.size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "reference out of the shared memory region",
+            ));
+        }
+        let mut buf = vec![0; r.length as usize];
+        self.file.read_exact_at(&mut buf, r.offset)?;
+        Ok(buf)
+    }
+}
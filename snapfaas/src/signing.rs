@@ -0,0 +1,99 @@
+//! Signed function images
+//!
+//! Blobs are named by the SHA-256 of their content, so signing a blob's name signs its content.
+//! `admin_fstools sign-image` signs kernel, runtime and app image blobs with an operator's key
+//! and stores the signatures in the blobstore. A worker given a `Keyset` boots a VM only from
+//! blobs one of the keys signed and whose content still hashes to their name, so that unsigned
+//! images and images tampered with on disk are rejected. It hashes the very files it hands the
+//! hypervisor, on every boot.
+use std::io::{self, Read};
+use std::path::Path;
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::sign::{Signer, Verifier};
+use sha2::{Digest, Sha256};
+
+use crate::blobstore::Blobstore;
+
+#[derive(Debug)]
+pub enum SignatureError {
+    /// no signature is stored for the blob
+    Unsigned,
+    /// no trusted key made the signature
+    BadSignature,
+    /// the blob's content doesn't hash to its name
+    Tampered,
+    Io(io::Error),
+}
+
+/// Signs the blob `name`
+pub fn sign(key: &PKey<Private>, name: &str) -> Vec<u8> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+    signer.sign_oneshot_to_vec(name.as_bytes()).unwrap()
+}
+
+/// Public keys trusted to sign images
+#[derive(Debug, Clone, Default)]
+pub struct Keyset(Vec<PKey<Public>>);
+
+impl Keyset {
+    /// Loads the PEM encoded public key at `path`, or every `.pem` file in it if a directory
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let paths = if path.is_dir() {
+            let mut paths = vec![];
+            for entry in std::fs::read_dir(path)? {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "pem") {
+                    paths.push(path);
+                }
+            }
+            paths
+        } else {
+            vec![path.to_path_buf()]
+        };
+        let mut keys = vec![];
+        for path in paths {
+            let key = PKey::public_key_from_pem(&std::fs::read(&path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            keys.push(key);
+        }
+        Ok(Keyset(keys))
+    }
+
+    /// Checks that a trusted key signed blob `name` and that the file at `path`, the copy of the
+    /// blob about to be booted, hashes to the name. Reads the whole file.
+    pub fn verify(
+        &self,
+        blobstore: &Blobstore,
+        name: &str,
+        path: &Path,
+    ) -> Result<(), SignatureError> {
+        let signature = match blobstore.signature(name) {
+            Ok(signature) => signature,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(SignatureError::Unsigned),
+            Err(e) => return Err(SignatureError::Io(e)),
+        };
+        let signed = self.0.iter().any(|key| {
+            Verifier::new(MessageDigest::sha256(), key)
+                .and_then(|mut v| v.verify_oneshot(&signature, name.as_bytes()))
+                .unwrap_or(false)
+        });
+        if !signed {
+            return Err(SignatureError::BadSignature);
+        }
+        let mut blob = std::fs::File::open(path).map_err(SignatureError::Io)?;
+        let mut digest = Sha256::new();
+        let mut buf = vec![0; 1 << 20];
+        loop {
+            match blob.read(&mut buf).map_err(SignatureError::Io)? {
+                0 => break,
+                n => digest.update(&buf[..n]),
+            }
+        }
+        if hex::encode(digest.finalize()) != name {
+            return Err(SignatureError::Tampered);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+//! Supervision of hypervisor processes
+//!
+//! A hypervisor that exits while its VM is in use breaks the VM's syscall channel at best. At
+//! worst something else holds the other end of the channel and the worker waits on a dead VM.
+//! One thread watches the pidfds of all the hypervisors the process runs. When one exits, the
+//! thread shuts the VM's channel down, so that whoever waits on it notices right away, and marks
+//! the VM's `Watch`, so that the worker can tell a dead hypervisor from a broken guest.
+use std::collections::HashMap;
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use log::error;
+
+struct Watched {
+    conn: UnixStream,
+    exited: Arc<AtomicBool>,
+}
+
+struct Supervisor {
+    epoll: OwnedFd,
+    watched: Mutex<HashMap<u64, Watched>>,
+    next: AtomicU64,
+}
+
+static SUPERVISOR: OnceLock<Option<Supervisor>> = OnceLock::new();
+
+// The supervisor, started on first use. None if the kernel lacks epoll.
+fn supervisor() -> Option<&'static Supervisor> {
+    SUPERVISOR
+        .get_or_init(|| {
+            let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+            if fd < 0 {
+                error!("cannot supervise hypervisors: {:?}", io::Error::last_os_error());
+                return None;
+            }
+            std::thread::spawn(run);
+            Some(Supervisor {
+                epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+                watched: Default::default(),
+                next: AtomicU64::new(0),
+            })
+        })
+        .as_ref()
+}
+
+fn run() {
+    let supervisor = supervisor().unwrap();
+    let mut events = [libc::epoll_event { events: 0, u64: 0 }; 16];
+    loop {
+        let n = unsafe {
+            libc::epoll_wait(
+                supervisor.epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as i32,
+                -1,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("stopped supervising hypervisors: {:?}", e);
+            return;
+        }
+        for event in events[..n as usize].iter() {
+            let token = event.u64;
+            if let Some(watched) = supervisor.watched.lock().unwrap().remove(&token) {
+                watched.exited.store(true, Ordering::Release);
+                let _ = watched.conn.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// Watches a hypervisor until dropped
+#[derive(Debug)]
+pub struct Watch {
+    token: u64,
+    // closing it takes it out of the epoll set
+    _pidfd: OwnedFd,
+    exited: Arc<AtomicBool>,
+}
+
+impl Watch {
+    /// Whether the hypervisor exited
+    pub fn exited(&self) -> bool {
+        self.exited.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        if let Some(supervisor) = supervisor() {
+            supervisor.watched.lock().unwrap().remove(&self.token);
+        }
+    }
+}
+
+/// Shuts `conn` down once hypervisor `pid` exits
+pub fn watch(pid: u32, conn: &UnixStream) -> io::Result<Watch> {
+    let supervisor = supervisor().ok_or_else(|| io::Error::other("no supervisor"))?;
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let pidfd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+    let token = supervisor.next.fetch_add(1, Ordering::Relaxed);
+    let exited = Arc::new(AtomicBool::new(false));
+    let watched = Watched {
+        conn: conn.try_clone()?,
+        exited: exited.clone(),
+    };
+    supervisor.watched.lock().unwrap().insert(token, watched);
+    // a pidfd becomes readable when the process exits, which only needs reporting once
+    let mut event = libc::epoll_event {
+        events: (libc::EPOLLIN | libc::EPOLLONESHOT) as u32,
+        u64: token,
+    };
+    let res = unsafe {
+        libc::epoll_ctl(
+            supervisor.epoll.as_raw_fd(),
+            libc::EPOLL_CTL_ADD,
+            pidfd.as_raw_fd(),
+            &mut event,
+        )
+    };
+    if res < 0 {
+        let e = io::Error::last_os_error();
+        supervisor.watched.lock().unwrap().remove(&token);
+        return Err(e);
+    }
+    Ok(Watch {
+        token,
+        _pidfd: pidfd,
+        exited,
+    })
+}
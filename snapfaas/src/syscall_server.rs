@@ -1,30 +1,43 @@
 ///! secure runtime that holds the handles to the VM and the global file system
 use std::collections::HashMap;
 use std::io::Write;
-use std::net::TcpStream;
 
 use crate::blobstore::{self, Blob, Blobstore};
+use crate::delegation::{Delegation, DelegationError};
+use crate::download::DownloadToken;
 use crate::fs::{
-    self, BackingStore, DirEntry, DirectGate, FsError, Function, Gate, RedirectGate, Service,
-    CURRENT_LABEL, FS, PRIVILEGE,
+    self, BackingStore, BlobMeta, DirEntry, DirectGate, FsError, Function, Gate, RedirectGate,
+    Service, CURRENT_LABEL, FS, PRIVILEGE,
 };
+use crate::receipt::Receipt;
+use crate::scratch::Scratch;
 use crate::sched::message::{ReturnCode, TaskReturn};
-use crate::sched::{self, message};
+use crate::sched;
+use crate::shm::SharedMemory;
 use crate::syscalls::DentInvoke;
 use crate::syscalls::{self, syscall::Syscall as SC};
 use labeled::buckle::{Buckle, Component};
-use labeled::Label;
+use labeled::{HasPrivilege, Label};
+
+// attempts at queueing the invocation of a complete barrier's gate
+const BARRIER_FIRE_ATTEMPTS: u32 = 3;
 
 #[derive(Debug)]
 pub enum SyscallChannelError {
     Read,
     Decode,
     Write,
+    /// the function did not make a syscall in time
+    Timeout,
 }
 
 pub trait SyscallChannel {
     fn send(&mut self, bytes: Vec<u8>) -> Result<(), SyscallChannelError>;
     fn wait(&mut self) -> Result<Option<SC>, SyscallChannelError>;
+    /// memory region shared with the other end, if any
+    fn shared_memory(&self) -> Option<&SharedMemory> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -35,8 +48,25 @@ pub enum SyscallProcessorError {
     Database,
     Http(reqwest::Error),
     HttpAuth,
+    EgressDenied(String),
     BadStrPath,
     BadUrlArgs,
+    SharedMemory(std::io::Error),
+    InitFailed(Option<syscalls::Abort>),
+}
+
+impl SyscallProcessorError {
+    /// The code of an invocation that failed with this error
+    pub fn return_code(&self) -> ReturnCode {
+        match self {
+            SyscallProcessorError::Channel(SyscallChannelError::Timeout) => ReturnCode::Timeout,
+            SyscallProcessorError::Channel(_) | SyscallProcessorError::SharedMemory(_) => {
+                ReturnCode::GuestCrash
+            }
+            SyscallProcessorError::InitFailed(_) => ReturnCode::LaunchFailed,
+            _ => ReturnCode::ProcessRequestFailed,
+        }
+    }
 }
 
 impl From<SyscallChannelError> for SyscallProcessorError {
@@ -47,9 +77,21 @@ impl From<SyscallChannelError> for SyscallProcessorError {
 
 #[derive(Debug)]
 pub struct SyscallGlobalEnv<B: BackingStore> {
-    pub sched_conn: Option<TcpStream>,
+    pub sched_conn: Option<sched::rpc::Client>,
     pub fs: FS<B>,
     pub blobstore: Blobstore,
+    /// key used to verify delegation certificates, `None` disables delegations
+    pub delegation_key: Option<openssl::pkey::PKey<openssl::pkey::Public>>,
+    /// payloads and responses larger than this many bytes pass through the scheduler as blobs,
+    /// 0 passes everything inline
+    pub max_inline: usize,
+    /// key used to sign declassification receipts and download tokens, `None` issues neither
+    pub receipt_key: Option<openssl::pkey::PKey<openssl::pkey::Private>>,
+    /// host directory invocations' scratch spaces go in, ideally a tmpfs. `None` uses the
+    /// system's temporary directory.
+    pub scratch_dir: Option<std::path::PathBuf>,
+    /// hosts services may send requests to, `None` allows any host
+    pub egress: Option<Vec<String>>,
 }
 
 pub struct SyscallProcessor<'a, B: BackingStore> {
@@ -60,6 +102,23 @@ pub struct SyscallProcessor<'a, B: BackingStore> {
     max_blob_id: u64,
     max_dent_id: u64,
     http_client: reqwest::blocking::Client,
+    // task to forward response chunks to, if the invoker streams them
+    stream_to: Option<String>,
+    // response chunks to prepend to the response body otherwise
+    chunks: Vec<u8>,
+    // task that declassification receipts are issued for
+    task_id: String,
+    receipts: Vec<String>,
+    // label raises so far, if tracing them
+    taint_trace: Option<Vec<sched::message::LabelRaise>>,
+    // paths of the open dents, if tracing label raises
+    dent_paths: HashMap<u64, String>,
+    // bytes of scratch space the invocation may use
+    scratch_limit: u64,
+    // created on first use and removed along with the processor
+    scratch: Option<Scratch>,
+    // label aliases, loaded on first use
+    aliases: Option<fs::aliases::LabelAliases>,
 }
 
 impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
@@ -82,6 +141,15 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             max_dent_id: 1,
             max_blob_id: 1,
             http_client: reqwest::blocking::Client::new(),
+            stream_to: None,
+            chunks: Vec::new(),
+            task_id: String::new(),
+            receipts: Vec::new(),
+            taint_trace: None,
+            dent_paths: Default::default(),
+            scratch_limit: 0,
+            scratch: None,
+            aliases: None,
         }
     }
 
@@ -94,6 +162,116 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             max_blob_id: 0,
             max_dent_id: 0,
             http_client: reqwest::blocking::Client::new(),
+            stream_to: None,
+            chunks: Vec::new(),
+            task_id: String::new(),
+            receipts: Vec::new(),
+            taint_trace: None,
+            dent_paths: Default::default(),
+            scratch_limit: 0,
+            scratch: None,
+            aliases: None,
+        }
+    }
+
+    /// Forwards the function's response chunks to the invoker of task `task_id` as they come
+    pub fn stream_chunks(&mut self, task_id: String) {
+        self.stream_to = Some(task_id);
+    }
+
+    /// Issues declassification receipts for task `task_id`
+    pub fn set_task_id(&mut self, task_id: String) {
+        self.task_id = task_id;
+    }
+
+    /// Gives the invocation `mib` MiB of scratch space, removed when the processor is dropped
+    pub fn set_scratch(&mut self, mib: usize) {
+        self.scratch_limit = mib as u64 * 1024 * 1024;
+    }
+
+    /// Records every syscall that raises the label from now on, and returns the raises with the
+    /// result. Costs a label comparison per syscall.
+    pub fn trace_taint(&mut self) {
+        self.taint_trace = Some(Vec::new());
+        self.dent_paths.insert(0, String::from("/"));
+    }
+
+    // name of the syscall and path of the object it touches, for the taint trace
+    fn describe(&self, sc: &SC) -> (&'static str, String) {
+        let dent = |fd: &u64| {
+            self.dent_paths
+                .get(fd)
+                .cloned()
+                .unwrap_or_else(|| format!("<dent {}>", fd))
+        };
+        match sc {
+            SC::TaintWithLabel(_) => ("taintWithLabel", String::new()),
+            SC::CounterIncrement(c) => ("counterIncrement", c.path.clone()),
+            SC::LockAcquire(l) => ("lockAcquire", l.path.clone()),
+            SC::LockRelease(l) => ("lockRelease", l.path.clone()),
+            SC::BarrierInit(b) => ("barrierInit", b.path.clone()),
+            SC::BarrierArrive(b) => ("barrierArrive", b.path.clone()),
+            SC::DentOpen(syscalls::DentOpen { fd, entry }) => {
+                let name = match entry {
+                    Some(syscalls::dent_open::Entry::Name(name)) => name.clone(),
+                    Some(syscalls::dent_open::Entry::Facet(facet)) => {
+                        let facet: Buckle = facet.clone().into();
+                        format!("{:?}", facet)
+                    }
+                    None => String::new(),
+                };
+                ("dentOpen", format!("{}/{}", dent(fd).trim_end_matches('/'), name))
+            }
+            SC::DentRead(fd) => ("dentRead", dent(fd)),
+            SC::DentStat(fd) => ("dentStat", dent(fd)),
+            SC::DentLogRead(r) => ("dentLogRead", dent(&r.fd)),
+            SC::DentLogAppend(a) => ("dentLogAppend", dent(&a.fd)),
+            SC::DentDownloadToken(d) => ("dentDownloadToken", dent(&d.fd)),
+            SC::DentList(fd) => ("dentList", dent(fd)),
+            SC::DentLsFaceted(l) => ("dentLsFaceted", dent(&l.fd)),
+            SC::DentLsGate(fd) => ("dentLsGate", dent(fd)),
+            SC::DentGetBlob(fd) => ("dentGetBlob", dent(fd)),
+            SC::DentInvoke(i) => ("dentInvoke", dent(&i.fd)),
+            SC::DentInvokeGang(g) => {
+                let fds: Vec<String> = g.invokes.iter().map(|i| dent(&i.fd)).collect();
+                ("dentInvokeGang", fds.join(","))
+            }
+            SC::DentUpdate(u) => ("dentUpdate", dent(&u.fd)),
+            SC::DentLink(l) => ("dentLink", dent(&l.dir_fd)),
+            SC::DentUnlink(u) => ("dentUnlink", dent(&u.fd)),
+            SC::BlobRead(_) => ("blobRead", String::new()),
+            _ => ("other", String::new()),
+        }
+    }
+
+    /// Declassifies the current label with the gate's privilege, see `record_declassification`
+    fn declassify_result(&mut self) -> Buckle {
+        let privilege = fs::utils::get_privilege();
+        let from = fs::utils::get_current_label();
+        let to = fs::utils::declassify_with(&privilege);
+        self.record_declassification(privilege, from, to.clone());
+        to
+    }
+
+    // If declassifying `from` to `to` with `privilege` lowers the label, records it in the
+    // audit log and issues a receipt for it
+    fn record_declassification(&mut self, privilege: Component, from: Buckle, to: Buckle) {
+        if to == from {
+            return;
+        }
+        let task = self.task_id.clone();
+        let recorded = fs::audit::record_declassification(
+            &self.env.fs,
+            task.clone(),
+            privilege.clone(),
+            from.clone(),
+            to.clone(),
+        );
+        if let Err(e) = recorded {
+            log::error!("failed to audit the declassification by task {}: {:?}", task, e);
+        }
+        if let Some(key) = self.env.receipt_key.as_ref() {
+            self.receipts.push(Receipt::new(task, privilege, from, to).sign(key));
         }
     }
 
@@ -105,6 +283,15 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
     ) -> Result<reqwest::blocking::Response, SyscallProcessorError> {
         let url = strfmt::strfmt(&service_info.url, &parameters)
             .map_err(|_| SyscallProcessorError::BadUrlArgs)?;
+        if let Some(egress) = self.env.egress.as_ref() {
+            let host = url::Url::parse(&url)
+                .ok()
+                .and_then(|u| u.host_str().map(String::from))
+                .unwrap_or_default();
+            if !egress.contains(&host) {
+                return Err(SyscallProcessorError::EgressDenied(host));
+            }
+        }
         let method = service_info.verb.clone().into();
         let headers = service_info
             .headers
@@ -125,6 +312,14 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
 }
 
 impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
+    // The label alias table, read once per invocation
+    fn aliases(&mut self) -> &fs::aliases::LabelAliases {
+        if self.aliases.is_none() {
+            self.aliases = Some(fs::aliases::LabelAliases::load(&self.env.fs));
+        }
+        self.aliases.as_ref().unwrap()
+    }
+
     fn root(&self) -> syscalls::DentResult {
         syscalls::DentResult {
             success: true,
@@ -155,7 +350,8 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                         DirEntry::FacetedDirectory(base_dir),
                         syscalls::dent_open::Entry::Facet(label),
                     ) => {
-                        let dent = DirEntry::Directory(base_dir.open(&label.into(), &self.env.fs));
+                        let dent =
+                            DirEntry::Directory(base_dir.open(&label.into(), &self.env.fs).ok()?);
                         let res_id = self.max_dent_id;
                         let _ = self.dents.insert(self.max_dent_id, dent.clone());
                         self.max_dent_id += 1;
@@ -165,8 +361,9 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                         DirEntry::FacetedDirectory(base_dir),
                         syscalls::dent_open::Entry::Name(label_name),
                     ) => {
-                        if let Ok(label) = Buckle::parse(label_name.as_str()) {
-                            let dent = DirEntry::Directory(base_dir.open(&label, &self.env.fs));
+                        if let Ok(label) = self.aliases().resolve(label_name.as_str()) {
+                            let dent =
+                                DirEntry::Directory(base_dir.open(&label, &self.env.fs).ok()?);
                             let res_id = self.max_dent_id;
                             let _ = self.dents.insert(self.max_dent_id, dent.clone());
                             self.max_dent_id += 1;
@@ -204,6 +401,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         &mut self,
         kind: syscalls::dent_create::Kind,
         label: Option<Buckle>,
+        content_type: Option<String>,
     ) -> Result<syscalls::DentResult, FsError> {
         use syscalls::dent_create::Kind;
         let label = label.unwrap_or(Buckle::public());
@@ -236,6 +434,15 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                             else {
                                 Err(FsError::NotABlob)?
                             };
+                            let mut app_layers = Vec::new();
+                            for fd in function.app_layers.iter() {
+                                let DirEntry::Blob(layer) =
+                                    self.dents.get(fd).ok_or(FsError::InvalidFd)?
+                                else {
+                                    Err(FsError::NotABlob)?
+                                };
+                                app_layers.push(layer.read(&self.env.fs));
+                            }
 
                             let func = Function {
                                 memory: function.memory as usize,
@@ -246,6 +453,19 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                     .unlabel()
                                     .clone(),
                                 kernel: kernel.get(&self.env.fs).unwrap().unlabel().clone(),
+                                devices: function.devices,
+                                keep_alive: function.keep_alive,
+                                min_instances: function.min_instances as usize,
+                                speculative_boot: function.speculative_boot,
+                                idempotent: function.idempotent,
+                                idle_ttl: function.idle_ttl,
+                                requirements: function.requirements.into_iter().collect(),
+                                forward_headers: function.forward_headers,
+                                concurrency_target: function.concurrency_target as usize,
+                                scratch: function.scratch as usize,
+                                app_layers,
+                                provenance: None,
+                                results: None,
                             };
                             self.env.fs.create_direct_gate(
                                 label,
@@ -260,6 +480,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                         .map(|d| d.into())
                                         .unwrap_or(Component::dc_true()),
                                     function: func,
+                                    metadata: dg.metadata.map(|m| Box::new(m.into())),
                                 },
                             )?
                         }
@@ -296,6 +517,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                 url,
                 verb,
                 mut headers,
+                metadata,
             }) => {
                 let verb = syscalls::HttpVerb::from_i32(verb)
                     .unwrap_or(syscalls::HttpVerb::HttpHead)
@@ -310,13 +532,17 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                         url,
                         verb,
                         headers,
+                        metadata: metadata.map(|m| Box::new(m.into())),
                     },
                 )?
             }
             Kind::Blob(blobfd) => {
                 let blob = self.blobs.get(&blobfd).ok_or(FsError::NotABlob)?;
-                self.env.fs.create_blob(label, blob.name.clone())?
+                let size = blob.len().map_err(|_| FsError::NotABlob)?;
+                let meta = BlobMeta::new(size, content_type);
+                self.env.fs.create_blob(label, blob.name.clone(), meta)?
             }
+            Kind::Log(syscalls::Void {}) => self.env.fs.create_log(label),
         };
         let res_id = self.max_dent_id;
         let _ = self.dents.insert(self.max_dent_id, entry);
@@ -332,6 +558,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         &mut self,
         fd: u64,
         kind: syscalls::dent_update::Kind,
+        content_type: Option<String>,
     ) -> Result<syscalls::DentResult, FsError> {
         use syscalls::dent_update::Kind;
         match kind {
@@ -396,12 +623,20 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                     if function.memory > 0 {
                                         gate.function.memory = function.memory as usize;
                                     }
+
+                                    if !function.devices.is_empty() {
+                                        gate.function.devices = function.devices;
+                                    }
                                 }
 
                                 if let Some(privilege) = dg.privilege {
                                     gate.privilege = privilege.into();
                                 }
 
+                                if let Some(metadata) = dg.metadata {
+                                    gate.metadata = Some(Box::new(metadata.into()));
+                                }
+
                                 if let Some(invoker_integrity_clearance) =
                                     dg.invoker_integrity_clearance
                                 {
@@ -455,6 +690,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                 url,
                 verb,
                 mut headers,
+                metadata,
             }) => {
                 if let Some(DirEntry::Service(service)) = self.dents.get(&fd) {
                     let verb = syscalls::HttpVerb::from_i32(verb)
@@ -472,6 +708,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                             url,
                             verb,
                             headers,
+                            metadata: metadata.map(|m| Box::new(m.into())),
                         },
                         &self.env.fs,
                     )?
@@ -482,7 +719,9 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             Kind::Blob(blobfd) => {
                 let blob = self.blobs.get(&blobfd).ok_or(FsError::NotABlob)?;
                 if let Some(DirEntry::Blob(blobentry)) = self.dents.get(&fd) {
-                    blobentry.replace(blob.name.clone(), &self.env.fs)?;
+                    let size = blob.len().map_err(|_| FsError::NotABlob)?;
+                    let meta = BlobMeta::new(size, content_type);
+                    blobentry.replace(blob.name.clone(), meta, &self.env.fs)?;
                 } else {
                     return Err(FsError::NotABlob);
                 }
@@ -495,6 +734,150 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         })
     }
 
+    fn counter_increment(&self, path: &str, delta: i64) -> syscalls::CounterResult {
+        let result = fs::path::Path::parse(path)
+            .map_err(|_| FsError::BadPath)
+            .and_then(|path| self.env.fs.increment_counter(path, delta));
+        match result {
+            Ok(value) => syscalls::CounterResult {
+                success: true,
+                value,
+            },
+            Err(e) => {
+                log::debug!("counter {} increment failed: {:?}", path, e);
+                syscalls::CounterResult {
+                    success: false,
+                    value: 0,
+                }
+            }
+        }
+    }
+
+    fn lock_acquire(&self, path: &str, ttl: u64, token: Option<u64>) -> syscalls::LockResult {
+        let result = fs::path::Path::parse(path)
+            .map_err(|_| FsError::BadPath)
+            .and_then(|path| self.env.fs.lock(path, ttl, token));
+        match result {
+            Ok(token) => syscalls::LockResult {
+                success: token.is_some(),
+                token: token.unwrap_or_default(),
+            },
+            Err(e) => {
+                log::debug!("lock {} acquire failed: {:?}", path, e);
+                syscalls::LockResult {
+                    success: false,
+                    token: 0,
+                }
+            }
+        }
+    }
+
+    fn lock_release(&self, path: &str, token: u64) -> syscalls::LockResult {
+        let result = fs::path::Path::parse(path)
+            .map_err(|_| FsError::BadPath)
+            .and_then(|path| self.env.fs.unlock(path, token));
+        if let Err(e) = result.as_ref() {
+            log::debug!("lock {} release failed: {:?}", path, e);
+        }
+        syscalls::LockResult {
+            success: result.unwrap_or(false),
+            token,
+        }
+    }
+
+    fn barrier_init(&self, path: &str, expected: u64, gate: String) -> syscalls::BarrierResult {
+        let result = fs::path::Path::parse(path)
+            .map_err(|_| FsError::BadPath)
+            .and_then(|path| self.env.fs.init_barrier(path, expected, gate));
+        if let Err(e) = result.as_ref() {
+            log::debug!("barrier {} init failed: {:?}", path, e);
+        }
+        syscalls::BarrierResult {
+            success: result.is_ok(),
+            arrived: 0,
+            expected,
+            fired: false,
+        }
+    }
+
+    fn barrier_arrive(&mut self, path: &str, result: String) -> syscalls::BarrierResult {
+        let file = fs::path::Path::parse(path)
+            .map_err(|_| FsError::BadPath)
+            .and_then(|path| match self.env.fs.read_path(path)? {
+                DirEntry::File(file) => Ok(file),
+                _ => Err(FsError::NotAFile),
+            });
+        let arrived = file.and_then(|file| Ok((file, file.arrive(result, &self.env.fs)?)));
+        match arrived {
+            Ok((file, barrier)) => {
+                // only the arrival that claims the complete barrier sees it complete
+                let label = file.get(&self.env.fs).map(|f| f.label().clone());
+                let fired = barrier.is_complete()
+                    && label.is_some_and(|label| self.fire_barrier(&barrier, &label));
+                if fired {
+                    if let Err(e) = file.barrier_fired(&self.env.fs) {
+                        log::warn!("failed to record that barrier {} fired: {:?}", path, e);
+                    }
+                }
+                syscalls::BarrierResult {
+                    success: true,
+                    arrived: barrier.results.len() as u64,
+                    expected: barrier.expected,
+                    fired,
+                }
+            }
+            Err(e) => {
+                log::debug!("barrier {} arrive failed: {:?}", path, e);
+                syscalls::BarrierResult {
+                    success: false,
+                    arrived: 0,
+                    expected: 0,
+                    fired: false,
+                }
+            }
+        }
+    }
+
+    // Queues the invocation of the completion gate of `barrier`, which is in a file labeled
+    // `label`, with the scheduler, retrying with backoff. The gate is invoked with the barrier's
+    // privilege rather than the last arrival's. Unless it is queued, a later arrival may try
+    // again.
+    fn fire_barrier(&mut self, barrier: &fs::Barrier, label: &Buckle) -> bool {
+        let privilege = barrier.firing_privilege(label);
+        let gate = match fs::path::Path::parse(&barrier.gate).map(|p| self.env.fs.read_path(p)) {
+            Ok(Ok(DirEntry::Gate(gate))) => gate.to_invokable(&self.env.fs),
+            _ => {
+                log::warn!("barrier gate {} is not a gate", barrier.gate);
+                return false;
+            }
+        };
+        if !privilege.implies(&gate.invoker_integrity_clearance) {
+            return false;
+        }
+        let payload = serde_json::to_vec(&barrier.results).unwrap();
+        let mut invoke = gate_invocation(gate, payload, Default::default(), false);
+        invoke.invoker = Some(privilege.into());
+        if invoke
+            .spill_payload(&mut self.env.blobstore, self.env.max_inline)
+            .is_err()
+        {
+            return false;
+        }
+        let conn = self.env.sched_conn.as_mut().unwrap();
+        for attempt in 1..=BARRIER_FIRE_ATTEMPTS {
+            match sched::rpc::labeled_invoke(conn, invoke.clone()) {
+                Ok(_) => return true,
+                Err(e) => {
+                    log::warn!("failed to invoke barrier gate {}: {:?}", barrier.gate, e);
+                    if attempt < BARRIER_FIRE_ATTEMPTS {
+                        std::thread::sleep(sched::rpc::backoff(attempt));
+                    }
+                }
+            }
+        }
+        false
+    }
+
     fn dent_read(&mut self, fd: u64) -> syscalls::DentResult {
         let result = self.dents.get(&fd).and_then(|entry| {
             match entry {
@@ -510,6 +893,205 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         }
     }
 
+    // the invocation's scratch space, created on first use
+    fn scratch(&mut self) -> std::io::Result<&mut Scratch> {
+        if self.scratch_limit == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "the gate grants no scratch space",
+            ));
+        }
+        if self.scratch.is_none() {
+            let base = self
+                .env
+                .scratch_dir
+                .clone()
+                .unwrap_or_else(|| std::env::temp_dir().join("faasten-scratch"));
+            let scratch = Scratch::create(&base, &self.task_id, self.scratch_limit)?;
+            self.scratch = Some(scratch);
+        }
+        Ok(self.scratch.as_mut().unwrap())
+    }
+
+    fn scratch_write(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        offset: Option<u64>,
+    ) -> syscalls::ScratchResult {
+        let result = self.scratch().and_then(|scratch| match offset {
+            Some(offset) => scratch.write(name, offset, data),
+            None => scratch.append(name, data),
+        });
+        match result {
+            Ok(size) => syscalls::ScratchResult {
+                success: true,
+                size,
+                data: vec![],
+            },
+            Err(e) => {
+                log::debug!("scratch write to {} failed: {:?}", name, e);
+                syscalls::ScratchResult::default()
+            }
+        }
+    }
+
+    fn scratch_read(&mut self, name: &str, offset: u64, length: u64) -> syscalls::ScratchResult {
+        match self.scratch().and_then(|scratch| scratch.read(name, offset, length)) {
+            Ok(data) => syscalls::ScratchResult {
+                success: true,
+                size: 0,
+                data,
+            },
+            Err(e) => {
+                log::debug!("scratch read of {} failed: {:?}", name, e);
+                syscalls::ScratchResult::default()
+            }
+        }
+    }
+
+    fn scratch_remove(&mut self, name: &str) -> syscalls::ScratchResult {
+        syscalls::ScratchResult {
+            success: self.scratch().and_then(|scratch| scratch.remove(name)).is_ok(),
+            ..Default::default()
+        }
+    }
+
+    fn scratch_list(&mut self) -> syscalls::ScratchListResult {
+        let limit = self.scratch_limit;
+        match self.scratch().and_then(|scratch| Ok((scratch.list()?, scratch.usage()))) {
+            Ok((files, (used, limit))) => syscalls::ScratchListResult {
+                files: files.into_iter().collect(),
+                used,
+                limit,
+            },
+            Err(_) => syscalls::ScratchListResult {
+                limit,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn dent_stat(&mut self, fd: u64) -> syscalls::DentStatResult {
+        let fs = &self.env.fs;
+        let Some(entry) = self.dents.get(&fd) else {
+            return syscalls::DentStatResult {
+                success: false,
+                ..Default::default()
+            };
+        };
+        let label = |l: &Buckle| Some(l.clone().into());
+        let mut meta = None;
+        let (kind, label, etag) = match entry {
+            DirEntry::Directory(dir) => (
+                syscalls::DentKind::DentDirectory,
+                dir.get(fs).and_then(|d| label(d.label())),
+                None,
+            ),
+            DirEntry::File(file) => (
+                syscalls::DentKind::DentFile,
+                file.get(fs).and_then(|f| label(f.label())),
+                Some(file.etag(fs)),
+            ),
+            DirEntry::FacetedDirectory(_) => (syscalls::DentKind::DentFacetedDirectory, None, None),
+            DirEntry::Gate(gate) => (
+                syscalls::DentKind::DentGate,
+                gate.get(fs).and_then(|g| label(g.label())),
+                None,
+            ),
+            DirEntry::Service(service) => (
+                syscalls::DentKind::DentService,
+                service.get(fs).and_then(|s| label(s.label())),
+                None,
+            ),
+            DirEntry::Blob(blob) => {
+                meta = blob.meta(fs);
+                (
+                    syscalls::DentKind::DentBlob,
+                    blob.get(fs).and_then(|b| label(b.label())),
+                    None,
+                )
+            }
+            DirEntry::Log(log) => (
+                syscalls::DentKind::DentLog,
+                log.get(fs).and_then(|l| label(l.label())),
+                None,
+            ),
+        };
+        syscalls::DentStatResult {
+            success: true,
+            kind: kind.into(),
+            label,
+            etag,
+            size: meta.as_ref().map(|m| m.size),
+            content_type: meta.and_then(|m| m.content_type),
+        }
+    }
+
+    fn dent_download_token(&mut self, fd: u64, ttl: u64) -> syscalls::DownloadTokenResult {
+        let (Some(entry), Some(key)) = (self.dents.get(&fd), self.env.receipt_key.as_ref()) else {
+            return syscalls::DownloadTokenResult::default();
+        };
+        match DownloadToken::mint(&self.env.fs, entry.clone(), ttl) {
+            Ok(token) => {
+                let path = self.dent_paths.get(&fd).cloned().unwrap_or_default();
+                let recorded = fs::audit::record_download(
+                    &self.env.fs,
+                    self.task_id.clone(),
+                    path,
+                    token.label.clone(),
+                );
+                // releases that leave no trace are refused
+                if let Err(e) = recorded {
+                    log::error!("failed to audit the download token of {}: {:?}", self.task_id, e);
+                    return syscalls::DownloadTokenResult::default();
+                }
+                syscalls::DownloadTokenResult {
+                    success: true,
+                    token: Some(token.sign(key)),
+                }
+            }
+            Err(e) => {
+                log::debug!("download token failed: {:?}", e);
+                syscalls::DownloadTokenResult::default()
+            }
+        }
+    }
+
+    fn dent_log_append(&mut self, fd: u64, data: Vec<u8>) -> syscalls::LogResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::Log(log)) => log.append(data, &self.env.fs),
+            _ => Err(FsError::NotALog),
+        };
+        if let Err(e) = result.as_ref() {
+            log::debug!("log append failed: {:?}", e);
+        }
+        syscalls::LogResult {
+            success: result.is_ok(),
+            index: result.unwrap_or_default(),
+            entries: vec![],
+        }
+    }
+
+    fn dent_log_read(&mut self, fd: u64, from: u64, to: u64) -> syscalls::LogResult {
+        let result = match self.dents.get(&fd) {
+            Some(DirEntry::Log(log)) => log.read_range(from, to, &self.env.fs),
+            _ => Err(FsError::NotALog),
+        };
+        syscalls::LogResult {
+            success: result.is_ok(),
+            index: 0,
+            entries: result
+                .unwrap_or_default()
+                .into_iter()
+                .map(|e| syscalls::LogEntry {
+                    time: e.time,
+                    data: e.data,
+                })
+                .collect(),
+        }
+    }
+
     fn dent_list(&mut self, fd: u64) -> syscalls::DentListResult {
         let result = self.dents.get(&fd).and_then(|entry| {
             match entry {
@@ -526,6 +1108,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                             DirEntry::Gate(_) => syscalls::DentKind::DentGate,
                             DirEntry::Service(_) => syscalls::DentKind::DentService,
                             DirEntry::Blob(_) => syscalls::DentKind::DentBlob,
+                            DirEntry::Log(_) => syscalls::DentKind::DentLog,
                         };
                         (name.clone(), kind as i32)
                     })
@@ -547,11 +1130,16 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         }
     }
 
-    fn dent_list_faceted(&mut self, fd: u64, clearance: Buckle) -> syscalls::DentLsFacetedResult {
+    fn dent_list_faceted(
+        &mut self,
+        fd: u64,
+        clearance: Buckle,
+        pattern: fs::FacetPattern,
+    ) -> syscalls::DentLsFacetedResult {
         let result = self.dents.get(&fd).and_then(|entry| {
             match entry {
                 DirEntry::FacetedDirectory(faceted) => Ok(faceted
-                    .list(&self.env.fs, &clearance)
+                    .query(&self.env.fs, &clearance, &pattern)
                     .iter()
                     .map(|(label, _)| label.clone().into())
                     .collect()),
@@ -610,11 +1198,34 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                             self.blobs.insert(blobid, blob);
                             blobid
                         };
+                        let app_layers = dg
+                            .function
+                            .app_layers
+                            .iter()
+                            .map(|layer| {
+                                let blobid = self.max_blob_id;
+                                self.max_blob_id += 1;
+                                let blob = self.env.blobstore.open(layer.clone()).expect("open");
+                                self.blobs.insert(blobid, blob);
+                                blobid
+                            })
+                            .collect();
                         let function = syscalls::Function {
                             memory: dg.function.memory as u64,
                             app_image: app_image_fd,
                             runtime: runtime_fd,
                             kernel: kernel_fd,
+                            devices: dg.function.devices.clone(),
+                            keep_alive: dg.function.keep_alive,
+                            min_instances: dg.function.min_instances as u32,
+                            speculative_boot: dg.function.speculative_boot,
+                            idempotent: dg.function.idempotent,
+                            idle_ttl: dg.function.idle_ttl,
+                            requirements: dg.function.requirements.clone().into_iter().collect(),
+                            forward_headers: dg.function.forward_headers.clone(),
+                            concurrency_target: dg.function.concurrency_target as u64,
+                            scratch: dg.function.scratch as u64,
+                            app_layers,
                         };
                         syscalls::Gate {
                             kind: Some(syscalls::gate::Kind::Direct(syscalls::DirectGate {
@@ -624,6 +1235,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                 ),
                                 declassify: Some(dg.declassify.clone().into()),
                                 function: Some(function),
+                                metadata: dg.metadata.as_deref().cloned().map(Into::into),
                             })),
                         }
                     }
@@ -653,9 +1265,7 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         let target_obj_m = self.dents.get(&target_fd).cloned();
         let result = base_dir_m.zip(target_obj_m).and_then(|(base, target)| {
             match base {
-                DirEntry::Directory(base_dir) => base_dir
-                    .link(name, target, &self.env.fs)
-                    .map_err(|e| Into::into(e)),
+                DirEntry::Directory(base_dir) => base_dir.link(name, target, &self.env.fs),
                 _ => Err(FsError::NotADir),
             }
             .ok()
@@ -698,46 +1308,15 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                     {
                         return None;
                     }
-                    sched::rpc::labeled_invoke(
-                        self.env.sched_conn.as_mut().unwrap(),
-                        sched::message::LabeledInvoke {
-                            function: Some(gate.function.into()),
-                            label: Some(CURRENT_LABEL.with(|cl| cl.borrow().clone()).into()),
-                            gate_privilege: Some(gate.privilege.into()),
-                            blobs: Default::default(),
-                            payload,
-                            headers: parameters,
-                            sync,
-                            invoker: Some(PRIVILEGE.with(|p| p.borrow().clone()).into()),
-                        },
-                    )
-                    .ok()?;
+                    let mut invoke = gate_invocation(gate, payload, parameters, sync);
+                    invoke
+                        .spill_payload(&mut self.env.blobstore, self.env.max_inline)
+                        .ok()?;
+                    let res =
+                        sched::rpc::labeled_invoke(self.env.sched_conn.as_mut().unwrap(), invoke)
+                            .ok()?;
                     if sync {
-                        let res =
-                            message::read::<TaskReturn>(self.env.sched_conn.as_mut().unwrap())
-                                .ok()?;
-                        let res_label = res
-                            .label
-                            .clone()
-                            .map(Into::into)
-                            .unwrap_or(Buckle::public());
-                        fs::utils::taint_with_label(res_label);
-                        if toblob {
-                            // TODO(alevy): would be better to just pass this intent
-                            // through the request and have the target just write a
-                            // blob in the first place
-                            let mut newblob = self.env.blobstore.create().expect("Create blob");
-                            newblob
-                                .write_all(res.payload.unwrap().body())
-                                .expect("Write to blob");
-                            let blob = self.env.blobstore.save(newblob).expect("Save blob");
-                            let blobfd = self.max_blob_id;
-                            self.max_blob_id += 1;
-                            self.blobs.insert(blobfd, blob);
-                            Some((Some(blobfd), None, None))
-                        } else {
-                            Some((None, res.payload.unwrap().body, None))
-                        }
+                        self.gate_result(res, toblob).map(|(blobfd, data)| (blobfd, data, None))
                     } else {
                         Some((None, Some(vec![]), None))
                     }
@@ -761,7 +1340,11 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                                 .collect();
                             if toblob {
                                 let mut newblob = self.env.blobstore.create().expect("Create blob");
-                                response.copy_to(&mut newblob).expect("Copy to blob");
+                                if let Err(e) = response.copy_to(&mut newblob) {
+                                    log::debug!("failed to copy the response to a blob: {:?}", e);
+                                    let _ = self.env.blobstore.abort(newblob);
+                                    return None;
+                                }
                                 let blob = self.env.blobstore.save(newblob).expect("Save blob");
                                 let blobfd = self.max_blob_id;
                                 self.max_blob_id += 1;
@@ -787,9 +1370,183 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             fd: blobfd,
             data,
             headers: headers.unwrap_or(Default::default()),
+            more: false,
         }
     }
 
+    /// Taints the current label with the result of a sync gate invocation and returns its
+    /// response body, either in a new blob descriptor or inline
+    fn gate_result(
+        &mut self,
+        res: TaskReturn,
+        toblob: bool,
+    ) -> Option<(Option<u64>, Option<Vec<u8>>)> {
+        let res_label = res
+            .label
+            .clone()
+            .map(Into::into)
+            .unwrap_or(Buckle::public());
+        fs::utils::taint_with_label(res_label);
+        // only successful invocations have a response
+        let mut response = res.payload?;
+        if toblob && !response.body_blob.is_empty() {
+            // the response is in a blob already
+            let blob = self.env.blobstore.open(response.body_blob).ok()?;
+            let blobfd = self.max_blob_id;
+            self.max_blob_id += 1;
+            self.blobs.insert(blobfd, blob);
+            Some((Some(blobfd), None))
+        } else if toblob {
+            // TODO(alevy): would be better to just pass this intent
+            // through the request and have the target just write a
+            // blob in the first place
+            let mut newblob = self.env.blobstore.create().expect("Create blob");
+            if let Err(e) = newblob.write_all(response.body()) {
+                log::debug!("failed to write the response to a blob: {:?}", e);
+                let _ = self.env.blobstore.abort(newblob);
+                return None;
+            }
+            let blob = self.env.blobstore.save(newblob).expect("Save blob");
+            let blobfd = self.max_blob_id;
+            self.max_blob_id += 1;
+            self.blobs.insert(blobfd, blob);
+            Some((Some(blobfd), None))
+        } else {
+            response.unspill_body(&mut self.env.blobstore).ok()?;
+            Some((None, response.body))
+        }
+    }
+
+    /// Invokes the gates of a fan-out together, see `sched::rpc::gang_invoke`. Services can't
+    /// be part of a gang, and the whole gang fails if any of its members is not an invokable
+    /// gate.
+    fn dent_invoke_gang(
+        &mut self,
+        invokes: Vec<DentInvoke>,
+        min_fraction: f64,
+    ) -> syscalls::DentInvokeGangResult {
+        let failed = syscalls::DentInvokeGangResult {
+            success: false,
+            results: vec![],
+        };
+        let mut labeled_invokes = Vec::with_capacity(invokes.len());
+        for i in invokes.iter() {
+            let gate = match self.dents.get(&i.fd) {
+                Some(DirEntry::Gate(gate)) => gate.to_invokable(&self.env.fs),
+                _ => return failed,
+            };
+            if !fs::utils::get_privilege().implies(&gate.invoker_integrity_clearance) {
+                return failed;
+            }
+            let mut invoke =
+                gate_invocation(gate, i.payload.clone(), i.parameters.clone(), i.sync);
+            if invoke
+                .spill_payload(&mut self.env.blobstore, self.env.max_inline)
+                .is_err()
+            {
+                return failed;
+            }
+            labeled_invokes.push(invoke);
+        }
+        let conn = self.env.sched_conn.as_mut().unwrap();
+        let results = match sched::rpc::gang_invoke(conn, labeled_invokes, min_fraction) {
+            Ok(results) => results,
+            Err(_) => return failed,
+        };
+        let results = invokes
+            .into_iter()
+            .zip(results)
+            .map(|(i, res)| {
+                let (blobfd, data) = if !i.sync {
+                    (None, Some(vec![]))
+                } else {
+                    self.gate_result(res, i.toblob).unwrap_or((None, None))
+                };
+                syscalls::DentInvokeResult {
+                    success: blobfd.is_some() || data.is_some(),
+                    fd: blobfd,
+                    data,
+                    headers: Default::default(),
+                    more: false,
+                }
+            })
+            .collect();
+        syscalls::DentInvokeGangResult {
+            success: true,
+            results,
+        }
+    }
+
+    /// Invokes the gate at `fd` synchronously and returns each of its response chunks in a
+    /// `DentInvokeResult` of its own as it comes, followed by the response body
+    fn dent_invoke_stream(
+        &mut self,
+        fd: u64,
+        payload: Vec<u8>,
+        parameters: HashMap<String, String>,
+        s: &mut impl SyscallChannel,
+    ) -> Result<(), SyscallProcessorError> {
+        use prost::Message;
+        let gate = match self.dents.get(&fd) {
+            Some(DirEntry::Gate(gate)) => gate.to_invokable(&self.env.fs),
+            // services and everything else answer at once
+            _ => {
+                let result = self.dent_invoke(fd, payload, true, false, parameters);
+                s.send(result.encode_to_vec())?;
+                return Ok(());
+            }
+        };
+        if !crate::fs::utils::get_privilege().implies(&gate.invoker_integrity_clearance) {
+            s.send(syscalls::DentInvokeResult::default().encode_to_vec())?;
+            return Ok(());
+        }
+        let mut client = self
+            .env
+            .sched_conn
+            .clone()
+            .ok_or(SyscallProcessorError::UnreachableScheduler)?;
+        let mut invoke = gate_invocation(gate, payload, parameters, true);
+        invoke.stream = true;
+        if invoke
+            .spill_payload(&mut self.env.blobstore, self.env.max_inline)
+            .is_err()
+        {
+            s.send(syscalls::DentInvokeResult::default().encode_to_vec())?;
+            return Ok(());
+        }
+        let mut sent = Ok(());
+        let res = sched::rpc::labeled_invoke_stream(&mut client, invoke, |chunk| {
+            if sent.is_err() {
+                return;
+            }
+            // the function sees the chunk, so it is tainted with the label the chunk was sent at
+            fs::utils::taint_with_label(chunk.label.map(Into::into).unwrap_or(Buckle::public()));
+            let result = syscalls::DentInvokeResult {
+                success: true,
+                data: Some(chunk.data),
+                more: true,
+                ..Default::default()
+            };
+            sent = s.send(result.encode_to_vec());
+        });
+        sent?;
+        let blobstore = &mut self.env.blobstore;
+        let data = res.ok().and_then(|res| {
+            let res_label = res.label.map(Into::into).unwrap_or(Buckle::public());
+            fs::utils::taint_with_label(res_label);
+            let mut response = res.payload?;
+            response.unspill_body(blobstore).ok()?;
+            response.body
+        });
+        let result = syscalls::DentInvokeResult {
+            success: data.is_some(),
+            data,
+            ..Default::default()
+        };
+        s.send(result.encode_to_vec())?;
+        Ok(())
+    }
+
     fn dent_get_blob(&mut self, fd: u64) -> syscalls::BlobResult {
         match self.dents.get(&fd) {
             Some(DirEntry::Blob(blobentry)) => {
@@ -800,7 +1557,10 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                     .expect("blob");
                 let blobfd = self.max_blob_id;
                 self.max_blob_id += 1;
-                let len = blob.len().expect("blob should exist");
+                let len = match blobentry.meta(&self.env.fs) {
+                    Some(meta) => meta.size,
+                    None => blob.len().expect("blob should exist"),
+                };
                 self.blobs.insert(blobfd, blob);
                 syscalls::BlobResult {
                     success: true,
@@ -849,12 +1609,18 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                     len: len as u64,
                     data: None,
                 },
-                Err(e) => syscalls::BlobResult {
-                    success: false,
-                    fd,
-                    len: 0,
-                    data: Some(e.to_string().into()),
-                },
+                Err(e) => {
+                    // a blob missing a write is garbage
+                    if let Some(blob) = self.create_blobs.remove(&fd) {
+                        let _ = self.env.blobstore.abort(blob);
+                    }
+                    syscalls::BlobResult {
+                        success: false,
+                        fd,
+                        len: 0,
+                        data: Some(e.to_string().into()),
+                    }
+                }
             }
         } else {
             syscalls::BlobResult {
@@ -952,18 +1718,71 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         use prost::Message;
 
         match sc {
-            SC::Response(r) => {
-                let result_label = fs::utils::declassify_with(&crate::fs::utils::get_privilege());
+            SC::Response(mut r) => {
+                if let Some(body) = r.body_shm.take() {
+                    let body = s
+                        .shared_memory()
+                        .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+                        .and_then(|shm| shm.read(&body))
+                        .map_err(SyscallProcessorError::SharedMemory)?;
+                    r.body = Some(body);
+                }
+                if !self.chunks.is_empty() {
+                    let mut body = std::mem::take(&mut self.chunks);
+                    body.extend(r.body.unwrap_or_default());
+                    r.body = Some(body);
+                }
+                let result_label = self.declassify_result();
                 return Ok(Some(TaskReturn {
                     code: ReturnCode::Success as i32,
                     payload: Some(r),
                     label: Some(result_label.into()),
+                    error: None,
+                    receipts: std::mem::take(&mut self.receipts),
+                    taint_trace: self.taint_trace.clone().unwrap_or_default(),
                 }));
             }
+            SC::Abort(abort) => {
+                // the error says as much about the function's data as a response would
+                let result_label = self.declassify_result();
+                let code = if abort.out_of_memory {
+                    ReturnCode::OutOfMemory
+                } else {
+                    ReturnCode::FunctionError
+                };
+                return Ok(Some(TaskReturn {
+                    code: code as i32,
+                    payload: None,
+                    label: Some(result_label.into()),
+                    error: Some(abort),
+                    receipts: std::mem::take(&mut self.receipts),
+                    taint_trace: self.taint_trace.clone().unwrap_or_default(),
+                }));
+            }
+
+            SC::ResponseChunk(data) => match self.stream_to.clone() {
+                Some(task_id) => {
+                    let label = fs::utils::get_current_label()
+                        .downgrade(&crate::fs::utils::get_privilege());
+                    let chunk = sched::message::ResponseChunk {
+                        task_id,
+                        data,
+                        label: Some(label.into()),
+                    };
+                    let client = self
+                        .env
+                        .sched_conn
+                        .as_mut()
+                        .ok_or(SyscallProcessorError::UnreachableScheduler)?;
+                    sched::rpc::send_chunk(client, chunk)
+                        .map_err(|_| SyscallProcessorError::UnreachableScheduler)?;
+                }
+                None => self.chunks.extend(data),
+            },
 
             SC::BuckleParse(label) => {
                 let result: Result<syscalls::Buckle, _> =
-                    Buckle::parse(label.as_str()).map(Into::into);
+                    self.aliases().resolve(label.as_str()).map(Into::into);
                 s.send(syscalls::MaybeBuckle { label: result.ok() }.encode_to_vec())?;
             }
             SC::GetCurrentLabel(syscalls::Void {}) => {
@@ -985,12 +1804,43 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             }
             SC::Declassify(component) => {
                 let target = component.into();
+                let declassified = fs::utils::declassify(target).ok();
+                if let Some(to) = declassified.as_ref() {
+                    let from = fs::utils::get_current_label();
+                    self.record_declassification(fs::utils::get_privilege(), from, to.clone());
+                }
                 let result = syscalls::MaybeBuckle {
-                    label: fs::utils::declassify(target).map(Into::into).ok(),
+                    label: declassified.map(Into::into),
                 };
                 s.send(result.encode_to_vec())?;
             }
             SC::SubPrivilege(_) => todo!(),
+            SC::ApplyDelegation(token) => {
+                let result = self
+                    .env
+                    .delegation_key
+                    .as_ref()
+                    .ok_or(DelegationError::BadSignature)
+                    .and_then(|key| Delegation::verify(&token, key))
+                    .and_then(|d| d.apply(fs::utils::get_privilege()));
+                let result = match result {
+                    Ok(newpriv) => {
+                        fs::utils::set_my_privilge(newpriv.clone());
+                        syscalls::DelegationResult {
+                            success: true,
+                            privilege: Some(newpriv.into()),
+                        }
+                    }
+                    Err(e) => {
+                        log::info!("Delegation rejected {:?}", e);
+                        syscalls::DelegationResult {
+                            success: false,
+                            privilege: Some(fs::utils::get_privilege().into()),
+                        }
+                    }
+                };
+                s.send(result.encode_to_vec())?;
+            }
 
             SC::Root(syscalls::Void {}) => s.send(self.root().encode_to_vec())?,
 
@@ -1000,11 +1850,15 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             SC::DentClose(fd) => {
                 s.send(self.dent_close(fd).encode_to_vec())?;
             }
-            SC::DentCreate(syscalls::DentCreate { kind, label }) => {
+            SC::DentCreate(syscalls::DentCreate {
+                kind,
+                label,
+                content_type,
+            }) => {
                 let label = label.map(Into::into);
                 s.send(
                     (if let Some(kind) = kind {
-                        self.dent_create(kind, label)
+                        self.dent_create(kind, label, content_type)
                             .map_err(|e| log::info!("Err {:?}", e))
                             .unwrap_or(syscalls::DentResult {
                                 success: false,
@@ -1021,10 +1875,14 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                     .encode_to_vec(),
                 )?;
             }
-            SC::DentUpdate(syscalls::DentUpdate { kind, fd }) => {
+            SC::DentUpdate(syscalls::DentUpdate {
+                kind,
+                fd,
+                content_type,
+            }) => {
                 s.send(
                     (if let Some(kind) = kind {
-                        self.dent_update(fd, kind)
+                        self.dent_update(fd, kind, content_type)
                             .map_err(|e| log::info!("Err {:?}", e))
                             .unwrap_or(syscalls::DentResult {
                                 success: false,
@@ -1041,11 +1899,57 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                     .encode_to_vec(),
                 )?;
             }
+            SC::CounterIncrement(syscalls::CounterIncrement { path, delta }) => {
+                s.send(self.counter_increment(&path, delta).encode_to_vec())?
+            }
+            SC::LockAcquire(syscalls::LockAcquire { path, ttl, token }) => {
+                s.send(self.lock_acquire(&path, ttl, token).encode_to_vec())?
+            }
+            SC::LockRelease(syscalls::LockRelease { path, token }) => {
+                s.send(self.lock_release(&path, token).encode_to_vec())?
+            }
+            SC::BarrierInit(syscalls::BarrierInit {
+                path,
+                expected,
+                gate,
+            }) => s.send(self.barrier_init(&path, expected, gate).encode_to_vec())?,
+            SC::BarrierArrive(syscalls::BarrierArrive { path, result }) => {
+                s.send(self.barrier_arrive(&path, result).encode_to_vec())?
+            }
+            SC::ScratchWrite(syscalls::ScratchWrite { name, data, offset }) => {
+                s.send(self.scratch_write(&name, &data, offset).encode_to_vec())?
+            }
+            SC::ScratchRead(syscalls::ScratchRead {
+                name,
+                offset,
+                length,
+            }) => s.send(self.scratch_read(&name, offset, length).encode_to_vec())?,
+            SC::ScratchRemove(name) => s.send(self.scratch_remove(&name).encode_to_vec())?,
+            SC::ScratchList(syscalls::Void {}) => s.send(self.scratch_list().encode_to_vec())?,
+
             SC::DentRead(fd) => s.send(self.dent_read(fd).encode_to_vec())?,
+            SC::DentStat(fd) => s.send(self.dent_stat(fd).encode_to_vec())?,
+            SC::DentLogAppend(syscalls::DentLogAppend { fd, data }) => {
+                s.send(self.dent_log_append(fd, data).encode_to_vec())?
+            }
+            SC::DentLogRead(syscalls::DentLogRead { fd, from, to }) => {
+                s.send(self.dent_log_read(fd, from, to).encode_to_vec())?
+            }
+            SC::DentDownloadToken(syscalls::DentDownloadToken { fd, ttl }) => {
+                s.send(self.dent_download_token(fd, ttl).encode_to_vec())?
+            }
             SC::DentList(fd) => s.send(self.dent_list(fd).encode_to_vec())?,
-            SC::DentLsFaceted(syscalls::DentLsFaceted { fd, clearance }) => s.send(
-                self.dent_list_faceted(fd, clearance.map(Into::into).unwrap_or(Buckle::public()))
-                    .encode_to_vec(),
+            SC::DentLsFaceted(syscalls::DentLsFaceted {
+                fd,
+                clearance,
+                pattern,
+            }) => s.send(
+                self.dent_list_faceted(
+                    fd,
+                    clearance.map(Into::into).unwrap_or(Buckle::public()),
+                    pattern.map(Into::into).unwrap_or_default(),
+                )
+                .encode_to_vec(),
             )?,
             SC::DentLsGate(fd) => s.send(self.dent_ls_gate(fd).encode_to_vec())?,
             SC::DentLink(syscalls::DentLink {
@@ -1056,17 +1960,30 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
             SC::DentUnlink(syscalls::DentUnlink { fd, name }) => {
                 s.send(self.dent_unlink(fd, &name).encode_to_vec())?
             }
+            SC::DentInvoke(DentInvoke {
+                fd,
+                sync: true,
+                payload,
+                toblob: false,
+                parameters,
+                stream: true,
+            }) => self.dent_invoke_stream(fd, payload, parameters, s)?,
             SC::DentInvoke(DentInvoke {
                 fd,
                 sync,
                 payload,
                 toblob,
                 parameters,
+                stream: _,
             }) => s.send(
                 self.dent_invoke(fd, payload, sync, toblob, parameters)
                     .encode_to_vec(),
             )?,
             SC::DentGetBlob(fd) => s.send(self.dent_get_blob(fd).encode_to_vec())?,
+            SC::DentInvokeGang(syscalls::DentInvokeGang {
+                invokes,
+                min_fraction,
+            }) => s.send(self.dent_invoke_gang(invokes, min_fraction).encode_to_vec())?,
 
             SC::BlobCreate(syscalls::BlobCreate { size: _ }) => {
                 s.send(self.blob_create().encode_to_vec())?;
@@ -1098,7 +2015,6 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         invoker: Component,
         s: &mut impl SyscallChannel,
     ) -> Result<TaskReturn, SyscallProcessorError> {
-        use prost::Message;
         let blobfds = blobs
             .drain()
             .map(|(k, b)| {
@@ -1128,19 +2044,76 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
                 }
             }
         }
-        s.send(
-            syscalls::Request {
-                payload,
-                blobs: blobfds,
-                headers,
-                invoker: from_invoker_privilege_to_invoker_principal_list(invoker),
+        let request = syscalls::Request {
+            payload,
+            blobs: blobfds,
+            headers,
+            invoker: from_invoker_privilege_to_invoker_principal_list(invoker),
+            ..Default::default()
+        };
+        self.serve(request, s)
+    }
+
+    /// Runs the function's init entrypoint. The label and privilege carry over to the request
+    /// that `run` handles next.
+    pub fn init(&mut self, s: &mut impl SyscallChannel) -> Result<(), SyscallProcessorError> {
+        let request = syscalls::Request {
+            init: true,
+            ..Default::default()
+        };
+        let result = self.serve(request, s)?;
+        if result.code == ReturnCode::Success as i32 {
+            Ok(())
+        } else {
+            Err(SyscallProcessorError::InitFailed(result.error))
+        }
+    }
+
+    // Sends the request to the function and processes its syscalls until it completes
+    fn serve(
+        &mut self,
+        mut request: syscalls::Request,
+        s: &mut impl SyscallChannel,
+    ) -> Result<TaskReturn, SyscallProcessorError> {
+        use prost::Message;
+        if let Some(shm) = s.shared_memory() {
+            request.shm_device = shm.guest_device().to_string();
+            request.shm_size = shm.size();
+            if shm.fits(request.payload.len()) {
+                let payload_shm = shm
+                    .write(&request.payload)
+                    .map_err(SyscallProcessorError::SharedMemory)?;
+                request.payload_shm = Some(payload_shm);
+                request.payload.clear();
             }
-            .encode_to_vec(),
-        )?;
+        }
+        s.send(request.encode_to_vec())?;
 
         loop {
             if let Some(sc) = s.wait()? {
-                match self.do_syscall(sc, s) {
+                let traced = self
+                    .taint_trace
+                    .is_some()
+                    .then(|| (self.describe(&sc), fs::utils::get_current_label()));
+                let next_dent = self.max_dent_id;
+                let result = self.do_syscall(sc, s);
+                if let (Some(((syscall, path), from)), Some(trace)) =
+                    (traced, self.taint_trace.as_mut())
+                {
+                    if syscall == "dentOpen" && self.max_dent_id > next_dent {
+                        self.dent_paths.insert(next_dent, path.clone());
+                    }
+                    let to = fs::utils::get_current_label();
+                    if to != from && from.can_flow_to(&to) {
+                        trace.push(sched::message::LabelRaise {
+                            syscall: syscall.to_string(),
+                            path,
+                            from: Some(from.into()),
+                            to: Some(to.into()),
+                        });
+                    }
+                }
+                match result {
                     Err(er) => return Err(er),
                     Ok(Some(tr)) => return Ok(tr),
                     _ => {}
@@ -1151,3 +2124,32 @@ impl<'a, B: BackingStore + 'a> SyscallProcessor<'a, B> {
         }
     }
 }
+
+// invocation of `gate` on behalf of the current function
+fn gate_invocation(
+    gate: DirectGate,
+    payload: Vec<u8>,
+    parameters: HashMap<String, String>,
+    sync: bool,
+) -> sched::message::LabeledInvoke {
+    sched::message::LabeledInvoke {
+        function: Some(gate.function.into()),
+        label: Some(CURRENT_LABEL.with(|cl| cl.borrow().clone()).into()),
+        gate_privilege: Some(gate.privilege.into()),
+        blobs: Default::default(),
+        payload,
+        headers: parameters,
+        sync,
+        invoker: Some(PRIVILEGE.with(|p| p.borrow().clone()).into()),
+        priority: 0,
+        stream: false,
+        trace_taint: false,
+        preemptible: false,
+        sealed: false,
+        reply_key: vec![],
+        callback: String::new(),
+        payload_blob: String::new(),
+        payload_blob_transient: false,
+        seal_id: String::new(),
+    }
+}
@@ -1,5 +1,48 @@
 include!(concat!(env!("OUT_DIR"), "/snapfaas.syscalls.rs"));
 
+use std::io::{Read, Write};
+
+use crate::blobstore::Blobstore;
+
+impl Response {
+    /// Moves a body larger than `limit` bytes into a blob, unless `limit` is 0. A blob the
+    /// function named itself is discarded, only spilling sets one.
+    pub fn spill_body(&mut self, blobstore: &mut Blobstore, limit: usize) -> std::io::Result<()> {
+        self.body_blob.clear();
+        self.body_blob_transient = false;
+        match self.body.as_ref() {
+            Some(body) if limit > 0 && body.len() > limit => {
+                let mut newblob = blobstore.create()?;
+                if let Err(e) = newblob.write_all(body) {
+                    let _ = blobstore.abort(newblob);
+                    return Err(e);
+                }
+                let (blob, created) = blobstore.save_new(newblob)?;
+                self.body_blob = blob.name;
+                self.body_blob_transient = created;
+                self.body = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Moves a body that `spill_body` moved into a blob back into `body`, deleting the blob if
+    /// spilling created it
+    pub fn unspill_body(&mut self, blobstore: &mut Blobstore) -> std::io::Result<()> {
+        if !self.body_blob.is_empty() {
+            let name = std::mem::take(&mut self.body_blob);
+            let mut body = Vec::new();
+            blobstore.open(name.clone())?.read_to_end(&mut body)?;
+            self.body = Some(body);
+            if std::mem::take(&mut self.body_blob_transient) {
+                blobstore.delete(&name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Into<labeled::buckle::Component> for Component {
     fn into(self) -> labeled::buckle::Component {
         match self.component.unwrap() {
@@ -69,6 +112,36 @@ impl From<&crate::fs::DirEntry> for DentKind {
             DirEntry::Gate(_) => DentKind::DentGate,
             DirEntry::Service(_) => DentKind::DentService,
             DirEntry::Blob(_) => DentKind::DentBlob,
+            DirEntry::Log(_) => DentKind::DentLog,
+        }
+    }
+}
+
+impl From<FacetPattern> for crate::fs::FacetPattern {
+    fn from(p: FacetPattern) -> Self {
+        crate::fs::FacetPattern {
+            secrecy: p.secrecy.map(|t| t.tokens),
+            integrity: p.integrity.map(|t| t.tokens),
+        }
+    }
+}
+
+impl From<Metadata> for crate::fs::Metadata {
+    fn from(m: Metadata) -> Self {
+        crate::fs::Metadata {
+            description: m.description,
+            tags: m.tags,
+            example_payload: m.example_payload,
+        }
+    }
+}
+
+impl From<crate::fs::Metadata> for Metadata {
+    fn from(m: crate::fs::Metadata) -> Self {
+        Metadata {
+            description: m.description,
+            tags: m.tags,
+            example_payload: m.example_payload,
         }
     }
 }
@@ -81,6 +154,7 @@ impl Into<crate::fs::HttpVerb> for HttpVerb {
             HttpVerb::HttpPost   => crate::fs::HttpVerb::POST,
             HttpVerb::HttpPut    => crate::fs::HttpVerb::PUT,
             HttpVerb::HttpDelete => crate::fs::HttpVerb::DELETE,
+            HttpVerb::HttpPatch  => crate::fs::HttpVerb::PATCH,
         }
     }
 }
@@ -93,6 +167,73 @@ impl From<crate::fs::HttpVerb> for HttpVerb {
             crate::fs::HttpVerb::POST   => HttpVerb::HttpPost,
             crate::fs::HttpVerb::PUT    => HttpVerb::HttpPut,
             crate::fs::HttpVerb::DELETE => HttpVerb::HttpDelete,
+            crate::fs::HttpVerb::PATCH  => HttpVerb::HttpPatch,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blobstore(dir: &tempfile::TempDir) -> Blobstore {
+        let base = dir.path().join("blobs");
+        let tmp = dir.path().join("tmp");
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::create_dir_all(&tmp).unwrap();
+        Blobstore::new(base.into_os_string(), tmp.into_os_string())
+    }
+
+    #[test]
+    fn test_spill_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut blobstore = blobstore(&dir);
+        let mut response = Response {
+            body: Some(vec![7; 64]),
+            ..Default::default()
+        };
+        response.spill_body(&mut blobstore, 16).unwrap();
+        assert!(response.body.is_none());
+        let name = response.body_blob.clone();
+        assert!(response.body_blob_transient);
+        response.unspill_body(&mut blobstore).unwrap();
+        assert_eq!(response.body, Some(vec![7; 64]));
+        assert!(response.body_blob.is_empty());
+        // delivered, the blob is gone
+        assert!(blobstore.open(name).is_err());
+    }
+
+    #[test]
+    fn test_spill_body_keeps_existing_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut blobstore = blobstore(&dir);
+        let mut newblob = blobstore.create().unwrap();
+        newblob.write_all(&[7; 64]).unwrap();
+        let existing = blobstore.save(newblob).unwrap().name;
+        let mut response = Response {
+            body: Some(vec![7; 64]),
+            ..Default::default()
+        };
+        response.spill_body(&mut blobstore, 16).unwrap();
+        assert_eq!(response.body_blob, existing);
+        assert!(!response.body_blob_transient);
+        response.unspill_body(&mut blobstore).unwrap();
+        assert!(blobstore.open(existing).is_ok());
+    }
+
+    #[test]
+    fn test_spill_body_discards_function_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut blobstore = blobstore(&dir);
+        let mut response = Response {
+            body: Some(b"small".to_vec()),
+            body_blob: "00someone_elses_blob".to_string(),
+            body_blob_transient: true,
+            ..Default::default()
+        };
+        response.spill_body(&mut blobstore, 16).unwrap();
+        assert!(response.body_blob.is_empty());
+        assert!(!response.body_blob_transient);
+        assert_eq!(response.body.as_deref(), Some(&b"small"[..]));
+    }
+}
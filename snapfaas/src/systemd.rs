@@ -0,0 +1,47 @@
+//! systemd integration for the servers. Services with `Type=notify` learn when a server is ready
+//! to serve, and with socket activation a server serves the socket systemd passes it instead of
+//! binding its own, so that restarts don't refuse connections. Outside systemd, both do nothing.
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+
+use log::warn;
+use sd_notify::NotifyState;
+
+/// Tells systemd the server is ready
+pub fn notify_ready() {
+    notify(NotifyState::Ready);
+}
+
+/// Tells systemd the server is shutting down
+pub fn notify_stopping() {
+    notify(NotifyState::Stopping);
+}
+
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("failed to notify systemd: {}", e);
+    }
+}
+
+/// The TCP socket systemd passed, if any. Only the first call gets it.
+pub fn tcp_listener() -> Option<TcpListener> {
+    // systemd passed the descriptor for this process to own
+    activated_fd().map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+/// The Unix socket systemd passed, if any. Only the first call gets it.
+pub fn unix_listener() -> Option<UnixListener> {
+    // systemd passed the descriptor for this process to own
+    activated_fd().map(|fd| unsafe { UnixListener::from_raw_fd(fd) })
+}
+
+fn activated_fd() -> Option<RawFd> {
+    match sd_notify::listen_fds() {
+        Ok(mut fds) => fds.next(),
+        Err(e) => {
+            warn!("ignoring sockets passed by systemd: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Helpers for testing code that talks to external HTTP services
+//!
+//! `MockHttpService` stands in for services such as the GitHub API. It serves programmed
+//! responses on a local port and records every request it receives, so a test can point a
+//! `Service` object, the webhook frontend or any other HTTP client at `url()` and check what was
+//! sent. Other crates get it with the `testutil` feature, as a dev-dependency.
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use rouille::{Request, Response};
+
+/// A request the mock received
+#[derive(Clone, Debug)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// path and query string
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> serde_json::Result<serde_json::Value> {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+/// A programmed response
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn new(status_code: u16, body: impl Into<Vec<u8>>) -> Self {
+        MockResponse {
+            status_code,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    pub fn json(status_code: u16, body: &serde_json::Value) -> Self {
+        MockResponse::new(status_code, serde_json::to_vec(body).unwrap())
+            .with_header("Content-Type", "application/json")
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+// a programmed route, matching `method` and the path without the query string
+struct Route {
+    method: String,
+    path: String,
+    responses: Vec<MockResponse>,
+}
+
+#[derive(Default)]
+struct State {
+    routes: Vec<Route>,
+    requests: Vec<RecordedRequest>,
+}
+
+impl State {
+    // the response programmed for `method` and `path`, see `MockHttpService::respond`
+    fn next_response(&mut self, method: &str, path: &str) -> Option<MockResponse> {
+        let route = self.routes.iter_mut().find(|r| r.method == method && r.path == path)?;
+        if route.responses.len() > 1 {
+            Some(route.responses.remove(0))
+        } else {
+            Some(route.responses[0].clone())
+        }
+    }
+}
+
+pub struct MockHttpService {
+    addr: std::net::SocketAddr,
+    state: Arc<Mutex<State>>,
+    stop: Option<std::sync::mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockHttpService {
+    /// Starts serving on a free local port. Requests no route matches get a 404.
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+        let handler_state = state.clone();
+        let server = rouille::Server::new("127.0.0.1:0", move |request| {
+            handle(&handler_state, request)
+        })
+        .expect("bind mock HTTP service");
+        let addr = server.server_addr();
+        let (thread, stop) = server.stoppable();
+        MockHttpService {
+            addr,
+            state,
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Base URL of the mock, e.g. `http://127.0.0.1:4321`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Answers `method` requests for `path`, without the query string, with `response`.
+    /// Programming the same method and path again queues another response. Queued responses are
+    /// served in order, the last one repeatedly.
+    pub fn respond(&self, method: &str, path: &str, response: MockResponse) {
+        let mut state = self.state.lock().unwrap();
+        match state
+            .routes
+            .iter_mut()
+            .find(|r| r.method == method && r.path == path)
+        {
+            Some(route) => route.responses.push(response),
+            None => state.routes.push(Route {
+                method: method.to_string(),
+                path: path.to_string(),
+                responses: vec![response],
+            }),
+        }
+    }
+
+    /// Requests received so far, oldest first
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Requests received so far for `method` and `path`
+    pub fn requests_to(&self, method: &str, path: &str) -> Vec<RecordedRequest> {
+        self.requests()
+            .into_iter()
+            .filter(|r| r.method == method && r.url.split('?').next() == Some(path))
+            .collect()
+    }
+}
+
+impl Drop for MockHttpService {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn handle(state: &Mutex<State>, request: &Request) -> Response {
+    let mut body = vec![];
+    if let Some(mut data) = request.data() {
+        let _ = std::io::Read::read_to_end(&mut data, &mut body);
+    }
+    let recorded = RecordedRequest {
+        method: request.method().to_string(),
+        url: request.raw_url().to_string(),
+        headers: request
+            .headers()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect(),
+        body,
+    };
+    let mut state = state.lock().unwrap();
+    let response = state.next_response(&recorded.method, &request.url());
+    state.requests.push(recorded);
+    match response {
+        Some(mock) => {
+            let mut response = Response::from_data("application/octet-stream", mock.body)
+                .with_status_code(mock.status_code);
+            for (name, value) in mock.headers {
+                response = response.with_unique_header(name, value);
+            }
+            response
+        }
+        None => Response::empty_404(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_response() {
+        let mut state = State::default();
+        state.routes.push(Route {
+            method: "GET".to_string(),
+            path: "/user".to_string(),
+            responses: vec![MockResponse::new(500, "busy"), MockResponse::new(200, "alice")],
+        });
+        assert_eq!(state.next_response("GET", "/user").unwrap().status_code, 500);
+        // the last response repeats
+        assert_eq!(state.next_response("GET", "/user").unwrap().body, b"alice");
+        assert_eq!(state.next_response("GET", "/user").unwrap().body, b"alice");
+        assert!(state.next_response("POST", "/user").is_none());
+        assert!(state.next_response("GET", "/repos").is_none());
+    }
+
+    #[test]
+    fn test_recorded_request() {
+        let request = RecordedRequest {
+            method: "POST".to_string(),
+            url: "/hook?x=1".to_string(),
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: br#"{"ok":true}"#.to_vec(),
+        };
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(request.header("Accept"), None);
+        assert_eq!(request.json().unwrap()["ok"], true);
+
+        let response = MockResponse::json(201, &serde_json::json!({}));
+        assert_eq!(response.headers[0].1, "application/json");
+    }
+}
@@ -1,24 +1,39 @@
 //! Host-side VM handle that transfer data in and out of the VM through VSOCK socket and
 //! implements syscall API
 
-use std::io::{Read, Write};
+use std::collections::VecDeque;
+use std::io::{BufRead, Read, Write};
 use std::net::Shutdown;
+use std::os::fd::OwnedFd;
 use std::os::unix::net::UnixStream;
-use std::process::Stdio;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
 use std::string::String;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use labeled::buckle::Buckle;
 use log::{debug, error};
 use prost::Message;
 use tokio::process::{Child, Command};
 
+use crate::cid::{self, Lease};
 use crate::configs::FunctionConfig;
+use crate::hypervisor::LaunchSpec;
+use crate::manifest::{self, VmRecord};
+use crate::shm::SharedMemory;
+use crate::supervisor::{self, Watch};
 use crate::syscall_server::{SyscallChannel, SyscallChannelError};
 use crate::syscalls;
 use crate::syscalls::syscall::Syscall as SC;
 
 //const MACPREFIX: &str = "AA:BB:CC:DD";
 
+// number of the guest's most recent console lines kept for diagnostics
+const CONSOLE_LINES: usize = 64;
+// what the guest kernel prints when it runs out of memory
+const OOM_MARKERS: [&str; 2] = ["Out of memory", "invoked oom-killer"];
+
 #[derive(Debug)]
 pub enum Error {
     ProcessSpawn(std::io::Error),
@@ -35,6 +50,14 @@ pub enum Error {
     LoadDirNotExist,
     DB(lmdb::Error),
     BlobError(std::io::Error),
+    SharedMemory(std::io::Error),
+    Unsupported(&'static str),
+    /// no vsock CID is available
+    Cid(std::io::Error),
+    /// the hypervisor exited before the VM's runtime connected
+    BootFailed(Option<ExitStatus>),
+    /// no trusted key signed the image blob, or it was tampered with, see `signing`
+    UntrustedImage(String),
 }
 
 impl From<std::io::Error> for Error {
@@ -54,10 +77,86 @@ pub struct OdirectOption {
 #[derive(Debug)]
 pub struct VmHandle {
     conn: UnixStream,
-    #[allow(dead_code)]
-    // This field is never used, but we need to it make sure the Child isn't dropped and, thus,
-    // killed, before the VmHandle is dropped.
-    vm_process: Child,
+    // We need it to make sure the Child isn't dropped and, thus, killed, before the VmHandle is
+    // dropped, and for the hypervisor's exit status. None for a VM reattached after a restart,
+    // whose hypervisor is not our child.
+    vm_process: Option<Child>,
+    // the hypervisor process and its start time, see `manifest::VmRecord`
+    pid: u32,
+    started: u64,
+    cid: Lease,
+    // the Unix socket the VM connected to
+    vsock_path: PathBuf,
+    // the guest's most recent console lines
+    console: Arc<Mutex<VecDeque<String>>>,
+    // tells whether the hypervisor exited, none if it cannot be supervised
+    watch: Option<Watch>,
+}
+
+// ioctl that makes a file share the extents of another on copy-on-write file systems
+const FICLONE: libc::c_ulong = 0x40049409;
+
+/// A directory of a VM's own links to the blobs it boots from, so that the hypervisor only
+/// needs to see its VM's files rather than the whole blobstore. Removed when dropped.
+#[derive(Debug)]
+pub struct Jail {
+    dir: PathBuf,
+}
+
+impl Jail {
+    /// Creates the jail of VM `vm_id` under `base`
+    pub fn create(base: &Path, vm_id: usize) -> std::io::Result<Self> {
+        // workers on the same machine may share `base`
+        let dir = base.join(format!("{}-{}", std::process::id(), vm_id));
+        std::fs::create_dir_all(&dir)?;
+        Ok(Jail { dir })
+    }
+
+    /// Takes over the existing jail at `dir`, e.g. of a VM a previous worker launched
+    pub fn adopt(dir: PathBuf) -> Self {
+        Jail { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Puts the file at `src` into the jail as `name` and returns its path in the jail. Blobs
+    /// never change, so a hard link does. Across file systems, it is a reflink where the file
+    /// system supports them and a copy elsewhere.
+    pub fn link(&self, src: &str, name: &str) -> std::io::Result<String> {
+        let dest = self.dir.join(name);
+        if !dest.exists() {
+            if let Err(e) = std::fs::hard_link(src, &dest) {
+                if e.raw_os_error() != Some(libc::EXDEV) {
+                    return Err(e);
+                }
+                reflink_or_copy(Path::new(src), &dest)?;
+            }
+        }
+        dest.into_os_string()
+            .into_string()
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))
+    }
+}
+
+impl Drop for Jail {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            error!("failed to remove the VM jail {:?}: {:?}", self.dir, e);
+        }
+    }
+}
+
+fn reflink_or_copy(src: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let from = std::fs::File::open(src)?;
+    let to = std::fs::File::create(dest)?;
+    if unsafe { libc::ioctl(to.as_raw_fd(), FICLONE, from.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+    drop(to);
+    std::fs::copy(src, dest).map(|_| ())
 }
 
 #[derive(Debug)]
@@ -66,6 +165,14 @@ pub struct Vm {
     pub function: super::fs::Function,
     pub label: Buckle,
     pub handle: Option<VmHandle>,
+    /// when the VM last became idle
+    pub idle_since: SystemTime,
+    /// PCI addresses of the passthrough devices assigned to the VM, in the order of
+    /// `function.devices`
+    pub devices: Vec<String>,
+    /// links to the VM's blobs, if the worker jails VMs
+    pub jail: Option<Jail>,
+    shm: Option<SharedMemory>,
 }
 
 impl Vm {
@@ -75,15 +182,67 @@ impl Vm {
             function,
             label: Buckle::public(),
             handle: None,
+            idle_since: SystemTime::now(),
+            devices: Vec::new(),
+            jail: None,
+            shm: None,
+        }
+    }
+
+    /// The VM a previous worker recorded as `record`, whose runtime connected again at `conn`
+    /// and whose CID is held by `cid`
+    pub fn reattach(record: VmRecord, conn: UnixStream, cid: Lease) -> Self {
+        let idle_since = record.idle_since.unwrap_or_default();
+        let idle_since = SystemTime::UNIX_EPOCH + Duration::from_secs(idle_since);
+        Self {
+            id: record.id,
+            function: record.function,
+            label: record.label,
+            handle: Some(VmHandle {
+                vm_process: None,
+                pid: record.pid,
+                started: record.started,
+                cid,
+                vsock_path: record.vsock_path,
+                console: Default::default(),
+                watch: watch(record.pid, &conn),
+                conn,
+            }),
+            idle_since,
+            devices: record.devices,
+            jail: record.jail.map(Jail::adopt),
+            shm: None,
         }
     }
 
-    /// Launch the current Vm instance.
+    /// What a manifest records about the VM, none if it isn't running
+    pub fn record(&self, idle: bool) -> Option<VmRecord> {
+        let handle = self.handle.as_ref()?;
+        let idle_since = self
+            .idle_since
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        Some(VmRecord {
+            id: self.id,
+            pid: handle.pid,
+            started: handle.started,
+            cid: handle.cid.cid(),
+            vsock_path: handle.vsock_path.clone(),
+            function: self.function.clone(),
+            label: self.label.clone(),
+            devices: self.devices.clone(),
+            jail: self.jail.as_ref().map(|j| j.dir().to_path_buf()),
+            shared_mem: self.shm.is_some(),
+            idle_since: idle.then_some(idle_since),
+        })
+    }
+
+    /// Launch the current Vm instance with vsock CID `cid`, or one allocated host-wide if `None`.
     /// When this function returns, the VM has finished booting and is ready to accept requests.
     pub fn launch(
         &mut self,
-        vm_listener: std::os::unix::net::UnixListener,
-        cid: u32,
+        cid: Option<u32>,
         force_exit: bool,
         function_config: FunctionConfig,
         odirect: Option<OdirectOption>,
@@ -91,94 +250,85 @@ impl Vm {
         if self.handle.is_some() {
             return Ok(());
         }
-        let mem_str = function_config.memory.to_string();
-        let vcpu_str = function_config.vcpus.to_string();
-        let cid_str = cid.to_string();
-        let id_str = self.id.to_string();
-        let mut args = vec![
-            "--id",
-            &id_str,
-            "--kernel",
-            &function_config.kernel,
-            "--memory",
-            &mem_str,
-            "--vcpu",
-            &vcpu_str,
-            "--rootfs",
-            &function_config.runtimefs,
-            "--vsock-cid",
-            &cid_str,
-        ];
-
-        if let Some(f) = function_config.appfs.as_ref() {
-            args.extend_from_slice(&["--appfs", f]);
-        }
-        if let Some(load_dir) = function_config.load_dir.as_ref() {
-            args.extend_from_slice(&["--load-dir", load_dir]);
-            if function_config.copy_base {
-                args.push("--copy-base");
-            }
-            if function_config.copy_diff {
-                args.push("--copy-diff");
-            }
-            if function_config.load_ws {
-                args.push("--load-ws");
-            }
-        }
-        if let Some(dump_dir) = function_config.dump_dir.as_ref() {
-            args.extend_from_slice(&["--dump-to", dump_dir]);
-            if function_config.dump_ws {
-                args.push("--dump-ws");
-            }
-        }
-        if let Some(cmdline) = function_config.cmdline.as_ref() {
-            args.extend_from_slice(&["--kernel-args", cmdline]);
+        // the hypervisor attaches the region after the rootfs, the appfs and its layers
+        let shm = if function_config.shared_mem > 0 {
+            let drives = 1
+                + function_config.appfs.is_some() as u8
+                + function_config.app_layers.len() as u8;
+            let device = format!("/dev/vd{}", (b'a' + drives) as char);
+            let shm = SharedMemory::new(function_config.shared_mem, device)
+                .map_err(Error::SharedMemory)?;
+            Some(shm)
         } else {
-            args.extend_from_slice(&["--kernel-args", "console=ttyS0"]);
-        }
-
-        // network config should be of the format <TAP-Name>/<MAC Address>
-        //let tap_name = format!("tap{}", cid - 100);
-        //let mac_addr = format!(
-        //    "{}:{:02X}:{:02X}",
-        //    MACPREFIX,
-        //    ((cid - 100) & 0xff00) >> 8,
-        //    (cid - 100) & 0xff
-        //);
-        if function_config.mac.is_some() {
-            args.extend_from_slice(&["--tap-name", function_config.tap.as_ref().unwrap()]);
-            args.extend_from_slice(&["--mac", function_config.mac.as_ref().unwrap()]);
-        }
-
-        // odirect
-        if let Some(odirect) = odirect {
-            if odirect.base {
-                args.push("--odirect-base");
-            }
-            if !odirect.diff {
-                args.push("--no-odirect-diff");
-            }
-            if !odirect.rootfs {
-                args.push("--no-odirect-root");
-            }
-            if !odirect.appfs {
-                args.push("--no-odirect-app");
-            }
+            None
+        };
+        let shm_path = shm.as_ref().map(|shm| format!("/proc/self/fd/{}", shm.fd()));
+        let cid = match cid {
+            Some(cid) => cid::reserve(cid),
+            None => cid::allocate(),
         }
+        .map_err(Error::Cid)?;
+        // the hypervisor forwards the runtime's connection to port 1234 here
+        let vsock_path = crate::run_dir().join(format!("worker-{}.sock_1234", cid.cid()));
+        let spec = LaunchSpec {
+            id: self.id,
+            cid: cid.cid(),
+            config: &function_config,
+            odirect: odirect.as_ref(),
+            shared_mem_file: shm_path.as_deref(),
+        };
+        let command = function_config.hypervisor.hypervisor().command(&spec)?;
+        // the guest console is the hypervisor's stdout. Keep its tail for diagnostics and pass it
+        // on to ours.
+        let console = Arc::new(Mutex::new(VecDeque::new()));
+        let console_pipe = if force_exit {
+            None
+        } else {
+            let (reader, writer) = UnixStream::pair().map_err(Error::ProcessSpawn)?;
+            let tail = console.clone();
+            std::thread::spawn(move || {
+                for line in std::io::BufReader::new(reader).lines() {
+                    let Ok(line) = line else { break };
+                    println!("{}", line);
+                    let mut tail = tail.lock().unwrap();
+                    if tail.len() == CONSOLE_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+            });
+            Some(writer)
+        };
 
         let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_io()
             .build()
             .unwrap();
         let (conn, vm_process) = runtime.block_on(async {
-            debug!("args: {:?}", args);
-            let mut vm_process = Command::new("firerunner")
-                .args(args)
+            let _ = std::fs::remove_file(&vsock_path);
+            let vm_listener =
+                tokio::net::UnixListener::bind(&vsock_path).map_err(Error::VsockListen)?;
+            debug!("command: {:?}", command);
+            let mut command = Command::from(command);
+            command
                 .kill_on_drop(true)
-                .stdin(Stdio::null())
                 //.stderr(std::io::stderr())
-                .spawn()
-                .map_err(|e| Error::ProcessSpawn(e))?;
+                .stdin(Stdio::null());
+            if let Some(writer) = console_pipe {
+                command.stdout(Stdio::from(OwnedFd::from(writer)));
+            }
+            if let Some(fd) = shm.as_ref().map(SharedMemory::fd) {
+                // the region is created close-on-exec so that only this VM's hypervisor inherits it
+                unsafe {
+                    command.pre_exec(move || {
+                        if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+            }
+            let mut vm_process = command.spawn().map_err(|e| Error::ProcessSpawn(e))?;
 
             if force_exit {
                 let output = vm_process
@@ -194,32 +344,115 @@ impl Vm {
                 std::process::exit(status);
             }
 
-            let vm_listener = tokio::net::UnixListener::from_std(vm_listener).unwrap();
-            let conn = tokio::select! {
-                res = vm_listener.accept() => {
-                    res.unwrap().0.into_std().unwrap()
-                },
-                res = vm_process.wait() => {
-                    crate::unlink_unix_sockets();
-                    error!("[Worker] cannot connect to the VM {:?}", res);
-                    std::process::exit(1);
+            let pid = vm_process.id();
+            let conn = loop {
+                let conn = tokio::select! {
+                    res = vm_listener.accept() => {
+                        res.unwrap().0.into_std().unwrap()
+                    },
+                    res = vm_process.wait() => {
+                        let _ = std::fs::remove_file(&vsock_path);
+                        error!("[Worker] hypervisor exited during boot: {:?}", res);
+                        for line in console.lock().unwrap().iter() {
+                            error!("[Worker] console: {}", line);
+                        }
+                        return Err(Error::BootFailed(res.ok()));
+                    }
+                };
+                // runtimes reconnect when their connection breaks, so a VM the worker is shutting
+                // down may connect until its hypervisor is gone
+                if manifest::peer_pid(&conn) == pid {
+                    break conn;
                 }
+                debug!("dropping a connection from another VM");
             };
+            let _ = std::fs::remove_file(&vsock_path);
             conn.set_nonblocking(false)
                 .map_err(|e| Error::VsockListen(e))?;
             let x: Result<_, Error> = Ok((conn, vm_process));
             x
         })?;
 
-        let handle = VmHandle { conn, vm_process };
+        let pid = vm_process.id().unwrap_or_default();
+        let handle = VmHandle {
+            watch: watch(pid, &conn),
+            conn,
+            vm_process: Some(vm_process),
+            pid,
+            started: manifest::process_start(pid).unwrap_or_default(),
+            cid,
+            vsock_path,
+            console,
+        };
 
         self.handle = Some(handle);
+        self.shm = shm;
 
         Ok(())
     }
+
+    /// The hypervisor process, if the VM is running
+    pub fn pid(&self) -> Option<u32> {
+        self.handle.as_ref().map(|h| h.pid)
+    }
+
+    /// Another handle to the VM's syscall channel, e.g. to shut it down from another thread
+    pub fn try_clone_conn(&self) -> Option<UnixStream> {
+        self.handle.as_ref().and_then(|h| h.conn.try_clone().ok())
+    }
+
+    /// Whether the hypervisor exited, e.g. after the syscall channel broke
+    pub fn hypervisor_exited(&self) -> bool {
+        let Some(handle) = self.handle.as_ref() else {
+            return false;
+        };
+        match handle.watch.as_ref() {
+            Some(watch) => watch.exited(),
+            None => manifest::process_start(handle.pid) != Some(handle.started),
+        }
+    }
+
+    /// Exit status of the hypervisor, if it is our child and exited
+    pub fn exit_status(&mut self) -> Option<ExitStatus> {
+        let child = self.handle.as_mut()?.vm_process.as_mut()?;
+        child.try_wait().ok().flatten()
+    }
+
+    /// Whether the guest kernel reports running out of memory on the console, e.g. after the
+    /// syscall channel broke because the OOM killer killed the runtime
+    pub fn out_of_memory(&self) -> bool {
+        let Some(handle) = self.handle.as_ref() else {
+            return false;
+        };
+        // the console may lag the syscall channel a little
+        for _ in 0..10 {
+            let oom = handle
+                .console
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|line| OOM_MARKERS.iter().any(|m| line.contains(m)));
+            if oom {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+}
+
+// Supervises hypervisor `pid`, see `supervisor`
+fn watch(pid: u32, conn: &UnixStream) -> Option<Watch> {
+    supervisor::watch(pid, conn)
+        .map_err(|e| error!("cannot supervise hypervisor {}: {:?}", pid, e))
+        .ok()
 }
 
 impl SyscallChannel for Vm {
+    fn shared_memory(&self) -> Option<&SharedMemory> {
+        self.shm.as_ref()
+    }
+
     fn send(&mut self, bytes: Vec<u8>) -> Result<(), SyscallChannelError> {
         let mut conn = &self.handle.as_ref().unwrap().conn;
         conn.write_all(&(bytes.len() as u32).to_be_bytes())
@@ -238,7 +471,12 @@ impl SyscallChannel for Vm {
         let mut conn = &self.handle.as_ref().unwrap().conn;
         conn.read_exact(&mut lenbuf).map_err(|e| {
             error!("read_exact size {:?}", e);
-            SyscallChannelError::Read
+            match e.kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+                    SyscallChannelError::Timeout
+                }
+                _ => SyscallChannelError::Read,
+            }
         })?;
         let size = u32::from_be_bytes(lenbuf);
         let mut buf = vec![0u8; size as usize];
@@ -265,6 +503,12 @@ impl Drop for Vm {
             } else {
                 debug!("shutdown vm connection {:?}", handle.conn);
             }
+            // our own children are killed when dropped
+            if handle.vm_process.is_none() {
+                unsafe {
+                    libc::kill(handle.pid as libc::pid_t, libc::SIGKILL);
+                }
+            }
         } else {
             debug!("dropping vm. unlaunched.")
         }
@@ -1,69 +1,121 @@
 //! Workers proxies requests and responses between the request manager and VMs.
-//! Each worker runs in its own thread and is modeled as the following state
-//! machine:
-use std::net::{SocketAddr, TcpStream};
-use std::os::unix::net::UnixListener;
+//!
+//! A `WorkerPool` waits on the scheduler for tasks on a tokio runtime and runs each task on a
+//! `Worker`, a reusable slot with its own vsock listener, in a thread of its own. The pool keeps
+//! one outstanding request for a task per VM that still fits in memory, so the number of
+//! concurrent tasks follows the machine's memory rather than a fixed number of threads.
+use std::collections::HashMap;
+use std::io::Read;
+use std::os::unix::net::UnixStream;
 use std::sync::{Arc, Mutex};
-use std::thread::{self, ThreadId};
+use std::time::Duration;
 
 use labeled::buckle::{Buckle, Component};
 use labeled::Label;
-use log::{debug, error};
+use log::{debug, error, info, warn};
+use openssl::pkey::{PKey, Private, Public};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 
-use crate::configs::FunctionConfig;
+use crate::configs::{FunctionConfig, WorkerConfig};
 use crate::vm::Vm;
 //use crate::metrics::{self, WorkerMetrics};
 use crate::fs::{self, BackingStore, Function, FS};
+use crate::hypervisor::Backend;
+use crate::janitor::Janitor;
 use crate::resource_manager;
+use crate::signing::Keyset;
 use crate::sched::{
     self,
-    message::{ReturnCode, TaskReturn},
+    message::{LabeledInvoke, ProcessTask, ProvisionVm, ReturnCode, TaskReturn},
 };
 use crate::syscall_server::*;
 
 // one hour
 //const FLUSH_INTERVAL_SECS: u64 = 3600;
 
+// smallest VM, i.e., the memory one more outstanding task request must fit in
+const MIN_VM_MEM: usize = 128;
+// how often idle VMs that outlived their idle TTL are shut down
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// When a worker replaces a VM whose hypervisor exited with a fresh one and retries the
+/// invocation on it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RestartPolicy {
+    /// never, the invocation fails
+    Never,
+    /// if the hypervisor exited while booting, before the invocation started
+    #[default]
+    Boot,
+    /// also if the hypervisor exited during the invocation, which then runs again from the start
+    Always,
+}
+
+/// Lets the worker pool stop a preemptible invocation running in a worker's thread
+#[derive(Debug, Default)]
+pub struct Preemption {
+    // whether the invocation was preempted, and the connection of the VM it runs in
+    state: Mutex<(bool, Option<UnixStream>)>,
+}
+
+impl Preemption {
+    /// Stops the invocation by shutting down its VM's connection
+    pub fn preempt(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = true;
+        if let Some(conn) = state.1.as_ref() {
+            let _ = conn.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    pub fn is_preempted(&self) -> bool {
+        self.state.lock().unwrap().0
+    }
+
+    // Makes `preempt` stop the invocation running in `vm`. Returns false if it was preempted
+    // already.
+    fn attach(&self, vm: &Vm) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.1 = vm.try_clone_conn();
+        !state.0
+    }
+}
+
 #[derive(Debug)]
-/// Manages VM allocation and boot process and communicates with the scheduler
+/// Manages VM allocation and boot process
 pub struct Worker<B: BackingStore> {
     //pub thread: JoinHandle<()>,
-    // each worker listens at the Unix socket worker-[cid].sock_1234
-    cid: u32,
-    thread_id: ThreadId,
+    id: u32,
     localrm: Arc<Mutex<resource_manager::ResourceManager>>,
-    vm_listener: std::os::unix::net::UnixListener,
     //stat: WorkerMetrics,
     env: SyscallGlobalEnv<B>,
+    // MBs of memory shared with each VM's runtime
+    shared_mem: usize,
+    // local paths of the kernel blobs this worker has booted
+    kernels: HashMap<fs::Blob, String>,
+    hypervisor: Backend,
+    // directory to jail each VM's blobs under, see `vm::Jail`
+    jail_dir: Option<std::path::PathBuf>,
+    // stops the current invocation if it is preemptible
+    preemption: Option<Arc<Preemption>>,
+    restart: RestartPolicy,
+    // opens sealed payloads, see `envelope`
+    payload_secret: Option<Vec<u8>>,
+    // seal ids of the payloads workers of this node opened, shared by the pool
+    opened: Arc<Mutex<crate::envelope::Opened>>,
+    // keys VM images must be signed with, see `signing`
+    image_keys: Option<Keyset>,
 }
 
 impl<B: BackingStore> Worker<B> {
     pub fn new(
-        cid: u32,
-        sched_addr: SocketAddr,
+        id: u32,
+        sched_conn: sched::rpc::Client,
         localrm: Arc<Mutex<resource_manager::ResourceManager>>,
         backing_store: B,
+        delegation_key: Option<PKey<Public>>,
     ) -> Self {
-        let thread_id = thread::current().id();
-
-        // connection to the scheduler
-        let sched_conn = loop {
-            debug!(
-                "[Worker {:?}] trying to connect to the scheduler at {:?}",
-                thread_id, sched_addr
-            );
-            if let Ok(conn) = TcpStream::connect(sched_addr) {
-                break conn;
-            }
-            std::thread::sleep(std::time::Duration::new(5, 0));
-        };
-        debug!("[Worker{:?}] connected.", thread_id);
-
-        // UNIX listener VMs connect to
-        let vm_listener_path = format!("worker-{}.sock_1234", cid);
-        let _ = std::fs::remove_file(&vm_listener_path);
-        let vm_listener = UnixListener::bind(vm_listener_path).expect("bind to the Unix listener");
-
         // TODO what metrics do we want?
         // let _ = std::fs::create_dir_all("./out").unwrap();
         // let log_file = std::fs::File::create(format!("./out/thread-{:?}.stat", thread::current().id())).unwrap();
@@ -71,164 +123,432 @@ impl<B: BackingStore> Worker<B> {
         //stat.start_timed_flush(FLUSH_INTERVAL_SECS);
 
         let default_fs = FS::new(backing_store);
+        default_fs.assert_partitioned();
 
         let env = SyscallGlobalEnv {
             sched_conn: Some(sched_conn),
             fs: default_fs,
             blobstore: Default::default(),
+            delegation_key,
+            max_inline: 0,
+            receipt_key: None,
+            egress: None,
+            scratch_dir: None,
         };
 
         Self {
-            cid,
-            thread_id,
+            id,
             localrm,
-            vm_listener,
             /* stat, */ env,
+            shared_mem: 0,
+            kernels: Default::default(),
+            hypervisor: Backend::Firecracker,
+            jail_dir: None,
+            preemption: None,
+            restart: RestartPolicy::default(),
+            payload_secret: None,
+            opened: Default::default(),
+            image_keys: None,
         }
     }
 
-    pub fn wait_and_process(&mut self) {
-        use sched::message::response::Kind;
+    /// Resolves the kernel blob `kernel` to a local path. Gates pin their kernel, and kernel
+    /// blobs are immutable, so a resolved path stays valid for the life of the worker.
+    fn kernel_path(&mut self, kernel: &fs::Blob) -> Option<String> {
+        if let Some(path) = self.kernels.get(kernel) {
+            return Some(path.clone());
+        }
+        if kernel.len() < 2 {
+            return None;
+        }
+        let path = self
+            .env
+            .blobstore
+            .local_path_string(kernel)
+            .filter(|p| std::path::Path::new(p).exists())?;
+        self.kernels.insert(kernel.clone(), path.clone());
+        Some(path)
+    }
+
+    /// Runs the invocation to completion and returns its result
+    pub fn process(&mut self, task_id: String, mut invoke: LabeledInvoke) -> TaskReturn {
+        let spilled = std::mem::take(&mut invoke.payload_blob);
+        if !spilled.is_empty() {
+            invoke.payload.clear();
+            let read = self
+                .env
+                .blobstore
+                .open(spilled.clone())
+                .and_then(|mut blob| blob.read_to_end(&mut invoke.payload));
+            if let Err(e) = read {
+                error!("[Worker {}] Failed to read the spilled payload: {:?}", self.id, e);
+                return TaskReturn {
+                    code: ReturnCode::ProcessRequestFailed as i32,
+                    label: Some(fs::utils::get_current_label().into()),
+                    ..Default::default()
+                };
+            }
+        }
+        let transient = invoke.payload_blob_transient;
+        let ret = self.process_inline(task_id, invoke);
+        // a preempted invocation runs again later and needs its payload then
+        if transient && ret.code != ReturnCode::Preempted as i32 {
+            if let Err(e) = self.env.blobstore.delete(&spilled) {
+                warn!("[Worker {}] Failed to delete the spilled payload: {:?}", self.id, e);
+            }
+        }
+        ret
+    }
+
+    // Runs the invocation, whose payload is inline
+    fn process_inline(&mut self, task_id: String, mut invoke: LabeledInvoke) -> TaskReturn {
+        let label = invoke.label.take().unwrap();
+        let privilege: Component = invoke.gate_privilege.take().unwrap().into();
+        if invoke.sealed {
+            if let Err(reason) = self.open_payload(&mut invoke, &privilege, &label, &task_id) {
+                error!("[Worker {}] {}", self.id, reason);
+                return TaskReturn {
+                    code: ReturnCode::ProcessRequestFailed as i32,
+                    label: Some(fs::utils::get_current_label().into()),
+                    error: Some(abort(reason)),
+                    ..Default::default()
+                };
+            }
+        }
+        let label = label.into();
+        let Some(mut vm) = self.try_allocate(&invoke.function.unwrap().into(), &label) else {
+            return TaskReturn {
+                code: ReturnCode::ResourceExhausted as i32,
+                payload: None,
+                label: Some(fs::utils::get_current_label().into()),
+                error: None,
+                receipts: vec![],
+                taint_trace: vec![],
+            };
+        };
+        let Some(kernel) = self.kernel_path(&vm.function.kernel) else {
+            error!("[Worker {}] Unknown kernel {:?}", self.id, vm.function.kernel);
+            self.localrm.lock().unwrap().delete(vm);
+            return TaskReturn {
+                code: ReturnCode::LaunchFailed as i32,
+                payload: None,
+                label: Some(fs::utils::get_current_label().into()),
+                error: None,
+                receipts: vec![],
+                taint_trace: vec![],
+            };
+        };
+        let results = vm.function.results.clone();
+        let mut cnt = 0;
+        let mut ret = TaskReturn {
+            code: ReturnCode::ProcessRequestFailed as i32,
+            payload: None,
+            label: Some(fs::utils::get_current_label().into()),
+            error: None,
+            receipts: vec![],
+            taint_trace: vec![],
+        };
         loop {
-            // rpc::get is blocking
-            match sched::rpc::get(self.env.sched_conn.as_mut().unwrap()) {
-                Err(e) => {
-                    error!(
-                        "[Worker {:?}] Failed to receive a scheduler response: {:?}",
-                        self.thread_id, e
-                    );
+            cnt += 1;
+            let fresh = vm.handle.is_none();
+            if let Err(e) = self.launch(&mut vm, &kernel) {
+                error!("[Worker {}] Failed VM launch: {:?}", self.id, e);
+                ret.code = ReturnCode::LaunchFailed as i32;
+                ret.error = Some(abort(launch_error(&e)));
+                let untrusted = matches!(e, crate::vm::Error::UntrustedImage(_));
+                if self.restart == RestartPolicy::Never || cnt == 5 || untrusted {
+                    self.localrm.lock().unwrap().delete(vm);
+                    break;
+                }
+                match self.replace(vm, &label) {
+                    Some(new_vm) => vm = new_vm,
+                    None => break,
+                }
+                continue;
+            }
+            if let Some(preemption) = self.preemption.as_ref() {
+                if !preemption.attach(&vm) {
+                    ret.code = ReturnCode::Preempted as i32;
+                    self.localrm.lock().unwrap().release(vm);
+                    break;
+                }
+            }
+            // TODO consider using meaningful clearance
+            let blobs = invoke
+                .blobs
+                .iter()
+                .map(|(k, b)| {
+                    (
+                        k.clone(),
+                        (self.env.blobstore.open(b.clone()).unwrap()),
+                    )
+                })
+                .collect();
+            let mut processor =
+                SyscallProcessor::new(&mut self.env, label.clone(), privilege.clone());
+            if fresh {
+                if let Err(e) = processor.init(&mut vm) {
+                    // the VM is unusable, but the request can still run on another one
+                    error!("[Worker {}] Failed VM init: {:?}", self.id, e);
+                    let function = vm.function.clone();
+                    self.localrm.lock().unwrap().delete(vm);
+                    if cnt == 5 {
+                        ret.code = ReturnCode::LaunchFailed as i32;
+                        break;
+                    }
+                    let Some(new_vm) = self.try_allocate(&function, &label) else {
+                        ret.code = ReturnCode::ResourceExhausted as i32;
+                        break;
+                    };
+                    vm = new_vm;
                     continue;
                 }
-                Ok(resp) => {
-                    match resp.kind {
-                        Some(Kind::Terminate(_)) => {
-                            debug!("[Worker {:?}] terminate received", self.thread_id);
-                            //self.stat.flush();
-                            return;
+            }
+            processor.set_task_id(task_id.clone());
+            processor.set_scratch(vm.function.scratch);
+            if invoke.trace_taint {
+                processor.trace_taint();
+            }
+            if invoke.stream {
+                processor.stream_chunks(task_id.clone());
+            }
+            let headers = invoke
+                .headers
+                .iter()
+                .filter(|(name, _)| vm.function.forwards_header(name))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            match processor.run(
+                invoke.payload.clone(),
+                blobs,
+                headers,
+                invoke.invoker.clone().unwrap().into(),
+                &mut vm,
+            ) {
+                Ok(result) => {
+                    ret = result;
+                    if !invoke.reply_key.is_empty() {
+                        if let Err(e) = seal_response(&mut ret, &invoke.reply_key) {
+                            error!("[Worker {}] Failed to seal response: {:?}", self.id, e);
+                            ret.code = ReturnCode::ProcessRequestFailed as i32;
+                            ret.payload = None;
                         }
-                        Some(Kind::ProcessTask(r)) => {
-                            debug!("{:?}", r);
-                            if r.labeled_invoke.is_none() {
-                                error!("[Worker {:?}] labeled_invoke is None", self.thread_id);
-                                continue;
-                            }
-                            let task_id = r.task_id;
-                            let invoke = r.labeled_invoke.unwrap();
-                            let label = invoke.label.unwrap().into();
-                            let privilege: Component = invoke.gate_privilege.unwrap().into();
-                            if let Some(mut vm) =
-                                self.try_allocate(&invoke.function.unwrap().into(), &label)
-                            {
-                                let mut cnt = 0;
-                                let mut ret = TaskReturn {
-                                    code: ReturnCode::ProcessRequestFailed as i32,
-                                    payload: None,
-                                    label: Some(fs::utils::get_current_label().into()),
-                                };
-                                loop {
-                                    cnt += 1;
-                                    let mut config: FunctionConfig = vm.function.clone().into();
-                                    config.kernel = self
-                                        .env
-                                        .blobstore
-                                        .local_path_string(&vm.function.kernel)
-                                        .unwrap_or_default();
-                                    config.appfs = self
-                                        .env
-                                        .blobstore
-                                        .local_path_string(&vm.function.app_image);
-                                    config.runtimefs = self
-                                        .env
-                                        .blobstore
-                                        .local_path_string(&vm.function.runtime_image)
-                                        .unwrap_or_default();
-                                    if let Err(e) = vm.launch(
-                                        self.vm_listener.try_clone().unwrap(),
-                                        self.cid,
-                                        false,
-                                        config,
-                                        None,
-                                    ) {
-                                        error!(
-                                            "[Worker {:?}] Failed VM launch: {:?}",
-                                            self.thread_id, e
-                                        );
-                                        continue;
-                                    }
-                                    // TODO consider using meaningful clearance
-                                    let blobs = invoke
-                                        .blobs
-                                        .iter()
-                                        .map(|(k, b)| {
-                                            (
-                                                k.clone(),
-                                                (self.env.blobstore.open(b.clone()).unwrap()),
-                                            )
-                                        })
-                                        .collect();
-                                    let processor = SyscallProcessor::new(
-                                        &mut self.env,
-                                        label.clone(),
-                                        privilege.clone(),
-                                    );
-                                    if let Ok(result) = processor.run(
-                                        invoke.payload.clone(),
-                                        blobs,
-                                        invoke.headers.clone(),
-                                        invoke.invoker.clone().unwrap().into(),
-                                        &mut vm,
-                                    ) {
-                                        ret = result;
-                                        self.localrm.lock().unwrap().release(vm);
-                                        break;
-                                    }
-                                    if cnt == 5 {
-                                        if vm.handle.is_none() {
-                                            ret.code = ReturnCode::LaunchFailed as i32;
-                                        }
-                                        self.localrm.lock().unwrap().delete(vm);
-                                        break;
-                                    }
-                                }
-                                if let Err(e) = sched::rpc::finish(
-                                    &mut self.env.sched_conn.as_mut().unwrap(),
-                                    task_id,
-                                    ret,
-                                ) {
-                                    error!(
-                                        "[Worker {:?}] Failed scheduler finish RPC: {:?}",
-                                        self.thread_id, e
-                                    );
-                                }
-                            } else {
-                                let ret = TaskReturn {
-                                    code: ReturnCode::ResourceExhausted as i32,
-                                    payload: None,
-                                    label: Some(fs::utils::get_current_label().into()),
-                                };
-                                if let Err(e) = sched::rpc::finish(
-                                    &mut self.env.sched_conn.as_mut().unwrap(),
-                                    task_id,
-                                    ret,
-                                ) {
-                                    error!(
-                                        "[Worker {:?}] Failed scheduler finish RPC: {:?}",
-                                        self.thread_id, e
-                                    );
-                                };
-                            }
+                    }
+                    if let Some(response) = ret.payload.as_mut() {
+                        let max_inline = self.env.max_inline;
+                        if let Err(e) = response.spill_body(&mut self.env.blobstore, max_inline)
+                        {
+                            error!("[Worker {}] Failed to spill response: {:?}", self.id, e);
+                            ret.code = ReturnCode::ProcessRequestFailed as i32;
+                            ret.payload = None;
+                        }
+                    }
+                    self.localrm.lock().unwrap().release(vm);
+                    break;
+                }
+                Err(e) => {
+                    error!("[Worker {}] Failed request: {:?}", self.id, e);
+                    let preempted = self.preemption.as_ref().is_some_and(|p| p.is_preempted());
+                    if !preempted && vm.hypervisor_exited() {
+                        let status = vm.exit_status();
+                        error!("[Worker {}] Hypervisor exited: {:?}", self.id, status);
+                        ret.code = ReturnCode::HypervisorExited as i32;
+                        ret.error = Some(abort(match status {
+                            Some(status) => format!("the hypervisor exited with {}", status),
+                            None => "the hypervisor exited".to_string(),
+                        }));
+                        if self.restart != RestartPolicy::Always || cnt == 5 {
+                            self.localrm.lock().unwrap().delete(vm);
+                            break;
                         }
-                        _ => {
-                            error!(
-                                "[Worker {:?}] Unknown scheduler response: {:?}",
-                                self.thread_id, resp
-                            );
-                            continue;
+                        match self.replace(vm, &label) {
+                            Some(new_vm) => vm = new_vm,
+                            None => break,
                         }
+                        continue;
                     }
+                    ret.code = if preempted {
+                        ReturnCode::Preempted
+                    } else if vm.out_of_memory() {
+                        ReturnCode::OutOfMemory
+                    } else {
+                        e.return_code()
+                    } as i32;
                 }
-            };
+            }
+            // a function that timed out or ran out of memory would do so again
+            let hopeless = [
+                ReturnCode::Timeout as i32,
+                ReturnCode::OutOfMemory as i32,
+                ReturnCode::Preempted as i32,
+            ];
+            if cnt == 5 || hopeless.contains(&ret.code) {
+                if vm.handle.is_none() {
+                    ret.code = ReturnCode::LaunchFailed as i32;
+                }
+                self.localrm.lock().unwrap().delete(vm);
+                break;
+            }
+        }
+        // a preempted invocation runs again later
+        if !invoke.sync && ret.code != ReturnCode::Preempted as i32 {
+            if let Some(retention) = results {
+                self.store_result(&task_id, &retention, &ret);
+            }
+        }
+        ret
+    }
+
+    // Stores the result of an async invocation for its invoker to pick up, see `fs::retention`
+    fn store_result(&self, task_id: &str, retention: &fs::ResultRetention, ret: &TaskReturn) {
+        use prost::Message;
+        let result = ret.encode_to_vec();
+        if let Err(e) = fs::retention::store(&self.env.fs, retention, task_id, result) {
+            warn!("[Worker {}] Failed to store the result in {}: {:?}", self.id, retention.dir, e);
+        }
+    }
+
+    // Replaces the sealed payload of `invoke` with the plaintext, if it was sealed for an
+    // invocation labeled `label` and not opened for another task before
+    fn open_payload(
+        &self,
+        invoke: &mut LabeledInvoke,
+        gate: &Component,
+        label: &crate::syscalls::Buckle,
+        task_id: &str,
+    ) -> Result<(), String> {
+        let secret = self
+            .payload_secret
+            .as_ref()
+            .ok_or("the worker cannot open sealed payloads")?;
+        if invoke.seal_id.is_empty() {
+            return Err("the sealed payload has no seal id".to_string());
+        }
+        let key = crate::envelope::gate_key(secret, gate);
+        let binding = crate::envelope::Binding {
+            label,
+            seal_id: &invoke.seal_id,
+        };
+        let payload = crate::envelope::open(&key, &binding, &invoke.payload)
+            .map_err(|e| format!("failed to open the sealed payload: {:?}", e))?;
+        if !self.opened.lock().unwrap().record(&invoke.seal_id, task_id) {
+            return Err(format!("the sealed payload {} is replayed", invoke.seal_id));
+        }
+        invoke.payload = payload;
+        Ok(())
+    }
+
+    /// Boots and initializes a VM of `f` and leaves it idle, to keep the function warm
+    pub fn provision(&mut self, f: Function) -> TaskReturn {
+        let mut ret = TaskReturn {
+            code: ReturnCode::Success as i32,
+            ..Default::default()
+        };
+        let Some(mut vm) = self.localrm.lock().unwrap().new_vm(f) else {
+            ret.code = ReturnCode::ResourceExhausted as i32;
+            return ret;
+        };
+        let launched = match self.kernel_path(&vm.function.kernel) {
+            Some(kernel) => self.launch(&mut vm, &kernel).is_ok(),
+            None => false,
+        };
+        let initialized = launched && {
+            let mut processor =
+                SyscallProcessor::new(&mut self.env, Buckle::public(), Component::dc_true());
+            processor.init(&mut vm).is_ok()
+        };
+        if initialized {
+            self.localrm.lock().unwrap().release(vm);
+        } else {
+            error!("[Worker {}] Failed to provision a VM of {:?}", self.id, vm.function);
+            self.localrm.lock().unwrap().delete(vm);
+            ret.code = ReturnCode::LaunchFailed as i32;
+        }
+        ret
+    }
+
+    // Checks that a trusted key signed each image blob and that the file the hypervisor boots
+    // it from hashes to its name, if the worker has keys. Runs on every boot, since the files
+    // could change between boots.
+    fn verify_images(&self, images: &[(&fs::Blob, &str)]) -> Result<(), crate::vm::Error> {
+        let Some(keys) = self.image_keys.as_ref() else {
+            return Ok(());
+        };
+        for (blob, path) in images.iter().filter(|(blob, _)| !blob.is_empty()) {
+            if let Err(e) = keys.verify(&self.env.blobstore, blob, std::path::Path::new(path)) {
+                error!("[Worker {}] Rejected image {}: {:?}", self.id, blob, e);
+                return Err(crate::vm::Error::UntrustedImage(blob.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    // Boots `vm` with the kernel at the local path `kernel` unless it is running already
+    fn launch(&mut self, vm: &mut Vm, kernel: &str) -> Result<(), crate::vm::Error> {
+        let mut config: FunctionConfig = vm.function.clone().into();
+        config.kernel = kernel.to_string();
+        config.appfs = self
+            .env
+            .blobstore
+            .local_path_string(&vm.function.app_image);
+        config.runtimefs = self
+            .env
+            .blobstore
+            .local_path_string(&vm.function.runtime_image)
+            .unwrap_or_default();
+        // layers without a local copy are left out, so keep which blob each one is
+        let (layers, paths): (Vec<_>, Vec<_>) = vm
+            .function
+            .app_layers
+            .iter()
+            .filter_map(|layer| Some((layer, self.env.blobstore.local_path_string(layer)?)))
+            .unzip();
+        config.app_layers = paths;
+        config.shared_mem = self.shared_mem;
+        config.devices = vm.devices.clone();
+        config.hypervisor = self.hypervisor;
+        if let Some(base) = self.jail_dir.as_ref().filter(|_| vm.handle.is_none()) {
+            if vm.jail.is_none() {
+                vm.jail = Some(crate::vm::Jail::create(base, vm.id)?);
+            }
+            let jail = vm.jail.as_ref().unwrap();
+            config.kernel = jail.link(&config.kernel, "kernel")?;
+            if let Some(appfs) = config.appfs.as_ref() {
+                config.appfs = Some(jail.link(appfs, "appfs")?);
+            }
+            if !config.runtimefs.is_empty() {
+                config.runtimefs = jail.link(&config.runtimefs, "runtimefs")?;
+            }
+            for (i, layer) in config.app_layers.iter_mut().enumerate() {
+                *layer = jail.link(layer, &format!("layer{}", i))?;
+            }
+        }
+        let fresh = vm.handle.is_none();
+        if fresh {
+            // the files as the hypervisor will see them, i.e. after jailing
+            let f = &vm.function;
+            let mut images = vec![(&f.kernel, config.kernel.as_str())];
+            if let Some(appfs) = config.appfs.as_ref() {
+                images.push((&f.app_image, appfs.as_str()));
+            }
+            if !config.runtimefs.is_empty() {
+                images.push((&f.runtime_image, config.runtimefs.as_str()));
+            }
+            images.extend(layers.into_iter().zip(config.app_layers.iter().map(String::as_str)));
+            self.verify_images(&images)?;
+        }
+        vm.launch(None, false, config, None)?;
+        if fresh {
+            self.localrm.lock().unwrap().launched(vm);
         }
+        Ok(())
+    }
+
+    // Shuts `vm` down and allocates a fresh VM of its function in its place
+    fn replace(&self, vm: Vm, payload_label: &Buckle) -> Option<Vm> {
+        let function = vm.function.clone();
+        self.localrm.lock().unwrap().delete(vm);
+        self.try_allocate(&function, payload_label)
     }
 
     fn try_allocate(&self, f: &Function, payload_label: &Buckle) -> Option<Vm> {
@@ -244,3 +564,454 @@ impl<B: BackingStore> Worker<B> {
         localrm.new_vm(f.clone())
     }
 }
+
+fn abort(reason: String) -> crate::syscalls::Abort {
+    crate::syscalls::Abort {
+        reason,
+        ..Default::default()
+    }
+}
+
+// Seals the body of the function's response to the invoker's DER encoded public key `key`
+fn seal_response(ret: &mut TaskReturn, key: &[u8]) -> Result<(), crate::envelope::EnvelopeError> {
+    let Some(body) = ret.payload.as_mut().and_then(|r| r.body.as_mut()) else {
+        return Ok(());
+    };
+    let key = PKey::public_key_from_der(key)?;
+    *body = crate::envelope::seal_to(&key, body)?;
+    Ok(())
+}
+
+// What to tell the invoker about a VM that failed to launch
+fn launch_error(e: &crate::vm::Error) -> String {
+    match e {
+        crate::vm::Error::BootFailed(Some(status)) => {
+            format!("the hypervisor exited with {} while booting", status)
+        }
+        crate::vm::Error::BootFailed(None) => "the hypervisor exited while booting".to_string(),
+        crate::vm::Error::Cid(_) => "no vsock CID is available".to_string(),
+        crate::vm::Error::UntrustedImage(blob) => {
+            format!("image {} is not signed by a trusted key", blob)
+        }
+        _ => "failed to start the hypervisor".to_string(),
+    }
+}
+
+/// Waits on the scheduler for tasks and runs them on `Worker`s
+pub struct WorkerPool<B: BackingStore> {
+    sched_addr: String,
+    localrm: Arc<Mutex<resource_manager::ResourceManager>>,
+    backing_store: B,
+    delegation_key: Option<PKey<Public>>,
+    shared_mem: usize,
+    hypervisor: Backend,
+    jail_dir: Option<std::path::PathBuf>,
+    scratch_dir: Option<std::path::PathBuf>,
+    max_inline: usize,
+    receipt_key: Option<PKey<Private>>,
+    // FS path of the worker configuration
+    config_path: Option<String>,
+    // most tasks running at once
+    pool_size: Option<usize>,
+    egress: Option<Vec<String>>,
+    restart: RestartPolicy,
+    payload_secret: Option<Vec<u8>>,
+    opened: Arc<Mutex<crate::envelope::Opened>>,
+    image_keys: Option<Keyset>,
+}
+
+impl<B: BackingStore + Clone + Send + 'static> WorkerPool<B> {
+    pub fn new(
+        sched_addr: String,
+        localrm: Arc<Mutex<resource_manager::ResourceManager>>,
+        backing_store: B,
+        delegation_key: Option<PKey<Public>>,
+    ) -> Self {
+        Self {
+            sched_addr,
+            localrm,
+            backing_store,
+            delegation_key,
+            shared_mem: 0,
+            hypervisor: Backend::Firecracker,
+            jail_dir: None,
+            scratch_dir: None,
+            max_inline: 0,
+            receipt_key: None,
+            config_path: None,
+            pool_size: None,
+            egress: None,
+            restart: RestartPolicy::default(),
+            payload_secret: None,
+            opened: Default::default(),
+            image_keys: None,
+        }
+    }
+
+    /// Reads the `WorkerConfig` from the file at `path` in the FS at startup and whenever the
+    /// scheduler asks for a reload. The file must be endorsed by faasten.
+    pub fn set_config_path(&mut self, path: String) {
+        self.config_path = Some(path);
+    }
+
+    /// Signs declassification receipts with `key`
+    pub fn set_receipt_key(&mut self, key: PKey<Private>) {
+        self.receipt_key = Some(key);
+    }
+
+    /// Passes payloads and responses larger than `bytes` through the scheduler as blobs, 0
+    /// passes everything inline
+    pub fn set_max_inline(&mut self, bytes: usize) {
+        self.max_inline = bytes;
+    }
+
+    /// Gives each VM a memory region of `mb` MBs shared with its runtime
+    pub fn set_shared_mem(&mut self, mb: usize) {
+        self.shared_mem = mb;
+    }
+
+    /// Boots VMs with `hypervisor`
+    pub fn set_hypervisor(&mut self, hypervisor: Backend) {
+        self.hypervisor = hypervisor;
+    }
+
+    /// Boots each VM from links to its blobs in a directory of its own under `dir` rather than
+    /// from the blobstore
+    pub fn set_jail_dir(&mut self, dir: std::path::PathBuf) {
+        self.jail_dir = Some(dir);
+    }
+
+    /// Puts invocations' scratch spaces under `dir` rather than the system's temporary directory
+    pub fn set_scratch_dir(&mut self, dir: std::path::PathBuf) {
+        self.scratch_dir = Some(dir);
+    }
+
+    /// Opens payloads the webfront sealed with `secret`, see `envelope`
+    pub fn set_payload_secret(&mut self, secret: Vec<u8>) {
+        self.payload_secret = Some(secret);
+    }
+
+    /// Boots VMs only from images one of `keys` signed, see `signing`
+    pub fn set_image_keys(&mut self, keys: Keyset) {
+        self.image_keys = Some(keys);
+    }
+
+    /// Replaces VMs whose hypervisor exits according to `policy`
+    pub fn set_restart_policy(&mut self, policy: RestartPolicy) {
+        self.restart = policy;
+    }
+
+    /// Processes tasks until the scheduler tells the pool to terminate
+    pub fn run(self) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("tokio runtime");
+        debug!(
+            "[WorkerPool] trying to connect to the scheduler at {}",
+            self.sched_addr
+        );
+        // the connection is shared by the pool and all workers and reconnects on its own once
+        // the scheduler is reachable again
+        let client = sched::rpc::connect_retrying(&self.sched_addr);
+        debug!("[WorkerPool] connected.");
+        crate::blobstore::Blobstore::<sha2::Sha256>::default().spawn_tmp_cleaner();
+        // clean up after workers that crashed here before
+        Janitor::default().sweep(&self.localrm.lock().unwrap().claimed_pids());
+        rt.block_on(self.serve(client));
+    }
+
+    // Applies the worker configuration in the FS, keeping the current one if the file cannot be
+    // read
+    async fn reload_config(&mut self) {
+        let Some(path) = self.config_path.clone() else {
+            return;
+        };
+        let store = self.backing_store.clone();
+        let localrm = self.localrm.clone();
+        // reading the FS blocks
+        let config = tokio::task::spawn_blocking(move || {
+            let config = read_config(&FS::new(store), &path)?;
+            let mut localrm = localrm.lock().unwrap();
+            localrm.set_cache_budget(config.cache_budget);
+            localrm.set_idle_ttl(config.idle_ttl);
+            localrm.set_max_min_instances(config.max_min_instances);
+            localrm.set_max_keep_alive(config.max_keep_alive);
+            crate::blobstore::set_usage_alert(config.blob_usage_alert);
+            Some(config)
+        })
+        .await
+        .expect("join configuration reload");
+        if let Some(config) = config {
+            info!("[WorkerPool] configuration {:?}", config);
+            self.pool_size = config.pool_size;
+            self.egress = config.egress;
+        }
+    }
+
+    async fn serve(mut self, client: sched::rpc::Client) {
+        use sched::message::response::Kind;
+
+        self.reload_config().await;
+
+        let total_mem = self.localrm.lock().unwrap().total_mem_in_mb();
+        // memory reserved by running tasks
+        let mut busy_mem = 0;
+        let mut running = 0;
+        let mut polls = JoinSet::new();
+        let mut finishes = JoinSet::new();
+        // watches for the scheduler preempting running tasks, by task id
+        let mut watches: HashMap<String, tokio::task::JoinHandle<()>> = HashMap::new();
+        let mut idle_workers: Vec<Worker<B>> = Vec::new();
+        let mut next_id = 0;
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+        let mut terminating = false;
+        // consecutive failed task requests
+        let mut failures = 0;
+        let mut reap = tokio::time::interval(REAP_INTERVAL);
+
+        loop {
+            while !terminating
+                && busy_mem + (polls.len() + 1) * MIN_VM_MEM <= total_mem
+                && self.pool_size.is_none_or(|n| running + polls.len() < n)
+            {
+                let mut client = client.clone();
+                let delay = if failures > 0 {
+                    sched::rpc::backoff(failures)
+                } else {
+                    Duration::ZERO
+                };
+                polls.spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    sched::rpc::get(&mut client).await
+                });
+            }
+            if terminating && running == 0 {
+                // deliver the last results before the runtime goes away
+                while finishes.join_next().await.is_some() {}
+                return;
+            }
+            tokio::select! {
+                Some(res) = polls.join_next(), if !terminating => {
+                    let res = res.expect("join a task request");
+                    if res.is_ok() && failures > 0 {
+                        // the scheduler may have restarted and lost the node, and the
+                        // re-issued task requests alone do not tell it about cached VMs
+                        info!("[WorkerPool] scheduler reachable again, re-registering");
+                        failures = 0;
+                        let localrm = self.localrm.clone();
+                        std::thread::spawn(move || localrm.lock().unwrap().update_scheduler());
+                    }
+                    let job = match res.map(|r| r.kind) {
+                        Err(e) => {
+                            if failures == 0 {
+                                warn!("[WorkerPool] lost the scheduler: {:?}", e);
+                            }
+                            failures += 1;
+                            None
+                        }
+                        Ok(Some(Kind::ReloadConfig(_))) => {
+                            debug!("[WorkerPool] reload received");
+                            self.reload_config().await;
+                            None
+                        }
+                        Ok(Some(Kind::PauseVm(pause))) => {
+                            debug!("[WorkerPool] {:?} received", pause);
+                            let localrm = self.localrm.clone();
+                            std::thread::spawn(move || {
+                                let mut localrm = localrm.lock().unwrap();
+                                let id = pause.vm as usize;
+                                let res = if pause.resume {
+                                    localrm.resume(id)
+                                } else {
+                                    localrm.pause(id)
+                                };
+                                if let Err(e) = res {
+                                    warn!("[WorkerPool] failed to (un)pause vm {}: {:?}", id, e);
+                                }
+                            });
+                            None
+                        }
+                        Ok(Some(Kind::Prefetch(prefetch))) => {
+                            if let Some(function) = prefetch.function {
+                                let function: Function = function.into();
+                                debug!("[WorkerPool] prefetch {:?}", function);
+                                std::thread::spawn(move || prefetch_images(&function));
+                            }
+                            None
+                        }
+                        Ok(Some(Kind::Terminate(_))) => {
+                            debug!("[WorkerPool] terminate received");
+                            terminating = true;
+                            polls.abort_all();
+                            None
+                        }
+                        Ok(Some(Kind::ProcessTask(ProcessTask {
+                            task_id,
+                            labeled_invoke: Some(invoke),
+                        }))) => {
+                            debug!("{:?}", invoke);
+                            Some((task_id, invoke.function.clone(), Job::Invoke(Box::new(invoke))))
+                        }
+                        Ok(Some(Kind::ProvisionVm(ProvisionVm {
+                            task_id,
+                            function: Some(function),
+                        }))) => {
+                            debug!("[WorkerPool] provision {:?}", function);
+                            let job = Job::Provision(Box::new(function.clone().into()));
+                            Some((task_id, Some(function), job))
+                        }
+                        Ok(kind) => {
+                            error!("[WorkerPool] Unknown scheduler response: {:?}", kind);
+                            None
+                        }
+                    };
+                    if let Some((task_id, function, job)) = job {
+                        let memory = function.as_ref().map_or(MIN_VM_MEM, |f| f.memory as usize);
+                        busy_mem += memory;
+                        running += 1;
+                        let worker = idle_workers.pop();
+                        let id = next_id;
+                        if worker.is_none() {
+                            next_id += 1;
+                        }
+                        let sched_conn = client.clone();
+                        let localrm = self.localrm.clone();
+                        let backing_store = self.backing_store.clone();
+                        let delegation_key = self.delegation_key.clone();
+                        let done_tx = done_tx.clone();
+                        let shared_mem = self.shared_mem;
+                        let hypervisor = self.hypervisor;
+                        let jail_dir = self.jail_dir.clone();
+                        let restart = self.restart;
+                        let payload_secret = self.payload_secret.clone();
+                        let opened = self.opened.clone();
+                        let image_keys = self.image_keys.clone();
+                        let scratch_dir = self.scratch_dir.clone();
+                        let max_inline = self.max_inline;
+                        let receipt_key = self.receipt_key.clone();
+                        let egress = self.egress.clone();
+                        let preemptible = matches!(&job, Job::Invoke(invoke)
+                            if invoke.preemptible
+                                && invoke.function.as_ref().is_some_and(|f| f.idempotent));
+                        let preemption = preemptible.then(|| {
+                            let preemption = Arc::new(Preemption::default());
+                            let watched = preemption.clone();
+                            let mut client = client.clone();
+                            let id = task_id.clone();
+                            let watch = tokio::spawn(async move {
+                                if sched::rpc::watch_preemption(&mut client, id).await.is_ok() {
+                                    watched.preempt();
+                                }
+                            });
+                            watches.insert(task_id.clone(), watch);
+                            preemption
+                        });
+                        // tasks block on VMs and the file system, so they get threads of
+                        // their own
+                        std::thread::spawn(move || {
+                            let mut worker = worker.unwrap_or_else(|| {
+                                let store = backing_store;
+                                let mut worker =
+                                    Worker::new(id, sched_conn, localrm, store, delegation_key);
+                                worker.shared_mem = shared_mem;
+                                worker.hypervisor = hypervisor;
+                                worker.jail_dir = jail_dir;
+                                worker.restart = restart;
+                                worker.payload_secret = payload_secret;
+                                worker.opened = opened;
+                                worker.image_keys = image_keys;
+                                worker.env.scratch_dir = scratch_dir;
+                                worker.env.max_inline = max_inline;
+                                worker.env.receipt_key = receipt_key;
+                                worker
+                            });
+                            worker.env.egress = egress;
+                            worker.preemption = preemption;
+                            let ret = match job {
+                                Job::Invoke(invoke) => worker.process(task_id.clone(), *invoke),
+                                Job::Provision(function) => worker.provision(*function),
+                            };
+                            let _ = done_tx.send((worker, task_id, ret, function));
+                        });
+                    }
+                }
+                Some((worker, task_id, ret, function)) = done_rx.recv() => {
+                    busy_mem -= function.as_ref().map_or(MIN_VM_MEM, |f| f.memory as usize);
+                    running -= 1;
+                    if let Some(watch) = watches.remove(&task_id) {
+                        watch.abort();
+                    }
+                    idle_workers.push(worker);
+                    let mut client = client.clone();
+                    finishes.spawn(async move {
+                        let finish = sched::rpc::finish(&mut client, task_id, ret, function);
+                        if let Err(e) = finish.await {
+                            error!("[WorkerPool] Failed scheduler finish RPC: {:?}", e);
+                        }
+                    });
+                }
+                // reap delivered results
+                Some(_) = finishes.join_next() => {}
+                _ = reap.tick() => {
+                    // reporting to the scheduler blocks
+                    let localrm = self.localrm.clone();
+                    std::thread::spawn(move || {
+                        let claimed = {
+                            let mut localrm = localrm.lock().unwrap();
+                            localrm.reap_idle();
+                            localrm.claimed_pids()
+                        };
+                        Janitor::default().sweep(&claimed);
+                    });
+                }
+            }
+        }
+    }
+}
+
+// What a worker does for a task the scheduler hands out
+enum Job {
+    Invoke(Box<LabeledInvoke>),
+    Provision(Box<Function>),
+}
+
+// Reads the worker configuration at `path`, ignoring it unless only faasten can write it
+fn read_config<B: BackingStore>(fs: &FS<B>, path: &str) -> Option<WorkerConfig> {
+    fs::utils::clear_label();
+    fs::utils::set_my_privilge(Component::dc_true());
+    let entry = fs::path::Path::parse(path).ok().and_then(|p| fs.read_path(p).ok());
+    let Some(fs::DirEntry::File(file)) = entry else {
+        warn!("[WorkerPool] no configuration file at {}", path);
+        return None;
+    };
+    let endorsed = file
+        .get(fs)
+        .is_some_and(|f| f.label().integrity.implies(&fs::bootstrap::FAASTEN_PRIV));
+    if !endorsed {
+        warn!("[WorkerPool] configuration {} is not endorsed by faasten", path);
+        return None;
+    }
+    serde_json::from_slice(&file.read(fs))
+        .map_err(|e| warn!("[WorkerPool] bad configuration {}: {}", path, e))
+        .ok()
+}
+
+// Reads the image blobs of `f` so that they are in the page cache when a VM of it boots
+fn prefetch_images(f: &Function) {
+    let blobstore = crate::blobstore::Blobstore::<sha2::Sha256>::default();
+    let images = [&f.kernel, &f.runtime_image, &f.app_image];
+    let images = images
+        .iter()
+        .copied()
+        .chain(f.app_layers.iter())
+        .filter(|blob| !blob.is_empty());
+    for blob in images {
+        let res = blobstore
+            .open(blob.clone())
+            .and_then(|mut b| std::io::copy(&mut b, &mut std::io::sink()));
+        if let Err(e) = res {
+            warn!("[WorkerPool] failed to prefetch image {}: {:?}", blob, e);
+        }
+    }
+}